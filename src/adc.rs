@@ -20,15 +20,26 @@ use std::time::Duration;
 use anyhow::Result;
 use async_std::sync::Arc;
 use async_std::task::sleep;
+use tide::Server;
 
-use crate::broker::{BrokerBuilder, Topic};
-use crate::measurement::{Measurement, Timestamp};
+use crate::backends::{Backend, Backends, Degraded};
+use crate::broker::{BrokerBuilder, Topic, TopicMeta};
+use crate::measurement::{DisplayFormat, Measurement, Timestamp};
+use crate::realtime::Realtime;
 use crate::system::HardwareGeneration;
 use crate::watched_tasks::WatchedTasksBuilder;
 
 const HISTORY_LENGTH: usize = 200;
 const SLOW_INTERVAL: Duration = Duration::from_millis(100);
 
+/// Values of channels that were not updated for this long (e.g. because the
+/// ADC thread stopped) are marked as stale
+const MEASUREMENT_TTL: Duration = Duration::from_secs(1);
+
+/// Below this current (in A) the load resistance is not estimated,
+/// as it is dominated by the noise of the current measurement
+const MIN_RESISTANCE_CURRENT: f32 = 0.005;
+
 #[cfg(test)]
 mod iio {
     mod test;
@@ -49,6 +60,8 @@ mod iio {
 
 pub use iio::{CalibratedChannel, IioThread};
 
+mod stream;
+
 /// A reference to an ADC channel.
 ///
 /// The channel can be used in two different ways:
@@ -61,6 +74,93 @@ pub use iio::{CalibratedChannel, IioThread};
 pub struct AdcChannel {
     pub fast: CalibratedChannel,
     pub topic: Arc<Topic<Measurement>>,
+    pub format: Arc<Topic<DisplayFormat>>,
+}
+
+/// Set up the measurement topic of a channel and its display format
+fn channel_topics(
+    bb: &mut BrokerBuilder,
+    path: &str,
+    description: &str,
+    unit: &str,
+    significant_digits: u8,
+) -> (Arc<Topic<Measurement>>, Arc<Topic<DisplayFormat>>) {
+    let topic = bb.topic(path, true, false, false, None, HISTORY_LENGTH);
+
+    bb.describe(
+        &topic,
+        TopicMeta::new(description)
+            .unit(unit)
+            .tag("measurement")
+            .ttl(MEASUREMENT_TTL),
+    );
+
+    let format = bb.topic(
+        &format!("{path}/format"),
+        true,
+        true,
+        true,
+        Some(DisplayFormat::new(unit, significant_digits)),
+        1,
+    );
+
+    (topic, format)
+}
+
+impl AdcChannel {
+    fn new(
+        bb: &mut BrokerBuilder,
+        fast: CalibratedChannel,
+        path: &str,
+        description: &str,
+        unit: &str,
+        significant_digits: u8,
+    ) -> Self {
+        let (topic, format) = channel_topics(bb, path, description, unit, significant_digits);
+
+        Self {
+            fast,
+            topic,
+            format,
+        }
+    }
+
+    /// Get a function that formats values of this channel for the LCD
+    /// according to the configured display format
+    pub fn lcd_formatter(&self) -> impl Fn(f32) -> String + Send + Sync + 'static {
+        let format = self.format.clone();
+
+        move |value| {
+            format
+                .try_get()
+                .map(|f| f.format_ascii(value))
+                .unwrap_or_default()
+        }
+    }
+}
+
+/// Set up the topics of a channel that is computed from time-aligned samples
+/// of other channels instead of being sampled itself
+fn computed_channel(
+    bb: &mut BrokerBuilder,
+    path: &str,
+    description: &str,
+    unit: &str,
+    significant_digits: u8,
+) -> Arc<Topic<Measurement>> {
+    // The format topic is only there for API clients, as the value is not
+    // shown on the LCD.
+    let (topic, _format) = channel_topics(bb, path, description, unit, significant_digits);
+
+    topic
+}
+
+/// Estimate the resistance of the load from a voltage and current sample
+///
+/// Returns None if there is (almost) no current flowing, as the estimate
+/// would be meaningless or infinite.
+fn load_resistance(volt: f32, curr: f32) -> Option<f32> {
+    (curr.abs() >= MIN_RESISTANCE_CURRENT).then_some(volt / curr)
 }
 
 #[derive(Clone)]
@@ -75,145 +175,152 @@ pub struct Adc {
     pub iobus_volt: AdcChannel,
     pub pwr_volt: AdcChannel,
     pub pwr_curr: AdcChannel,
+    /// The power delivered to the DUT, computed from time-aligned samples
+    /// of `pwr_volt` and `pwr_curr`
+    pub pwr_power: Arc<Topic<Measurement>>,
+    /// The estimated resistance of the DUT. Not updated while there is
+    /// (almost) no current flowing.
+    pub pwr_resistance: Arc<Topic<Measurement>>,
     pub time: Arc<Topic<Timestamp>>,
+    /// The power board ADC provides values, i.e. it is neither stubbed out
+    /// nor did it fail to probe (e.g. because the power board is missing)
+    pub pwr_available: bool,
 }
 
 impl Adc {
     pub async fn new(
         bb: &mut BrokerBuilder,
         wtb: &mut WatchedTasksBuilder,
+        realtime: &Realtime,
         hardware_generation: HardwareGeneration,
+        backends: &Backends,
+        degraded: &Degraded,
     ) -> Result<Self> {
-        let stm32_thread = IioThread::new_stm32(wtb, hardware_generation).await?;
-        let powerboard_thread = IioThread::new_powerboard(wtb, hardware_generation).await?;
+        let stm32_thread = match backends.adc_stm32 {
+            Backend::Hardware => IioThread::new_stm32(wtb, realtime, hardware_generation).await?,
+            Backend::Stub => IioThread::new_stub_stm32(hardware_generation).await?,
+        };
+
+        let powerboard = match backends.adc_powerboard {
+            Backend::Hardware => IioThread::new_powerboard(wtb, realtime, hardware_generation)
+                .await
+                .map_err(|e| degraded.report("adc_powerboard", &e))
+                .ok(),
+            Backend::Stub => None,
+        };
+
+        let pwr_available = powerboard.is_some();
+
+        let powerboard_thread = match powerboard {
+            Some(thread) => thread,
+            None => IioThread::new_stub_powerboard(hardware_generation).await?,
+        };
 
         let adc = Self {
-            usb_host_curr: AdcChannel {
-                fast: stm32_thread.clone().get_channel("usb-host-curr").unwrap(),
-                topic: bb.topic(
-                    "/v1/usb/host/total/feedback/current",
-                    true,
-                    false,
-                    false,
-                    None,
-                    HISTORY_LENGTH,
-                ),
-            },
-            usb_host1_curr: AdcChannel {
-                fast: stm32_thread.clone().get_channel("usb-host1-curr").unwrap(),
-                topic: bb.topic(
-                    "/v1/usb/host/port1/feedback/current",
-                    true,
-                    false,
-                    false,
-                    None,
-                    HISTORY_LENGTH,
-                ),
-            },
-            usb_host2_curr: AdcChannel {
-                fast: stm32_thread.clone().get_channel("usb-host2-curr").unwrap(),
-                topic: bb.topic(
-                    "/v1/usb/host/port2/feedback/current",
-                    true,
-                    false,
-                    false,
-                    None,
-                    HISTORY_LENGTH,
-                ),
-            },
-            usb_host3_curr: AdcChannel {
-                fast: stm32_thread.clone().get_channel("usb-host3-curr").unwrap(),
-                topic: bb.topic(
-                    "/v1/usb/host/port3/feedback/current",
-                    true,
-                    false,
-                    false,
-                    None,
-                    HISTORY_LENGTH,
-                ),
-            },
-            out0_volt: AdcChannel {
-                fast: stm32_thread.clone().get_channel("out0-volt").unwrap(),
-                topic: bb.topic(
-                    "/v1/output/out_0/feedback/voltage",
-                    true,
-                    false,
-                    false,
-                    None,
-                    HISTORY_LENGTH,
-                ),
-            },
-            out1_volt: AdcChannel {
-                fast: stm32_thread.clone().get_channel("out1-volt").unwrap(),
-                topic: bb.topic(
-                    "/v1/output/out_1/feedback/voltage",
-                    true,
-                    false,
-                    false,
-                    None,
-                    HISTORY_LENGTH,
-                ),
-            },
-            iobus_curr: AdcChannel {
-                fast: stm32_thread.clone().get_channel("iobus-curr").unwrap(),
-                topic: bb.topic(
-                    "/v1/iobus/feedback/current",
-                    true,
-                    false,
-                    false,
-                    None,
-                    HISTORY_LENGTH,
-                ),
-            },
-            iobus_volt: AdcChannel {
-                fast: stm32_thread.clone().get_channel("iobus-volt").unwrap(),
-                topic: bb.topic(
-                    "/v1/iobus/feedback/voltage",
-                    true,
-                    false,
-                    false,
-                    None,
-                    HISTORY_LENGTH,
-                ),
-            },
-            pwr_volt: AdcChannel {
-                fast: powerboard_thread.clone().get_channel("pwr-volt").unwrap(),
-                topic: bb.topic(
-                    "/v1/dut/feedback/voltage",
-                    true,
-                    false,
-                    false,
-                    None,
-                    HISTORY_LENGTH,
-                ),
-            },
-            pwr_curr: AdcChannel {
-                fast: powerboard_thread.get_channel("pwr-curr").unwrap(),
-                topic: bb.topic(
-                    "/v1/dut/feedback/current",
-                    true,
-                    false,
-                    false,
-                    None,
-                    HISTORY_LENGTH,
-                ),
-            },
+            usb_host_curr: AdcChannel::new(
+                bb,
+                stm32_thread.clone().get_channel("usb-host-curr").unwrap(),
+                "/v1/usb/host/total/feedback/current",
+                "Total current drawn from the USB host ports",
+                "A",
+                3,
+            ),
+            usb_host1_curr: AdcChannel::new(
+                bb,
+                stm32_thread.clone().get_channel("usb-host1-curr").unwrap(),
+                "/v1/usb/host/port1/feedback/current",
+                "Current drawn from USB host port 1",
+                "A",
+                3,
+            ),
+            usb_host2_curr: AdcChannel::new(
+                bb,
+                stm32_thread.clone().get_channel("usb-host2-curr").unwrap(),
+                "/v1/usb/host/port2/feedback/current",
+                "Current drawn from USB host port 2",
+                "A",
+                3,
+            ),
+            usb_host3_curr: AdcChannel::new(
+                bb,
+                stm32_thread.clone().get_channel("usb-host3-curr").unwrap(),
+                "/v1/usb/host/port3/feedback/current",
+                "Current drawn from USB host port 3",
+                "A",
+                3,
+            ),
+            out0_volt: AdcChannel::new(
+                bb,
+                stm32_thread.clone().get_channel("out0-volt").unwrap(),
+                "/v1/output/out_0/feedback/voltage",
+                "Voltage on output OUT_0",
+                "V",
+                3,
+            ),
+            out1_volt: AdcChannel::new(
+                bb,
+                stm32_thread.clone().get_channel("out1-volt").unwrap(),
+                "/v1/output/out_1/feedback/voltage",
+                "Voltage on output OUT_1",
+                "V",
+                3,
+            ),
+            iobus_curr: AdcChannel::new(
+                bb,
+                stm32_thread.clone().get_channel("iobus-curr").unwrap(),
+                "/v1/iobus/feedback/current",
+                "Current drawn from the IOBus power supply",
+                "A",
+                3,
+            ),
+            iobus_volt: AdcChannel::new(
+                bb,
+                stm32_thread.clone().get_channel("iobus-volt").unwrap(),
+                "/v1/iobus/feedback/voltage",
+                "Voltage of the IOBus power supply",
+                "V",
+                4,
+            ),
+            pwr_volt: AdcChannel::new(
+                bb,
+                powerboard_thread.clone().get_channel("pwr-volt").unwrap(),
+                "/v1/dut/feedback/voltage",
+                "Voltage on the DUT power output",
+                "V",
+                4,
+            ),
+            pwr_curr: AdcChannel::new(
+                bb,
+                powerboard_thread.get_channel("pwr-curr").unwrap(),
+                "/v1/dut/feedback/current",
+                "Current drawn by the DUT",
+                "A",
+                3,
+            ),
+            pwr_power: computed_channel(
+                bb,
+                "/v1/dut/feedback/power",
+                "Power delivered to the DUT",
+                "W",
+                3,
+            ),
+            pwr_resistance: computed_channel(
+                bb,
+                "/v1/dut/feedback/resistance",
+                "Estimated resistance of the DUT",
+                "Ohm",
+                3,
+            ),
             time: bb.topic_ro("/v1/tac/time/now", None),
+            pwr_available,
         };
 
-        let channels = [
-            adc.usb_host_curr.clone(),
-            adc.usb_host1_curr.clone(),
-            adc.usb_host2_curr.clone(),
-            adc.usb_host3_curr.clone(),
-            adc.out0_volt.clone(),
-            adc.out1_volt.clone(),
-            adc.iobus_curr.clone(),
-            adc.iobus_volt.clone(),
-            adc.pwr_volt.clone(),
-            adc.pwr_curr.clone(),
-        ];
+        let channels = adc.channels();
 
+        let pwr_volt = adc.pwr_volt.fast.clone();
+        let pwr_curr = adc.pwr_curr.fast.clone();
+        let pwr_power = adc.pwr_power.clone();
+        let pwr_resistance = adc.pwr_resistance.clone();
         let time = adc.time.clone();
 
         // Spawn an async task to transfer values from the Atomic value based
@@ -224,9 +331,27 @@ impl Adc {
 
                 for channel in &channels {
                     if let Ok(val) = channel.fast.get() {
-                        // The adc channel topic should likely be wrapped in a Result
-                        // or otherwise be able to contain an error state.
-                        channel.topic.set(val)
+                        // Do not keep re-publishing the last value of a
+                        // stalled ADC, so that the topic is marked as stale.
+                        if val.ts.elapsed() < MEASUREMENT_TTL {
+                            channel.topic.set(val)
+                        }
+                    }
+                }
+
+                // Use samples of the voltage and current that were taken at
+                // the same time, so that e.g. a current spike is not
+                // multiplied with the voltage from before the spike.
+                if let Ok([volt, curr]) = pwr_volt.try_get_multiple([&pwr_volt, &pwr_curr]) {
+                    if volt.ts.elapsed() < MEASUREMENT_TTL {
+                        pwr_power.set(Measurement {
+                            ts: volt.ts,
+                            value: volt.value * curr.value,
+                        });
+
+                        if let Some(value) = load_resistance(volt.value, curr.value) {
+                            pwr_resistance.set(Measurement { ts: volt.ts, value });
+                        }
                     }
                 }
 
@@ -236,4 +361,51 @@ impl Adc {
 
         Ok(adc)
     }
+
+    /// Stream the samples of the ADC channels at full rate via a websocket
+    pub fn serve_stream(&self, server: &mut Server<()>) {
+        stream::serve(server, self.channels().to_vec());
+    }
+
+    /// The measurement topics of all sampled and computed channels,
+    /// e.g. to keep a history of
+    pub fn measurement_topics(&self) -> Vec<Arc<Topic<Measurement>>> {
+        self.channels()
+            .iter()
+            .map(|ch| ch.topic.clone())
+            .chain([self.pwr_power.clone(), self.pwr_resistance.clone()])
+            .collect()
+    }
+
+    /// All sampled measurement channels (excluding the time)
+    pub fn channels(&self) -> [AdcChannel; 10] {
+        [
+            self.usb_host_curr.clone(),
+            self.usb_host1_curr.clone(),
+            self.usb_host2_curr.clone(),
+            self.usb_host3_curr.clone(),
+            self.out0_volt.clone(),
+            self.out1_volt.clone(),
+            self.iobus_curr.clone(),
+            self.iobus_volt.clone(),
+            self.pwr_volt.clone(),
+            self.pwr_curr.clone(),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{load_resistance, MIN_RESISTANCE_CURRENT};
+
+    #[test]
+    fn resistance() {
+        println!("The resistance is estimated from voltage and current");
+        assert_eq!(load_resistance(12.0, 0.5), Some(24.0));
+        assert!(load_resistance(12.0, MIN_RESISTANCE_CURRENT).is_some());
+
+        println!("But not while there is (almost) no current");
+        assert_eq!(load_resistance(12.0, 0.0), None);
+        assert_eq!(load_resistance(12.0, -0.001), None);
+    }
 }
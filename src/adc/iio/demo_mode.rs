@@ -160,7 +160,11 @@ pub struct IioThread {
 }
 
 impl IioThread {
-    pub async fn new_stm32<W, G>(_wtb: &W, _hardware_generation: G) -> Result<Arc<Self>> {
+    pub async fn new_stm32<W, R, G>(
+        _wtb: &W,
+        _realtime: &R,
+        _hardware_generation: G,
+    ) -> Result<Arc<Self>> {
         let mut demo_magic = block_on(DEMO_MAGIC_STM32.lock());
 
         // Only ever set up a single demo_mode "IioThread" per ADC
@@ -195,7 +199,11 @@ impl IioThread {
         Ok(this)
     }
 
-    pub async fn new_powerboard<W, G>(_wtb: &W, _hardware_generation: G) -> Result<Arc<Self>> {
+    pub async fn new_powerboard<W, R, G>(
+        _wtb: &W,
+        _realtime: &R,
+        _hardware_generation: G,
+    ) -> Result<Arc<Self>> {
         let mut demo_magic = block_on(DEMO_MAGIC_POWERBOARD.lock());
 
         // Only ever set up a single demo_mode "IioThread" per ADC
@@ -215,6 +223,16 @@ impl IioThread {
         Ok(this)
     }
 
+    // The demo mode does not access any hardware, so there is nothing to
+    // stub out and the simulated channels are used instead.
+    pub async fn new_stub_stm32<G>(hardware_generation: G) -> Result<Arc<Self>> {
+        Self::new_stm32(&(), &(), hardware_generation).await
+    }
+
+    pub async fn new_stub_powerboard<G>(hardware_generation: G) -> Result<Arc<Self>> {
+        Self::new_powerboard(&(), &(), hardware_generation).await
+    }
+
     pub fn get_channel(self: Arc<Self>, ch_name: &str) -> Result<CalibratedChannel> {
         self.channels
             .iter()
@@ -18,7 +18,6 @@
 use std::convert::{TryFrom, TryInto};
 use std::fs::create_dir;
 use std::io::Read;
-use std::path::Path;
 use std::sync::atomic::{AtomicU16, AtomicU64, Ordering};
 use std::time::{Duration, Instant};
 
@@ -31,7 +30,9 @@ use industrial_io::{Buffer, Channel};
 use log::{debug, error, warn};
 use thread_priority::*;
 
+use crate::fs_root;
 use crate::measurement::{Measurement, Timestamp};
+use crate::realtime::Realtime;
 use crate::system::HardwareGeneration;
 use crate::watched_tasks::WatchedTasksBuilder;
 
@@ -89,8 +90,16 @@ impl Calibration {
         Ok(Self { scale, offset })
     }
 
+    /// A calibration that does not change the values
+    fn identity() -> Self {
+        Self {
+            scale: 1.0,
+            offset: 0.0,
+        }
+    }
+
     fn from_devicetree_chosen(name: &str) -> Result<Self> {
-        let path = std::path::Path::new("/sys/firmware/devicetree/base/chosen").join(name);
+        let path = fs_root::path("/sys/firmware/devicetree/base/chosen").join(name);
 
         Self::from_file(path)
     }
@@ -111,7 +120,13 @@ impl CalibratedChannel {
     /// Create a new calibrated channel using calibration data from `calibration_name`.
     /// Values will be read from the value array of `iio_thread` at index `index`.
     fn from_name(iio_thread: Arc<IioThread>, index: usize, calibration_name: &str) -> Result<Self> {
-        let calibration = Calibration::from_devicetree_chosen(calibration_name)?;
+        // A stubbed ADC never provides values, so there is nothing to calibrate.
+        // The calibration data may also be missing if e.g. the EEPROM on a
+        // damaged board can not be read.
+        let calibration = match iio_thread.stubbed {
+            true => Calibration::identity(),
+            false => Calibration::from_devicetree_chosen(calibration_name)?,
+        };
 
         Ok(Self {
             iio_thread,
@@ -192,6 +207,7 @@ pub struct IioThread {
     timestamp: AtomicU64,
     values: Vec<AtomicU16>,
     channel_descs: &'static [ChannelDesc],
+    stubbed: bool,
 }
 
 impl IioThread {
@@ -259,8 +275,27 @@ impl IioThread {
         Ok((channels, buf))
     }
 
+    /// Check that the kernel knows about the ADC and its trigger
+    ///
+    /// This is done before spawning the ADC thread, so that a missing ADC
+    /// (e.g. on a TAC without power board) results in an error the caller
+    /// can handle, instead of an exiting thread that ends the tacd.
+    fn adc_present(adc_name: &str, trigger_name: &str) -> Result<()> {
+        let ctx = industrial_io::Context::new()?;
+
+        ctx.find_device(adc_name)
+            .ok_or(anyhow!("Could not find ADC: {}", adc_name))?;
+
+        ctx.find_device(trigger_name)
+            .ok_or(anyhow!("Could not find IIO trigger: {}", trigger_name))?;
+
+        Ok(())
+    }
+
+    #[allow(clippy::too_many_arguments)]
     async fn new(
         wtb: &mut WatchedTasksBuilder,
+        realtime: &Realtime,
         thread_name: &'static str,
         adc_name: &'static str,
         trigger_name: &'static str,
@@ -277,6 +312,12 @@ impl IioThread {
         // to the calling thread via a queue.
         let (thread_tx, thread_rx) = bounded(1);
 
+        Self::adc_present(adc_name, trigger_name)?;
+
+        // Every refill of the buffer takes buffer_len samples at sample_rate
+        let period = Duration::from_secs_f64(buffer_len as f64 / sample_rate as f64);
+        let mut monitor = realtime.monitor(thread_name, period);
+
         // Spawn a high priority thread that updates the atomic values in `thread`.
         wtb.spawn_thread(thread_name, move || {
             let (channels, mut buf) = Self::adc_setup(
@@ -292,6 +333,7 @@ impl IioThread {
                 timestamp: AtomicU64::new(TIMESTAMP_ERROR),
                 values: channels.iter().map(|_| AtomicU16::new(0)).collect(),
                 channel_descs,
+                stubbed: false,
             });
 
             let thread_weak = Arc::downgrade(&thread);
@@ -308,6 +350,8 @@ impl IioThread {
                     Err(e)?;
                 }
 
+                monitor.woke_up();
+
                 let values = channels.iter().map(|ch| {
                     let buf_sum: u32 = buf.channel_iter::<u16>(ch).map(|v| v as u32).sum();
                     (buf_sum / (buf.capacity() as u32)) as u16
@@ -344,12 +388,14 @@ impl IioThread {
 
     pub async fn new_stm32(
         wtb: &mut WatchedTasksBuilder,
+        realtime: &Realtime,
         hardware_generation: HardwareGeneration,
     ) -> Result<Arc<Self>> {
         let channels = hardware_generation.channels_stm32();
 
         Self::new(
             wtb,
+            realtime,
             "adc-stm32",
             "48003000.adc:adc@0",
             "tim4_trgo",
@@ -362,18 +408,20 @@ impl IioThread {
 
     pub async fn new_powerboard(
         wtb: &mut WatchedTasksBuilder,
+        realtime: &Realtime,
         hardware_generation: HardwareGeneration,
     ) -> Result<Arc<Self>> {
-        let hr_trigger_path = Path::new(TRIGGER_HR_PWR_DIR);
+        let hr_trigger_path = fs_root::path(TRIGGER_HR_PWR_DIR);
 
         if !hr_trigger_path.is_dir() {
-            create_dir(hr_trigger_path)?;
+            create_dir(&hr_trigger_path)?;
         }
 
         let channels = hardware_generation.channels_pwr();
 
         Self::new(
             wtb,
+            realtime,
             "adc-powerboard",
             "lmp92064",
             "tacd-pwr",
@@ -384,6 +432,28 @@ impl IioThread {
         .await
     }
 
+    /// Set up an IioThread that does not access the ADC at all
+    ///
+    /// Reading from its channels always fails with an AquisitionError,
+    /// just like it would for an ADC that stopped working.
+    fn new_stub(channel_descs: &'static [ChannelDesc]) -> Arc<Self> {
+        Arc::new(Self {
+            ref_instant: Instant::now(),
+            timestamp: AtomicU64::new(TIMESTAMP_ERROR),
+            values: channel_descs.iter().map(|_| AtomicU16::new(0)).collect(),
+            channel_descs,
+            stubbed: true,
+        })
+    }
+
+    pub async fn new_stub_stm32(hardware_generation: HardwareGeneration) -> Result<Arc<Self>> {
+        Ok(Self::new_stub(hardware_generation.channels_stm32()))
+    }
+
+    pub async fn new_stub_powerboard(hardware_generation: HardwareGeneration) -> Result<Arc<Self>> {
+        Ok(Self::new_stub(hardware_generation.channels_pwr()))
+    }
+
     /// Use the channel names defined at the top of the file to get a reference
     /// to a channel
     pub fn get_channel(self: Arc<Self>, ch_name: &str) -> Result<CalibratedChannel> {
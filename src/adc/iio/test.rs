@@ -107,7 +107,11 @@ pub struct IioThread {
 }
 
 impl IioThread {
-    pub async fn new_stm32<W, G>(_wtb: &W, _hardware_generation: G) -> Result<Arc<Self>> {
+    pub async fn new_stm32<W, R, G>(
+        _wtb: &W,
+        _realtime: &R,
+        _hardware_generation: G,
+    ) -> Result<Arc<Self>> {
         let mut channels = Vec::new();
 
         for name in CHANNELS_STM32 {
@@ -117,7 +121,11 @@ impl IioThread {
         Ok(Arc::new(Self { channels }))
     }
 
-    pub async fn new_powerboard<W, G>(_wtb: &W, _hardware_generation: G) -> Result<Arc<Self>> {
+    pub async fn new_powerboard<W, R, G>(
+        _wtb: &W,
+        _realtime: &R,
+        _hardware_generation: G,
+    ) -> Result<Arc<Self>> {
         let mut channels = Vec::new();
 
         for name in CHANNELS_PWR {
@@ -127,6 +135,16 @@ impl IioThread {
         Ok(Arc::new(Self { channels }))
     }
 
+    // The tests do not access any hardware, so there is nothing to
+    // stub out.
+    pub async fn new_stub_stm32<G>(hardware_generation: G) -> Result<Arc<Self>> {
+        Self::new_stm32(&(), &(), hardware_generation).await
+    }
+
+    pub async fn new_stub_powerboard<G>(hardware_generation: G) -> Result<Arc<Self>> {
+        Self::new_powerboard(&(), &(), hardware_generation).await
+    }
+
     pub fn get_channel(self: Arc<Self>, ch_name: &str) -> Result<CalibratedChannel> {
         self.channels
             .iter()
@@ -0,0 +1,249 @@
+// This file is part of tacd, the LXA TAC system daemon
+// Copyright (C) 2024 Pengutronix e.K.
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along
+// with this program; if not, write to the Free Software Foundation, Inc.,
+// 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+
+//! Stream ADC samples at the full rate via a websocket
+//!
+//! The broker only carries values decimated to `SLOW_INTERVAL`. Clients that
+//! need every sample (e.g. scope-like views) can connect to
+//! `/v1/adc/stream?channels=<topic path>,<topic path>` instead.
+//! Without the `channels` parameter all channels are streamed.
+//!
+//! The first message is a text message containing a JSON list of the
+//! streamed channels. It is followed by binary messages containing any
+//! number of 13 byte records:
+//!
+//! | Offset | Type   | Content                                          |
+//! |--------|--------|--------------------------------------------------|
+//! | 0      | u8     | Index of the channel in the list                 |
+//! | 1      | f64 LE | Timestamp in milliseconds since the Unix Epoch   |
+//! | 9      | f32 LE | Calibrated value                                 |
+
+use std::time::{Duration, Instant, SystemTime};
+
+use anyhow::{bail, Result};
+use async_std::task::sleep;
+use async_tungstenite::tungstenite::Message;
+use async_tungstenite::WebSocketStream;
+use futures::{select, FutureExt, SinkExt, StreamExt};
+use log::warn;
+use serde::Deserialize;
+use tide::http::upgrade::Connection;
+use tide::{http::mime, Request, Response, Server};
+
+use super::AdcChannel;
+use crate::broker::AnyTopic;
+use crate::http_server::websocket;
+use crate::measurement::Measurement;
+
+const STREAM_ROUTE: &str = "/v1/adc/stream";
+
+/// How often the channels are checked for new samples.
+/// This is well above the rate the ADCs provide new values at.
+const POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+/// Collect samples for this long before sending them in a single message
+const FRAME_INTERVAL: Duration = Duration::from_millis(100);
+
+const RECORD_LEN: usize = 13;
+
+#[derive(Deserialize)]
+struct StreamQuery {
+    channels: Option<String>,
+}
+
+fn path(channel: &AdcChannel) -> &str {
+    channel.topic.path()
+}
+
+/// Get the indices of the channels requested via `query` (a comma separated
+/// list of topic paths) in `available`
+fn select_channels(available: &[&str], query: Option<&str>) -> Result<Vec<usize>> {
+    let query = match query {
+        Some(query) => query,
+        None => return Ok((0..available.len()).collect()),
+    };
+
+    let mut selected = Vec::new();
+
+    for path in query.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+        match available.iter().position(|a| *a == path) {
+            Some(idx) if !selected.contains(&idx) => selected.push(idx),
+            Some(_) => {}
+            None => bail!("No such channel: {path}"),
+        }
+    }
+
+    if selected.is_empty() {
+        bail!("No channels selected");
+    }
+
+    Ok(selected)
+}
+
+/// Append a sample of the `index`th streamed channel to `frame`
+fn encode(frame: &mut Vec<u8>, index: u8, measurement: &Measurement) {
+    let ts = measurement
+        .ts
+        .in_system_time()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0.0, |d| d.as_secs_f64() * 1000.0);
+
+    frame.reserve(RECORD_LEN);
+    frame.push(index);
+    frame.extend_from_slice(&ts.to_le_bytes());
+    frame.extend_from_slice(&measurement.value.to_le_bytes());
+}
+
+/// Send new samples of `channels` to the client until it goes away
+async fn stream(ws: WebSocketStream<Connection>, channels: Vec<AdcChannel>) -> Result<()> {
+    let (mut ws_tx, mut ws_rx) = ws.split();
+
+    let paths: Vec<&str> = channels.iter().map(path).collect();
+    ws_tx
+        .send(Message::text(serde_json::to_string(&paths)?))
+        .await?;
+
+    let mut last_seen: Vec<Option<Instant>> = vec![None; channels.len()];
+    let mut frame = Vec::new();
+    let mut frame_start = Instant::now();
+
+    loop {
+        select! {
+            msg = ws_rx.next().fuse() => match msg {
+                Some(Ok(Message::Close(_))) | None => break,
+                Some(Ok(_)) => {}
+                Some(Err(e)) => return Err(e.into()),
+            },
+            _ = sleep(POLL_INTERVAL).fuse() => {
+                for (index, (channel, last)) in channels.iter().zip(&mut last_seen).enumerate() {
+                    let measurement = match channel.fast.get() {
+                        Ok(measurement) => measurement,
+                        Err(_) => continue,
+                    };
+
+                    if *last != Some(*measurement.ts) {
+                        *last = Some(*measurement.ts);
+                        encode(&mut frame, index as u8, &measurement);
+                    }
+                }
+
+                if frame_start.elapsed() >= FRAME_INTERVAL {
+                    if !frame.is_empty() {
+                        ws_tx.send(Message::binary(std::mem::take(&mut frame))).await?;
+                    }
+
+                    frame_start = Instant::now();
+                }
+            },
+        }
+    }
+
+    Ok(())
+}
+
+fn plain(status: u16, msg: &str) -> Response {
+    Response::builder(status)
+        .body(msg)
+        .content_type(mime::PLAIN)
+        .build()
+}
+
+pub(super) fn serve(server: &mut Server<()>, channels: Vec<AdcChannel>) {
+    server.at(STREAM_ROUTE).get(move |req: Request<()>| {
+        let channels = channels.clone();
+
+        async move {
+            let query = match req.query::<StreamQuery>() {
+                Ok(query) => query,
+                Err(_) => return Ok(plain(400, "Invalid query parameters")),
+            };
+
+            let paths: Vec<&str> = channels.iter().map(path).collect();
+
+            let selected = match select_channels(&paths, query.channels.as_deref()) {
+                Ok(selected) => selected,
+                Err(e) => return Ok(plain(400, &e.to_string())),
+            };
+
+            let selected: Vec<AdcChannel> =
+                selected.into_iter().map(|i| channels[i].clone()).collect();
+
+            websocket::upgrade(&req, &[], move |ws| async move {
+                if let Err(e) = stream(ws, selected).await {
+                    warn!("ADC stream ended: {e}");
+                }
+            })
+            .await
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryInto;
+    use std::time::SystemTime;
+
+    use super::{encode, select_channels, RECORD_LEN};
+    use crate::measurement::{Measurement, Timestamp};
+
+    #[test]
+    fn channels() {
+        let available = [
+            "/v1/dut/feedback/voltage",
+            "/v1/dut/feedback/current",
+            "/v1/iobus/feedback/voltage",
+        ];
+
+        println!("All channels are streamed by default");
+        assert_eq!(select_channels(&available, None).unwrap(), [0, 1, 2]);
+
+        println!("Channels are streamed in the requested order");
+        let query = "/v1/iobus/feedback/voltage, /v1/dut/feedback/voltage";
+        assert_eq!(select_channels(&available, Some(query)).unwrap(), [2, 0]);
+
+        println!("Unknown and empty selections are refused");
+        assert!(select_channels(&available, Some("/v1/dut/powered")).is_err());
+        assert!(select_channels(&available, Some("")).is_err());
+    }
+
+    #[test]
+    fn records() {
+        let measurement = Measurement {
+            ts: Timestamp::now(),
+            value: 12.5,
+        };
+
+        let mut frame = Vec::new();
+        encode(&mut frame, 3, &measurement);
+        encode(&mut frame, 4, &measurement);
+
+        assert_eq!(frame.len(), 2 * RECORD_LEN);
+        assert_eq!(frame[0], 3);
+        assert_eq!(frame[RECORD_LEN], 4);
+
+        let ts = f64::from_le_bytes(frame[1..9].try_into().unwrap());
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs_f64()
+            * 1000.0;
+        assert!((now - ts).abs() < 1000.0);
+
+        let value = f32::from_le_bytes(frame[9..13].try_into().unwrap());
+        assert_eq!(value, 12.5);
+    }
+}
@@ -0,0 +1,235 @@
+// This file is part of tacd, the LXA TAC system daemon
+// Copyright (C) 2024 Pengutronix e.K.
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along
+// with this program; if not, write to the Free Software Foundation, Inc.,
+// 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+
+//! Let test scripts mark events like "boot started" during long
+//! measurement captures
+//!
+//! Annotations belong to the DUT profile that was active when they were
+//! made (unless a profile is given explicitly), so that the markers of one
+//! DUT do not show up in the measurements of another.
+//! They are written into running recordings and are served alongside the
+//! measurement history.
+
+use std::time::SystemTime;
+
+use anyhow::Result;
+use async_std::prelude::*;
+use async_std::sync::Arc;
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::broker::{BrokerBuilder, Topic};
+use crate::watched_tasks::WatchedTasksBuilder;
+
+/// Number of annotations to keep around
+const HISTORY_LENGTH: usize = 64;
+
+/// Longest label (in bytes) that is accepted
+const LABEL_MAX_LEN: usize = 128;
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct AnnotationRequest {
+    /// Javascript timestamp (milliseconds since Unix Epoch 0) of the event.
+    /// The time of arrival is used if not set.
+    ts: Option<f64>,
+    label: String,
+    /// The DUT profile the annotation belongs to.
+    /// The currently selected profile is used if not set.
+    #[serde(default)]
+    profile: Option<String>,
+}
+
+/// A marker for an event that happened at some point in time
+///
+/// The timestamp uses the same format as the `ts` field in measurements,
+/// so that the two can be displayed alongside each other.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct Annotation {
+    pub ts: f64,
+    pub label: String,
+    pub profile: String,
+}
+
+fn js_timestamp_now() -> f64 {
+    let since_epoch = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default();
+
+    1000.0 * since_epoch.as_secs_f64()
+}
+
+impl AnnotationRequest {
+    fn into_annotation(self, current_profile: String) -> Option<Annotation> {
+        let label = self.label.trim();
+
+        if label.is_empty() || label.len() > LABEL_MAX_LEN {
+            return None;
+        }
+
+        let ts = match self.ts {
+            Some(ts) if ts.is_finite() && ts >= 0.0 => ts,
+            Some(_) => return None,
+            None => js_timestamp_now(),
+        };
+
+        Some(Annotation {
+            ts,
+            label: label.to_owned(),
+            profile: self.profile.unwrap_or(current_profile),
+        })
+    }
+}
+
+impl Annotation {
+    /// Write the annotation in the same `time_ms,channel,value` format that
+    /// is used for samples in recordings.
+    /// The label is quoted, as it may contain commas, quotes or newlines.
+    pub fn csv_line(&self) -> String {
+        format!(
+            "{:.3},/v1/annotations,\"{}\"\n",
+            self.ts,
+            self.label.replace('"', "\"\"")
+        )
+    }
+}
+
+#[derive(Clone)]
+pub struct Annotations {
+    /// The most recent annotations of all profiles, sorted by time
+    pub list: Arc<Topic<Vec<Annotation>>>,
+    profile: Arc<Topic<String>>,
+}
+
+impl Annotations {
+    /// The DUT profile that is currently selected
+    pub fn current_profile(&self) -> String {
+        self.profile.try_get().unwrap_or_default()
+    }
+}
+
+/// The annotations in `list` that are not in `prev` and belong to `profile`
+pub fn added<'a>(
+    prev: &'a [Annotation],
+    list: &'a [Annotation],
+    profile: &'a str,
+) -> impl Iterator<Item = &'a Annotation> {
+    list.iter()
+        .filter(move |a| a.profile == profile && !prev.contains(a))
+}
+
+/// Allow test scripts to mark events like "boot started" during long
+/// measurement captures
+///
+/// Annotations are kept in the same (in-memory) way as the measurement
+/// history and can be displayed next to it.
+pub fn run(
+    bb: &mut BrokerBuilder,
+    wtb: &mut WatchedTasksBuilder,
+    profile: Arc<Topic<String>>,
+) -> Result<Annotations> {
+    let request = bb.topic_wo::<AnnotationRequest>("/v1/annotations", None);
+    let list = bb.topic_ro::<Vec<Annotation>>("/v1/annotations", Some(Vec::new()));
+
+    let annotations = Annotations { list, profile };
+    let annotations_task = annotations.clone();
+
+    let (mut request_events, _) = request.subscribe_unbounded();
+
+    wtb.spawn_task("annotations-update", async move {
+        while let Some(req) = request_events.next().await {
+            let current_profile = annotations_task.current_profile();
+
+            let annotation = match req.into_annotation(current_profile) {
+                Some(annotation) => annotation,
+                None => {
+                    warn!("Ignoring annotation with an empty/too long label or invalid timestamp");
+                    continue;
+                }
+            };
+
+            annotations_task.list.modify(|prev| {
+                let mut list = prev.unwrap_or_default();
+
+                // Keep the list sorted by time, even if annotations with
+                // timestamps in the past are added.
+                let pos = list.partition_point(|a| a.ts <= annotation.ts);
+                list.insert(pos, annotation);
+
+                if list.len() > HISTORY_LENGTH {
+                    list.drain(..(list.len() - HISTORY_LENGTH));
+                }
+
+                Some(list)
+            });
+        }
+
+        Ok(())
+    })?;
+
+    Ok(annotations)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{added, Annotation, AnnotationRequest};
+
+    fn annotation(ts: f64, label: &str, profile: &str) -> Annotation {
+        Annotation {
+            ts,
+            label: label.to_string(),
+            profile: profile.to_string(),
+        }
+    }
+
+    #[test]
+    fn profiles() {
+        let request = |profile: Option<&str>| AnnotationRequest {
+            ts: Some(1.0),
+            label: " boot ".to_string(),
+            profile: profile.map(str::to_string),
+        };
+
+        println!("The current profile is used unless one is requested");
+        assert_eq!(
+            request(None).into_annotation("board-a".to_string()),
+            Some(annotation(1.0, "boot", "board-a"))
+        );
+        assert_eq!(
+            request(Some("board-b")).into_annotation("board-a".to_string()),
+            Some(annotation(1.0, "boot", "board-b"))
+        );
+
+        println!("Only new annotations of the given profile are picked up");
+        let prev = vec![annotation(1.0, "boot", "a")];
+        let list = vec![
+            annotation(1.0, "boot", "a"),
+            annotation(2.0, "test", "a"),
+            annotation(3.0, "test", "b"),
+        ];
+        let new: Vec<&Annotation> = added(&prev, &list, "a").collect();
+        assert_eq!(new, vec![&list[1]]);
+    }
+
+    #[test]
+    fn csv_lines() {
+        let line = annotation(1234.5, "say \"hi\", then boot", "a").csv_line();
+        assert_eq!(
+            line,
+            "1234.500,/v1/annotations,\"say \"\"hi\"\", then boot\"\n"
+        );
+    }
+}
@@ -0,0 +1,182 @@
+// This file is part of tacd, the LXA TAC system daemon
+// Copyright (C) 2024 Pengutronix e.K.
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along
+// with this program; if not, write to the Free Software Foundation, Inc.,
+// 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+
+//! Select between hardware and stub backends per subsystem at runtime
+//!
+//! This deliberately only covers the parts of the power board, which are
+//! the ADC and the DUT power switching, as well as the ADC in the STM32
+//! co-processor, which is set up together with the power board ADC.
+//! With those stubbed the USB, network and other functions of a TAC with a
+//! damaged power board keep working.
+//!
+//! The other subsystems are not runtime-selectable and keep selecting their
+//! demo implementations at compile time via the `demo_mode` feature.
+//! Their hardware is part of the base board or the SoC, which also provide
+//! the USB and network functions that stubbing is meant to keep alive.
+//! Config entries for them are rejected instead of being silently ignored.
+
+use std::fs::read_to_string;
+use std::io::ErrorKind;
+
+use anyhow::{Error, Result};
+use async_std::sync::Arc;
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::broker::{BrokerBuilder, Topic};
+use crate::fs_root;
+
+const BACKENDS_PATH: &str = "/etc/tacd/backends.yaml";
+
+/// How a subsystem accesses the hardware
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum Backend {
+    /// Use the hardware (or the simulation in demo mode)
+    #[default]
+    Hardware,
+    /// Do not access the hardware and never provide any values
+    Stub,
+}
+
+/// The backends to use for the individual subsystems
+///
+/// This allows running the tacd on a TAC with a damaged part (e.g. the
+/// power board) by stubbing out the subsystem that uses it, while keeping
+/// the rest functional.
+/// The backends are read from a config file at startup, subsystems not
+/// mentioned in it use the hardware.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Backends {
+    /// The ADC in the STM32 co-processor (USB, IOBus and OUT_0/1 feedback)
+    pub adc_stm32: Backend,
+    /// The ADC on the power board (DUT power feedback).
+    /// With this stubbed the DUT power output stays off instead of turning
+    /// on without overcurrent protection.
+    pub adc_powerboard: Backend,
+    /// The DUT power switch on the power board.
+    /// With this stubbed the output stays off and its GPIO lines are not
+    /// requested.
+    pub dut_power: Backend,
+}
+
+/// A subsystem that uses the stub backend because its hardware could not
+/// be set up
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct Degradation {
+    pub subsystem: String,
+    pub reason: String,
+}
+
+/// Keep track of subsystems that fell back to their stub backend
+///
+/// Instead of failing to start e.g. if the power board is missing, the
+/// tacd keeps serving the rest of the API, to allow debugging the issue.
+#[derive(Clone)]
+pub struct Degraded {
+    pub subsystems: Arc<Topic<Vec<Degradation>>>,
+}
+
+impl Degraded {
+    pub fn new(bb: &mut BrokerBuilder) -> Self {
+        Self {
+            subsystems: bb.topic_ro("/v1/tac/daemon/degraded", Some(Vec::new())),
+        }
+    }
+
+    /// Record that a subsystem runs without its hardware
+    pub fn report(&self, subsystem: &str, reason: &Error) {
+        error!("Running without {subsystem}: {reason:#}");
+
+        self.subsystems.modify(|prev| {
+            let mut subsystems = prev.unwrap_or_default();
+
+            subsystems.push(Degradation {
+                subsystem: subsystem.to_owned(),
+                reason: format!("{reason:#}"),
+            });
+
+            Some(subsystems)
+        });
+    }
+}
+
+impl Backends {
+    fn parse(content: &str) -> Result<Self> {
+        // A file that only contains comments is an empty document (None)
+        let backends: Option<Self> = serde_yaml::from_str(content)?;
+
+        Ok(backends.unwrap_or_default())
+    }
+
+    /// Read the backend config file and publish the result
+    ///
+    /// A missing or broken config file results in all subsystems using the
+    /// hardware, as that is what the tacd would do without this mechanism.
+    pub fn load(bb: &mut BrokerBuilder) -> Self {
+        let path = fs_root::path(BACKENDS_PATH);
+
+        let backends = match read_to_string(&path) {
+            Ok(content) => Self::parse(&content).unwrap_or_else(|e| {
+                let path = path.display();
+                warn!("Failed to parse {path}, using the hardware everywhere: {e}");
+                Self::default()
+            }),
+            Err(e) if e.kind() == ErrorKind::NotFound => Self::default(),
+            Err(e) => {
+                let path = path.display();
+                warn!("Failed to read {path}, using the hardware everywhere: {e}");
+                Self::default()
+            }
+        };
+
+        if backends != Self::default() {
+            info!("Using non-default subsystem backends: {backends:?}");
+        }
+
+        bb.topic_ro("/v1/tac/daemon/backends", Some(backends.clone()));
+
+        backends
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Backend, Backends};
+
+    #[test]
+    fn parse() {
+        println!("An empty config uses the hardware everywhere");
+        assert_eq!(Backends::parse("{}").unwrap(), Backends::default());
+        assert_eq!(Backends::parse("# Nothing\n").unwrap(), Backends::default());
+
+        println!("Single subsystems can be stubbed");
+        let backends = Backends::parse("adc_powerboard: Stub\n").unwrap();
+        assert_eq!(backends.adc_powerboard, Backend::Stub);
+        assert_eq!(backends.adc_stm32, Backend::Hardware);
+        assert_eq!(backends.dut_power, Backend::Hardware);
+
+        println!("All parts of the power board can be stubbed");
+        let backends = Backends::parse("adc_powerboard: Stub\ndut_power: Stub\n").unwrap();
+        assert_eq!(backends.adc_powerboard, Backend::Stub);
+        assert_eq!(backends.dut_power, Backend::Stub);
+
+        println!("Typos in subsystem names are not silently ignored");
+        assert!(Backends::parse("adc_powreboard: Stub\n").is_err());
+        assert!(Backends::parse("adc_stm32: Broken\n").is_err());
+    }
+}
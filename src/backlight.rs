@@ -15,38 +15,233 @@
 // with this program; if not, write to the Free Software Foundation, Inc.,
 // 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
 
+//! Control the display backlight and dim it when the TAC is not in use
+//!
+//! Once no button was pressed for `dim_timeout` seconds the display is
+//! dimmed to `dim_brightness` and the screensaver is shown.
+//! After another `off_timeout` seconds the backlight is turned off.
+//! Pressing any button wakes the display up again.
+//! A timeout of zero disables the respective step.
+
+use std::time::{Duration, Instant};
+
 use anyhow::Result;
+use async_std::future::timeout;
 use async_std::prelude::*;
 use async_std::sync::Arc;
+use futures::stream::select;
 use log::warn;
+use serde::{Deserialize, Serialize};
 
 #[cfg(feature = "demo_mode")]
 mod demo_mode;
 
 #[cfg(feature = "demo_mode")]
-use demo_mode::{Backlight as SysBacklight, Brightness, SysClass};
+use demo_mode::{sys_class, Backlight as SysBacklight, Brightness};
 
 #[cfg(not(feature = "demo_mode"))]
-use sysfs_class::{Backlight as SysBacklight, Brightness, SysClass};
+use crate::fs_root::sys_class;
+#[cfg(not(feature = "demo_mode"))]
+use sysfs_class::{Backlight as SysBacklight, Brightness};
 
 use crate::broker::{BrokerBuilder, Topic};
 use crate::watched_tasks::WatchedTasksBuilder;
 
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub enum IdleState {
+    Active,
+    Dimmed,
+    Off,
+}
+
+/// Things that affect the idle state other than timeouts
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+enum IdleEvent {
+    /// Someone uses the TAC, e.g. pressed a button
+    Activity,
+    /// Dim right away, e.g. because the screensaver was activated
+    Dim,
+    /// The timeouts were changed
+    Reconfigured,
+}
+
+impl IdleState {
+    /// Time to stay in this state before moving on to the next one (if any)
+    fn timeout(&self, dim_timeout: u64, off_timeout: u64) -> Option<Duration> {
+        let secs = match self {
+            Self::Active => dim_timeout,
+            Self::Dimmed => off_timeout,
+            Self::Off => 0,
+        };
+
+        (secs != 0).then(|| Duration::from_secs(secs))
+    }
+
+    fn next(&self) -> Self {
+        match self {
+            Self::Active => Self::Dimmed,
+            Self::Dimmed | Self::Off => Self::Off,
+        }
+    }
+
+    /// The backlight brightness to use in this state
+    fn brightness(&self, brightness: f32, dim_brightness: f32) -> f32 {
+        match self {
+            Self::Active => brightness,
+            Self::Dimmed => brightness.min(dim_brightness),
+            Self::Off => 0.0,
+        }
+    }
+}
+
+#[derive(Clone)]
 pub struct Backlight {
     pub brightness: Arc<Topic<f32>>,
+    pub dim_timeout: Arc<Topic<u64>>,
+    pub dim_brightness: Arc<Topic<f32>>,
+    pub off_timeout: Arc<Topic<u64>>,
+    pub idle: Arc<Topic<IdleState>>,
+    idle_events: Arc<Topic<IdleEvent>>,
 }
 
 impl Backlight {
     pub fn new(bb: &mut BrokerBuilder, wtb: &mut WatchedTasksBuilder) -> Result<Self> {
         let brightness = bb.topic_rw("/v1/tac/display/backlight/brightness", Some(1.0));
+        let dim_timeout = bb.topic(
+            "/v1/tac/display/backlight/idle/dim_timeout",
+            true,
+            true,
+            true,
+            Some(600),
+            1,
+        );
+        let dim_brightness = bb.topic(
+            "/v1/tac/display/backlight/idle/dim_brightness",
+            true,
+            true,
+            true,
+            Some(0.1),
+            1,
+        );
+        let off_timeout = bb.topic(
+            "/v1/tac/display/backlight/idle/off_timeout",
+            true,
+            true,
+            true,
+            Some(0),
+            1,
+        );
+        let idle = bb.topic_ro(
+            "/v1/tac/display/backlight/idle/state",
+            Some(IdleState::Active),
+        );
+        let idle_events = Topic::anonymous(None);
+
+        let this = Self {
+            brightness,
+            dim_timeout,
+            dim_brightness,
+            off_timeout,
+            idle,
+            idle_events,
+        };
+
+        this.handle_idle(wtb)?;
+        this.handle_brightness(wtb)?;
+
+        Ok(this)
+    }
+
+    /// Reset the idle timeout and wake the display up if it was dimmed or off
+    pub fn wake(&self) {
+        self.idle_events.set(IdleEvent::Activity);
+    }
+
+    /// Dim the display now instead of waiting for the timeout
+    pub fn dim(&self) {
+        self.idle_events.set(IdleEvent::Dim);
+    }
 
-        let (mut rx, _) = brightness.clone().subscribe_unbounded();
+    /// Move through the idle states based on the timeouts and idle events
+    fn handle_idle(&self, wtb: &mut WatchedTasksBuilder) -> Result<()> {
+        let (events, _) = self.idle_events.clone().subscribe_unbounded();
+        let (dim_timeout_events, _) = self.dim_timeout.clone().subscribe_unbounded();
+        let (off_timeout_events, _) = self.off_timeout.clone().subscribe_unbounded();
 
-        let backlight = SysBacklight::new("backlight")?;
+        let mut events = select(
+            events,
+            select(dim_timeout_events, off_timeout_events).map(|_| IdleEvent::Reconfigured),
+        );
+
+        let dim_timeout = self.dim_timeout.clone();
+        let off_timeout = self.off_timeout.clone();
+        let idle = self.idle.clone();
+
+        wtb.spawn_task("backlight-idle", async move {
+            let mut state = IdleState::Active;
+            let mut since = Instant::now();
+
+            loop {
+                idle.set_if_changed(state);
+
+                let state_timeout = state.timeout(
+                    dim_timeout.try_get().unwrap_or(0),
+                    off_timeout.try_get().unwrap_or(0),
+                );
+
+                let ev = match state_timeout {
+                    Some(t) => timeout(t.saturating_sub(since.elapsed()), events.next()).await,
+                    None => Ok(events.next().await),
+                };
+
+                match ev {
+                    Ok(Some(IdleEvent::Activity)) => {
+                        state = IdleState::Active;
+                        since = Instant::now();
+                    }
+                    Ok(Some(IdleEvent::Dim)) if state == IdleState::Active => {
+                        state = IdleState::Dimmed;
+                        since = Instant::now();
+                    }
+                    Ok(Some(_)) => {}
+                    Ok(None) => break,
+                    Err(_) => {
+                        state = state.next();
+                        since = Instant::now();
+                    }
+                }
+            }
+
+            Ok(())
+        })
+    }
+
+    /// Set the backlight brightness based on the requested brightness
+    /// and the idle state
+    fn handle_brightness(&self, wtb: &mut WatchedTasksBuilder) -> Result<()> {
+        let (brightness_events, _) = self.brightness.clone().subscribe_unbounded();
+        let (dim_brightness_events, _) = self.dim_brightness.clone().subscribe_unbounded();
+        let (idle_events, _) = self.idle.clone().subscribe_unbounded();
+
+        let mut rx = select(
+            select(brightness_events, dim_brightness_events).map(|_| ()),
+            idle_events.map(|_| ()),
+        );
+
+        let brightness = self.brightness.clone();
+        let dim_brightness = self.dim_brightness.clone();
+        let idle = self.idle.clone();
+
+        let backlight: SysBacklight = sys_class("backlight")?;
         let max_brightness = backlight.max_brightness()?;
 
         wtb.spawn_task("backlight-dimmer", async move {
-            while let Some(fraction) = rx.next().await {
+            while rx.next().await.is_some() {
+                let fraction = idle.try_get().unwrap_or(IdleState::Active).brightness(
+                    brightness.try_get().unwrap_or(1.0),
+                    dim_brightness.try_get().unwrap_or(1.0),
+                );
+
                 let brightness = (max_brightness as f32) * fraction;
                 let mut brightness = brightness.clamp(0.0, max_brightness as f32) as u64;
 
@@ -63,8 +258,39 @@ impl Backlight {
             }
 
             Ok(())
-        })?;
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::IdleState;
+
+    #[test]
+    fn idle_states() {
+        println!("The display is dimmed and turned off after the timeouts");
+        assert_eq!(
+            IdleState::Active.timeout(600, 60),
+            Some(Duration::from_secs(600))
+        );
+        assert_eq!(IdleState::Active.next(), IdleState::Dimmed);
+        assert_eq!(
+            IdleState::Dimmed.timeout(600, 60),
+            Some(Duration::from_secs(60))
+        );
+        assert_eq!(IdleState::Dimmed.next(), IdleState::Off);
+        assert_eq!(IdleState::Off.timeout(600, 60), None);
+
+        println!("A timeout of zero disables the step");
+        assert_eq!(IdleState::Active.timeout(0, 60), None);
+        assert_eq!(IdleState::Dimmed.timeout(600, 0), None);
 
-        Ok(Self { brightness })
+        println!("Dimming does not make the display brighter");
+        assert_eq!(IdleState::Active.brightness(0.8, 0.1), 0.8);
+        assert_eq!(IdleState::Dimmed.brightness(0.8, 0.1), 0.1);
+        assert_eq!(IdleState::Dimmed.brightness(0.05, 0.1), 0.05);
+        assert_eq!(IdleState::Off.brightness(0.8, 0.1), 0.0);
     }
 }
@@ -0,0 +1,213 @@
+// This file is part of tacd, the LXA TAC system daemon
+// Copyright (C) 2024 Pengutronix e.K.
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along
+// with this program; if not, write to the Free Software Foundation, Inc.,
+// 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+
+//! Announce the state of the TAC via UDP broadcasts
+//!
+//! Wallboards that show dozens of TACs at once would otherwise have to poll
+//! every one of them via HTTP. Instead they can passively listen for the
+//! beacons, which are sent as broadcasts once enabled.
+//!
+//! A beacon is a compact JSON object. If a key is placed in `KEY_PATH` it is
+//! followed by a newline and the hex encoded HMAC-SHA256 of the JSON object
+//! using that key, so that listeners can tell real beacons from spoofed ones.
+
+use std::fs::read_to_string;
+use std::net::Ipv4Addr;
+use std::time::{Duration, SystemTime};
+
+use anyhow::Result;
+use async_std::net::UdpSocket;
+use async_std::sync::Arc;
+use async_std::task::sleep;
+use hmac::{Hmac, Mac};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use crate::broker::{BrokerBuilder, Topic};
+use crate::dut_power::OutputState;
+use crate::fs_root;
+use crate::status_page::{Status, StatusTopics};
+use crate::watched_tasks::WatchedTasksBuilder;
+
+const KEY_PATH: &str = "/etc/tacd/beacon-key";
+
+const DEFAULT_INTERVAL_S: u64 = 10;
+const DEFAULT_PORT: u16 = 47474;
+
+/// Hex encoded HMAC-SHA256 of `msg` using `key`
+fn sign(key: &[u8], msg: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any length");
+    mac.update(msg);
+
+    format!("{:x}", mac.finalize().into_bytes())
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct Beacon {
+    pub hostname: Option<String>,
+    pub version: Option<String>,
+    pub dut_pwr: Option<OutputState>,
+    pub alarms: Vec<String>,
+    /// Seconds since Unix Epoch 0 at which the beacon was sent
+    pub ts: u64,
+}
+
+impl Beacon {
+    fn new(status: Status, ts: u64) -> Self {
+        Self {
+            hostname: status.hostname,
+            version: status.tacd_version,
+            dut_pwr: status.dut_pwr,
+            alarms: status.alarms,
+            ts,
+        }
+    }
+
+    /// Serialize the beacon and sign it using `key` (if any)
+    fn encode(&self, key: Option<&str>) -> Result<Vec<u8>> {
+        let mut datagram = serde_json::to_vec(self)?;
+
+        if let Some(key) = key {
+            let signature = sign(key.as_bytes(), &datagram);
+
+            datagram.push(b'\n');
+            datagram.extend_from_slice(signature.as_bytes());
+        }
+
+        Ok(datagram)
+    }
+}
+
+/// Read the signing key. Beacons are sent unsigned if there is none.
+fn read_key() -> Option<String> {
+    read_to_string(fs_root::path(KEY_PATH))
+        .ok()
+        .map(|key| key.trim().to_string())
+        .filter(|key| !key.is_empty())
+}
+
+async fn send(port: u16, datagram: &[u8]) -> std::io::Result<()> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    socket.set_broadcast(true)?;
+    socket
+        .send_to(datagram, (Ipv4Addr::BROADCAST, port))
+        .await?;
+
+    Ok(())
+}
+
+pub fn run(
+    bb: &mut BrokerBuilder,
+    wtb: &mut WatchedTasksBuilder,
+    topics: StatusTopics,
+) -> Result<()> {
+    let enabled: Arc<Topic<bool>> =
+        bb.topic("/v1/tac/beacon/enabled", true, true, true, Some(false), 1);
+    let interval: Arc<Topic<u64>> = bb.topic(
+        "/v1/tac/beacon/interval",
+        true,
+        true,
+        true,
+        Some(DEFAULT_INTERVAL_S),
+        1,
+    );
+    let port: Arc<Topic<u16>> = bb.topic(
+        "/v1/tac/beacon/port",
+        true,
+        true,
+        true,
+        Some(DEFAULT_PORT),
+        1,
+    );
+
+    wtb.spawn_task("beacon", async move {
+        // Only warn about the first of a series of failed sends
+        let mut failing = false;
+
+        loop {
+            let interval_s = interval.try_get().unwrap_or(DEFAULT_INTERVAL_S).max(1);
+            sleep(Duration::from_secs(interval_s)).await;
+
+            if !enabled.try_get().unwrap_or(false) {
+                continue;
+            }
+
+            let ts = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map_or(0, |ts| ts.as_secs());
+
+            let datagram = Beacon::new(topics.status(), ts).encode(read_key().as_deref())?;
+            let port = port.try_get().unwrap_or(DEFAULT_PORT);
+
+            match send(port, &datagram).await {
+                Ok(()) if failing => {
+                    info!("Sending beacons works again");
+                    failing = false;
+                }
+                Ok(()) => {}
+                Err(e) if !failing => {
+                    warn!("Failed to send beacon: {e}");
+                    failing = true;
+                }
+                Err(_) => {}
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{sign, Beacon};
+    use crate::dut_power::OutputState;
+
+    #[test]
+    fn signature() {
+        // RFC 4231 test case 2
+        assert_eq!(
+            sign(b"Jefe", b"what do ya want for nothing?"),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn encode() {
+        let beacon = Beacon {
+            hostname: Some("lxatac-00001".to_string()),
+            version: Some("1.0.0".to_string()),
+            dut_pwr: Some(OutputState::On),
+            alarms: vec!["The TAC is overheating".to_string()],
+            ts: 1700000000,
+        };
+
+        println!("Beacons without a key are plain JSON");
+        let unsigned = beacon.encode(None).unwrap();
+        let decoded: Beacon = serde_json::from_slice(&unsigned).unwrap();
+        assert_eq!(decoded, beacon);
+        assert!(!unsigned.contains(&b'\n'));
+
+        println!("Signed beacons carry the HMAC of the JSON in the second line");
+        let signed = beacon.encode(Some("secret")).unwrap();
+        let (json, signature) = signed.split_at(unsigned.len());
+
+        assert_eq!(json, &unsigned[..]);
+        assert_eq!(
+            signature,
+            format!("\n{}", sign(b"secret", &unsigned)).as_bytes()
+        );
+    }
+}
@@ -0,0 +1,194 @@
+// This file is part of tacd, the LXA TAC system daemon
+// Copyright (C) 2024 Pengutronix e.K.
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along
+// with this program; if not, write to the Free Software Foundation, Inc.,
+// 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+
+//! Audible alerts via the buzzer fitted to some hardware revisions
+//!
+//! The buzzer is connected like an LED (via gpio-leds) and is driven using
+//! the same pattern trigger as the LEDs.
+//! On hardware without a buzzer the topics still exist but have no effect.
+
+use std::time::Duration;
+
+use anyhow::Result;
+use async_std::sync::Arc;
+use futures::{select, FutureExt, StreamExt};
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::broker::{BrokerBuilder, Topic};
+use crate::dut_power::OutputState;
+use crate::led::{pattern_output, BlinkPattern, BlinkPatternBuilder};
+use crate::temperatures::Warning;
+use crate::watched_tasks::WatchedTasksBuilder;
+
+const HARDWARE_NAME: &str = "tac:buzzer";
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum BeepPattern {
+    Silent,
+    /// A single short beep, e.g. as acknowledgement
+    Short,
+    /// Two short beeps
+    Double,
+    /// A single one second long beep
+    Long,
+    /// Beep repeatedly until a different pattern is set
+    Alarm,
+}
+
+impl BeepPattern {
+    fn blink_pattern(&self) -> BlinkPattern {
+        let ms = Duration::from_millis;
+        let beep = |len| BlinkPatternBuilder::new(1.0).stay_for(len).step_to(0.0);
+
+        match self {
+            Self::Silent => BlinkPattern::solid(0.0),
+            Self::Short => beep(ms(100)).stay_for(ms(100)).once(),
+            Self::Double => beep(ms(100)).stay_for(ms(100)).repeat(2),
+            Self::Long => beep(ms(1000)).stay_for(ms(100)).once(),
+            Self::Alarm => beep(ms(250)).stay_for(ms(250)).forever(),
+        }
+    }
+}
+
+/// Does the TAC state warrant an audible alert?
+fn is_critical(dut_pwr: Option<&OutputState>, temperature: Option<&Warning>) -> bool {
+    matches!(dut_pwr, Some(OutputState::OverCurrent))
+        || matches!(temperature, Some(Warning::SocCritical))
+}
+
+/// Sound the alarm while the TAC is in a critical state (if enabled)
+fn handle_alerts(
+    wtb: &mut WatchedTasksBuilder,
+    pattern: Arc<Topic<BeepPattern>>,
+    alerts: Arc<Topic<bool>>,
+    dut_pwr_state: Arc<Topic<OutputState>>,
+    temperature_warning: Arc<Topic<Warning>>,
+) -> Result<()> {
+    let (mut dut_pwr_events, _) = dut_pwr_state.clone().subscribe_unbounded();
+    let (mut temperature_events, _) = temperature_warning.clone().subscribe_unbounded();
+
+    wtb.spawn_task("beeper-alerts", async move {
+        let mut was_critical = false;
+
+        loop {
+            select! {
+                ev = dut_pwr_events.next().fuse() => if ev.is_none() { break },
+                ev = temperature_events.next().fuse() => if ev.is_none() { break },
+            }
+
+            let critical = is_critical(
+                dut_pwr_state.try_get().as_ref(),
+                temperature_warning.try_get().as_ref(),
+            );
+
+            if critical == was_critical {
+                continue;
+            }
+
+            was_critical = critical;
+
+            if critical && alerts.try_get().unwrap_or(false) {
+                pattern.set(BeepPattern::Alarm);
+            }
+
+            // Only end alarms we started and not e.g. one requested via the API
+            if !critical && pattern.try_get() == Some(BeepPattern::Alarm) {
+                pattern.set(BeepPattern::Silent);
+            }
+        }
+
+        Ok(())
+    })
+}
+
+pub fn run(
+    bb: &mut BrokerBuilder,
+    wtb: &mut WatchedTasksBuilder,
+    dut_pwr_state: Arc<Topic<OutputState>>,
+    temperature_warning: Arc<Topic<Warning>>,
+) -> Result<()> {
+    let pattern = bb.topic_rw("/v1/tac/beeper/pattern", Some(BeepPattern::Silent));
+    let alerts = bb.topic("/v1/tac/beeper/alerts", true, true, true, Some(false), 1);
+    let muted = bb.topic("/v1/tac/beeper/muted", true, true, true, Some(false), 1);
+
+    handle_alerts(
+        wtb,
+        pattern.clone(),
+        alerts,
+        dut_pwr_state,
+        temperature_warning,
+    )?;
+
+    let output = match pattern_output(HARDWARE_NAME) {
+        Some(output) => output,
+        None => return Ok(()),
+    };
+
+    let (mut pattern_events, _) = pattern.clone().subscribe_unbounded();
+    let (mut muted_events, _) = muted.clone().subscribe_unbounded();
+
+    wtb.spawn_task("beeper-update", async move {
+        loop {
+            select! {
+                ev = pattern_events.next().fuse() => if ev.is_none() { break },
+                ev = muted_events.next().fuse() => if ev.is_none() { break },
+            }
+
+            let beep = match muted.try_get().unwrap_or(false) {
+                true => BeepPattern::Silent,
+                false => pattern.try_get().unwrap_or(BeepPattern::Silent),
+            };
+
+            if let Err(e) = output(beep.blink_pattern()) {
+                warn!("Failed to set beeper pattern: {e}");
+            }
+        }
+
+        Ok(())
+    })?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_critical, BeepPattern};
+    use crate::dut_power::OutputState;
+    use crate::temperatures::Warning;
+
+    #[test]
+    fn patterns() {
+        assert!(BeepPattern::Silent.blink_pattern().is_off());
+        assert!(BeepPattern::Short.blink_pattern().is_blinking());
+        assert!(BeepPattern::Alarm.blink_pattern().is_blinking());
+    }
+
+    #[test]
+    fn critical() {
+        assert!(!is_critical(None, None));
+        assert!(!is_critical(
+            Some(&OutputState::On),
+            Some(&Warning::SocHigh)
+        ));
+        assert!(is_critical(Some(&OutputState::OverCurrent), None));
+        assert!(is_critical(
+            Some(&OutputState::Off),
+            Some(&Warning::SocCritical)
+        ));
+    }
+}
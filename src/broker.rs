@@ -15,27 +15,60 @@
 // with this program; if not, write to the Free Software Foundation, Inc.,
 // 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
 
+use std::collections::HashMap;
+
 use anyhow::Result;
 use async_std::sync::Arc;
 use serde::{de::DeserializeOwned, Serialize};
 
 use crate::watched_tasks::WatchedTasksBuilder;
 
+mod bridge;
+mod expiry;
+mod link;
 mod mqtt_conn;
 mod persistence;
+mod queue_stats;
+mod registry;
 mod rest;
+mod scratch;
 mod topic;
+mod transaction;
 
+pub use link::TopicLink;
 pub use mqtt_conn::TopicName;
+pub use persistence::StateRecovery;
+pub use registry::{TopicInfo, TopicMeta};
 pub use topic::{AnySubscriptionHandle, AnyTopic, Native, SubscriptionHandle, Topic};
 
 pub struct BrokerBuilder {
     topics: Vec<Arc<dyn AnyTopic>>,
+    links: Vec<TopicLink>,
+    meta: HashMap<String, TopicMeta>,
+    state_recovery: Arc<Topic<Option<StateRecovery>>>,
 }
 
 impl BrokerBuilder {
     pub fn new() -> Self {
-        Self { topics: Vec::new() }
+        // The persistent topics are only loaded in build(), but users of the
+        // recovery information (like the UI) are set up before that.
+        // The topic is only registered in build() as well.
+        // Writing null to the topic dismisses the notification.
+        let state_recovery = Arc::new(Topic::new(
+            "/v1/tac/config/recovery",
+            true,
+            true,
+            false,
+            Some(None),
+            1,
+        ));
+
+        Self {
+            topics: Vec::new(),
+            links: Vec::new(),
+            meta: HashMap::new(),
+            state_recovery,
+        }
     }
 
     /// Register a new topic
@@ -113,15 +146,97 @@ impl BrokerBuilder {
         self.topic(path, false, true, false, initial, 1)
     }
 
+    /// Forward every value set on one topic to another topic
+    ///
+    /// The value is converted using the `map` function on the way.
+    /// Values for which `map` returns None are not forwarded.
+    /// All links are listed in the `/v1/tac/daemon/topic_links` topic,
+    /// so that the relations between topics can be inspected from the
+    /// outside.
+    ///
+    /// Every link is forwarded by its own task, so there is no order
+    /// between two links from the same source. Destinations that have to
+    /// change together (like the pattern and color of an LED) should be
+    /// set from a single task instead.
+    pub fn link<S, D, F>(
+        &mut self,
+        wtb: &mut WatchedTasksBuilder,
+        from: &Arc<Topic<S>>,
+        to: &Arc<Topic<D>>,
+        map: F,
+    ) -> Result<()>
+    where
+        S: Serialize + DeserializeOwned + Send + Sync + Clone + 'static,
+        D: Serialize + DeserializeOwned + Send + Sync + Clone + 'static,
+        F: Fn(S) -> Option<D> + Send + Sync + 'static,
+    {
+        let link = link::spawn(wtb, from.clone(), to.clone(), map)?;
+
+        self.links.push(link);
+
+        Ok(())
+    }
+
+    /// Attach human readable metadata (like a description and unit) to a topic
+    ///
+    /// The metadata of all topics is listed in the `/v1/topics` topic, so
+    /// that generic clients can label values without knowing about them.
+    /// Metadata is stored per path, so a read only and a write only topic
+    /// with the same path share it.
+    pub fn describe<E: Serialize + DeserializeOwned + Sync + Send + Clone + 'static>(
+        &mut self,
+        topic: &Arc<Topic<E>>,
+        meta: TopicMeta,
+    ) {
+        self.meta.insert(topic.path().to_string(), meta);
+    }
+
+    /// Settings that could not be restored from a damaged state file
+    ///
+    /// Set once the persistent topics were loaded in build().
+    pub fn state_recovery(&self) -> Arc<Topic<Option<StateRecovery>>> {
+        self.state_recovery.clone()
+    }
+
+    /// List the externally accessible topics registered so far
+    fn registry(&self) -> Vec<TopicInfo> {
+        registry::list(&self.topics, &self.meta)
+    }
+
     /// Finish building the broker
     ///
     /// This consumes the builder so that no new topics can be registered
-    pub fn build(self, wtb: &mut WatchedTasksBuilder, server: &mut tide::Server<()>) -> Result<()> {
+    pub fn build(
+        mut self,
+        wtb: &mut WatchedTasksBuilder,
+        server: &mut tide::Server<()>,
+    ) -> Result<()> {
+        let mqtt_stats = self.topic_ro(
+            "/v1/tac/daemon/websocket_connections",
+            Some(mqtt_conn::ConnectionStats::default()),
+        );
+        let queue_stats = queue_stats::QueueStats::new(&mut self, wtb)?;
+
+        let bridge = bridge::Bridge::new(&mut self, wtb)?;
+
+        self.topics.push(self.state_recovery.clone());
+
+        let links = std::mem::take(&mut self.links);
+        self.topic_ro("/v1/tac/daemon/topic_links", Some(links));
+
+        // The registry should list itself, so register it before filling it
+        let registry = self.topic_ro("/v1/topics", None);
+        registry.set(self.registry());
+
         let topics = Arc::new(self.topics);
 
-        persistence::register(wtb, topics.clone())?;
+        persistence::register(wtb, topics.clone(), &self.state_recovery)?;
+        persistence::serve(server, topics.clone());
+        expiry::register(wtb, &topics, &self.meta)?;
         rest::register(server, topics.clone());
-        mqtt_conn::register(server, topics);
+        transaction::register(server, topics.clone());
+        mqtt_conn::register(server, topics.clone(), mqtt_stats, queue_stats);
+        bridge.run(wtb, topics)?;
 
         Ok(())
     }
@@ -0,0 +1,704 @@
+// This file is part of tacd, the LXA TAC system daemon
+// Copyright (C) 2024 Pengutronix e.K.
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along
+// with this program; if not, write to the Free Software Foundation, Inc.,
+// 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+
+//! Mirror topics to and from an external MQTT broker
+//!
+//! The MQTT over websocket endpoint only allows clients to connect to the
+//! TAC. The bridge is a (minimal) MQTT 3.1.1 client that connects to a
+//! broker in the lab instead, so that e.g. dashboards can follow the state
+//! of many TACs without having to poll each of them.
+//!
+//! Topics are published with their tacd path, prefixed by a configurable
+//! string, e.g. `lab/tac-1/v1/dut/powered` for the prefix `lab/tac-1`.
+
+use std::collections::HashMap;
+use std::fs::{rename, File, OpenOptions};
+use std::io::{Cursor, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail, Result};
+use async_std::channel::bounded;
+use async_std::future::timeout;
+use async_std::net::TcpStream;
+use async_std::sync::Arc;
+use async_std::task::sleep;
+use async_tls::TlsConnector;
+use futures::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use futures_lite::future::race;
+use futures_util::{FutureExt, StreamExt};
+use log::{info, warn};
+use mqtt::control::variable_header::ConnectReturnCode;
+use mqtt::{packet::*, Decodable, Encodable, QualityOfService, TopicFilter};
+use serde::{Deserialize, Serialize};
+use serde_json::{from_reader, to_vec_pretty};
+
+use super::{AnyTopic, BrokerBuilder, Topic, TopicName};
+use crate::fs_root;
+use crate::watched_tasks::WatchedTasksBuilder;
+
+const CONFIG_PATH: &str = "/srv/tacd/mqtt_bridge.json";
+
+/// Give up on connection attempts (including the TLS and MQTT handshake)
+/// that take longer than this.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Send a PINGREQ to the broker this often and consider the connection
+/// dead if we did not hear back for twice as long.
+const KEEP_ALIVE: Duration = Duration::from_secs(30);
+
+/// Wait this long before re-connecting after the connection was lost
+const RETRY_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Drop the connection if the broker can not keep up with our updates,
+/// just like the websocket connections do.
+const MAX_QUEUE_LENGTH: usize = 4096;
+
+/// The tacd does not have any topics this large. Larger packets are most
+/// likely garbage.
+const MAX_PACKET_SIZE: usize = 1024 * 1024;
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct BridgeConfig {
+    pub enabled: bool,
+    pub host: String,
+    pub port: u16,
+    pub tls: bool,
+    pub client_id: String,
+    pub username: Option<String>,
+    /// Only stored on the TAC and never sent out via the API.
+    /// Sending `None` keeps the stored password (as long as the broker and
+    /// user stay the same), an empty string clears it.
+    pub password: Option<String>,
+    /// Put in front of the tacd topic paths on the external broker
+    pub prefix: String,
+    /// Filters (MQTT wildcards are allowed) for the topics to publish
+    pub publish: Vec<String>,
+    /// Filters for the topics that may be set via the external broker
+    pub subscribe: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub enum BridgeStatus {
+    Disabled,
+    Connecting,
+    Connected,
+    Error(String),
+}
+
+impl Default for BridgeConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            host: String::new(),
+            port: 1883,
+            tls: false,
+            client_id: "tacd".to_string(),
+            username: None,
+            password: None,
+            prefix: String::new(),
+            publish: Vec::new(),
+            subscribe: Vec::new(),
+        }
+    }
+}
+
+impl BridgeConfig {
+    fn load(path: &Path) -> Result<Self> {
+        if !path.is_file() {
+            return Ok(Self::default());
+        }
+
+        Ok(from_reader(File::open(path)?)?)
+    }
+
+    /// Resolve the password of a config requested via the API
+    ///
+    /// The stored password is only kept if the request connects to the
+    /// same broker, in the same way, as the same user. Otherwise any web
+    /// client could point the bridge at their own broker and receive the
+    /// stored credentials.
+    fn resolve_password(&mut self, current: &Self) {
+        let same_endpoint = self.host == current.host
+            && self.port == current.port
+            && self.tls == current.tls
+            && self.username == current.username;
+
+        self.password = match self.password.take() {
+            None if same_endpoint => current.password.clone(),
+            None => {
+                if current.password.is_some() {
+                    info!("MQTT bridge endpoint changed, dropping the stored password");
+                }
+
+                None
+            }
+            Some(pw) if pw.is_empty() => None,
+            Some(pw) => Some(pw),
+        };
+    }
+
+    /// Store the config in a file only we can read, as it contains the password
+    fn save(&self, path: &Path) -> Result<()> {
+        let path_tmp = path.with_extension("tmp");
+
+        {
+            let mut fd = OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .mode(0o600)
+                .open(&path_tmp)?;
+
+            fd.write_all(&to_vec_pretty(self)?)?;
+            fd.sync_all()?;
+        }
+
+        rename(path_tmp, path)?;
+
+        Ok(())
+    }
+
+    /// The config as it is shown via the API
+    fn redacted(&self) -> Self {
+        Self {
+            password: None,
+            ..self.clone()
+        }
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.enabled && self.host.is_empty() {
+            bail!("A host is required to enable the bridge");
+        }
+
+        if self.client_id.is_empty() {
+            bail!("The client id must not be empty");
+        }
+
+        if self.prefix.contains(['+', '#']) || self.prefix.ends_with('/') {
+            bail!("The prefix must not contain wildcards or end in a slash");
+        }
+
+        for filter in self.publish.iter().chain(self.subscribe.iter()) {
+            TopicFilter::new(filter.as_str())
+                .map_err(|_| anyhow!("\"{filter}\" is not a valid topic filter"))?;
+        }
+
+        Ok(())
+    }
+
+    fn filters(filters: &[String]) -> Result<Vec<TopicFilter>> {
+        filters
+            .iter()
+            .map(|f| TopicFilter::new(f.as_str()).map_err(|e| anyhow!("{e}")))
+            .collect()
+    }
+}
+
+pub(super) struct Bridge {
+    config: Arc<Topic<BridgeConfig>>,
+    status: Arc<Topic<BridgeStatus>>,
+}
+
+/// Read a complete MQTT packet from a byte stream
+///
+/// Unlike the websocket connections there is nothing that aligns packets
+/// with messages, so the remaining length field has to be decoded first.
+async fn read_packet<R: AsyncRead + Unpin>(rx: &mut R) -> Result<VariablePacket> {
+    let mut buf = vec![0; 1];
+    rx.read_exact(&mut buf).await?;
+
+    // The remaining length is encoded in up to four bytes with seven
+    // bits of length per byte. The top bit signals that more bytes follow.
+    let mut len = 0;
+
+    for i in 0..4 {
+        let mut byte = [0];
+        rx.read_exact(&mut byte).await?;
+        buf.push(byte[0]);
+
+        len |= ((byte[0] & 0x7f) as usize) << (7 * i);
+
+        if byte[0] & 0x80 == 0 {
+            break;
+        }
+
+        if i == 3 {
+            bail!("Malformed remaining length in MQTT packet");
+        }
+    }
+
+    if len > MAX_PACKET_SIZE {
+        bail!("MQTT packet of {len} bytes is too large");
+    }
+
+    let start = buf.len();
+    buf.resize(start + len, 0);
+    rx.read_exact(&mut buf[start..]).await?;
+
+    Ok(VariablePacket::decode(&mut Cursor::new(buf))?)
+}
+
+async fn write_packet<W: AsyncWrite + Unpin, P: Encodable>(tx: &mut W, pkg: &P) -> Result<()> {
+    let mut buf = Vec::new();
+    pkg.encode(&mut buf)?;
+
+    tx.write_all(&buf).await?;
+    tx.flush().await?;
+
+    Ok(())
+}
+
+/// Mirror topics via an established connection to the external broker
+async fn mirror<S: AsyncRead + AsyncWrite + Unpin>(
+    config: &BridgeConfig,
+    topics: &[Arc<dyn AnyTopic>],
+    status: &Topic<BridgeStatus>,
+    stream: S,
+) -> Result<()> {
+    let (mut rx, mut tx) = stream.split();
+
+    let mut connect = ConnectPacket::new(config.client_id.clone());
+    connect.set_clean_session(true);
+    connect.set_keep_alive(KEEP_ALIVE.as_secs() as u16);
+    connect.set_user_name(config.username.clone());
+    connect.set_password(config.password.clone());
+
+    write_packet(&mut tx, &connect).await?;
+
+    match timeout(CONNECT_TIMEOUT, read_packet(&mut rx)).await?? {
+        VariablePacket::ConnackPacket(ack)
+            if ack.connect_return_code() == ConnectReturnCode::ConnectionAccepted => {}
+        VariablePacket::ConnackPacket(ack) => {
+            bail!(
+                "The broker refused the connection: {:?}",
+                ack.connect_return_code()
+            )
+        }
+        _ => bail!("The broker did not acknowledge the connection"),
+    }
+
+    let publish_filters = BridgeConfig::filters(&config.publish)?;
+    let subscribe_filters = BridgeConfig::filters(&config.subscribe)?;
+
+    if !subscribe_filters.is_empty() {
+        let subscribes = config
+            .subscribe
+            .iter()
+            .map(|f| {
+                TopicFilter::new(format!("{}{f}", config.prefix))
+                    .map(|f| (f, QualityOfService::Level0))
+                    .map_err(|e| anyhow!("{e}"))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        write_packet(&mut tx, &SubscribePacket::new(1, subscribes)).await?;
+    }
+
+    status.set(BridgeStatus::Connected);
+
+    let (to_broker, mut for_broker) = bounded::<(TopicName, Arc<[u8]>)>(MAX_QUEUE_LENGTH);
+
+    let subscription_handles: Vec<_> = topics
+        .iter()
+        .filter(|topic| {
+            topic.web_readable()
+                && publish_filters
+                    .iter()
+                    .any(|f| f.get_matcher().is_match(topic.path()))
+        })
+        .map(|topic| topic.clone().subscribe_as_bytes(to_broker.clone(), true))
+        .collect();
+
+    // The last payload seen per path in either direction.
+    // A topic that is both published and subscribed to would otherwise
+    // bounce the same value between the TAC and the broker forever.
+    let last_payloads: Mutex<HashMap<String, Arc<[u8]>>> = Mutex::new(HashMap::new());
+
+    let from_broker = async {
+        loop {
+            let pkg = timeout(KEEP_ALIVE * 2, read_packet(&mut rx))
+                .await
+                .map_err(|_| anyhow!("The broker stopped responding"))??;
+
+            let pub_pkg = match pkg {
+                VariablePacket::PublishPacket(pub_pkg) => pub_pkg,
+                VariablePacket::SubackPacket(_) | VariablePacket::PingrespPacket(_) => continue,
+                _ => bail!("Unexpected packet from the broker"),
+            };
+
+            // Retained messages are sent right after subscribing and may be
+            // arbitrarily old. Only act on values that are set from now on.
+            if pub_pkg.retain() {
+                continue;
+            }
+
+            let path = match pub_pkg.topic_name().strip_prefix(config.prefix.as_str()) {
+                Some(path) => path,
+                None => continue,
+            };
+
+            let topic = topics.iter().find(|t| {
+                t.web_writable()
+                    && &t.path()[..] == path
+                    && subscribe_filters
+                        .iter()
+                        .any(|f| f.get_matcher().is_match(t.path()))
+            });
+
+            let topic = match topic {
+                Some(topic) => topic,
+                None => continue,
+            };
+
+            let payload: Arc<[u8]> = Arc::from(pub_pkg.payload());
+
+            {
+                let mut last_payloads = last_payloads.lock().unwrap();
+
+                if last_payloads.get(path) == Some(&payload) {
+                    continue;
+                }
+
+                last_payloads.insert(path.to_string(), payload.clone());
+            }
+
+            if let Err(e) = topic.set_from_bytes(&payload) {
+                warn!("Ignoring malformed value for {path} from the MQTT broker: {e}");
+            }
+        }
+    };
+
+    let to_broker_task = async {
+        let mut next_ping = Instant::now() + KEEP_ALIVE;
+
+        loop {
+            let ping_in = next_ping.saturating_duration_since(Instant::now());
+
+            let ev = race(for_broker.next().map(Some), sleep(ping_in).map(|_| None)).await;
+
+            match ev {
+                Some(Some((path, payload))) => {
+                    let path: &str = &path;
+
+                    {
+                        let mut last_payloads = last_payloads.lock().unwrap();
+
+                        if last_payloads.get(path) == Some(&payload) {
+                            continue;
+                        }
+
+                        last_payloads.insert(path.to_string(), payload.clone());
+                    }
+
+                    let name = TopicName::new(format!("{}{path}", config.prefix))?;
+                    let mut pkg =
+                        PublishPacket::new(name, QoSWithPacketIdentifier::Level0, payload.to_vec());
+
+                    // Let dashboards that connect later see the current state
+                    pkg.set_retain(true);
+
+                    write_packet(&mut tx, &pkg).await?;
+                }
+                Some(None) => {
+                    // The topics close the queue if it is full
+                    bail!("The broker does not keep up with the updates");
+                }
+                None => {
+                    write_packet(&mut tx, &PingreqPacket::new()).await?;
+                    next_ping = Instant::now() + KEEP_ALIVE;
+                }
+            }
+        }
+    };
+
+    let res = race(from_broker, to_broker_task).await;
+
+    for handle in subscription_handles {
+        handle.unsubscribe();
+    }
+
+    res
+}
+
+/// Connect to the external broker and mirror topics until an error occurs
+async fn session(
+    config: &BridgeConfig,
+    topics: &[Arc<dyn AnyTopic>],
+    status: &Topic<BridgeStatus>,
+) -> Result<()> {
+    let address = (config.host.as_str(), config.port);
+    let tcp = timeout(CONNECT_TIMEOUT, TcpStream::connect(address)).await??;
+
+    if config.tls {
+        let tls = timeout(
+            CONNECT_TIMEOUT,
+            TlsConnector::default().connect(&config.host, tcp),
+        )
+        .await??;
+
+        mirror(config, topics, status, tls).await
+    } else {
+        mirror(config, topics, status, tcp).await
+    }
+}
+
+impl Bridge {
+    pub(super) fn new(bb: &mut BrokerBuilder, wtb: &mut WatchedTasksBuilder) -> Result<Self> {
+        let initial = BridgeConfig::load(&fs_root::path(CONFIG_PATH)).unwrap_or_else(|e| {
+            warn!("Failed to load MQTT bridge config: {e}");
+            BridgeConfig::default()
+        });
+
+        // The config contains the password, so the full config is kept in an
+        // anonymous topic and only a redacted version is exposed.
+        let config_ro = bb.topic_ro("/v1/tac/mqtt_bridge/config", Some(initial.redacted()));
+        let requests = bb.topic_wo::<BridgeConfig>("/v1/tac/mqtt_bridge/config", None);
+        let status = bb.topic_ro("/v1/tac/mqtt_bridge/status", Some(BridgeStatus::Disabled));
+        let config = Topic::anonymous(Some(initial));
+
+        let config_task = config.clone();
+        let (mut requests, _) = requests.subscribe_unbounded();
+
+        wtb.spawn_task("mqtt-bridge-config", async move {
+            while let Some(mut req) = requests.next().await {
+                let current = config_task.try_get().unwrap_or_default();
+
+                req.resolve_password(&current);
+
+                if let Err(e) = req.validate() {
+                    warn!("Refusing invalid MQTT bridge config: {e}");
+                    continue;
+                }
+
+                if let Err(e) = req.save(&fs_root::path(CONFIG_PATH)) {
+                    warn!("Failed to save MQTT bridge config: {e}");
+                }
+
+                config_ro.set(req.redacted());
+                config_task.set(req);
+            }
+
+            Ok(())
+        })?;
+
+        Ok(Self { config, status })
+    }
+
+    /// Keep a connection to the external broker while the bridge is enabled
+    pub(super) fn run(
+        self,
+        wtb: &mut WatchedTasksBuilder,
+        topics: Arc<Vec<Arc<dyn AnyTopic>>>,
+    ) -> Result<()> {
+        let Self { config, status } = self;
+
+        let (mut config_events, _) = config.subscribe_unbounded();
+
+        wtb.spawn_task("mqtt-bridge", async move {
+            let mut config = match config_events.next().await {
+                Some(config) => config,
+                None => return Ok(()),
+            };
+
+            loop {
+                if !config.enabled {
+                    status.set(BridgeStatus::Disabled);
+
+                    config = match config_events.next().await {
+                        Some(config) => config,
+                        None => break,
+                    };
+
+                    continue;
+                }
+
+                status.set(BridgeStatus::Connecting);
+
+                // Re-connect with the new settings whenever the config changes
+                let ev = race(
+                    session(&config, &topics, &status).map(Err),
+                    config_events.next().map(Ok),
+                )
+                .await;
+
+                let err = match ev {
+                    Ok(Some(new_config)) => {
+                        config = new_config;
+                        continue;
+                    }
+                    Ok(None) => break,
+                    Err(Ok(())) => anyhow!("The connection was closed"),
+                    Err(Err(e)) => e,
+                };
+
+                info!("MQTT bridge connection to {} failed: {err}", config.host);
+                status.set(BridgeStatus::Error(err.to_string()));
+
+                let ev = race(
+                    sleep(RETRY_INTERVAL).map(|_| None),
+                    config_events.next().map(Some),
+                )
+                .await;
+
+                match ev {
+                    Some(Some(new_config)) => config = new_config,
+                    Some(None) => break,
+                    None => {}
+                }
+            }
+
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_std::task::block_on;
+    use futures::io::Cursor;
+    use mqtt::packet::*;
+    use mqtt::{Encodable, TopicName};
+
+    use super::{read_packet, BridgeConfig};
+
+    #[test]
+    fn framing() {
+        let mut buf = Vec::new();
+
+        let payloads = [vec![b'1'; 10], vec![b'2'; 300], vec![b'3'; 20_000]];
+
+        for payload in payloads.iter() {
+            let name = TopicName::new("lab/tac/v1/test").unwrap();
+            let pkg = PublishPacket::new(name, QoSWithPacketIdentifier::Level0, payload.clone());
+            pkg.encode(&mut buf).unwrap();
+        }
+
+        PingrespPacket::new().encode(&mut buf).unwrap();
+
+        let mut rx = Cursor::new(buf);
+
+        println!("Packets with one, two and three byte long length fields are split");
+        for payload in payloads.iter() {
+            match block_on(read_packet(&mut rx)).unwrap() {
+                VariablePacket::PublishPacket(pkg) => {
+                    assert_eq!(pkg.topic_name(), "lab/tac/v1/test");
+                    assert_eq!(pkg.payload(), &payload[..]);
+                }
+                _ => panic!("Expected a publish packet"),
+            }
+        }
+
+        assert!(matches!(
+            block_on(read_packet(&mut rx)).unwrap(),
+            VariablePacket::PingrespPacket(_)
+        ));
+
+        println!("Truncated streams are an error");
+        assert!(block_on(read_packet(&mut rx)).is_err());
+
+        println!("Overly long length fields are an error");
+        let mut rx = Cursor::new(vec![0x30, 0xff, 0xff, 0xff, 0xff, 0x01]);
+        assert!(block_on(read_packet(&mut rx)).is_err());
+    }
+
+    #[test]
+    fn config() {
+        let mut config = BridgeConfig {
+            enabled: true,
+            host: "broker.lab".to_string(),
+            username: Some("tac".to_string()),
+            password: Some("secret".to_string()),
+            prefix: "lab/tac-1".to_string(),
+            publish: vec![
+                "/v1/dut/#".to_string(),
+                "/v1/tac/temperatures/+".to_string(),
+            ],
+            subscribe: vec!["/v1/dut/powered".to_string()],
+            ..Default::default()
+        };
+
+        println!("A valid config is accepted");
+        assert!(config.validate().is_ok());
+
+        println!("The password is not exposed");
+        assert_eq!(config.redacted().password, None);
+        assert_eq!(config.redacted().username, config.username);
+
+        println!("Passwords are kept for the same endpoint only");
+        let mut req = BridgeConfig {
+            password: None,
+            ..config.clone()
+        };
+        req.resolve_password(&config);
+        assert_eq!(req.password, config.password);
+
+        let changes: [fn(&mut BridgeConfig); 4] = [
+            |req| req.host = "evil.example.com".to_string(),
+            |req| req.port = 1884,
+            |req| req.tls = !req.tls,
+            |req| req.username = Some("mallory".to_string()),
+        ];
+
+        for change in changes {
+            let mut req = BridgeConfig {
+                password: None,
+                ..config.clone()
+            };
+            change(&mut req);
+            req.resolve_password(&config);
+            assert_eq!(req.password, None);
+        }
+
+        println!("New passwords are used and empty ones clear it");
+        let mut req = BridgeConfig {
+            host: "other.lab".to_string(),
+            password: Some("other".to_string()),
+            ..config.clone()
+        };
+        req.resolve_password(&config);
+        assert_eq!(req.password.as_deref(), Some("other"));
+
+        let mut req = BridgeConfig {
+            password: Some(String::new()),
+            ..config.clone()
+        };
+        req.resolve_password(&config);
+        assert_eq!(req.password, None);
+
+        println!("Prefixes with wildcards are rejected");
+        config.prefix = "lab/+".to_string();
+        assert!(config.validate().is_err());
+        config.prefix = "lab/tac-1/".to_string();
+        assert!(config.validate().is_err());
+        config.prefix = "lab/tac-1".to_string();
+
+        println!("Invalid filters are rejected");
+        config.publish.push("/v1/#/dut".to_string());
+        assert!(config.validate().is_err());
+        config.publish.pop();
+
+        println!("Enabling the bridge requires a host");
+        config.host.clear();
+        assert!(config.validate().is_err());
+        config.enabled = false;
+        assert!(config.validate().is_ok());
+    }
+}
@@ -0,0 +1,71 @@
+// This file is part of tacd, the LXA TAC system daemon
+// Copyright (C) 2024 Pengutronix e.K.
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along
+// with this program; if not, write to the Free Software Foundation, Inc.,
+// 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+
+//! Mark values of topics that were not updated in time as stale
+//!
+//! If e.g. an ADC thread stops working, the last measured value would
+//! otherwise be shown forever. Topics with a time to live in their metadata
+//! (see `TopicMeta::ttl`) are serialized as `null` once their value is
+//! older than that.
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use anyhow::Result;
+use async_std::sync::Arc;
+use async_std::task::sleep;
+use log::warn;
+
+use super::{AnyTopic, TopicMeta};
+use crate::watched_tasks::WatchedTasksBuilder;
+
+/// How often the age of the values is checked.
+/// This limits how precisely the time to live is honored.
+const CHECK_INTERVAL: Duration = Duration::from_millis(250);
+
+pub(super) fn register(
+    wtb: &mut WatchedTasksBuilder,
+    topics: &[Arc<dyn AnyTopic>],
+    meta: &HashMap<String, TopicMeta>,
+) -> Result<()> {
+    let expiring: Vec<(Arc<dyn AnyTopic>, Duration)> = topics
+        .iter()
+        .filter_map(|topic| {
+            let path: &str = topic.path();
+            let ttl = meta.get(path)?.ttl?;
+
+            Some((topic.clone(), Duration::from_millis(ttl)))
+        })
+        .collect();
+
+    if expiring.is_empty() {
+        return Ok(());
+    }
+
+    wtb.spawn_task("broker-expiry", async move {
+        loop {
+            sleep(CHECK_INTERVAL).await;
+
+            for (topic, ttl) in &expiring {
+                if topic.expire(*ttl) {
+                    let path: &str = topic.path();
+                    warn!("Value of {path} is stale, it was not updated for {ttl:?}");
+                }
+            }
+        }
+    })
+}
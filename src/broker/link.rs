@@ -0,0 +1,115 @@
+// This file is part of tacd, the LXA TAC system daemon
+// Copyright (C) 2024 Pengutronix e.K.
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along
+// with this program; if not, write to the Free Software Foundation, Inc.,
+// 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+
+use anyhow::Result;
+use async_std::prelude::*;
+use async_std::sync::Arc;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use super::{AnyTopic, Topic};
+use crate::watched_tasks::WatchedTasksBuilder;
+
+/// An entry in the list of links between topics
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct TopicLink {
+    pub from: String,
+    pub to: String,
+}
+
+/// Spawn a task that forwards values from one topic to another
+pub(super) fn spawn<S, D, F>(
+    wtb: &mut WatchedTasksBuilder,
+    from: Arc<Topic<S>>,
+    to: Arc<Topic<D>>,
+    map: F,
+) -> Result<TopicLink>
+where
+    S: Serialize + DeserializeOwned + Send + Sync + Clone + 'static,
+    D: Serialize + DeserializeOwned + Send + Sync + Clone + 'static,
+    F: Fn(S) -> Option<D> + Send + Sync + 'static,
+{
+    let link = TopicLink {
+        from: from.path().to_string(),
+        to: to.path().to_string(),
+    };
+
+    let (mut stream, _) = from.subscribe_unbounded();
+
+    wtb.spawn_task(format!("link {} -> {}", link.from, link.to), async move {
+        while let Some(val) = stream.next().await {
+            if let Some(val) = map(val) {
+                to.set(val);
+            }
+        }
+
+        Ok(())
+    })?;
+
+    Ok(link)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use async_std::future::timeout;
+    use async_std::prelude::*;
+    use async_std::task::block_on;
+
+    use super::TopicLink;
+    use crate::broker::BrokerBuilder;
+    use crate::watched_tasks::WatchedTasksBuilder;
+
+    const TIMEOUT: Duration = Duration::from_millis(100);
+
+    #[test]
+    fn values_are_forwarded() {
+        let mut bb = BrokerBuilder::new();
+        let mut wtb = WatchedTasksBuilder::new();
+
+        let from = bb.topic_rw::<u8>("/test/from", None);
+        let to = bb.topic_ro::<bool>("/test/to", None);
+
+        bb.link(&mut wtb, &from, &to, |v| match v {
+            0 => Some(false),
+            1 => Some(true),
+            _ => None,
+        })
+        .unwrap();
+
+        assert_eq!(
+            bb.links,
+            vec![TopicLink {
+                from: "/test/from".to_string(),
+                to: "/test/to".to_string(),
+            }]
+        );
+
+        let (mut to_stream, _) = to.clone().subscribe_unbounded();
+
+        println!("Values the mapping function accepts are forwarded");
+        from.set(1);
+        let val = block_on(timeout(TIMEOUT, to_stream.next())).unwrap();
+        assert_eq!(val, Some(true));
+
+        println!("Values the mapping function rejects are not");
+        from.set(7);
+        from.set(0);
+        let val = block_on(timeout(TIMEOUT, to_stream.next())).unwrap();
+        assert_eq!(val, Some(false));
+    }
+}
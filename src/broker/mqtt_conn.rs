@@ -17,26 +17,22 @@
 
 use std::collections::HashMap;
 use std::io::Cursor;
+use std::time::{Duration, Instant};
 
 use anyhow::{anyhow, Result};
 
 use async_std::channel::bounded;
+use async_std::future::timeout;
 use async_std::sync::{Arc, Mutex};
-use async_std::task::spawn;
+use async_std::task::{sleep, spawn};
 
 use async_tungstenite::tungstenite::{
-    protocol::{
-        frame::{coding::CloseCode, CloseFrame},
-        Role,
-    },
+    protocol::frame::{coding::CloseCode, CloseFrame},
     Message,
 };
 use async_tungstenite::WebSocketStream;
 
-use base64::Engine;
-
 use futures_lite::future::race;
-use futures_util::future::Either;
 use futures_util::{FutureExt, SinkExt, StreamExt};
 
 use mqtt::control::variable_header::{ConnectReturnCode, ProtocolLevel};
@@ -44,15 +40,19 @@ use mqtt::packet::suback::SubscribeReturnCode;
 use mqtt::TopicFilter;
 use mqtt::{packet::*, Decodable, Encodable};
 
-use sha1::{digest::Update, Digest, Sha1};
-use tide::http::format_err;
-use tide::http::headers::{HeaderName, CONNECTION, UPGRADE};
 use tide::http::upgrade::Connection;
-use tide::{Request, Response, StatusCode};
+use tide::Request;
+
+use serde::{Deserialize, Serialize};
+
+use tracing::info_span;
 
 pub use mqtt::TopicName;
 
-use super::{AnySubscriptionHandle, AnyTopic};
+use super::queue_stats::QueueStats;
+use super::rest::client_label;
+use super::{AnySubscriptionHandle, AnyTopic, Topic};
+use crate::http_server::{websocket, WriteAccess};
 
 /// Limit the number of elements in the queue leading to the websocket
 /// connection. This assumes that the websocket connection will provide
@@ -65,8 +65,44 @@ const MAX_QUEUE_LENGTH: usize = 4096;
 /// the backpressure mechanism mentioned above actually does something.
 const MAX_PENDING_BYTES: usize = 256 * 1024;
 
-/// This is used in the WebSocket handshake
-const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+/// Send a WebSocket ping to the client if we did not hear from it for
+/// this long.
+/// Browsers answer pings on their own, so this does not need any support
+/// in the web interface.
+const PING_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Consider a client dead if we did not receive anything (including pongs)
+/// from it for this long.
+/// Clients behind a NAT may silently disappear, which we would otherwise
+/// only notice once the queue leading to them overflows.
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(45);
+
+/// Statistics about the MQTT over WebSocket connections for diagnostics
+#[derive(Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct ConnectionStats {
+    /// Number of currently open connections
+    pub active: u64,
+    /// Number of connections accepted since the tacd started
+    pub total: u64,
+    /// Connections closed because the client stopped responding
+    pub reaped_timeout: u64,
+    /// Connections closed because the client could not keep up with updates
+    pub reaped_overload: u64,
+}
+
+/// Why a connection was closed by us
+#[derive(Clone, Copy)]
+enum Reaped {
+    Timeout,
+    Overload,
+}
+
+/// The events handled in the main loop of a connection
+enum Event {
+    Client(Option<Result<Message, async_tungstenite::tungstenite::Error>>),
+    TxDone(Result<()>),
+    Keepalive,
+}
 
 // The mqtt crate provides the Decodable and Encodable traits that can decode/
 // encode packets from/to Readers/Writers.
@@ -98,12 +134,70 @@ trait EncodableExt: Encodable {
 
 impl<E> EncodableExt for E where E: Encodable {}
 
+/// Answer a subscribe with a single WebSocket frame
+///
+/// The frame contains the suback followed by a publish packet for every
+/// retained value of the subscribed topics.
+/// MQTT over WebSocket clients must not assume that packets are aligned
+/// with frames, so this is allowed and saves a lot of small frames when a
+/// client subscribes to many topics at once, e.g. on page load.
+fn batch_message(
+    suback: &SubackPacket,
+    retained: Vec<(TopicName, Arc<[u8]>)>,
+) -> std::io::Result<Message> {
+    let mut cursor = Cursor::new(Vec::new());
+
+    suback.encode(&mut cursor)?;
+
+    for (topic, payload) in retained {
+        PublishPacket::new(topic, QoSWithPacketIdentifier::Level0, payload.to_vec())
+            .encode(&mut cursor)?;
+    }
+
+    Ok(Message::binary(cursor.into_inner()))
+}
+
 /// Handle the full lifetime of a MQTT over websocket connection,
 /// from protocol handshake to teardown.
 async fn handle_connection(
     topics: Arc<Vec<Arc<dyn AnyTopic>>>,
-    mut stream: WebSocketStream<Connection>,
+    stats: Arc<Topic<ConnectionStats>>,
+    queue_stats: QueueStats,
+    access: Option<WriteAccess>,
+    stream: WebSocketStream<Connection>,
 ) {
+    stats.modify(|prev| {
+        let mut stats = prev.unwrap_or_default();
+        stats.active += 1;
+        stats.total += 1;
+        Some(stats)
+    });
+
+    let reaped = serve_connection(topics, queue_stats, access, stream).await;
+
+    stats.modify(|prev| {
+        let mut stats = prev.unwrap_or_default();
+        stats.active = stats.active.saturating_sub(1);
+
+        match reaped {
+            Some(Reaped::Timeout) => stats.reaped_timeout += 1,
+            Some(Reaped::Overload) => stats.reaped_overload += 1,
+            None => {}
+        }
+
+        Some(stats)
+    });
+}
+
+/// Talk MQTT to a client until either side closes the connection
+///
+/// Returns the reason if the connection was forcefully closed by us.
+async fn serve_connection(
+    topics: Arc<Vec<Arc<dyn AnyTopic>>>,
+    queue_stats: QueueStats,
+    access: Option<WriteAccess>,
+    mut stream: WebSocketStream<Connection>,
+) -> Option<Reaped> {
     // The MQTT connection starts with a CONNECT packet.
     // Since we are only targeting the one MQTT (over WebSockets)
     // implementation used in the web interface we can make some assumptions.
@@ -113,12 +207,12 @@ async fn handle_connection(
     let conn_pkg = {
         let msg = match stream.next().await {
             Some(Ok(msg)) => msg,
-            _ => return,
+            _ => return None,
         };
 
         match VariablePacket::from_message(msg) {
             Ok(VariablePacket::ConnectPacket(conn)) => conn,
-            _ => return,
+            _ => return None,
         }
     };
 
@@ -131,9 +225,13 @@ async fn handle_connection(
         || conn_pkg.will_retain()
         || conn_pkg.protocol_level() != ProtocolLevel::Version311
     {
-        return;
+        return None;
     }
 
+    // The client ID is used to label the writes performed by the client.
+    // The web interface uses random "webinterface-..." IDs.
+    let client = client_label(Some(conn_pkg.client_identifier()));
+
     // Send CONNACK packet to signal a successful connection setup
     if stream
         .send(
@@ -144,11 +242,11 @@ async fn handle_connection(
         .await
         .is_err()
     {
-        return;
+        return None;
     }
 
     if stream.flush().await.is_err() {
-        return;
+        return None;
     }
 
     let (stream_tx, mut stream_rx) = stream.split();
@@ -168,17 +266,24 @@ async fn handle_connection(
     // This should generate backpressure on the queue if the websocket can not
     // make progress and the senders should close the queue if it is full.
     let (to_websocket, mut for_websocket) = bounded::<(TopicName, Arc<[u8]>)>(MAX_QUEUE_LENGTH);
+    let queue_monitor = queue_stats.monitor(client.clone(), MAX_QUEUE_LENGTH);
     let stream_tx_task = stream_tx.clone();
-    let mut tx_done = spawn(async move {
+    let mut tx_task = spawn(async move {
         let mut pending_bytes = 0;
 
         loop {
             // Take the next message provided by the serialized topic
-            // subscription channel
-            let (topic, payload) = for_websocket
-                .next()
-                .await
-                .ok_or(anyhow!("subscription channel closed"))?;
+            // subscription channel.
+            // The channel is only closed by the topics if it is full.
+            let (topic, payload) = match for_websocket.next().await {
+                Some(msg) => msg,
+                None => {
+                    queue_monitor.closed_full();
+                    return Err(anyhow!("subscription queue overflowed"));
+                }
+            };
+
+            queue_monitor.dequeued(for_websocket.len());
 
             // Wrap a MQTT publish header around it
             let msg = PublishPacket::new(topic, QoSWithPacketIdentifier::Level0, payload.to_vec())
@@ -201,8 +306,7 @@ async fn handle_connection(
                 pending_bytes = 0;
             }
         }
-    })
-    .into_stream();
+    });
 
     // Keep track of the currently subscribed topics to be able to handle
     // unsubscribe requests and clean up once the connection is closed.
@@ -210,34 +314,93 @@ async fn handle_connection(
         HashMap::new();
 
     let mut res: Result<()> = Ok(());
+    let mut reaped = None;
+    let mut last_seen = Instant::now();
+    let mut next_ping = last_seen + PING_INTERVAL;
 
-    // Handle two kinds of events:
+    // Handle three kinds of events:
     // - packets sent by the client
     // - the tx task exiting for some reason
+    // - the client being quiet for too long
     'connection: loop {
+        let ping_in = next_ping.saturating_duration_since(Instant::now());
+
         let ev = race(
-            stream_rx.next().map(Either::Left),
-            tx_done.next().map(Either::Right),
+            race(
+                stream_rx.next().map(Event::Client),
+                (&mut tx_task).map(Event::TxDone),
+            ),
+            sleep(ping_in).map(|_| Event::Keepalive),
         )
         .await;
 
-        let pkg = match ev {
-            Either::Left(Some(Ok(message))) => match VariablePacket::from_message(message) {
-                Ok(p) => p,
-                Err(e) => {
-                    res = Err(e.into());
-                    break;
-                }
-            },
-            Either::Left(Some(Err(e))) => {
+        let message = match ev {
+            Event::Client(Some(Ok(message))) => message,
+            Event::Client(Some(Err(e))) => {
                 res = Err(e.into());
                 break;
             }
-            Either::Right(Some(r)) => {
+            Event::Client(None) => {
+                break;
+            }
+            Event::TxDone(r) => {
+                // The topics close the queue if it is full,
+                // because the client does not keep up.
+                if to_websocket.is_closed() {
+                    reaped = Some(Reaped::Overload);
+                }
+
                 res = r;
                 break;
             }
-            Either::Left(None) | Either::Right(None) => {
+            Event::Keepalive => {
+                if last_seen.elapsed() >= CLIENT_TIMEOUT {
+                    res = Err(anyhow!("Client did not respond to keepalive pings"));
+                    reaped = Some(Reaped::Timeout);
+                    break;
+                }
+
+                // The tx task may be stuck sending to a dead client while
+                // holding the lock, so do not wait forever.
+                let ping = async {
+                    let mut stream_tx = stream_tx.lock().await;
+                    let stream_tx = stream_tx.as_mut().unwrap();
+                    stream_tx.send(Message::Ping(Vec::new())).await?;
+                    stream_tx.flush().await
+                };
+
+                match timeout(CLIENT_TIMEOUT, ping).await {
+                    Ok(Ok(())) => {}
+                    Ok(Err(e)) => {
+                        res = Err(e.into());
+                        break;
+                    }
+                    Err(_) => {
+                        res = Err(anyhow!("Timeout while sending keepalive ping"));
+                        reaped = Some(Reaped::Timeout);
+                        break;
+                    }
+                }
+
+                next_ping = Instant::now() + PING_INTERVAL;
+
+                continue;
+            }
+        };
+
+        // Any message (including pongs) shows that the client is still there
+        last_seen = Instant::now();
+        next_ping = last_seen + PING_INTERVAL;
+
+        // Pings are answered by the WebSocket implementation itself
+        if message.is_ping() || message.is_pong() {
+            continue;
+        }
+
+        let pkg = match VariablePacket::from_message(message) {
+            Ok(p) => p,
+            Err(e) => {
+                res = Err(e.into());
                 break;
             }
         };
@@ -251,29 +414,18 @@ async fn handle_connection(
                         .iter()
                         .map(|_| SubscribeReturnCode::MaximumQoSLevel0)
                         .collect(),
-                )
-                .as_message()
-                .unwrap();
+                );
 
-                // We should get the suback out before sending the retained
-                // values. So send it now even though we did not do the
-                // subscribing yet.
-                if let Err(e) = stream_tx
-                    .lock()
-                    .await
-                    .as_mut()
-                    .unwrap()
-                    .send(suback_pkg)
-                    .await
-                {
-                    res = Err(e.into());
-                    break 'connection;
-                }
+                // Hold the lock on the WebSocket while subscribing, so that
+                // the tx task can not send updates for the new subscriptions
+                // before the suback and the retained values are out.
+                let mut stream_tx_lock = stream_tx.lock().await;
+                let mut retained = Vec::new();
 
-                // One subscribe packet can (in theory) contain multiple topics
+                // One subscribe packet can contain multiple topics
                 // (including wildcards) to subscribe to.
-                // Currently the web interface uses neither of these features,
-                // but it could.
+                // The web interface uses this to subscribe to all topics
+                // it is interested in at once.
                 for (filter, _qos) in sub_pkg.subscribes() {
                     // Go through all registered topics and check if the
                     // subscribe request matches. This should make sure that
@@ -282,7 +434,17 @@ async fn handle_connection(
                     let new_subscribes: Vec<_> = topics
                         .iter()
                         .filter(|topic| topic.web_readable() && matcher.is_match(topic.path()))
-                        .map(|topic| topic.clone().subscribe_as_bytes(to_websocket.clone(), true))
+                        .map(|topic| {
+                            let (values, handle) = topic
+                                .clone()
+                                .subscribe_as_bytes_with_retained(to_websocket.clone());
+
+                            for payload in values {
+                                retained.push((topic.path().clone(), payload));
+                            }
+
+                            handle
+                        })
                         .collect();
 
                     // Only allow one subscribe with the same match per
@@ -296,6 +458,19 @@ async fn handle_connection(
                         }
                     }
                 }
+
+                let batch = match batch_message(&suback_pkg, retained) {
+                    Ok(batch) => batch,
+                    Err(e) => {
+                        res = Err(e.into());
+                        break 'connection;
+                    }
+                };
+
+                if let Err(e) = stream_tx_lock.as_mut().unwrap().send(batch).await {
+                    res = Err(e.into());
+                    break 'connection;
+                }
             }
             VariablePacket::UnsubscribePacket(unsub_pkg) => {
                 for filter in unsub_pkg.subscribes() {
@@ -335,7 +510,19 @@ async fn handle_connection(
                     .iter()
                     .find(|t| t.web_writable() && &t.path()[..] == pub_pkg.topic_name());
 
+                // Writes by clients that are not (or no longer) authenticated
+                // are ignored, just like writes to topics that do not exist.
+                // Access is checked for every write, so that e.g. logging out
+                // also affects open connections.
+                let topic = topic.filter(|_| access.as_ref().map_or(true, WriteAccess::allowed));
+
                 if let Some(topic) = topic {
+                    let _span = info_span!(
+                        "web_write",
+                        client = client.as_deref().unwrap_or("unlabeled"),
+                    )
+                    .entered();
+
                     if let Err(e) = topic.set_from_bytes(pub_pkg.payload()) {
                         res = Err(e.into());
                         break 'connection;
@@ -369,12 +556,17 @@ async fn handle_connection(
         desub.unsubscribe()
     }
 
+    // Stop the tx task, even if it is stuck sending to a dead client.
+    // This also releases its lock on stream_tx.
+    tx_task.cancel().await;
+
     // We may be able to get a closing frame with some information about errors
     // causing the connection to close through to the peer.
     // This is a best effort action for a couple of reasons:
     //
     // - Clients don't care
     // - The WebSocket may be closed by the peer and not by us
+    // - The peer may be gone without telling us
     let stream_tx = stream_tx.lock().await.take().unwrap();
     let mut ws = stream_tx.reunite(stream_rx).unwrap();
 
@@ -394,72 +586,29 @@ async fn handle_connection(
         reason: std::borrow::Cow::from(&reason),
     };
 
-    let _ = ws.close(Some(close_frame)).await;
-}
+    let _ = timeout(PING_INTERVAL, ws.close(Some(close_frame))).await;
 
-fn header_contains_ignore_case(req: &Request<()>, header_name: HeaderName, value: &str) -> bool {
-    req.header(header_name)
-        .map(|h| {
-            h.as_str()
-                .split(',')
-                .any(|s| s.trim().eq_ignore_ascii_case(value.trim()))
-        })
-        .unwrap_or(false)
+    reaped
 }
 
-pub(super) fn register(server: &mut tide::Server<()>, topics: Arc<Vec<Arc<dyn AnyTopic>>>) {
+pub(super) fn register(
+    server: &mut tide::Server<()>,
+    topics: Arc<Vec<Arc<dyn AnyTopic>>>,
+    stats: Arc<Topic<ConnectionStats>>,
+    queue_stats: QueueStats,
+) {
     server.at("/v1/mqtt").get(move |req: Request<()>| {
         let topics = topics.clone();
+        let stats = stats.clone();
+        let queue_stats = queue_stats.clone();
 
         async move {
-            // These are the good parts from tide-websockets without the bad
-            // WebSocketConnection wrapper.
-
-            let connection_upgrade = header_contains_ignore_case(&req, CONNECTION, "upgrade");
-            let upgrade_to_websocket = header_contains_ignore_case(&req, UPGRADE, "websocket");
-            let upgrade_requested = connection_upgrade && upgrade_to_websocket;
-
-            if !upgrade_requested {
-                return Ok(Response::new(StatusCode::UpgradeRequired));
-            }
-
-            let header = match req.header("Sec-Websocket-Key") {
-                Some(h) => h.as_str(),
-                None => return Err(format_err!("expected sec-websocket-key")),
-            };
-
-            let protocol = req.header("Sec-Websocket-Protocol").and_then(|value| {
-                value
-                    .as_str()
-                    .split(',')
-                    .map(str::trim)
-                    .find(|req_p| req_p == &"mqttv3.1" || req_p == &"mqtt")
-            });
-
-            let mut response = Response::new(StatusCode::SwitchingProtocols);
-
-            response.insert_header(UPGRADE, "websocket");
-            response.insert_header(CONNECTION, "Upgrade");
-            let hash = Sha1::new().chain(header).chain(WEBSOCKET_GUID).finalize();
-            let hash = base64::engine::general_purpose::STANDARD.encode(&hash[..]);
-            response.insert_header("Sec-Websocket-Accept", hash);
-            response.insert_header("Sec-Websocket-Version", "13");
-
-            if let Some(protocol) = protocol {
-                response.insert_header("Sec-Websocket-Protocol", protocol);
-            }
-
-            let http_res: &mut tide::http::Response = response.as_mut();
-            let upgrade_receiver = http_res.recv_upgrade().await;
-
-            spawn(async move {
-                if let Some(stream) = upgrade_receiver.await {
-                    let ws = WebSocketStream::from_raw_socket(stream, Role::Server, None).await;
-                    handle_connection(topics, ws).await;
-                }
-            });
+            let access = req.ext::<WriteAccess>().cloned();
 
-            Ok(response)
+            websocket::upgrade(&req, &["mqttv3.1", "mqtt"], move |ws| {
+                handle_connection(topics, stats, queue_stats, access, ws)
+            })
+            .await
         }
     });
 }
@@ -15,7 +15,8 @@
 // with this program; if not, write to the Free Software Foundation, Inc.,
 // 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
 
-use std::fs::{create_dir, rename, File};
+use std::collections::HashMap;
+use std::fs::{copy, create_dir, rename, File};
 use std::path::Path;
 
 use anyhow::{bail, Result};
@@ -25,47 +26,65 @@ use async_std::sync::Arc;
 use log::{error, info, warn};
 use serde::{Deserialize, Serialize};
 use serde_json::{from_reader, to_writer_pretty, Map, Value};
+use tide::{Request, Response, Server};
 
-use super::{AnyTopic, TopicName};
+use super::{AnyTopic, Topic, TopicName};
 
+use crate::fs_root;
+use crate::http_server::WriteAccess;
 use crate::watched_tasks::WatchedTasksBuilder;
 
-#[cfg(feature = "demo_mode")]
-const PERSISTENCE_PATH: &str = "demo_files/srv/tacd/state.json";
-
-#[cfg(not(feature = "demo_mode"))]
 const PERSISTENCE_PATH: &str = "/srv/tacd/state.json";
 
+/// Damaged state files are copied here before they are overwritten
+const DAMAGED_PATH: &str = "/srv/tacd/state.damaged.json";
+
+const EXPORT_PATH: &str = "/v1/tac/config/export";
+const IMPORT_PATH: &str = "/v1/tac/config/import";
+
 #[derive(Serialize, Deserialize)]
 struct PersistenceFile {
     format_version: u64,
     persistent_topics: Map<String, Value>,
 }
 
-fn load(topics: &[Arc<dyn AnyTopic>]) -> Result<()> {
-    let path = Path::new(PERSISTENCE_PATH);
-
-    if !path.is_file() {
-        info!(
-            "State file at \"{}\" does not yet exist. Using defaults",
-            PERSISTENCE_PATH
-        );
-        return Ok(());
-    }
+/// Settings that could not be restored from a damaged state file
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct StateRecovery {
+    /// Why (parts of) the state file could not be loaded
+    pub reason: String,
+    /// Where the damaged state file was kept for inspection (if it could be)
+    pub damaged_file: Option<String>,
+    /// Paths of the persistent topics that fell back to their defaults
+    pub defaulted: Vec<String>,
+}
 
+fn read(path: &Path) -> Result<PersistenceFile> {
     let file: PersistenceFile = from_reader(File::open(path)?)?;
 
     if file.format_version != 1 {
         bail!("Unknown state file version: {}", file.format_version);
     }
 
+    Ok(file)
+}
+
+/// Set the persistent topics to the values from a state file
+///
+/// Returns the paths of the topics whose values could not be applied.
+/// These keep their defaults.
+fn apply(topics: &[Arc<dyn AnyTopic>], file: PersistenceFile) -> Vec<String> {
     let mut content = file.persistent_topics;
+    let mut defaulted = Vec::new();
 
     for topic in topics.iter().filter(|t| t.persistent()) {
         let path: &str = topic.path();
 
         if let Some(value) = content.remove(path) {
-            topic.set_from_json_value(value)?;
+            if let Err(e) = topic.set_from_json_value(value) {
+                warn!("Malformed value for \"{path}\" in state file: {e}");
+                defaulted.push(path.to_string());
+            }
         }
     }
 
@@ -76,35 +95,145 @@ fn load(topics: &[Arc<dyn AnyTopic>]) -> Result<()> {
         }
     }
 
-    Ok(())
+    defaulted
 }
 
-fn save(topics: &Arc<Vec<Arc<dyn AnyTopic>>>) -> Result<()> {
-    let persistent_topics = {
-        let mut map = Map::new();
-
-        for topic in topics.iter().filter(|t| t.persistent()) {
-            let key = topic.path().to_string();
-            let value = topic.try_get_json_value();
-
-            if let Some(value) = value {
-                if map.insert(key, value).is_some() {
-                    let name: &str = topic.path();
-                    error!("Duplicate persistent topic: \"{name}\"");
-                    // continue anyways
-                }
+/// Load the persistent topics from the state file
+///
+/// A damaged state file does not keep the tacd from starting. The topics
+/// that could not be loaded keep their defaults instead and the file is
+/// kept for inspection, as it is overwritten on the next change.
+fn load(topics: &[Arc<dyn AnyTopic>]) -> Option<StateRecovery> {
+    let path = fs_root::path(PERSISTENCE_PATH);
+
+    if !path.is_file() {
+        info!(
+            "State file at \"{}\" does not yet exist. Using defaults",
+            path.display()
+        );
+        return None;
+    }
+
+    let (reason, defaulted) = match read(&path) {
+        Ok(file) => {
+            let defaulted = apply(topics, file);
+
+            if defaulted.is_empty() {
+                return None;
             }
+
+            (
+                "The state file contained malformed values".to_string(),
+                defaulted,
+            )
         }
+        Err(e) => {
+            // We can not tell which topics were set, so all of them may
+            // have been reset.
+            let mut defaulted: Vec<String> = topics
+                .iter()
+                .filter(|t| t.persistent())
+                .map(|t| t.path().to_string())
+                .collect();
+
+            defaulted.sort();
+            defaulted.dedup();
+
+            (format!("Failed to read the state file: {e}"), defaulted)
+        }
+    };
+
+    error!(
+        "{reason}. {} settings were reset to defaults",
+        defaulted.len()
+    );
 
-        map
+    let damaged_file = match copy(&path, fs_root::path(DAMAGED_PATH)) {
+        Ok(_) => Some(DAMAGED_PATH.to_string()),
+        Err(e) => {
+            error!("Failed to keep a copy of the damaged state file: {e}");
+            None
+        }
     };
 
-    let file_contents = PersistenceFile {
+    Some(StateRecovery {
+        reason,
+        damaged_file,
+        defaulted,
+    })
+}
+
+/// Get the current values of all persistent topics
+fn snapshot(topics: &[Arc<dyn AnyTopic>]) -> PersistenceFile {
+    let mut persistent_topics = Map::new();
+
+    for topic in topics.iter().filter(|t| t.persistent()) {
+        let key = topic.path().to_string();
+        let value = topic.try_get_json_value();
+
+        if let Some(value) = value {
+            if persistent_topics.insert(key, value).is_some() {
+                let name: &str = topic.path();
+                error!("Duplicate persistent topic: \"{name}\"");
+                // continue anyways
+            }
+        }
+    }
+
+    PersistenceFile {
         format_version: 1,
         persistent_topics,
-    };
+    }
+}
 
-    let path = Path::new(PERSISTENCE_PATH);
+/// Apply a snapshot (e.g. from another TAC) to the persistent topics
+///
+/// All values are checked first. Nothing is applied if the snapshot has an
+/// unknown format or any of the values does not belong to a persistent
+/// topic or does not match its type.
+fn import(topics: &[Arc<dyn AnyTopic>], snapshot: PersistenceFile) -> Result<(), Vec<String>> {
+    if snapshot.format_version != 1 {
+        let version = snapshot.format_version;
+        return Err(vec![format!("Unknown snapshot version: {version}")]);
+    }
+
+    let mut persistent: HashMap<&str, &Arc<dyn AnyTopic>> = HashMap::new();
+
+    for topic in topics.iter().filter(|t| t.persistent()) {
+        let path: &str = topic.path();
+        persistent.entry(path).or_insert(topic);
+    }
+
+    let errors: Vec<String> = snapshot
+        .persistent_topics
+        .iter()
+        .filter_map(|(path, value)| match persistent.get(path.as_str()) {
+            Some(topic) => topic
+                .check_json_value(value)
+                .err()
+                .map(|e| format!("{path}: Malformed value: {e}")),
+            None => Some(format!("{path}: Not a persistent topic")),
+        })
+        .collect();
+
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    for (path, value) in snapshot.persistent_topics {
+        // The value was already checked, so this can not fail
+        persistent[path.as_str()]
+            .set_from_json_value(value)
+            .unwrap();
+    }
+
+    Ok(())
+}
+
+fn save(topics: &Arc<Vec<Arc<dyn AnyTopic>>>) -> Result<()> {
+    let file_contents = snapshot(topics);
+
+    let path = fs_root::path(PERSISTENCE_PATH);
     let parent = path.parent().unwrap();
 
     let path_tmp = {
@@ -146,8 +275,16 @@ async fn save_on_change(
     Ok(())
 }
 
-pub fn register(wtb: &mut WatchedTasksBuilder, topics: Arc<Vec<Arc<dyn AnyTopic>>>) -> Result<()> {
-    load(&topics).unwrap();
+/// Load the persistent topics and save them whenever they change
+///
+/// What could not be loaded is published via `recovery`, so that users
+/// know which settings were reset.
+pub fn register(
+    wtb: &mut WatchedTasksBuilder,
+    topics: Arc<Vec<Arc<dyn AnyTopic>>>,
+    recovery: &Topic<Option<StateRecovery>>,
+) -> Result<()> {
+    recovery.set(load(&topics));
 
     let (tx, rx) = unbounded();
 
@@ -157,3 +294,143 @@ pub fn register(wtb: &mut WatchedTasksBuilder, topics: Arc<Vec<Arc<dyn AnyTopic>
 
     wtb.spawn_task("persistence-save", save_on_change(topics, rx))
 }
+
+/// Allow exporting the persistent topics e.g. before re-flashing a TAC and
+/// importing them afterwards or on another TAC
+pub(super) fn serve(server: &mut Server<()>, topics: Arc<Vec<Arc<dyn AnyTopic>>>) {
+    let topics_export = topics.clone();
+
+    server.at(EXPORT_PATH).get(move |_req| {
+        let topics = topics_export.clone();
+
+        async move {
+            let res = Response::builder(200)
+                .body(serde_json::to_vec_pretty(&snapshot(&topics))?)
+                .content_type("application/json")
+                .header(
+                    "Content-Disposition",
+                    "attachment; filename=\"tacd-config.json\"",
+                )
+                .build();
+
+            Ok(res)
+        }
+    });
+
+    server.at(IMPORT_PATH).post(move |mut req: Request<()>| {
+        let topics = topics.clone();
+
+        async move {
+            if let Some(res) = WriteAccess::refuse(&req) {
+                return Ok(res);
+            }
+
+            let snapshot: PersistenceFile = req
+                .body_json()
+                .await
+                .map_err(|_| tide::Error::from_str(400, "Malformed configuration snapshot"))?;
+
+            match import(&topics, snapshot) {
+                Ok(()) => {
+                    info!("Imported a configuration snapshot");
+                    Ok(Response::new(204))
+                }
+                Err(errors) => Ok(Response::builder(422)
+                    .body(serde_json::to_vec(&errors)?)
+                    .content_type("application/json")
+                    .build()),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use async_std::sync::Arc;
+    use serde_json::{json, Map};
+
+    use super::{apply, import, snapshot, AnyTopic, PersistenceFile};
+    use crate::broker::{BrokerBuilder, Topic};
+
+    #[test]
+    fn export_import() {
+        let mut bb = BrokerBuilder::new();
+
+        let limit: Arc<Topic<f32>> = bb.topic("/v1/test/limit", true, true, true, Some(1.0), 1);
+        let name: Arc<Topic<String>> = bb.topic("/v1/test/name", true, true, true, None, 1);
+        let _volatile: Arc<Topic<bool>> = bb.topic_rw("/v1/test/volatile", Some(false));
+
+        let topics: Vec<Arc<dyn AnyTopic>> = bb.topics.clone();
+
+        println!("Only persistent topics with a value are exported");
+        let exported = snapshot(&topics);
+        assert_eq!(exported.persistent_topics.len(), 1);
+        assert_eq!(exported.persistent_topics["/v1/test/limit"], json!(1.0));
+
+        let file = |entries: &[(&str, serde_json::Value)]| PersistenceFile {
+            format_version: 1,
+            persistent_topics: entries
+                .iter()
+                .map(|(path, value)| (path.to_string(), value.clone()))
+                .collect::<Map<_, _>>(),
+        };
+
+        println!("Nothing is applied if one of the values is invalid");
+        let errors = import(
+            &topics,
+            file(&[
+                ("/v1/test/limit", json!(2.0)),
+                ("/v1/test/name", json!(5)),
+                ("/v1/test/volatile", json!(true)),
+            ]),
+        )
+        .unwrap_err();
+
+        assert_eq!(errors.len(), 2);
+        assert_eq!(limit.try_get(), Some(1.0));
+
+        println!("Valid snapshots are applied");
+        import(
+            &topics,
+            file(&[
+                ("/v1/test/limit", json!(2.0)),
+                ("/v1/test/name", json!("dut")),
+            ]),
+        )
+        .unwrap();
+
+        assert_eq!(limit.try_get(), Some(2.0));
+        assert_eq!(name.try_get().as_deref(), Some("dut"));
+
+        println!("Snapshots of unknown versions are refused");
+        let mut future = file(&[]);
+        future.format_version = 2;
+        assert!(import(&topics, future).is_err());
+    }
+
+    #[test]
+    fn apply_damaged() {
+        let mut bb = BrokerBuilder::new();
+
+        let limit: Arc<Topic<f32>> = bb.topic("/v1/test/limit", true, true, true, Some(1.0), 1);
+        let name: Arc<Topic<String>> = bb.topic("/v1/test/name", true, true, true, None, 1);
+
+        let topics: Vec<Arc<dyn AnyTopic>> = bb.topics.clone();
+
+        let mut persistent_topics = Map::new();
+        persistent_topics.insert("/v1/test/limit".to_string(), json!("not a number"));
+        persistent_topics.insert("/v1/test/name".to_string(), json!("dut"));
+
+        let file = PersistenceFile {
+            format_version: 1,
+            persistent_topics,
+        };
+
+        println!("Malformed values are reported and keep their defaults");
+        assert_eq!(apply(&topics, file), vec!["/v1/test/limit".to_string()]);
+        assert_eq!(limit.try_get(), Some(1.0));
+
+        println!("The other values are still applied");
+        assert_eq!(name.try_get().as_deref(), Some("dut"));
+    }
+}
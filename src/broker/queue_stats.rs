@@ -0,0 +1,249 @@
+// This file is part of tacd, the LXA TAC system daemon
+// Copyright (C) 2024 Pengutronix e.K.
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along
+// with this program; if not, write to the Free Software Foundation, Inc.,
+// 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+
+//! Backpressure statistics of the queues leading to outside subscribers
+//!
+//! The topics close the queue of a subscriber that does not keep up with
+//! the updates (see `Topic::set`). Clients notice that as a lost connection.
+//! The statistics show which clients came close to or hit the queue limit,
+//! to tell slow clients apart from problems on the TAC.
+
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use anyhow::Result;
+use async_std::sync::Arc;
+use async_std::task::sleep;
+use serde::{Deserialize, Serialize};
+
+use super::{BrokerBuilder, Topic};
+use crate::watched_tasks::WatchedTasksBuilder;
+
+/// How often the statistics are published
+const UPDATE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Number of overflowed subscribers to keep after they went away
+const MAX_RECENTLY_CLOSED: usize = 10;
+
+/// Number of subscriber queues closed because they were full, counted in
+/// the `TrySendError::Full` paths of the topics.
+static CLOSED_FULL: AtomicU64 = AtomicU64::new(0);
+
+pub(super) fn record_closed_full() {
+    CLOSED_FULL.fetch_add(1, Ordering::Relaxed);
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct SubscriberQueue {
+    /// The label the client identified itself with (if any)
+    pub client: Option<String>,
+    pub capacity: usize,
+    /// Messages in the queue when the last one was taken out
+    pub depth: usize,
+    /// Most messages that were in the queue at once
+    pub high_water: usize,
+    /// Messages taken out of the queue and sent to the client
+    pub messages: u64,
+    /// The queue was closed because it was full
+    pub closed_full: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug, Default)]
+pub struct QueueReport {
+    /// Queues closed because they were full since the tacd started
+    pub closed_full: u64,
+    /// The queues of the currently connected subscribers
+    pub subscribers: Vec<SubscriberQueue>,
+    /// Subscribers that went away because their queue was full,
+    /// with the most recent one last
+    pub recently_closed: Vec<SubscriberQueue>,
+}
+
+struct MonitorState {
+    client: Option<String>,
+    capacity: usize,
+    depth: AtomicUsize,
+    high_water: AtomicUsize,
+    messages: AtomicU64,
+    closed_full: AtomicBool,
+}
+
+impl MonitorState {
+    fn report(&self) -> SubscriberQueue {
+        SubscriberQueue {
+            client: self.client.clone(),
+            capacity: self.capacity,
+            depth: self.depth.load(Ordering::Relaxed),
+            high_water: self.high_water.load(Ordering::Relaxed),
+            messages: self.messages.load(Ordering::Relaxed),
+            closed_full: self.closed_full.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Monitors {
+    active: Vec<Arc<MonitorState>>,
+    recently_closed: Vec<SubscriberQueue>,
+}
+
+/// Keep track of the queue of a single subscriber
+///
+/// The statistics are removed from the report once this is dropped.
+pub(super) struct QueueMonitor {
+    state: Arc<MonitorState>,
+    monitors: Arc<Mutex<Monitors>>,
+}
+
+impl QueueMonitor {
+    /// Record that a message was taken out of the queue, leaving `depth`
+    /// messages behind
+    pub(super) fn dequeued(&self, depth: usize) {
+        self.state.depth.store(depth, Ordering::Relaxed);
+        self.state
+            .high_water
+            .fetch_max(depth + 1, Ordering::Relaxed);
+        self.state.messages.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record that the queue was closed because it was full
+    pub(super) fn closed_full(&self) {
+        self.state
+            .depth
+            .store(self.state.capacity, Ordering::Relaxed);
+        self.state
+            .high_water
+            .store(self.state.capacity, Ordering::Relaxed);
+        self.state.closed_full.store(true, Ordering::Relaxed);
+    }
+}
+
+impl Drop for QueueMonitor {
+    fn drop(&mut self) {
+        let mut monitors = self.monitors.lock().unwrap();
+
+        monitors.active.retain(|m| !Arc::ptr_eq(m, &self.state));
+
+        if self.state.closed_full.load(Ordering::Relaxed) {
+            monitors.recently_closed.push(self.state.report());
+
+            let excess = monitors
+                .recently_closed
+                .len()
+                .saturating_sub(MAX_RECENTLY_CLOSED);
+            monitors.recently_closed.drain(..excess);
+        }
+    }
+}
+
+#[derive(Clone)]
+pub(super) struct QueueStats {
+    monitors: Arc<Mutex<Monitors>>,
+}
+
+impl QueueStats {
+    pub(super) fn new(bb: &mut BrokerBuilder, wtb: &mut WatchedTasksBuilder) -> Result<Self> {
+        let report: Arc<Topic<QueueReport>> = bb.topic_ro(
+            "/v1/tac/daemon/subscriber_queues",
+            Some(QueueReport::default()),
+        );
+
+        let monitors: Arc<Mutex<Monitors>> = Arc::default();
+        let monitors_task = monitors.clone();
+
+        wtb.spawn_task("broker-queue-stats", async move {
+            loop {
+                sleep(UPDATE_INTERVAL).await;
+
+                let new = {
+                    let monitors = monitors_task.lock().unwrap();
+
+                    QueueReport {
+                        closed_full: CLOSED_FULL.load(Ordering::Relaxed),
+                        subscribers: monitors.active.iter().map(|m| m.report()).collect(),
+                        recently_closed: monitors.recently_closed.clone(),
+                    }
+                };
+
+                report.set_if_changed(new);
+            }
+        })?;
+
+        Ok(Self { monitors })
+    }
+
+    /// Start keeping track of the queue of a new subscriber
+    pub(super) fn monitor(&self, client: Option<String>, capacity: usize) -> QueueMonitor {
+        let state = Arc::new(MonitorState {
+            client,
+            capacity,
+            depth: AtomicUsize::new(0),
+            high_water: AtomicUsize::new(0),
+            messages: AtomicU64::new(0),
+            closed_full: AtomicBool::new(false),
+        });
+
+        self.monitors.lock().unwrap().active.push(state.clone());
+
+        QueueMonitor {
+            state,
+            monitors: self.monitors.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{QueueStats, MAX_RECENTLY_CLOSED};
+    use crate::broker::BrokerBuilder;
+    use crate::watched_tasks::WatchedTasksBuilder;
+
+    #[test]
+    fn queue_stats() {
+        let mut bb = BrokerBuilder::new();
+        let mut wtb = WatchedTasksBuilder::new();
+        let stats = QueueStats::new(&mut bb, &mut wtb).unwrap();
+
+        println!("The high water mark is kept");
+        let monitor = stats.monitor(Some("slow".to_string()), 16);
+        monitor.dequeued(5);
+        monitor.dequeued(2);
+
+        let report = monitor.state.report();
+        assert_eq!(report.depth, 2);
+        assert_eq!(report.high_water, 6);
+        assert_eq!(report.messages, 2);
+        assert!(!report.closed_full);
+
+        println!("Subscribers are removed once they go away");
+        drop(monitor);
+        assert!(stats.monitors.lock().unwrap().active.is_empty());
+        assert!(stats.monitors.lock().unwrap().recently_closed.is_empty());
+
+        println!("Unless their queue was full");
+        for _ in 0..(MAX_RECENTLY_CLOSED + 2) {
+            let monitor = stats.monitor(Some("slow".to_string()), 16);
+            monitor.closed_full();
+        }
+
+        let monitors = stats.monitors.lock().unwrap();
+        assert!(monitors.active.is_empty());
+        assert_eq!(monitors.recently_closed.len(), MAX_RECENTLY_CLOSED);
+        assert!(monitors.recently_closed.iter().all(|q| q.high_water == 16));
+    }
+}
@@ -0,0 +1,146 @@
+// This file is part of tacd, the LXA TAC system daemon
+// Copyright (C) 2024 Pengutronix e.K.
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along
+// with this program; if not, write to the Free Software Foundation, Inc.,
+// 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+
+use std::collections::{BTreeMap, HashMap};
+use std::time::Duration;
+
+use async_std::sync::Arc;
+use serde::{Deserialize, Serialize};
+
+use super::AnyTopic;
+
+/// Human readable information about the value of a topic
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug, Default)]
+pub struct TopicMeta {
+    pub description: Option<String>,
+    /// The unit of the value, e.g. "V" or "°C"
+    pub unit: Option<String>,
+    /// Categories the topic belongs to, e.g. "measurement" or "power"
+    pub tags: Vec<String>,
+    /// Time to live of a value (in milliseconds). Values that are older
+    /// are serialized as `null`.
+    pub ttl: Option<u64>,
+}
+
+impl TopicMeta {
+    pub fn new(description: &str) -> Self {
+        Self {
+            description: Some(description.to_string()),
+            ..Default::default()
+        }
+    }
+
+    pub fn unit(mut self, unit: &str) -> Self {
+        self.unit = Some(unit.to_string());
+        self
+    }
+
+    pub fn tag(mut self, tag: &str) -> Self {
+        self.tags.push(tag.to_string());
+        self
+    }
+
+    pub fn ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = Some(ttl.as_millis() as u64);
+        self
+    }
+}
+
+/// An entry in the list of topics that are accessible from the outside
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct TopicInfo {
+    pub path: String,
+    pub readable: bool,
+    pub writable: bool,
+    pub persistent: bool,
+    #[serde(flatten)]
+    pub meta: TopicMeta,
+}
+
+/// List all topics that can be read or written from the outside, sorted by
+/// path
+///
+/// A read only and a write only topic with the same path are combined into
+/// a single entry.
+pub(super) fn list(
+    topics: &[Arc<dyn AnyTopic>],
+    meta: &HashMap<String, TopicMeta>,
+) -> Vec<TopicInfo> {
+    let mut infos: BTreeMap<&str, TopicInfo> = BTreeMap::new();
+
+    let external = topics
+        .iter()
+        .filter(|topic| topic.web_readable() || topic.web_writable());
+
+    for topic in external {
+        let path: &str = topic.path();
+
+        let info = infos.entry(path).or_insert_with(|| TopicInfo {
+            path: path.to_string(),
+            readable: false,
+            writable: false,
+            persistent: false,
+            meta: meta.get(path).cloned().unwrap_or_default(),
+        });
+
+        info.readable |= topic.web_readable();
+        info.writable |= topic.web_writable();
+        info.persistent |= topic.persistent();
+    }
+
+    infos.into_values().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TopicMeta;
+    use crate::broker::BrokerBuilder;
+
+    #[test]
+    fn topics_are_listed() {
+        let mut bb = BrokerBuilder::new();
+
+        let voltage = bb.topic_ro::<f32>("/test/voltage", None);
+        bb.topic_wo::<bool>("/test/switch", None);
+        bb.topic_ro::<bool>("/test/switch", None);
+        bb.topic::<u8>("/test/hidden", false, false, false, None, 1);
+
+        bb.describe(
+            &voltage,
+            TopicMeta::new("A voltage").unit("V").tag("measurement"),
+        );
+
+        let list = bb.registry();
+
+        println!("Only external topics are listed and merged by path");
+        let paths: Vec<&str> = list.iter().map(|info| info.path.as_str()).collect();
+        assert_eq!(paths, vec!["/test/switch", "/test/voltage"]);
+        assert!(list[0].readable && list[0].writable);
+        assert!(list[1].readable && !list[1].writable);
+
+        println!("Metadata is attached to the topics");
+        assert_eq!(list[0].meta, TopicMeta::default());
+        assert_eq!(list[1].meta.description.as_deref(), Some("A voltage"));
+        assert_eq!(list[1].meta.unit.as_deref(), Some("V"));
+        assert_eq!(list[1].meta.tags, vec!["measurement".to_string()]);
+
+        println!("Metadata is flattened into the serialized entries");
+        let json = serde_json::to_value(&list[1]).unwrap();
+        assert_eq!(json["unit"], "V");
+        assert_eq!(json["persistent"], false);
+    }
+}
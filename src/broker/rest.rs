@@ -15,30 +15,160 @@
 // with this program; if not, write to the Free Software Foundation, Inc.,
 // 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
 
+use std::time::Duration;
+
+use async_std::channel::unbounded;
+use async_std::future::timeout;
 use async_std::sync::Arc;
 
+use serde::Deserialize;
 use tide::{Request, Response};
+use tracing::info_span;
 
 use super::AnyTopic;
+use crate::http_server::WriteAccess;
+
+/// Header containing the sequence number of the returned topic value
+const SEQUENCE_HEADER: &str = "X-Tacd-Sequence";
+
+/// Header that clients can use to label the writes they perform,
+/// e.g. with the name of the test script or the user
+pub(super) const CLIENT_HEADER: &str = "X-Tacd-Client";
+
+/// Longest client label that is accepted
+const MAX_CLIENT_LABEL_LEN: usize = 64;
+
+/// How long to hold a long-poll request if the client does not say otherwise
+const LONG_POLL_TIMEOUT_DEFAULT: Duration = Duration::from_secs(30);
+
+/// Upper limit for the time a long-poll request is held
+const LONG_POLL_TIMEOUT_MAX: Duration = Duration::from_secs(120);
+
+#[derive(Deserialize)]
+struct QueryParams {
+    /// Sequence number of the last value the client has seen.
+    /// If set the request is held until the topic changes.
+    since: Option<u64>,
+    /// Maximum time in seconds to hold a long-poll request
+    timeout: Option<u64>,
+}
+
+fn value_response(sequence: u64, value: Arc<[u8]>) -> Response {
+    tide::Response::builder(200)
+        .body(value.to_vec())
+        .content_type("application/json")
+        .header(SEQUENCE_HEADER, sequence.to_string())
+        .build()
+}
+
+/// Wait for the topic to have a value with a different sequence number than
+/// `since` and return it
+///
+/// This is a fallback for clients that can not use websockets
+/// (e.g. because they are behind a restrictive proxy).
+/// If the topic does not change within `wait` a "204 No Content" response
+/// containing the current sequence number is returned instead.
+async fn long_poll(topic: Arc<dyn AnyTopic>, since: u64, wait: Duration) -> Response {
+    let (tx, rx) = unbounded();
+
+    // Subscribe before checking the current value so we do not miss updates
+    // that happen in between.
+    let sub = topic.clone().subscribe_as_bytes(tx, false);
+
+    let current = topic.try_get_sequenced_as_bytes();
+
+    let changed = match current {
+        Some((sequence, _)) if sequence != since => current,
+        _ => match timeout(wait, rx.recv()).await {
+            Ok(Ok(_)) => topic.try_get_sequenced_as_bytes(),
+            Ok(Err(_)) | Err(_) => None,
+        },
+    };
+
+    sub.unsubscribe();
+
+    // Make sure that no proxy in between tries to cache long-poll responses
+    let mut res = match changed {
+        Some((sequence, value)) => value_response(sequence, value),
+        None => {
+            let sequence = topic
+                .try_get_sequenced_as_bytes()
+                .map_or(since, |(sequence, _)| sequence);
+
+            tide::Response::builder(204)
+                .header(SEQUENCE_HEADER, sequence.to_string())
+                .build()
+        }
+    };
+
+    res.insert_header("Cache-Control", "no-store");
+
+    res
+}
+
+/// Sanitize a label a client gave itself
+///
+/// Labels end up in traces and logs, so only short labels of printable
+/// ASCII characters are accepted.
+pub(super) fn client_label(label: Option<&str>) -> Option<String> {
+    let label = label?.trim();
+
+    let valid = !label.is_empty()
+        && label.len() <= MAX_CLIENT_LABEL_LEN
+        && label.chars().all(|c| c.is_ascii_graphic() || c == ' ');
+
+    valid.then(|| label.to_string())
+}
+
+/// Get the label of the client that sent a request (if it provided one)
+pub(super) fn request_client_label(req: &Request<()>) -> Option<String> {
+    client_label(req.header(CLIENT_HEADER).map(|h| h.as_str()))
+}
+
+async fn get_handler(topic: Arc<dyn AnyTopic>, req: Request<()>) -> tide::Result {
+    let params: QueryParams = req
+        .query()
+        .map_err(|_| tide::Error::from_str(400, "Malformed query parameters"))?;
+
+    if let Some(since) = params.since {
+        let wait = params
+            .timeout
+            .map_or(LONG_POLL_TIMEOUT_DEFAULT, Duration::from_secs)
+            .min(LONG_POLL_TIMEOUT_MAX);
+
+        return Ok(long_poll(topic, since, wait).await);
+    }
 
-async fn get_handler(topic: Arc<dyn AnyTopic>, mut _req: Request<()>) -> tide::Result {
     topic
-        .try_get_as_bytes()
+        .try_get_sequenced_as_bytes()
         .ok_or(tide::Error::from_str(
             404,
             "Don't have a retained message yet",
         ))
-        .map(|r| {
-            tide::Response::builder(200)
-                .body(r.to_vec())
-                .content_type("application/json")
-                .build()
-        })
+        .map(|(sequence, value)| value_response(sequence, value))
 }
 
 async fn put_handler(topic: Arc<dyn AnyTopic>, mut req: Request<()>) -> tide::Result {
+    // Every topic that is writable via the web controls some aspect of
+    // the TAC, so writing it requires authentication (if enabled).
+    if let Some(res) = WriteAccess::refuse(&req) {
+        return Ok(res);
+    }
+
+    let body = req.body_bytes().await?;
+    let client = request_client_label(&req);
+
+    // Attach the label to the "broker_set" span and anything that happens
+    // in response to the write.
+    let _span = info_span!(
+        "web_write",
+        client = client.as_deref().unwrap_or("unlabeled"),
+        peer = req.peer_addr().unwrap_or("unknown"),
+    )
+    .entered();
+
     topic
-        .set_from_bytes(&req.body_bytes().await?)
+        .set_from_bytes(&body)
         .map(|_| Response::new(204))
         .map_err(|_| tide::Error::from_str(400, "Malformed payload"))
 }
@@ -61,3 +191,26 @@ pub(super) fn register(server: &mut tide::Server<()>, topics: Arc<Vec<Arc<dyn An
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{client_label, MAX_CLIENT_LABEL_LEN};
+
+    #[test]
+    fn client_labels() {
+        assert_eq!(client_label(None), None);
+        assert_eq!(
+            client_label(Some(" nightly-test (alice) ")).as_deref(),
+            Some("nightly-test (alice)")
+        );
+
+        println!("Empty, overly long and non printable labels are refused");
+        assert_eq!(client_label(Some("  ")), None);
+        assert_eq!(
+            client_label(Some(&"a".repeat(MAX_CLIENT_LABEL_LEN + 1))),
+            None
+        );
+        assert_eq!(client_label(Some("line\nbreak")), None);
+        assert_eq!(client_label(Some("ünicode")), None);
+    }
+}
@@ -0,0 +1,60 @@
+// This file is part of tacd, the LXA TAC system daemon
+// Copyright (C) 2022 Pengutronix e.K.
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along
+// with this program; if not, write to the Free Software Foundation, Inc.,
+// 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+
+//! Per-thread buffer used to serialize topic values
+//!
+//! This module only depends on serde, so that the allocation test in
+//! `tests/serialize_allocations.rs` can include it on its own.
+
+use std::cell::RefCell;
+use std::sync::Arc;
+
+use serde::Serialize;
+
+/// Scratch buffers that grew larger than this are not kept around,
+/// so that a single large value does not pin its memory forever
+const SCRATCH_MAX_CAPACITY: usize = 64 * 1024;
+
+std::thread_local! {
+    /// Buffer that values are serialized into before being copied into an
+    /// exactly sized `Arc<[u8]>`
+    static SCRATCH: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Serialize a value as json, reusing the scratch buffer of this thread
+///
+/// Topics like the ADC measurements are set many times per second.
+/// Serializing into a fresh `Vec` and converting that into an `Arc<[u8]>`
+/// grows the `Vec`, shrinks it into a boxed slice and then copies it into a
+/// new allocation for the `Arc`. With the reused buffer only the allocation
+/// for the `Arc`, which is shared by all subscribers, remains.
+pub(super) fn serialize_pooled<E: Serialize>(val: &E) -> Arc<[u8]> {
+    SCRATCH.with(|scratch| {
+        let mut scratch = scratch.borrow_mut();
+
+        scratch.clear();
+        serde_json::to_writer(&mut *scratch, val).unwrap();
+
+        let ser = Arc::from(scratch.as_slice());
+
+        if scratch.capacity() > SCRATCH_MAX_CAPACITY {
+            *scratch = Vec::new();
+        }
+
+        ser
+    })
+}
@@ -19,14 +19,18 @@ use std::collections::VecDeque;
 use std::marker::PhantomData;
 use std::ops::Not;
 use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
 
 use async_std::channel::{unbounded, Receiver, Sender, TrySendError};
 use async_std::prelude::*;
 
-use serde::{de::DeserializeOwned, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tracing::info_span;
 
 use unique_token::Unique;
 
+use super::queue_stats::record_closed_full;
+use super::scratch::serialize_pooled;
 use super::TopicName;
 
 pub(super) struct RetainedValue<E> {
@@ -50,22 +54,31 @@ impl<E: Serialize + Clone> RetainedValue<E> {
     ///
     /// Returns either a cached result or serializes the value and caches it
     /// for later.
+    /// Values are only serialized once someone asks for them, so topics
+    /// without serialized subscribers do not pay for it on every set.
     fn serialized(&mut self) -> Arc<[u8]> {
         let native = &self.native;
 
         self.serialized
-            .get_or_insert_with(|| {
-                let ser = serde_json::to_vec(native).unwrap();
-                Arc::from(ser.into_boxed_slice())
-            })
+            .get_or_insert_with(|| serialize_pooled(native))
             .clone()
     }
 }
 
 type SerializedSender = Sender<(TopicName, Arc<[u8]>)>;
 
+/// Serialized representation of a value that has expired (see `AnyTopic::expire`)
+const STALE: &[u8] = b"null";
+
 pub struct TopicInner<E> {
     retained: VecDeque<RetainedValue<E>>,
+    /// Incremented on every set, so that clients can tell if they have
+    /// already seen the most recent value.
+    sequence: u64,
+    /// When the topic was last set, to detect stale values
+    last_set: Option<Instant>,
+    /// The most recent value is older than the time to live of the topic
+    stale: bool,
     senders: Vec<(Unique, Sender<E>)>,
     senders_serialized: Vec<(Unique, SerializedSender)>,
 }
@@ -73,6 +86,7 @@ pub struct TopicInner<E> {
 impl<E: Serialize + Clone> TopicInner<E> {
     fn new(retained_length: usize, initial: Option<E>) -> Self {
         let mut retained = VecDeque::with_capacity(retained_length + 1);
+        let last_set = initial.as_ref().map(|_| Instant::now());
 
         if let Some(v) = initial {
             retained.push_back(RetainedValue::new(v))
@@ -80,6 +94,9 @@ impl<E: Serialize + Clone> TopicInner<E> {
 
         Self {
             retained,
+            sequence: 0,
+            last_set,
+            stale: false,
             senders: Vec::new(),
             senders_serialized: Vec::new(),
         }
@@ -197,6 +214,7 @@ impl<E: Serialize + DeserializeOwned + Clone> Topic<E> {
                 Ok(_) => true,
                 Err(TrySendError::Full(_)) => {
                     s.close();
+                    record_closed_full();
                     false
                 }
                 Err(TrySendError::Closed(_)) => false,
@@ -208,6 +226,7 @@ impl<E: Serialize + DeserializeOwned + Clone> Topic<E> {
                 Ok(_) => true,
                 Err(TrySendError::Full(_)) => {
                     s.close();
+                    record_closed_full();
                     false
                 }
                 Err(TrySendError::Closed(_)) => false,
@@ -215,6 +234,9 @@ impl<E: Serialize + DeserializeOwned + Clone> Topic<E> {
         });
 
         inner.retained.push_back(val);
+        inner.sequence = inner.sequence.wrapping_add(1);
+        inner.last_set = Some(Instant::now());
+        inner.stale = false;
 
         while inner.retained.len() > self.retained_length {
             inner.retained.pop_front();
@@ -303,6 +325,7 @@ impl<E: Serialize + DeserializeOwned + Clone> Topic<E> {
             }
             Err(TrySendError::Full(_)) => {
                 sender.close();
+                record_closed_full();
             }
             Err(TrySendError::Closed(_)) => {}
         };
@@ -368,13 +391,20 @@ pub trait AnyTopic: Sync + Send {
     fn persistent(&self) -> bool;
     fn set_from_bytes(&self, msg: &[u8]) -> serde_json::Result<()>;
     fn set_from_json_value(&self, msg: serde_json::Value) -> serde_json::Result<()>;
+    fn check_json_value(&self, msg: &serde_json::Value) -> serde_json::Result<()>;
     fn subscribe_as_bytes(
         self: Arc<Self>,
         sender: Sender<(TopicName, Arc<[u8]>)>,
         enqueue_retained: bool,
     ) -> Box<dyn AnySubscriptionHandle>;
+    fn subscribe_as_bytes_with_retained(
+        self: Arc<Self>,
+        sender: Sender<(TopicName, Arc<[u8]>)>,
+    ) -> (Vec<Arc<[u8]>>, Box<dyn AnySubscriptionHandle>);
     fn try_get_as_bytes(&self) -> Option<Arc<[u8]>>;
+    fn try_get_sequenced_as_bytes(&self) -> Option<(u64, Arc<[u8]>)>;
     fn try_get_json_value(&self) -> Option<serde_json::Value>;
+    fn expire(&self, ttl: Duration) -> bool;
 }
 
 impl<E: Serialize + DeserializeOwned + Send + Sync + Clone + 'static> AnyTopic for Topic<E> {
@@ -398,6 +428,9 @@ impl<E: Serialize + DeserializeOwned + Send + Sync + Clone + 'static> AnyTopic f
     ///
     /// Returns an Err if deserialization failed.
     fn set_from_bytes(&self, msg: &[u8]) -> serde_json::Result<()> {
+        let path: &str = &self.path;
+        let _span = info_span!("broker_set", topic = path).entered();
+
         let msg = serde_json::from_slice(msg)?;
         self.set(msg);
         Ok(())
@@ -414,6 +447,13 @@ impl<E: Serialize + DeserializeOwned + Send + Sync + Clone + 'static> AnyTopic f
         Ok(())
     }
 
+    /// Check if a generic serde_json value could be de-structured into
+    /// this specific type without setting the topic
+    fn check_json_value(&self, msg: &serde_json::Value) -> serde_json::Result<()> {
+        E::deserialize(msg)?;
+        Ok(())
+    }
+
     /// Add a queue to the list of subscribers for serialized values
     ///
     /// The Returned AnySubscriptionHandle can be used to remove the queue
@@ -441,6 +481,7 @@ impl<E: Serialize + DeserializeOwned + Send + Sync + Clone + 'static> AnyTopic f
                     Ok(_) => {}
                     Err(TrySendError::Full(_)) => {
                         sender.close();
+                        record_closed_full();
                         should_add = false;
                         break;
                     }
@@ -450,6 +491,18 @@ impl<E: Serialize + DeserializeOwned + Send + Sync + Clone + 'static> AnyTopic f
                     }
                 }
             }
+
+            if should_add && inner.stale {
+                match sender.try_send((self.path.clone(), STALE.into())) {
+                    Ok(_) => {}
+                    Err(TrySendError::Full(_)) => {
+                        sender.close();
+                        record_closed_full();
+                        should_add = false;
+                    }
+                    Err(TrySendError::Closed(_)) => should_add = false,
+                }
+            }
         }
 
         if should_add {
@@ -465,34 +518,117 @@ impl<E: Serialize + DeserializeOwned + Send + Sync + Clone + 'static> AnyTopic f
         Box::new(handle)
     }
 
+    /// Add a queue to the list of subscribers and return the currently
+    /// retained values instead of enqueueing them
+    ///
+    /// Both happen while holding the topic lock, so that the caller can
+    /// e.g. send the retained values of many topics in one batch without
+    /// missing updates or receiving them out of order.
+    ///
+    /// # Arguments
+    ///
+    /// * `sender` - The sender side of the queue to add
+    fn subscribe_as_bytes_with_retained(
+        self: Arc<Self>,
+        sender: Sender<(TopicName, Arc<[u8]>)>,
+    ) -> (Vec<Arc<[u8]>>, Box<dyn AnySubscriptionHandle>) {
+        let mut inner = self.inner.lock().unwrap();
+        let token = Unique::new();
+
+        let mut retained: Vec<Arc<[u8]>> =
+            inner.retained.iter_mut().map(|v| v.serialized()).collect();
+
+        if inner.stale {
+            retained.push(STALE.into());
+        }
+
+        inner.senders_serialized.push((token, sender));
+
+        let handle = SubscriptionHandle {
+            topic: Arc::downgrade(&self),
+            token,
+            phantom: PhantomData,
+        };
+
+        (retained, Box::new(handle))
+    }
+
     /// Try to get the current serialized topic value
     ///
     /// Returns None if no value was set yet.
     fn try_get_as_bytes(&self) -> Option<Arc<[u8]>> {
-        self.inner
-            .lock()
-            .unwrap()
-            .retained
-            .back_mut()
-            .map(|v| v.serialized())
+        self.try_get_sequenced_as_bytes().map(|(_, v)| v)
+    }
+
+    /// Try to get the current serialized topic value alongside its sequence
+    /// number
+    ///
+    /// The sequence number changes every time the topic is set and can be
+    /// used to detect if a value was already seen before.
+    /// Returns None if no value was set yet.
+    fn try_get_sequenced_as_bytes(&self) -> Option<(u64, Arc<[u8]>)> {
+        let mut inner = self.inner.lock().unwrap();
+        let sequence = inner.sequence;
+        let stale = inner.stale;
+
+        inner.retained.back_mut().map(|v| match stale {
+            true => (sequence, STALE.into()),
+            false => (sequence, v.serialized()),
+        })
     }
 
     /// Try to get the current value as serde_json value
     ///
     /// Returns None if no value was set yet.
     fn try_get_json_value(&self) -> Option<serde_json::Value> {
-        self.inner
-            .lock()
-            .unwrap()
-            .retained
-            .back()
-            .map(|v| serde_json::to_value(v.native()).unwrap())
+        let inner = self.inner.lock().unwrap();
+
+        inner.retained.back().map(|v| match inner.stale {
+            true => serde_json::Value::Null,
+            false => serde_json::to_value(v.native()).unwrap(),
+        })
+    }
+
+    /// Mark the value of the topic as stale if it was not updated for `ttl`
+    ///
+    /// Stale values are serialized as `null` for outside subscribers until
+    /// the topic is set again. Native subscribers are not notified.
+    /// Returns true if the value just became stale.
+    fn expire(&self, ttl: Duration) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+
+        let expired = inner.last_set.map_or(false, |ts| ts.elapsed() > ttl);
+
+        if inner.stale || !expired {
+            return false;
+        }
+
+        inner.stale = true;
+        inner.sequence = inner.sequence.wrapping_add(1);
+
+        inner.senders_serialized.retain(|(_, s)| {
+            match s.try_send((self.path.clone(), STALE.into())) {
+                Ok(_) => true,
+                Err(TrySendError::Full(_)) => {
+                    s.close();
+                    record_closed_full();
+                    false
+                }
+                Err(TrySendError::Closed(_)) => false,
+            }
+        });
+
+        true
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{AnyTopic, RetainedValue, Topic, TopicName};
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    use super::{serialize_pooled, AnyTopic, RetainedValue, Topic, TopicName};
+    use crate::measurement::Measurement;
     use async_std::channel::{unbounded, Receiver};
     use async_std::sync::Arc;
     use serde::{de::DeserializeOwned, Deserialize, Serialize};
@@ -526,6 +662,23 @@ mod tests {
         assert_eq!(&*retained.serialized(), &b"1"[..]);
     }
 
+    #[test]
+    fn serialization_scratch() {
+        // The number of allocations is checked in tests/serialize_allocations.rs,
+        // as counting them requires a global allocator of its own.
+        let value = Measurement::now(12.345);
+
+        println!("Values serialized via the scratch buffer are plain json");
+        assert_eq!(
+            &*RetainedValue::new(value).serialized(),
+            &serde_json::to_vec(&value).unwrap()[..]
+        );
+
+        println!("The result does not depend on previous contents of the buffer");
+        let _ = serialize_pooled(&"a much longer string than the next value");
+        assert_eq!(&*serialize_pooled(&1u32), &b"1"[..]);
+    }
+
     #[test]
     fn unsubscribe_works() {
         let topic = new_topic::<u32>();
@@ -592,6 +745,34 @@ mod tests {
         assert_eq!(&ser_3, &[b"2", b"1", b"3"]);
     }
 
+    #[test]
+    fn subscribe_with_retained() {
+        let topic: Arc<Topic<u32>> = Arc::new(Topic::new("/", true, true, true, None, 2));
+
+        println!("Nothing is retained yet");
+        let (tx, rx_1) = unbounded();
+        let (retained, _handle_1) = topic.clone().subscribe_as_bytes_with_retained(tx);
+        assert!(retained.is_empty());
+
+        topic.set(1);
+        topic.set(2);
+
+        println!("The retained values are returned instead of enqueued");
+        let (tx, rx_2) = unbounded();
+        let (retained, handle_2) = topic.clone().subscribe_as_bytes_with_retained(tx);
+        let retained: Vec<_> = retained.iter().map(|v| v.to_vec()).collect();
+        assert_eq!(&retained, &[b"1", b"2"]);
+        assert!(rx_2.is_empty());
+
+        println!("Later updates are enqueued as usual");
+        topic.set(3);
+        handle_2.unsubscribe();
+        topic.set(4);
+
+        assert_eq!(&collect_serialized(rx_1), &[b"1", b"2", b"3", b"4"]);
+        assert_eq!(&collect_serialized(rx_2), &[b"3"]);
+    }
+
     #[test]
     fn serialize_roundtrip() {
         let topic = new_topic::<SerTestType>();
@@ -617,4 +798,61 @@ mod tests {
 
         assert_eq!(ser_str, r#"{"a":true,"b":1,"c":"test"}"#);
     }
+
+    #[test]
+    fn values_expire() {
+        let topic = new_topic::<u32>();
+        let ttl = Duration::from_millis(50);
+
+        println!("Topics without a value do not expire");
+        assert!(!topic.expire(Duration::ZERO));
+
+        let (tx, rx) = unbounded();
+        let _handle = topic.clone().subscribe_as_bytes(tx, true);
+
+        topic.set(1);
+        assert!(!topic.expire(ttl));
+
+        println!("Values become stale after the ttl");
+        sleep(2 * ttl);
+        assert!(topic.expire(ttl));
+        assert!(!topic.expire(ttl));
+        assert_eq!(&*topic.try_get_as_bytes().unwrap(), &b"null"[..]);
+        assert_eq!(topic.try_get_json_value(), Some(serde_json::Value::Null));
+        assert_eq!(topic.try_get(), Some(1));
+
+        println!("New subscribers see that the value is stale");
+        let (tx, rx_late) = unbounded();
+        let _handle_late = topic.clone().subscribe_as_bytes(tx, true);
+
+        println!("Setting the topic makes the value fresh again");
+        topic.set(2);
+        assert_eq!(&*topic.try_get_as_bytes().unwrap(), &b"2"[..]);
+
+        assert_eq!(&collect_serialized(rx), &[&b"1"[..], b"null", b"2"]);
+        assert_eq!(&collect_serialized(rx_late), &[&b"1"[..], b"null", b"2"]);
+    }
+
+    #[test]
+    fn sequence_changes_on_set() {
+        let topic = new_topic::<u32>();
+
+        assert_eq!(topic.try_get_sequenced_as_bytes(), None);
+
+        topic.set(1);
+        let (seq_1, val_1) = topic.try_get_sequenced_as_bytes().unwrap();
+
+        topic.set(1);
+        let (seq_2, val_2) = topic.try_get_sequenced_as_bytes().unwrap();
+
+        assert_ne!(seq_1, seq_2);
+        assert_eq!(&*val_1, &b"1"[..]);
+        assert_eq!(&*val_2, &b"1"[..]);
+
+        // Setting a topic to the same value should not touch the sequence
+        topic.set_if_changed(1);
+        let (seq_3, _) = topic.try_get_sequenced_as_bytes().unwrap();
+
+        assert_eq!(seq_2, seq_3);
+    }
 }
@@ -0,0 +1,325 @@
+// This file is part of tacd, the LXA TAC system daemon
+// Copyright (C) 2024 Pengutronix e.K.
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along
+// with this program; if not, write to the Free Software Foundation, Inc.,
+// 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+
+//! Write multiple topics at once with all-or-nothing semantics
+//!
+//! All writes in a transaction are checked first (the topic has to exist,
+//! has to be writable via the web and the value has to match its type).
+//! Only if all of them pass are they applied, in the order they were given.
+//!
+//! Checks that subsystems perform once a value was set (e.g. via a write
+//! only topic that is linked to a read only one) can not be done up front.
+//!
+//! Batches are the relaxed variant of this: every write that passes the
+//! checks is applied, in order, and the result of every write is reported
+//! back.
+
+use std::collections::{HashMap, HashSet};
+
+use async_std::sync::Arc;
+use serde::{Deserialize, Serialize};
+use tide::{Request, Response};
+use tracing::info_span;
+
+use super::rest::request_client_label;
+use super::AnyTopic;
+use crate::http_server::WriteAccess;
+
+const TRANSACTION_PATH: &str = "/v1/topics/transaction";
+const BATCH_PATH: &str = "/v1/batch";
+
+/// Upper limit for the number of writes in a single transaction or batch
+const MAX_WRITES: usize = 64;
+
+#[derive(Deserialize)]
+struct Write {
+    topic: String,
+    value: serde_json::Value,
+}
+
+#[derive(Serialize, PartialEq, Debug)]
+struct WriteResult {
+    topic: String,
+    /// The reason this write would fail or None if it would succeed
+    error: Option<String>,
+}
+
+struct Transactions {
+    /// Topics that can be written via the web interface
+    writable: HashMap<String, Arc<dyn AnyTopic>>,
+    /// All topic paths, used to distinguish read only from unknown topics
+    known: HashSet<String>,
+}
+
+impl Transactions {
+    fn new(topics: &[Arc<dyn AnyTopic>]) -> Self {
+        let writable = topics
+            .iter()
+            .filter(|topic| topic.web_writable())
+            .map(|topic| (topic.path().to_string(), topic.clone()))
+            .collect();
+
+        let known = topics
+            .iter()
+            .map(|topic| topic.path().to_string())
+            .collect();
+
+        Self { writable, known }
+    }
+
+    fn check(&self, write: &Write) -> Result<Arc<dyn AnyTopic>, String> {
+        match self.writable.get(&write.topic) {
+            Some(topic) => match topic.check_json_value(&write.value) {
+                Ok(()) => Ok(topic.clone()),
+                Err(e) => Err(format!("Malformed value: {e}")),
+            },
+            None if self.known.contains(&write.topic) => {
+                Err("The topic is not writable".to_string())
+            }
+            None => Err("Unknown topic".to_string()),
+        }
+    }
+
+    /// Check all writes and apply them if all checks passed
+    ///
+    /// Returns the per-write results if any of the checks failed.
+    fn apply(&self, writes: Vec<Write>) -> Result<(), Vec<WriteResult>> {
+        let checked: Vec<_> = writes.iter().map(|write| self.check(write)).collect();
+
+        if checked.iter().any(|res| res.is_err()) {
+            let results = writes
+                .into_iter()
+                .zip(checked)
+                .map(|(write, res)| WriteResult {
+                    topic: write.topic,
+                    error: res.err(),
+                })
+                .collect();
+
+            return Err(results);
+        }
+
+        for (write, topic) in writes.into_iter().zip(checked) {
+            // The value was already checked, so this can not fail
+            topic.unwrap().set_from_json_value(write.value).unwrap();
+        }
+
+        Ok(())
+    }
+
+    /// Apply all writes that pass the checks, skipping the others
+    fn apply_each(&self, writes: Vec<Write>) -> Vec<WriteResult> {
+        writes
+            .into_iter()
+            .map(|write| {
+                let error = match self.check(&write) {
+                    // The value was already checked, so this can not fail
+                    Ok(topic) => {
+                        topic.set_from_json_value(write.value).unwrap();
+                        None
+                    }
+                    Err(e) => Some(e),
+                };
+
+                WriteResult {
+                    topic: write.topic,
+                    error,
+                }
+            })
+            .collect()
+    }
+}
+
+/// Parse the list of writes in a transaction or batch request
+async fn read_writes(req: &mut Request<()>, kind: &str) -> tide::Result<Vec<Write>> {
+    let writes: Vec<Write> = req
+        .body_json()
+        .await
+        .map_err(|_| tide::Error::from_str(400, format!("Malformed {kind}")))?;
+
+    if writes.len() > MAX_WRITES {
+        return Err(tide::Error::from_str(
+            400,
+            format!("A {kind} can contain at most {MAX_WRITES} writes"),
+        ));
+    }
+
+    Ok(writes)
+}
+
+async fn transaction_handler(
+    transactions: Arc<Transactions>,
+    mut req: Request<()>,
+) -> tide::Result {
+    if let Some(res) = WriteAccess::refuse(&req) {
+        return Ok(res);
+    }
+
+    let writes = read_writes(&mut req, "transaction").await?;
+
+    let client = request_client_label(&req);
+    let _span = info_span!(
+        "web_transaction",
+        client = client.as_deref().unwrap_or("unlabeled"),
+        peer = req.peer_addr().unwrap_or("unknown"),
+    )
+    .entered();
+
+    match transactions.apply(writes) {
+        Ok(()) => Ok(Response::new(204)),
+        Err(results) => Ok(Response::builder(422)
+            .body(serde_json::to_vec(&results)?)
+            .content_type("application/json")
+            .build()),
+    }
+}
+
+async fn batch_handler(transactions: Arc<Transactions>, mut req: Request<()>) -> tide::Result {
+    if let Some(res) = WriteAccess::refuse(&req) {
+        return Ok(res);
+    }
+
+    let writes = read_writes(&mut req, "batch").await?;
+
+    let client = request_client_label(&req);
+    let _span = info_span!(
+        "web_batch",
+        client = client.as_deref().unwrap_or("unlabeled"),
+        peer = req.peer_addr().unwrap_or("unknown"),
+    )
+    .entered();
+
+    let results = transactions.apply_each(writes);
+
+    Ok(Response::builder(200)
+        .body(serde_json::to_vec(&results)?)
+        .content_type("application/json")
+        .build())
+}
+
+pub(super) fn register(server: &mut tide::Server<()>, topics: Arc<Vec<Arc<dyn AnyTopic>>>) {
+    let transactions = Arc::new(Transactions::new(&topics));
+
+    let transactions_clone = transactions.clone();
+    server
+        .at(TRANSACTION_PATH)
+        .post(move |req| transaction_handler(transactions_clone.clone(), req));
+
+    server
+        .at(BATCH_PATH)
+        .post(move |req| batch_handler(transactions.clone(), req));
+}
+
+#[cfg(test)]
+mod tests {
+    use async_std::sync::Arc;
+    use serde_json::json;
+
+    use super::{AnyTopic, Transactions, Write};
+    use crate::broker::{BrokerBuilder, Topic};
+
+    fn write(topic: &str, value: serde_json::Value) -> Write {
+        Write {
+            topic: topic.to_string(),
+            value,
+        }
+    }
+
+    #[test]
+    fn transaction() {
+        let mut bb = BrokerBuilder::new();
+
+        let limit: Arc<Topic<f32>> = bb.topic_rw("/v1/test/limit", Some(1.0));
+        let name: Arc<Topic<String>> = bb.topic_rw("/v1/test/name", None);
+        let _status: Arc<Topic<bool>> = bb.topic_ro("/v1/test/status", Some(false));
+
+        let topics: Vec<Arc<dyn AnyTopic>> = bb.topics.clone();
+        let transactions = Transactions::new(&topics);
+
+        println!("Nothing is applied if one of the writes is malformed");
+        let results = transactions
+            .apply(vec![
+                write("/v1/test/limit", json!(2.0)),
+                write("/v1/test/name", json!(5)),
+            ])
+            .unwrap_err();
+
+        assert_eq!(limit.try_get(), Some(1.0));
+        assert_eq!(name.try_get(), None);
+        assert!(results[0].error.is_none());
+        assert!(results[1].error.is_some());
+
+        println!("Read only and unknown topics are refused");
+        let results = transactions
+            .apply(vec![
+                write("/v1/test/status", json!(true)),
+                write("/v1/test/unknown", json!(true)),
+            ])
+            .unwrap_err();
+
+        assert_eq!(
+            results[0].error.as_deref(),
+            Some("The topic is not writable")
+        );
+        assert_eq!(results[1].error.as_deref(), Some("Unknown topic"));
+
+        println!("All writes are applied if all of them are valid");
+        transactions
+            .apply(vec![
+                write("/v1/test/limit", json!(2.0)),
+                write("/v1/test/name", json!("dut")),
+            ])
+            .unwrap();
+
+        assert_eq!(limit.try_get(), Some(2.0));
+        assert_eq!(name.try_get().as_deref(), Some("dut"));
+    }
+
+    #[test]
+    fn batch() {
+        let mut bb = BrokerBuilder::new();
+
+        let limit: Arc<Topic<f32>> = bb.topic_rw("/v1/test/limit", Some(1.0));
+        let name: Arc<Topic<String>> = bb.topic_rw("/v1/test/name", None);
+
+        let topics: Vec<Arc<dyn AnyTopic>> = bb.topics.clone();
+        let transactions = Transactions::new(&topics);
+
+        println!("Valid writes are applied even if others are not");
+        let results = transactions.apply_each(vec![
+            write("/v1/test/limit", json!(2.0)),
+            write("/v1/test/name", json!(5)),
+            write("/v1/test/unknown", json!(true)),
+        ]);
+
+        assert_eq!(limit.try_get(), Some(2.0));
+        assert_eq!(name.try_get(), None);
+        assert_eq!(results.len(), 3);
+        assert!(results[0].error.is_none());
+        assert!(results[1].error.is_some());
+        assert_eq!(results[2].error.as_deref(), Some("Unknown topic"));
+
+        println!("Writes are applied in order");
+        let results = transactions.apply_each(vec![
+            write("/v1/test/name", json!("first")),
+            write("/v1/test/name", json!("second")),
+        ]);
+
+        assert!(results.iter().all(|res| res.error.is_none()));
+        assert_eq!(name.try_get().as_deref(), Some("second"));
+    }
+}
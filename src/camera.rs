@@ -0,0 +1,477 @@
+// This file is part of tacd, the LXA TAC system daemon
+// Copyright (C) 2024 Pengutronix e.K.
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along
+// with this program; if not, write to the Free Software Foundation, Inc.,
+// 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+
+use std::io;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use async_std::channel::{bounded, Sender};
+use async_std::prelude::*;
+use async_std::sync::Arc;
+use async_std::task::{block_on, sleep, spawn_blocking};
+use futures::stream::select_all;
+use futures::TryStreamExt;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use tide::http::Body;
+use tide::{Response, Server};
+
+use crate::broker::{BrokerBuilder, Topic};
+use crate::usb_hub::UsbHub;
+use crate::watched_tasks::WatchedTasksBuilder;
+
+#[cfg(feature = "demo_mode")]
+mod v4l2 {
+    use anyhow::{bail, Result};
+
+    pub(super) struct Device;
+
+    impl Device {
+        pub(super) fn open(_path: &str, _width: u32, _height: u32) -> Result<Self> {
+            bail!("There is no camera in demo mode")
+        }
+
+        pub(super) fn capture(&mut self) -> Result<Vec<u8>> {
+            bail!("There is no camera in demo mode")
+        }
+    }
+}
+
+#[cfg(not(feature = "demo_mode"))]
+mod v4l2;
+
+use v4l2::Device;
+
+/// The device node of a camera takes a moment to show up after the USB
+/// port it is attached to was powered on
+const DEVICE_TIMEOUT: Duration = Duration::from_secs(5);
+const DEVICE_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The first frames after starting a capture tend to be badly exposed,
+/// as the camera did not have a chance to adjust yet
+const WARMUP_FRAMES: usize = 5;
+
+const CONFIG_PATH: &str = "/v1/camera/config";
+
+const STREAM_BOUNDARY: &str = "frame";
+const STREAM_MIME: &str = "multipart/x-mixed-replace;boundary=frame";
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub enum CameraPort {
+    Port1,
+    Port2,
+    Port3,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct CameraConfig {
+    pub enabled: bool,
+    /// The V4L2 device to capture from (e.g. "/dev/video0")
+    pub device: String,
+    /// The requested resolution. The camera may pick the closest one it supports.
+    pub width: u32,
+    pub height: u32,
+    /// The USB host port the camera is attached to (if any)
+    pub usb_port: Option<CameraPort>,
+}
+
+impl Default for CameraConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            device: "/dev/video0".to_string(),
+            width: 1280,
+            height: 720,
+            usb_port: None,
+        }
+    }
+}
+
+/// Only allow V4L2 device nodes like "/dev/video0", so that the config can
+/// not be used to make the tacd open arbitrary files
+fn is_video_device(path: &str) -> bool {
+    path.strip_prefix("/dev/video").map_or(false, |num| {
+        !num.is_empty() && num.chars().all(|c| c.is_ascii_digit())
+    })
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub enum CameraStatus {
+    Disabled,
+    /// The USB host port the camera is attached to is powered off
+    Unpowered,
+    Ready,
+    Streaming,
+    /// The last capture attempt failed with the contained error
+    Failed(String),
+}
+
+/// Marks the camera as in use until dropped
+///
+/// V4L2 devices only support a single capture stream at a time.
+struct Busy(Arc<AtomicBool>);
+
+impl Busy {
+    fn acquire(flag: &Arc<AtomicBool>) -> Option<Self> {
+        flag.compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+            .ok()
+            .map(|_| Self(flag.clone()))
+    }
+}
+
+impl Drop for Busy {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::SeqCst);
+    }
+}
+
+fn error_response(status: u16, msg: String) -> Response {
+    Response::builder(status).body(msg).build()
+}
+
+#[derive(Clone)]
+struct Camera {
+    config: Arc<Topic<CameraConfig>>,
+    status: Arc<Topic<CameraStatus>>,
+    port_power: [Arc<Topic<bool>>; 3],
+    busy: Arc<AtomicBool>,
+}
+
+impl Camera {
+    fn port_power(&self, config: &CameraConfig) -> Option<Arc<Topic<bool>>> {
+        config
+            .usb_port
+            .map(|port| self.port_power[port as usize].clone())
+    }
+
+    /// Is the camera usable with the given configuration?
+    fn availability(&self, config: &CameraConfig) -> CameraStatus {
+        let unpowered = self
+            .port_power(config)
+            .map(|power| power.try_get() != Some(true))
+            .unwrap_or(false);
+
+        if !config.enabled {
+            CameraStatus::Disabled
+        } else if unpowered {
+            CameraStatus::Unpowered
+        } else {
+            CameraStatus::Ready
+        }
+    }
+
+    fn capture_done(&self, res: &Result<()>) {
+        let status = match res {
+            Ok(()) => self.availability(&self.config.try_get().unwrap_or_default()),
+            Err(e) => {
+                warn!("Failed to capture from camera: {e}");
+                CameraStatus::Failed(e.to_string())
+            }
+        };
+
+        self.status.set_if_changed(status);
+    }
+
+    /// Check if the camera can be used right now and reserve it
+    ///
+    /// Returns a response to send to the client if it can not.
+    async fn prepare(&self) -> std::result::Result<(CameraConfig, Busy), Response> {
+        let config = self.config.try_get().unwrap_or_default();
+
+        // The persisted config may have been edited by hand
+        if !is_video_device(&config.device) {
+            return Err(error_response(
+                500,
+                format!("{} is not a camera device", config.device),
+            ));
+        }
+
+        match self.availability(&config) {
+            CameraStatus::Disabled => {
+                return Err(error_response(503, "The camera is disabled".into()))
+            }
+            CameraStatus::Unpowered => {
+                return Err(error_response(
+                    503,
+                    "The USB port the camera is attached to is powered off".into(),
+                ))
+            }
+            _ => {}
+        }
+
+        let busy = Busy::acquire(&self.busy)
+            .ok_or_else(|| error_response(409, "The camera is already in use".into()))?;
+
+        let start = Instant::now();
+
+        while !Path::new(&config.device).exists() {
+            if start.elapsed() > DEVICE_TIMEOUT {
+                return Err(error_response(
+                    503,
+                    format!("The camera device {} does not exist", config.device),
+                ));
+            }
+
+            sleep(DEVICE_POLL_INTERVAL).await;
+        }
+
+        Ok((config, busy))
+    }
+
+    fn snapshot(config: &CameraConfig) -> Result<Vec<u8>> {
+        let mut device = Device::open(&config.device, config.width, config.height)?;
+
+        for _ in 0..WARMUP_FRAMES {
+            device.capture()?;
+        }
+
+        device.capture()
+    }
+
+    /// Send frames to the client until it goes away or the camera is powered off
+    fn stream(
+        mut device: Device,
+        power: Option<Arc<Topic<bool>>>,
+        frames: Sender<io::Result<Vec<u8>>>,
+    ) -> Result<()> {
+        loop {
+            if power.as_ref().map(|p| p.try_get()) == Some(Some(false)) {
+                return Ok(());
+            }
+
+            let jpeg = device.capture()?;
+
+            let mut part = format!(
+                "--{STREAM_BOUNDARY}\r\nContent-Type: image/jpeg\r\nContent-Length: {}\r\n\r\n",
+                jpeg.len()
+            )
+            .into_bytes();
+
+            part.extend_from_slice(&jpeg);
+            part.extend_from_slice(b"\r\n");
+
+            if block_on(frames.send(Ok(part))).is_err() {
+                // The client closed the connection
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Provide camera snapshots and an MJPEG stream via HTTP
+///
+/// The camera is meant to be attached to one of the TAC's USB host ports to
+/// e.g. watch the display or LEDs of the DUT.
+pub fn run(
+    bb: &mut BrokerBuilder,
+    wtb: &mut WatchedTasksBuilder,
+    server: &mut Server<()>,
+    usb_hub: &UsbHub,
+) -> Result<()> {
+    // Use the "register a read-only and a write-only topic with the same
+    // name to perform validation" trick, so that only configs with valid
+    // device paths are stored.
+    let config_requests = bb.topic_wo::<CameraConfig>(CONFIG_PATH, None);
+
+    let camera = Camera {
+        config: bb.topic(
+            CONFIG_PATH,
+            true,
+            false,
+            true,
+            Some(CameraConfig::default()),
+            1,
+        ),
+        status: bb.topic_ro("/v1/camera/status", Some(CameraStatus::Disabled)),
+        port_power: [
+            usb_hub.port1.status.clone(),
+            usb_hub.port2.status.clone(),
+            usb_hub.port3.status.clone(),
+        ],
+        busy: Arc::new(AtomicBool::new(false)),
+    };
+
+    let (mut config_request_events, _) = config_requests.subscribe_unbounded();
+    let config_task = camera.config.clone();
+    wtb.spawn_task("camera-config-requests", async move {
+        while let Some(config) = config_request_events.next().await {
+            if is_video_device(&config.device) {
+                config_task.set(config);
+            } else {
+                warn!("Refusing camera config with device {}", config.device);
+            }
+        }
+
+        Ok(())
+    })?;
+
+    // Update the status whenever the configuration or the power state of
+    // one of the USB ports changes.
+    let camera_task = camera.clone();
+    wtb.spawn_task("camera-status", async move {
+        let (config_events, _) = camera_task.config.clone().subscribe_unbounded();
+
+        let mut events: Vec<Pin<Box<dyn Stream<Item = ()> + Send>>> =
+            vec![Box::pin(config_events.map(|_| ()))];
+
+        for power in camera_task.port_power.iter() {
+            let (power_events, _) = power.clone().subscribe_unbounded();
+            events.push(Box::pin(power_events.map(|_| ())));
+        }
+
+        let mut events = select_all(events);
+
+        while events.next().await.is_some() {
+            let config = camera_task.config.try_get().unwrap_or_default();
+            let available = camera_task.availability(&config);
+
+            camera_task.status.modify(|prev| match prev {
+                // The stream will update the status once it ends
+                Some(CameraStatus::Streaming) => None,
+                prev => (prev.as_ref() != Some(&available)).then_some(available),
+            });
+        }
+
+        Ok(())
+    })?;
+
+    let camera_snapshot = camera.clone();
+    server.at("/v1/camera/snapshot").get(move |_| {
+        let camera = camera_snapshot.clone();
+
+        async move {
+            let (config, busy) = match camera.prepare().await {
+                Ok(prepared) => prepared,
+                Err(resp) => return Ok(resp),
+            };
+
+            let res = spawn_blocking(move || Camera::snapshot(&config)).await;
+
+            drop(busy);
+
+            let resp = match res {
+                Ok(jpeg) => {
+                    camera.capture_done(&Ok(()));
+
+                    Response::builder(200)
+                        .content_type("image/jpeg")
+                        .header("Cache-Control", "no-store")
+                        .body(jpeg)
+                        .build()
+                }
+                Err(e) => {
+                    let resp = error_response(500, format!("Failed to capture a snapshot: {e}"));
+                    camera.capture_done(&Err(e));
+                    resp
+                }
+            };
+
+            Ok(resp)
+        }
+    });
+
+    let camera_stream = camera;
+    server.at("/v1/camera/stream").get(move |_| {
+        let camera = camera_stream.clone();
+
+        async move {
+            let (config, busy) = match camera.prepare().await {
+                Ok(prepared) => prepared,
+                Err(resp) => return Ok(resp),
+            };
+
+            let (response_tx, mut response_rx) = bounded::<Response>(1);
+            let camera_thread = camera.clone();
+
+            // Capturing frames is a blocking operation that may go on for
+            // as long as the client is connected, so give it its own thread.
+            // Like for the journal the response is sent back via a channel,
+            // so that errors while opening the device can be reported via
+            // the HTTP status code.
+            let spawned = thread::Builder::new()
+                .name("camera-stream".into())
+                .spawn(move || {
+                    let _busy = busy;
+
+                    let device = match Device::open(&config.device, config.width, config.height) {
+                        Ok(device) => device,
+                        Err(e) => {
+                            let resp = error_response(500, format!("Failed to open camera: {e}"));
+                            let _ = response_tx.try_send(resp);
+                            camera_thread.capture_done(&Err(e));
+                            return;
+                        }
+                    };
+
+                    let (frames_tx, frames_rx) = bounded::<io::Result<Vec<u8>>>(1);
+
+                    let mut resp = Response::builder(200)
+                        .body(Body::from_reader(frames_rx.into_async_read(), None))
+                        .header("Cache-Control", "no-store")
+                        .build();
+
+                    resp.insert_header("Content-Type", STREAM_MIME);
+
+                    if response_tx.try_send(resp).is_err() {
+                        return;
+                    }
+
+                    camera_thread.status.set(CameraStatus::Streaming);
+
+                    let power = camera_thread.port_power(&config);
+                    let res = Camera::stream(device, power, frames_tx);
+
+                    camera_thread.capture_done(&res);
+                });
+
+            if let Err(e) = spawned {
+                return Ok(error_response(
+                    500,
+                    format!("Failed to start camera stream: {e}"),
+                ));
+            }
+
+            let resp = response_rx.next().await.unwrap_or_else(|| {
+                error_response(500, "Camera stream stopped unexpectedly".into())
+            });
+
+            Ok(resp)
+        }
+    });
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_video_device, CameraConfig};
+
+    #[test]
+    fn device_paths() {
+        assert!(is_video_device(&CameraConfig::default().device));
+        assert!(is_video_device("/dev/video12"));
+
+        assert!(!is_video_device("/dev/video"));
+        assert!(!is_video_device("/dev/video0/../../etc/shadow"));
+        assert!(!is_video_device("/dev/sda"));
+        assert!(!is_video_device("/etc/tacd/api-token"));
+    }
+}
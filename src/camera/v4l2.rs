@@ -0,0 +1,320 @@
+// This file is part of tacd, the LXA TAC system daemon
+// Copyright (C) 2024 Pengutronix e.K.
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along
+// with this program; if not, write to the Free Software Foundation, Inc.,
+// 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+
+//! A minimal V4L2 capture implementation
+//!
+//! Only supports what is needed to get JPEG frames out of a UVC camera:
+//! single-planar capture devices that provide MJPEG via mmap streaming I/O.
+//! The structure definitions follow `linux/videodev2.h`.
+
+use std::fs::{File, OpenOptions};
+use std::io::Error;
+use std::mem::zeroed;
+use std::os::unix::io::AsRawFd;
+use std::ptr::null_mut;
+use std::slice::from_raw_parts;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context, Result};
+use nix::libc::{self, c_int, c_ulong, c_void};
+use nix::{ioctl_read, ioctl_readwrite, ioctl_write_ptr};
+
+const V4L2_CAP_VIDEO_CAPTURE: u32 = 0x0000_0001;
+const V4L2_CAP_STREAMING: u32 = 0x0400_0000;
+const V4L2_CAP_DEVICE_CAPS: u32 = 0x8000_0000;
+
+const V4L2_BUF_TYPE_VIDEO_CAPTURE: u32 = 1;
+const V4L2_MEMORY_MMAP: u32 = 1;
+const V4L2_FIELD_ANY: u32 = 0;
+const V4L2_BUF_FLAG_ERROR: u32 = 0x0000_0040;
+
+const V4L2_PIX_FMT_MJPEG: u32 = u32::from_le_bytes(*b"MJPG");
+
+const BUFFER_COUNT: u32 = 4;
+const FRAME_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[repr(C)]
+struct Capability {
+    driver: [u8; 16],
+    card: [u8; 32],
+    bus_info: [u8; 32],
+    version: u32,
+    capabilities: u32,
+    device_caps: u32,
+    reserved: [u32; 3],
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct PixFormat {
+    width: u32,
+    height: u32,
+    pixelformat: u32,
+    field: u32,
+    bytesperline: u32,
+    sizeimage: u32,
+    colorspace: u32,
+    private: u32,
+    flags: u32,
+    ycbcr_enc: u32,
+    quantization: u32,
+    xfer_func: u32,
+}
+
+#[repr(C)]
+union FormatData {
+    pix: PixFormat,
+    raw_data: [u8; 200],
+    // Some of the formats in the kernel's union contain pointers,
+    // which determine the alignment of the union.
+    _align: *mut c_void,
+}
+
+#[repr(C)]
+struct Format {
+    buf_type: u32,
+    fmt: FormatData,
+}
+
+#[repr(C)]
+struct RequestBuffers {
+    count: u32,
+    buf_type: u32,
+    memory: u32,
+    capabilities: u32,
+    flags: u8,
+    reserved: [u8; 3],
+}
+
+#[repr(C)]
+struct Timecode {
+    tc_type: u32,
+    flags: u32,
+    frames: u8,
+    seconds: u8,
+    minutes: u8,
+    hours: u8,
+    userbits: [u8; 4],
+}
+
+#[repr(C)]
+union BufferLocation {
+    offset: u32,
+    userptr: c_ulong,
+    planes: *mut c_void,
+    fd: i32,
+}
+
+#[repr(C)]
+struct Buffer {
+    index: u32,
+    buf_type: u32,
+    bytesused: u32,
+    flags: u32,
+    field: u32,
+    timestamp: libc::timeval,
+    timecode: Timecode,
+    sequence: u32,
+    memory: u32,
+    m: BufferLocation,
+    length: u32,
+    reserved2: u32,
+    request_fd: i32,
+}
+
+impl Buffer {
+    fn new(index: u32) -> Self {
+        // All-zero is a valid value for this plain C struct
+        let mut buf: Self = unsafe { zeroed() };
+
+        buf.index = index;
+        buf.buf_type = V4L2_BUF_TYPE_VIDEO_CAPTURE;
+        buf.memory = V4L2_MEMORY_MMAP;
+
+        buf
+    }
+}
+
+ioctl_read!(vidioc_querycap, b'V', 0, Capability);
+ioctl_readwrite!(vidioc_s_fmt, b'V', 5, Format);
+ioctl_readwrite!(vidioc_reqbufs, b'V', 8, RequestBuffers);
+ioctl_readwrite!(vidioc_querybuf, b'V', 9, Buffer);
+ioctl_readwrite!(vidioc_qbuf, b'V', 15, Buffer);
+ioctl_readwrite!(vidioc_dqbuf, b'V', 17, Buffer);
+ioctl_write_ptr!(vidioc_streamon, b'V', 18, c_int);
+ioctl_write_ptr!(vidioc_streamoff, b'V', 19, c_int);
+
+/// A buffer shared with the kernel via mmap()
+struct Mapping {
+    ptr: *mut c_void,
+    len: usize,
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.ptr, self.len);
+        }
+    }
+}
+
+/// A V4L2 capture device in streaming mode
+pub(super) struct Device {
+    file: File,
+    buffers: Vec<Mapping>,
+}
+
+impl Device {
+    /// Open the capture device at `path` and start streaming MJPEG frames
+    ///
+    /// The camera may pick a different resolution if it does not support
+    /// the requested one.
+    pub(super) fn open(path: &str, width: u32, height: u32) -> Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
+            .with_context(|| format!("Failed to open {path}"))?;
+
+        let fd = file.as_raw_fd();
+
+        let mut cap: Capability = unsafe { zeroed() };
+        unsafe { vidioc_querycap(fd, &mut cap) }
+            .with_context(|| format!("{path} is not a V4L2 device"))?;
+
+        let caps = if cap.capabilities & V4L2_CAP_DEVICE_CAPS != 0 {
+            cap.device_caps
+        } else {
+            cap.capabilities
+        };
+
+        if caps & V4L2_CAP_VIDEO_CAPTURE == 0 || caps & V4L2_CAP_STREAMING == 0 {
+            bail!("{path} is not a video capture device");
+        }
+
+        let mut fmt: Format = unsafe { zeroed() };
+        fmt.buf_type = V4L2_BUF_TYPE_VIDEO_CAPTURE;
+        fmt.fmt.pix = PixFormat {
+            width,
+            height,
+            pixelformat: V4L2_PIX_FMT_MJPEG,
+            field: V4L2_FIELD_ANY,
+            ..Default::default()
+        };
+
+        unsafe { vidioc_s_fmt(fd, &mut fmt) }.context("Failed to set the capture format")?;
+
+        // The driver adjusts the format to something it supports
+        if unsafe { fmt.fmt.pix.pixelformat } != V4L2_PIX_FMT_MJPEG {
+            bail!("The camera does not support MJPEG");
+        }
+
+        let mut req: RequestBuffers = unsafe { zeroed() };
+        req.count = BUFFER_COUNT;
+        req.buf_type = V4L2_BUF_TYPE_VIDEO_CAPTURE;
+        req.memory = V4L2_MEMORY_MMAP;
+
+        unsafe { vidioc_reqbufs(fd, &mut req) }.context("Failed to request capture buffers")?;
+
+        if req.count < 2 {
+            bail!("Insufficient buffer memory on the camera");
+        }
+
+        let mut buffers = Vec::new();
+
+        for index in 0..req.count {
+            let mut buf = Buffer::new(index);
+
+            unsafe { vidioc_querybuf(fd, &mut buf) }.context("Failed to query capture buffer")?;
+
+            let len = buf.length as usize;
+
+            let ptr = unsafe {
+                libc::mmap(
+                    null_mut(),
+                    len,
+                    libc::PROT_READ | libc::PROT_WRITE,
+                    libc::MAP_SHARED,
+                    fd,
+                    buf.m.offset as libc::off_t,
+                )
+            };
+
+            if ptr == libc::MAP_FAILED {
+                return Err(Error::last_os_error()).context("Failed to map capture buffer");
+            }
+
+            buffers.push(Mapping { ptr, len });
+
+            unsafe { vidioc_qbuf(fd, &mut buf) }.context("Failed to queue capture buffer")?;
+        }
+
+        let buf_type = V4L2_BUF_TYPE_VIDEO_CAPTURE as c_int;
+        unsafe { vidioc_streamon(fd, &buf_type) }.context("Failed to start streaming")?;
+
+        Ok(Self { file, buffers })
+    }
+
+    /// Wait for the next frame and return it as JPEG
+    pub(super) fn capture(&mut self) -> Result<Vec<u8>> {
+        let fd = self.file.as_raw_fd();
+
+        let mut pollfd = libc::pollfd {
+            fd,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+
+        match unsafe { libc::poll(&mut pollfd, 1, FRAME_TIMEOUT.as_millis() as c_int) } {
+            -1 => return Err(Error::last_os_error()).context("Failed to wait for a frame"),
+            0 => bail!("Timeout while waiting for a frame"),
+            _ => {}
+        }
+
+        let mut buf = Buffer::new(0);
+
+        unsafe { vidioc_dqbuf(fd, &mut buf) }.context("Failed to dequeue frame")?;
+
+        let frame = self
+            .buffers
+            .get(buf.index as usize)
+            .map(|mapping| {
+                let len = (buf.bytesused as usize).min(mapping.len);
+                unsafe { from_raw_parts(mapping.ptr as *const u8, len) }.to_vec()
+            })
+            .ok_or_else(|| anyhow!("Got frame in unknown buffer {}", buf.index));
+
+        // Hand the buffer back to the driver, so it can be filled again
+        unsafe { vidioc_qbuf(fd, &mut buf) }.context("Failed to queue capture buffer")?;
+
+        if buf.flags & V4L2_BUF_FLAG_ERROR != 0 {
+            bail!("The camera delivered a corrupted frame");
+        }
+
+        frame
+    }
+}
+
+impl Drop for Device {
+    fn drop(&mut self) {
+        let buf_type = V4L2_BUF_TYPE_VIDEO_CAPTURE as c_int;
+
+        unsafe {
+            let _ = vidioc_streamoff(self.file.as_raw_fd(), &buf_type);
+        }
+    }
+}
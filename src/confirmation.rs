@@ -0,0 +1,277 @@
+// This file is part of tacd, the LXA TAC system daemon
+// Copyright (C) 2024 Pengutronix e.K.
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along
+// with this program; if not, write to the Free Software Foundation, Inc.,
+// 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+
+//! Require a confirmation on the TAC itself for destructive actions
+//!
+//! In shared labs a request sent via the API may e.g. turn off a DUT someone
+//! else is working with. If confirmations are required such requests only
+//! arm the action. It is performed once someone long presses the lower
+//! button on the TAC within `CONFIRM_TIMEOUT` and is dropped otherwise.
+//! Only one action can be armed at a time, arming another one replaces it.
+//!
+//! The setting itself can only be changed via the API in setup mode or by
+//! confirming the change on the TAC, so that it can not simply be turned
+//! off by whoever wants to perform an action.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use anyhow::Result;
+use async_std::future::timeout;
+use async_std::prelude::*;
+use async_std::sync::Arc;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::broker::{BrokerBuilder, Topic};
+use crate::measurement::Timestamp;
+use crate::watched_tasks::WatchedTasksBuilder;
+
+/// Time to confirm an armed action before it is dropped
+pub const CONFIRM_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub enum ConfirmableAction {
+    Install,
+    PowerOff,
+    /// Enable or disable the confirmations themselves
+    ChangeSetting,
+}
+
+/// An action that waits for a confirmation on the TAC
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ArmedAction {
+    pub id: u64,
+    pub action: ConfirmableAction,
+    /// The time the action was armed at
+    pub ts: Timestamp,
+    /// Seconds after `ts` at which the action is dropped
+    pub timeout: u64,
+}
+
+#[derive(Clone)]
+pub struct Confirmation {
+    pub required: Arc<Topic<bool>>,
+    pub armed: Arc<Topic<Option<ArmedAction>>>,
+    answer: Arc<Topic<(u64, bool)>>,
+    next_id: Arc<AtomicU64>,
+}
+
+impl Confirmation {
+    pub fn new(bb: &mut BrokerBuilder) -> Self {
+        Self {
+            // Changed via the request topic set up in handle_requests()
+            required: bb.topic(
+                "/v1/tac/confirmation/required",
+                true,
+                false,
+                true,
+                Some(false),
+                1,
+            ),
+            armed: bb.topic_ro("/v1/tac/confirmation/armed", Some(None)),
+            answer: Topic::anonymous(None),
+            next_id: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Handle requests to enable or disable the confirmations via the API
+    ///
+    /// Uses the "register a read-only and a write-only topic with the same
+    /// name" trick, so that only changes that were allowed are reported.
+    pub fn handle_requests(
+        &self,
+        bb: &mut BrokerBuilder,
+        wtb: &mut WatchedTasksBuilder,
+        setup_mode: Arc<Topic<bool>>,
+    ) -> Result<()> {
+        let request = bb.topic_wo::<bool>("/v1/tac/confirmation/required", None);
+        let (mut requests, _) = request.subscribe_unbounded();
+        let this = self.clone();
+
+        wtb.spawn_task("confirmation-required", async move {
+            while let Some(required) = requests.next().await {
+                let setup_mode = setup_mode.try_get().unwrap_or(false);
+                this.set_required(required, setup_mode).await;
+            }
+
+            Ok(())
+        })
+    }
+
+    /// Enable or disable the confirmations
+    ///
+    /// Outside of setup mode the change has to be confirmed on the TAC,
+    /// regardless of the current setting.
+    async fn set_required(&self, required: bool, setup_mode: bool) {
+        if self.required.try_get() == Some(required) {
+            return;
+        }
+
+        if !setup_mode && !self.arm(ConfirmableAction::ChangeSetting).await {
+            return;
+        }
+
+        info!("Setting required confirmations to {required}");
+
+        self.required.set(required);
+    }
+
+    /// Wait until `action` may be performed
+    ///
+    /// Returns immediately if no confirmation is required. Otherwise the
+    /// action is armed and `false` is returned if it was cancelled or not
+    /// confirmed in time.
+    pub async fn confirmed(&self, action: ConfirmableAction) -> bool {
+        if !self.required.try_get().unwrap_or(false) {
+            return true;
+        }
+
+        self.arm(action).await
+    }
+
+    /// Arm `action` and wait for it to be confirmed on the TAC
+    async fn arm(&self, action: ConfirmableAction) -> bool {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (mut answers, sub) = self.answer.clone().subscribe_unbounded();
+
+        info!("Armed {action:?} request, waiting for a confirmation on the TAC");
+
+        self.armed.set(Some(ArmedAction {
+            id,
+            action,
+            ts: Timestamp::now(),
+            timeout: CONFIRM_TIMEOUT.as_secs(),
+        }));
+
+        let answer = async {
+            while let Some((answer_id, confirmed)) = answers.next().await {
+                if answer_id == id {
+                    return confirmed;
+                }
+            }
+
+            false
+        };
+
+        let confirmed = timeout(CONFIRM_TIMEOUT, answer).await.unwrap_or(false);
+
+        sub.unsubscribe();
+
+        // Do not disarm an action that replaced this one in the meantime
+        self.armed.modify(|armed| match armed {
+            Some(Some(armed)) if armed.id == id => Some(None),
+            _ => None,
+        });
+
+        if !confirmed {
+            warn!("Dropping {action:?} request, it was not confirmed");
+        }
+
+        confirmed
+    }
+
+    /// Confirm or cancel the currently armed action (if any)
+    pub fn answer(&self, confirm: bool) {
+        if let Some(Some(armed)) = self.armed.try_get() {
+            self.answer.set((armed.id, confirm));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_std::prelude::*;
+    use async_std::task::block_on;
+    use futures::join;
+
+    use super::{ConfirmableAction, Confirmation};
+    use crate::broker::BrokerBuilder;
+
+    /// Answer the next action armed via `confirmation`
+    async fn answer(confirmation: &Confirmation, confirm: bool) {
+        let (mut armed, _) = confirmation.armed.clone().subscribe_unbounded();
+
+        while let Some(action) = armed.next().await {
+            if action.is_some() {
+                confirmation.answer(confirm);
+                break;
+            }
+        }
+    }
+
+    #[test]
+    fn confirmation() {
+        let mut bb = BrokerBuilder::new();
+        let confirmation = Confirmation::new(&mut bb);
+
+        block_on(async {
+            println!("Actions are performed right away by default");
+            assert!(confirmation.confirmed(ConfirmableAction::PowerOff).await);
+
+            confirmation.required.set(true);
+
+            println!("Confirmed actions are performed");
+            let (confirmed, _) = join!(
+                confirmation.confirmed(ConfirmableAction::Install),
+                answer(&confirmation, true)
+            );
+            assert!(confirmed);
+            assert!(confirmation.armed.try_get().unwrap().is_none());
+
+            println!("Cancelled actions are not");
+            let (confirmed, _) = join!(
+                confirmation.confirmed(ConfirmableAction::PowerOff),
+                answer(&confirmation, false)
+            );
+            assert!(!confirmed);
+            assert!(confirmation.armed.try_get().unwrap().is_none());
+        });
+    }
+
+    #[test]
+    fn change_setting() {
+        let mut bb = BrokerBuilder::new();
+        let confirmation = Confirmation::new(&mut bb);
+
+        block_on(async {
+            println!("The setting is changed right away in setup mode");
+            confirmation.set_required(true, true).await;
+            assert_eq!(confirmation.required.try_get(), Some(true));
+
+            println!("Changes outside of setup mode have to be confirmed");
+            join!(
+                confirmation.set_required(false, false),
+                answer(&confirmation, false)
+            );
+            assert_eq!(confirmation.required.try_get(), Some(true));
+
+            join!(
+                confirmation.set_required(false, false),
+                answer(&confirmation, true)
+            );
+            assert_eq!(confirmation.required.try_get(), Some(false));
+
+            println!("Even when no confirmations are required (yet)");
+            join!(
+                confirmation.set_required(true, false),
+                answer(&confirmation, false)
+            );
+            assert_eq!(confirmation.required.try_get(), Some(false));
+        });
+    }
+}
@@ -0,0 +1,333 @@
+// This file is part of tacd, the LXA TAC system daemon
+// Copyright (C) 2024 Pengutronix e.K.
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along
+// with this program; if not, write to the Free Software Foundation, Inc.,
+// 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+
+//! Guard against conflicting requests made on the LCD and via the API
+//!
+//! If someone switches an output on the LCD while someone else requests the
+//! opposite via the API, the result depends on which request happens to be
+//! processed last. Instead, a request that reverts a request made from the
+//! other source within the conflict `window` is held back. It is performed
+//! once either side confirms it within `CONFIRM_TIMEOUT` and is dropped
+//! otherwise.
+//! Only one request can be held at a time, holding another one replaces it.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use async_std::future::timeout;
+use async_std::prelude::*;
+use async_std::sync::Arc;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::broker::{BrokerBuilder, Topic};
+use crate::confirmation::CONFIRM_TIMEOUT;
+use crate::measurement::Timestamp;
+use crate::watched_tasks::WatchedTasksBuilder;
+
+/// Opposing requests within this many seconds are considered a conflict
+const DEFAULT_WINDOW_S: u64 = 5;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub enum RequestSource {
+    Lcd,
+    Api,
+}
+
+/// A request to turn an output on or off
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub struct SourcedRequest {
+    pub source: RequestSource,
+    pub on: bool,
+}
+
+/// A request that is held back, as it conflicts with a recent request
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Conflict {
+    pub id: u64,
+    /// The output both requests were made for, e.g. "DUT power"
+    pub output: String,
+    /// The recent request, which was already performed
+    pub applied: SourcedRequest,
+    /// The request that waits for a confirmation
+    pub held: SourcedRequest,
+    /// The time the request was held back at
+    pub ts: Timestamp,
+    /// Seconds after `ts` at which the held request is dropped
+    pub timeout: u64,
+}
+
+type RecentRequests = HashMap<String, (SourcedRequest, Instant)>;
+
+/// Get the recent request for `output` that `req` conflicts with (if any)
+fn conflicting(
+    recent: &RecentRequests,
+    output: &str,
+    req: SourcedRequest,
+    window: Duration,
+    now: Instant,
+) -> Option<SourcedRequest> {
+    let (prev, ts) = recent.get(output)?;
+
+    let conflicts = prev.source != req.source
+        && prev.on != req.on
+        && now.saturating_duration_since(*ts) < window;
+
+    conflicts.then_some(*prev)
+}
+
+#[derive(Clone)]
+pub struct ConflictGuard {
+    pub window: Arc<Topic<u64>>,
+    pub pending: Arc<Topic<Option<Conflict>>>,
+    answer: Arc<Topic<(u64, bool)>>,
+    recent: Arc<Mutex<RecentRequests>>,
+    next_id: Arc<AtomicU64>,
+}
+
+impl ConflictGuard {
+    pub fn new(bb: &mut BrokerBuilder, wtb: &mut WatchedTasksBuilder) -> Result<Self> {
+        let guard = Self {
+            window: bb.topic(
+                "/v1/tac/conflicts/window",
+                true,
+                true,
+                true,
+                Some(DEFAULT_WINDOW_S),
+                1,
+            ),
+            pending: bb.topic_ro("/v1/tac/conflicts/pending", Some(None)),
+            answer: Topic::anonymous(None),
+            recent: Arc::new(Mutex::new(HashMap::new())),
+            next_id: Arc::new(AtomicU64::new(0)),
+        };
+
+        // Unlike confirmations of destructive actions the API user may
+        // resolve a conflict as well, as both sides are equally involved.
+        let api_answer = bb.topic_wo::<bool>("/v1/tac/conflicts/answer", None);
+        let (mut api_answers, _) = api_answer.subscribe_unbounded();
+        let guard_task = guard.clone();
+
+        wtb.spawn_task("conflicts-answer", async move {
+            while let Some(confirm) = api_answers.next().await {
+                guard_task.answer(confirm);
+            }
+
+            Ok(())
+        })?;
+
+        Ok(guard)
+    }
+
+    /// Wait until `req` for `output` may be performed
+    ///
+    /// Returns immediately if `req` does not conflict with a recent request.
+    /// Otherwise the request is held and `false` is returned if it was
+    /// cancelled or not confirmed in time.
+    pub async fn allowed(&self, output: &str, req: SourcedRequest) -> bool {
+        let window = Duration::from_secs(self.window.try_get().unwrap_or(DEFAULT_WINDOW_S));
+        let applied = conflicting(
+            &self.recent.lock().unwrap(),
+            output,
+            req,
+            window,
+            Instant::now(),
+        );
+
+        let allowed = match applied {
+            Some(applied) => self.confirmed(output, applied, req).await,
+            None => true,
+        };
+
+        if allowed {
+            self.recent
+                .lock()
+                .unwrap()
+                .insert(output.to_string(), (req, Instant::now()));
+        }
+
+        allowed
+    }
+
+    async fn confirmed(&self, output: &str, applied: SourcedRequest, held: SourcedRequest) -> bool {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (mut answers, sub) = self.answer.clone().subscribe_unbounded();
+
+        info!(
+            "Holding request via {:?} for {output}, it conflicts with a recent request via {:?}",
+            held.source, applied.source
+        );
+
+        // The request that was held before (if any) is dropped
+        self.answer(false);
+
+        self.pending.set(Some(Conflict {
+            id,
+            output: output.to_string(),
+            applied,
+            held,
+            ts: Timestamp::now(),
+            timeout: CONFIRM_TIMEOUT.as_secs(),
+        }));
+
+        let answer = async {
+            while let Some((answer_id, confirmed)) = answers.next().await {
+                if answer_id == id {
+                    return confirmed;
+                }
+            }
+
+            false
+        };
+
+        let confirmed = timeout(CONFIRM_TIMEOUT, answer).await.unwrap_or(false);
+
+        sub.unsubscribe();
+
+        // Do not clear a conflict that replaced this one in the meantime
+        self.pending.modify(|pending| match pending {
+            Some(Some(pending)) if pending.id == id => Some(None),
+            _ => None,
+        });
+
+        if !confirmed {
+            warn!("Dropping conflicting request for {output}, it was not confirmed");
+        }
+
+        confirmed
+    }
+
+    /// Confirm or cancel the currently held request (if any)
+    pub fn answer(&self, confirm: bool) {
+        if let Some(Some(pending)) = self.pending.try_get() {
+            self.answer.set((pending.id, confirm));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::time::{Duration, Instant};
+
+    use async_std::prelude::*;
+    use async_std::task::block_on;
+    use futures::join;
+
+    use super::{conflicting, ConflictGuard, RequestSource, SourcedRequest};
+    use crate::broker::BrokerBuilder;
+    use crate::watched_tasks::WatchedTasksBuilder;
+
+    const LCD_ON: SourcedRequest = SourcedRequest {
+        source: RequestSource::Lcd,
+        on: true,
+    };
+    const API_ON: SourcedRequest = SourcedRequest {
+        source: RequestSource::Api,
+        on: true,
+    };
+    const API_OFF: SourcedRequest = SourcedRequest {
+        source: RequestSource::Api,
+        on: false,
+    };
+    const LCD_OFF: SourcedRequest = SourcedRequest {
+        source: RequestSource::Lcd,
+        on: false,
+    };
+
+    /// Answer the next request held via `guard`
+    async fn answer(guard: &ConflictGuard, confirm: bool) {
+        let (mut pending, _) = guard.pending.clone().subscribe_unbounded();
+
+        while let Some(conflict) = pending.next().await {
+            if conflict.is_some() {
+                guard.answer(confirm);
+                break;
+            }
+        }
+    }
+
+    #[test]
+    fn detection() {
+        let window = Duration::from_secs(5);
+        let start = Instant::now();
+
+        let mut recent = HashMap::new();
+        recent.insert("DUT power".to_string(), (LCD_ON, start));
+
+        println!("Opposing requests from different sources conflict");
+        assert_eq!(
+            conflicting(&recent, "DUT power", API_OFF, window, start),
+            Some(LCD_ON)
+        );
+
+        println!("Requests for other outputs do not");
+        assert_eq!(
+            conflicting(&recent, "USB port1", API_OFF, window, start),
+            None
+        );
+
+        println!("Neither do requests that agree or come from the same source");
+        assert_eq!(
+            conflicting(&recent, "DUT power", API_ON, window, start),
+            None
+        );
+        assert_eq!(
+            conflicting(&recent, "DUT power", LCD_OFF, window, start),
+            None
+        );
+
+        println!("Only requests within the window conflict");
+        assert_eq!(
+            conflicting(&recent, "DUT power", API_OFF, window, start + window),
+            None
+        );
+        assert_eq!(
+            conflicting(&recent, "DUT power", API_OFF, Duration::ZERO, start),
+            None
+        );
+    }
+
+    #[test]
+    fn conflicts() {
+        let mut bb = BrokerBuilder::new();
+        let mut wtb = WatchedTasksBuilder::new();
+        let guard = ConflictGuard::new(&mut bb, &mut wtb).unwrap();
+
+        block_on(async {
+            println!("Requests without a conflict are performed right away");
+            assert!(guard.allowed("DUT power", LCD_ON).await);
+
+            println!("Conflicting requests are performed once confirmed");
+            let (allowed, _) = join!(guard.allowed("DUT power", API_OFF), answer(&guard, true));
+            assert!(allowed);
+            assert!(guard.pending.try_get().unwrap().is_none());
+
+            println!("And dropped if cancelled");
+            let (allowed, _) = join!(guard.allowed("DUT power", LCD_ON), answer(&guard, false));
+            assert!(!allowed);
+            assert!(guard.pending.try_get().unwrap().is_none());
+
+            println!("Conflicts can be disabled");
+            guard.window.set(0);
+            assert!(guard.allowed("DUT power", LCD_ON).await);
+        });
+    }
+}
@@ -0,0 +1,172 @@
+// This file is part of tacd, the LXA TAC system daemon
+// Copyright (C) 2024 Pengutronix e.K.
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along
+// with this program; if not, write to the Free Software Foundation, Inc.,
+// 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+
+use std::any::Any;
+use std::backtrace::Backtrace;
+use std::fs::{create_dir_all, rename, File};
+use std::panic::{self, Location};
+use std::time::SystemTime;
+
+use anyhow::Result;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use serde_json::{from_reader, to_writer_pretty};
+
+use crate::broker::BrokerBuilder;
+use crate::fs_root;
+use crate::watched_tasks::panic_message;
+
+const CRASH_REPORT_PATH: &str = "/srv/tacd/last_crash.json";
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub enum CrashKind {
+    Panic,
+    Error,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct CrashReport {
+    /// Seconds since Unix Epoch 0
+    pub timestamp: u64,
+    pub tacd_version: String,
+    pub kind: CrashKind,
+    /// Name of the task or thread the crash happened in (if known)
+    pub origin: Option<String>,
+    pub message: String,
+    pub location: Option<String>,
+    pub backtrace: Option<String>,
+}
+
+/// Get the name of the async task or thread we are currently running in
+fn current_origin() -> Option<String> {
+    async_std::task::try_current()
+        .and_then(|task| task.name().map(str::to_owned))
+        .or_else(|| std::thread::current().name().map(str::to_owned))
+}
+
+impl CrashReport {
+    fn new(kind: CrashKind, message: String) -> Self {
+        let timestamp = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|t| t.as_secs())
+            .unwrap_or(0);
+
+        Self {
+            timestamp,
+            tacd_version: env!("VERSION_STRING").to_string(),
+            kind,
+            origin: current_origin(),
+            message,
+            location: None,
+            backtrace: None,
+        }
+    }
+
+    fn from_panic(payload: &(dyn Any + Send), location: Option<&Location>) -> Self {
+        let mut report = Self::new(CrashKind::Panic, panic_message(payload).to_string());
+
+        report.location = location.map(|l| l.to_string());
+        report.backtrace = Some(Backtrace::force_capture().to_string());
+
+        report
+    }
+
+    /// Write the report to disk
+    ///
+    /// This has to be done synchronously, as the tacd is about to go down
+    /// and the broker persistence task may not get a chance to run anymore.
+    fn save(&self) -> Result<()> {
+        let path = fs_root::path(CRASH_REPORT_PATH);
+        let path_tmp = path.with_extension("tmp");
+
+        if let Some(parent) = path.parent() {
+            create_dir_all(parent)?;
+        }
+
+        {
+            let fd = File::create(&path_tmp)?;
+            to_writer_pretty(&fd, self)?;
+            fd.sync_all()?;
+        }
+
+        rename(path_tmp, path)?;
+
+        Ok(())
+    }
+
+    fn load() -> Result<Option<Self>> {
+        let path = fs_root::path(CRASH_REPORT_PATH);
+
+        if !path.is_file() {
+            return Ok(None);
+        }
+
+        Ok(Some(from_reader(File::open(path)?)?))
+    }
+}
+
+/// Record panics (including a backtrace) in a crash report file
+///
+/// The report is kept across restarts of the tacd, so that the cause of
+/// a crash can be diagnosed after systemd restarted the service.
+/// The previously installed panic hook (which prints the panic to stderr)
+/// is still called afterwards.
+pub fn install_panic_hook() {
+    let default_hook = panic::take_hook();
+
+    panic::set_hook(Box::new(move |info| {
+        if let Err(e) = CrashReport::from_panic(info.payload(), info.location()).save() {
+            eprintln!("Failed to save crash report: {e}");
+        }
+
+        default_hook(info)
+    }));
+}
+
+/// Record an error that ended the tacd in the crash report file
+pub fn record_error(err: &anyhow::Error) {
+    // The debug representation includes the chain of contexts and
+    // a backtrace (if enabled via RUST_BACKTRACE).
+    let report = CrashReport::new(CrashKind::Error, format!("{err:?}"));
+
+    if let Err(e) = report.save() {
+        error!("Failed to save crash report: {e}");
+    }
+}
+
+/// Expose the crash report of the last crash (if any)
+pub fn register(bb: &mut BrokerBuilder) {
+    let last_crash = match CrashReport::load() {
+        Ok(report) => {
+            if let Some(report) = &report {
+                info!(
+                    "The tacd previously crashed in {}: {}",
+                    report.origin.as_deref().unwrap_or("<unknown>"),
+                    report.message
+                );
+            }
+
+            report
+        }
+        Err(e) => {
+            error!("Failed to load crash report: {e}");
+            None
+        }
+    };
+
+    bb.topic_ro("/v1/tac/daemon/last_crash", Some(last_crash));
+}
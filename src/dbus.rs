@@ -15,9 +15,23 @@
 // with this program; if not, write to the Free Software Foundation, Inc.,
 // 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
 
+use std::future::Future;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use anyhow::anyhow;
+use async_std::future::timeout;
 use async_std::sync::Arc;
+use async_std::task::sleep;
+use futures_lite::future::race;
+use futures_util::future::Either;
+use futures_util::{FutureExt, StreamExt};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
 
 use crate::broker::{BrokerBuilder, Topic};
+use crate::confirmation::Confirmation;
+use crate::dut_power::OutputState;
 use crate::led::BlinkPattern;
 use crate::watched_tasks::WatchedTasksBuilder;
 
@@ -45,27 +59,231 @@ mod zb {
             Ok(Connection)
         }
     }
+
+    pub(super) async fn ping(_conn: &Connection) -> Result<()> {
+        Ok(())
+    }
 }
 
 #[cfg(not(feature = "demo_mode"))]
 mod zb {
     pub(super) use zbus::Result;
     pub use zbus::{Connection, ConnectionBuilder};
+
+    /// Check if the bus is still responding to requests
+    pub(super) async fn ping(conn: &Connection) -> Result<()> {
+        zbus::fdo::DBusProxy::new(conn).await?.get_id().await?;
+
+        Ok(())
+    }
 }
 
-use zb::{Connection, ConnectionBuilder, Result};
+use zb::{ping, Connection, ConnectionBuilder, Result};
 
 pub mod hostname;
+pub mod interlock;
 pub mod networkmanager;
 pub mod rauc;
 pub mod systemd;
 pub mod tacd;
+pub mod timedate;
+
+#[cfg(test)]
+mod mock;
 
 pub use self::systemd::Systemd;
 pub use hostname::Hostname;
+pub use interlock::Interlock;
 pub use networkmanager::Network;
 pub use rauc::Rauc;
 pub use tacd::Tacd;
+pub use timedate::Timedate;
+
+// Check if the bus is still alive every now and then and reconnect with
+// an exponential backoff if it is not.
+const PING_INTERVAL: Duration = Duration::from_secs(10);
+const PING_TIMEOUT: Duration = Duration::from_secs(5);
+const RECONNECT_INTERVAL_MIN: Duration = Duration::from_secs(1);
+const RECONNECT_INTERVAL_MAX: Duration = Duration::from_secs(60);
+
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+pub struct BusState {
+    pub connected: bool,
+    /// Number of times the connection had to be re-established
+    pub reconnects: u32,
+    pub last_error: Option<String>,
+}
+
+async fn connect() -> anyhow::Result<Connection> {
+    let tacd = Tacd::new();
+
+    let conn_builder = ConnectionBuilder::system()?.name("de.pengutronix.tacd")?;
+
+    Ok(tacd.serve(conn_builder).build().await?)
+}
+
+async fn is_alive(conn: &Connection) -> bool {
+    matches!(timeout(PING_TIMEOUT, ping(conn)).await, Ok(Ok(())))
+}
+
+/// The connection to the DBus system bus
+///
+/// The connection is supervised and re-established (e.g. if the
+/// dbus-daemon was restarted), so it should not be held onto for longer
+/// than necessary.
+#[derive(Clone)]
+pub struct SystemBus {
+    conn: Arc<Mutex<Arc<Connection>>>,
+    /// Incremented whenever `conn` is replaced
+    generation: Arc<Topic<u32>>,
+}
+
+impl SystemBus {
+    fn new(
+        bb: &mut BrokerBuilder,
+        wtb: &mut WatchedTasksBuilder,
+        conn: Connection,
+    ) -> anyhow::Result<Self> {
+        let conn = Arc::new(Mutex::new(Arc::new(conn)));
+        let generation = Topic::anonymous(Some(0));
+        let state = bb.topic_ro(
+            "/v1/tac/daemon/dbus",
+            Some(BusState {
+                connected: true,
+                reconnects: 0,
+                last_error: None,
+            }),
+        );
+
+        let conn_task = conn.clone();
+        let generation_task = generation.clone();
+
+        wtb.spawn_task("dbus-supervisor", async move {
+            loop {
+                sleep(PING_INTERVAL).await;
+
+                let current = conn_task.lock().unwrap().clone();
+
+                if is_alive(&current).await {
+                    continue;
+                }
+
+                warn!("Lost connection to the DBus system bus. Reconnecting");
+
+                state.modify(|prev| {
+                    let mut state = prev?;
+                    state.connected = false;
+                    state.last_error = Some("Bus did not respond".to_string());
+                    Some(state)
+                });
+
+                let mut retry_interval = RECONNECT_INTERVAL_MIN;
+
+                let new_conn = loop {
+                    match connect().await {
+                        Ok(conn) => break conn,
+                        Err(e) => {
+                            warn!(
+                                "Failed to reconnect to the DBus system bus: {e}. Retrying in {}s.",
+                                retry_interval.as_secs()
+                            );
+
+                            state.modify(|prev| {
+                                let mut state = prev?;
+                                state.last_error = Some(e.to_string());
+                                Some(state)
+                            });
+                        }
+                    }
+
+                    sleep(retry_interval).await;
+                    retry_interval = (retry_interval * 2).min(RECONNECT_INTERVAL_MAX);
+                };
+
+                info!("Reconnected to the DBus system bus");
+
+                // This restarts all tasks spawned via spawn_task() below
+                *conn_task.lock().unwrap() = Arc::new(new_conn);
+                generation_task.modify(|prev| Some(prev.unwrap_or_default().wrapping_add(1)));
+
+                state.modify(|prev| {
+                    let mut state = prev?;
+                    state.connected = true;
+                    state.reconnects += 1;
+                    Some(state)
+                });
+            }
+        })?;
+
+        Ok(Self { conn, generation })
+    }
+
+    /// Get the current connection to the bus
+    pub fn connection(&self) -> Arc<Connection> {
+        self.conn.lock().unwrap().clone()
+    }
+
+    /// Spawn a task that uses the bus, e.g. to subscribe to property changes
+    ///
+    /// The task is restarted with the new connection whenever the connection
+    /// to the bus is re-established, so it should create its proxies and
+    /// subscriptions from scratch.
+    /// If the task ends while the bus is still alive the result is returned,
+    /// just like for tasks spawned via `WatchedTasksBuilder::spawn_task()`.
+    pub fn spawn_task<S, F, Fut>(
+        &self,
+        wtb: &mut WatchedTasksBuilder,
+        name: S,
+        mut task: F,
+    ) -> anyhow::Result<()>
+    where
+        S: Into<String>,
+        F: FnMut(Arc<Connection>) -> Fut + Send + 'static,
+        Fut: Future<Output = anyhow::Result<()>> + Send + 'static,
+    {
+        let name = name.into();
+        let bus = self.clone();
+        let (mut generations, _) = self.generation.clone().subscribe_unbounded();
+
+        wtb.spawn_task(name.clone(), async move {
+            // The first element is the currently retained generation
+            generations
+                .next()
+                .await
+                .ok_or_else(|| anyhow!("DBus connection went away"))?;
+
+            loop {
+                let conn = bus.connection();
+
+                let ev = race(
+                    task(conn.clone()).map(Either::Left),
+                    generations.next().map(Either::Right),
+                )
+                .await;
+
+                match ev {
+                    Either::Left(res) => {
+                        if is_alive(&conn).await {
+                            break res;
+                        }
+
+                        if let Err(e) = res {
+                            warn!("Task {name} failed due to a lost DBus connection: {e}");
+                        }
+
+                        // Wait for the supervisor to re-establish the connection
+                        generations
+                            .next()
+                            .await
+                            .ok_or_else(|| anyhow!("DBus connection went away"))?;
+                    }
+                    Either::Right(Some(_)) => {}
+                    Either::Right(None) => break Err(anyhow!("DBus connection went away")),
+                }
+            }
+        })
+    }
+}
 
 /// Bunch together everything that uses a DBus system connection here, even
 /// though it is conceptionally independent
@@ -74,6 +292,7 @@ pub struct DbusSession {
     pub network: Network,
     pub rauc: Rauc,
     pub systemd: Systemd,
+    pub timedate: Timedate,
 }
 
 impl DbusSession {
@@ -82,18 +301,37 @@ impl DbusSession {
         wtb: &mut WatchedTasksBuilder,
         led_dut: Arc<Topic<BlinkPattern>>,
         led_uplink: Arc<Topic<BlinkPattern>>,
+        setup_mode: Arc<Topic<bool>>,
+        dut_pwr_state: Arc<Topic<OutputState>>,
+        confirmation: Confirmation,
     ) -> anyhow::Result<Self> {
-        let tacd = Tacd::new();
+        let bus = SystemBus::new(bb, wtb, connect().await?)?;
+
+        let hostname = Hostname::new(bb, wtb, &bus, setup_mode.clone())?;
+        let network = Network::new(bb, wtb, &bus, led_dut, led_uplink, setup_mode)?;
+        // Keep updates and reboots from interrupting the DUT
+        let interlock = Interlock::new(bb, dut_pwr_state.clone());
 
-        let conn_builder = ConnectionBuilder::system()?.name("de.pengutronix.tacd")?;
+        let rauc = Rauc::new(bb, wtb, &bus, interlock.clone(), confirmation)?;
+        let systemd = Systemd::new(bb, wtb, &bus, interlock).await?;
+        let timedate = Timedate::new(bb, wtb, &bus, network.dhcp_timezone.clone())?;
 
-        let conn = Arc::new(tacd.serve(conn_builder).build().await?);
+        // Updates are only marked good once the TAC is known to work
+        // with them, which requires information from the other services.
+        rauc.handle_rollout(
+            wtb,
+            &bus,
+            network.uplink_interface.clone(),
+            dut_pwr_state,
+            systemd.reboot.clone(),
+        )?;
 
         Ok(Self {
-            hostname: Hostname::new(bb, wtb, &conn)?,
-            network: Network::new(bb, wtb, &conn, led_dut, led_uplink)?,
-            rauc: Rauc::new(bb, wtb, &conn)?,
-            systemd: Systemd::new(bb, wtb, &conn).await?,
+            hostname,
+            network,
+            rauc,
+            systemd,
+            timedate,
         })
     }
 }
@@ -15,65 +15,203 @@
 // with this program; if not, write to the Free Software Foundation, Inc.,
 // 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
 
+use std::future::Future;
+
 use anyhow::Result;
+use async_std::prelude::*;
 use async_std::sync::Arc;
+use log::warn;
 
-#[cfg(not(feature = "demo_mode"))]
-use async_std::stream::StreamExt;
-
-#[cfg(not(feature = "demo_mode"))]
-use zbus::Connection;
-
+use super::SystemBus;
 use crate::broker::{BrokerBuilder, Topic};
+use crate::http_server::auth_enabled;
 use crate::watched_tasks::WatchedTasksBuilder;
 
 mod hostnamed;
 
+// The kernel limits hostnames to 64 bytes (HOST_NAME_MAX), which is stricter
+// than the 253 characters allowed for fully qualified domain names.
+const HOSTNAME_MAX_LEN: usize = 64;
+const LABEL_MAX_LEN: usize = 63;
+
 pub struct Hostname {
     pub hostname: Arc<Topic<String>>,
 }
 
+/// Check if a hostname is valid according to RFC 1123
+///
+/// The hostname may consist of multiple dot-separated labels, each of which
+/// may only contain ASCII letters, digits and hyphens and must neither start
+/// nor end with a hyphen.
+fn is_valid_hostname(hostname: &str) -> bool {
+    if hostname.is_empty() || hostname.len() > HOSTNAME_MAX_LEN {
+        return false;
+    }
+
+    hostname.split('.').all(|label| {
+        !label.is_empty()
+            && label.len() <= LABEL_MAX_LEN
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+            && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+    })
+}
+
 impl Hostname {
+    /// Subscribe to hostname change requests from the web
+    ///
+    /// Use the "register a read-only and a write-only topic with the same name
+    /// to perform validation" trick that is also used with the DUT power endpoint.
+    /// Renaming the TAC is only allowed while it is in setup mode or if
+    /// authentication is enabled, in which case the HTTP server only lets
+    /// authenticated clients write the topic.
+    /// Only requests containing valid hostnames are passed on to `set_fn`.
+    fn handle_change_requests<F, Fut>(
+        bb: &mut BrokerBuilder,
+        wtb: &mut WatchedTasksBuilder,
+        setup_mode: Arc<Topic<bool>>,
+        set_fn: F,
+    ) -> Result<()>
+    where
+        F: Fn(String) -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send,
+    {
+        let (mut requests, _) = bb
+            .topic_wo::<String>("/v1/tac/network/hostname", None)
+            .subscribe_unbounded();
+
+        wtb.spawn_task("hostname-change-request", async move {
+            while let Some(hostname) = requests.next().await {
+                if !setup_mode.try_get().unwrap_or(false) && !auth_enabled() {
+                    warn!(
+                        "Refusing hostname \"{hostname}\", not in setup mode and no authentication"
+                    );
+                    continue;
+                }
+
+                if !is_valid_hostname(&hostname) {
+                    warn!("Refusing to change hostname to invalid value \"{hostname}\"");
+                    continue;
+                }
+
+                set_fn(hostname).await;
+            }
+
+            Ok(())
+        })
+    }
+
     #[cfg(feature = "demo_mode")]
-    pub fn new<C>(
+    pub fn new(
         bb: &mut BrokerBuilder,
-        _wtb: &mut WatchedTasksBuilder,
-        _conn: C,
+        wtb: &mut WatchedTasksBuilder,
+        _bus: &SystemBus,
+        setup_mode: Arc<Topic<bool>>,
     ) -> Result<Self> {
-        Ok(Self {
-            hostname: bb.topic_ro("/v1/tac/network/hostname", Some("lxatac".into())),
-        })
+        let hostname = bb.topic_ro("/v1/tac/network/hostname", Some("lxatac".into()));
+
+        let hostname_task = hostname.clone();
+        Self::handle_change_requests(bb, wtb, setup_mode, move |h| {
+            hostname_task.set(h);
+            async {}
+        })?;
+
+        Ok(Self { hostname })
     }
 
     #[cfg(not(feature = "demo_mode"))]
     pub fn new(
         bb: &mut BrokerBuilder,
         wtb: &mut WatchedTasksBuilder,
-        conn: &Arc<Connection>,
+        bus: &SystemBus,
+        setup_mode: Arc<Topic<bool>>,
     ) -> Result<Self> {
         let hostname = bb.topic_ro("/v1/tac/network/hostname", None);
 
-        let conn = conn.clone();
         let hostname_topic = hostname.clone();
 
-        wtb.spawn_task("hostname-update", async move {
-            let proxy = hostnamed::HostnameProxy::new(&conn).await.unwrap();
+        bus.spawn_task(wtb, "hostname-update", move |conn| {
+            let hostname_topic = hostname_topic.clone();
 
-            let mut stream = proxy.receive_hostname_changed().await;
+            async move {
+                let proxy = hostnamed::HostnameProxy::new(&conn).await?;
 
-            if let Ok(h) = proxy.hostname().await {
-                hostname_topic.set(h);
-            }
+                let mut stream = proxy.receive_hostname_changed().await;
 
-            while let Some(v) = stream.next().await {
-                if let Ok(h) = v.get().await {
+                if let Ok(h) = proxy.hostname().await {
                     hostname_topic.set(h);
                 }
+
+                while let Some(v) = stream.next().await {
+                    if let Ok(h) = v.get().await {
+                        hostname_topic.set(h);
+                    }
+                }
+
+                Ok(())
             }
+        })?;
 
-            Ok(())
+        // The new hostname is not set on the topic directly.
+        // Instead hostnamed will notify us about the change via the
+        // "hostname-update" task above, which in turn updates everything
+        // that depends on the hostname (like the setup screen).
+        // The mDNS announcement is handled by avahi, which picks up
+        // hostname changes on its own.
+        let bus = bus.clone();
+        Self::handle_change_requests(bb, wtb, setup_mode, move |h| {
+            let conn = bus.connection();
+
+            async move {
+                let res = match hostnamed::HostnameProxy::new(&conn).await {
+                    Ok(proxy) => proxy.set_static_hostname(&h, false).await,
+                    Err(e) => Err(e),
+                };
+
+                if let Err(e) = res {
+                    warn!("Failed to set hostname to \"{h}\": {e}");
+                }
+            }
         })?;
 
         Ok(Self { hostname })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::is_valid_hostname;
+
+    #[test]
+    fn hostname_validation() {
+        let too_long = "a".repeat(65);
+
+        let valid = [
+            "lxatac",
+            "lxatac-12345",
+            "LXATAC-1",
+            "tac.lab.example.com",
+            "1tac",
+        ];
+
+        let invalid = [
+            "",
+            "-lxatac",
+            "lxatac-",
+            "lxa_tac",
+            "lxa tac",
+            "lxatac.",
+            "lxa..tac",
+            "lxätac",
+            too_long.as_str(),
+        ];
+
+        for hostname in valid {
+            assert!(is_valid_hostname(hostname), "{hostname} should be valid");
+        }
+
+        for hostname in invalid {
+            assert!(!is_valid_hostname(hostname), "{hostname} should be invalid");
+        }
+    }
+}
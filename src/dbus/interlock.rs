@@ -0,0 +1,125 @@
+// This file is part of tacd, the LXA TAC system daemon
+// Copyright (C) 2024 Pengutronix e.K.
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along
+// with this program; if not, write to the Free Software Foundation, Inc.,
+// 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+
+//! Prevent installing updates and rebooting while the DUT is powered
+//!
+//! Rebooting the TAC in the middle of a test run turns off the DUT and
+//! loses the test results. Requests to do so are refused while the
+//! interlock is enabled and the DUT is powered, unless they are sent to the
+//! explicit override topics.
+
+use async_std::sync::Arc;
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::broker::{BrokerBuilder, Topic};
+use crate::dut_power::OutputState;
+use crate::measurement::Timestamp;
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub enum InterlockedAction {
+    Install,
+    Reboot,
+}
+
+/// The last request that was refused because of the interlock
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct BlockedRequest {
+    pub action: InterlockedAction,
+    pub reason: String,
+    pub ts: Timestamp,
+}
+
+#[derive(Clone)]
+pub struct Interlock {
+    enabled: Arc<Topic<bool>>,
+    blocked: Arc<Topic<Option<BlockedRequest>>>,
+    dut_pwr: Arc<Topic<OutputState>>,
+}
+
+/// Why a request should be refused (if it should be)
+fn block_reason(enabled: bool, dut_pwr: Option<OutputState>) -> Option<&'static str> {
+    if !enabled {
+        return None;
+    }
+
+    match dut_pwr? {
+        OutputState::On | OutputState::Changing | OutputState::Probing => {
+            Some("The DUT is powered")
+        }
+        _ => None,
+    }
+}
+
+impl Interlock {
+    pub fn new(bb: &mut BrokerBuilder, dut_pwr: Arc<Topic<OutputState>>) -> Self {
+        Self {
+            enabled: bb.topic("/v1/tac/update/interlock", true, true, true, Some(true), 1),
+            blocked: bb.topic_ro("/v1/tac/update/interlock/blocked", Some(None)),
+            dut_pwr,
+        }
+    }
+
+    /// Check if `action` may be performed right now
+    ///
+    /// Refused requests are reported via the `blocked` topic, so that the
+    /// user can be notified about them.
+    pub fn permits(&self, action: InterlockedAction, overridden: bool) -> bool {
+        let enabled = self.enabled.try_get().unwrap_or(true);
+
+        match block_reason(enabled, self.dut_pwr.try_get()) {
+            Some(_) if overridden => {
+                warn!("Performing {action:?} request despite the interlock");
+                true
+            }
+            Some(reason) => {
+                warn!("Refusing {action:?} request: {reason}");
+
+                self.blocked.set(Some(BlockedRequest {
+                    action,
+                    reason: reason.to_string(),
+                    ts: Timestamp::now(),
+                }));
+
+                false
+            }
+            None => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::block_reason;
+    use crate::dut_power::OutputState;
+
+    #[test]
+    fn interlock() {
+        println!("Requests are refused while the DUT is powered");
+        assert!(block_reason(true, Some(OutputState::On)).is_some());
+        assert!(block_reason(true, Some(OutputState::Changing)).is_some());
+
+        println!("But not if the DUT is off or its state is unknown");
+        assert!(block_reason(true, Some(OutputState::Off)).is_none());
+        assert!(block_reason(true, Some(OutputState::OffFloating)).is_none());
+        assert!(block_reason(true, Some(OutputState::OverCurrent)).is_none());
+        assert!(block_reason(true, None).is_none());
+
+        println!("Or if the interlock is disabled");
+        assert!(block_reason(false, Some(OutputState::On)).is_none());
+    }
+}
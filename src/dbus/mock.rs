@@ -0,0 +1,377 @@
+// This file is part of tacd, the LXA TAC system daemon
+// Copyright (C) 2024 Pengutronix e.K.
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along
+// with this program; if not, write to the Free Software Foundation, Inc.,
+// 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+
+//! Mock implementations of the DBus services the tacd talks to
+//!
+//! The mocks are served on a private peer-to-peer connection (no
+//! dbus-daemon required), so that the proxies and the property mapping
+//! code in the sibling modules can be tested via `cargo test`.
+
+use std::collections::HashMap;
+use std::os::unix::net::UnixStream;
+use std::sync::Mutex;
+
+use async_std::sync::Arc;
+use async_std::task::block_on;
+use zbus::object_server::Interface;
+use zbus::zvariant::{OwnedObjectPath, OwnedValue, Value};
+use zbus::{fdo, interface, Connection, ConnectionBuilder, Guid};
+
+use super::SystemBus;
+use crate::broker::Topic;
+
+/// A private bus between the code under test and the mock services
+pub(crate) struct MockBus {
+    /// The side of the connection the mock services are served on
+    pub(crate) server: Connection,
+    /// The side of the connection the code under test uses
+    pub(crate) bus: SystemBus,
+}
+
+impl MockBus {
+    /// Serve the mock services set up in `serve` on a new private bus
+    pub(crate) fn new<F>(serve: F) -> Self
+    where
+        F: FnOnce(ConnectionBuilder<'static>) -> zbus::Result<ConnectionBuilder<'static>>,
+    {
+        let (server_stream, client_stream) = UnixStream::pair().unwrap();
+
+        let server_builder = ConnectionBuilder::unix_stream(server_stream)
+            .server(Guid::generate())
+            .unwrap()
+            .p2p();
+
+        let server = serve(server_builder).unwrap().build();
+        let client = ConnectionBuilder::unix_stream(client_stream).p2p().build();
+
+        let (server, client) = block_on(futures::future::try_join(server, client)).unwrap();
+
+        // Do not use SystemBus::new() here, as its supervisor would try to
+        // ping a dbus-daemon that is not there and reconnect to the real
+        // system bus.
+        let bus = SystemBus {
+            conn: Arc::new(Mutex::new(Arc::new(client))),
+            generation: Topic::anonymous(Some(0)),
+        };
+
+        Self { server, bus }
+    }
+
+    /// Modify the state of a mock service and announce the changed properties
+    ///
+    /// `properties` are the DBus names (e.g. "ActiveState") of the properties
+    /// affected by the change.
+    pub(crate) fn modify<I, F>(&self, path: &str, properties: &[&str], f: F)
+    where
+        I: Interface,
+        F: FnOnce(&mut I),
+    {
+        block_on(async {
+            let iface = self
+                .server
+                .object_server()
+                .interface::<_, I>(path)
+                .await
+                .unwrap();
+
+            f(&mut iface.get_mut().await);
+
+            let guard = iface.get().await;
+            let mut values = Vec::new();
+
+            for name in properties {
+                values.push((*name, guard.get(name).await.unwrap().unwrap()));
+            }
+
+            let changed: HashMap<&str, &Value> = values.iter().map(|(n, v)| (*n, &**v)).collect();
+
+            fdo::Properties::properties_changed(iface.signal_context(), I::name(), &changed, &[])
+                .await
+                .unwrap();
+        })
+    }
+
+    /// Get a copy of a value from the state of a mock service
+    pub(crate) fn inspect<I, F, R>(&self, path: &str, f: F) -> R
+    where
+        I: Interface,
+        F: FnOnce(&I) -> R,
+    {
+        block_on(async {
+            let iface = self
+                .server
+                .object_server()
+                .interface::<_, I>(path)
+                .await
+                .unwrap();
+
+            let guard = iface.get().await;
+
+            f(&guard)
+        })
+    }
+}
+
+fn owned<'a>(v: impl Into<Value<'a>>) -> OwnedValue {
+    v.into().try_to_owned().unwrap()
+}
+
+pub(crate) const NM_PATH: &str = "/org/freedesktop/NetworkManager";
+
+pub(crate) struct NetworkManager {
+    /// Mapping of interface names to device object paths
+    pub(crate) devices: HashMap<String, OwnedObjectPath>,
+}
+
+#[interface(name = "org.freedesktop.NetworkManager")]
+impl NetworkManager {
+    fn get_device_by_ip_iface(&self, iface: &str) -> fdo::Result<OwnedObjectPath> {
+        self.devices
+            .get(iface)
+            .cloned()
+            .ok_or_else(|| fdo::Error::UnknownObject(format!("No device for {iface}")))
+    }
+}
+
+pub(crate) struct NmDevice {
+    pub(crate) state: u32,
+    pub(crate) ip4_config: OwnedObjectPath,
+    pub(crate) ip6_config: OwnedObjectPath,
+    pub(crate) hw_address: String,
+}
+
+#[interface(name = "org.freedesktop.NetworkManager.Device")]
+impl NmDevice {
+    #[zbus(property)]
+    fn state(&self) -> u32 {
+        self.state
+    }
+
+    #[zbus(property)]
+    fn ip4_config(&self) -> OwnedObjectPath {
+        self.ip4_config.clone()
+    }
+
+    #[zbus(property)]
+    fn ip6_config(&self) -> OwnedObjectPath {
+        self.ip6_config.clone()
+    }
+
+    #[zbus(property)]
+    fn hw_address(&self) -> String {
+        self.hw_address.clone()
+    }
+}
+
+pub(crate) struct NmWiredDevice {
+    pub(crate) carrier: bool,
+    pub(crate) speed: u32,
+}
+
+#[interface(name = "org.freedesktop.NetworkManager.Device.Wired")]
+impl NmWiredDevice {
+    #[zbus(property)]
+    fn carrier(&self) -> bool {
+        self.carrier
+    }
+
+    #[zbus(property)]
+    fn speed(&self) -> u32 {
+        self.speed
+    }
+}
+
+pub(crate) struct NmIp4Config {
+    pub(crate) addresses: Vec<String>,
+}
+
+#[interface(name = "org.freedesktop.NetworkManager.IP4Config")]
+impl NmIp4Config {
+    #[zbus(property)]
+    fn address_data(&self) -> Vec<HashMap<String, OwnedValue>> {
+        self.addresses
+            .iter()
+            .map(|address| {
+                HashMap::from([
+                    ("address".to_string(), owned(address.as_str())),
+                    ("prefix".to_string(), owned(24u32)),
+                ])
+            })
+            .collect()
+    }
+}
+
+pub(crate) struct NmIp6Config {
+    pub(crate) addresses: Vec<String>,
+}
+
+#[interface(name = "org.freedesktop.NetworkManager.IP6Config")]
+impl NmIp6Config {
+    #[zbus(property)]
+    fn address_data(&self) -> Vec<HashMap<String, OwnedValue>> {
+        self.addresses
+            .iter()
+            .map(|address| {
+                HashMap::from([
+                    ("address".to_string(), owned(address.as_str())),
+                    ("prefix".to_string(), owned(64u32)),
+                ])
+            })
+            .collect()
+    }
+}
+
+pub(crate) const RAUC_PATH: &str = "/";
+
+/// A slot property as reported by RAUC. Either a string or an integer.
+pub(crate) enum SlotProperty {
+    Str(&'static str),
+    U32(u32),
+    U64(u64),
+}
+
+pub(crate) struct RaucInstaller {
+    pub(crate) operation: String,
+    pub(crate) progress: (i32, String, i32),
+    pub(crate) last_error: String,
+    pub(crate) primary: String,
+    pub(crate) slots: Vec<(&'static str, Vec<(&'static str, SlotProperty)>)>,
+    /// Bundles that were requested to be installed
+    pub(crate) installed: Vec<String>,
+}
+
+#[interface(name = "de.pengutronix.rauc.Installer")]
+impl RaucInstaller {
+    fn get_primary(&self) -> String {
+        self.primary.clone()
+    }
+
+    fn get_slot_status(&self) -> Vec<(String, HashMap<String, OwnedValue>)> {
+        self.slots
+            .iter()
+            .map(|(name, props)| {
+                let props = props
+                    .iter()
+                    .map(|(k, v)| {
+                        let v = match v {
+                            SlotProperty::Str(s) => owned(*s),
+                            SlotProperty::U32(i) => owned(*i),
+                            SlotProperty::U64(i) => owned(*i),
+                        };
+
+                        (k.to_string(), v)
+                    })
+                    .collect();
+
+                (name.to_string(), props)
+            })
+            .collect()
+    }
+
+    fn install_bundle(&mut self, source: String, _args: HashMap<String, OwnedValue>) {
+        self.installed.push(source);
+    }
+
+    #[zbus(property)]
+    fn operation(&self) -> String {
+        self.operation.clone()
+    }
+
+    #[zbus(property)]
+    fn progress(&self) -> (i32, String, i32) {
+        self.progress.clone()
+    }
+
+    #[zbus(property)]
+    fn last_error(&self) -> String {
+        self.last_error.clone()
+    }
+}
+
+pub(crate) const SYSTEMD_PATH: &str = "/org/freedesktop/systemd1";
+
+pub(crate) struct SystemdManager {
+    /// Mapping of unit names to unit object paths
+    pub(crate) units: HashMap<String, OwnedObjectPath>,
+    pub(crate) reboot_requested: bool,
+}
+
+#[interface(name = "org.freedesktop.systemd1.Manager")]
+impl SystemdManager {
+    fn get_unit(&self, name: &str) -> fdo::Result<OwnedObjectPath> {
+        self.units
+            .get(name)
+            .cloned()
+            .ok_or_else(|| fdo::Error::UnknownObject(format!("Unit {name} not loaded")))
+    }
+
+    fn reboot(&mut self) {
+        self.reboot_requested = true;
+    }
+}
+
+pub(crate) struct SystemdUnit {
+    pub(crate) active_state: String,
+    pub(crate) sub_state: String,
+    pub(crate) active_enter_timestamp: u64,
+    pub(crate) active_exit_timestamp: u64,
+    /// Actions (e.g. "start") and their modes (e.g. "replace") that were requested
+    pub(crate) actions: Vec<(String, String)>,
+}
+
+impl SystemdUnit {
+    fn job(&mut self, action: &str, mode: &str) -> OwnedObjectPath {
+        self.actions.push((action.to_string(), mode.to_string()));
+
+        OwnedObjectPath::try_from("/org/freedesktop/systemd1/job/1").unwrap()
+    }
+}
+
+#[interface(name = "org.freedesktop.systemd1.Unit")]
+impl SystemdUnit {
+    fn start(&mut self, mode: &str) -> OwnedObjectPath {
+        self.job("start", mode)
+    }
+
+    fn stop(&mut self, mode: &str) -> OwnedObjectPath {
+        self.job("stop", mode)
+    }
+
+    fn restart(&mut self, mode: &str) -> OwnedObjectPath {
+        self.job("restart", mode)
+    }
+
+    #[zbus(property)]
+    fn active_state(&self) -> String {
+        self.active_state.clone()
+    }
+
+    #[zbus(property)]
+    fn sub_state(&self) -> String {
+        self.sub_state.clone()
+    }
+
+    #[zbus(property)]
+    fn active_enter_timestamp(&self) -> u64 {
+        self.active_enter_timestamp
+    }
+
+    #[zbus(property)]
+    fn active_exit_timestamp(&self) -> u64 {
+        self.active_exit_timestamp
+    }
+}
@@ -19,37 +19,47 @@ use anyhow::Result;
 use async_std::sync::Arc;
 use serde::{Deserialize, Serialize};
 
+use super::SystemBus;
 use crate::broker::{BrokerBuilder, Topic};
 use crate::led::BlinkPattern;
 use crate::watched_tasks::WatchedTasksBuilder;
 
 // Macro use makes these modules quite heavy, so we keep them commented
 // out until they are actually used
-//mod active_connection;
+mod active_connection;
 mod devices;
-//mod dhcp4_config;
+mod dhcp4_config;
 //mod dhcp6_config;
 mod ipv4_config;
-//mod ipv6_config;
+mod ipv6_config;
 mod manager;
-//mod settings;
+mod settings;
+
+mod dns;
+mod mac;
+
+pub use mac::MacSpoof;
 
 // All of the following includes are not used in demo_mode.
 // Put them inside a mod so we do not have to decorate each one with
 #[cfg(not(feature = "demo_mode"))]
 mod optional_includes {
-    pub(super) use anyhow::anyhow;
+    pub(super) use anyhow::{anyhow, bail};
     pub(super) use async_std::stream::StreamExt;
     pub(super) use async_std::task::sleep;
     pub(super) use futures::{future::FutureExt, select};
-    pub(super) use log::{info, trace};
+    pub(super) use log::{info, trace, warn};
     pub(super) use std::time::Duration;
     pub(super) use zbus::Connection;
     pub(super) use zvariant::OwnedObjectPath;
 
+    pub(super) use super::active_connection::ActiveProxy;
     pub(super) use super::devices::{DeviceProxy, WiredProxy, NM_DEVICE_STATE_ACTIVATED};
+    pub(super) use super::dhcp4_config::DHCP4ConfigProxy;
     pub(super) use super::ipv4_config::IP4ConfigProxy;
+    pub(super) use super::ipv6_config::IP6ConfigProxy;
     pub(super) use super::manager::NetworkManagerProxy;
+    pub(super) use super::settings::ConnectionProxy;
 }
 
 #[cfg(not(feature = "demo_mode"))]
@@ -86,6 +96,39 @@ async fn get_device_path(conn: &Arc<Connection>, interface_name: &str) -> OwnedO
     }
 }
 
+/// Get the proxies for a device and the settings of the connection that is
+/// active on it
+#[cfg(not(feature = "demo_mode"))]
+async fn device_connection(
+    conn: &Arc<Connection>,
+    interface_name: &str,
+) -> Result<(DeviceProxy<'static>, ConnectionProxy<'static>)> {
+    let device_path = get_device_path(conn, interface_name).await;
+    let device = DeviceProxy::builder(conn)
+        .path(device_path)?
+        .build()
+        .await?;
+
+    let active_path = device.active_connection().await?;
+
+    // Devices without an active connection reference the root object instead
+    if active_path.as_str() == "/" {
+        bail!("Interface {interface_name} has no active connection");
+    }
+
+    let active = ActiveProxy::builder(conn)
+        .path(active_path)?
+        .build()
+        .await?;
+
+    let settings = ConnectionProxy::builder(conn)
+        .path(active.connection().await?)?
+        .build()
+        .await?;
+
+    Ok((device, settings))
+}
+
 #[cfg(not(feature = "demo_mode"))]
 async fn handle_link_updates(
     conn: &Arc<Connection>,
@@ -146,10 +189,18 @@ async fn handle_link_updates(
 }
 
 #[cfg(not(feature = "demo_mode"))]
-async fn handle_ipv4_updates(
+#[derive(Clone, Copy, Debug)]
+enum IpFamily {
+    V4,
+    V6,
+}
+
+#[cfg(not(feature = "demo_mode"))]
+async fn handle_ip_updates(
     conn: &Arc<Connection>,
     topic: Arc<Topic<Vec<String>>>,
     interface_name: &str,
+    family: IpFamily,
 ) -> Result<()> {
     let device_path = get_device_path(conn, interface_name).await;
     let device = DeviceProxy::builder(conn)
@@ -160,7 +211,8 @@ async fn handle_ipv4_updates(
     let mut state_changes = device.receive_state_property_changed().await;
 
     loop {
-        // The NetworkManager DBus documentation says the Ip4Config property is
+        // The NetworkManager DBus documentation says the Ip4Config and
+        // Ip6Config properties are
         // "Only valid when the device is in the NM_DEVICE_STATE_ACTIVATED state".
         // Loop until that is the case.
         'wait_activated: loop {
@@ -178,13 +230,29 @@ async fn handle_ipv4_updates(
             }
         }
 
-        let ip4_config_path = device.ip4_config().await?;
-        let ip4_config = IP4ConfigProxy::builder(conn)
-            .path(ip4_config_path)?
-            .build()
-            .await?;
+        // Both proxies provide the same AddressData property.
+        // They have to be kept around for as long as the changes are received.
+        let ip4_config;
+        let ip6_config;
 
-        let mut address_data_changes = ip4_config.receive_address_data_changed().await;
+        let mut address_data_changes = match family {
+            IpFamily::V4 => {
+                ip4_config = IP4ConfigProxy::builder(conn)
+                    .path(device.ip4_config().await?)?
+                    .build()
+                    .await?;
+
+                ip4_config.receive_address_data_changed().await
+            }
+            IpFamily::V6 => {
+                ip6_config = IP6ConfigProxy::builder(conn)
+                    .path(device.ip6_config().await?)?
+                    .build()
+                    .await?;
+
+                ip6_config.receive_address_data_changed().await
+            }
+        };
 
         'wait_deactivated: loop {
             select! {
@@ -217,7 +285,9 @@ async fn handle_ipv4_updates(
                         })
                         .collect();
 
-                    trace!("Interface {interface_name} got new IP addresses: {addresses:?}");
+                    trace!(
+                        "Interface {interface_name} got new {family:?} addresses: {addresses:?}"
+                    );
 
                     topic.set(addresses);
                 }
@@ -226,32 +296,115 @@ async fn handle_ipv4_updates(
     }
 }
 
+/// How often the DHCP options of the uplink are checked for changes
+#[cfg(not(feature = "demo_mode"))]
+const DHCP_POLL_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Get a (string) option from the current DHCP lease of a device
+///
+/// NetworkManager names options like dhclient does, e.g. "tcode" for the
+/// tz database timezone (option 101).
+#[cfg(not(feature = "demo_mode"))]
+async fn dhcp4_option(
+    conn: &Arc<Connection>,
+    device: &DeviceProxy<'_>,
+    name: &str,
+) -> Result<Option<String>> {
+    let config_path = device.dhcp4_config().await?;
+
+    // Devices without a DHCP lease reference the root object instead
+    if config_path.as_str() == "/" {
+        return Ok(None);
+    }
+
+    let config = DHCP4ConfigProxy::builder(conn)
+        .path(config_path)?
+        .build()
+        .await?;
+
+    let option = config
+        .options()
+        .await?
+        .get(name)
+        .and_then(|v| v.downcast_ref::<zvariant::Str>().ok())
+        .map(|v| v.as_str().to_owned());
+
+    Ok(option)
+}
+
+#[cfg(not(feature = "demo_mode"))]
+async fn handle_dhcp_timezone_updates(
+    conn: &Arc<Connection>,
+    topic: Arc<Topic<Option<String>>>,
+    interface_name: &str,
+) -> Result<()> {
+    let device_path = get_device_path(conn, interface_name).await;
+    let device = DeviceProxy::builder(conn)
+        .path(device_path)?
+        .build()
+        .await?;
+
+    loop {
+        match dhcp4_option(conn, &device, "tcode").await {
+            Ok(tz) => topic.set_if_changed(tz),
+            Err(e) => trace!("Failed to get DHCP options of {interface_name}: {e}"),
+        }
+
+        sleep(DHCP_POLL_INTERVAL).await;
+    }
+}
+
 pub struct Network {
     pub bridge_interface: Arc<Topic<Vec<String>>>,
+    pub bridge_interface_ipv6: Arc<Topic<Vec<String>>>,
     pub dut_interface: Arc<Topic<LinkInfo>>,
     pub uplink_interface: Arc<Topic<LinkInfo>>,
+    /// The timezone announced by the DHCP server in the uplink network
+    pub dhcp_timezone: Arc<Topic<Option<String>>>,
+    /// Static DNS servers for the uplink network.
+    /// Empty if the servers announced via DHCP are used.
+    pub dns_servers: Arc<Topic<Vec<String>>>,
+    pub bridge_mac: Arc<Topic<String>>,
+    pub dut_mac: Arc<Topic<String>>,
+    pub uplink_mac: Arc<Topic<String>>,
+    /// The address used on the bridge instead of its default address.
+    /// This is the peer address the DUT sees.
+    pub bridge_mac_spoof: Arc<Topic<Option<MacSpoof>>>,
 }
 
 impl Network {
     fn setup_topics(bb: &mut BrokerBuilder) -> Self {
         Self {
             bridge_interface: bb.topic_ro("/v1/tac/network/interface/tac-bridge", Some(Vec::new())),
+            bridge_interface_ipv6: bb.topic_ro(
+                "/v1/tac/network/interface/tac-bridge/ipv6",
+                Some(Vec::new()),
+            ),
             dut_interface: bb.topic_ro("/v1/tac/network/interface/dut", None),
             uplink_interface: bb.topic_ro("/v1/tac/network/interface/uplink", None),
+            dhcp_timezone: Topic::anonymous(Some(None)),
+            dns_servers: bb.topic_ro(dns::DNS_PATH, None),
+            bridge_mac: bb.topic_ro("/v1/tac/network/interface/tac-bridge/mac", None),
+            dut_mac: bb.topic_ro("/v1/tac/network/interface/dut/mac", None),
+            uplink_mac: bb.topic_ro("/v1/tac/network/interface/uplink/mac", None),
+            bridge_mac_spoof: bb.topic_ro(mac::SPOOF_PATH, None),
         }
     }
 
     #[cfg(feature = "demo_mode")]
-    pub fn new<C>(
+    pub fn new(
         bb: &mut BrokerBuilder,
-        _wtb: &mut WatchedTasksBuilder,
-        _conn: C,
+        wtb: &mut WatchedTasksBuilder,
+        _bus: &SystemBus,
         _led_dut: Arc<Topic<BlinkPattern>>,
         _led_uplink: Arc<Topic<BlinkPattern>>,
+        setup_mode: Arc<Topic<bool>>,
     ) -> Result<Self> {
         let this = Self::setup_topics(bb);
 
         this.bridge_interface.set(vec![String::from("192.168.1.1")]);
+        this.bridge_interface_ipv6
+            .set(vec![String::from("2001:db8::1"), String::from("fe80::1")]);
         this.dut_interface.set(LinkInfo {
             speed: 0,
             carrier: false,
@@ -260,6 +413,26 @@ impl Network {
             speed: 1000,
             carrier: true,
         });
+        this.dns_servers.set(Vec::new());
+        this.bridge_mac.set(String::from("00:00:5E:00:53:01"));
+        this.dut_mac.set(String::from("00:00:5E:00:53:02"));
+        this.uplink_mac.set(String::from("00:00:5E:00:53:03"));
+        this.bridge_mac_spoof.set(None);
+
+        let dns_servers = this.dns_servers.clone();
+        dns::handle_change_requests(bb, wtb, setup_mode, move |servers| {
+            dns_servers.set(servers.iter().map(ToString::to_string).collect());
+            async {}
+        })?;
+
+        let bridge_mac = this.bridge_mac.clone();
+        let bridge_mac_spoof = this.bridge_mac_spoof.clone();
+        mac::handle_change_requests(bb, wtb, move |spoof| {
+            let address = spoof.as_ref().map(|s| s.address.as_str());
+            bridge_mac.set(address.unwrap_or("00:00:5E:00:53:01").to_string());
+            bridge_mac_spoof.set(spoof);
+            async {}
+        })?;
 
         Ok(this)
     }
@@ -268,30 +441,278 @@ impl Network {
     pub fn new(
         bb: &mut BrokerBuilder,
         wtb: &mut WatchedTasksBuilder,
-        conn: &Arc<Connection>,
+        bus: &SystemBus,
         led_dut: Arc<Topic<BlinkPattern>>,
         led_uplink: Arc<Topic<BlinkPattern>>,
+        setup_mode: Arc<Topic<bool>>,
     ) -> Result<Self> {
         let this = Self::setup_topics(bb);
 
-        let conn_task = conn.clone();
         let dut_interface = this.dut_interface.clone();
-        wtb.spawn_task("link-dut-update", async move {
-            handle_link_updates(&conn_task, dut_interface, "dut", led_dut).await
+        bus.spawn_task(wtb, "link-dut-update", move |conn| {
+            let dut_interface = dut_interface.clone();
+            let led_dut = led_dut.clone();
+
+            async move { handle_link_updates(&conn, dut_interface, "dut", led_dut).await }
         })?;
 
-        let conn_task = conn.clone();
         let uplink_interface = this.uplink_interface.clone();
-        wtb.spawn_task("link-uplink-update", async move {
-            handle_link_updates(&conn_task, uplink_interface, "uplink", led_uplink).await
+        bus.spawn_task(wtb, "link-uplink-update", move |conn| {
+            let uplink_interface = uplink_interface.clone();
+            let led_uplink = led_uplink.clone();
+
+            async move { handle_link_updates(&conn, uplink_interface, "uplink", led_uplink).await }
         })?;
 
-        let conn_task = conn.clone();
         let bridge_interface = this.bridge_interface.clone();
-        wtb.spawn_task("ip-tac-bridge-update", async move {
-            handle_ipv4_updates(&conn_task, bridge_interface, "tac-bridge").await
+        bus.spawn_task(wtb, "ip-tac-bridge-update", move |conn| {
+            let bridge_interface = bridge_interface.clone();
+
+            async move {
+                handle_ip_updates(&conn, bridge_interface, "tac-bridge", IpFamily::V4).await
+            }
+        })?;
+
+        let bridge_interface_ipv6 = this.bridge_interface_ipv6.clone();
+        bus.spawn_task(wtb, "ipv6-tac-bridge-update", move |conn| {
+            let bridge_interface_ipv6 = bridge_interface_ipv6.clone();
+
+            async move {
+                handle_ip_updates(&conn, bridge_interface_ipv6, "tac-bridge", IpFamily::V6).await
+            }
+        })?;
+
+        let dhcp_timezone = this.dhcp_timezone.clone();
+        bus.spawn_task(wtb, "dhcp-timezone-update", move |conn| {
+            let dhcp_timezone = dhcp_timezone.clone();
+
+            async move { handle_dhcp_timezone_updates(&conn, dhcp_timezone, "tac-bridge").await }
+        })?;
+
+        let dns_servers = this.dns_servers.clone();
+        bus.spawn_task(wtb, "dns-update", move |conn| {
+            let dns_servers = dns_servers.clone();
+
+            async move { dns::handle_updates(&conn, dns_servers).await }
+        })?;
+
+        for (name, topic) in [
+            ("tac-bridge", this.bridge_mac.clone()),
+            ("dut", this.dut_mac.clone()),
+            ("uplink", this.uplink_mac.clone()),
+        ] {
+            bus.spawn_task(wtb, format!("mac-{name}-update"), move |conn| {
+                let topic = topic.clone();
+
+                async move { mac::handle_address_updates(&conn, topic, name).await }
+            })?;
+        }
+
+        let bridge_mac_spoof = this.bridge_mac_spoof.clone();
+        bus.spawn_task(wtb, "mac-spoof-update", move |conn| {
+            let bridge_mac_spoof = bridge_mac_spoof.clone();
+
+            async move { mac::handle_spoof_updates(&conn, bridge_mac_spoof).await }
+        })?;
+
+        // Unlike the hostname the servers are set on the topic directly,
+        // as NetworkManager does not notify us about changed settings.
+        let dns_servers = this.dns_servers.clone();
+        let dns_bus = bus.clone();
+        dns::handle_change_requests(bb, wtb, setup_mode, move |servers| {
+            let conn = dns_bus.connection();
+            let dns_servers = dns_servers.clone();
+
+            async move {
+                match dns::apply(&conn, &servers).await {
+                    Ok(()) => dns_servers.set(servers.iter().map(ToString::to_string).collect()),
+                    Err(e) => warn!("Failed to set DNS servers to {servers:?}: {e}"),
+                }
+            }
+        })?;
+
+        // The addresses are polled, so update them right away to not
+        // leave the topics outdated until the next poll.
+        // This includes going back to the default address, which is only
+        // known once NetworkManager applied the change.
+        let bridge_mac = this.bridge_mac.clone();
+        let bridge_mac_spoof = this.bridge_mac_spoof.clone();
+        let mac_bus = bus.clone();
+        mac::handle_change_requests(bb, wtb, move |spoof| {
+            let conn = mac_bus.connection();
+            let bridge_mac = bridge_mac.clone();
+            let bridge_mac_spoof = bridge_mac_spoof.clone();
+
+            async move {
+                match mac::apply(&conn, spoof.as_ref()).await {
+                    Ok(address) => bridge_mac.set(address),
+                    Err(e) => {
+                        warn!("Failed to change the bridge MAC address to {spoof:?}: {e}");
+                        return;
+                    }
+                }
+
+                bridge_mac_spoof.set(spoof);
+            }
         })?;
 
         Ok(this)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    use async_std::task::{block_on, sleep};
+    use zbus::zvariant::OwnedObjectPath;
+
+    use super::{Network, NM_DEVICE_STATE_ACTIVATED};
+    use crate::broker::{BrokerBuilder, Topic};
+    use crate::dbus::mock::{
+        MockBus, NetworkManager, NmDevice, NmIp4Config, NmIp6Config, NmWiredDevice, NM_PATH,
+    };
+    use crate::watched_tasks::WatchedTasksBuilder;
+
+    const DUT_PATH: &str = "/org/freedesktop/NetworkManager/Devices/1";
+    const UPLINK_PATH: &str = "/org/freedesktop/NetworkManager/Devices/2";
+    const BRIDGE_PATH: &str = "/org/freedesktop/NetworkManager/Devices/3";
+    const IP4_CONFIG_PATH: &str = "/org/freedesktop/NetworkManager/IP4Config/1";
+    const IP6_CONFIG_PATH: &str = "/org/freedesktop/NetworkManager/IP6Config/1";
+
+    const NM_DEVICE_STATE_DISCONNECTED: u32 = 30;
+
+    fn path(p: &str) -> OwnedObjectPath {
+        OwnedObjectPath::try_from(p).unwrap()
+    }
+
+    #[test]
+    fn property_mapping() {
+        let mock = MockBus::new(|builder| {
+            let devices = HashMap::from([
+                ("dut".to_string(), path(DUT_PATH)),
+                ("uplink".to_string(), path(UPLINK_PATH)),
+                ("tac-bridge".to_string(), path(BRIDGE_PATH)),
+            ]);
+
+            builder
+                .serve_at(NM_PATH, NetworkManager { devices })?
+                .serve_at(
+                    DUT_PATH,
+                    NmWiredDevice {
+                        carrier: true,
+                        speed: 10,
+                    },
+                )?
+                .serve_at(
+                    UPLINK_PATH,
+                    NmWiredDevice {
+                        carrier: true,
+                        speed: 1000,
+                    },
+                )?
+                .serve_at(
+                    BRIDGE_PATH,
+                    NmDevice {
+                        state: NM_DEVICE_STATE_ACTIVATED,
+                        ip4_config: path(IP4_CONFIG_PATH),
+                        ip6_config: path(IP6_CONFIG_PATH),
+                        hw_address: "02:00:00:00:00:01".to_string(),
+                    },
+                )?
+                .serve_at(
+                    IP4_CONFIG_PATH,
+                    NmIp4Config {
+                        addresses: vec!["192.168.1.1".to_string()],
+                    },
+                )?
+                .serve_at(
+                    IP6_CONFIG_PATH,
+                    NmIp6Config {
+                        addresses: vec!["2001:db8::1".to_string(), "fe80::1".to_string()],
+                    },
+                )
+        });
+
+        let mut wtb = WatchedTasksBuilder::new();
+        let mut bb = BrokerBuilder::new();
+        let led_dut = Topic::anonymous(None);
+        let led_uplink = Topic::anonymous(None);
+        let setup_mode = Topic::anonymous(Some(false));
+
+        let network = Network::new(
+            &mut bb,
+            &mut wtb,
+            &mock.bus,
+            led_dut.clone(),
+            led_uplink.clone(),
+            setup_mode,
+        )
+        .unwrap();
+
+        block_on(sleep(Duration::from_millis(500)));
+
+        println!("Initial link and address state");
+        let dut = network.dut_interface.try_get().unwrap();
+        assert!(dut.carrier);
+        assert_eq!(dut.speed, 10);
+
+        let uplink = network.uplink_interface.try_get().unwrap();
+        assert!(uplink.carrier);
+        assert_eq!(uplink.speed, 1000);
+
+        // The switch IC does not light the LED for 10MBit/s links,
+        // so the tacd does it instead.
+        assert!(block_on(led_dut.get()).is_on());
+        assert!(block_on(led_uplink.get()).is_off());
+
+        assert_eq!(
+            network.bridge_interface.try_get().unwrap(),
+            vec!["192.168.1.1".to_string()]
+        );
+        assert_eq!(
+            network.bridge_interface_ipv6.try_get().unwrap(),
+            vec!["2001:db8::1".to_string(), "fe80::1".to_string()]
+        );
+        assert_eq!(
+            network.bridge_mac.try_get().unwrap(),
+            "02:00:00:00:00:01".to_string()
+        );
+
+        println!("Unplug the DUT interface");
+        mock.modify::<NmWiredDevice, _>(DUT_PATH, &["Carrier", "Speed"], |dev| {
+            dev.carrier = false;
+            dev.speed = 0;
+        });
+
+        block_on(sleep(Duration::from_millis(500)));
+
+        let dut = network.dut_interface.try_get().unwrap();
+        assert!(!dut.carrier);
+        assert_eq!(dut.speed, 0);
+        assert!(block_on(led_dut.get()).is_off());
+
+        println!("Change the bridge address");
+        mock.modify::<NmIp4Config, _>(IP4_CONFIG_PATH, &["AddressData"], |config| {
+            config.addresses = vec!["10.0.0.2".to_string(), "10.0.1.2".to_string()];
+        });
+
+        block_on(sleep(Duration::from_millis(500)));
+
+        assert_eq!(
+            network.bridge_interface.try_get().unwrap(),
+            vec!["10.0.0.2".to_string(), "10.0.1.2".to_string()]
+        );
+
+        println!("Deactivate the bridge");
+        mock.modify::<NmDevice, _>(BRIDGE_PATH, &["State"], |dev| {
+            dev.state = NM_DEVICE_STATE_DISCONNECTED;
+        });
+
+        block_on(sleep(Duration::from_millis(500)));
+
+        assert!(network.bridge_interface.try_get().unwrap().is_empty());
+        assert!(network.bridge_interface_ipv6.try_get().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,353 @@
+// This file is part of tacd, the LXA TAC system daemon
+// Copyright (C) 2024 Pengutronix e.K.
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along
+// with this program; if not, write to the Free Software Foundation, Inc.,
+// 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+
+//! Configure static DNS servers for the uplink network
+//!
+//! The servers are stored in the NetworkManager connection profile of the
+//! bridge interface, so that they are kept across reboots.
+//! An empty list goes back to using the servers announced via DHCP.
+//! Servers are set per address family, so a list of only IPv4 servers
+//! keeps using the IPv6 servers announced to the TAC and vice versa.
+
+use std::future::Future;
+use std::net::IpAddr;
+
+use anyhow::{anyhow, bail, Result};
+use async_std::prelude::*;
+use async_std::sync::Arc;
+use log::warn;
+
+use crate::broker::{BrokerBuilder, Topic};
+use crate::watched_tasks::WatchedTasksBuilder;
+
+#[cfg(not(feature = "demo_mode"))]
+mod nm {
+    pub(super) use std::collections::HashMap;
+    pub(super) use std::net::{Ipv4Addr, Ipv6Addr};
+
+    pub(super) use async_std::task::sleep;
+    pub(super) use log::trace;
+    pub(super) use zbus::Connection;
+    pub(super) use zvariant::{Array, OwnedValue, Value};
+
+    pub(super) use super::super::{device_connection, DHCP_POLL_INTERVAL};
+}
+
+#[cfg(not(feature = "demo_mode"))]
+use nm::*;
+
+pub(super) const DNS_PATH: &str = "/v1/tac/network/dns";
+
+/// The resolver does not use more servers than this (MAXNS in resolv.h)
+const MAX_SERVERS: usize = 3;
+
+/// The interface that connects the TAC to the uplink network
+#[cfg(not(feature = "demo_mode"))]
+const INTERFACE: &str = "tac-bridge";
+
+/// Connection settings in the format NetworkManager uses on DBus
+#[cfg(not(feature = "demo_mode"))]
+type Settings = HashMap<String, HashMap<String, OwnedValue>>;
+
+/// Check a list of DNS servers requested via the API
+fn parse_servers(servers: &[String]) -> Result<Vec<IpAddr>> {
+    if servers.len() > MAX_SERVERS {
+        bail!("At most {MAX_SERVERS} DNS servers can be used");
+    }
+
+    servers
+        .iter()
+        .map(|server| {
+            let addr: IpAddr = server
+                .parse()
+                .map_err(|_| anyhow!("\"{server}\" is not an IP address"))?;
+
+            if addr.is_unspecified() || addr.is_multicast() {
+                bail!("\"{server}\" can not be used as DNS server");
+            }
+
+            Ok(addr)
+        })
+        .collect()
+}
+
+/// Get the static DNS servers from the settings of a connection
+///
+/// Returns an empty list if the servers announced via DHCP are used.
+#[cfg(not(feature = "demo_mode"))]
+fn servers_from_settings(settings: &Settings) -> Vec<String> {
+    let mut servers = Vec::new();
+
+    for family in ["ipv4", "ipv6"] {
+        let section = match settings.get(family) {
+            Some(section) => section,
+            None => continue,
+        };
+
+        let ignore_auto_dns = section
+            .get("ignore-auto-dns")
+            .and_then(|v| v.downcast_ref::<bool>().ok())
+            .unwrap_or(false);
+
+        let dns = section
+            .get("dns")
+            .and_then(|v| v.downcast_ref::<&Array>().ok());
+
+        let dns = match dns {
+            Some(dns) if ignore_auto_dns => dns,
+            _ => continue,
+        };
+
+        for entry in dns.inner() {
+            // NetworkManager uses u32s in network byte order for IPv4
+            // addresses and arrays of bytes for IPv6 addresses.
+            let addr = match family {
+                "ipv4" => entry
+                    .downcast_ref::<u32>()
+                    .ok()
+                    .map(|addr| Ipv4Addr::from(addr.to_ne_bytes()).to_string()),
+                _ => entry.downcast_ref::<&Array>().ok().and_then(|bytes| {
+                    let bytes: Vec<u8> = bytes
+                        .inner()
+                        .iter()
+                        .filter_map(|b| b.downcast_ref::<u8>().ok())
+                        .collect();
+
+                    <[u8; 16]>::try_from(bytes.as_slice())
+                        .ok()
+                        .map(|addr| Ipv6Addr::from(addr).to_string())
+                }),
+            };
+
+            servers.extend(addr);
+        }
+    }
+
+    servers
+}
+
+/// Store the DNS servers in the connection profile and apply them right away
+#[cfg(not(feature = "demo_mode"))]
+pub(super) async fn apply(conn: &Arc<Connection>, servers: &[IpAddr]) -> Result<()> {
+    let (device, connection) = device_connection(conn, INTERFACE).await?;
+    let mut settings = connection.get_settings().await?;
+
+    // Update() replaces the whole connection profile, including secrets
+    // (e.g. 802.1X passwords) that GetSettings() does not return.
+    // Add them back in so that they are not lost.
+    let names: Vec<String> = settings.keys().cloned().collect();
+
+    for name in names {
+        match connection.get_secrets(&name).await {
+            Ok(secrets) => {
+                for (section_name, section) in secrets {
+                    settings.entry(section_name).or_default().extend(section);
+                }
+            }
+            Err(e) => trace!("Failed to get {name} secrets of {INTERFACE}: {e}"),
+        }
+    }
+
+    let dns4: Vec<u32> = servers
+        .iter()
+        .filter_map(|addr| match addr {
+            IpAddr::V4(addr) => Some(u32::from_ne_bytes(addr.octets())),
+            IpAddr::V6(_) => None,
+        })
+        .collect();
+
+    let dns6: Vec<Vec<u8>> = servers
+        .iter()
+        .filter_map(|addr| match addr {
+            IpAddr::V4(_) => None,
+            IpAddr::V6(addr) => Some(addr.octets().to_vec()),
+        })
+        .collect();
+
+    if !dns6.is_empty() && !settings.contains_key("ipv6") {
+        bail!("IPv6 is not configured on interface {INTERFACE}");
+    }
+
+    let dns4 = (!dns4.is_empty()).then(|| Value::from(dns4));
+    let dns6 = (!dns6.is_empty()).then(|| Value::from(dns6));
+
+    // Only families with static servers stop using the announced ones
+    let ignore_auto_dns4 = Value::from(dns4.is_some());
+    let ignore_auto_dns6 = Value::from(dns6.is_some());
+
+    let mut update: HashMap<&str, HashMap<&str, &Value>> = settings
+        .iter()
+        .map(|(name, section)| {
+            let section = section.iter().map(|(k, v)| (k.as_str(), &**v)).collect();
+            (name.as_str(), section)
+        })
+        .collect();
+
+    let families = [
+        ("ipv4", &dns4, &ignore_auto_dns4),
+        ("ipv6", &dns6, &ignore_auto_dns6),
+    ];
+
+    for (family, dns, ignore_auto_dns) in families {
+        if let Some(section) = update.get_mut(family) {
+            // The property is left out instead of being set to an empty
+            // list, as some IP methods do not allow DNS servers at all.
+            match dns {
+                Some(dns) => section.insert("dns", dns),
+                None => section.remove("dns"),
+            };
+
+            section.insert("ignore-auto-dns", ignore_auto_dns);
+        }
+    }
+
+    connection.update(update).await?;
+
+    // Reapplying without settings uses the just updated connection profile
+    device.reapply(HashMap::new(), 0, 0).await?;
+
+    Ok(())
+}
+
+/// Keep the topic up to date with the DNS servers in the connection profile,
+/// e.g. if they are changed via nmcli
+#[cfg(not(feature = "demo_mode"))]
+pub(super) async fn handle_updates(
+    conn: &Arc<Connection>,
+    topic: Arc<Topic<Vec<String>>>,
+) -> Result<()> {
+    loop {
+        let settings = match device_connection(conn, INTERFACE).await {
+            Ok((_, connection)) => connection.get_settings().await.map_err(Into::into),
+            Err(e) => Err(e),
+        };
+
+        match settings {
+            Ok(settings) => topic.set_if_changed(servers_from_settings(&settings)),
+            Err(e) => trace!("Failed to get DNS servers of {INTERFACE}: {e}"),
+        }
+
+        sleep(DHCP_POLL_INTERVAL).await;
+    }
+}
+
+/// Subscribe to DNS server change requests from the web
+///
+/// Like renaming the TAC, this is only allowed while it is in setup mode,
+/// and only requests containing valid lists of servers are passed on to
+/// `set_fn`.
+pub(super) fn handle_change_requests<F, Fut>(
+    bb: &mut BrokerBuilder,
+    wtb: &mut WatchedTasksBuilder,
+    setup_mode: Arc<Topic<bool>>,
+    set_fn: F,
+) -> Result<()>
+where
+    F: Fn(Vec<IpAddr>) -> Fut + Send + 'static,
+    Fut: Future<Output = ()> + Send,
+{
+    let (mut requests, _) = bb
+        .topic_wo::<Vec<String>>(DNS_PATH, None)
+        .subscribe_unbounded();
+
+    wtb.spawn_task("dns-change-request", async move {
+        while let Some(servers) = requests.next().await {
+            if !setup_mode.try_get().unwrap_or(false) {
+                warn!("Refusing to change DNS servers to {servers:?} outside of setup mode");
+                continue;
+            }
+
+            match parse_servers(&servers) {
+                Ok(servers) => set_fn(servers).await,
+                Err(e) => warn!("Refusing to change DNS servers to {servers:?}: {e}"),
+            }
+        }
+
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use zvariant::{OwnedValue, Value};
+
+    use super::{parse_servers, servers_from_settings, MAX_SERVERS};
+
+    fn owned(value: Value<'static>) -> OwnedValue {
+        value.try_into().unwrap()
+    }
+
+    #[test]
+    fn server_validation() {
+        let servers = |s: &[&str]| -> Vec<String> { s.iter().map(|s| s.to_string()).collect() };
+
+        println!("IPv4 and IPv6 addresses are accepted");
+        let parsed = parse_servers(&servers(&["192.168.1.1", "2001:db8::53"])).unwrap();
+        assert_eq!(parsed.len(), 2);
+        assert!(parse_servers(&[]).unwrap().is_empty());
+
+        println!("Hostnames and unusable addresses are not");
+        assert!(parse_servers(&servers(&["dns.example.com"])).is_err());
+        assert!(parse_servers(&servers(&["192.168.1.256"])).is_err());
+        assert!(parse_servers(&servers(&["0.0.0.0"])).is_err());
+        assert!(parse_servers(&servers(&["224.0.0.251"])).is_err());
+
+        println!("Neither are more servers than the resolver uses");
+        let too_many = vec!["192.168.1.1".to_string(); MAX_SERVERS + 1];
+        assert!(parse_servers(&too_many).is_err());
+    }
+
+    #[test]
+    fn settings_parsing() {
+        let section = |dns: Value<'static>, ignore_auto_dns: bool| {
+            HashMap::from([
+                ("dns".to_string(), owned(dns)),
+                (
+                    "ignore-auto-dns".to_string(),
+                    owned(Value::from(ignore_auto_dns)),
+                ),
+            ])
+        };
+
+        let dns4 = || Value::from(vec![u32::from_ne_bytes([192, 168, 1, 1])]);
+        let dns6 = || {
+            let mut addr = vec![0u8; 16];
+            addr[..2].copy_from_slice(&[0x20, 0x01]);
+            addr[15] = 0x53;
+
+            Value::from(vec![addr])
+        };
+
+        println!("Static servers of both families are picked up");
+        let settings = HashMap::from([
+            ("ipv4".to_string(), section(dns4(), true)),
+            ("ipv6".to_string(), section(dns6(), true)),
+        ]);
+
+        assert_eq!(
+            servers_from_settings(&settings),
+            vec!["192.168.1.1".to_string(), "2001::53".to_string()]
+        );
+
+        println!("Servers that are not used exclusively are not");
+        let settings = HashMap::from([("ipv4".to_string(), section(dns4(), false))]);
+
+        assert!(servers_from_settings(&settings).is_empty());
+    }
+}
@@ -0,0 +1,309 @@
+// This file is part of tacd, the LXA TAC system daemon
+// Copyright (C) 2024 Pengutronix e.K.
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along
+// with this program; if not, write to the Free Software Foundation, Inc.,
+// 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+
+//! Expose the MAC addresses of the network interfaces and allow using a
+//! different one towards the DUT
+//!
+//! Some DUT provisioning flows identify the DUT by the MAC address of its
+//! peer. The `dut` and `uplink` interfaces are ports of the `tac-bridge`
+//! bridge and the TAC itself sends its frames via the bridge. So the peer
+//! address the DUT sees is the address of `tac-bridge`, not the one of the
+//! `dut` port, and that is where the address is changed.
+//! This also changes the address the TAC uses in the uplink network.
+//!
+//! The address is stored as `assigned-mac-address` in the NetworkManager
+//! connection profile of the bridge.
+//! Unless it is requested to be persistent the changed profile is not
+//! written to disk, so that the default address is used again after a
+//! reboot.
+
+use std::future::Future;
+
+use anyhow::{bail, Result};
+use async_std::prelude::*;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::broker::BrokerBuilder;
+use crate::watched_tasks::WatchedTasksBuilder;
+
+#[cfg(not(feature = "demo_mode"))]
+mod nm {
+    pub(super) use std::collections::HashMap;
+    pub(super) use std::time::Duration;
+
+    pub(super) use async_std::sync::Arc;
+    pub(super) use async_std::task::sleep;
+    pub(super) use log::trace;
+    pub(super) use zbus::Connection;
+    pub(super) use zvariant::{OwnedValue, Str, Value};
+
+    pub(super) use super::super::devices::DeviceProxy;
+    pub(super) use super::super::settings::ConnectionProxy;
+    pub(super) use super::super::{device_connection, get_device_path};
+    pub(super) use crate::broker::Topic;
+}
+
+#[cfg(not(feature = "demo_mode"))]
+use nm::*;
+
+pub(super) const SPOOF_PATH: &str = "/v1/tac/network/interface/tac-bridge/mac/spoof";
+
+/// The interface a different MAC address can be used on.
+/// See the module documentation on why this is not the `dut` port.
+#[cfg(not(feature = "demo_mode"))]
+const SPOOF_INTERFACE: &str = "tac-bridge";
+
+/// NetworkManager does not notify us about changed settings or addresses,
+/// so they are polled instead
+#[cfg(not(feature = "demo_mode"))]
+const POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The name of the ethernet section in NetworkManager connection settings
+#[cfg(not(feature = "demo_mode"))]
+const ETHERNET: &str = "802-3-ethernet";
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct MacSpoof {
+    /// A locally administered unicast address like "02:00:00:00:00:01"
+    pub address: String,
+    /// Keep using the address after a reboot
+    #[serde(default)]
+    pub persistent: bool,
+}
+
+/// Check a MAC address requested via the API and bring it into the format
+/// NetworkManager uses
+///
+/// Only locally administered unicast addresses are accepted, so that the
+/// TAC can not impersonate other devices in the lab.
+fn parse_mac(mac: &str) -> Result<String> {
+    let octets: Vec<u8> = mac
+        .split(':')
+        .map(|octet| {
+            let is_hex = octet.len() == 2 && octet.chars().all(|c| c.is_ascii_hexdigit());
+            is_hex.then(|| u8::from_str_radix(octet, 16).ok()).flatten()
+        })
+        .collect::<Option<_>>()
+        .unwrap_or_default();
+
+    if octets.len() != 6 {
+        bail!("\"{mac}\" is not a MAC address");
+    }
+
+    if octets[0] & 0x01 != 0 {
+        bail!("\"{mac}\" is a multicast address");
+    }
+
+    if octets[0] & 0x02 == 0 {
+        bail!("\"{mac}\" is not a locally administered address");
+    }
+
+    let normalized: Vec<String> = octets.iter().map(|o| format!("{o:02X}")).collect();
+
+    Ok(normalized.join(":"))
+}
+
+/// Get the MAC address assigned in the settings of a connection
+///
+/// Returns None if one of the special values like "permanent" is used.
+#[cfg(not(feature = "demo_mode"))]
+fn assigned_mac(settings: &HashMap<String, HashMap<String, OwnedValue>>) -> Option<String> {
+    let assigned = settings
+        .get(ETHERNET)?
+        .get("assigned-mac-address")?
+        .downcast_ref::<Str>()
+        .ok()?;
+
+    parse_mac(assigned.as_str()).ok()
+}
+
+/// Use `spoof` on the bridge, or its default address if it is None
+///
+/// Returns the address the bridge uses afterwards.
+#[cfg(not(feature = "demo_mode"))]
+pub(super) async fn apply(conn: &Arc<Connection>, spoof: Option<&MacSpoof>) -> Result<String> {
+    let (device, connection) = device_connection(conn, SPOOF_INTERFACE).await?;
+    let settings = connection.get_settings().await?;
+
+    let assigned = spoof.map(|spoof| Value::from(spoof.address.as_str()));
+
+    let mut update: HashMap<&str, HashMap<&str, &Value>> = settings
+        .iter()
+        .map(|(name, section)| {
+            let section = section.iter().map(|(k, v)| (k.as_str(), &**v)).collect();
+            (name.as_str(), section)
+        })
+        .collect();
+
+    let ethernet = update.entry(ETHERNET).or_default();
+
+    // The deprecated byte array variant would take precedence otherwise
+    ethernet.remove("cloned-mac-address");
+
+    match &assigned {
+        Some(assigned) => ethernet.insert("assigned-mac-address", assigned),
+        None => ethernet.remove("assigned-mac-address"),
+    };
+
+    // Unsaved changes are lost on reboot, which is exactly what we want for
+    // non-persistent addresses. Going back to the hardware address is
+    // always saved, as there may be a persistent address on disk.
+    match spoof {
+        Some(spoof) if !spoof.persistent => connection.update_unsaved(update).await?,
+        _ => connection.update(update).await?,
+    }
+
+    // Reapplying without settings uses the just updated connection profile
+    device.reapply(HashMap::new(), 0, 0).await?;
+
+    Ok(device.hw_address().await?)
+}
+
+/// Keep the topics up to date with the MAC addresses of the interfaces
+#[cfg(not(feature = "demo_mode"))]
+pub(super) async fn handle_address_updates(
+    conn: &Arc<Connection>,
+    topic: Arc<Topic<String>>,
+    interface_name: &str,
+) -> Result<()> {
+    let device_path = get_device_path(conn, interface_name).await;
+    let device = DeviceProxy::builder(conn)
+        .path(device_path)?
+        .build()
+        .await?;
+
+    loop {
+        match device.hw_address().await {
+            Ok(mac) => topic.set_if_changed(mac),
+            Err(e) => trace!("Failed to get MAC address of {interface_name}: {e}"),
+        }
+
+        sleep(POLL_INTERVAL).await;
+    }
+}
+
+/// Get the address assigned in the connection profile of the bridge
+#[cfg(not(feature = "demo_mode"))]
+async fn current_spoof(connection: &ConnectionProxy<'_>) -> Result<Option<MacSpoof>> {
+    let address = match assigned_mac(&connection.get_settings().await?) {
+        Some(address) => address,
+        None => return Ok(None),
+    };
+
+    // Changes that were not written to disk do not survive a reboot
+    let persistent = !connection.unsaved().await?;
+
+    Ok(Some(MacSpoof {
+        address,
+        persistent,
+    }))
+}
+
+/// Keep the topic up to date with the address assigned to the bridge,
+/// e.g. if it is changed via nmcli
+#[cfg(not(feature = "demo_mode"))]
+pub(super) async fn handle_spoof_updates(
+    conn: &Arc<Connection>,
+    topic: Arc<Topic<Option<MacSpoof>>>,
+) -> Result<()> {
+    loop {
+        let spoof = match device_connection(conn, SPOOF_INTERFACE).await {
+            Ok((_, connection)) => current_spoof(&connection).await,
+            Err(e) => Err(e),
+        };
+
+        match spoof {
+            Ok(spoof) => topic.set_if_changed(spoof),
+            Err(e) => trace!("Failed to get assigned MAC of {SPOOF_INTERFACE}: {e}"),
+        }
+
+        sleep(POLL_INTERVAL).await;
+    }
+}
+
+/// Subscribe to MAC address change requests from the web
+///
+/// Only requests containing locally administered unicast addresses are
+/// passed on to `set_fn`. `None` goes back to the default address.
+pub(super) fn handle_change_requests<F, Fut>(
+    bb: &mut BrokerBuilder,
+    wtb: &mut WatchedTasksBuilder,
+    set_fn: F,
+) -> Result<()>
+where
+    F: Fn(Option<MacSpoof>) -> Fut + Send + 'static,
+    Fut: Future<Output = ()> + Send,
+{
+    let (mut requests, _) = bb
+        .topic_wo::<Option<MacSpoof>>(SPOOF_PATH, None)
+        .subscribe_unbounded();
+
+    wtb.spawn_task("mac-spoof-request", async move {
+        while let Some(req) = requests.next().await {
+            let spoof = match req {
+                Some(MacSpoof {
+                    address,
+                    persistent,
+                }) => match parse_mac(&address) {
+                    Ok(address) => Some(MacSpoof {
+                        address,
+                        persistent,
+                    }),
+                    Err(e) => {
+                        warn!("Refusing to change the bridge MAC address: {e}");
+                        continue;
+                    }
+                },
+                None => None,
+            };
+
+            info!("Changing the bridge MAC address to {spoof:?}");
+
+            set_fn(spoof).await;
+        }
+
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_mac;
+
+    #[test]
+    fn mac_validation() {
+        println!("Locally administered unicast addresses are accepted");
+        assert_eq!(parse_mac("02:00:00:00:00:01").unwrap(), "02:00:00:00:00:01");
+        assert_eq!(parse_mac("b6:5d:ab:cd:ef:01").unwrap(), "B6:5D:AB:CD:EF:01");
+
+        println!("Addresses of other vendors' devices are not");
+        assert!(parse_mac("00:1a:2b:3c:4d:5e").is_err());
+
+        println!("Neither are multicast addresses");
+        assert!(parse_mac("03:00:00:00:00:01").is_err());
+        assert!(parse_mac("ff:ff:ff:ff:ff:ff").is_err());
+
+        println!("Or things that are not MAC addresses at all");
+        assert!(parse_mac("02:00:00:00:00").is_err());
+        assert!(parse_mac("02:00:00:00:00:01:02").is_err());
+        assert!(parse_mac("02-00-00-00-00-01").is_err());
+        assert!(parse_mac("02:00:00:00:00:1").is_err());
+        assert!(parse_mac("02:00:00:00:00:0g").is_err());
+        assert!(parse_mac("").is_err());
+    }
+}
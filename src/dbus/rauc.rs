@@ -17,7 +17,7 @@
 
 use std::cmp::Ordering;
 use std::collections::HashMap;
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime};
 
 use anyhow::Result;
 use async_std::channel::Receiver;
@@ -27,12 +27,32 @@ use async_std::task::{sleep, spawn, JoinHandle};
 use log::warn;
 use serde::{Deserialize, Serialize};
 
-use super::Connection;
+use super::interlock::Interlock;
+use super::networkmanager::LinkInfo;
+use super::SystemBus;
 use crate::broker::{BrokerBuilder, Topic};
+use crate::confirmation::Confirmation;
+use crate::dut_power::OutputState;
+use crate::fs_root;
 use crate::watched_tasks::WatchedTasksBuilder;
 
+mod bundle_info;
+pub use bundle_info::BundleInfo;
+
+mod eta;
+pub use eta::Eta;
+#[cfg(not(feature = "demo_mode"))]
+use eta::EtaEstimator;
+
+mod polling;
+pub use polling::PollingConfig;
+
+mod rollout;
+pub use rollout::{RolloutConfig, RolloutPhase};
+
 mod update_channels;
 pub use update_channels::Channel;
+use update_channels::{CredentialStore, Credentials, CredentialsRequest};
 
 #[cfg(feature = "demo_mode")]
 mod demo_mode;
@@ -52,14 +72,14 @@ mod imports {
     }
 
     impl<'a> InstallerProxy<'a> {
-        pub async fn new<C>(_conn: C) -> Option<InstallerProxy<'a>> {
-            Some(Self { _dummy: &() })
+        pub async fn new<C>(_conn: C) -> zbus::Result<InstallerProxy<'a>> {
+            Ok(Self { _dummy: &() })
         }
 
         pub async fn inspect_bundle(
             &self,
             _source: &str,
-            _args: HashMap<&str, zbus::zvariant::Value<'_>>,
+            _args: HashMap<&str, &zbus::zvariant::Value<'_>>,
         ) -> zbus::Result<HashMap<String, zbus::zvariant::OwnedValue>> {
             let update: HashMap<String, String> = [
                 (
@@ -67,6 +87,7 @@ mod imports {
                     "Linux Automation GmbH - LXA TAC".into(),
                 ),
                 ("version".into(), "24.04-20240415070800".into()),
+                ("build".into(), "20240415070800".into()),
             ]
             .into();
 
@@ -75,19 +96,33 @@ mod imports {
 
             Ok(info)
         }
-    }
 
-    pub(super) const CHANNELS_DIR: &str = "demo_files/usr/share/tacd/update_channels";
+        pub async fn mark(
+            &self,
+            _state: &str,
+            _slot_identifier: &str,
+        ) -> zbus::Result<(String, String)> {
+            Ok(("rootfs.0".to_string(), "marked slot as good".to_string()))
+        }
+    }
 }
 
 #[cfg(not(feature = "demo_mode"))]
 mod imports {
     pub(super) use anyhow::bail;
+    pub(super) use futures::stream::select;
+    pub(super) use futures_lite::future::race;
+    pub(super) use futures_util::future::Either;
+    pub(super) use futures_util::FutureExt;
     pub(super) use log::error;
 
-    pub(super) const CHANNELS_DIR: &str = "/usr/share/tacd/update_channels";
+    pub(super) use crate::confirmation::ConfirmableAction;
+    pub(super) use crate::dbus::interlock::InterlockedAction;
 }
 
+const CHANNELS_DIR: &str = "/usr/share/tacd/update_channels";
+const CREDENTIALS_PATH: &str = "/srv/tacd/update_credentials.json";
+
 const RELOAD_RATE_LIMIT: Duration = Duration::from_secs(10 * 60);
 const RETRY_INTERVAL_MIN: Duration = Duration::from_secs(60);
 const RETRY_INTERVAL_MAX: Duration = Duration::from_secs(60 * 60);
@@ -111,20 +146,158 @@ impl From<(i32, String, i32)> for Progress {
     }
 }
 
+/// RAUC reports this step when it starts a new installation
+const INSTALL_START_MESSAGE: &str = "Installing";
+
+/// RAUC reports this step once the bundle was fetched and checked and it
+/// starts writing to the slots
+const SLOT_UPDATE_MESSAGE: &str = "Updating slots";
+
+/// The progress of one of the two phases of an installation
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct PhaseProgress {
+    /// Progress of the phase in percent. Not known while the bundle is
+    /// fetched, as RAUC does not report how much of it was downloaded.
+    pub percentage: Option<i32>,
+    pub message: String,
+    pub done: bool,
+}
+
+impl PhaseProgress {
+    fn pending() -> Self {
+        Self {
+            percentage: None,
+            message: String::new(),
+            done: false,
+        }
+    }
+}
+
+/// Split RAUC's single progress value into a download and an install phase
+///
+/// The download phase covers fetching and checking the bundle, the install
+/// phase writing the images to the slots.
+struct PhaseTracker {
+    /// RAUC's overall percentage at the start of the install phase
+    install_start: Option<i32>,
+    download: PhaseProgress,
+    install: PhaseProgress,
+}
+
+impl PhaseTracker {
+    fn new() -> Self {
+        Self {
+            install_start: None,
+            download: PhaseProgress::pending(),
+            install: PhaseProgress::pending(),
+        }
+    }
+
+    fn update(&mut self, progress: &Progress) {
+        if progress.nesting_depth <= 1 && progress.message == INSTALL_START_MESSAGE {
+            *self = Self::new();
+        }
+
+        if self.install_start.is_none() && progress.message == SLOT_UPDATE_MESSAGE {
+            self.install_start = Some(progress.percentage);
+            self.download.percentage = Some(100);
+            self.download.done = true;
+        }
+
+        match self.install_start {
+            None => self.download.message.clone_from(&progress.message),
+            Some(start) => {
+                // Map the remaining part of RAUC's progress onto 0 to 100%
+                let remaining = (100 - start).max(1);
+                let percentage = (progress.percentage - start) * 100 / remaining;
+
+                self.install.percentage = Some(percentage.clamp(0, 100));
+                self.install.message.clone_from(&progress.message);
+                self.install.done = progress.percentage >= 100;
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub enum InstallOutcome {
+    Running,
+    Succeeded,
+    Failed(String),
+    /// The installation ended while the tacd was not running and RAUC did
+    /// not report an error. It may or may not have succeeded.
+    Unknown,
+}
+
+/// An installation of an update bundle and how it ended
+///
+/// The session is persisted, so that an installation that is in progress
+/// while the tacd is restarted can be picked up again afterwards.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct InstallSession {
+    /// Javascript timestamp (milliseconds since Unix Epoch 0) of the start
+    /// of the installation
+    pub started: f64,
+    /// The bundle URL. Not known for installations that were not started
+    /// via the tacd (e.g. via the rauc command line tool).
+    pub source: Option<String>,
+    /// The name of the update channel the bundle belongs to (if any)
+    pub channel: Option<String>,
+    pub outcome: InstallOutcome,
+    /// Set if this instance of the tacd saw RAUC start the installation.
+    /// Sessions restored from the state file have to be correlated with
+    /// RAUC's current operation instead.
+    #[serde(skip)]
+    tracked: bool,
+}
+
+impl InstallSession {
+    fn start(source: Option<String>, channel: Option<String>) -> Self {
+        let since_epoch = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+
+        Self {
+            started: 1000.0 * since_epoch.as_secs_f64(),
+            source,
+            channel,
+            outcome: InstallOutcome::Running,
+            tracked: true,
+        }
+    }
+
+    fn is_running(&self) -> bool {
+        self.outcome == InstallOutcome::Running
+    }
+}
+
 type SlotStatus = HashMap<String, HashMap<String, String>>;
 
 pub struct Rauc {
     pub operation: Arc<Topic<String>>,
     pub progress: Arc<Topic<Progress>>,
-    pub slot_status: Arc<Topic<Arc<SlotStatus>>>,
     #[cfg_attr(feature = "demo_mode", allow(dead_code))]
+    pub download_progress: Arc<Topic<PhaseProgress>>,
+    #[cfg_attr(feature = "demo_mode", allow(dead_code))]
+    pub install_progress: Arc<Topic<PhaseProgress>>,
+    /// Estimated remaining time of the running installation
+    pub eta: Arc<Topic<Option<Eta>>>,
+    pub slot_status: Arc<Topic<Arc<SlotStatus>>>,
     pub primary: Arc<Topic<String>>,
     pub last_error: Arc<Topic<String>>,
     pub install: Arc<Topic<String>>,
+    /// URLs of bundles to inspect and what they contain
+    pub inspect: Arc<Topic<String>>,
+    pub bundle_info: Arc<Topic<Option<BundleInfo>>>,
     pub channels: Arc<Topic<Vec<Channel>>>,
     pub reload: Arc<Topic<bool>>,
     pub should_reboot: Arc<Topic<bool>>,
     pub enable_polling: Arc<Topic<bool>>,
+    pub polling_config: Arc<Topic<PollingConfig>>,
+    pub install_session: Arc<Topic<InstallSession>>,
+    pub rollout_config: Arc<Topic<RolloutConfig>>,
+    pub rollout: Arc<Topic<RolloutPhase>>,
+    pub rollout_health: Arc<Topic<Vec<String>>>,
 }
 
 fn compare_versions(v1: &str, v2: &str) -> Option<Ordering> {
@@ -198,14 +371,14 @@ fn would_reboot_into_other_slot(slot_status: &SlotStatus, primary: Option<String
 }
 
 async fn channel_polling_task(
-    conn: Arc<Connection>,
+    bus: SystemBus,
     enable_polling: Arc<Topic<bool>>,
+    polling_config: Arc<Topic<PollingConfig>>,
     channels: Arc<Topic<Vec<Channel>>>,
     slot_status: Arc<Topic<Arc<SlotStatus>>>,
+    credentials: Arc<Topic<CredentialStore>>,
     name: String,
 ) {
-    let proxy = InstallerProxy::new(&conn).await.unwrap();
-
     let mut retry_interval = RETRY_INTERVAL_MIN;
 
     while let Some(mut channel) = channels
@@ -216,10 +389,28 @@ async fn channel_polling_task(
         // as contacting the update server requires user consent.
         enable_polling.wait_for(true).await;
 
-        let polling_interval = channel.polling_interval;
+        // Spread out the load on the update server, if configured to do so
+        polling::wait_for_window(&polling_config).await;
+
+        let polling_interval = polling_config
+            .try_get()
+            .unwrap_or_default()
+            .interval(channel.polling_interval);
         let slot_status = slot_status.try_get();
+        let credentials = credentials.try_get().unwrap_or_default();
+
+        // Use a fresh proxy for every poll, as the connection to the bus
+        // may have been re-established in the meantime.
+        let res = match InstallerProxy::new(&bus.connection()).await {
+            Ok(proxy) => {
+                channel
+                    .poll(&proxy, slot_status.as_deref(), &credentials)
+                    .await
+            }
+            Err(e) => Err(e.into()),
+        };
 
-        if let Err(e) = channel.poll(&proxy, slot_status.as_deref()).await {
+        if let Err(e) = res {
             warn!(
                 "Failed to fetch update for update channel \"{}\": {}. Retrying in {}s.",
                 channel.name,
@@ -265,11 +456,13 @@ async fn channel_polling_task(
 }
 
 async fn channel_list_update_task(
-    conn: Arc<Connection>,
+    bus: SystemBus,
     mut reload_stream: Receiver<bool>,
     enable_polling: Arc<Topic<bool>>,
+    polling_config: Arc<Topic<PollingConfig>>,
     channels: Arc<Topic<Vec<Channel>>>,
     slot_status: Arc<Topic<Arc<SlotStatus>>>,
+    credentials: Arc<Topic<CredentialStore>>,
 ) -> Result<()> {
     let mut previous: Option<Instant> = None;
     let mut polling_tasks: Vec<JoinHandle<_>> = Vec::new();
@@ -289,7 +482,7 @@ async fn channel_list_update_task(
         }
 
         // Read the list of available update channels
-        let new_channels = match Channel::from_directory(CHANNELS_DIR) {
+        let mut new_channels = match Channel::from_directory(&fs_root::path(CHANNELS_DIR)) {
             Ok(chs) => chs,
             Err(e) => {
                 warn!("Failed to get list of update channels: {e}");
@@ -302,6 +495,12 @@ async fn channel_list_update_task(
             task.cancel().await;
         }
 
+        let store = credentials.try_get().unwrap_or_default();
+
+        for ch in new_channels.iter_mut() {
+            ch.update_credentials(&store);
+        }
+
         let names: Vec<String> = new_channels.iter().map(|c| c.name.clone()).collect();
 
         channels.set(new_channels);
@@ -309,10 +508,12 @@ async fn channel_list_update_task(
         // Spawn new polling tasks. They will poll once immediately.
         for name in names.into_iter() {
             let polling_task = spawn(channel_polling_task(
-                conn.clone(),
+                bus.clone(),
                 enable_polling.clone(),
+                polling_config.clone(),
                 channels.clone(),
                 slot_status.clone(),
+                credentials.clone(),
                 name,
             ));
 
@@ -325,15 +526,75 @@ async fn channel_list_update_task(
     Ok(())
 }
 
+/// Handle requests to set the credentials used to access update servers
+///
+/// The credentials are kept in an anonymous topic (so they are not exposed
+/// via the API) and stored in a file that is only readable by the tacd.
+fn setup_credentials(
+    bb: &mut BrokerBuilder,
+    wtb: &mut WatchedTasksBuilder,
+    channels: Arc<Topic<Vec<Channel>>>,
+) -> Result<Arc<Topic<CredentialStore>>> {
+    let request = bb.topic_wo::<CredentialsRequest>("/v1/tac/update/channels/credentials", None);
+
+    let initial = Credentials::load(&fs_root::path(CREDENTIALS_PATH)).unwrap_or_else(|e| {
+        warn!("Failed to load update channel credentials: {e}");
+        CredentialStore::new()
+    });
+
+    let credentials = Topic::anonymous(Some(initial));
+    let credentials_task = credentials.clone();
+    let (mut request_stream, _) = request.subscribe_unbounded();
+
+    wtb.spawn_task("rauc-channel-credentials", async move {
+        while let Some(req) = request_stream.next().await {
+            let mut store = credentials_task.try_get().unwrap_or_default();
+
+            match req.credentials {
+                Some(cred) => store.insert(req.channel, cred),
+                None => store.remove(&req.channel),
+            };
+
+            if let Err(e) = Credentials::save(&fs_root::path(CREDENTIALS_PATH), &store) {
+                warn!("Failed to save update channel credentials: {e}");
+            }
+
+            // Let the user know which channels have usable credentials.
+            // The new credentials will be used on the next poll.
+            channels.modify(|prev| {
+                let prev = prev?;
+                let mut new = prev.clone();
+
+                for ch in new.iter_mut() {
+                    ch.update_credentials(&store);
+                }
+
+                (new != prev).then_some(new)
+            });
+
+            credentials_task.set(store);
+        }
+
+        Ok(())
+    })?;
+
+    Ok(credentials)
+}
+
 impl Rauc {
     fn setup_topics(bb: &mut BrokerBuilder) -> Self {
         Self {
             operation: bb.topic_ro("/v1/tac/update/operation", None),
             progress: bb.topic_ro("/v1/tac/update/progress", None),
+            download_progress: bb.topic_ro("/v1/tac/update/progress/download", None),
+            install_progress: bb.topic_ro("/v1/tac/update/progress/install", None),
+            eta: bb.topic_ro("/v1/tac/update/progress/eta", Some(None)),
             slot_status: bb.topic_ro("/v1/tac/update/slots", None),
             primary: bb.topic_ro("/v1/tac/update/primary", None),
             last_error: bb.topic_ro("/v1/tac/update/last_error", None),
             install: bb.topic_wo("/v1/tac/update/install", Some("".to_string())),
+            inspect: bb.topic_wo("/v1/tac/update/bundle_info", None),
+            bundle_info: bb.topic_ro("/v1/tac/update/bundle_info", Some(None)),
             channels: bb.topic_ro("/v1/tac/update/channels", None),
             reload: bb.topic_wo("/v1/tac/update/channels/reload", Some(true)),
             should_reboot: bb.topic_ro("/v1/tac/update/should_reboot", Some(false)),
@@ -345,31 +606,94 @@ impl Rauc {
                 Some(false),
                 1,
             ),
+            polling_config: bb.topic(
+                "/v1/tac/update/polling",
+                true,
+                true,
+                true,
+                Some(PollingConfig::default()),
+                1,
+            ),
+            install_session: bb.topic("/v1/tac/update/session", true, false, true, None, 1),
+            rollout_config: bb.topic(
+                "/v1/tac/update/rollout/config",
+                true,
+                true,
+                true,
+                Some(RolloutConfig::default()),
+                1,
+            ),
+            rollout: bb.topic(
+                "/v1/tac/update/rollout",
+                true,
+                false,
+                true,
+                Some(RolloutPhase::Idle),
+                1,
+            ),
+            rollout_health: bb.topic_ro("/v1/tac/update/rollout/health", Some(Vec::new())),
         }
     }
 
+    /// Reboot into installed updates and only mark them good once the TAC
+    /// is healthy (if enabled in the rollout config)
+    pub fn handle_rollout(
+        &self,
+        wtb: &mut WatchedTasksBuilder,
+        bus: &SystemBus,
+        uplink: Arc<Topic<LinkInfo>>,
+        dut_pwr: Arc<Topic<OutputState>>,
+        reboot: Arc<Topic<bool>>,
+    ) -> Result<()> {
+        let topics = rollout::Topics {
+            config: self.rollout_config.clone(),
+            phase: self.rollout.clone(),
+            health: self.rollout_health.clone(),
+            session: self.install_session.clone(),
+            slot_status: self.slot_status.clone(),
+            primary: self.primary.clone(),
+        };
+
+        rollout::run(wtb, bus.clone(), topics, uplink, dut_pwr, reboot)
+    }
+
     #[cfg(feature = "demo_mode")]
     pub fn new(
         bb: &mut BrokerBuilder,
         wtb: &mut WatchedTasksBuilder,
-        _conn: &Arc<Connection>,
+        bus: &SystemBus,
+        _interlock: Interlock,
+        _confirmation: Confirmation,
     ) -> Result<Self> {
         let inst = Self::setup_topics(bb);
+        let credentials = setup_credentials(bb, wtb, inst.channels.clone())?;
 
         inst.operation.set("idle".to_string());
         inst.slot_status.set(Arc::new(demo_mode::slot_status()));
         inst.last_error.set("".to_string());
 
+        // Look into bundles before they are installed
+        bundle_info::run(
+            wtb,
+            bus.clone(),
+            inst.inspect.clone(),
+            inst.bundle_info.clone(),
+            inst.channels.clone(),
+            credentials.clone(),
+        )?;
+
         // Reload the channel list on request
         let (reload_stream, _) = inst.reload.clone().subscribe_unbounded();
         wtb.spawn_task(
             "rauc-channel-list-update",
             channel_list_update_task(
-                Arc::new(Connection),
+                bus.clone(),
                 reload_stream,
                 inst.enable_polling.clone(),
+                inst.polling_config.clone(),
                 inst.channels.clone(),
                 inst.slot_status.clone(),
+                credentials,
             ),
         )?;
 
@@ -380,177 +704,352 @@ impl Rauc {
     pub fn new(
         bb: &mut BrokerBuilder,
         wtb: &mut WatchedTasksBuilder,
-        conn: &Arc<Connection>,
+        bus: &SystemBus,
+        interlock: Interlock,
+        confirmation: Confirmation,
     ) -> Result<Self> {
         let inst = Self::setup_topics(bb);
+        let credentials = setup_credentials(bb, wtb, inst.channels.clone())?;
 
-        let conn_task = conn.clone();
         let operation = inst.operation.clone();
         let slot_status = inst.slot_status.clone();
         let primary = inst.primary.clone();
         let channels = inst.channels.clone();
         let should_reboot = inst.should_reboot.clone();
 
-        wtb.spawn_task("rauc-slot-status-update", async move {
-            let proxy = InstallerProxy::new(&conn_task).await.unwrap();
+        bus.spawn_task(wtb, "rauc-slot-status-update", move |conn| {
+            let operation = operation.clone();
+            let slot_status = slot_status.clone();
+            let primary = primary.clone();
+            let channels = channels.clone();
+            let should_reboot = should_reboot.clone();
 
-            let mut stream = proxy.receive_operation_changed().await;
+            async move {
+                let proxy = InstallerProxy::new(&conn).await?;
 
-            if let Ok(v) = proxy.operation().await {
-                operation.set(v);
-            }
-
-            loop {
-                // Update which slot is considered the primary whenever the current
-                // operation changes.
-                // (The one that should be booted next _if it is bootable_)
-                let new_primary = proxy.get_primary().await.ok().map(|p| p.replace('.', "_"));
+                let mut stream = proxy.receive_operation_changed().await;
 
-                if let Some(p) = new_primary.clone() {
-                    primary.set_if_changed(p);
+                if let Ok(v) = proxy.operation().await {
+                    operation.set(v);
                 }
 
-                // Refresh the slot status whenever the current operation changes
-                // This is mostly relevant for "installing" -> "idle" transitions
-                // but it can't hurt to do it on any transition.
-                if let Ok(slots) = proxy.get_slot_status().await {
-                    let slots = slots
-                        .into_iter()
-                        .map(|(slot_name, slot_info)| {
-                            let mut info: HashMap<String, String> = slot_info
-                                .into_iter()
-                                .map(|(k, v)| {
-                                    // Convert integers to strings as raw zvariant values are
-                                    // unusable when json serialized and I can not be bothered
-                                    // to fiddle around with an enum that wraps strings and integers
-                                    // or something like that
-                                    let ss = v.downcast_ref::<String>();
-                                    let s32 = v.downcast_ref::<u32>().map(|i| format!("{i}"));
-                                    let s64 = v.downcast_ref::<u64>().map(|i| format!("{i}"));
-
-                                    // Some of the field names make defining a "RaucSlot" type
-                                    // in Typescript difficult. Not matching the names defined
-                                    // in RAUC's API is also not great, but the lesser evil in
-                                    // this case.
-                                    let k = k
-                                        .replace("type", "fs_type")
-                                        .replace("class", "slot_class")
-                                        .replace(['.', '-'], "_");
-
-                                    (k, ss.or(s32).or(s64).unwrap_or_default())
-                                })
-                                .collect();
-
-                            // Include the (unmangled) slot name as a field in the slot
-                            // dict, once again to make life in the Web Interface easier.
-                            info.insert("name".to_string(), slot_name.clone());
-
-                            // Remove "." from the dictionary key to make defining a typescript
-                            // type easier ("rootfs.0" -> "rootfs_0").
-                            (slot_name.replace('.', "_"), info)
-                        })
-                        .collect();
-
-                    // Update the `newer_than_installed` field for the upstream bundles inside
-                    // of the update channels.
-                    channels.modify(|prev| {
-                        let prev = prev?;
-
-                        let mut new = prev.clone();
-
-                        for ch in new.iter_mut() {
-                            if let Some(bundle) = ch.bundle.as_mut() {
-                                bundle.update_install(&slots);
+                loop {
+                    // Update which slot is considered the primary whenever the current
+                    // operation changes.
+                    // (The one that should be booted next _if it is bootable_)
+                    let new_primary = proxy.get_primary().await.ok().map(|p| p.replace('.', "_"));
+
+                    if let Some(p) = new_primary.clone() {
+                        primary.set_if_changed(p);
+                    }
+
+                    // Refresh the slot status whenever the current operation changes
+                    // This is mostly relevant for "installing" -> "idle" transitions
+                    // but it can't hurt to do it on any transition.
+                    if let Ok(slots) = proxy.get_slot_status().await {
+                        let slots = slots
+                            .into_iter()
+                            .map(|(slot_name, slot_info)| {
+                                let mut info: HashMap<String, String> = slot_info
+                                    .into_iter()
+                                    .map(|(k, v)| {
+                                        // Convert integers to strings as raw zvariant values are
+                                        // unusable when json serialized and I can not be bothered
+                                        // to fiddle around with an enum that wraps strings and integers
+                                        // or something like that
+                                        let ss = v.downcast_ref::<String>();
+                                        let s32 = v.downcast_ref::<u32>().map(|i| format!("{i}"));
+                                        let s64 = v.downcast_ref::<u64>().map(|i| format!("{i}"));
+
+                                        // Some of the field names make defining a "RaucSlot" type
+                                        // in Typescript difficult. Not matching the names defined
+                                        // in RAUC's API is also not great, but the lesser evil in
+                                        // this case.
+                                        let k = k
+                                            .replace("type", "fs_type")
+                                            .replace("class", "slot_class")
+                                            .replace(['.', '-'], "_");
+
+                                        (k, ss.or(s32).or(s64).unwrap_or_default())
+                                    })
+                                    .collect();
+
+                                // Include the (unmangled) slot name as a field in the slot
+                                // dict, once again to make life in the Web Interface easier.
+                                info.insert("name".to_string(), slot_name.clone());
+
+                                // Remove "." from the dictionary key to make defining a typescript
+                                // type easier ("rootfs.0" -> "rootfs_0").
+                                (slot_name.replace('.', "_"), info)
+                            })
+                            .collect();
+
+                        // Update the `newer_than_installed` field for the upstream bundles inside
+                        // of the update channels.
+                        channels.modify(|prev| {
+                            let prev = prev?;
+
+                            let mut new = prev.clone();
+
+                            for ch in new.iter_mut() {
+                                if let Some(bundle) = ch.bundle.as_mut() {
+                                    bundle.update_install(&slots);
+                                }
                             }
-                        }
 
-                        // Only send out messages if anything changed
-                        (new != prev).then_some(new)
-                    });
+                            // Only send out messages if anything changed
+                            (new != prev).then_some(new)
+                        });
 
-                    // Provide a simple yes/no "should reboot into other slot?" information
-                    // based on the bundle versions in the booted slot and the other slot.
-                    match would_reboot_into_other_slot(&slots, new_primary) {
-                        Ok(b) => should_reboot.set_if_changed(b),
-                        Err(e) => warn!("Could not determine if TAC should be rebooted: {e}"),
-                    }
+                        // Provide a simple yes/no "should reboot into other slot?" information
+                        // based on the bundle versions in the booted slot and the other slot.
+                        match would_reboot_into_other_slot(&slots, new_primary) {
+                            Ok(b) => should_reboot.set_if_changed(b),
+                            Err(e) => warn!("Could not determine if TAC should be rebooted: {e}"),
+                        }
 
-                    // In the RAUC API the slot status is a list of (name, info) tuples.
-                    // It is once again easier in typescript to represent it as a dict with
-                    // the names as keys, so that is what's exposed here.
-                    slot_status.set(Arc::new(slots));
-                }
+                        // In the RAUC API the slot status is a list of (name, info) tuples.
+                        // It is once again easier in typescript to represent it as a dict with
+                        // the names as keys, so that is what's exposed here.
+                        slot_status.set(Arc::new(slots));
+                    }
 
-                // Wait for the current operation to change
-                if let Some(v) = stream.next().await {
-                    if let Ok(v) = v.get().await {
-                        operation.set(v);
+                    // Wait for the current operation to change
+                    if let Some(v) = stream.next().await {
+                        if let Ok(v) = v.get().await {
+                            operation.set(v);
+                        }
+                    } else {
+                        break Ok(());
                     }
-                } else {
-                    break Ok(());
                 }
             }
         })?;
 
-        let conn_task = conn.clone();
         let progress = inst.progress.clone();
 
         // Forward the "progress" property to the broker framework
-        wtb.spawn_task("rauc-progress-update", async move {
-            let proxy = InstallerProxy::new(&conn_task).await.unwrap();
+        bus.spawn_task(wtb, "rauc-progress-update", move |conn| {
+            let progress = progress.clone();
 
-            let mut stream = proxy.receive_progress_changed().await;
+            async move {
+                let proxy = InstallerProxy::new(&conn).await?;
 
-            if let Ok(p) = proxy.progress().await {
-                progress.set(p.into());
-            }
+                let mut stream = proxy.receive_progress_changed().await;
 
-            while let Some(v) = stream.next().await {
-                if let Ok(p) = v.get().await {
+                if let Ok(p) = proxy.progress().await {
                     progress.set(p.into());
                 }
+
+                while let Some(v) = stream.next().await {
+                    if let Ok(p) = v.get().await {
+                        progress.set(p.into());
+                    }
+                }
+
+                Ok(())
+            }
+        })?;
+
+        let (progress_stream, _) = inst.progress.clone().subscribe_unbounded();
+        let (operation_stream, _) = inst.operation.clone().subscribe_unbounded();
+        let download_progress = inst.download_progress.clone();
+        let install_progress = inst.install_progress.clone();
+        let eta = inst.eta.clone();
+
+        let mut events = select(
+            progress_stream.map(Either::Left),
+            operation_stream.map(Either::Right),
+        );
+
+        // Provide separate progress information for the download and install
+        // phase and estimate how long the installation will take
+        wtb.spawn_task("rauc-progress-phases", async move {
+            let mut tracker = PhaseTracker::new();
+            let mut estimator = EtaEstimator::new();
+            let mut installing = false;
+
+            while let Some(ev) = events.next().await {
+                match ev {
+                    Either::Left(progress) => {
+                        tracker.update(&progress);
+
+                        download_progress.set_if_changed(tracker.download.clone());
+                        install_progress.set_if_changed(tracker.install.clone());
+
+                        if installing {
+                            let estimate = estimator.update(progress.percentage, Instant::now());
+                            eta.set_if_changed(estimate);
+                        }
+                    }
+                    Either::Right(operation) => {
+                        installing = operation == "installing";
+
+                        if !installing {
+                            estimator = EtaEstimator::new();
+                            eta.set_if_changed(None);
+                        }
+                    }
+                }
             }
 
             Ok(())
         })?;
 
-        let conn_task = conn.clone();
         let last_error = inst.last_error.clone();
 
         // Forward the "last_error" property to the broker framework
-        wtb.spawn_task("rauc-forward-error", async move {
-            let proxy = InstallerProxy::new(&conn_task).await.unwrap();
+        bus.spawn_task(wtb, "rauc-forward-error", move |conn| {
+            let last_error = last_error.clone();
 
-            let mut stream = proxy.receive_last_error_changed().await;
+            async move {
+                let proxy = InstallerProxy::new(&conn).await?;
 
-            if let Ok(e) = proxy.last_error().await {
-                last_error.set(e);
-            }
+                let mut stream = proxy.receive_last_error_changed().await;
 
-            while let Some(v) = stream.next().await {
-                if let Ok(e) = v.get().await {
+                if let Ok(e) = proxy.last_error().await {
                     last_error.set(e);
                 }
+
+                while let Some(v) = stream.next().await {
+                    if let Ok(e) = v.get().await {
+                        last_error.set(e);
+                    }
+                }
+
+                Ok(())
             }
+        })?;
 
-            Ok(())
+        let session = inst.install_session.clone();
+
+        // Correlate the install session with the operation RAUC reports.
+        // This also picks up sessions restored from the state file after
+        // a restart of the tacd and installations not started via the tacd.
+        bus.spawn_task(wtb, "rauc-install-session", move |conn| {
+            let session = session.clone();
+
+            async move {
+                let proxy = InstallerProxy::new(&conn).await?;
+
+                let mut operation_stream = proxy.receive_operation_changed().await;
+                let (mut session_stream, _) = session.clone().subscribe_unbounded();
+
+                let mut operation = proxy.operation().await?;
+                let mut prev_operation = operation.clone();
+
+                loop {
+                    let installing = operation == "installing";
+                    let install_done = prev_operation == "installing" && !installing;
+
+                    let last_error = if installing {
+                        String::new()
+                    } else {
+                        proxy.last_error().await.unwrap_or_default()
+                    };
+
+                    session.modify(|prev| match prev {
+                        Some(mut s) if s.is_running() => {
+                            if installing && !s.tracked {
+                                // The installation survived the restart of the tacd
+                                s.tracked = true;
+                            } else if install_done || (!installing && !s.tracked) {
+                                s.outcome = match (last_error.is_empty(), s.tracked) {
+                                    (false, _) => InstallOutcome::Failed(last_error),
+                                    (true, true) => InstallOutcome::Succeeded,
+                                    (true, false) => InstallOutcome::Unknown,
+                                };
+                            } else {
+                                return None;
+                            }
+
+                            Some(s)
+                        }
+                        _ if installing => Some(InstallSession::start(None, None)),
+                        _ => None,
+                    });
+
+                    prev_operation = operation.clone();
+
+                    let ev = race(
+                        operation_stream.next().map(Either::Left),
+                        session_stream.next().map(Either::Right),
+                    )
+                    .await;
+
+                    match ev {
+                        Either::Left(Some(v)) => {
+                            if let Ok(v) = v.get().await {
+                                operation = v;
+                            }
+                        }
+                        Either::Right(Some(_)) => {}
+                        Either::Left(None) | Either::Right(None) => break Ok(()),
+                    }
+                }
+            }
         })?;
 
-        let conn_task = conn.clone();
-        let (mut install_stream, _) = inst.install.clone().subscribe_unbounded();
+        let bus_task = bus.clone();
+        let install_override = bb.topic_wo::<String>("/v1/tac/update/install/override", None);
+        let (install_stream, _) = inst.install.clone().subscribe_unbounded();
+        let (override_stream, _) = install_override.subscribe_unbounded();
+        let mut install_stream = select(
+            install_stream.map(|url| (url, false)),
+            override_stream.map(|url| (url, true)),
+        );
+        let channels = inst.channels.clone();
+        let credentials_task = credentials.clone();
+        let session = inst.install_session.clone();
 
         // Forward the "install" topic from the broker framework to RAUC
         wtb.spawn_task("rauc-forward-install", async move {
-            let proxy = InstallerProxy::new(&conn_task).await.unwrap();
-
-            while let Some(url) = install_stream.next().await {
+            while let Some((url, overridden)) = install_stream.next().await {
                 // Poor-mans validation. It feels wrong to let someone point to any
                 // file on the TAC from the web interface.
-                if url.starts_with("http://") || url.starts_with("https://") {
-                    let args = HashMap::new();
+                if !url.starts_with("http://") && !url.starts_with("https://") {
+                    continue;
+                }
 
-                    if let Err(e) = proxy.install_bundle(&url, args).await {
+                // Installing an update is usually followed by a reboot,
+                // which would interrupt whatever the DUT is doing.
+                // In shared labs someone at the TAC may also have to confirm it.
+                if interlock.permits(InterlockedAction::Install, overridden)
+                    && confirmation.confirmed(ConfirmableAction::Install).await
+                {
+                    // Authenticate against the update server if the bundle
+                    // belongs to an update channel that requires it.
+                    let store = credentials_task.try_get().unwrap_or_default();
+                    let channel_list = channels.try_get().unwrap_or_default();
+                    let channel = channel_list.iter().find(|ch| ch.url == url);
+                    let http_headers = channel
+                        .and_then(|ch| ch.credentials(&store))
+                        .map(Credentials::rauc_http_headers);
+
+                    session.set(InstallSession::start(
+                        Some(url.clone()),
+                        channel.map(|ch| ch.name.clone()),
+                    ));
+
+                    let mut args = HashMap::new();
+
+                    if let Some(http_headers) = &http_headers {
+                        args.insert("http-headers", http_headers);
+                    }
+
+                    let res = match InstallerProxy::new(&bus_task.connection()).await {
+                        Ok(proxy) => proxy.install_bundle(&url, args).await,
+                        Err(e) => Err(e),
+                    };
+
+                    if let Err(e) = res {
                         error!("Failed to install bundle: {}", e);
+
+                        session.modify(|prev| {
+                            let mut s = prev?;
+                            s.outcome = InstallOutcome::Failed(e.to_string());
+                            Some(s)
+                        });
                     }
                 }
             }
@@ -558,19 +1057,39 @@ impl Rauc {
             Ok(())
         })?;
 
+        // Look into bundles before they are installed
+        bundle_info::run(
+            wtb,
+            bus.clone(),
+            inst.inspect.clone(),
+            inst.bundle_info.clone(),
+            inst.channels.clone(),
+            credentials.clone(),
+        )?;
+
         // Reload the channel list on request
         let (reload_stream, _) = inst.reload.clone().subscribe_unbounded();
         wtb.spawn_task(
             "rauc-channel-list-update",
             channel_list_update_task(
-                conn.clone(),
+                bus.clone(),
                 reload_stream,
                 inst.enable_polling.clone(),
+                inst.polling_config.clone(),
                 inst.channels.clone(),
                 inst.slot_status.clone(),
+                credentials,
             ),
         )?;
 
+        // Apply the configured proxy to RAUC
+        polling::handle_proxy(
+            wtb,
+            bus.clone(),
+            inst.polling_config.clone(),
+            inst.operation.clone(),
+        )?;
+
         Ok(inst)
     }
 }
@@ -578,8 +1097,19 @@ impl Rauc {
 #[cfg(test)]
 mod tests {
     use std::collections::HashMap;
+    use std::time::Duration;
 
-    use super::{would_reboot_into_other_slot, SlotStatus};
+    use async_std::task::{block_on, sleep};
+
+    use super::{
+        would_reboot_into_other_slot, InstallOutcome, InstallSession, PhaseTracker, Progress, Rauc,
+        SlotStatus,
+    };
+    use crate::broker::{BrokerBuilder, Topic};
+    use crate::confirmation::Confirmation;
+    use crate::dbus::interlock::Interlock;
+    use crate::dbus::mock::{MockBus, RaucInstaller, SlotProperty, RAUC_PATH};
+    use crate::watched_tasks::WatchedTasksBuilder;
 
     #[test]
     fn reboot_notifications() {
@@ -657,4 +1187,265 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn progress_phases() {
+        let mut tracker = PhaseTracker::new();
+
+        let mut step = |percentage, message: &str, nesting_depth| {
+            tracker.update(&Progress {
+                percentage,
+                message: message.to_string(),
+                nesting_depth,
+            });
+
+            (tracker.download.clone(), tracker.install.clone())
+        };
+
+        println!("Fetch and check the bundle");
+        let (download, install) = step(0, "Installing", 1);
+        assert_eq!(download.percentage, None);
+        assert_eq!(download.message, "Installing");
+        assert!(!download.done);
+        assert_eq!(install.percentage, None);
+
+        let (download, _) = step(20, "Verifying signature", 3);
+        assert_eq!(download.percentage, None);
+        assert_eq!(download.message, "Verifying signature");
+
+        println!("Write the slots");
+        let (download, install) = step(40, "Updating slots", 2);
+        assert_eq!(download.percentage, Some(100));
+        assert!(download.done);
+        assert_eq!(install.percentage, Some(0));
+        assert!(!install.done);
+
+        let (download, install) = step(70, "Copying image to rootfs.1", 3);
+        assert_eq!(download.message, "Verifying signature");
+        assert_eq!(install.percentage, Some(50));
+        assert_eq!(install.message, "Copying image to rootfs.1");
+
+        let (_, install) = step(100, "Installing done.", 1);
+        assert_eq!(install.percentage, Some(100));
+        assert!(install.done);
+
+        println!("Start over");
+        let (download, install) = step(0, "Installing", 1);
+        assert!(!download.done);
+        assert_eq!(install.percentage, None);
+    }
+
+    #[test]
+    fn property_mapping() {
+        let mock = MockBus::new(|builder| {
+            let slot = |state, version| {
+                vec![
+                    ("class", SlotProperty::Str("rootfs")),
+                    ("type", SlotProperty::Str("ext4")),
+                    ("state", SlotProperty::Str(state)),
+                    ("boot_status", SlotProperty::Str("good")),
+                    ("status", SlotProperty::Str("ok")),
+                    ("bundle.version", SlotProperty::Str(version)),
+                    ("installed.count", SlotProperty::U32(3)),
+                    ("size", SlotProperty::U64(1234)),
+                ]
+            };
+
+            builder.serve_at(
+                RAUC_PATH,
+                RaucInstaller {
+                    operation: "idle".to_string(),
+                    progress: (100, "Installing done.".to_string(), 1),
+                    last_error: "".to_string(),
+                    primary: "rootfs.1".to_string(),
+                    slots: vec![
+                        ("rootfs.0", slot("booted", "24.04-20240415070800")),
+                        ("rootfs.1", slot("inactive", "24.06-20240624084500")),
+                    ],
+                    installed: Vec::new(),
+                },
+            )
+        });
+
+        let mut wtb = WatchedTasksBuilder::new();
+        let mut bb = BrokerBuilder::new();
+
+        let interlock = Interlock::new(&mut bb, Topic::anonymous(None));
+        let confirmation = Confirmation::new(&mut bb);
+        let rauc = Rauc::new(&mut bb, &mut wtb, &mock.bus, interlock, confirmation).unwrap();
+
+        block_on(sleep(Duration::from_millis(500)));
+
+        println!("Initial state");
+        assert_eq!(rauc.operation.try_get().unwrap(), "idle");
+        assert_eq!(rauc.primary.try_get().unwrap(), "rootfs_1");
+        assert_eq!(rauc.last_error.try_get().unwrap(), "");
+        assert!(rauc.install_session.try_get().is_none());
+
+        let progress = rauc.progress.try_get().unwrap();
+        assert_eq!(progress.percentage, 100);
+        assert_eq!(progress.message, "Installing done.");
+        assert_eq!(progress.nesting_depth, 1);
+
+        let slots = rauc.slot_status.try_get().unwrap();
+        let rootfs_0 = &slots["rootfs_0"];
+        assert_eq!(rootfs_0["name"], "rootfs.0");
+        assert_eq!(rootfs_0["slot_class"], "rootfs");
+        assert_eq!(rootfs_0["fs_type"], "ext4");
+        assert_eq!(rootfs_0["state"], "booted");
+        assert_eq!(rootfs_0["bundle_version"], "24.04-20240415070800");
+        assert_eq!(rootfs_0["installed_count"], "3");
+        assert_eq!(rootfs_0["size"], "1234");
+        assert_eq!(slots["rootfs_1"]["state"], "inactive");
+
+        // The primary slot is not the booted one
+        assert!(rauc.should_reboot.try_get().unwrap());
+
+        println!("Start an installation");
+        mock.modify::<RaucInstaller, _>(RAUC_PATH, &["Operation", "Progress"], |installer| {
+            installer.operation = "installing".to_string();
+            installer.progress = (40, "Copying image to rootfs.1".to_string(), 2);
+        });
+
+        block_on(sleep(Duration::from_millis(500)));
+
+        assert_eq!(rauc.operation.try_get().unwrap(), "installing");
+
+        // The installation was not started via the tacd, but is tracked anyways
+        let session = rauc.install_session.try_get().unwrap();
+        assert_eq!(session.source, None);
+        assert_eq!(session.outcome, InstallOutcome::Running);
+
+        let progress = rauc.progress.try_get().unwrap();
+        assert_eq!(progress.percentage, 40);
+        assert_eq!(progress.message, "Copying image to rootfs.1");
+        assert_eq!(progress.nesting_depth, 2);
+
+        println!("Fail the installation");
+        mock.modify::<RaucInstaller, _>(RAUC_PATH, &["Operation", "LastError"], |installer| {
+            installer.operation = "idle".to_string();
+            installer.last_error = "Installation error: Bundle is not compatible".to_string();
+        });
+
+        block_on(sleep(Duration::from_millis(500)));
+
+        assert_eq!(rauc.operation.try_get().unwrap(), "idle");
+        assert_eq!(
+            rauc.last_error.try_get().unwrap(),
+            "Installation error: Bundle is not compatible"
+        );
+        assert_eq!(
+            rauc.install_session.try_get().unwrap().outcome,
+            InstallOutcome::Failed("Installation error: Bundle is not compatible".to_string())
+        );
+
+        println!("Request bundle installations");
+        rauc.install.set("/tmp/bundle.raucb".to_string());
+        rauc.install
+            .set("https://example.com/bundle.raucb".to_string());
+
+        block_on(sleep(Duration::from_millis(500)));
+
+        // Only bundles from the network may be installed via the API
+        let installed = mock.inspect(RAUC_PATH, |installer: &RaucInstaller| {
+            installer.installed.clone()
+        });
+        assert_eq!(installed, vec!["https://example.com/bundle.raucb"]);
+
+        let session = rauc.install_session.try_get().unwrap();
+        assert_eq!(
+            session.source.as_deref(),
+            Some("https://example.com/bundle.raucb")
+        );
+        assert_eq!(session.outcome, InstallOutcome::Running);
+
+        println!("Finish the installation");
+        mock.modify::<RaucInstaller, _>(RAUC_PATH, &["Operation", "LastError"], |installer| {
+            installer.operation = "installing".to_string();
+            installer.last_error = "".to_string();
+        });
+
+        block_on(sleep(Duration::from_millis(500)));
+
+        mock.modify::<RaucInstaller, _>(RAUC_PATH, &["Operation"], |installer| {
+            installer.operation = "idle".to_string();
+        });
+
+        block_on(sleep(Duration::from_millis(500)));
+
+        let session = rauc.install_session.try_get().unwrap();
+        assert_eq!(
+            session.source.as_deref(),
+            Some("https://example.com/bundle.raucb")
+        );
+        assert_eq!(session.outcome, InstallOutcome::Succeeded);
+    }
+
+    #[test]
+    fn install_session_restore() {
+        let mock = MockBus::new(|builder| {
+            builder.serve_at(
+                RAUC_PATH,
+                RaucInstaller {
+                    operation: "installing".to_string(),
+                    progress: (40, "Copying image to rootfs.1".to_string(), 2),
+                    last_error: "".to_string(),
+                    primary: "rootfs.0".to_string(),
+                    slots: Vec::new(),
+                    installed: Vec::new(),
+                },
+            )
+        });
+
+        let mut wtb = WatchedTasksBuilder::new();
+        let mut bb = BrokerBuilder::new();
+
+        let interlock = Interlock::new(&mut bb, Topic::anonymous(None));
+        let confirmation = Confirmation::new(&mut bb);
+        let rauc = Rauc::new(&mut bb, &mut wtb, &mock.bus, interlock, confirmation).unwrap();
+
+        // This is what loading the session from the state file looks like
+        let restore = |rauc: &Rauc| {
+            let session: InstallSession = serde_json::from_str(
+                r#"{
+                    "started": 1700000000000.0,
+                    "source": "https://example.com/bundle.raucb",
+                    "channel": "stable",
+                    "outcome": "Running"
+                }"#,
+            )
+            .unwrap();
+
+            rauc.install_session.set(session);
+        };
+
+        println!("Restore a session while RAUC is still installing");
+        restore(&rauc);
+
+        block_on(sleep(Duration::from_millis(500)));
+
+        let session = rauc.install_session.try_get().unwrap();
+        assert_eq!(session.channel.as_deref(), Some("stable"));
+        assert_eq!(session.outcome, InstallOutcome::Running);
+
+        mock.modify::<RaucInstaller, _>(RAUC_PATH, &["Operation"], |installer| {
+            installer.operation = "idle".to_string();
+        });
+
+        block_on(sleep(Duration::from_millis(500)));
+
+        assert_eq!(
+            rauc.install_session.try_get().unwrap().outcome,
+            InstallOutcome::Succeeded
+        );
+
+        println!("Restore a session that ended while the tacd was not running");
+        restore(&rauc);
+
+        block_on(sleep(Duration::from_millis(500)));
+
+        let session = rauc.install_session.try_get().unwrap();
+        assert_eq!(session.started, 1700000000000.0);
+        assert_eq!(session.outcome, InstallOutcome::Unknown);
+    }
 }
@@ -0,0 +1,195 @@
+// This file is part of tacd, the LXA TAC system daemon
+// Copyright (C) 2024 Pengutronix e.K.
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along
+// with this program; if not, write to the Free Software Foundation, Inc.,
+// 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+
+//! Look into bundles before installing them
+//!
+//! RAUC can tell what a bundle contains without installing it, so that
+//! users can check e.g. the version and build date of a bundle first.
+
+use std::collections::HashMap;
+
+use anyhow::Result;
+use async_std::stream::StreamExt;
+use async_std::sync::Arc;
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use super::update_channels::{zvariant_walk_nested_dicts, Channel, CredentialStore, Credentials};
+use super::InstallerProxy;
+use crate::broker::Topic;
+use crate::dbus::SystemBus;
+use crate::watched_tasks::WatchedTasksBuilder;
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct BundleInfo {
+    pub url: String,
+    pub compatible: Option<String>,
+    pub version: Option<String>,
+    /// The date the bundle was built at (if RAUC knows it)
+    pub build: Option<String>,
+    /// Why the bundle could not be inspected (if it could not)
+    pub error: Option<String>,
+}
+
+impl BundleInfo {
+    fn from_dict(url: String, bundle: &zvariant::Dict) -> Result<Self> {
+        let compatible = zvariant_walk_nested_dicts(bundle, &["update", "compatible"])?;
+        let version = zvariant_walk_nested_dicts(bundle, &["update", "version"])?;
+        let build = zvariant_walk_nested_dicts(bundle, &["update", "build"]).ok();
+
+        Ok(Self {
+            url,
+            compatible: Some(compatible),
+            version: Some(version),
+            build,
+            error: None,
+        })
+    }
+
+    fn failed(url: String, error: String) -> Self {
+        Self {
+            url,
+            compatible: None,
+            version: None,
+            build: None,
+            error: Some(error),
+        }
+    }
+}
+
+async fn inspect(
+    bus: &SystemBus,
+    url: &str,
+    channels: &[Channel],
+    store: &CredentialStore,
+) -> Result<zvariant::Dict<'static, 'static>> {
+    // Authenticate against the update server if the bundle belongs to
+    // an update channel that requires it.
+    let http_headers = channels
+        .iter()
+        .find(|ch| ch.url == url)
+        .and_then(|ch| ch.credentials(store))
+        .map(Credentials::rauc_http_headers);
+
+    let mut args = HashMap::new();
+
+    if let Some(http_headers) = &http_headers {
+        args.insert("http-headers", http_headers);
+    }
+
+    let proxy = InstallerProxy::new(&bus.connection()).await?;
+    let bundle = proxy.inspect_bundle(url, args).await?;
+
+    Ok(bundle.into())
+}
+
+/// Inspect the bundles whose URLs are published via `requests`
+/// and publish what they contain via `info`
+pub(super) fn run(
+    wtb: &mut WatchedTasksBuilder,
+    bus: SystemBus,
+    requests: Arc<Topic<String>>,
+    info: Arc<Topic<Option<BundleInfo>>>,
+    channels: Arc<Topic<Vec<Channel>>>,
+    credentials: Arc<Topic<CredentialStore>>,
+) -> Result<()> {
+    let (mut requests, _) = requests.subscribe_unbounded();
+
+    wtb.spawn_task("rauc-inspect-bundle", async move {
+        while let Some(url) = requests.next().await {
+            // The same poor-mans validation as for installations
+            if !url.starts_with("http://") && !url.starts_with("https://") {
+                continue;
+            }
+
+            let channels = channels.try_get().unwrap_or_default();
+            let store = credentials.try_get().unwrap_or_default();
+
+            let res = inspect(&bus, &url, &channels, &store)
+                .await
+                .and_then(|bundle| BundleInfo::from_dict(url.clone(), &bundle));
+
+            let bundle_info = res.unwrap_or_else(|e| {
+                warn!("Failed to inspect bundle {url}: {e}");
+                BundleInfo::failed(url, e.to_string())
+            });
+
+            info.set(Some(bundle_info));
+        }
+
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use zvariant::OwnedValue;
+
+    use super::BundleInfo;
+
+    fn bundle(update: &[(&str, &str)]) -> zvariant::Dict<'static, 'static> {
+        let update: HashMap<String, String> = update
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+
+        let bundle: HashMap<String, OwnedValue> = [("update".into(), update.into())].into();
+
+        bundle.into()
+    }
+
+    #[test]
+    fn from_dict() {
+        let url = "https://example.com/bundle.raucb".to_string();
+
+        println!("All information is picked up");
+        let info = BundleInfo::from_dict(
+            url.clone(),
+            &bundle(&[
+                ("compatible", "Linux Automation GmbH - LXA TAC"),
+                ("version", "24.04-20240415070800"),
+                ("build", "20240415070800"),
+            ]),
+        )
+        .unwrap();
+
+        assert_eq!(
+            info.compatible.as_deref(),
+            Some("Linux Automation GmbH - LXA TAC")
+        );
+        assert_eq!(info.version.as_deref(), Some("24.04-20240415070800"));
+        assert_eq!(info.build.as_deref(), Some("20240415070800"));
+        assert_eq!(info.error, None);
+
+        println!("The build date is optional");
+        let info = BundleInfo::from_dict(
+            url.clone(),
+            &bundle(&[
+                ("compatible", "Linux Automation GmbH - LXA TAC"),
+                ("version", "24.04-20240415070800"),
+            ]),
+        )
+        .unwrap();
+
+        assert_eq!(info.build, None);
+
+        println!("The version is not");
+        assert!(BundleInfo::from_dict(url, &bundle(&[("compatible", "LXA TAC")])).is_err());
+    }
+}
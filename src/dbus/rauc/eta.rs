@@ -0,0 +1,182 @@
+// This file is part of tacd, the LXA TAC system daemon
+// Copyright (C) 2024 Pengutronix e.K.
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along
+// with this program; if not, write to the Free Software Foundation, Inc.,
+// 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+
+//! Estimate the remaining time of an installation
+//!
+//! RAUC's progress is not linear in time (e.g. downloading the bundle is
+//! a single step), so the estimate is based on a smoothed rate of progress
+//! and comes with a rough indication of how much it can be trusted.
+
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+/// Weight of the most recent rate of progress in the smoothed rate
+const SMOOTHING: f64 = 0.3;
+
+/// Number of progress updates needed before the estimate is considered
+/// to be of medium / high confidence
+const MEDIUM_CONFIDENCE_UPDATES: u32 = 3;
+const HIGH_CONFIDENCE_UPDATES: u32 = 8;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub enum EtaConfidence {
+    Low,
+    Medium,
+    High,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct Eta {
+    /// Estimated time until the installation is complete in seconds
+    pub remaining: u64,
+    pub confidence: EtaConfidence,
+}
+
+impl Eta {
+    /// A short human readable representation like "~3min"
+    pub fn format(&self) -> String {
+        let prefix = match self.confidence {
+            EtaConfidence::Low => "~",
+            EtaConfidence::Medium | EtaConfidence::High => "",
+        };
+
+        match self.remaining {
+            0..=59 => format!("{prefix}{}s", self.remaining),
+            _ => format!("{prefix}{}min", (self.remaining + 30) / 60),
+        }
+    }
+}
+
+#[cfg_attr(feature = "demo_mode", allow(dead_code))]
+pub(super) struct EtaEstimator {
+    /// Time and percentage of the last change in progress
+    last: Option<(Instant, i32)>,
+    /// Smoothed rate of progress in percent per second
+    rate: Option<f64>,
+    updates: u32,
+}
+
+#[cfg_attr(feature = "demo_mode", allow(dead_code))]
+impl EtaEstimator {
+    pub(super) fn new() -> Self {
+        Self {
+            last: None,
+            rate: None,
+            updates: 0,
+        }
+    }
+
+    /// Add a progress update and return the new estimate (if there is one)
+    pub(super) fn update(&mut self, percentage: i32, now: Instant) -> Option<Eta> {
+        if percentage >= 100 {
+            *self = Self::new();
+            return None;
+        }
+
+        match self.last {
+            // Progress only ever goes forward during an installation,
+            // so this has to be a new one.
+            Some((_, last)) if percentage < last => {
+                *self = Self::new();
+                self.last = Some((now, percentage));
+            }
+            Some((since, last)) if percentage > last => {
+                let elapsed = now.duration_since(since).as_secs_f64();
+
+                if elapsed > 0.0 {
+                    let rate = f64::from(percentage - last) / elapsed;
+
+                    self.rate = Some(match self.rate {
+                        Some(prev) => SMOOTHING * rate + (1.0 - SMOOTHING) * prev,
+                        None => rate,
+                    });
+                    self.updates += 1;
+                }
+
+                self.last = Some((now, percentage));
+            }
+            Some(_) => {}
+            None => self.last = Some((now, percentage)),
+        }
+
+        let rate = self.rate.filter(|r| *r > 0.0)?;
+        let remaining = Duration::from_secs_f64(f64::from(100 - percentage) / rate);
+
+        let confidence = match self.updates {
+            u if u >= HIGH_CONFIDENCE_UPDATES => EtaConfidence::High,
+            u if u >= MEDIUM_CONFIDENCE_UPDATES => EtaConfidence::Medium,
+            _ => EtaConfidence::Low,
+        };
+
+        Some(Eta {
+            remaining: remaining.as_secs(),
+            confidence,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{Eta, EtaConfidence, EtaEstimator};
+
+    #[test]
+    fn eta() {
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let mut estimator = EtaEstimator::new();
+
+        println!("No estimate without a rate of progress");
+        assert_eq!(estimator.update(0, at(0)), None);
+        assert_eq!(estimator.update(0, at(5)), None);
+
+        println!("Constant progress of 1% per second");
+        let eta = estimator.update(10, at(10)).unwrap();
+        assert_eq!(eta.remaining, 90);
+        assert_eq!(eta.confidence, EtaConfidence::Low);
+
+        for i in 2..=8 {
+            estimator.update(i * 10, at((i * 10) as u64));
+        }
+
+        let eta = estimator.update(90, at(90)).unwrap();
+        assert_eq!(eta.remaining, 10);
+        assert_eq!(eta.confidence, EtaConfidence::High);
+
+        println!("Completed installations have no estimate");
+        assert_eq!(estimator.update(100, at(100)), None);
+
+        println!("Going backwards starts a new estimate");
+        estimator.update(50, at(110));
+        assert_eq!(estimator.update(10, at(120)), None);
+
+        println!("Formatting");
+        let eta = Eta {
+            remaining: 150,
+            confidence: EtaConfidence::Low,
+        };
+        assert_eq!(eta.format(), "~3min");
+
+        let eta = Eta {
+            remaining: 42,
+            confidence: EtaConfidence::High,
+        };
+        assert_eq!(eta.format(), "42s");
+    }
+}
@@ -0,0 +1,313 @@
+// This file is part of tacd, the LXA TAC system daemon
+// Copyright (C) 2024 Pengutronix e.K.
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along
+// with this program; if not, write to the Free Software Foundation, Inc.,
+// 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+
+//! Configure when and how the update channels are polled
+//!
+//! Fleets of TACs that poll the update server at the same time of day can
+//! cause considerable load. The polling interval and a time of day window
+//! can be configured per TAC to spread the polls out.
+
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use async_std::sync::Arc;
+use async_std::task::sleep;
+use chrono::{Local, NaiveTime, Timelike};
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::broker::Topic;
+
+#[cfg(not(feature = "demo_mode"))]
+mod proxy {
+    pub(super) use std::fs::{create_dir_all, remove_file, write};
+    pub(super) use std::io::ErrorKind;
+
+    pub(super) use async_std::stream::StreamExt;
+
+    pub(super) use crate::dbus::systemd::manager::ManagerProxy;
+    pub(super) use crate::dbus::SystemBus;
+    pub(super) use crate::fs_root;
+    pub(super) use crate::watched_tasks::WatchedTasksBuilder;
+
+    /// Runtime drop-in for the RAUC service. It is re-created on every start
+    /// of the tacd from the persisted polling config.
+    pub(super) const DROPIN_PATH: &str = "/run/systemd/system/rauc.service.d/50-tacd-proxy.conf";
+    pub(super) const RAUC_SERVICE: &str = "rauc.service";
+}
+
+#[cfg(not(feature = "demo_mode"))]
+use proxy::*;
+
+/// Polling more often than this does not provide any benefit
+const MIN_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// How often to check if the polling window opened (or was re-configured)
+const WINDOW_RECHECK: Duration = Duration::from_secs(60);
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct PollingWindow {
+    /// Local time of day at which polling is allowed to start, e.g. "01:00"
+    pub start: String,
+    /// Local time of day at which polling has to stop, e.g. "05:30".
+    /// The window may span midnight.
+    pub end: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug, Default)]
+pub struct PollingConfig {
+    /// Time (in seconds) between polls of an update channel.
+    /// Overrides the interval given in the channel definition.
+    pub interval: Option<u64>,
+    /// Only poll the update channels during this time of day
+    pub window: Option<PollingWindow>,
+    /// HTTP proxy to use to access the update server,
+    /// e.g. "http://proxy.example.com:3128"
+    pub proxy: Option<String>,
+}
+
+impl PollingWindow {
+    fn parse(&self) -> Result<(NaiveTime, NaiveTime)> {
+        let start = NaiveTime::parse_from_str(&self.start, "%H:%M")?;
+        let end = NaiveTime::parse_from_str(&self.end, "%H:%M")?;
+
+        Ok((start, end))
+    }
+}
+
+impl PollingConfig {
+    /// The time to wait between two polls of a channel (if it should be
+    /// polled periodically at all)
+    pub(super) fn interval(&self, channel: Option<Duration>) -> Option<Duration> {
+        self.interval
+            .map(|secs| Duration::from_secs(secs).max(MIN_INTERVAL))
+            .or(channel)
+    }
+}
+
+/// Time until `now` is inside of the window from `start` to `end`
+///
+/// Returns zero if it already is. A window with `start` == `end` is open
+/// all day.
+fn until_window(start: NaiveTime, end: NaiveTime, now: NaiveTime) -> Duration {
+    let secs = |t: NaiveTime| i64::from(t.num_seconds_from_midnight());
+    let day = 24 * 60 * 60;
+
+    let (start, end, now) = (secs(start), secs(end), secs(now));
+
+    let inside = match start <= end {
+        true => start == end || (start <= now && now < end),
+        false => now >= start || now < end,
+    };
+
+    match inside {
+        true => Duration::ZERO,
+        false => Duration::from_secs((start - now).rem_euclid(day) as u64),
+    }
+}
+
+/// Wait until the current time of day is inside of the polling window
+pub(super) async fn wait_for_window(config: &Arc<Topic<PollingConfig>>) {
+    loop {
+        let config = config.try_get().unwrap_or_default();
+
+        let delay = match config.window.as_ref().map(PollingWindow::parse) {
+            None => return,
+            Some(Ok((start, end))) => until_window(start, end, Local::now().time()),
+            Some(Err(e)) => {
+                warn!("Ignoring malformed update polling window: {e}");
+                return;
+            }
+        };
+
+        if delay.is_zero() {
+            return;
+        }
+
+        sleep(delay.min(WINDOW_RECHECK)).await;
+    }
+}
+
+/// Build a systemd drop-in that makes RAUC use `proxy`
+#[cfg_attr(feature = "demo_mode", allow(dead_code))]
+fn proxy_dropin(proxy: &str) -> Result<String> {
+    let scheme_ok = ["http://", "https://", "socks5://", "socks5h://"]
+        .iter()
+        .any(|scheme| proxy.starts_with(scheme));
+
+    // Make sure the URL can not break out of the Environment= line.
+    // The % character would be interpreted as systemd specifier.
+    let chars_ok = proxy
+        .chars()
+        .all(|c| c.is_ascii_graphic() && !matches!(c, '"' | '\'' | '\\' | '%'));
+
+    if !scheme_ok || !chars_ok {
+        bail!("\"{proxy}\" is not a valid proxy URL");
+    }
+
+    Ok(format!(
+        "[Service]\nEnvironment=\"http_proxy={proxy}\" \"https_proxy={proxy}\"\n"
+    ))
+}
+
+#[cfg(not(feature = "demo_mode"))]
+fn write_dropin(dropin: Option<&str>) -> Result<()> {
+    let path = fs_root::path(DROPIN_PATH);
+
+    match dropin {
+        Some(dropin) => {
+            if let Some(dir) = path.parent() {
+                create_dir_all(dir)?;
+            }
+
+            write(&path, dropin)?;
+        }
+        None => match remove_file(&path) {
+            Err(e) if e.kind() != ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        },
+    }
+
+    Ok(())
+}
+
+#[cfg(not(feature = "demo_mode"))]
+async fn restart_rauc(bus: &SystemBus) -> Result<()> {
+    let manager = ManagerProxy::new(&bus.connection()).await?;
+
+    manager.reload().await?;
+    manager.try_restart_unit(RAUC_SERVICE, "replace").await?;
+
+    Ok(())
+}
+
+/// Make RAUC use the configured proxy
+///
+/// RAUC (or rather libcurl) takes the proxy from its environment,
+/// which is set via a systemd drop-in. RAUC only picks up the new
+/// environment when it is restarted, which is delayed until it is idle.
+#[cfg(not(feature = "demo_mode"))]
+pub(super) fn handle_proxy(
+    wtb: &mut WatchedTasksBuilder,
+    bus: SystemBus,
+    config: Arc<Topic<PollingConfig>>,
+    operation: Arc<Topic<String>>,
+) -> Result<()> {
+    let (mut config_stream, _) = config.subscribe_unbounded();
+
+    wtb.spawn_task("rauc-proxy", async move {
+        // The drop-in lives in /run, so it is gone after a reboot
+        let mut current = std::fs::read_to_string(fs_root::path(DROPIN_PATH)).ok();
+
+        while let Some(config) = config_stream.next().await {
+            let dropin = config
+                .proxy
+                .as_deref()
+                .and_then(|proxy| match proxy_dropin(proxy) {
+                    Ok(dropin) => Some(dropin),
+                    Err(e) => {
+                        warn!("Not using update proxy: {e}");
+                        None
+                    }
+                });
+
+            if dropin == current {
+                continue;
+            }
+
+            if let Err(e) = write_dropin(dropin.as_deref()) {
+                warn!("Failed to set up the proxy for RAUC: {e}");
+                continue;
+            }
+
+            current = dropin;
+
+            operation.wait_for("idle".to_string()).await;
+
+            if let Err(e) = restart_rauc(&bus).await {
+                warn!("Failed to restart RAUC to apply the proxy: {e}");
+            }
+        }
+
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use chrono::NaiveTime;
+
+    use super::{proxy_dropin, until_window, PollingConfig, MIN_INTERVAL};
+
+    #[test]
+    fn window() {
+        let t = |h, m| NaiveTime::from_hms_opt(h, m, 0).unwrap();
+        let hours = |h| Duration::from_secs(h * 60 * 60);
+
+        println!("Inside of the window there is no need to wait");
+        assert_eq!(until_window(t(1, 0), t(5, 0), t(1, 0)), Duration::ZERO);
+        assert_eq!(until_window(t(1, 0), t(5, 0), t(4, 59)), Duration::ZERO);
+
+        println!("Outside of it wait for the start");
+        assert_eq!(until_window(t(1, 0), t(5, 0), t(5, 0)), hours(20));
+        assert_eq!(until_window(t(1, 0), t(5, 0), t(0, 0)), hours(1));
+
+        println!("Windows may span midnight");
+        assert_eq!(until_window(t(22, 0), t(2, 0), t(23, 0)), Duration::ZERO);
+        assert_eq!(until_window(t(22, 0), t(2, 0), t(1, 0)), Duration::ZERO);
+        assert_eq!(until_window(t(22, 0), t(2, 0), t(12, 0)), hours(10));
+
+        println!("Empty windows are open all day");
+        assert_eq!(until_window(t(3, 0), t(3, 0), t(12, 0)), Duration::ZERO);
+    }
+
+    #[test]
+    fn interval() {
+        let channel = Some(Duration::from_secs(24 * 60 * 60));
+
+        let mut config = PollingConfig::default();
+        assert_eq!(config.interval(channel), channel);
+        assert_eq!(config.interval(None), None);
+
+        config.interval = Some(7 * 24 * 60 * 60);
+        assert_eq!(
+            config.interval(None),
+            Some(Duration::from_secs(7 * 24 * 60 * 60))
+        );
+
+        config.interval = Some(1);
+        assert_eq!(config.interval(channel), Some(MIN_INTERVAL));
+    }
+
+    #[test]
+    fn proxy() {
+        assert_eq!(
+            proxy_dropin("http://proxy:3128").unwrap(),
+            concat!(
+                "[Service]\n",
+                "Environment=\"http_proxy=http://proxy:3128\" \"https_proxy=http://proxy:3128\"\n"
+            )
+        );
+
+        assert!(proxy_dropin("proxy:3128").is_err());
+        assert!(proxy_dropin("http://proxy:3128\nExecStart=/bin/sh").is_err());
+        assert!(proxy_dropin("http://proxy\" \"LD_PRELOAD=x").is_err());
+        assert!(proxy_dropin("http://%H:3128").is_err());
+    }
+}
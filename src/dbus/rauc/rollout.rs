@@ -0,0 +1,370 @@
+// This file is part of tacd, the LXA TAC system daemon
+// Copyright (C) 2024 Pengutronix e.K.
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along
+// with this program; if not, write to the Free Software Foundation, Inc.,
+// 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+
+use std::fs::read_to_string;
+use std::time::{Duration, SystemTime};
+
+use anyhow::Result;
+use async_std::future::timeout;
+use async_std::stream::StreamExt;
+use async_std::sync::Arc;
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use super::{InstallOutcome, InstallSession, InstallerProxy, SlotStatus};
+use crate::broker::Topic;
+use crate::dbus::networkmanager::LinkInfo;
+use crate::dbus::SystemBus;
+use crate::dut_power::OutputState;
+use crate::watched_tasks::WatchedTasksBuilder;
+
+const BOOT_ID_PATH: &str = "/proc/sys/kernel/random/boot_id";
+
+/// How often the health of the TAC is checked
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The TAC has to be healthy for this long (in seconds) before an update
+/// is marked good
+const STABLE_TIME: u64 = 30;
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct RolloutConfig {
+    /// Reboot into freshly installed updates and only mark them good once
+    /// the TAC is known to work
+    pub enabled: bool,
+    /// Time (in seconds) an update has to become healthy in after booting it
+    pub timeout: u64,
+}
+
+impl Default for RolloutConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            timeout: 300,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct StagedUpdate {
+    /// The slot the update was installed to, e.g. "rootfs_1"
+    pub slot: String,
+    pub version: Option<String>,
+    /// The boot_id of the kernel that was running during the installation
+    pub boot_id: String,
+}
+
+/// The phases a staged update goes through
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub enum RolloutPhase {
+    Idle,
+    /// The update was installed to the other slot. Waiting for the reboot
+    /// into it.
+    Staged(StagedUpdate),
+    /// The update was booted. Waiting for the TAC to become healthy.
+    Verifying {
+        update: StagedUpdate,
+        /// Seconds since Unix Epoch 0 at which the update is given up on
+        deadline: u64,
+    },
+    /// The TAC was healthy and the update was marked good
+    Confirmed(StagedUpdate),
+    /// The update was marked bad and the previous slot is used again
+    RolledBack {
+        update: StagedUpdate,
+        reason: String,
+    },
+}
+
+enum Verdict {
+    Pending,
+    Good,
+    Bad(String),
+}
+
+/// Decide whether a freshly booted update works based on periodic checks
+struct Verifier {
+    deadline: u64,
+    healthy_since: Option<u64>,
+}
+
+impl Verifier {
+    fn new(deadline: u64) -> Self {
+        Self {
+            deadline,
+            healthy_since: None,
+        }
+    }
+
+    fn step(&mut self, now: u64, problems: &[String]) -> Verdict {
+        if problems.is_empty() {
+            let since = *self.healthy_since.get_or_insert(now);
+
+            if now >= since + STABLE_TIME {
+                return Verdict::Good;
+            }
+        } else {
+            self.healthy_since = None;
+        }
+
+        if now < self.deadline {
+            return Verdict::Pending;
+        }
+
+        if problems.is_empty() {
+            Verdict::Bad("The TAC was not healthy for long enough".to_string())
+        } else {
+            Verdict::Bad(problems.join(", "))
+        }
+    }
+}
+
+/// Reasons for the TAC not to be considered healthy
+fn health_problems(uplink: Option<LinkInfo>, dut_pwr: Option<OutputState>) -> Vec<String> {
+    let mut problems = Vec::new();
+
+    if !uplink.map_or(false, |link| link.carrier) {
+        problems.push("The uplink network is down".to_string());
+    }
+
+    match dut_pwr {
+        Some(OutputState::InvertedPolarity)
+        | Some(OutputState::OverCurrent)
+        | Some(OutputState::OverVoltage)
+        | Some(OutputState::RealtimeViolation) => {
+            problems.push("The DUT power protection tripped".to_string())
+        }
+        _ => {}
+    }
+
+    problems
+}
+
+fn booted_slot(slots: &SlotStatus) -> Option<String> {
+    slots
+        .iter()
+        .find(|(_, info)| info.get("state").map_or(false, |s| s == "booted"))
+        .map(|(name, _)| name.clone())
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|t| t.as_secs())
+        .unwrap_or(0)
+}
+
+async fn mark(bus: &SystemBus, state: &str) {
+    let res = match InstallerProxy::new(&bus.connection()).await {
+        Ok(proxy) => proxy.mark(state, "booted").await.map(|_| ()),
+        Err(e) => Err(e),
+    };
+
+    if let Err(e) = res {
+        warn!("Failed to mark the booted slot {state}: {e}");
+    }
+}
+
+pub(super) struct Topics {
+    pub config: Arc<Topic<RolloutConfig>>,
+    pub phase: Arc<Topic<RolloutPhase>>,
+    pub health: Arc<Topic<Vec<String>>>,
+    pub session: Arc<Topic<InstallSession>>,
+    pub slot_status: Arc<Topic<Arc<SlotStatus>>>,
+    pub primary: Arc<Topic<String>>,
+}
+
+/// Stage installed updates, verify them after the reboot and fall back to
+/// the previous slot if they do not become healthy in time
+///
+/// This requires the automatic marking of booted slots as good (e.g. via
+/// rauc-mark-good.service) to be disabled.
+pub(super) fn run(
+    wtb: &mut WatchedTasksBuilder,
+    bus: SystemBus,
+    topics: Topics,
+    uplink: Arc<Topic<LinkInfo>>,
+    dut_pwr: Arc<Topic<OutputState>>,
+    reboot: Arc<Topic<bool>>,
+) -> Result<()> {
+    let boot_id = read_to_string(BOOT_ID_PATH)
+        .map(|id| id.trim().to_string())
+        .unwrap_or_default();
+
+    let (mut session_stream, _) = topics.session.clone().subscribe_unbounded();
+
+    wtb.spawn_task("rauc-rollout", async move {
+        let mut install_running = false;
+        let mut install_done = false;
+        let mut verifier: Option<Verifier> = None;
+
+        loop {
+            // Wait for the next change of the install session or the next check
+            if let Ok(session) = timeout(CHECK_INTERVAL, session_stream.next()).await {
+                let session = match session {
+                    Some(s) => s,
+                    None => break Ok(()),
+                };
+
+                let succeeded = session.outcome == InstallOutcome::Succeeded;
+                install_done |= install_running && succeeded;
+                install_running = session.is_running();
+
+                continue;
+            }
+
+            let problems = health_problems(uplink.try_get(), dut_pwr.try_get());
+            topics.health.set_if_changed(problems.clone());
+
+            let config = topics.config.try_get().unwrap_or_default();
+            let slots = topics.slot_status.try_get().unwrap_or_default();
+            let booted = booted_slot(&slots);
+
+            // The slot status is refreshed after an installation, so give
+            // it one CHECK_INTERVAL before looking at the new primary slot.
+            if install_done && config.enabled {
+                let primary = topics.primary.try_get();
+
+                if let Some(slot) = primary.filter(|p| Some(p) != booted.as_ref()) {
+                    let version = slots
+                        .get(&slot)
+                        .and_then(|info| info.get("bundle_version"))
+                        .cloned();
+
+                    topics.phase.set(RolloutPhase::Staged(StagedUpdate {
+                        slot,
+                        version,
+                        boot_id: boot_id.clone(),
+                    }));
+
+                    reboot.set(true);
+                } else {
+                    warn!("Not staging the update, as it does not boot into another slot");
+                }
+            }
+
+            install_done = false;
+
+            match topics.phase.try_get().unwrap_or(RolloutPhase::Idle) {
+                RolloutPhase::Staged(update) if update.boot_id != boot_id => {
+                    let phase = match booted {
+                        Some(slot) if slot == update.slot => RolloutPhase::Verifying {
+                            update,
+                            deadline: now() + config.timeout,
+                        },
+                        Some(_) => RolloutPhase::RolledBack {
+                            update,
+                            reason: "The bootloader did not boot the update".to_string(),
+                        },
+                        None => continue,
+                    };
+
+                    topics.phase.set(phase);
+                }
+                RolloutPhase::Verifying { update, deadline } => {
+                    let verdict = verifier
+                        .get_or_insert_with(|| Verifier::new(deadline))
+                        .step(now(), &problems);
+
+                    match verdict {
+                        Verdict::Pending => {}
+                        Verdict::Good => {
+                            mark(&bus, "good").await;
+                            topics.phase.set(RolloutPhase::Confirmed(update));
+                        }
+                        Verdict::Bad(reason) => {
+                            warn!("Rolling back the update: {reason}");
+
+                            mark(&bus, "bad").await;
+                            topics
+                                .phase
+                                .set(RolloutPhase::RolledBack { update, reason });
+                            reboot.set(true);
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+    })?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{health_problems, Verdict, Verifier, STABLE_TIME};
+    use crate::dbus::networkmanager::LinkInfo;
+    use crate::dut_power::OutputState;
+
+    #[test]
+    fn health() {
+        let up = Some(LinkInfo {
+            speed: 1000,
+            carrier: true,
+        });
+        let down = Some(LinkInfo {
+            speed: 0,
+            carrier: false,
+        });
+
+        assert!(health_problems(up.clone(), Some(OutputState::On)).is_empty());
+        assert!(health_problems(up.clone(), Some(OutputState::Off)).is_empty());
+        assert_eq!(health_problems(down, Some(OutputState::Off)).len(), 1);
+        assert_eq!(health_problems(None, None).len(), 1);
+        assert_eq!(
+            health_problems(up, Some(OutputState::RealtimeViolation)).len(),
+            1
+        );
+    }
+
+    #[test]
+    fn verifier() {
+        let problem = vec!["The uplink network is down".to_string()];
+
+        println!("Updates have to stay healthy for a while");
+        let mut verifier = Verifier::new(1000);
+
+        assert!(matches!(verifier.step(100, &[]), Verdict::Pending));
+        assert!(matches!(verifier.step(110, &problem), Verdict::Pending));
+        assert!(matches!(verifier.step(120, &[]), Verdict::Pending));
+        assert!(matches!(
+            verifier.step(120 + STABLE_TIME - 1, &[]),
+            Verdict::Pending
+        ));
+        assert!(matches!(
+            verifier.step(120 + STABLE_TIME, &[]),
+            Verdict::Good
+        ));
+
+        println!("Updates that do not become healthy are rolled back");
+        let mut verifier = Verifier::new(1000);
+
+        assert!(matches!(verifier.step(990, &problem), Verdict::Pending));
+
+        match verifier.step(1000, &problem) {
+            Verdict::Bad(reason) => assert_eq!(reason, "The uplink network is down"),
+            _ => panic!("Update was not rolled back"),
+        }
+
+        println!("Updates that become healthy too late are rolled back");
+        let mut verifier = Verifier::new(1000);
+
+        assert!(matches!(verifier.step(990, &[]), Verdict::Pending));
+        assert!(matches!(verifier.step(1000, &[]), Verdict::Bad(_)));
+    }
+}
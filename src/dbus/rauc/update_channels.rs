@@ -16,20 +16,21 @@
 // 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
 
 use std::collections::HashMap;
-use std::fs::{read_dir, read_to_string, DirEntry};
+use std::fs::{read_dir, read_to_string, rename, DirEntry, File, OpenOptions};
+use std::io::Write;
 use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::OpenOptionsExt;
 use std::path::Path;
 use std::time::Duration;
 
 use anyhow::{anyhow, bail, Result};
+use base64::Engine;
 use serde::{Deserialize, Serialize};
+use serde_json::{from_reader, to_vec_pretty};
 
 use super::{compare_versions, InstallerProxy, SlotStatus};
+use crate::fs_root;
 
-#[cfg(feature = "demo_mode")]
-const ENABLE_DIR: &str = "demo_files/etc/rauc/certificates-enabled";
-
-#[cfg(not(feature = "demo_mode"))]
 const ENABLE_DIR: &str = "/etc/rauc/certificates-enabled";
 
 const ONE_MINUTE: Duration = Duration::from_secs(60);
@@ -43,6 +44,33 @@ pub struct UpstreamBundle {
     pub newer_than_installed: bool,
 }
 
+/// The kind of authentication the update server of a channel expects
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Authentication {
+    Basic,
+    Bearer,
+}
+
+/// Secrets used to authenticate against the update server of a channel
+///
+/// These are never exposed via the API and are stored in a file that is
+/// only readable by the tacd.
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+pub enum Credentials {
+    Basic { username: String, password: String },
+    Bearer { token: String },
+}
+
+/// Set (or remove, if `credentials` is None) the credentials for a channel
+#[derive(Serialize, Deserialize, Clone)]
+pub struct CredentialsRequest {
+    pub channel: String,
+    pub credentials: Option<Credentials>,
+}
+
+pub type CredentialStore = HashMap<String, Credentials>;
+
 #[derive(Serialize, Deserialize, Clone, PartialEq)]
 pub struct Channel {
     pub name: String,
@@ -50,6 +78,8 @@ pub struct Channel {
     pub description: String,
     pub url: String,
     pub polling_interval: Option<Duration>,
+    pub authentication: Option<Authentication>,
+    pub has_credentials: bool,
     pub enabled: bool,
     pub bundle: Option<UpstreamBundle>,
 }
@@ -61,9 +91,66 @@ pub struct ChannelFile {
     pub description: String,
     pub url: String,
     pub polling_interval: Option<String>,
+    pub authentication: Option<Authentication>,
 }
 
-fn zvariant_walk_nested_dicts(map: &zvariant::Dict, path: &[&str]) -> Result<String> {
+impl Credentials {
+    fn authentication(&self) -> Authentication {
+        match self {
+            Self::Basic { .. } => Authentication::Basic,
+            Self::Bearer { .. } => Authentication::Bearer,
+        }
+    }
+
+    /// Get the HTTP headers RAUC should send when accessing the update server
+    ///
+    /// The result can be passed to RAUC as "http-headers" argument.
+    pub(super) fn rauc_http_headers(&self) -> zvariant::Value<'static> {
+        let header = match self {
+            Self::Basic { username, password } => {
+                let encoded = base64::engine::general_purpose::STANDARD
+                    .encode(format!("{username}:{password}"));
+
+                format!("Authorization: Basic {encoded}")
+            }
+            Self::Bearer { token } => format!("Authorization: Bearer {token}"),
+        };
+
+        zvariant::Value::from(vec![header])
+    }
+
+    /// Load the stored credentials for all channels
+    pub(super) fn load(path: &Path) -> Result<CredentialStore> {
+        if !path.is_file() {
+            return Ok(CredentialStore::new());
+        }
+
+        Ok(from_reader(File::open(path)?)?)
+    }
+
+    /// Store the credentials for all channels in a file only we can read
+    pub(super) fn save(path: &Path, store: &CredentialStore) -> Result<()> {
+        let path_tmp = path.with_extension("tmp");
+
+        {
+            let mut fd = OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .mode(0o600)
+                .open(&path_tmp)?;
+
+            fd.write_all(&to_vec_pretty(store)?)?;
+            fd.sync_all()?;
+        }
+
+        rename(path_tmp, path)?;
+
+        Ok(())
+    }
+}
+
+pub(super) fn zvariant_walk_nested_dicts(map: &zvariant::Dict, path: &[&str]) -> Result<String> {
     let (&key, rem) = path
         .split_first()
         .ok_or_else(|| anyhow!("Got an empty path to walk"))?;
@@ -131,6 +218,8 @@ impl Channel {
             description: channel_file.description,
             url: channel_file.url.trim().to_string(),
             polling_interval,
+            authentication: channel_file.authentication,
+            has_credentials: false,
             enabled: false,
             bundle: None,
         };
@@ -140,7 +229,7 @@ impl Channel {
         Ok(ch)
     }
 
-    pub(super) fn from_directory(dir: &str) -> Result<Vec<Self>> {
+    pub(super) fn from_directory(dir: &Path) -> Result<Vec<Self>> {
         // Find all .yaml files in CHANNELS_DIR
         let mut dir_entries: Vec<DirEntry> = read_dir(dir)?
             .filter_map(|dir_entry| dir_entry.ok())
@@ -172,10 +261,29 @@ impl Channel {
         Ok(channels)
     }
 
+    /// Update the `has_credentials` field based on the stored credentials
+    ///
+    /// Credentials for the wrong kind of authentication are not counted.
+    pub(super) fn update_credentials(&mut self, store: &CredentialStore) {
+        self.has_credentials = match (self.authentication, store.get(&self.name)) {
+            (Some(auth), Some(credentials)) => credentials.authentication() == auth,
+            _ => false,
+        };
+    }
+
+    /// Get the credentials to use when talking to the update server (if any)
+    pub(super) fn credentials<'a>(&self, store: &'a CredentialStore) -> Option<&'a Credentials> {
+        let auth = self.authentication?;
+
+        store
+            .get(&self.name)
+            .filter(|credentials| credentials.authentication() == auth)
+    }
+
     fn update_enabled(&mut self) {
         // Which channels are enabled is decided based on which RAUC certificates are enabled.
         let cert_file = self.name.clone() + ".cert.pem";
-        let cert_path = Path::new(ENABLE_DIR).join(cert_file);
+        let cert_path = fs_root::path(ENABLE_DIR).join(cert_file);
 
         self.enabled = cert_path.exists();
     }
@@ -185,13 +293,24 @@ impl Channel {
         &mut self,
         proxy: &InstallerProxy<'_>,
         slot_status: Option<&SlotStatus>,
+        credentials: &CredentialStore,
     ) -> Result<()> {
         self.update_enabled();
+        self.update_credentials(credentials);
 
         self.bundle = None;
 
         if self.enabled {
-            let args = HashMap::new();
+            let http_headers = self
+                .credentials(credentials)
+                .map(Credentials::rauc_http_headers);
+
+            let mut args = HashMap::new();
+
+            if let Some(http_headers) = &http_headers {
+                args.insert("http-headers", http_headers);
+            }
+
             let bundle = proxy.inspect_bundle(&self.url, args).await?;
             let bundle: zvariant::Dict = bundle.into();
 
@@ -17,6 +17,7 @@
 
 use async_std::prelude::*;
 use async_std::sync::Arc;
+use futures::stream::select;
 use serde::{Deserialize, Serialize};
 
 #[cfg(not(feature = "demo_mode"))]
@@ -25,12 +26,19 @@ use futures_lite::future::race;
 #[cfg(not(feature = "demo_mode"))]
 pub use log::warn;
 
-use super::{Connection, Result};
+#[cfg(not(feature = "demo_mode"))]
+use anyhow::anyhow;
+
+#[cfg(not(feature = "demo_mode"))]
+use super::Connection;
+
+use super::interlock::{Interlock, InterlockedAction};
+use super::{Result, SystemBus};
 use crate::broker::{BrokerBuilder, Topic};
 use crate::watched_tasks::WatchedTasksBuilder;
 
 #[cfg(not(feature = "demo_mode"))]
-mod manager;
+pub(super) mod manager;
 
 #[cfg(not(feature = "demo_mode"))]
 mod service;
@@ -102,7 +110,7 @@ impl Service {
     async fn connect(
         &self,
         _wtb: &mut WatchedTasksBuilder,
-        _conn: Arc<Connection>,
+        _bus: &SystemBus,
         _unit_name: &str,
     ) -> anyhow::Result<()> {
         self.status.set(ServiceStatus::get().await.unwrap());
@@ -111,61 +119,70 @@ impl Service {
     }
 
     #[cfg(not(feature = "demo_mode"))]
-    async fn connect(
-        &self,
-        wtb: &mut WatchedTasksBuilder,
-        conn: Arc<Connection>,
-        unit_name: &'static str,
-    ) -> anyhow::Result<()> {
+    async fn unit(conn: &Connection, unit_name: &str) -> Result<service::UnitProxy<'static>> {
         let unit_path = {
-            let manager = manager::ManagerProxy::new(&conn).await.unwrap();
-            manager.get_unit(unit_name).await.unwrap()
+            let manager = manager::ManagerProxy::new(conn).await?;
+            manager.get_unit(unit_name).await?
         };
 
-        let unit = service::UnitProxy::builder(&conn)
-            .path(unit_path)
-            .unwrap()
+        service::UnitProxy::builder(conn)
+            .path(unit_path)?
             .build()
             .await
-            .unwrap();
+    }
 
-        let unit_task = unit.clone();
+    #[cfg(not(feature = "demo_mode"))]
+    async fn connect(
+        &self,
+        wtb: &mut WatchedTasksBuilder,
+        bus: &SystemBus,
+        unit_name: &'static str,
+    ) -> anyhow::Result<()> {
         let status_topic = self.status.clone();
 
-        wtb.spawn_task(format!("systemd-{unit_name}-state"), async move {
-            let mut active_state_stream =
-                unit_task.receive_active_state_changed().await.map(|_| ());
-            let mut sub_state_stream = unit_task.receive_sub_state_changed().await.map(|_| ());
-            let mut active_enter_stream = unit_task
-                .receive_active_enter_timestamp_changed()
-                .await
-                .map(|_| ());
-            let mut active_exit_stream = unit_task
-                .receive_active_exit_timestamp_changed()
-                .await
-                .map(|_| ());
-
-            loop {
-                let status = ServiceStatus::get(&unit_task).await.unwrap();
-                status_topic.set(status);
-
-                race(
-                    race(active_state_stream.next(), sub_state_stream.next()),
-                    race(active_enter_stream.next(), active_exit_stream.next()),
-                )
-                .await
-                .unwrap();
+        bus.spawn_task(wtb, format!("systemd-{unit_name}-state"), move |conn| {
+            let status_topic = status_topic.clone();
+
+            async move {
+                let unit = Self::unit(&conn, unit_name).await?;
+
+                let mut active_state_stream = unit.receive_active_state_changed().await.map(|_| ());
+                let mut sub_state_stream = unit.receive_sub_state_changed().await.map(|_| ());
+                let mut active_enter_stream = unit
+                    .receive_active_enter_timestamp_changed()
+                    .await
+                    .map(|_| ());
+                let mut active_exit_stream = unit
+                    .receive_active_exit_timestamp_changed()
+                    .await
+                    .map(|_| ());
+
+                loop {
+                    let status = ServiceStatus::get(&unit).await?;
+                    status_topic.set(status);
+
+                    race(
+                        race(active_state_stream.next(), sub_state_stream.next()),
+                        race(active_enter_stream.next(), active_exit_stream.next()),
+                    )
+                    .await
+                    .ok_or_else(|| anyhow!("Unexpected end of unit property subscription"))?;
+                }
             }
         })?;
 
         let (mut action_reqs, _) = self.action.clone().subscribe_unbounded();
+        let bus = bus.clone();
 
         wtb.spawn_task(format!("systemd-{unit_name}-actions"), async move {
             while let Some(action) = action_reqs.next().await {
-                let res = match action {
-                    ServiceAction::Start => unit.start("replace").await,
-                    ServiceAction::Stop => unit.stop("replace").await,
-                    ServiceAction::Restart => unit.restart("replace").await,
+                let res = match Self::unit(&bus.connection(), unit_name).await {
+                    Ok(unit) => match action {
+                        ServiceAction::Start => unit.start("replace").await,
+                        ServiceAction::Stop => unit.stop("replace").await,
+                        ServiceAction::Restart => unit.restart("replace").await,
+                    },
+                    Err(e) => Err(e),
                 };
 
                 if let Err(e) = res {
@@ -184,17 +201,35 @@ impl Service {
 }
 
 impl Systemd {
+    /// Merge reboot requests with requests that override the interlock
+    ///
+    /// Yields the request and whether it overrides the interlock.
+    fn reboot_requests(
+        reboot: Arc<Topic<bool>>,
+        reboot_override: Arc<Topic<bool>>,
+    ) -> impl Stream<Item = (bool, bool)> + Unpin {
+        let (reboot_reqs, _) = reboot.subscribe_unbounded();
+        let (override_reqs, _) = reboot_override.subscribe_unbounded();
+
+        select(
+            reboot_reqs.map(|req| (req, false)),
+            override_reqs.map(|req| (req, true)),
+        )
+    }
+
     #[cfg(feature = "demo_mode")]
     pub fn handle_reboot(
         wtb: &mut WatchedTasksBuilder,
         reboot: Arc<Topic<bool>>,
-        _conn: Arc<Connection>,
+        reboot_override: Arc<Topic<bool>>,
+        interlock: Interlock,
+        _bus: SystemBus,
     ) -> anyhow::Result<()> {
-        let (mut reboot_reqs, _) = reboot.subscribe_unbounded();
+        let mut reboot_reqs = Self::reboot_requests(reboot, reboot_override);
 
         wtb.spawn_task("systemd-reboot", async move {
-            while let Some(req) = reboot_reqs.next().await {
-                if req {
+            while let Some((req, overridden)) = reboot_reqs.next().await {
+                if req && interlock.permits(InterlockedAction::Reboot, overridden) {
                     println!("Asked to reboot but don't feel like it");
                 }
             }
@@ -207,16 +242,23 @@ impl Systemd {
     pub fn handle_reboot(
         wtb: &mut WatchedTasksBuilder,
         reboot: Arc<Topic<bool>>,
-        conn: Arc<Connection>,
+        reboot_override: Arc<Topic<bool>>,
+        interlock: Interlock,
+        bus: SystemBus,
     ) -> anyhow::Result<()> {
-        let (mut reboot_reqs, _) = reboot.subscribe_unbounded();
+        let mut reboot_reqs = Self::reboot_requests(reboot, reboot_override);
 
         wtb.spawn_task("systemd-reboot", async move {
-            let manager = manager::ManagerProxy::new(&conn).await.unwrap();
+            while let Some((req, overridden)) = reboot_reqs.next().await {
+                if req && interlock.permits(InterlockedAction::Reboot, overridden) {
+                    let conn = bus.connection();
 
-            while let Some(req) = reboot_reqs.next().await {
-                if req {
-                    if let Err(e) = manager.reboot().await {
+                    let res = match manager::ManagerProxy::new(&conn).await {
+                        Ok(manager) => manager.reboot().await,
+                        Err(e) => Err(e),
+                    };
+
+                    if let Err(e) = res {
                         warn!("Failed to trigger reboot: {}", e);
                     }
                 }
@@ -229,25 +271,25 @@ impl Systemd {
     pub async fn new(
         bb: &mut BrokerBuilder,
         wtb: &mut WatchedTasksBuilder,
-        conn: &Arc<Connection>,
+        bus: &SystemBus,
+        interlock: Interlock,
     ) -> anyhow::Result<Self> {
         let reboot = bb.topic_rw("/v1/tac/reboot", Some(false));
+        let reboot_override = bb.topic_wo("/v1/tac/reboot/override", None);
 
-        Self::handle_reboot(wtb, reboot.clone(), conn.clone())?;
+        Self::handle_reboot(wtb, reboot.clone(), reboot_override, interlock, bus.clone())?;
 
         let networkmanager = Service::new(bb, "network-manager");
         let labgrid = Service::new(bb, "labgrid-exporter");
         let iobus = Service::new(bb, "lxa-iobus");
 
         networkmanager
-            .connect(wtb, conn.clone(), "NetworkManager.service")
+            .connect(wtb, bus, "NetworkManager.service")
             .await?;
         labgrid
-            .connect(wtb, conn.clone(), "labgrid-exporter.service")
-            .await?;
-        iobus
-            .connect(wtb, conn.clone(), "lxa-iobus.service")
+            .connect(wtb, bus, "labgrid-exporter.service")
             .await?;
+        iobus.connect(wtb, bus, "lxa-iobus.service").await?;
 
         Ok(Self {
             reboot,
@@ -257,3 +299,131 @@ impl Systemd {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    use async_std::task::{block_on, sleep};
+    use zbus::zvariant::OwnedObjectPath;
+
+    use super::{ServiceAction, Systemd};
+    use crate::broker::{BrokerBuilder, Topic};
+    use crate::dbus::interlock::Interlock;
+    use crate::dbus::mock::{MockBus, SystemdManager, SystemdUnit, SYSTEMD_PATH};
+    use crate::watched_tasks::WatchedTasksBuilder;
+
+    const UNITS: [(&str, &str); 3] = [
+        (
+            "NetworkManager.service",
+            "/org/freedesktop/systemd1/unit/NetworkManager_2eservice",
+        ),
+        (
+            "labgrid-exporter.service",
+            "/org/freedesktop/systemd1/unit/labgrid_2dexporter_2eservice",
+        ),
+        (
+            "lxa-iobus.service",
+            "/org/freedesktop/systemd1/unit/lxa_2diobus_2eservice",
+        ),
+    ];
+
+    #[test]
+    fn property_mapping() {
+        let mock = MockBus::new(|builder| {
+            let units = UNITS
+                .iter()
+                .map(|(name, path)| (name.to_string(), OwnedObjectPath::try_from(*path).unwrap()))
+                .collect();
+
+            let mut builder = builder.serve_at(
+                SYSTEMD_PATH,
+                SystemdManager {
+                    units,
+                    reboot_requested: false,
+                },
+            )?;
+
+            for (_, path) in UNITS {
+                builder = builder.serve_at(
+                    path,
+                    SystemdUnit {
+                        active_state: "active".to_string(),
+                        sub_state: "running".to_string(),
+                        active_enter_timestamp: 1000,
+                        active_exit_timestamp: 0,
+                        actions: Vec::new(),
+                    },
+                )?;
+            }
+
+            Ok(builder)
+        });
+
+        let units: HashMap<&str, &str> = UNITS.into();
+
+        let mut wtb = WatchedTasksBuilder::new();
+        let mut bb = BrokerBuilder::new();
+
+        let interlock = Interlock::new(&mut bb, Topic::anonymous(None));
+        let systemd = block_on(Systemd::new(&mut bb, &mut wtb, &mock.bus, interlock)).unwrap();
+
+        block_on(sleep(Duration::from_millis(500)));
+
+        println!("Initial state");
+        for service in [&systemd.networkmanager, &systemd.labgrid, &systemd.iobus] {
+            let status = service.status.try_get().unwrap();
+            assert_eq!(status.active_state, "active");
+            assert_eq!(status.sub_state, "running");
+            assert_eq!(status.active_enter_ts, 1000);
+            assert_eq!(status.active_exit_ts, 0);
+        }
+
+        println!("Stop the labgrid exporter");
+        mock.modify::<SystemdUnit, _>(
+            units["labgrid-exporter.service"],
+            &["ActiveState", "SubState", "ActiveExitTimestamp"],
+            |unit| {
+                unit.active_state = "inactive".to_string();
+                unit.sub_state = "dead".to_string();
+                unit.active_exit_timestamp = 2000;
+            },
+        );
+
+        block_on(sleep(Duration::from_millis(500)));
+
+        let status = systemd.labgrid.status.try_get().unwrap();
+        assert_eq!(status.active_state, "inactive");
+        assert_eq!(status.sub_state, "dead");
+        assert_eq!(status.active_enter_ts, 1000);
+        assert_eq!(status.active_exit_ts, 2000);
+
+        // The other services should be unaffected
+        let status = systemd.iobus.status.try_get().unwrap();
+        assert_eq!(status.active_state, "active");
+
+        println!("Restart the IOBus server");
+        systemd.iobus.action.set(ServiceAction::Restart);
+
+        block_on(sleep(Duration::from_millis(500)));
+
+        let actions = mock.inspect(units["lxa-iobus.service"], |unit: &SystemdUnit| {
+            unit.actions.clone()
+        });
+        assert_eq!(
+            actions,
+            vec![("restart".to_string(), "replace".to_string())]
+        );
+
+        println!("Reboot");
+        systemd.reboot.set(true);
+
+        block_on(sleep(Duration::from_millis(500)));
+
+        let reboot_requested = mock.inspect(SYSTEMD_PATH, |manager: &SystemdManager| {
+            manager.reboot_requested
+        });
+        assert!(reboot_requested);
+    }
+}
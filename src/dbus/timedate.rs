@@ -0,0 +1,244 @@
+// This file is part of tacd, the LXA TAC system daemon
+// Copyright (C) 2024 Pengutronix e.K.
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along
+// with this program; if not, write to the Free Software Foundation, Inc.,
+// 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+use std::future::Future;
+
+use anyhow::Result;
+use async_std::prelude::*;
+use async_std::sync::Arc;
+use futures::stream::select;
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use super::SystemBus;
+use crate::broker::{BrokerBuilder, Topic};
+use crate::watched_tasks::WatchedTasksBuilder;
+
+mod timedated;
+
+// The longest names in the tz database are about 30 characters long.
+const TIMEZONE_MAX_LEN: usize = 64;
+
+/// Where the timezone of the TAC comes from
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub enum TimezoneSource {
+    /// The timezone is only changed on request
+    Manual,
+    /// Follow the timezone announced by the DHCP server in the uplink
+    /// network (option 101)
+    Dhcp,
+}
+
+pub struct Timedate {
+    pub timezone: Arc<Topic<String>>,
+}
+
+/// Check if a timezone looks like a name from the tz database
+///
+/// E.g. "UTC", "Europe/Berlin" or "America/Argentina/Buenos_Aires".
+/// Whether the timezone actually exists is checked by timedated.
+fn is_valid_timezone(timezone: &str) -> bool {
+    if timezone.is_empty() || timezone.len() > TIMEZONE_MAX_LEN {
+        return false;
+    }
+
+    timezone.split('/').all(|part| {
+        !part.is_empty()
+            && !part.starts_with('.')
+            && !part.starts_with('-')
+            && part
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '+')
+    })
+}
+
+impl Timedate {
+    /// Subscribe to timezone change requests from the web and the uplink
+    ///
+    /// Requests are validated before they are passed on to `set_fn`.
+    /// While the timezone source is set to DHCP the timezone announced in the
+    /// uplink network is requested whenever it changes.
+    fn handle_change_requests<F, Fut>(
+        bb: &mut BrokerBuilder,
+        wtb: &mut WatchedTasksBuilder,
+        timezone: Arc<Topic<String>>,
+        dhcp_timezone: Arc<Topic<Option<String>>>,
+        set_fn: F,
+    ) -> Result<()>
+    where
+        F: Fn(String) -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send,
+    {
+        let requests = bb.topic_wo::<String>("/v1/tac/time/timezone", None);
+        let source = bb.topic(
+            "/v1/tac/time/timezone/source",
+            true,
+            true,
+            true,
+            Some(TimezoneSource::Manual),
+            1,
+        );
+
+        let (source_events, _) = source.clone().subscribe_unbounded();
+        let (dhcp_events, _) = dhcp_timezone.clone().subscribe_unbounded();
+        let mut events = select(source_events.map(|_| ()), dhcp_events.map(|_| ()));
+
+        let requests_task = requests.clone();
+
+        wtb.spawn_task("timezone-dhcp", async move {
+            while events.next().await.is_some() {
+                if source.try_get() != Some(TimezoneSource::Dhcp) {
+                    continue;
+                }
+
+                if let Some(Some(tz)) = dhcp_timezone.try_get() {
+                    if timezone.try_get().as_ref() != Some(&tz) {
+                        requests_task.set(tz);
+                    }
+                }
+            }
+
+            Ok(())
+        })?;
+
+        let (mut requests, _) = requests.subscribe_unbounded();
+
+        wtb.spawn_task("timezone-change-request", async move {
+            while let Some(tz) = requests.next().await {
+                if !is_valid_timezone(&tz) {
+                    warn!("Refusing to change timezone to invalid value \"{tz}\"");
+                    continue;
+                }
+
+                set_fn(tz).await;
+            }
+
+            Ok(())
+        })
+    }
+
+    #[cfg(feature = "demo_mode")]
+    pub fn new(
+        bb: &mut BrokerBuilder,
+        wtb: &mut WatchedTasksBuilder,
+        _bus: &SystemBus,
+        dhcp_timezone: Arc<Topic<Option<String>>>,
+    ) -> Result<Self> {
+        let timezone = bb.topic_ro("/v1/tac/time/timezone", Some("Europe/Berlin".into()));
+
+        let timezone_task = timezone.clone();
+        Self::handle_change_requests(bb, wtb, timezone.clone(), dhcp_timezone, move |tz| {
+            timezone_task.set(tz);
+            async {}
+        })?;
+
+        Ok(Self { timezone })
+    }
+
+    #[cfg(not(feature = "demo_mode"))]
+    pub fn new(
+        bb: &mut BrokerBuilder,
+        wtb: &mut WatchedTasksBuilder,
+        bus: &SystemBus,
+        dhcp_timezone: Arc<Topic<Option<String>>>,
+    ) -> Result<Self> {
+        let timezone = bb.topic_ro("/v1/tac/time/timezone", None);
+
+        let timezone_topic = timezone.clone();
+
+        bus.spawn_task(wtb, "timezone-update", move |conn| {
+            let timezone_topic = timezone_topic.clone();
+
+            async move {
+                let proxy = timedated::TimedateProxy::new(&conn).await?;
+
+                let mut stream = proxy.receive_timezone_changed().await;
+
+                if let Ok(tz) = proxy.timezone().await {
+                    timezone_topic.set(tz);
+                }
+
+                while let Some(v) = stream.next().await {
+                    if let Ok(tz) = v.get().await {
+                        timezone_topic.set(tz);
+                    }
+                }
+
+                Ok(())
+            }
+        })?;
+
+        // Like with the hostname the topic is updated via the
+        // "timezone-update" task once timedated reports the change.
+        // timedated also updates /etc/localtime, which is where all
+        // timestamps formatted in local time get their timezone from.
+        let bus = bus.clone();
+        Self::handle_change_requests(bb, wtb, timezone.clone(), dhcp_timezone, move |tz| {
+            let conn = bus.connection();
+
+            async move {
+                let res = match timedated::TimedateProxy::new(&conn).await {
+                    Ok(proxy) => proxy.set_timezone(&tz, false).await,
+                    Err(e) => Err(e),
+                };
+
+                if let Err(e) = res {
+                    warn!("Failed to set timezone to \"{tz}\": {e}");
+                }
+            }
+        })?;
+
+        Ok(Self { timezone })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::is_valid_timezone;
+
+    #[test]
+    fn timezone_validation() {
+        let too_long = "a".repeat(65);
+
+        let valid = [
+            "UTC",
+            "Europe/Berlin",
+            "America/Argentina/Buenos_Aires",
+            "America/Port-au-Prince",
+            "Etc/GMT+2",
+        ];
+
+        let invalid = [
+            "",
+            "/etc/passwd",
+            "../../etc/passwd",
+            "Europe/",
+            "Europe//Berlin",
+            "Europe/.Berlin",
+            "Europe/Berlin Mitte",
+            "-UTC",
+            too_long.as_str(),
+        ];
+
+        for timezone in valid {
+            assert!(is_valid_timezone(timezone), "{timezone} should be valid");
+        }
+
+        for timezone in invalid {
+            assert!(!is_valid_timezone(timezone), "{timezone} should be invalid");
+        }
+    }
+}
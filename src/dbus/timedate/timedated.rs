@@ -0,0 +1,63 @@
+//! This code was generated by `zbus-xmlgen` `4.1.0` from DBus introspection data.
+//!
+//! By running `zbus-xmlgen system org.freedesktop.timedate1 /org/freedesktop/timedate1`
+//! on the LXA TAC.
+
+use zbus::proxy;
+
+#[proxy(
+    interface = "org.freedesktop.timedate1",
+    default_service = "org.freedesktop.timedate1",
+    default_path = "/org/freedesktop/timedate1"
+)]
+trait Timedate {
+    /// ListTimezones method
+    fn list_timezones(&self) -> zbus::Result<Vec<String>>;
+
+    /// SetLocalRTC method
+    #[zbus(name = "SetLocalRTC")]
+    fn set_local_rtc(
+        &self,
+        local_rtc: bool,
+        fix_system: bool,
+        interactive: bool,
+    ) -> zbus::Result<()>;
+
+    /// SetNTP method
+    #[zbus(name = "SetNTP")]
+    fn set_ntp(&self, use_ntp: bool, interactive: bool) -> zbus::Result<()>;
+
+    /// SetTime method
+    fn set_time(&self, usec_utc: i64, relative: bool, interactive: bool) -> zbus::Result<()>;
+
+    /// SetTimezone method
+    fn set_timezone(&self, timezone: &str, interactive: bool) -> zbus::Result<()>;
+
+    /// CanNTP property
+    #[zbus(property, name = "CanNTP")]
+    fn can_ntp(&self) -> zbus::Result<bool>;
+
+    /// LocalRTC property
+    #[zbus(property, name = "LocalRTC")]
+    fn local_rtc(&self) -> zbus::Result<bool>;
+
+    /// NTP property
+    #[zbus(property, name = "NTP")]
+    fn ntp(&self) -> zbus::Result<bool>;
+
+    /// NTPSynchronized property
+    #[zbus(property, name = "NTPSynchronized")]
+    fn ntpsynchronized(&self) -> zbus::Result<bool>;
+
+    /// RTCTimeUSec property
+    #[zbus(property, name = "RTCTimeUSec")]
+    fn rtctime_usec(&self) -> zbus::Result<u64>;
+
+    /// TimeUSec property
+    #[zbus(property, name = "TimeUSec")]
+    fn time_usec(&self) -> zbus::Result<u64>;
+
+    /// Timezone property
+    #[zbus(property)]
+    fn timezone(&self) -> zbus::Result<String>;
+}
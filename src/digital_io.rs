@@ -15,14 +15,27 @@
 // with this program; if not, write to the Free Software Foundation, Inc.,
 // 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
 
-use anyhow::Result;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::atomic::AtomicU32;
+use std::time::Duration;
+
+use anyhow::{anyhow, Error, Result};
 use async_std::prelude::*;
 use async_std::sync::Arc;
+use async_std::task::sleep;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
 
 use crate::broker::{BrokerBuilder, Topic};
+use crate::dut_power::TickReader;
 use crate::led::BlinkPattern;
+use crate::realtime::Realtime;
 use crate::watched_tasks::WatchedTasksBuilder;
 
+mod waveform;
+pub use waveform::Waveform;
+
 #[allow(clippy::items_after_test_module)]
 #[cfg(test)]
 mod gpio {
@@ -42,13 +55,141 @@ mod gpio {
     pub use hardware::*;
 }
 
-pub use gpio::{find_line, LineHandle, LineRequestFlags};
+pub use gpio::{find_line, line_consumer, LineHandle, LineRequestFlags};
+
+/// Time to wait before retrying to request an unavailable GPIO line.
+/// The time is doubled on every failed attempt, up to RETRY_INTERVAL_MAX.
+const RETRY_INTERVAL_MIN: Duration = Duration::from_millis(100);
+const RETRY_INTERVAL_MAX: Duration = Duration::from_secs(5);
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub enum LineHealth {
+    /// The line was successfully requested by the tacd
+    Ok,
+    /// The line could not be requested, e.g. because another process
+    /// holds it. The process is named in `consumer` if it is known.
+    Conflict {
+        consumer: Option<String>,
+        attempts: u32,
+    },
+}
+
+/// The error returned if a GPIO line could not be requested
+#[derive(Debug)]
+pub struct LineConflict {
+    pub line: String,
+    pub consumer: Option<String>,
+}
+
+impl fmt::Display for LineConflict {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.consumer {
+            Some(consumer) => write!(f, "GPIO line {} is used by {}", self.line, consumer),
+            None => write!(f, "GPIO line {} could not be requested", self.line),
+        }
+    }
+}
+
+/// Keep track of GPIO lines that the tacd could not get exclusive access to
+#[derive(Clone)]
+pub struct GpioHealth {
+    pub lines: Arc<Topic<BTreeMap<String, LineHealth>>>,
+}
+
+impl GpioHealth {
+    pub fn new(bb: &mut BrokerBuilder) -> Self {
+        Self {
+            lines: bb.topic_ro("/v1/tac/hardware/gpio", Some(BTreeMap::new())),
+        }
+    }
+
+    fn update(&self, line_name: &str, health: LineHealth) {
+        self.lines.modify(|prev| {
+            let mut lines = prev.unwrap_or_default();
+
+            if lines.get(line_name) == Some(&health) {
+                None
+            } else {
+                lines.insert(line_name.to_owned(), health);
+                Some(lines)
+            }
+        });
+    }
+
+    /// Request a GPIO line, retrying with an increasing backoff if that fails
+    ///
+    /// Another process may hold a line e.g. while the tacd is restarting.
+    /// Instead of silently failing to switch the line the conflict is
+    /// exposed via the `lines` topic.
+    /// Gives up after `max_attempts` (if set) and returns a `LineConflict`
+    /// error.
+    pub async fn request(
+        &self,
+        line_name: &str,
+        flags: LineRequestFlags,
+        initial: u8,
+        max_attempts: Option<u32>,
+    ) -> Result<LineHandle> {
+        let line =
+            find_line(line_name).ok_or_else(|| anyhow!("Could not find GPIO line {line_name}"))?;
+
+        let mut interval = RETRY_INTERVAL_MIN;
+        let mut attempts = 0;
+
+        loop {
+            attempts += 1;
+
+            let err = match line.request(flags.clone(), initial, "tacd") {
+                Ok(handle) => {
+                    if attempts > 1 {
+                        info!("Got access to GPIO line {line_name} after {attempts} attempts");
+                    }
+
+                    self.update(line_name, LineHealth::Ok);
+
+                    return Ok(handle);
+                }
+                Err(e) => Error::from(e),
+            };
+
+            let consumer = line_consumer(&line);
+
+            if attempts == 1 {
+                warn!(
+                    "Failed to request GPIO line {line_name} (used by {}): {err}. Retrying",
+                    consumer.as_deref().unwrap_or("<unknown>")
+                );
+            }
+
+            self.update(
+                line_name,
+                LineHealth::Conflict {
+                    consumer: consumer.clone(),
+                    attempts,
+                },
+            );
+
+            if max_attempts.is_some_and(|max| attempts >= max) {
+                return Err(err.context(LineConflict {
+                    line: line_name.to_owned(),
+                    consumer,
+                }));
+            }
+
+            sleep(interval).await;
+            interval = (interval * 2).min(RETRY_INTERVAL_MAX);
+        }
+    }
+}
 
 pub struct DigitalIo {
     pub out_0: Arc<Topic<bool>>,
     pub out_1: Arc<Topic<bool>>,
+    pub out_0_waveform: Arc<Topic<Waveform>>,
+    pub out_1_waveform: Arc<Topic<Waveform>>,
     pub uart_rx_en: Arc<Topic<bool>>,
     pub uart_tx_en: Arc<Topic<bool>>,
+    tick: Arc<AtomicU32>,
 }
 
 /// Handle a GPIO line whose state is completely defined by the broker framework
@@ -56,28 +197,31 @@ pub struct DigitalIo {
 fn handle_line_wo(
     bb: &mut BrokerBuilder,
     wtb: &mut WatchedTasksBuilder,
+    gpio_health: &GpioHealth,
     path: &str,
-    line_name: &str,
+    line_name: &'static str,
     initial: bool,
     inverted: bool,
-    led_topic: Option<Arc<Topic<BlinkPattern>>>,
 ) -> Result<Arc<Topic<bool>>> {
     let topic = bb.topic_rw(path, Some(initial));
-    let line = find_line(line_name).unwrap();
-    let dst = line
-        .request(LineRequestFlags::OUTPUT, (initial ^ inverted) as _, "tacd")
-        .unwrap();
+    let gpio_health = gpio_health.clone();
 
     let (mut src, _) = topic.clone().subscribe_unbounded();
 
     wtb.spawn_task(format!("digital-io-{line_name}-set"), async move {
+        // Keep on trying to get the line until we succeed.
+        // Requests to set the line queue up in the meantime.
+        let dst = gpio_health
+            .request(
+                line_name,
+                LineRequestFlags::OUTPUT,
+                (initial ^ inverted) as _,
+                None,
+            )
+            .await?;
+
         while let Some(ev) = src.next().await {
             dst.set_value((ev ^ inverted) as _).unwrap();
-
-            if let Some(led) = &led_topic {
-                let pattern = BlinkPattern::solid(if ev { 1.0 } else { 0.0 });
-                led.set(pattern);
-            }
         }
 
         Ok(())
@@ -90,54 +234,47 @@ impl DigitalIo {
     pub fn new(
         bb: &mut BrokerBuilder,
         wtb: &mut WatchedTasksBuilder,
+        realtime: &Realtime,
+        gpio_health: &GpioHealth,
         led_0: Arc<Topic<BlinkPattern>>,
         led_1: Arc<Topic<BlinkPattern>>,
     ) -> Result<Self> {
-        let out_0 = handle_line_wo(
-            bb,
-            wtb,
-            "/v1/output/out_0/asserted",
-            "OUT_0",
-            false,
-            false,
-            Some(led_0),
-        )?;
-
-        let out_1 = handle_line_wo(
-            bb,
-            wtb,
-            "/v1/output/out_1/asserted",
-            "OUT_1",
-            false,
-            false,
-            Some(led_1),
-        )?;
+        // OUT_0 and OUT_1 can generate pulses and PWM signals in addition
+        // to being set statically, which is handled in a dedicated thread.
+        let (out_0, out_1, tick) = waveform::run(bb, wtb, realtime, gpio_health, led_0, led_1)?;
 
         let uart_rx_en = handle_line_wo(
             bb,
             wtb,
+            gpio_health,
             "/v1/uart/rx/enabled",
             "UART_RX_EN",
             true,
             true,
-            None,
         )?;
 
         let uart_tx_en = handle_line_wo(
             bb,
             wtb,
+            gpio_health,
             "/v1/uart/tx/enabled",
             "UART_TX_EN",
             true,
             true,
-            None,
         )?;
 
         Ok(Self {
-            out_0,
-            out_1,
+            out_0: out_0.asserted,
+            out_1: out_1.asserted,
+            out_0_waveform: out_0.waveform,
+            out_1_waveform: out_1.waveform,
             uart_rx_en,
             uart_tx_en,
+            tick,
         })
     }
+
+    pub fn tick(&self) -> TickReader {
+        TickReader::new(&self.tick)
+    }
 }
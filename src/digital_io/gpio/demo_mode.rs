@@ -16,6 +16,7 @@
 // 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
 
 use std::ops::BitOr;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use anyhow::Result;
 use async_std::task::block_on;
@@ -32,8 +33,8 @@ impl LineHandle {
         // It is just a hack to let adc/iio/demo_mode.rs
         // communicate with this function so that toggling an output
         // has an effect on the measured values.
-        let iio_thread_stm32 = block_on(IioThread::new_stm32(&(), ())).unwrap();
-        let iio_thread_pwr = block_on(IioThread::new_powerboard(&(), ())).unwrap();
+        let iio_thread_stm32 = block_on(IioThread::new_stm32(&(), &(), ())).unwrap();
+        let iio_thread_pwr = block_on(IioThread::new_powerboard(&(), &(), ())).unwrap();
 
         match self.name.as_str() {
             "OUT_0" => iio_thread_stm32
@@ -60,11 +61,22 @@ impl LineHandle {
 
         Ok(())
     }
+
+    /// Simulate an input signal that toggles every five seconds
+    pub fn get_value(&self) -> Result<u8> {
+        let secs = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+
+        Ok(((secs / 5) % 2) as u8)
+    }
 }
 
 #[allow(clippy::upper_case_acronyms, non_camel_case_types)]
 #[derive(Clone)]
 pub enum LineRequestFlags {
+    INPUT,
     OUTPUT,
     OPEN_DRAIN,
 }
@@ -101,3 +113,7 @@ pub fn find_line(name: &str) -> Option<FindDecoy> {
         name: name.to_string(),
     })
 }
+
+pub fn line_consumer(_line: &FindDecoy) -> Option<String> {
+    None
+}
@@ -23,3 +23,11 @@ pub fn find_line(name: &str) -> Option<Line> {
         .flat_map(|c| c.unwrap().lines())
         .find(|l| l.info().unwrap().name() == Some(name))
 }
+
+/// Get the name of the process (or kernel driver) that currently holds
+/// the line, if any
+pub fn line_consumer(line: &Line) -> Option<String> {
+    line.info()
+        .ok()
+        .and_then(|info| info.consumer().map(str::to_owned))
+}
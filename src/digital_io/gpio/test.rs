@@ -37,11 +37,16 @@ impl LineHandle {
         self.val.store(val, Ordering::Relaxed);
         Ok(())
     }
+
+    pub fn get_value(&self) -> Result<u8> {
+        Ok(self.val.load(Ordering::Relaxed))
+    }
 }
 
 #[allow(clippy::upper_case_acronyms, non_camel_case_types)]
 #[derive(Clone)]
 pub enum LineRequestFlags {
+    INPUT,
     OUTPUT,
     OPEN_DRAIN,
 }
@@ -93,3 +98,7 @@ pub fn find_line(name: &str) -> Option<FindDecoy> {
         val,
     })
 }
+
+pub fn line_consumer(_line: &FindDecoy) -> Option<String> {
+    None
+}
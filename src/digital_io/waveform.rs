@@ -0,0 +1,362 @@
+// This file is part of tacd, the LXA TAC system daemon
+// Copyright (C) 2024 Pengutronix e.K.
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along
+// with this program; if not, write to the Free Software Foundation, Inc.,
+// 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+
+//! Generate timed pulses and low-frequency PWM signals on OUT_0 and OUT_1
+//!
+//! The outputs are driven by a dedicated thread that wakes up every
+//! `THREAD_INTERVAL`, so that the timing does not depend on how busy the
+//! async runtime is. Like the power thread it increments a tick on every
+//! iteration, which the watchdog checks to make sure that the outputs do
+//! not get stuck in the middle of a waveform.
+
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use async_std::channel::bounded;
+use async_std::prelude::*;
+use async_std::sync::Arc;
+use futures::stream::select;
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use super::{GpioHealth, LineHandle, LineRequestFlags};
+use crate::broker::{BrokerBuilder, Topic};
+use crate::led::{BlinkPattern, BlinkPatternBuilder};
+use crate::realtime::Realtime;
+use crate::watched_tasks::WatchedTasksBuilder;
+
+/// The resolution of pulse widths and PWM periods
+const THREAD_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Shorter PWM periods are extended to this, as the signal would mostly
+/// consist of jitter otherwise
+const MIN_PERIOD_MS: u64 = 100;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub enum Waveform {
+    /// Follow the `asserted` topic
+    Static,
+    /// Invert the output for `width_ms`, then go back to `Static`
+    Pulse { width_ms: u64 },
+    /// Assert the output for `duty_cycle` (0.0 to 1.0) of every `period_ms`
+    Pwm { period_ms: u64, duty_cycle: f32 },
+}
+
+impl Waveform {
+    /// Bring the parameters into the range the thread can generate
+    fn clamped(self) -> Self {
+        match self {
+            Self::Pwm {
+                period_ms,
+                duty_cycle,
+            } => Self::Pwm {
+                period_ms: period_ms.max(MIN_PERIOD_MS),
+                duty_cycle: duty_cycle.clamp(0.0, 1.0),
+            },
+            waveform => waveform,
+        }
+    }
+
+    /// Get the level of the output `elapsed` after the waveform started
+    ///
+    /// Returns None once a pulse is complete.
+    fn level(&self, asserted: bool, elapsed: Duration) -> Option<bool> {
+        match *self {
+            Self::Static => Some(asserted),
+            Self::Pulse { width_ms } => {
+                (elapsed < Duration::from_millis(width_ms)).then_some(!asserted)
+            }
+            Self::Pwm {
+                period_ms,
+                duty_cycle,
+            } => {
+                let phase = (elapsed.as_millis() % (period_ms as u128)) as f32;
+
+                Some(phase < (period_ms as f32) * duty_cycle)
+            }
+        }
+    }
+
+    /// Mirror the waveform on the LED next to the output
+    fn led_pattern(&self, asserted: bool) -> BlinkPattern {
+        let solid = |on: bool| BlinkPattern::solid(if on { 1.0 } else { 0.0 });
+
+        match *self {
+            Self::Static => solid(asserted),
+            Self::Pulse { .. } => solid(!asserted),
+            Self::Pwm {
+                period_ms,
+                duty_cycle,
+            } => {
+                let period = Duration::from_millis(period_ms);
+                let on = period.mul_f32(duty_cycle);
+
+                BlinkPatternBuilder::new(1.0)
+                    .step_to(1.0)
+                    .stay_for(on)
+                    .step_to(0.0)
+                    .stay_for(period - on)
+                    .forever()
+            }
+        }
+    }
+}
+
+/// What an output should do, shared between the broker tasks and the thread
+struct Drive {
+    asserted: bool,
+    waveform: Waveform,
+    /// When the current waveform was started
+    since: Instant,
+}
+
+/// The part of an output that lives in the waveform thread
+struct ThreadOutput {
+    drive: Arc<Mutex<Drive>>,
+    line: Arc<Mutex<Option<LineHandle>>>,
+    level: Option<bool>,
+}
+
+impl ThreadOutput {
+    /// Set the line to the current level of the waveform
+    ///
+    /// Returns true if a pulse was just completed.
+    fn step(&mut self, now: Instant) -> Result<bool> {
+        // Try again in the next interval instead of blocking the thread
+        // while a broker task updates the output.
+        let (mut drive, line) = match (self.drive.try_lock(), self.line.try_lock()) {
+            (Ok(drive), Ok(line)) => (drive, line),
+            _ => return Ok(false),
+        };
+
+        // The line was not successfully requested (yet)
+        let line = match line.as_ref() {
+            Some(line) => line,
+            None => return Ok(false),
+        };
+
+        let elapsed = now.saturating_duration_since(drive.since);
+
+        let (level, completed) = match drive.waveform.level(drive.asserted, elapsed) {
+            Some(level) => (level, false),
+            None => {
+                drive.waveform = Waveform::Static;
+                (drive.asserted, true)
+            }
+        };
+
+        if self.level != Some(level) {
+            line.set_value(level as _)?;
+            self.level = Some(level);
+        }
+
+        Ok(completed)
+    }
+}
+
+/// The topics to control an output with
+pub struct WaveformOutput {
+    pub asserted: Arc<Topic<bool>>,
+    pub waveform: Arc<Topic<Waveform>>,
+}
+
+/// Set up the topics and broker tasks for an output
+fn handle_output(
+    bb: &mut BrokerBuilder,
+    wtb: &mut WatchedTasksBuilder,
+    gpio_health: &GpioHealth,
+    name: &'static str,
+    line_name: &'static str,
+    led: Arc<Topic<BlinkPattern>>,
+) -> Result<(WaveformOutput, ThreadOutput)> {
+    let asserted = bb.topic_rw(format!("/v1/output/{name}/asserted").as_str(), Some(false));
+    let waveform = bb.topic_rw(
+        format!("/v1/output/{name}/waveform").as_str(),
+        Some(Waveform::Static),
+    );
+
+    let drive = Arc::new(Mutex::new(Drive {
+        asserted: false,
+        waveform: Waveform::Static,
+        since: Instant::now(),
+    }));
+    let line = Arc::new(Mutex::new(None));
+
+    let thread_output = ThreadOutput {
+        drive: drive.clone(),
+        line: line.clone(),
+        level: None,
+    };
+
+    let gpio_health = gpio_health.clone();
+    let (asserted_events, _) = asserted.clone().subscribe_unbounded();
+    let (waveform_events, _) = waveform.clone().subscribe_unbounded();
+
+    let mut events = select(
+        asserted_events.map(|asserted| (Some(asserted), None)),
+        waveform_events.map(|waveform| (None, Some(waveform))),
+    );
+
+    wtb.spawn_task(format!("digital-io-{line_name}-set"), async move {
+        // Keep on trying to get the line until we succeed.
+        // The thread leaves the output alone and requests to set it
+        // queue up in the meantime.
+        let handle = gpio_health
+            .request(line_name, LineRequestFlags::OUTPUT, 0, None)
+            .await?;
+
+        *line.lock().unwrap() = Some(handle);
+
+        while let Some((new_asserted, new_waveform)) = events.next().await {
+            let pattern = {
+                let mut drive = drive.lock().unwrap();
+
+                if let Some(asserted) = new_asserted {
+                    drive.asserted = asserted;
+                }
+
+                if let Some(waveform) = new_waveform {
+                    drive.waveform = waveform.clamped();
+                    drive.since = Instant::now();
+                }
+
+                drive.waveform.led_pattern(drive.asserted)
+            };
+
+            led.set(pattern);
+        }
+
+        Ok(())
+    })?;
+
+    Ok((WaveformOutput { asserted, waveform }, thread_output))
+}
+
+/// Set up OUT_0 and OUT_1 and the thread that drives them
+///
+/// The thread runs as long as there is a strong reference to the returned
+/// tick.
+pub(super) fn run(
+    bb: &mut BrokerBuilder,
+    wtb: &mut WatchedTasksBuilder,
+    realtime: &Realtime,
+    gpio_health: &GpioHealth,
+    led_0: Arc<Topic<BlinkPattern>>,
+    led_1: Arc<Topic<BlinkPattern>>,
+) -> Result<(WaveformOutput, WaveformOutput, Arc<AtomicU32>)> {
+    let (out_0, thread_out_0) = handle_output(bb, wtb, gpio_health, "out_0", "OUT_0", led_0)?;
+    let (out_1, thread_out_1) = handle_output(bb, wtb, gpio_health, "out_1", "OUT_1", led_1)?;
+
+    // Completed pulses are reported back to the broker, so that the
+    // waveform topics show that the outputs are static again.
+    // try_send() never blocks, so this is safe to use from the thread.
+    let (completed_tx, mut completed_rx) = bounded(2);
+    let waveforms = [out_0.waveform.clone(), out_1.waveform.clone()];
+
+    wtb.spawn_task("digital-io-pulse-completed", async move {
+        while let Some(index) = completed_rx.next().await {
+            let waveform: &Arc<Topic<Waveform>> = &waveforms[index];
+
+            waveform.modify(|prev| match prev {
+                Some(Waveform::Pulse { .. }) => Some(Waveform::Static),
+                _ => None,
+            });
+        }
+
+        Ok(())
+    })?;
+
+    let tick = Arc::new(AtomicU32::new(0));
+    let tick_weak = Arc::downgrade(&tick);
+    let mut monitor = realtime.monitor("digital-io-thread", THREAD_INTERVAL);
+
+    wtb.spawn_thread("digital-io-thread", move || {
+        let mut outputs = [thread_out_0, thread_out_1];
+
+        // Run as long as there is a strong reference to `tick`.
+        // As tick is a private member of DigitalIo this is equivalent
+        // to running as long as the DigitalIo was not dropped.
+        while let Some(tick) = tick_weak.upgrade() {
+            thread::sleep(THREAD_INTERVAL);
+            monitor.woke_up();
+
+            let now = Instant::now();
+
+            for (index, output) in outputs.iter_mut().enumerate() {
+                if output.step(now)? && completed_tx.try_send(index).is_err() {
+                    warn!("Failed to report a completed pulse");
+                }
+            }
+
+            // Signal "everything is well" to the watchdog task.
+            tick.fetch_add(1, Ordering::Relaxed);
+        }
+
+        Ok(())
+    })?;
+
+    Ok((out_0, out_1, tick))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{Waveform, MIN_PERIOD_MS};
+
+    #[test]
+    fn waveform_levels() {
+        let ms = Duration::from_millis;
+
+        println!("Static outputs follow the asserted state");
+        assert_eq!(Waveform::Static.level(true, ms(1000)), Some(true));
+        assert_eq!(Waveform::Static.level(false, ms(1000)), Some(false));
+
+        println!("Pulses invert the output until they are complete");
+        let pulse = Waveform::Pulse { width_ms: 50 };
+        assert_eq!(pulse.level(false, ms(0)), Some(true));
+        assert_eq!(pulse.level(true, ms(49)), Some(false));
+        assert_eq!(pulse.level(false, ms(50)), None);
+
+        println!("PWM signals repeat every period");
+        let pwm = Waveform::Pwm {
+            period_ms: 200,
+            duty_cycle: 0.25,
+        };
+        assert_eq!(pwm.level(false, ms(0)), Some(true));
+        assert_eq!(pwm.level(false, ms(49)), Some(true));
+        assert_eq!(pwm.level(false, ms(50)), Some(false));
+        assert_eq!(pwm.level(true, ms(199)), Some(false));
+        assert_eq!(pwm.level(false, ms(200)), Some(true));
+
+        println!("Out of range parameters are clamped");
+        assert_eq!(
+            Waveform::Pwm {
+                period_ms: 1,
+                duty_cycle: 2.0
+            }
+            .clamped(),
+            Waveform::Pwm {
+                period_ms: MIN_PERIOD_MS,
+                duty_cycle: 1.0
+            }
+        );
+    }
+}
@@ -15,24 +15,44 @@
 // with this program; if not, write to the Free Software Foundation, Inc.,
 // 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
 
-use std::sync::atomic::{AtomicU32, AtomicU8, Ordering};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU32, AtomicU64, AtomicU8, AtomicUsize, Ordering};
+use std::sync::Mutex;
 use std::thread;
 use std::time::{Duration, Instant};
 
-use anyhow::{anyhow, Result};
+use anyhow::{bail, Result};
 use async_std::channel::bounded;
 use async_std::prelude::*;
 use async_std::sync::{Arc, Weak};
 use async_std::task;
+use log::warn;
+use nix::sys::eventfd::{EfdFlags, EventFd};
 use serde::{Deserialize, Serialize};
+use tide::Server;
+use tracing::{info_span, Span};
 
 use crate::adc::AdcChannel;
 use crate::broker::{BrokerBuilder, Topic};
-use crate::digital_io::{find_line, LineHandle, LineRequestFlags};
-use crate::led::{BlinkPattern, BlinkPatternBuilder};
+use crate::confirmation::{ConfirmableAction, Confirmation};
+use crate::conflicts::{ConflictGuard, RequestSource, SourcedRequest};
+use crate::digital_io::{GpioHealth, LineHandle, LineRequestFlags};
+use crate::led::{BlinkPattern, BlinkPatternBuilder, LedMeaning};
+use crate::measurement::Measurement;
+use crate::realtime::Realtime;
 use crate::system::HardwareGeneration;
 use crate::watched_tasks::WatchedTasksBuilder;
 
+mod energy;
+mod maintenance;
+mod profiles;
+mod sequence;
+use energy::setup_energy;
+use maintenance::{setup_maintenance_override, Relaxation};
+use sequence::setup_sequence;
+
+pub use maintenance::MaintenanceOverride;
+
 #[cfg(any(test, feature = "demo_mode"))]
 mod prio {
     use anyhow::Result;
@@ -72,9 +92,74 @@ const MAX_CURRENT: f32 = 5.0;
 const MAX_VOLTAGE: f32 = 48.0;
 const MIN_VOLTAGE: f32 = -1.0;
 
+// Users with sensitive DUTs can configure lower limits than MAX_CURRENT and
+// MAX_VOLTAGE. Limits below these minimums are refused, as the output
+// would likely be turned off due to measurement noise.
+const MIN_CURRENT_LIMIT: f32 = 0.05;
+const MIN_VOLTAGE_LIMIT: f32 = 1.0;
+
+// Number of commands that can be queued up for the power thread.
+// The thread handles at most one of them per THREAD_INTERVAL.
+// This must be a power of two, so that the ring buffer indices can wrap
+// around freely.
+const COMMAND_QUEUE_LEN: usize = 8;
+
 const PWR_LINE_ASSERTED: u8 = 0;
 const DISCHARGE_LINE_ASSERTED: u8 = 0;
 
+// How often to try to request the GPIO lines before giving up.
+// With the backoff in GpioHealth::request this is about ten seconds.
+const LINE_REQUEST_ATTEMPTS: u32 = 8;
+
+// None of the current hardware generations have a current-limited path to
+// the DUT, so the probe mode pulses the output instead.
+// The output is turned on for one THREAD_INTERVAL every PROBE_PULSE_EVERY
+// intervals and is turned off for good if the current exceeds
+// PROBE_MAX_CURRENT or the voltage is out of range during a pulse.
+// Nothing limits the current while a pulse is in progress, so the energy
+// budget has to account for the configured current limit being drawn for
+// the whole pulse.
+const PROBE_PULSE_EVERY: u32 = 10;
+const PROBE_MAX_CURRENT: f32 = 0.1;
+const PROBE_DEFAULT_ENERGY_LIMIT: f32 = 1.0;
+
+// A voltage above EXTERNAL_VOLTAGE_THRESHOLD that is measured for at least
+// EXTERNAL_VOLTAGE_MIN_DURATION while the output is off is reported as
+// externally applied.
+// The duration requirement prevents false reports while the output
+// capacitance is discharged after turning the output off.
+const EXTERNAL_VOLTAGE_THRESHOLD: f32 = 1.0;
+const EXTERNAL_VOLTAGE_MIN_DURATION: Duration = Duration::from_secs(2);
+
+// There is no temperature sensor on the discharge resistor, so its thermal
+// load is modeled instead.
+// While the discharge line is asserted the resistor dissipates a power
+// proportional to the square of the output voltage. The heat is accumulated
+// in units of "seconds of discharging MAX_VOLTAGE" and decays with
+// DISCHARGE_COOLING_TIME_CONSTANT.
+// Once DISCHARGE_HEAT_BUDGET is exhausted Off requests are refused until the
+// heat dropped below DISCHARGE_RESUME_PART of the budget.
+// OffFloating requests are still performed, as they do not use the resistor.
+// Turning the output off due to a fault always discharges it.
+const DISCHARGE_HEAT_BUDGET: f32 = 5.0;
+const DISCHARGE_COOLING_TIME_CONSTANT: Duration = Duration::from_secs(30);
+const DISCHARGE_RESUME_PART: f32 = 0.5;
+
+// The current is sampled every INRUSH_SAMPLE_INTERVAL for INRUSH_WINDOW
+// after turning the output on.
+// The average of the last INRUSH_STEADY_PART of the window is considered
+// the steady state current.
+// The current has settled once it stays within INRUSH_STEADY_TOLERANCE
+// (relative) or INRUSH_STEADY_MIN_BAND (absolute, whichever is larger) of
+// the steady state current.
+const INRUSH_WINDOW: Duration = Duration::from_secs(1);
+const INRUSH_SAMPLE_INTERVAL: Duration = Duration::from_millis(5);
+const INRUSH_MIN_SAMPLES: usize = 10;
+const INRUSH_STEADY_PART: f32 = 0.2;
+const INRUSH_STEADY_TOLERANCE: f32 = 0.1;
+const INRUSH_STEADY_MIN_BAND: f32 = 0.01;
+const DEFAULT_PROFILE: &str = "default";
+
 trait OutputFlags {
     fn output_flags(&self) -> LineRequestFlags;
 }
@@ -96,12 +181,16 @@ impl OutputFlags for HardwareGeneration {
     }
 }
 
-#[derive(PartialEq, Clone, Copy, Serialize, Deserialize)]
+#[derive(PartialEq, Clone, Copy, Debug, Serialize, Deserialize)]
 pub enum OutputRequest {
     Idle,
     On,
     Off,
     OffFloating,
+    /// Turn off using the off mode configured for the current profile.
+    /// This is resolved to Off or OffFloating before reaching the thread.
+    OffDefault,
+    Probe,
 }
 
 impl From<u8> for OutputRequest {
@@ -122,10 +211,37 @@ impl From<u8> for OutputRequest {
             return OutputRequest::OffFloating;
         }
 
+        if val == (OutputRequest::OffDefault as u8) {
+            return OutputRequest::OffDefault;
+        }
+
+        if val == (OutputRequest::Probe as u8) {
+            return OutputRequest::Probe;
+        }
+
         panic!()
     }
 }
 
+/// How the output should be turned off if no explicit mode is requested
+#[derive(PartialEq, Clone, Copy, Debug, Serialize, Deserialize)]
+pub enum OffMode {
+    /// Discharge the output
+    Off,
+    /// Leave the output floating, e.g. for DUTs that are powered via
+    /// other means as well
+    OffFloating,
+}
+
+impl OffMode {
+    fn request(self) -> OutputRequest {
+        match self {
+            Self::Off => OutputRequest::Off,
+            Self::OffFloating => OutputRequest::OffFloating,
+        }
+    }
+}
+
 #[derive(PartialEq, Clone, Copy, Serialize, Deserialize, Debug)]
 pub enum OutputState {
     On,
@@ -136,6 +252,7 @@ pub enum OutputState {
     OverCurrent,
     OverVoltage,
     RealtimeViolation,
+    Probing,
 }
 
 impl From<u8> for OutputState {
@@ -172,10 +289,115 @@ impl From<u8> for OutputState {
             return OutputState::RealtimeViolation;
         }
 
+        if val == (OutputState::Probing as u8) {
+            return OutputState::Probing;
+        }
+
         panic!()
     }
 }
 
+/// Hand the output state from the realtime power thread to the broker
+///
+/// The power thread updates an atomic variable and increments an eventfd,
+/// neither of which blocks or takes locks that are shared with lower
+/// priority threads.
+/// The receiving side blocks on the eventfd and always reads the most recent
+/// state, so that updates in quick succession are coalesced instead of being
+/// queued up.
+#[derive(Clone)]
+struct StateChannel {
+    state: Arc<AtomicU8>,
+    event: Arc<EventFd>,
+}
+
+impl StateChannel {
+    fn new(initial: OutputState) -> Result<Self> {
+        Ok(Self {
+            state: Arc::new(AtomicU8::new(initial as u8)),
+            // Start with a pending wakeup so that the initial state is
+            // picked up right away.
+            event: Arc::new(EventFd::from_value_and_flags(1, EfdFlags::EFD_CLOEXEC)?),
+        })
+    }
+
+    fn load(&self) -> OutputState {
+        self.state.load(Ordering::Relaxed).into()
+    }
+
+    /// Update the state and return the previous one
+    ///
+    /// The receiving side is only woken up if the state actually changed.
+    fn swap(&self, state: OutputState) -> OutputState {
+        let prev: OutputState = self.state.swap(state as u8, Ordering::Relaxed).into();
+
+        if prev != state {
+            self.notify();
+        }
+
+        prev
+    }
+
+    fn store(&self, state: OutputState) {
+        self.swap(state);
+    }
+
+    /// Wake up the receiving side, even if the state did not change
+    ///
+    /// Writing to an eventfd only blocks if the counter would exceed
+    /// 0xfffffffffffffffe. Even if the receiving side stopped resetting it,
+    /// that would take billions of years at one write per THREAD_INTERVAL.
+    /// The write does not wait for the receiving side to run.
+    fn notify(&self) {
+        let _ = self.event.write(1);
+    }
+
+    /// Block until the state may have changed and return the most recent one
+    fn wait(&self) -> Result<OutputState> {
+        self.event.read()?;
+
+        Ok(self.load())
+    }
+}
+
+/// The time at which the power thread performed an action, e.g. switched
+/// the output
+///
+/// The time is stored in an atomic variable as microseconds since
+/// `ref_instant`, so that the power thread can record it without taking
+/// any locks.
+#[derive(Clone)]
+struct EventTime {
+    ref_instant: Instant,
+    /// Zero if the action was not performed yet
+    since_ref_us: Arc<AtomicU64>,
+}
+
+impl EventTime {
+    fn new() -> Self {
+        Self {
+            ref_instant: Instant::now(),
+            since_ref_us: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    fn record(&self, now: Instant) {
+        let since_ref = now.saturating_duration_since(self.ref_instant);
+        let since_ref_us = u64::try_from(since_ref.as_micros()).unwrap_or(u64::MAX);
+
+        self.since_ref_us
+            .store(since_ref_us.max(1), Ordering::Relaxed);
+    }
+
+    fn get(&self) -> Option<Instant> {
+        match self.since_ref_us.load(Ordering::Relaxed) {
+            0 => None,
+            us => Some(self.ref_instant + Duration::from_micros(us)),
+        }
+    }
+}
+
+#[derive(Clone)]
 pub struct TickReader {
     src: Weak<AtomicU32>,
     val: u32,
@@ -197,23 +419,674 @@ impl TickReader {
     /// Ensuring that is_stale() is not called too frequently is up to the
     /// user.
     pub fn is_stale(&mut self) -> bool {
+        self.progress() == 0
+    }
+
+    /// Get the number of ticks since the last call to is_stale() or progress()
+    ///
+    /// Returns zero if the power thread is gone.
+    pub fn progress(&mut self) -> u32 {
         if let Some(tick) = self.src.upgrade() {
             let prev = self.val;
             self.val = tick.load(Ordering::Relaxed);
 
-            prev == self.val
+            self.val.wrapping_sub(prev)
         } else {
-            true
+            0
+        }
+    }
+}
+
+/// Measurements taken while in the current-limited probe mode
+#[derive(PartialEq, Clone, Copy, Serialize, Deserialize, Default, Debug)]
+pub struct ProbeResult {
+    /// Voltage measured during the last pulse
+    pub voltage: Option<f32>,
+    /// Current measured during the last pulse
+    pub current: Option<f32>,
+    /// Energy (in Joule) delivered to the DUT since probing started
+    pub energy: f32,
+    pub pulses: u32,
+    /// The next pulse could have exceeded the energy limit
+    pub limit_reached: bool,
+}
+
+/// What to do with the output in the next THREAD_INTERVAL while probing
+#[derive(PartialEq, Debug)]
+enum ProbeStep {
+    PulseOn,
+    PulseOff,
+    OverCurrent,
+    OverVoltage,
+    InvertedPolarity,
+    LimitReached,
+}
+
+/// The limits the probe mode has to stay within
+#[derive(Clone, Copy)]
+struct ProbeLimits {
+    /// Energy (in Joule) to deliver at most since probing started
+    energy: f32,
+    /// The configured voltage limit of the output
+    voltage: f32,
+    /// The configured current limit of the output
+    current: f32,
+}
+
+/// Pulse the output with a low duty cycle to measure the leakage current
+/// of a DUT without fully powering it
+struct Prober {
+    result: ProbeResult,
+    intervals: u32,
+    output_on: bool,
+}
+
+impl Prober {
+    fn new() -> Self {
+        Self {
+            result: ProbeResult::default(),
+            intervals: 0,
+            output_on: false,
+        }
+    }
+
+    /// Worst case energy delivered by the next pulse
+    ///
+    /// The current is only measured once a pulse is over, so the DUT may
+    /// draw up to the configured current limit for the whole pulse.
+    /// Before the first pulse the supply voltage is unknown (we measure
+    /// behind the power switch), so the configured voltage limit is assumed.
+    fn next_pulse_energy(&self, limits: ProbeLimits) -> f32 {
+        let voltage = self.result.voltage.unwrap_or(limits.voltage).abs();
+
+        voltage * limits.current * THREAD_INTERVAL.as_secs_f32()
+    }
+
+    /// Advance by one THREAD_INTERVAL using unfiltered measurements
+    ///
+    /// Filtered values can not be used here, as a single pulse would be
+    /// removed by the median filter.
+    fn step(&mut self, volt: f32, curr: f32, limits: ProbeLimits) -> ProbeStep {
+        if self.output_on {
+            // The measurement was taken while the output was on
+            self.output_on = false;
+            self.result.voltage = Some(volt);
+            self.result.current = Some(curr);
+            self.result.energy += volt.max(0.0) * curr.max(0.0) * THREAD_INTERVAL.as_secs_f32();
+            self.result.pulses += 1;
+
+            if volt > limits.voltage {
+                return ProbeStep::OverVoltage;
+            }
+
+            if volt < MIN_VOLTAGE {
+                return ProbeStep::InvertedPolarity;
+            }
+
+            if curr > PROBE_MAX_CURRENT {
+                return ProbeStep::OverCurrent;
+            }
+
+            return ProbeStep::PulseOff;
+        }
+
+        self.intervals += 1;
+
+        if self.intervals < PROBE_PULSE_EVERY {
+            return ProbeStep::PulseOff;
+        }
+
+        self.intervals = 0;
+
+        // This is written in a way that an invalid limit (e.g. NaN) also
+        // prevents the pulse.
+        let within_limit = self.result.energy + self.next_pulse_energy(limits) <= limits.energy;
+
+        if !within_limit {
+            self.result.limit_reached = true;
+            return ProbeStep::LimitReached;
+        }
+
+        self.output_on = true;
+        ProbeStep::PulseOn
+    }
+}
+
+/// A voltage that is present on the DUT power output while it is off
+///
+/// This usually hints at a miswired setup, e.g. the DUT being powered
+/// by a lab power supply in parallel to the TAC.
+/// With the output in the OffFloating state this may also be charge that
+/// is left in large capacitors in the DUT.
+#[derive(PartialEq, Clone, Copy, Serialize, Deserialize, Debug)]
+pub struct ExternalVoltage {
+    pub voltage: f32,
+    /// Time (in seconds) since the voltage was first detected
+    pub duration: u64,
+}
+
+struct ExternalVoltageDetector {
+    since: Option<Instant>,
+}
+
+impl ExternalVoltageDetector {
+    fn new() -> Self {
+        Self { since: None }
+    }
+
+    fn step(&mut self, state: OutputState, volt: f32, now: Instant) -> Option<ExternalVoltage> {
+        let is_off = matches!(state, OutputState::Off | OutputState::OffFloating);
+
+        if !is_off || volt.abs() < EXTERNAL_VOLTAGE_THRESHOLD {
+            self.since = None;
+            return None;
+        }
+
+        let since = *self.since.get_or_insert(now);
+        let duration = now.duration_since(since);
+
+        (duration >= EXTERNAL_VOLTAGE_MIN_DURATION).then_some(ExternalVoltage {
+            voltage: volt,
+            duration: duration.as_secs(),
+        })
+    }
+}
+
+/// Modeled thermal load of the discharge resistor
+#[derive(PartialEq, Clone, Copy, Serialize, Deserialize, Default, Debug)]
+pub struct DischargeStatus {
+    /// Accumulated heat relative to the budget (1.0 means exhausted)
+    pub load: f32,
+    /// Off requests are refused until the resistor cooled down
+    pub cooling_down: bool,
+    /// Number of Off requests that were refused since the tacd started
+    pub refused: u32,
+}
+
+/// Keep track of the heat dissipated in the discharge resistor
+struct DischargeBudget {
+    heat: f32,
+    cooling_down: bool,
+    refused: u32,
+}
+
+impl DischargeBudget {
+    fn new() -> Self {
+        Self {
+            heat: 0.0,
+            cooling_down: false,
+            refused: 0,
         }
     }
+
+    /// Heat added by discharging `volt` for one THREAD_INTERVAL
+    fn heat_of(volt: f32) -> f32 {
+        let relative = volt / MAX_VOLTAGE;
+
+        relative * relative * THREAD_INTERVAL.as_secs_f32()
+    }
+
+    /// Advance by one THREAD_INTERVAL
+    fn step(&mut self, discharging: bool, volt: f32) {
+        let decay = THREAD_INTERVAL.as_secs_f32() / DISCHARGE_COOLING_TIME_CONSTANT.as_secs_f32();
+
+        self.heat *= (-decay).exp();
+
+        if discharging {
+            self.heat += Self::heat_of(volt);
+        }
+
+        if self.heat >= DISCHARGE_HEAT_BUDGET {
+            self.cooling_down = true;
+        } else if self.heat < DISCHARGE_HEAT_BUDGET * DISCHARGE_RESUME_PART {
+            self.cooling_down = false;
+        }
+    }
+
+    /// Can the output be discharged from `volt` without exceeding the budget?
+    fn allows_discharge(&self, volt: f32) -> bool {
+        !self.cooling_down && self.heat + Self::heat_of(volt) <= DISCHARGE_HEAT_BUDGET
+    }
+
+    /// Count an Off request that was refused, as it would have exceeded
+    /// the budget
+    fn refuse(&mut self) {
+        self.refused = self.refused.wrapping_add(1);
+    }
+
+    fn status(&self) -> DischargeStatus {
+        DischargeStatus {
+            load: self.heat / DISCHARGE_HEAT_BUDGET,
+            cooling_down: self.cooling_down,
+            refused: self.refused,
+        }
+    }
+}
+
+/// Characteristics of the current drawn by the DUT right after power on
+#[derive(PartialEq, Clone, Copy, Serialize, Deserialize, Debug)]
+pub struct InrushStats {
+    /// Highest current (in Ampere) measured after turning the output on
+    pub peak: f32,
+    /// Time (in seconds) after which the current stayed close to the
+    /// steady state current or None if it did not settle
+    pub settle_time: Option<f32>,
+    /// Average current (in Ampere) at the end of the measurement window
+    pub steady_state: f32,
+}
+
+/// Analyze current samples taken after power on
+///
+/// The samples are tuples of the time since turning the output on and the
+/// measured current.
+fn analyze_inrush(samples: &[(Duration, f32)]) -> Option<InrushStats> {
+    if samples.len() < INRUSH_MIN_SAMPLES {
+        return None;
+    }
+
+    let steady_start = INRUSH_WINDOW.mul_f32(1.0 - INRUSH_STEADY_PART);
+    let steady: Vec<f32> = samples
+        .iter()
+        .filter(|(t, _)| *t >= steady_start)
+        .map(|(_, curr)| *curr)
+        .collect();
+
+    if steady.is_empty() {
+        return None;
+    }
+
+    let steady_state = steady.iter().sum::<f32>() / (steady.len() as f32);
+    let band = (steady_state.abs() * INRUSH_STEADY_TOLERANCE).max(INRUSH_STEADY_MIN_BAND);
+
+    let peak = samples
+        .iter()
+        .map(|(_, curr)| *curr)
+        .fold(f32::NEG_INFINITY, f32::max);
+
+    // The current has settled after the last sample outside of the band
+    let last_outside = samples
+        .iter()
+        .rposition(|(_, curr)| (curr - steady_state).abs() > band);
+
+    let settle_time = match last_outside {
+        None => Some(0.0),
+        Some(idx) => samples.get(idx + 1).map(|(t, _)| t.as_secs_f32()),
+    };
+
+    Some(InrushStats {
+        peak,
+        settle_time,
+        steady_state,
+    })
+}
+
+/// Measure the inrush current every time the output is turned on and
+/// publish the results per profile
+///
+/// The profile is a user chosen label (e.g. the name of the DUT or its
+/// configuration), so that results for different setups can be compared
+/// over time.
+/// The power thread records when it turned the output on in `turned_on`,
+/// which is picked up once the On state reaches the broker.
+fn setup_inrush(
+    bb: &mut BrokerBuilder,
+    wtb: &mut WatchedTasksBuilder,
+    pwr_curr: AdcChannel,
+    state: Arc<Topic<OutputState>>,
+    profile: Arc<Topic<String>>,
+    turned_on: EventTime,
+) -> Result<()> {
+    let results = bb.topic(
+        "/v1/dut/feedback/inrush",
+        true,
+        false,
+        true,
+        Some(BTreeMap::<String, InrushStats>::new()),
+        1,
+    );
+
+    let (mut state_stream, _) = state.clone().subscribe_unbounded();
+
+    wtb.spawn_task("power-inrush", async move {
+        let mut last_start = turned_on.get();
+
+        while let Some(curr_state) = state_stream.next().await {
+            let start = match turned_on.get() {
+                Some(start) if curr_state == OutputState::On && Some(start) != last_start => start,
+                _ => continue,
+            };
+
+            last_start = Some(start);
+
+            let mut samples = Vec::new();
+
+            while start.elapsed() < INRUSH_WINDOW {
+                task::sleep(INRUSH_SAMPLE_INTERVAL).await;
+
+                if let Ok(m) = pwr_curr.fast.get() {
+                    let ts = m.ts.as_instant();
+
+                    if ts >= start && ts.duration_since(start) < INRUSH_WINDOW {
+                        samples.push((ts.duration_since(start), m.value));
+                    }
+                }
+            }
+
+            // The samples are of no use if the output did not stay on
+            if state.try_get() != Some(OutputState::On) {
+                continue;
+            }
+
+            if let Some(stats) = analyze_inrush(&samples) {
+                let profile = profile
+                    .try_get()
+                    .unwrap_or_else(|| DEFAULT_PROFILE.to_string());
+
+                results.modify(|prev| {
+                    let mut results = prev.unwrap_or_default();
+                    results.insert(profile, stats);
+                    Some(results)
+                });
+            }
+        }
+
+        Ok(())
+    })?;
+
+    Ok(())
+}
+
+/// Publish voltages that are measured on the output while it is off
+fn setup_external_voltage(
+    bb: &mut BrokerBuilder,
+    wtb: &mut WatchedTasksBuilder,
+    pwr_volt: Arc<Topic<Measurement>>,
+    state: Arc<Topic<OutputState>>,
+) -> Result<Arc<Topic<Option<ExternalVoltage>>>> {
+    let external_voltage = bb.topic_ro("/v1/dut/feedback/external_voltage", Some(None));
+    let external_voltage_task = external_voltage.clone();
+
+    wtb.spawn_task("power-external-voltage", async move {
+        let mut detector = ExternalVoltageDetector::new();
+
+        loop {
+            task::sleep(TASK_INTERVAL).await;
+
+            let (state, volt) = match (state.try_get(), pwr_volt.try_get()) {
+                (Some(state), Some(volt)) => (state, volt.value),
+                _ => continue,
+            };
+
+            let detected = detector.step(state, volt, Instant::now());
+            external_voltage_task.set_if_changed(detected);
+        }
+    })?;
+
+    Ok(external_voltage)
+}
+
+/// Set up the topics to configure the probe mode and publish its results
+fn setup_probe(
+    bb: &mut BrokerBuilder,
+    wtb: &mut WatchedTasksBuilder,
+    energy_limit: Arc<AtomicU32>,
+    probe_result: Arc<Mutex<ProbeResult>>,
+) -> Result<()> {
+    let energy_limit_topic = bb.topic(
+        "/v1/dut/powered/probe/energy_limit",
+        true,
+        true,
+        true,
+        Some(PROBE_DEFAULT_ENERGY_LIMIT),
+        1,
+    );
+    let result_topic = bb.topic_ro("/v1/dut/powered/probe", Some(ProbeResult::default()));
+
+    let (mut energy_limit_stream, _) = energy_limit_topic.subscribe_unbounded();
+
+    wtb.spawn_task("power-probe-limit", async move {
+        while let Some(limit) = energy_limit_stream.next().await {
+            energy_limit.store(limit.to_bits(), Ordering::Relaxed);
+        }
+
+        Ok(())
+    })?;
+
+    wtb.spawn_task("power-probe-to-broker", async move {
+        loop {
+            task::sleep(TASK_INTERVAL).await;
+
+            let result = *probe_result.lock().unwrap();
+            result_topic.set_if_changed(result);
+        }
+    })?;
+
+    Ok(())
+}
+
+/// Check that a user configured limit is within `min..=max`
+fn check_limit(limit: f32, min: f32, max: f32) -> Result<f32> {
+    // This is written in a way that NaN is refused as well
+    if !(min..=max).contains(&limit) {
+        bail!("The limit must be between {min} and {max}");
+    }
+
+    Ok(limit)
+}
+
+/// Allow configuring a limit that trips the output earlier than `max`
+///
+/// Use the "register a read-only and a write-only topic with the same name
+/// to perform validation" trick, so that only valid limits are stored.
+/// The power thread reads the limit from `limit` on every iteration.
+fn setup_limit(
+    bb: &mut BrokerBuilder,
+    wtb: &mut WatchedTasksBuilder,
+    name: &str,
+    min: f32,
+    max: f32,
+    limit: Arc<AtomicU32>,
+) -> Result<()> {
+    let path = format!("/v1/dut/limits/{name}");
+    let limit_topic = bb.topic(&path, true, false, true, Some(max), 1);
+    let requests = bb.topic_wo::<f32>(&path, None);
+
+    let (mut request_stream, _) = requests.subscribe_unbounded();
+    let (mut limit_stream, _) = limit_topic.clone().subscribe_unbounded();
+
+    wtb.spawn_task(format!("power-limit-{name}-requests"), async move {
+        while let Some(req) = request_stream.next().await {
+            match check_limit(req, min, max) {
+                Ok(req) => limit_topic.set(req),
+                Err(e) => warn!("Refusing {req} as {path}: {e}"),
+            }
+        }
+
+        Ok(())
+    })?;
+
+    // The persisted value may have been edited by hand, so it is checked
+    // again before it is used.
+    wtb.spawn_task(format!("power-limit-{name}"), async move {
+        while let Some(val) = limit_stream.next().await {
+            let val = check_limit(val, min, max).unwrap_or(max);
+            limit.store(val.to_bits(), Ordering::Relaxed);
+        }
+
+        Ok(())
+    })?;
+
+    Ok(())
+}
+
+/// Publish the modeled thermal load of the discharge resistor
+fn setup_discharge(
+    bb: &mut BrokerBuilder,
+    wtb: &mut WatchedTasksBuilder,
+    discharge_status: Arc<Mutex<DischargeStatus>>,
+) -> Result<()> {
+    let status_topic = bb.topic_ro(
+        "/v1/dut/powered/discharge",
+        Some(DischargeStatus::default()),
+    );
+
+    wtb.spawn_task("power-discharge-to-broker", async move {
+        let mut refused = 0;
+
+        loop {
+            task::sleep(TASK_INTERVAL).await;
+
+            let status = *discharge_status.lock().unwrap();
+
+            if status.refused != refused {
+                refused = status.refused;
+                warn!("Refused a DUT power Off request, the discharge resistor is cooling down");
+            }
+
+            status_topic.set_if_changed(status);
+        }
+    })?;
+
+    Ok(())
+}
+
+/// Queue output transitions to be performed by the realtime power thread
+///
+/// The power thread is the only place that touches the GPIO lines.
+/// Other subsystems request transitions via the request topics, which are
+/// forwarded to the thread through this queue, so that they are subject to
+/// the same fault handling and can not interfere with the timing of the
+/// thread.
+///
+/// This is a ring buffer with a single sender and a single receiver that
+/// only uses atomic loads and stores, so that taking a request from it
+/// never blocks the power thread or makes it wait for the sender.
+/// In turn the thread does not wake up the sender once there is space in
+/// a full queue. The sender checks again every THREAD_INTERVAL instead.
+struct CommandQueue {
+    slots: [AtomicU8; COMMAND_QUEUE_LEN],
+    /// Number of requests taken from the queue so far (wrapping)
+    head: AtomicUsize,
+    /// Number of requests put into the queue so far (wrapping)
+    tail: AtomicUsize,
+}
+
+/// The sending half of a CommandQueue (not Clone, there is only one)
+struct CommandSender {
+    queue: Arc<CommandQueue>,
+}
+
+/// The receiving half of a CommandQueue (not Clone, there is only one)
+struct CommandReceiver {
+    queue: Arc<CommandQueue>,
+}
+
+impl CommandQueue {
+    fn new() -> (CommandSender, CommandReceiver) {
+        let queue = Arc::new(Self {
+            slots: Default::default(),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        });
+
+        (
+            CommandSender {
+                queue: queue.clone(),
+            },
+            CommandReceiver { queue },
+        )
+    }
+}
+
+impl CommandSender {
+    /// Enqueue a request unless the queue is full
+    fn try_send(&self, req: OutputRequest) -> bool {
+        let tail = self.queue.tail.load(Ordering::Relaxed);
+        let head = self.queue.head.load(Ordering::Acquire);
+
+        if tail.wrapping_sub(head) >= COMMAND_QUEUE_LEN {
+            return false;
+        }
+
+        self.queue.slots[tail % COMMAND_QUEUE_LEN].store(req as u8, Ordering::Relaxed);
+        self.queue
+            .tail
+            .store(tail.wrapping_add(1), Ordering::Release);
+
+        true
+    }
+
+    /// Enqueue a request, waiting for space in the queue if it is full
+    ///
+    /// Note that this does not update the state topic, which is only
+    /// done once the thread performed the transition.
+    async fn send(&self, req: OutputRequest) {
+        while !self.try_send(req) {
+            task::sleep(THREAD_INTERVAL).await;
+        }
+    }
+}
+
+impl CommandReceiver {
+    /// Take the oldest request from the queue (if any)
+    fn try_recv(&self) -> Option<OutputRequest> {
+        let head = self.queue.head.load(Ordering::Relaxed);
+        let tail = self.queue.tail.load(Ordering::Acquire);
+
+        if head == tail {
+            return None;
+        }
+
+        let req = self.queue.slots[head % COMMAND_QUEUE_LEN]
+            .load(Ordering::Relaxed)
+            .into();
+
+        self.queue
+            .head
+            .store(head.wrapping_add(1), Ordering::Release);
+
+        Some(req)
+    }
 }
 
 pub struct DutPwrThread {
     pub request: Arc<Topic<OutputRequest>>,
+    /// Requests made on the LCD, which are checked for conflicts with
+    /// requests made via the API before they are forwarded to `request`
+    pub lcd_request: Arc<Topic<OutputRequest>>,
     pub state: Arc<Topic<OutputState>>,
+    pub external_voltage: Arc<Topic<Option<ExternalVoltage>>>,
+    pub maintenance_override: Arc<Topic<Option<MaintenanceOverride>>>,
+    pub profile: Arc<Topic<String>>,
+    pub off_modes: Arc<Topic<BTreeMap<String, OffMode>>>,
     tick: Arc<AtomicU32>,
 }
 
+/// A user chosen label for the DUT (or its configuration).
+/// Inrush measurements are kept and the off mode is configured per profile.
+fn profile_topic(bb: &mut BrokerBuilder) -> Arc<Topic<String>> {
+    bb.topic(
+        "/v1/dut/feedback/inrush/profile",
+        true,
+        true,
+        true,
+        Some(DEFAULT_PROFILE.to_string()),
+        1,
+    )
+}
+
+/// The off mode to use for OffDefault requests per profile
+fn off_modes_topic(bb: &mut BrokerBuilder) -> Arc<Topic<BTreeMap<String, OffMode>>> {
+    bb.topic(
+        "/v1/dut/powered/off_mode",
+        true,
+        true,
+        true,
+        Some(BTreeMap::new()),
+        1,
+    )
+}
+
 struct MedianFilter<const N: usize> {
     history: [f32; N],
     index: usize,
@@ -262,11 +1135,11 @@ fn turn_off_with_reason(
     reason: OutputState,
     pwr_line: &LineHandle,
     discharge_line: &LineHandle,
-    fail_state: &AtomicU8,
+    fail_state: &StateChannel,
 ) -> Result<()> {
     pwr_line.set_value(1 - PWR_LINE_ASSERTED)?;
     discharge_line.set_value(DISCHARGE_LINE_ASSERTED)?;
-    fail_state.store(reason as u8, Ordering::Relaxed);
+    fail_state.store(reason);
 
     Ok(())
 }
@@ -285,53 +1158,164 @@ fn setup_labgrid_compat(
     let compat_request = bb.topic_wo::<u8>("/v1/dut/powered/compat", None);
     let compat_response = bb.topic_ro::<u8>("/v1/dut/powered/compat", None);
 
-    let (mut state_stream, _) = state.subscribe_unbounded();
-    let (mut compat_request_stream, _) = compat_request.subscribe_unbounded();
+    bb.link(wtb, &compat_request, &request, |req| match req {
+        0 => Some(OutputRequest::OffDefault),
+        1 => Some(OutputRequest::On),
+        _ => None,
+    })?;
+
+    bb.link(wtb, &state, &compat_response, |state| match state {
+        OutputState::On => Some(1),
+        OutputState::Changing => None,
+        _ => Some(0),
+    })?;
+
+    Ok(())
+}
+
+/// Resolve OffDefault requests to the off mode configured for `profile`
+///
+/// Profiles without a configured off mode discharge the output.
+fn resolve_off_mode(
+    req: OutputRequest,
+    profile: Option<&str>,
+    off_modes: &BTreeMap<String, OffMode>,
+) -> OutputRequest {
+    match req {
+        OutputRequest::OffDefault => profile
+            .and_then(|profile| off_modes.get(profile))
+            .copied()
+            .unwrap_or(OffMode::Off)
+            .request(),
+        req => req,
+    }
+}
+
+/// The name the DUT power output is shown as in conflicts
+const CONFLICT_OUTPUT: &str = "DUT power";
+
+/// Check if `req` made via `source` may be performed, as it does not
+/// conflict with a recent request from the other source (or was confirmed)
+async fn conflict_free(
+    conflicts: &ConflictGuard,
+    source: RequestSource,
+    req: OutputRequest,
+) -> bool {
+    let on = match req {
+        OutputRequest::On => true,
+        OutputRequest::Off | OutputRequest::OffFloating | OutputRequest::OffDefault => false,
+        OutputRequest::Idle | OutputRequest::Probe => return true,
+    };
+
+    conflicts
+        .allowed(CONFLICT_OUTPUT, SourcedRequest { source, on })
+        .await
+}
 
-    wtb.spawn_task("power-compat-from-labgrid", async move {
-        while let Some(req) = compat_request_stream.next().await {
-            match req {
-                0 => request.set(OutputRequest::Off),
-                1 => request.set(OutputRequest::On),
-                _ => {}
+/// Translate the requests received via the API and forward them to the
+/// power thread
+///
+/// Simple on/off clients like the toggle in the web interface and labgrid
+/// request OffDefault, which is resolved using the off mode of the current
+/// profile.
+/// Requests that conflict with a recent request made on the LCD and turning
+/// off a powered DUT may have to be confirmed on the TAC first.
+/// Later requests are held back until that is resolved, so that they are
+/// performed in order.
+/// Requests made via the LCD do not need a confirmation, as someone already
+/// is at the TAC to make them, but are checked for conflicts as well.
+#[allow(clippy::too_many_arguments)]
+fn setup_request_translation(
+    bb: &mut BrokerBuilder,
+    wtb: &mut WatchedTasksBuilder,
+    api_request: Arc<Topic<OutputRequest>>,
+    lcd_request: Arc<Topic<OutputRequest>>,
+    request: Arc<Topic<OutputRequest>>,
+    state: Arc<Topic<OutputState>>,
+    profile: Arc<Topic<String>>,
+    off_modes: Arc<Topic<BTreeMap<String, OffMode>>>,
+    confirmation: Confirmation,
+    conflicts: ConflictGuard,
+) -> Result<()> {
+    let (mut api_requests, _) = api_request.subscribe_unbounded();
+    let (mut lcd_requests, _) = lcd_request.subscribe_unbounded();
+    let request_lcd = request.clone();
+    let conflicts_lcd = conflicts.clone();
+
+    wtb.spawn_task("power-lcd-requests", async move {
+        while let Some(req) = lcd_requests.next().await {
+            if conflict_free(&conflicts_lcd, RequestSource::Lcd, req).await {
+                request_lcd.set(req);
             }
         }
 
         Ok(())
     })?;
 
-    wtb.spawn_task("power-compat-to-labgrid", async move {
-        while let Some(state) = state_stream.next().await {
-            match state {
-                OutputState::On => compat_response.set(1),
-                OutputState::Changing => {}
-                _ => compat_response.set(0),
+    wtb.spawn_task("power-translate-requests", async move {
+        while let Some(req) = api_requests.next().await {
+            let req = resolve_off_mode(
+                req,
+                profile.try_get().as_deref(),
+                &off_modes.try_get().unwrap_or_default(),
+            );
+
+            let turns_off = matches!(req, OutputRequest::Off | OutputRequest::OffFloating);
+            let powered = matches!(
+                state.try_get(),
+                Some(OutputState::On) | Some(OutputState::Probing)
+            );
+
+            if !conflict_free(&conflicts, RequestSource::Api, req).await {
+                continue;
             }
+
+            if turns_off && powered && !confirmation.confirmed(ConfirmableAction::PowerOff).await {
+                continue;
+            }
+
+            request.set(req);
         }
 
         Ok(())
-    })?;
-
-    Ok(())
+    })
 }
 
 impl DutPwrThread {
+    #[allow(clippy::too_many_arguments)]
     pub async fn new(
         bb: &mut BrokerBuilder,
         wtb: &mut WatchedTasksBuilder,
+        realtime: &Realtime,
         pwr_volt: AdcChannel,
         pwr_curr: AdcChannel,
         pwr_led: Arc<Topic<BlinkPattern>>,
+        gpio_health: &GpioHealth,
         hardware_generation: HardwareGeneration,
+        power_locked: Arc<Topic<bool>>,
+        confirmation: Confirmation,
+        conflicts: ConflictGuard,
     ) -> Result<Self> {
-        let pwr_line = find_line("DUT_PWR_EN")
-            .ok_or_else(|| anyhow!("Could not find GPIO line DUT_PWR_EN"))?;
-        let discharge_line = find_line("DUT_PWR_DISCH")
-            .ok_or_else(|| anyhow!("Could not find GPIO line DUT_PWR_DISCH"))?;
-
+        // Another process may hold the lines for a short time,
+        // e.g. while the tacd is restarting.
+        // Retry a couple of times before giving up.
         let flags = hardware_generation.output_flags();
-        let pwr_line = pwr_line.request(flags.clone(), 1 - PWR_LINE_ASSERTED, "tacd")?;
-        let discharge_line = discharge_line.request(flags, DISCHARGE_LINE_ASSERTED, "tacd")?;
+        let pwr_line = gpio_health
+            .request(
+                "DUT_PWR_EN",
+                flags.clone(),
+                1 - PWR_LINE_ASSERTED,
+                Some(LINE_REQUEST_ATTEMPTS),
+            )
+            .await?;
+        let discharge_line = gpio_health
+            .request(
+                "DUT_PWR_DISCH",
+                flags,
+                DISCHARGE_LINE_ASSERTED,
+                Some(LINE_REQUEST_ATTEMPTS),
+            )
+            .await?;
 
         // The realtime priority must be set up inside the thread, but
         // the operation may fail, in which case we want new() to fail
@@ -340,8 +1324,93 @@ impl DutPwrThread {
         // succeeded.
         let (thread_tx, thread_rx) = bounded(1);
 
+        let (commands, command_queue) = CommandQueue::new();
+
+        // The power thread records when it turns the output on for the
+        // inrush measurement.
+        let turned_on = EventTime::new();
+        let turned_on_thread = turned_on.clone();
+
+        // State changes performed by the thread are signaled to the broker
+        // right away instead of being polled.
+        let state = StateChannel::new(OutputState::Off)?;
+        let state_thread = state.clone();
+
+        let energy_limit = Arc::new(AtomicU32::new(PROBE_DEFAULT_ENERGY_LIMIT.to_bits()));
+        let probe_result = Arc::new(Mutex::new(ProbeResult::default()));
+
+        setup_probe(bb, wtb, energy_limit.clone(), probe_result.clone())?;
+
+        let current_limit = Arc::new(AtomicU32::new(MAX_CURRENT.to_bits()));
+        let voltage_limit = Arc::new(AtomicU32::new(MAX_VOLTAGE.to_bits()));
+
+        setup_limit(
+            bb,
+            wtb,
+            "current",
+            MIN_CURRENT_LIMIT,
+            MAX_CURRENT,
+            current_limit.clone(),
+        )?;
+        setup_limit(
+            bb,
+            wtb,
+            "voltage",
+            MIN_VOLTAGE_LIMIT,
+            MAX_VOLTAGE,
+            voltage_limit.clone(),
+        )?;
+
+        let relaxation = Relaxation::new();
+        let maintenance_override = setup_maintenance_override(bb, wtb, relaxation.clone())?;
+
+        let discharge_status = Arc::new(Mutex::new(DischargeStatus::default()));
+
+        setup_discharge(bb, wtb, discharge_status.clone())?;
+
+        // The power thread takes ownership of the channel
+        let pwr_volt_topic = pwr_volt.topic.clone();
+        let pwr_curr_inrush = pwr_curr.clone();
+        let pwr_volt_energy = pwr_volt.clone();
+        let pwr_curr_energy = pwr_curr.clone();
+
+        let mut monitor = realtime.monitor("power-thread", THREAD_INTERVAL);
+
+        // The trace span of the request that is currently being processed.
+        // It is handed from the broker task to the thread forwarding the
+        // state to the broker and on to the LED task, so that the whole round
+        // trip shows up as a single trace.
+        // The power thread itself does not touch the span, as creating spans
+        // allocates and takes locks when they are exported. It only records
+        // when it performed a request, which is added to the trace later on.
+        let request_span: Arc<Mutex<Option<Span>>> = Arc::new(Mutex::new(None));
+        let switched = EventTime::new();
+        let switched_thread = switched.clone();
+
         // Spawn a high priority thread that handles the power status
         // in a realtimey fashion.
+        //
+        // Nothing the thread does to communicate with other threads may
+        // wait for them, as that would let lower priority threads delay the
+        // fault handling. This is why each primitive used in the loop can
+        // not block:
+        //
+        // - pwr_volt / pwr_curr, tick, the limits, energy_limit and
+        //   relaxation are atomic variables, which are only loaded and
+        //   stored.
+        // - command_queue is a ring buffer of atomics. Taking a request never
+        //   waits for the sender (see CommandQueue).
+        // - state is an atomic variable plus an eventfd. The write to the
+        //   eventfd does not wait for the reader (see StateChannel::notify()).
+        // - switched and turned_on are atomic variables (see EventTime).
+        //   Trace spans are only created by the threads picking them up.
+        // - probe_result and discharge_status are only updated using
+        //   try_lock(), which does not wait if the publishing task holds the
+        //   lock. The update is then skipped and made in the next iteration.
+        // - monitor only stores atomic variables. Errors are logged by the
+        //   realtime audit task instead of the thread itself.
+        //
+        // thread_tx is only used once, before entering the loop.
         wtb.spawn_thread("power-thread", move || {
             let mut last_ts: Option<Instant> = None;
 
@@ -355,31 +1424,37 @@ impl DutPwrThread {
 
             realtime_priority()?;
 
-            let (tick_weak, request, state) = {
+            let state = state_thread;
+
+            let tick_weak = {
                 let tick = Arc::new(AtomicU32::new(0));
                 let tick_weak = Arc::downgrade(&tick);
 
-                let request = Arc::new(AtomicU8::new(OutputRequest::Idle as u8));
-                let state = Arc::new(AtomicU8::new(OutputState::Off as u8));
-
                 thread_tx
-                    .try_send((tick, request.clone(), state.clone()))
+                    .try_send(tick)
                     .expect("Queue that should be empty wasn't");
 
-                (tick_weak, request, state)
+                tick_weak
             };
 
-            // The grace period contains the number of loop iterations until
-            // we start handling over/under voltage and overcurrent events.
+            // The grace period contains the time until we start handling
+            // over/under voltage events.
             // This counts down to zero after turning on the output.
             // And is kept at TURN_ON_ERROR_GRACE_PERIOD while the output is off.
+            // Overcurrent events use a grace period of their own, which is
+            // longer during a maintenance override.
             let mut grace_period = TURN_ON_ERROR_GRACE_PERIOD;
+            let mut current_grace_period = relaxation.current_grace_period();
+
+            let mut prober = Prober::new();
+            let mut discharge_budget = DischargeBudget::new();
 
             // Run as long as there is a strong reference to `tick`.
             // As tick is a private member of the struct this is equivalent
             // to running as long as the DutPwrThread was not dropped.
             while let Some(tick) = tick_weak.upgrade() {
                 thread::sleep(THREAD_INTERVAL);
+                monitor.woke_up();
 
                 // Get new voltage and current readings while making sure
                 // that they are not stale
@@ -419,6 +1494,24 @@ impl DutPwrThread {
                     }
                 };
 
+                let (volt_unfiltered, curr_unfiltered) = (volt, curr);
+
+                // The discharge line is asserted in all of these states
+                let discharging = matches!(
+                    state.load(),
+                    OutputState::Off
+                        | OutputState::InvertedPolarity
+                        | OutputState::OverCurrent
+                        | OutputState::OverVoltage
+                        | OutputState::RealtimeViolation
+                );
+
+                discharge_budget.step(discharging, volt_unfiltered);
+
+                if let Ok(mut status) = discharge_status.try_lock() {
+                    *status = discharge_budget.status();
+                }
+
                 // The median filter needs some values in it's backlog before it
                 // starts outputting values.
                 let (volt, curr) = match (volt_filter.step(volt), curr_filter.step(curr)) {
@@ -426,14 +1519,14 @@ impl DutPwrThread {
                     _ => continue,
                 };
 
-                // Take the next pending OutputRequest (if any) even if it
+                // Take the next queued OutputRequest (if any) even if it
                 // may not be used due to a pending error condition, as it
                 // could be quite surprising for the output to turn on
                 // immediately when a fault is cleared after quite some time
                 // of the output being off.
-                let req = request
-                    .swap(OutputRequest::Idle as u8, Ordering::Relaxed)
-                    .into();
+                // try_recv() never blocks, so a busy queue can not delay the
+                // fault handling below.
+                let req = command_queue.try_recv().unwrap_or(OutputRequest::Idle);
 
                 // Checking for MAX_VOLTAGE, MIN_VOLTAGE, MAX_CURRENT error conditions while
                 // the DUT power switch is off does not make a lot of sense,
@@ -443,22 +1536,35 @@ impl DutPwrThread {
                 // likely due to our high-impedance measurements and not due to a real error.
                 // Ignore these kinds of errors while the output is off and for a few
                 // THREAD_INTERVALs after turning it on.
-                grace_period = match state.load(Ordering::Relaxed).into() {
-                    OutputState::On => grace_period.saturating_sub(THREAD_INTERVAL),
+                (grace_period, current_grace_period) = match state.load() {
+                    OutputState::On => (
+                        grace_period.saturating_sub(THREAD_INTERVAL),
+                        current_grace_period.saturating_sub(THREAD_INTERVAL),
+                    ),
                     OutputState::Off
                     | OutputState::OffFloating
                     | OutputState::Changing
                     | OutputState::InvertedPolarity
                     | OutputState::OverCurrent
                     | OutputState::OverVoltage
-                    | OutputState::RealtimeViolation => TURN_ON_ERROR_GRACE_PERIOD,
+                    | OutputState::RealtimeViolation
+                    | OutputState::Probing => (
+                        TURN_ON_ERROR_GRACE_PERIOD,
+                        relaxation.current_grace_period(),
+                    ),
                 };
 
+                // The user configured limits are never above
+                // MAX_VOLTAGE / MAX_CURRENT. Only a time-boxed
+                // maintenance override can raise the current limit.
+                let max_voltage = f32::from_bits(voltage_limit.load(Ordering::Relaxed));
+                let max_current =
+                    relaxation.max_current(f32::from_bits(current_limit.load(Ordering::Relaxed)));
+
                 if grace_period == Duration::ZERO {
                     // At this point the output is on and has been on for
                     // TURN_ON_ERROR_GRACE_PERIOD, so we start checking for error conditions.
-
-                    if volt > MAX_VOLTAGE {
+                    if volt > max_voltage {
                         turn_off_with_reason(
                             OutputState::OverVoltage,
                             &pwr_line,
@@ -479,16 +1585,62 @@ impl DutPwrThread {
 
                         continue;
                     }
+                }
 
-                    if curr > MAX_CURRENT {
-                        turn_off_with_reason(
+                if current_grace_period == Duration::ZERO && curr > max_current {
+                    turn_off_with_reason(
+                        OutputState::OverCurrent,
+                        &pwr_line,
+                        &discharge_line,
+                        &state,
+                    )?;
+
+                    continue;
+                }
+
+                // Pulse the output while probing. The output is never on for
+                // long enough to leave the grace period, so the probe mode
+                // has to check for error conditions on its own.
+                let probing = state.load() == OutputState::Probing;
+
+                if probing && req == OutputRequest::Idle {
+                    let limits = ProbeLimits {
+                        energy: f32::from_bits(energy_limit.load(Ordering::Relaxed)),
+                        voltage: max_voltage,
+                        current: max_current,
+                    };
+
+                    match prober.step(volt_unfiltered, curr_unfiltered, limits) {
+                        ProbeStep::PulseOn => pwr_line.set_value(PWR_LINE_ASSERTED)?,
+                        ProbeStep::PulseOff => pwr_line.set_value(1 - PWR_LINE_ASSERTED)?,
+                        ProbeStep::OverCurrent => turn_off_with_reason(
                             OutputState::OverCurrent,
                             &pwr_line,
                             &discharge_line,
                             &state,
-                        )?;
+                        )?,
+                        ProbeStep::OverVoltage => turn_off_with_reason(
+                            OutputState::OverVoltage,
+                            &pwr_line,
+                            &discharge_line,
+                            &state,
+                        )?,
+                        ProbeStep::InvertedPolarity => turn_off_with_reason(
+                            OutputState::InvertedPolarity,
+                            &pwr_line,
+                            &discharge_line,
+                            &state,
+                        )?,
+                        ProbeStep::LimitReached => turn_off_with_reason(
+                            OutputState::Off,
+                            &pwr_line,
+                            &discharge_line,
+                            &state,
+                        )?,
+                    }
 
-                        continue;
+                    if let Ok(mut result) = probe_result.try_lock() {
+                        *result = prober.result;
                     }
                 }
 
@@ -499,19 +1651,48 @@ impl DutPwrThread {
                     OutputRequest::On => {
                         discharge_line.set_value(1 - DISCHARGE_LINE_ASSERTED)?;
                         pwr_line.set_value(PWR_LINE_ASSERTED)?;
-                        state.store(OutputState::On as u8, Ordering::Relaxed);
+
+                        let was_on = state.swap(OutputState::On) == OutputState::On;
+
+                        if !was_on {
+                            turned_on_thread.record(Instant::now());
+                        }
                     }
-                    OutputRequest::Off => {
-                        discharge_line.set_value(DISCHARGE_LINE_ASSERTED)?;
-                        pwr_line.set_value(1 - PWR_LINE_ASSERTED)?;
-                        state.store(OutputState::Off as u8, Ordering::Relaxed);
+                    OutputRequest::Off | OutputRequest::OffDefault => {
+                        // Refuse the request rather than overheating the
+                        // discharge resistor. The output is not turned off
+                        // without discharging it instead, as that is not what
+                        // was requested. The unchanged state is published
+                        // below.
+                        if discharge_budget.allows_discharge(volt_unfiltered) {
+                            discharge_line.set_value(DISCHARGE_LINE_ASSERTED)?;
+                            pwr_line.set_value(1 - PWR_LINE_ASSERTED)?;
+                            state.store(OutputState::Off);
+                        } else {
+                            discharge_budget.refuse();
+                        }
                     }
                     OutputRequest::OffFloating => {
                         discharge_line.set_value(1 - DISCHARGE_LINE_ASSERTED)?;
                         pwr_line.set_value(1 - PWR_LINE_ASSERTED)?;
-                        state.store(OutputState::OffFloating as u8, Ordering::Relaxed);
+                        state.store(OutputState::OffFloating);
+                    }
+                    OutputRequest::Probe => {
+                        // Do not waste the energy budget on the discharge resistor
+                        discharge_line.set_value(1 - DISCHARGE_LINE_ASSERTED)?;
+                        pwr_line.set_value(1 - PWR_LINE_ASSERTED)?;
+                        state.store(OutputState::Probing);
+                        prober = Prober::new();
                     }
                 }
+
+                // The broker side marks the state as Changing when it sends
+                // a request. Resolve that even if the request did not change
+                // the state, e.g. when turning an output off that already was.
+                if req != OutputRequest::Idle {
+                    switched_thread.record(Instant::now());
+                    state.notify();
+                }
             }
 
             // Make sure to enter fail safe mode before leaving the thread
@@ -520,40 +1701,126 @@ impl DutPwrThread {
             Ok(())
         })?;
 
-        let (tick, request, state) = thread_rx.recv().await?;
+        let tick = thread_rx.recv().await?;
 
         // The request and state topic use the same external path, this way one
         // can e.g. publish "On" to the topic and be sure that the output is
         // actually on once a corresponding publish is received from the broker,
         // as it has done the full round trip through the realtime power thread
         // and is not just a copy of the received command.
-        let request_topic = bb.topic_wo::<OutputRequest>("/v1/dut/powered", None);
+        // Requests from the API are translated and forwarded to the topic
+        // used inside the tacd, as they may need a confirmation first.
+        // Requests made on the LCD are checked for conflicts with them.
+        let api_request_topic = bb.topic_wo::<OutputRequest>("/v1/dut/powered", None);
+        let lcd_request_topic = Topic::anonymous(None);
+        let request_topic = Topic::anonymous(None);
         let state_topic = bb.topic_ro::<OutputState>("/v1/dut/powered", None);
 
-        setup_labgrid_compat(bb, wtb, request_topic.clone(), state_topic.clone())?;
-
-        // Requests come from the broker framework and are placed into an atomic
-        // request variable read by the thread.
+        let profile = profile_topic(bb);
+        let off_modes = off_modes_topic(bb);
+
+        setup_labgrid_compat(bb, wtb, api_request_topic.clone(), state_topic.clone())?;
+
+        setup_request_translation(
+            bb,
+            wtb,
+            api_request_topic,
+            lcd_request_topic.clone(),
+            request_topic.clone(),
+            state_topic.clone(),
+            profile.clone(),
+            off_modes.clone(),
+            confirmation,
+            conflicts,
+        )?;
+
+        let external_voltage =
+            setup_external_voltage(bb, wtb, pwr_volt_topic, state_topic.clone())?;
+
+        setup_inrush(
+            bb,
+            wtb,
+            pwr_curr_inrush,
+            state_topic.clone(),
+            profile.clone(),
+            turned_on,
+        )?;
+
+        setup_sequence(bb, wtb, request_topic.clone(), state_topic.clone())?;
+
+        setup_energy(
+            bb,
+            wtb,
+            pwr_volt_energy,
+            pwr_curr_energy,
+            state_topic.clone(),
+        )?;
+
+        // Requests come from the broker framework and are placed into the
+        // command queue read by the thread.
         let state_topic_task = state_topic.clone();
+        let request_span_switch = request_span.clone();
+        let request_span_led = request_span.clone();
         let (mut request_stream, _) = request_topic.clone().subscribe_unbounded();
         wtb.spawn_task("power-from-broker", async move {
             while let Some(req) = request_stream.next().await {
+                // The calibration data may not match the hardware, in which
+                // case the output protection can not be trusted.
+                let turns_on = matches!(req, OutputRequest::On | OutputRequest::Probe);
+
+                if turns_on && power_locked.try_get().unwrap_or(false) {
+                    warn!(
+                        "Refusing DUT power request {req:?}, the hardware consistency check failed"
+                    );
+
+                    // Publish the unchanged state again, so that clients
+                    // waiting for the result of their request get an answer.
+                    state_topic_task.modify(|prev| prev);
+                    continue;
+                }
+
+                // The span is closed once the LED shows the new state
+                let span = info_span!("dut_power_request", request = ?req);
+                *request_span.lock().unwrap() = Some(span);
+
                 state_topic_task.set(OutputState::Changing);
-                request.store(req as u8, Ordering::Relaxed);
+                commands.send(req).await;
             }
 
             Ok(())
         })?;
 
-        // State information comes from the thread in the form of an atomic
-        // variable and is forwarded to the broker framework.
+        // State information is signaled by the thread and forwarded to the
+        // broker framework as soon as it changes.
+        // Waiting on the eventfd blocks, so this needs a thread of its own.
+        // The trace span for switching the output is created here, as the
+        // power thread can not do so itself.
         let state_topic_task = state_topic.clone();
-        wtb.spawn_task("power-to-broker", async move {
-            loop {
-                task::sleep(TASK_INTERVAL).await;
+        wtb.spawn_thread("power-to-broker", move || {
+            let mut last_switched = None;
 
-                let curr_state = state.load(Ordering::Relaxed).into();
-                state_topic_task.set_if_changed(curr_state);
+            loop {
+                let curr_state = state.wait()?;
+
+                // Only add a span if the thread performed a request since
+                // the last wakeup. The time it took for the state to get here
+                // is recorded as well.
+                let switched_at = switched.get();
+                let switch_span =
+                    switched_at
+                        .filter(|_| switched_at != last_switched)
+                        .and_then(|switched_at| {
+                            let handoff_us = switched_at.elapsed().as_micros() as u64;
+
+                            request_span_switch.lock().unwrap().as_ref().map(
+                                |span| info_span!(parent: span, "dut_power_switch", handoff_us),
+                            )
+                        })
+                        .unwrap_or_else(Span::none);
+
+                last_switched = switched_at;
+
+                switch_span.in_scope(|| state_topic_task.set_if_changed(curr_state));
             }
         })?;
 
@@ -575,16 +1842,33 @@ impl DutPwrThread {
                 }
 
                 // ... followed by a pause and repetition
-                pb.stay_for(Duration::from_millis(400)).forever()
+                pb.stay_for(Duration::from_millis(400))
+                    .forever()
+                    .with_meaning(LedMeaning::ErrorBlink)
             };
+            let pattern_probing = BlinkPatternBuilder::new(0.0)
+                .fade_to(0.3, Duration::from_millis(1000))
+                .fade_to(0.0, Duration::from_millis(1000))
+                .forever()
+                .with_meaning(LedMeaning::Probing);
 
             while let Some(state) = state_stream.next().await {
-                match state {
+                let led_span = match state {
+                    OutputState::Changing => None,
+                    _ => request_span_led.lock().unwrap().take(),
+                }
+                .map_or_else(
+                    Span::none,
+                    |span| info_span!(parent: &span, "dut_power_led"),
+                );
+
+                led_span.in_scope(|| match state {
                     OutputState::On => pwr_led.set(pattern_on.clone()),
                     OutputState::Off | OutputState::OffFloating => pwr_led.set(pattern_off.clone()),
+                    OutputState::Probing => pwr_led.set(pattern_probing.clone()),
                     OutputState::Changing => {}
                     _ => pwr_led.set(pattern_error.clone()),
-                }
+                });
             }
 
             Ok(())
@@ -592,11 +1876,81 @@ impl DutPwrThread {
 
         Ok(Self {
             request: request_topic,
+            lcd_request: lcd_request_topic,
             state: state_topic,
+            external_voltage,
+            maintenance_override,
+            profile,
+            off_modes,
             tick,
         })
     }
 
+    /// Set up the DUT power topics without a power thread
+    ///
+    /// This is used if the power board ADC is not available, e.g. because
+    /// the power board is missing, or if the DUT power backend is stubbed.
+    /// Without feedback the output can not be protected against overcurrent
+    /// events, so it stays off and all requests are ignored.
+    pub fn unavailable(
+        bb: &mut BrokerBuilder,
+        wtb: &mut WatchedTasksBuilder,
+        pwr_volt: AdcChannel,
+    ) -> Result<Self> {
+        let request_topic = bb.topic_wo::<OutputRequest>("/v1/dut/powered", None);
+        let state_topic = bb.topic_ro("/v1/dut/powered", Some(OutputState::Off));
+
+        setup_labgrid_compat(bb, wtb, request_topic.clone(), state_topic.clone())?;
+
+        let external_voltage =
+            setup_external_voltage(bb, wtb, pwr_volt.topic, state_topic.clone())?;
+
+        // There is nothing to relax, but keep the override API consistent
+        let maintenance_override = setup_maintenance_override(bb, wtb, Relaxation::new())?;
+
+        // The profiles are still used to group e.g. annotations and can be
+        // shared with other TACs.
+        let profile = profile_topic(bb);
+        let off_modes = off_modes_topic(bb);
+
+        let (mut request_stream, _) = request_topic.clone().subscribe_unbounded();
+        wtb.spawn_task("power-unavailable-requests", async move {
+            while let Some(req) = request_stream.next().await {
+                warn!("Ignoring DUT power request {req:?}, the power board is not available");
+            }
+
+            Ok(())
+        })?;
+
+        // There is no power thread for the watchdog to check on, but keep
+        // the tick going so that it still checks on the async runtime.
+        let tick = Arc::new(AtomicU32::new(0));
+        let tick_task = tick.clone();
+        wtb.spawn_task("power-unavailable-tick", async move {
+            loop {
+                task::sleep(THREAD_INTERVAL).await;
+
+                tick_task.fetch_add(1, Ordering::Relaxed);
+            }
+        })?;
+
+        Ok(Self {
+            request: request_topic.clone(),
+            lcd_request: request_topic,
+            state: state_topic,
+            external_voltage,
+            maintenance_override,
+            profile,
+            off_modes,
+            tick,
+        })
+    }
+
+    /// Add web endpoints to share profiles between TACs
+    pub fn serve_profiles(&self, server: &mut Server<()>) {
+        profiles::serve(server, self.profile.clone(), self.off_modes.clone());
+    }
+
     pub fn tick(&self) -> TickReader {
         TickReader::new(&self.tick)
     }
@@ -604,19 +1958,27 @@ impl DutPwrThread {
 
 #[cfg(test)]
 mod tests {
-    use std::time::Duration;
+    use std::collections::BTreeMap;
+    use std::time::{Duration, Instant};
 
     use async_std::task::{block_on, sleep};
 
     use crate::adc::Adc;
+    use crate::backends::{Backends, Degraded};
     use crate::broker::{BrokerBuilder, Topic};
-    use crate::digital_io::find_line;
+    use crate::confirmation::Confirmation;
+    use crate::conflicts::ConflictGuard;
+    use crate::digital_io::{find_line, GpioHealth};
+    use crate::realtime::Realtime;
     use crate::system::HardwareGeneration;
     use crate::watched_tasks::WatchedTasksBuilder;
 
     use super::{
-        DutPwrThread, OutputRequest, OutputState, DISCHARGE_LINE_ASSERTED, MAX_CURRENT,
-        MAX_VOLTAGE, MIN_VOLTAGE, PWR_LINE_ASSERTED,
+        analyze_inrush, check_limit, resolve_off_mode, CommandQueue, DischargeBudget, DutPwrThread,
+        ExternalVoltageDetector, LedMeaning, OffMode, OutputRequest, OutputState, ProbeLimits,
+        ProbeStep, Prober, StateChannel, COMMAND_QUEUE_LEN, DISCHARGE_LINE_ASSERTED,
+        EXTERNAL_VOLTAGE_MIN_DURATION, EXTERNAL_VOLTAGE_THRESHOLD, MAX_CURRENT, MAX_VOLTAGE,
+        MIN_VOLTAGE, PROBE_MAX_CURRENT, PROBE_PULSE_EVERY, PWR_LINE_ASSERTED, THREAD_INTERVAL,
     };
 
     #[test]
@@ -628,16 +1990,31 @@ mod tests {
 
         let (adc, dut_pwr, led) = {
             let mut bb = BrokerBuilder::new();
-            let adc = block_on(Adc::new(&mut bb, &mut wtb, hardware_generation)).unwrap();
+            let realtime = Realtime::new(&mut bb, &mut wtb).unwrap();
+            let adc = block_on(Adc::new(
+                &mut bb,
+                &mut wtb,
+                &realtime,
+                hardware_generation,
+                &Backends::default(),
+                &Degraded::new(&mut bb),
+            ))
+            .unwrap();
+            let gpio_health = GpioHealth::new(&mut bb);
             let led = Topic::anonymous(None);
 
             let dut_pwr = block_on(DutPwrThread::new(
                 &mut bb,
                 &mut wtb,
+                &realtime,
                 adc.pwr_volt.clone(),
                 adc.pwr_curr.clone(),
                 led.clone(),
+                &gpio_health,
                 hardware_generation,
+                Topic::anonymous(Some(false)),
+                Confirmation::new(&mut bb),
+                ConflictGuard::new(&mut bb, &mut wtb).unwrap(),
             ))
             .unwrap();
 
@@ -691,6 +2068,7 @@ mod tests {
         assert_eq!(discharge_line.stub_get(), DISCHARGE_LINE_ASSERTED);
         assert_eq!(block_on(dut_pwr.state.get()), OutputState::InvertedPolarity);
         assert!(block_on(led.get()).is_blinking());
+        assert_eq!(block_on(led.get()).meaning(), LedMeaning::ErrorBlink);
 
         println!("Turn on again");
         dut_pwr.request.set(OutputRequest::On);
@@ -717,6 +2095,7 @@ mod tests {
         assert_eq!(discharge_line.stub_get(), DISCHARGE_LINE_ASSERTED);
         assert_eq!(block_on(dut_pwr.state.get()), OutputState::OverCurrent);
         assert!(block_on(led.get()).is_blinking());
+        assert_eq!(block_on(led.get()).meaning(), LedMeaning::ErrorBlink);
 
         println!("Turn on again");
         dut_pwr.request.set(OutputRequest::On);
@@ -773,6 +2152,14 @@ mod tests {
         assert_eq!(block_on(dut_pwr.state.get()), OutputState::On);
         assert!(block_on(led.get()).is_on());
 
+        println!("Turn off");
+        dut_pwr.request.set(OutputRequest::Off);
+        block_on(sleep(Duration::from_millis(500)));
+        assert_eq!(pwr_line.stub_get(), 1 - PWR_LINE_ASSERTED);
+        assert_eq!(discharge_line.stub_get(), DISCHARGE_LINE_ASSERTED);
+        assert_eq!(block_on(dut_pwr.state.get()), OutputState::Off);
+        assert!(block_on(led.get()).is_off());
+
         println!("Drop DutPwrThread");
         std::mem::drop(dut_pwr);
         block_on(sleep(Duration::from_millis(500)));
@@ -789,16 +2176,31 @@ mod tests {
 
         let (adc, dut_pwr) = {
             let mut bb = BrokerBuilder::new();
-            let adc = block_on(Adc::new(&mut bb, &mut wtb, hardware_generation)).unwrap();
+            let realtime = Realtime::new(&mut bb, &mut wtb).unwrap();
+            let adc = block_on(Adc::new(
+                &mut bb,
+                &mut wtb,
+                &realtime,
+                hardware_generation,
+                &Backends::default(),
+                &Degraded::new(&mut bb),
+            ))
+            .unwrap();
+            let gpio_health = GpioHealth::new(&mut bb);
             let led = Topic::anonymous(None);
 
             let dut_pwr = block_on(DutPwrThread::new(
                 &mut bb,
                 &mut wtb,
+                &realtime,
                 adc.pwr_volt.clone(),
                 adc.pwr_curr.clone(),
                 led,
+                &gpio_health,
                 hardware_generation,
+                Topic::anonymous(Some(false)),
+                Confirmation::new(&mut bb),
+                ConflictGuard::new(&mut bb, &mut wtb).unwrap(),
             ))
             .unwrap();
 
@@ -838,4 +2240,289 @@ mod tests {
         assert_eq!(discharge_line.stub_get(), DISCHARGE_LINE_ASSERTED);
         assert_eq!(block_on(dut_pwr.state.get()), OutputState::OverVoltage);
     }
+
+    #[test]
+    fn probe_energy_limit() {
+        let limits = |energy| ProbeLimits {
+            energy,
+            voltage: MAX_VOLTAGE,
+            current: 0.5,
+        };
+
+        let mut prober = Prober::new();
+
+        println!("The first pulse assumes the voltage and current limit");
+        let first = prober.next_pulse_energy(limits(0.0));
+        assert_eq!(first, MAX_VOLTAGE * 0.5 * THREAD_INTERVAL.as_secs_f32());
+
+        // Allow for the first (worst case) pulse and a few more at 12V
+        let limit = first + 0.2;
+
+        let mut pulses = 0;
+
+        loop {
+            match prober.step(12.0, PROBE_MAX_CURRENT * 0.5, limits(limit)) {
+                ProbeStep::PulseOn => pulses += 1,
+                ProbeStep::PulseOff => {}
+                ProbeStep::LimitReached => break,
+                step => panic!("Unexpected {step:?}"),
+            }
+
+            assert!(pulses < 100);
+        }
+
+        assert!(prober.result.limit_reached);
+        assert_eq!(prober.result.pulses, pulses);
+        assert!(prober.result.energy <= limit);
+        assert_eq!(prober.result.current, Some(PROBE_MAX_CURRENT * 0.5));
+
+        println!("A pulse is never larger than what fits the energy limit");
+        let mut prober = Prober::new();
+
+        for _ in 1..PROBE_PULSE_EVERY {
+            prober.step(0.0, 0.0, limits(first * 0.99));
+        }
+
+        assert_eq!(
+            prober.step(0.0, 0.0, limits(first * 0.99)),
+            ProbeStep::LimitReached
+        );
+
+        let pulse = |volt, curr| {
+            let mut prober = Prober::new();
+
+            for _ in 1..PROBE_PULSE_EVERY {
+                assert_eq!(prober.step(0.0, 0.0, limits(10.0)), ProbeStep::PulseOff);
+            }
+
+            assert_eq!(prober.step(0.0, 0.0, limits(10.0)), ProbeStep::PulseOn);
+
+            prober.step(volt, curr, limits(10.0))
+        };
+
+        println!("Trip on overcurrent, overvoltage and inverted polarity");
+        assert_eq!(
+            pulse(12.0, PROBE_MAX_CURRENT * 1.01),
+            ProbeStep::OverCurrent
+        );
+        assert_eq!(pulse(MAX_VOLTAGE * 1.01, 0.0), ProbeStep::OverVoltage);
+        assert_eq!(pulse(MIN_VOLTAGE * 1.01, 0.0), ProbeStep::InvertedPolarity);
+        assert_eq!(pulse(12.0, PROBE_MAX_CURRENT * 0.5), ProbeStep::PulseOff);
+
+        println!("Do not pulse with an invalid limit");
+        let mut prober = Prober::new();
+
+        for _ in 1..PROBE_PULSE_EVERY {
+            prober.step(0.0, 0.0, limits(f32::NAN));
+        }
+
+        assert_eq!(
+            prober.step(0.0, 0.0, limits(f32::NAN)),
+            ProbeStep::LimitReached
+        );
+    }
+
+    #[test]
+    fn limits() {
+        assert_eq!(check_limit(1.5, 0.05, MAX_CURRENT).unwrap(), 1.5);
+        assert_eq!(
+            check_limit(MAX_CURRENT, 0.05, MAX_CURRENT).unwrap(),
+            MAX_CURRENT
+        );
+        assert!(check_limit(MAX_CURRENT * 1.01, 0.05, MAX_CURRENT).is_err());
+        assert!(check_limit(0.0, 0.05, MAX_CURRENT).is_err());
+        assert!(check_limit(-1.0, 0.05, MAX_CURRENT).is_err());
+        assert!(check_limit(f32::NAN, 0.05, MAX_CURRENT).is_err());
+        assert!(check_limit(f32::INFINITY, 0.05, MAX_CURRENT).is_err());
+    }
+
+    #[test]
+    fn discharge_budget() {
+        let mut budget = DischargeBudget::new();
+
+        println!("Discharging low voltages is always possible");
+        for _ in 0..10_000 {
+            budget.step(true, 5.0);
+        }
+        assert!(budget.allows_discharge(5.0));
+        assert!(!budget.status().cooling_down);
+
+        println!("Discharging the maximum voltage for too long exhausts the budget");
+        let mut budget = DischargeBudget::new();
+        let mut intervals = 0;
+
+        while budget.allows_discharge(MAX_VOLTAGE) {
+            budget.step(true, MAX_VOLTAGE);
+            intervals += 1;
+        }
+
+        assert!(intervals > 10);
+        assert!(budget.status().load > 0.9);
+
+        budget.step(true, MAX_VOLTAGE);
+        assert!(budget.status().cooling_down);
+        assert!(!budget.allows_discharge(0.0));
+
+        println!("Refused requests are counted");
+        assert_eq!(budget.status().refused, 0);
+        budget.refuse();
+        assert_eq!(budget.status().refused, 1);
+
+        println!("The resistor cools down while not discharging");
+        let mut intervals = 0;
+
+        while budget.status().cooling_down {
+            budget.step(false, MAX_VOLTAGE);
+            intervals += 1;
+        }
+
+        assert!(intervals > 10);
+        assert!(budget.status().load < 0.5);
+        assert!(budget.allows_discharge(MAX_VOLTAGE));
+    }
+
+    #[test]
+    fn external_voltage() {
+        let mut detector = ExternalVoltageDetector::new();
+        let start = Instant::now();
+        let volt = EXTERNAL_VOLTAGE_THRESHOLD * 12.0;
+
+        // Not reported before the minimum duration has passed
+        assert!(detector.step(OutputState::Off, volt, start).is_none());
+
+        let later = start + EXTERNAL_VOLTAGE_MIN_DURATION + Duration::from_secs(1);
+        let detected = detector.step(OutputState::Off, volt, later).unwrap();
+        assert_eq!(detected.voltage, volt);
+        assert_eq!(
+            detected.duration,
+            EXTERNAL_VOLTAGE_MIN_DURATION.as_secs() + 1
+        );
+
+        // The output voltage is expected when the output is on
+        assert!(detector.step(OutputState::On, volt, later).is_none());
+
+        // A negative external voltage is just as bad
+        assert!(detector
+            .step(OutputState::OffFloating, -volt, later)
+            .is_none());
+        let even_later = later + EXTERNAL_VOLTAGE_MIN_DURATION;
+        assert!(detector
+            .step(OutputState::OffFloating, -volt, even_later)
+            .is_some());
+
+        // Dropping below the threshold resets the duration
+        assert!(detector.step(OutputState::Off, 0.0, even_later).is_none());
+        assert!(detector.step(OutputState::Off, volt, even_later).is_none());
+    }
+
+    #[test]
+    fn inrush() {
+        let sample = |ms: u64, curr: f32| (Duration::from_millis(ms), curr);
+
+        println!("Too few samples can not be analyzed");
+        let few: Vec<_> = (0..5).map(|i| sample(i * 200, 0.5)).collect();
+        assert!(analyze_inrush(&few).is_none());
+
+        println!("A peak that decays to a steady state");
+        let decaying: Vec<_> = (0..200)
+            .map(|i| {
+                let ms = i * 5;
+                let curr = if ms < 100 {
+                    2.0 - (ms as f32) / 100.0
+                } else {
+                    0.5
+                };
+                sample(ms, curr)
+            })
+            .collect();
+
+        let stats = analyze_inrush(&decaying).unwrap();
+        assert_eq!(stats.peak, 2.0);
+        assert_eq!(stats.steady_state, 0.5);
+        assert_eq!(stats.settle_time, Some(0.1));
+
+        println!("A constant current settles immediately");
+        let constant: Vec<_> = (0..200).map(|i| sample(i * 5, 0.2)).collect();
+        let stats = analyze_inrush(&constant).unwrap();
+        assert_eq!(stats.settle_time, Some(0.0));
+
+        println!("A current that is still rising at the end did not settle");
+        let rising: Vec<_> = (0..200).map(|i| sample(i * 5, i as f32 * 0.05)).collect();
+        let stats = analyze_inrush(&rising).unwrap();
+        assert_eq!(stats.settle_time, None);
+    }
+
+    #[test]
+    fn off_mode() {
+        let mut off_modes = BTreeMap::new();
+        off_modes.insert("floating-dut".to_string(), OffMode::OffFloating);
+
+        println!("Profiles without an off mode discharge the output");
+        let req = resolve_off_mode(OutputRequest::OffDefault, Some("default"), &off_modes);
+        assert_eq!(req, OutputRequest::Off);
+        let req = resolve_off_mode(OutputRequest::OffDefault, None, &off_modes);
+        assert_eq!(req, OutputRequest::Off);
+
+        println!("Other profiles use the configured off mode");
+        let req = resolve_off_mode(OutputRequest::OffDefault, Some("floating-dut"), &off_modes);
+        assert_eq!(req, OutputRequest::OffFloating);
+
+        println!("Explicit requests are kept");
+        let req = resolve_off_mode(OutputRequest::Off, Some("floating-dut"), &off_modes);
+        assert_eq!(req, OutputRequest::Off);
+        let req = resolve_off_mode(OutputRequest::On, Some("floating-dut"), &off_modes);
+        assert_eq!(req, OutputRequest::On);
+    }
+
+    #[test]
+    fn command_queue() {
+        let (tx, rx) = CommandQueue::new();
+
+        println!("An empty queue does not block the receiver");
+        assert_eq!(rx.try_recv(), None);
+
+        println!("Requests are received in order");
+        assert!(tx.try_send(OutputRequest::On));
+        assert!(tx.try_send(OutputRequest::OffFloating));
+        assert_eq!(rx.try_recv(), Some(OutputRequest::On));
+        assert_eq!(rx.try_recv(), Some(OutputRequest::OffFloating));
+        assert_eq!(rx.try_recv(), None);
+
+        println!("A full queue refuses further requests");
+        for _ in 0..COMMAND_QUEUE_LEN {
+            assert!(tx.try_send(OutputRequest::Probe));
+        }
+        assert!(!tx.try_send(OutputRequest::Off));
+
+        println!("Space is freed when the receiver catches up");
+        assert_eq!(rx.try_recv(), Some(OutputRequest::Probe));
+        assert!(tx.try_send(OutputRequest::Off));
+
+        for _ in 1..COMMAND_QUEUE_LEN {
+            assert_eq!(rx.try_recv(), Some(OutputRequest::Probe));
+        }
+
+        assert_eq!(rx.try_recv(), Some(OutputRequest::Off));
+        assert_eq!(rx.try_recv(), None);
+    }
+
+    #[test]
+    fn state_channel() {
+        let state = StateChannel::new(OutputState::Off).unwrap();
+
+        println!("The initial state is signaled right away");
+        assert_eq!(state.wait().unwrap(), OutputState::Off);
+
+        println!("Changes in quick succession are picked up by a single read");
+        state.store(OutputState::On);
+        state.store(OutputState::Off);
+        assert_eq!(state.swap(OutputState::Probing), OutputState::Off);
+        assert_eq!(state.event.read().unwrap(), 3);
+        assert_eq!(state.load(), OutputState::Probing);
+
+        println!("Unchanged states are only signaled on request");
+        state.store(OutputState::Probing);
+        state.notify();
+        assert_eq!(state.event.read().unwrap(), 1);
+    }
 }
@@ -0,0 +1,201 @@
+// This file is part of tacd, the LXA TAC system daemon
+// Copyright (C) 2024 Pengutronix e.K.
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along
+// with this program; if not, write to the Free Software Foundation, Inc.,
+// 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+
+//! Meter the energy and charge delivered to the DUT
+//!
+//! The counters are reset every time the output is turned on and can be
+//! reset by writing to `/v1/dut/energy/reset`, e.g. to measure how long a
+//! battery powered DUT could run on a given battery.
+
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use async_std::sync::Arc;
+use async_std::task;
+use serde::{Deserialize, Serialize};
+
+use super::OutputState;
+use crate::adc::AdcChannel;
+use crate::broker::{BrokerBuilder, Topic};
+use crate::watched_tasks::WatchedTasksBuilder;
+
+/// How often the voltage and current are sampled for the integration
+const SAMPLE_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Longer gaps between two samples (e.g. because the ADC did not provide
+/// values) are not integrated over, as nothing is known about them.
+const MAX_GAP: Duration = Duration::from_secs(1);
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+pub struct EnergyCounters {
+    /// Energy (in Watt hours) delivered to the DUT
+    pub energy: f64,
+    /// Charge (in Ampere hours) delivered to the DUT
+    pub charge: f64,
+    /// Time (in seconds) the output was on
+    pub runtime: f64,
+}
+
+/// Integrate the voltage and current samples taken while the output is on
+///
+/// The counters use f64, as the increments are tiny compared to the
+/// totals after a couple of hours of runtime.
+struct EnergyMeter {
+    counters: EnergyCounters,
+    was_on: bool,
+    last_sample: Option<Instant>,
+}
+
+impl EnergyMeter {
+    fn new() -> Self {
+        Self {
+            counters: EnergyCounters::default(),
+            was_on: false,
+            last_sample: None,
+        }
+    }
+
+    fn reset(&mut self) {
+        self.counters = EnergyCounters::default();
+    }
+
+    /// Account for the time since the last sample using the values measured
+    /// at `ts`
+    fn step(&mut self, state: OutputState, volt: f32, curr: f32, ts: Instant) {
+        let is_on = state == OutputState::On;
+
+        if is_on && !self.was_on {
+            self.reset();
+        }
+
+        if is_on && self.was_on {
+            let dt = self
+                .last_sample
+                .map(|last| ts.saturating_duration_since(last))
+                .filter(|dt| *dt <= MAX_GAP);
+
+            if let Some(dt) = dt {
+                let hours = dt.as_secs_f64() / 3600.0;
+
+                self.counters.energy += f64::from(volt) * f64::from(curr) * hours;
+                self.counters.charge += f64::from(curr) * hours;
+                self.counters.runtime += dt.as_secs_f64();
+            }
+        }
+
+        self.was_on = is_on;
+        self.last_sample = Some(ts);
+    }
+}
+
+/// Publish the energy and charge delivered to the DUT since it was
+/// turned on
+pub(super) fn setup_energy(
+    bb: &mut BrokerBuilder,
+    wtb: &mut WatchedTasksBuilder,
+    pwr_volt: AdcChannel,
+    pwr_curr: AdcChannel,
+    state: Arc<Topic<OutputState>>,
+) -> Result<()> {
+    let counters = bb.topic_ro("/v1/dut/energy", Some(EnergyCounters::default()));
+    let reset = bb.topic_wo::<bool>("/v1/dut/energy/reset", None);
+
+    let (reset_requests, _) = reset.subscribe_unbounded();
+
+    wtb.spawn_task("power-energy", async move {
+        let mut meter = EnergyMeter::new();
+
+        loop {
+            task::sleep(SAMPLE_INTERVAL).await;
+
+            while reset_requests.try_recv().is_ok() {
+                meter.reset();
+            }
+
+            let state = state.try_get().unwrap_or(OutputState::Off);
+
+            if let Ok([volt, curr]) = pwr_volt
+                .fast
+                .try_get_multiple([&pwr_volt.fast, &pwr_curr.fast])
+            {
+                meter.step(state, volt.value, curr.value, volt.ts.as_instant());
+            }
+
+            counters.set_if_changed(meter.counters);
+        }
+    })?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{EnergyMeter, OutputState, MAX_GAP};
+
+    #[test]
+    fn energy_meter() {
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+
+        let mut meter = EnergyMeter::new();
+
+        println!("Nothing is counted while the output is off");
+        meter.step(OutputState::Off, 12.0, 1.0, at(0));
+        meter.step(OutputState::Off, 12.0, 1.0, at(100));
+        assert_eq!(meter.counters.energy, 0.0);
+        assert_eq!(meter.counters.runtime, 0.0);
+
+        println!("One hour at 12V and 0.5A are 6Wh and 0.5Ah");
+        for i in 1..=36000 {
+            meter.step(OutputState::On, 12.0, 0.5, at(100 + i * 100));
+        }
+
+        assert!((meter.counters.energy - 6.0).abs() < 0.01);
+        assert!((meter.counters.charge - 0.5).abs() < 0.001);
+        assert!((meter.counters.runtime - 3600.0).abs() < 0.2);
+
+        println!("The counters keep their values once the output is off");
+        let before = meter.counters;
+        meter.step(OutputState::Off, 0.0, 0.0, at(3_700_000));
+        meter.step(OutputState::Off, 12.0, 1.0, at(3_700_100));
+        assert_eq!(meter.counters, before);
+
+        println!("Gaps without samples are not integrated over");
+        meter.step(OutputState::On, 10.0, 1.0, at(3_800_000));
+        meter.step(OutputState::On, 10.0, 1.0, at(3_800_100));
+        let runtime = meter.counters.runtime;
+        assert!((runtime - 0.1).abs() < 0.001);
+        let gap = MAX_GAP.as_millis() as u64 + 1;
+        meter.step(OutputState::On, 10.0, 1.0, at(3_800_100 + gap));
+        assert_eq!(meter.counters.runtime, runtime);
+
+        println!("Turning the output on again starts from zero");
+        meter.step(OutputState::Off, 0.0, 0.0, at(3_900_000));
+        meter.step(OutputState::On, 10.0, 1.0, at(3_900_100));
+        assert_eq!(meter.counters.runtime, 0.0);
+
+        println!("Resetting clears the counters while the output is on");
+        meter.step(OutputState::On, 10.0, 1.0, at(3_900_200));
+        assert!(meter.counters.energy > 0.0);
+        meter.reset();
+        assert_eq!(meter.counters.energy, 0.0);
+        meter.step(OutputState::On, 10.0, 1.0, at(3_900_300));
+        assert!((meter.counters.runtime - 0.1).abs() < 0.001);
+    }
+}
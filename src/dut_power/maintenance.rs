@@ -0,0 +1,379 @@
+// This file is part of tacd, the LXA TAC system daemon
+// Copyright (C) 2024 Pengutronix e.K.
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along
+// with this program; if not, write to the Free Software Foundation, Inc.,
+// 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+
+//! Temporarily relax the output protections for hardware bring-up
+//!
+//! Some DUTs draw more than `MAX_CURRENT` for a moment (e.g. while charging
+//! large input capacitors). Instead of changing the constants, a maintenance
+//! override raises the current limit and extends the time the current is
+//! not checked after turning the output on for a limited time and then
+//! reverts on its own.
+//! The overvoltage and inverted polarity checks are never relaxed.
+//! Every override is written to an audit log and published via
+//! `/v1/dut/override`, so that nobody can miss that the output is not
+//! protected as usual.
+
+use std::fs::{create_dir_all, metadata, rename, OpenOptions};
+use std::io::Write;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Result};
+use async_std::future::timeout;
+use async_std::prelude::*;
+use async_std::sync::Arc;
+use chrono::Local;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use super::{check_limit, MAX_CURRENT, TURN_ON_ERROR_GRACE_PERIOD};
+use crate::broker::{BrokerBuilder, Topic};
+use crate::fs_root;
+use crate::measurement::Timestamp;
+use crate::watched_tasks::WatchedTasksBuilder;
+
+const OVERRIDE_PATH: &str = "/v1/dut/override";
+const AUDIT_LOG_PATH: &str = "/srv/tacd/maintenance-override.log";
+
+/// The audit log is moved to AUDIT_LOG_OLD_PATH (replacing the previous
+/// one) once it grows beyond this size, so that at most twice this amount
+/// of space is used.
+const AUDIT_LOG_MAX_SIZE: u64 = 64 * 1024;
+const AUDIT_LOG_OLD_PATH: &str = "/srv/tacd/maintenance-override.log.1";
+
+/// The output is turned off above this current, even with an override
+const OVERRIDE_MAX_CURRENT: f32 = 8.0;
+
+/// Overcurrent events are never ignored for longer than this after turning
+/// the output on
+const OVERRIDE_MAX_GRACE_PERIOD: Duration = Duration::from_secs(5);
+
+/// Overrides end after this time at the latest. Longer tasks require a new
+/// (and thus audited) override.
+const OVERRIDE_MAX_DURATION: Duration = Duration::from_secs(30 * 60);
+
+/// An override as requested via the API
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct OverrideRequest {
+    /// The current (in Ampere) above which the output is turned off
+    pub current_limit: f32,
+    /// How long (in milliseconds) overcurrent events are ignored after
+    /// turning the output on. The default grace period is kept if this is
+    /// not set.
+    #[serde(default)]
+    pub grace_period_ms: Option<u64>,
+    /// How long (in seconds) the override lasts
+    pub duration_s: u64,
+    /// Why the protections are relaxed
+    pub reason: String,
+}
+
+/// The override that is currently in effect
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct MaintenanceOverride {
+    pub current_limit: f32,
+    pub grace_period_ms: u64,
+    pub duration_s: u64,
+    pub reason: String,
+    /// When the override ends on its own
+    pub expires: Timestamp,
+}
+
+impl MaintenanceOverride {
+    fn describe(&self) -> String {
+        format!(
+            "current limit {}A, grace period {}ms, duration {}s, reason \"{}\"",
+            self.current_limit, self.grace_period_ms, self.duration_s, self.reason
+        )
+    }
+}
+
+/// Check an override request and turn it into an override starting at `now`
+fn check_request(req: &OverrideRequest, now: Instant) -> Result<MaintenanceOverride> {
+    let reason = req.reason.trim();
+
+    if reason.is_empty() {
+        bail!("A reason is required");
+    }
+
+    let current_limit = check_limit(req.current_limit, MAX_CURRENT, OVERRIDE_MAX_CURRENT)?;
+
+    // An override may only extend the grace period
+    let min_ms = TURN_ON_ERROR_GRACE_PERIOD.as_millis() as u64;
+    let max_ms = OVERRIDE_MAX_GRACE_PERIOD.as_millis() as u64;
+    let grace_period_ms = req.grace_period_ms.unwrap_or(min_ms);
+
+    if !(min_ms..=max_ms).contains(&grace_period_ms) {
+        bail!("The grace period must be between {min_ms}ms and {max_ms}ms");
+    }
+
+    let max_duration_s = OVERRIDE_MAX_DURATION.as_secs();
+
+    if !(1..=max_duration_s).contains(&req.duration_s) {
+        bail!("The duration must be between 1s and {max_duration_s}s");
+    }
+
+    Ok(MaintenanceOverride {
+        current_limit,
+        grace_period_ms,
+        duration_s: req.duration_s,
+        reason: reason.to_string(),
+        expires: Timestamp::new(now + Duration::from_secs(req.duration_s)),
+    })
+}
+
+/// The relaxed protections, shared with the power thread
+#[derive(Clone)]
+pub(super) struct Relaxation {
+    current_limit: Arc<AtomicU32>,
+    grace_period_ms: Arc<AtomicU32>,
+}
+
+impl Relaxation {
+    pub(super) fn new() -> Self {
+        let relaxation = Self {
+            current_limit: Arc::new(AtomicU32::new(0)),
+            grace_period_ms: Arc::new(AtomicU32::new(0)),
+        };
+
+        relaxation.apply(None);
+        relaxation
+    }
+
+    fn apply(&self, ov: Option<&MaintenanceOverride>) {
+        let (current_limit, grace_period_ms) = match ov {
+            Some(ov) => (ov.current_limit, ov.grace_period_ms as u32),
+            None => (0.0, TURN_ON_ERROR_GRACE_PERIOD.as_millis() as u32),
+        };
+
+        self.current_limit
+            .store(current_limit.to_bits(), Ordering::Relaxed);
+        self.grace_period_ms
+            .store(grace_period_ms, Ordering::Relaxed);
+    }
+
+    /// The current above which the output is turned off, given the user
+    /// configured `limit`
+    ///
+    /// An override can only ever raise the limit.
+    pub(super) fn max_current(&self, limit: f32) -> f32 {
+        let relaxed = f32::from_bits(self.current_limit.load(Ordering::Relaxed));
+
+        limit.max(relaxed)
+    }
+
+    /// How long overcurrent events are ignored after turning the output on
+    ///
+    /// Overvoltage and inverted polarity events always use
+    /// TURN_ON_ERROR_GRACE_PERIOD.
+    pub(super) fn current_grace_period(&self) -> Duration {
+        Duration::from_millis(self.grace_period_ms.load(Ordering::Relaxed) as u64)
+    }
+}
+
+/// Append a line to the log of all override requests
+fn audit(message: &str) {
+    let path = fs_root::path(AUDIT_LOG_PATH);
+    let line = format!("{} {message}\n", Local::now().to_rfc3339());
+
+    let full = metadata(&path).map_or(false, |m| m.len() >= AUDIT_LOG_MAX_SIZE);
+
+    if full {
+        if let Err(e) = rename(&path, fs_root::path(AUDIT_LOG_OLD_PATH)) {
+            warn!("Failed to rotate maintenance override audit log: {e}");
+        }
+    }
+
+    let res = path
+        .parent()
+        .map_or(Ok(()), create_dir_all)
+        .and_then(|_| OpenOptions::new().create(true).append(true).open(&path))
+        .and_then(|mut file| file.write_all(line.as_bytes()));
+
+    if let Err(e) = res {
+        warn!("Failed to write maintenance override audit log: {e}");
+    }
+}
+
+fn revert(relaxation: &Relaxation, status: &Arc<Topic<Option<MaintenanceOverride>>>, why: &str) {
+    relaxation.apply(None);
+    status.set(None);
+
+    info!("DUT power maintenance override {why}, protections are restored");
+    audit(&format!("reverted ({why})"));
+}
+
+/// Accept override requests via `/v1/dut/override` and revert them once
+/// they expire
+///
+/// Like the limits this uses a read-only and a write-only topic with the
+/// same name, so that only checked overrides are published.
+/// Overrides are not persistent and thus also end with a restart.
+pub(super) fn setup_maintenance_override(
+    bb: &mut BrokerBuilder,
+    wtb: &mut WatchedTasksBuilder,
+    relaxation: Relaxation,
+) -> Result<Arc<Topic<Option<MaintenanceOverride>>>> {
+    let status = bb.topic_ro::<Option<MaintenanceOverride>>(OVERRIDE_PATH, Some(None));
+    let requests = bb.topic_wo::<Option<OverrideRequest>>(OVERRIDE_PATH, None);
+
+    let (mut requests, _) = requests.subscribe_unbounded();
+    let status_task = status.clone();
+
+    wtb.spawn_task("power-maintenance-override", async move {
+        loop {
+            // Wait for the next request, but not past the end of the
+            // override that is currently in effect.
+            let req = match status_task.try_get().flatten() {
+                Some(ov) => {
+                    let remaining = ov.expires.saturating_duration_since(Instant::now());
+
+                    match timeout(remaining, requests.next()).await {
+                        Ok(req) => req,
+                        Err(_) => {
+                            revert(&relaxation, &status_task, "expired");
+                            continue;
+                        }
+                    }
+                }
+                None => requests.next().await,
+            };
+
+            let req = match req {
+                Some(req) => req,
+                None => break,
+            };
+
+            let req = match req {
+                Some(req) => req,
+                None => {
+                    if status_task.try_get().flatten().is_some() {
+                        revert(&relaxation, &status_task, "cancelled");
+                    }
+
+                    continue;
+                }
+            };
+
+            match check_request(&req, Instant::now()) {
+                Ok(ov) => {
+                    let description = ov.describe();
+
+                    warn!("DUT power maintenance override in effect: {description}");
+                    audit(&format!("activated: {description}"));
+
+                    relaxation.apply(Some(&ov));
+                    status_task.set(Some(ov));
+                }
+                Err(e) => {
+                    warn!("Refusing DUT power maintenance override {req:?}: {e}");
+                    audit(&format!("refused: {req:?}: {e}"));
+                }
+            }
+        }
+
+        Ok(())
+    })?;
+
+    Ok(status)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{
+        check_request, OverrideRequest, Relaxation, MAX_CURRENT, OVERRIDE_MAX_CURRENT,
+        OVERRIDE_MAX_DURATION, TURN_ON_ERROR_GRACE_PERIOD,
+    };
+
+    fn request(
+        current_limit: f32,
+        grace_period_ms: Option<u64>,
+        duration_s: u64,
+    ) -> OverrideRequest {
+        OverrideRequest {
+            current_limit,
+            grace_period_ms,
+            duration_s,
+            reason: "Inrush of the new carrier board".to_string(),
+        }
+    }
+
+    #[test]
+    fn request_validation() {
+        let now = Instant::now();
+
+        println!("Valid requests are accepted and expire after the duration");
+        let ov = check_request(&request(7.5, Some(2000), 600), now).unwrap();
+        assert_eq!(ov.current_limit, 7.5);
+        assert_eq!(ov.grace_period_ms, 2000);
+        assert_eq!(ov.expires.as_instant(), now + Duration::from_secs(600));
+
+        println!("The default grace period is kept if none is requested");
+        let ov = check_request(&request(6.0, None, 60), now).unwrap();
+        assert_eq!(
+            ov.grace_period_ms,
+            TURN_ON_ERROR_GRACE_PERIOD.as_millis() as u64
+        );
+
+        println!("Overrides can not relax the protections without bounds");
+        assert!(check_request(&request(OVERRIDE_MAX_CURRENT * 1.01, None, 60), now).is_err());
+        assert!(check_request(&request(MAX_CURRENT * 0.5, None, 60), now).is_err());
+        assert!(check_request(&request(f32::NAN, None, 60), now).is_err());
+        assert!(check_request(&request(6.0, Some(60_000), 60), now).is_err());
+        assert!(check_request(&request(6.0, Some(0), 60), now).is_err());
+        assert!(check_request(&request(6.0, None, 0), now).is_err());
+
+        let too_long = OVERRIDE_MAX_DURATION.as_secs() + 1;
+        assert!(check_request(&request(6.0, None, too_long), now).is_err());
+
+        println!("Overrides without a reason are refused");
+        let mut req = request(6.0, None, 60);
+        req.reason = "  ".to_string();
+        assert!(check_request(&req, now).is_err());
+    }
+
+    #[test]
+    fn relaxation() {
+        let relaxation = Relaxation::new();
+
+        println!("Without an override the usual protections apply");
+        assert_eq!(relaxation.max_current(1.5), 1.5);
+        assert_eq!(
+            relaxation.current_grace_period(),
+            TURN_ON_ERROR_GRACE_PERIOD
+        );
+
+        println!("An override raises the limits");
+        let ov = check_request(&request(7.5, Some(2000), 600), Instant::now()).unwrap();
+        relaxation.apply(Some(&ov));
+        assert_eq!(relaxation.max_current(1.5), 7.5);
+        assert_eq!(
+            relaxation.current_grace_period(),
+            Duration::from_millis(2000)
+        );
+
+        println!("Reverting restores them");
+        relaxation.apply(None);
+        assert_eq!(relaxation.max_current(MAX_CURRENT), MAX_CURRENT);
+        assert_eq!(
+            relaxation.current_grace_period(),
+            TURN_ON_ERROR_GRACE_PERIOD
+        );
+    }
+}
@@ -0,0 +1,410 @@
+// This file is part of tacd, the LXA TAC system daemon
+// Copyright (C) 2024 Pengutronix e.K.
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along
+// with this program; if not, write to the Free Software Foundation, Inc.,
+// 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+
+//! Share DUT power profiles between TACs
+//!
+//! A profile consists of its name and the off mode that is used for
+//! OffDefault requests while it is selected.
+//! Profiles are exported in the same way as USB port presets: as a
+//! self-contained JSON document that carries a schema version.
+//! The selected profile is part of the export and can optionally be
+//! selected on import as well.
+
+use std::collections::{BTreeMap, HashSet};
+use std::fs::{create_dir_all, OpenOptions};
+use std::io::Write;
+
+use anyhow::{bail, Result};
+use async_std::sync::Arc;
+use chrono::Local;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use tide::{http::mime, Request, Response, Server};
+
+use super::OffMode;
+use crate::broker::Topic;
+use crate::fs_root;
+use crate::http_server::WriteAccess;
+
+const AUDIT_LOG_PATH: &str = "/srv/tacd/dut-profile-imports.log";
+
+/// Version of the export format. Bump it on incompatible changes.
+const SCHEMA_VERSION: u32 = 1;
+
+const MAX_NAME_LEN: usize = 64;
+const MAX_PROFILES: usize = 64;
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct DutProfile {
+    pub name: String,
+    pub off_mode: OffMode,
+}
+
+/// A set of profiles as it is exported and imported
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct ProfileExport {
+    pub schema_version: u32,
+    /// The profile that was selected on the exporting TAC
+    pub selected: Option<String>,
+    pub profiles: Vec<DutProfile>,
+}
+
+/// What to do with imported profiles that have the same name as an
+/// existing one, but a different off mode
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+enum OnConflict {
+    /// Refuse the whole import
+    #[default]
+    Reject,
+    /// Overwrite the existing profile
+    Replace,
+}
+
+#[derive(Deserialize)]
+struct ExportParams {
+    /// Only export the profile with this name instead of all of them
+    name: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ImportParams {
+    #[serde(default)]
+    on_conflict: OnConflict,
+    /// Select the profile that was selected on the exporting TAC
+    #[serde(default)]
+    select: bool,
+}
+
+fn check_name(name: &str) -> Result<()> {
+    if name.trim().is_empty() || name.len() > MAX_NAME_LEN {
+        bail!("Profile names must be between 1 and {MAX_NAME_LEN} bytes long");
+    }
+
+    if name.chars().any(char::is_control) {
+        bail!(
+            "The name of profile \"{}\" is not printable",
+            name.escape_debug()
+        );
+    }
+
+    Ok(())
+}
+
+/// Check an imported document before anything is changed
+fn validate(export: &ProfileExport) -> Result<()> {
+    if export.schema_version != SCHEMA_VERSION {
+        bail!(
+            "Unsupported schema version {} (expected {SCHEMA_VERSION})",
+            export.schema_version
+        );
+    }
+
+    if export.profiles.len() > MAX_PROFILES {
+        bail!("Too many profiles (at most {MAX_PROFILES} are allowed)");
+    }
+
+    let mut names = HashSet::new();
+
+    for profile in &export.profiles {
+        check_name(&profile.name)?;
+
+        if !names.insert(profile.name.as_str()) {
+            bail!("Profile \"{}\" is contained more than once", profile.name);
+        }
+    }
+
+    if let Some(selected) = &export.selected {
+        check_name(selected)?;
+    }
+
+    Ok(())
+}
+
+/// The profiles to export, which are all profiles with a configured off
+/// mode and the selected profile
+fn export(
+    selected: Option<String>,
+    off_modes: &BTreeMap<String, OffMode>,
+    name: Option<&str>,
+) -> ProfileExport {
+    let mut profiles: Vec<DutProfile> = off_modes
+        .iter()
+        .map(|(name, off_mode)| DutProfile {
+            name: name.clone(),
+            off_mode: *off_mode,
+        })
+        .collect();
+
+    // Profiles without an entry discharge the output
+    if let Some(selected) = selected.as_ref().filter(|s| !off_modes.contains_key(*s)) {
+        profiles.push(DutProfile {
+            name: selected.clone(),
+            off_mode: OffMode::Off,
+        });
+    }
+
+    if let Some(name) = name {
+        profiles.retain(|p| p.name == name);
+    }
+
+    ProfileExport {
+        schema_version: SCHEMA_VERSION,
+        selected,
+        profiles,
+    }
+}
+
+/// Add the imported profiles to the existing ones
+///
+/// Returns the names of the imported profiles or, if conflicts are
+/// rejected, the names of the conflicting profiles as error.
+fn merge(
+    existing: &mut BTreeMap<String, OffMode>,
+    imported: Vec<DutProfile>,
+    on_conflict: OnConflict,
+) -> Result<Vec<String>, Vec<String>> {
+    let conflicts: Vec<String> = imported
+        .iter()
+        .filter(|i| existing.get(&i.name).map_or(false, |e| *e != i.off_mode))
+        .map(|i| i.name.clone())
+        .collect();
+
+    if on_conflict == OnConflict::Reject && !conflicts.is_empty() {
+        return Err(conflicts);
+    }
+
+    let names = imported.iter().map(|i| i.name.clone()).collect();
+
+    existing.extend(imported.into_iter().map(|i| (i.name, i.off_mode)));
+
+    Ok(names)
+}
+
+/// Append a line to the log of all imports
+fn audit(message: &str) {
+    let path = fs_root::path(AUDIT_LOG_PATH);
+    let line = format!("{} {message}\n", Local::now().to_rfc3339());
+
+    let res = path
+        .parent()
+        .map_or(Ok(()), create_dir_all)
+        .and_then(|_| OpenOptions::new().create(true).append(true).open(&path))
+        .and_then(|mut file| file.write_all(line.as_bytes()));
+
+    if let Err(e) = res {
+        warn!("Failed to write DUT profile import audit log: {e}");
+    }
+}
+
+fn plain(status: u16, msg: &str) -> Response {
+    Response::builder(status)
+        .body(msg)
+        .content_type(mime::PLAIN)
+        .build()
+}
+
+/// Add web endpoints to export and import profiles
+pub(super) fn serve(
+    server: &mut Server<()>,
+    profile: Arc<Topic<String>>,
+    off_modes: Arc<Topic<BTreeMap<String, OffMode>>>,
+) {
+    let profile_export = profile.clone();
+    let off_modes_export = off_modes.clone();
+
+    server
+        .at("/v1/dut/profiles/export")
+        .get(move |req: Request<()>| {
+            let profile = profile_export.clone();
+            let off_modes = off_modes_export.clone();
+
+            async move {
+                let params: ExportParams = req
+                    .query()
+                    .map_err(|_| tide::Error::from_str(400, "Malformed query parameters"))?;
+
+                let export = export(
+                    profile.try_get(),
+                    &off_modes.try_get().unwrap_or_default(),
+                    params.name.as_deref(),
+                );
+
+                if params.name.is_some() && export.profiles.is_empty() {
+                    return Ok(plain(404, "No such profile"));
+                }
+
+                let res = Response::builder(200)
+                    .body(tide::Body::from_json(&export)?)
+                    .header(
+                        "Content-Disposition",
+                        "attachment; filename=dut-profiles.json",
+                    )
+                    .build();
+
+                Ok(res)
+            }
+        });
+
+    server
+        .at("/v1/dut/profiles/import")
+        .put(move |mut req: Request<()>| {
+            let profile = profile.clone();
+            let off_modes = off_modes.clone();
+
+            async move {
+                if let Some(res) = WriteAccess::refuse(&req) {
+                    return Ok(res);
+                }
+
+                let params: ImportParams = req
+                    .query()
+                    .map_err(|_| tide::Error::from_str(400, "Malformed query parameters"))?;
+
+                let export: ProfileExport = match req.body_json().await {
+                    Ok(export) => export,
+                    Err(e) => return Ok(plain(400, &format!("Malformed profile export: {e}"))),
+                };
+
+                let peer = req.peer_addr().unwrap_or("an unknown address").to_string();
+
+                if let Err(e) = validate(&export) {
+                    audit(&format!("rejected import from {peer}: {e}"));
+                    return Ok(plain(422, &e.to_string()));
+                }
+
+                let mut merged = off_modes.try_get().unwrap_or_default();
+
+                match merge(&mut merged, export.profiles, params.on_conflict) {
+                    Ok(names) => {
+                        let selected = export.selected.filter(|_| params.select);
+
+                        audit(&format!(
+                            "imported from {peer} ({:?}): {}{}",
+                            params.on_conflict,
+                            names.join(", "),
+                            selected
+                                .as_ref()
+                                .map(|s| format!(", selected {s}"))
+                                .unwrap_or_default()
+                        ));
+
+                        off_modes.set(merged);
+
+                        if let Some(selected) = selected {
+                            profile.set(selected);
+                        }
+
+                        Ok(Response::builder(200)
+                            .body(tide::Body::from_json(&names)?)
+                            .build())
+                    }
+                    Err(conflicts) => {
+                        let msg = format!("Profiles already exist: {}", conflicts.join(", "));
+                        audit(&format!("rejected import from {peer}: {msg}"));
+                        Ok(plain(409, &msg))
+                    }
+                }
+            }
+        });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::{export, merge, validate, DutProfile, OnConflict, SCHEMA_VERSION};
+    use crate::dut_power::OffMode;
+
+    fn profile(name: &str, off_mode: OffMode) -> DutProfile {
+        DutProfile {
+            name: name.to_string(),
+            off_mode,
+        }
+    }
+
+    #[test]
+    fn exports() {
+        let off_modes = BTreeMap::from([("board-a".to_string(), OffMode::OffFloating)]);
+
+        println!("The selected profile is exported even without an off mode");
+        let all = export(Some("board-b".to_string()), &off_modes, None);
+        assert_eq!(all.schema_version, SCHEMA_VERSION);
+        assert_eq!(all.selected.as_deref(), Some("board-b"));
+        assert_eq!(
+            all.profiles,
+            vec![
+                profile("board-a", OffMode::OffFloating),
+                profile("board-b", OffMode::Off)
+            ]
+        );
+        assert!(validate(&all).is_ok());
+
+        println!("Single profiles can be exported");
+        let single = export(Some("board-b".to_string()), &off_modes, Some("board-a"));
+        assert_eq!(
+            single.profiles,
+            vec![profile("board-a", OffMode::OffFloating)]
+        );
+        assert!(export(None, &off_modes, Some("board-c"))
+            .profiles
+            .is_empty());
+
+        println!("Invalid documents are rejected");
+        let mut invalid = all.clone();
+        invalid.schema_version = SCHEMA_VERSION + 1;
+        assert!(validate(&invalid).is_err());
+        let mut invalid = all.clone();
+        invalid.profiles.push(profile("board-a", OffMode::Off));
+        assert!(validate(&invalid).is_err());
+        let mut invalid = all;
+        invalid.selected = Some("\n".to_string());
+        assert!(validate(&invalid).is_err());
+    }
+
+    #[test]
+    fn conflicts() {
+        let existing = BTreeMap::from([
+            ("board-a".to_string(), OffMode::Off),
+            ("board-b".to_string(), OffMode::Off),
+        ]);
+        let imported = vec![
+            profile("board-a", OffMode::OffFloating),
+            profile("board-b", OffMode::Off),
+            profile("board-c", OffMode::OffFloating),
+        ];
+
+        println!("Only profiles with a different off mode conflict");
+        let mut merged = existing.clone();
+        let res = merge(&mut merged, imported.clone(), OnConflict::Reject);
+        assert_eq!(res, Err(vec!["board-a".to_string()]));
+        assert_eq!(merged, existing);
+
+        println!("Existing profiles can be replaced");
+        let res = merge(&mut merged, imported, OnConflict::Replace);
+        assert_eq!(
+            res,
+            Ok(vec![
+                "board-a".to_string(),
+                "board-b".to_string(),
+                "board-c".to_string()
+            ])
+        );
+        assert_eq!(merged.len(), 3);
+        assert_eq!(merged["board-a"], OffMode::OffFloating);
+    }
+}
@@ -0,0 +1,276 @@
+// This file is part of tacd, the LXA TAC system daemon
+// Copyright (C) 2024 Pengutronix e.K.
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along
+// with this program; if not, write to the Free Software Foundation, Inc.,
+// 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+
+//! Run user defined power sequences like "on 2s, off 500ms, on"
+//!
+//! The steps of a sequence are submitted as requests to the regular
+//! `/v1/dut/powered` topic, so they are subject to the same fault handling
+//! as requests sent by the user.
+
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use async_std::future::timeout;
+use async_std::prelude::*;
+use async_std::sync::Arc;
+use async_std::task::sleep;
+use futures_lite::future::race;
+use futures_util::future::Either;
+use futures_util::FutureExt;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use super::{OutputRequest, OutputState};
+use crate::broker::{BrokerBuilder, Topic};
+use crate::watched_tasks::WatchedTasksBuilder;
+
+/// Longest sequence that is accepted
+const MAX_STEPS: usize = 64;
+
+/// Longest time a single step may hold the output in a state
+const MAX_STEP_DURATION: Duration = Duration::from_secs(60 * 60);
+
+/// How long to wait for the output to reach the requested state
+const TRANSITION_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct SequenceStep {
+    /// The state to put the output in. Only `On`, `Off` and `OffFloating`
+    /// are allowed.
+    pub request: OutputRequest,
+    /// Time in milliseconds to stay in this state before the next step
+    #[serde(default)]
+    pub duration: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub enum SequenceRequest {
+    /// Run a sequence. A sequence that is already running is cancelled.
+    Run(Vec<SequenceStep>),
+    /// Stop the running sequence and leave the output in its current state
+    Cancel,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub enum SequenceStatus {
+    Idle,
+    /// Executing the step with the (zero based) index `step`
+    Running {
+        step: usize,
+        steps: usize,
+    },
+    Done,
+    Cancelled,
+    Failed(String),
+}
+
+/// Check that a sequence can be run before touching the output
+fn validate(steps: &[SequenceStep]) -> Result<()> {
+    if steps.is_empty() {
+        bail!("The sequence is empty");
+    }
+
+    if steps.len() > MAX_STEPS {
+        bail!("Sequences can have at most {MAX_STEPS} steps");
+    }
+
+    for (idx, step) in steps.iter().enumerate() {
+        match step.request {
+            OutputRequest::On | OutputRequest::Off | OutputRequest::OffFloating => {}
+            req => bail!("Step {idx}: {req:?} can not be used in a sequence"),
+        }
+
+        if Duration::from_millis(step.duration) > MAX_STEP_DURATION {
+            bail!(
+                "Step {idx}: the duration must be at most {}s",
+                MAX_STEP_DURATION.as_secs()
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// The output state that is expected once a step was performed
+fn target_state(req: OutputRequest) -> OutputState {
+    match req {
+        OutputRequest::On => OutputState::On,
+        OutputRequest::OffFloating => OutputState::OffFloating,
+        _ => OutputState::Off,
+    }
+}
+
+async fn run_sequence(
+    steps: &[SequenceStep],
+    request: &Topic<OutputRequest>,
+    state: &Arc<Topic<OutputState>>,
+    status: &Topic<SequenceStatus>,
+) -> Result<()> {
+    for (idx, step) in steps.iter().enumerate() {
+        status.set(SequenceStatus::Running {
+            step: idx,
+            steps: steps.len(),
+        });
+
+        let target = target_state(step.request);
+
+        request.set(step.request);
+
+        if timeout(TRANSITION_TIMEOUT, state.wait_for(target))
+            .await
+            .is_err()
+        {
+            bail!(
+                "Step {idx}: the output did not turn {target:?} (it is {:?})",
+                state.try_get()
+            );
+        }
+
+        sleep(Duration::from_millis(step.duration)).await;
+
+        // The output may have been turned off due to e.g. an over current
+        // event or a request by the user in the meantime.
+        let curr = state.try_get();
+
+        if curr != Some(target) {
+            bail!("Step {idx}: the output left the {target:?} state (it is {curr:?})");
+        }
+    }
+
+    Ok(())
+}
+
+/// Allow running power sequences via the broker framework
+pub(super) fn setup_sequence(
+    bb: &mut BrokerBuilder,
+    wtb: &mut WatchedTasksBuilder,
+    request: Arc<Topic<OutputRequest>>,
+    state: Arc<Topic<OutputState>>,
+) -> Result<()> {
+    let sequence = bb.topic_wo::<SequenceRequest>("/v1/dut/powered/sequence", None);
+    let status = bb.topic_ro(
+        "/v1/dut/powered/sequence/status",
+        Some(SequenceStatus::Idle),
+    );
+
+    let (mut requests, _) = sequence.subscribe_unbounded();
+
+    wtb.spawn_task("power-sequence", async move {
+        // A request that arrived while a sequence was running
+        let mut pending = None;
+
+        loop {
+            let req = match pending.take() {
+                Some(req) => req,
+                None => match requests.next().await {
+                    Some(req) => req,
+                    None => break,
+                },
+            };
+
+            // Cancelling when there is nothing to cancel is a no-op
+            let steps = match req {
+                SequenceRequest::Run(steps) => steps,
+                SequenceRequest::Cancel => continue,
+            };
+
+            if let Err(e) = validate(&steps) {
+                warn!("Refusing power sequence: {e}");
+                status.set(SequenceStatus::Failed(e.to_string()));
+                continue;
+            }
+
+            info!("Running power sequence with {} steps", steps.len());
+
+            let ev = race(
+                run_sequence(&steps, &request, &state, &status).map(Either::Left),
+                requests.next().map(Either::Right),
+            )
+            .await;
+
+            match ev {
+                Either::Left(Ok(())) => status.set(SequenceStatus::Done),
+                Either::Left(Err(e)) => {
+                    warn!("Power sequence failed: {e}");
+                    status.set(SequenceStatus::Failed(e.to_string()));
+                }
+                Either::Right(Some(req)) => {
+                    info!("Power sequence cancelled");
+                    status.set(SequenceStatus::Cancelled);
+                    pending = Some(req);
+                }
+                Either::Right(None) => break,
+            }
+        }
+
+        Ok(())
+    })?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{validate, SequenceRequest, SequenceStep, MAX_STEPS};
+    use crate::dut_power::OutputRequest;
+
+    #[test]
+    fn sequence() {
+        println!("Parse a sequence like it would be sent via the API");
+        let req: SequenceRequest = serde_json::from_str(
+            r#"{"Run": [
+                {"request": "On", "duration": 2000},
+                {"request": "Off", "duration": 500},
+                {"request": "On"}
+            ]}"#,
+        )
+        .unwrap();
+
+        let steps = match req {
+            SequenceRequest::Run(steps) => steps,
+            SequenceRequest::Cancel => panic!("Expected a sequence"),
+        };
+
+        assert_eq!(steps.len(), 3);
+        assert_eq!(steps[2].duration, 0);
+        assert!(validate(&steps).is_ok());
+
+        println!("Empty and overly long sequences are refused");
+        assert!(validate(&[]).is_err());
+
+        let step = SequenceStep {
+            request: OutputRequest::On,
+            duration: 10,
+        };
+        assert!(validate(&vec![step.clone(); MAX_STEPS]).is_ok());
+        assert!(validate(&vec![step; MAX_STEPS + 1]).is_err());
+
+        println!("Probing can not be part of a sequence");
+        let steps = [SequenceStep {
+            request: OutputRequest::Probe,
+            duration: 0,
+        }];
+        assert!(validate(&steps).is_err());
+
+        println!("Neither can steps that take forever");
+        let steps = [SequenceStep {
+            request: OutputRequest::Off,
+            duration: u64::MAX,
+        }];
+        assert!(validate(&steps).is_err());
+    }
+}
@@ -0,0 +1,158 @@
+// This file is part of tacd, the LXA TAC system daemon
+// Copyright (C) 2024 Pengutronix e.K.
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along
+// with this program; if not, write to the Free Software Foundation, Inc.,
+// 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+
+use anyhow::Result;
+use async_std::prelude::*;
+use async_std::sync::Arc;
+use async_std::task::spawn_blocking;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::broker::{BrokerBuilder, Topic};
+use crate::watched_tasks::WatchedTasksBuilder;
+
+#[cfg(feature = "demo_mode")]
+mod nft {
+    use anyhow::Result;
+
+    pub(super) fn apply(ruleset: &str) -> Result<()> {
+        println!(
+            "Firewall: would apply a ruleset ({} lines) but don't feel like it",
+            ruleset.lines().count()
+        );
+
+        Ok(())
+    }
+}
+
+#[cfg(not(feature = "demo_mode"))]
+mod nft {
+    use std::io::Write;
+    use std::process::{Command, Stdio};
+
+    use anyhow::{bail, Context, Result};
+
+    /// Load an nftables ruleset by piping it into `nft`
+    ///
+    /// `nft -f` applies all commands in a file in a single transaction,
+    /// so either the complete profile is applied or nothing is changed.
+    /// The rulesets are expected to (re-)create the tables managed by
+    /// the tacd from scratch, so that no leftovers from the previous profile
+    /// remain.
+    pub(super) fn apply(ruleset: &str) -> Result<()> {
+        let mut child = Command::new("nft")
+            .args(["-f", "-"])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()?;
+
+        // Dropping stdin after writing closes it, so that nft sees the end
+        // of the ruleset.
+        child
+            .stdin
+            .take()
+            .context("Failed to open the stdin of nft")?
+            .write_all(ruleset.as_bytes())?;
+
+        let output = child.wait_with_output()?;
+
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            bail!("nft exited with {}: {}", output.status, stderr.trim());
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub enum FirewallProfile {
+    /// The DUT and uplink interface are bridged and traffic may flow freely
+    Bridged,
+    /// The DUT can only talk to the TAC itself, but not to the uplink network
+    Isolated,
+    /// Traffic from the DUT is NATed to the uplink network.
+    /// Connections from the uplink to the DUT are blocked.
+    /// Both IPv4 and IPv6 are NATed, regardless of the DUT's subnet.
+    NatOnly,
+}
+
+impl FirewallProfile {
+    /// The nftables ruleset of the profile
+    ///
+    /// The rulesets are built into the tacd, so that they always match the
+    /// profiles it offers.
+    fn ruleset(&self) -> &'static str {
+        match self {
+            Self::Bridged => include_str!("firewall/bridged.nft"),
+            Self::Isolated => include_str!("firewall/isolated.nft"),
+            Self::NatOnly => include_str!("firewall/nat-only.nft"),
+        }
+    }
+}
+
+pub struct Firewall {
+    pub profile: Arc<Topic<FirewallProfile>>,
+    pub error: Arc<Topic<Option<String>>>,
+}
+
+impl Firewall {
+    pub fn new(bb: &mut BrokerBuilder, wtb: &mut WatchedTasksBuilder) -> Result<Self> {
+        // Use the "register a read-only and a write-only topic with the same
+        // name" trick to only report a profile as active once it was actually
+        // applied.
+        // The requested profile is persisted, so that it is re-applied after
+        // a restart of the tacd.
+        let request = bb.topic(
+            "/v1/tac/network/firewall/profile",
+            false,
+            true,
+            true,
+            Some(FirewallProfile::Bridged),
+            1,
+        );
+        let profile = bb.topic_ro("/v1/tac/network/firewall/profile", None);
+        let error = bb.topic_ro("/v1/tac/network/firewall/error", Some(None));
+
+        let (mut requests, _) = request.subscribe_unbounded();
+        let profile_task = profile.clone();
+        let error_task = error.clone();
+
+        wtb.spawn_task("firewall-profile-update", async move {
+            while let Some(req) = requests.next().await {
+                match spawn_blocking(move || nft::apply(req.ruleset())).await {
+                    Ok(()) => {
+                        info!("Applied firewall profile {req:?}");
+                        profile_task.set(req);
+                        error_task.set_if_changed(None);
+                    }
+                    Err(e) => {
+                        // The previous ruleset is still in place, so the
+                        // profile topic keeps its previous value.
+                        warn!("Failed to apply firewall profile {req:?}: {e}");
+                        error_task.set(Some(format!("{req:?}: {e}")));
+                    }
+                }
+            }
+
+            Ok(())
+        })?;
+
+        Ok(Self { profile, error })
+    }
+}
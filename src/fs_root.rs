@@ -0,0 +1,186 @@
+// This file is part of tacd, the LXA TAC system daemon
+// Copyright (C) 2024 Pengutronix e.K.
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along
+// with this program; if not, write to the Free Software Foundation, Inc.,
+// 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+
+//! Relocate the files the tacd reads and writes
+//!
+//! Paths in the code are the ones used on the TAC (e.g. `/srv/tacd/state.json`)
+//! and are resolved using `path()`. They are looked up below `/` on the TAC
+//! and below `demo_files` in demo mode. `TACD_ROOT` overrides the root
+//! directory, e.g. to run the tacd in a CI container.
+//!
+//! If `TACD_EPHEMERAL_STATE` is set the files the tacd writes at runtime
+//! (everything below `STATE_DIRS`) are kept in a new temporary directory
+//! instead, so that every run starts out with the default state and does
+//! not modify the root directory.
+//! The temporary directory is not removed when the tacd exits.
+//!
+//! This also covers the sysfs files the tacd uses outside of demo mode
+//! (e.g. LEDs, hwmon, the USB hub ports and the IIO trigger), so that they
+//! can be faked below `TACD_ROOT`. The exception are the IIO devices
+//! themselves, which are accessed via libiio and always use `/sys`.
+
+use std::env::{temp_dir, var_os};
+use std::fs::create_dir_all;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use anyhow::Result;
+use log::info;
+
+#[cfg(feature = "demo_mode")]
+const DEFAULT_ROOT: &str = "demo_files";
+
+#[cfg(not(feature = "demo_mode"))]
+const DEFAULT_ROOT: &str = "/";
+
+const ROOT_ENV: &str = "TACD_ROOT";
+const EPHEMERAL_STATE_ENV: &str = "TACD_EPHEMERAL_STATE";
+
+/// Directories the tacd keeps its runtime state in
+const STATE_DIRS: &[&str] = &["/srv/tacd", "/var/run/tacd", "/run"];
+
+static ROOTS: OnceLock<Roots> = OnceLock::new();
+
+struct Roots {
+    root: PathBuf,
+    state: Option<PathBuf>,
+}
+
+impl Roots {
+    fn from_env() -> Self {
+        let root = var_os(ROOT_ENV)
+            .map(PathBuf::from)
+            .unwrap_or_else(|| PathBuf::from(DEFAULT_ROOT));
+
+        let state = var_os(EPHEMERAL_STATE_ENV)
+            .map(|_| temp_dir().join(format!("tacd-state-{}", std::process::id())));
+
+        Self { root, state }
+    }
+
+    fn resolve(&self, on_tac: impl AsRef<Path>) -> PathBuf {
+        let on_tac = on_tac.as_ref();
+        let relative = on_tac.strip_prefix("/").unwrap_or(on_tac);
+
+        match &self.state {
+            Some(state) if STATE_DIRS.iter().any(|dir| on_tac.starts_with(dir)) => {
+                state.join(relative)
+            }
+            _ => self.root.join(relative),
+        }
+    }
+}
+
+fn roots() -> &'static Roots {
+    ROOTS.get_or_init(Roots::from_env)
+}
+
+/// Get the path to use for the file that is at `on_tac` on the TAC
+pub fn path(on_tac: impl AsRef<Path>) -> PathBuf {
+    roots().resolve(on_tac)
+}
+
+/// Open the sysfs class device `id` (e.g. the LED "rgb:status")
+///
+/// This is `SysClass::new()` with the class directory resolved using
+/// `path()`.
+#[cfg(not(feature = "demo_mode"))]
+pub fn sys_class<C: sysfs_class::SysClass>(id: &str) -> std::io::Result<C> {
+    let dev_path = path(C::dir().join(id));
+
+    if !dev_path.is_dir() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            format!("{} not found", dev_path.display()),
+        ));
+    }
+
+    // from_path_unchecked() only skips the check that the path is inside
+    // of the class directory below /sys, which does not hold for a
+    // relocated root.
+    Ok(unsafe { C::from_path_unchecked(dev_path) })
+}
+
+/// Log the directories in use and create the ephemeral state directory
+/// (if requested)
+///
+/// This should happen before anything else accesses the filesystem.
+pub fn init() -> Result<()> {
+    let roots = roots();
+
+    info!("Using {} as filesystem root", roots.root.display());
+
+    if let Some(state) = &roots.state {
+        info!("Keeping ephemeral state in {}", state.display());
+
+        for dir in STATE_DIRS {
+            create_dir_all(roots.resolve(dir))?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use super::Roots;
+
+    #[test]
+    fn resolve() {
+        let roots = Roots {
+            root: PathBuf::from("demo_files"),
+            state: None,
+        };
+
+        println!("Paths are relative to the root");
+        assert_eq!(
+            roots.resolve("/etc/tacd/backends.yaml"),
+            Path::new("demo_files/etc/tacd/backends.yaml")
+        );
+        assert_eq!(
+            roots.resolve("/srv/tacd/state.json"),
+            Path::new("demo_files/srv/tacd/state.json")
+        );
+
+        let roots = Roots {
+            root: PathBuf::from("/"),
+            state: Some(PathBuf::from("/tmp/tacd-state-1")),
+        };
+
+        println!("Only the state is kept in the ephemeral state directory");
+        assert_eq!(
+            roots.resolve("/etc/tacd/backends.yaml"),
+            Path::new("/etc/tacd/backends.yaml")
+        );
+        assert_eq!(
+            roots.resolve("/srv/tacd/state.json"),
+            Path::new("/tmp/tacd-state-1/srv/tacd/state.json")
+        );
+        assert_eq!(
+            roots.resolve("/run/lock"),
+            Path::new("/tmp/tacd-state-1/run/lock")
+        );
+
+        println!("Directories are matched as a whole");
+        assert_eq!(
+            roots.resolve("/srv/tacd-backup"),
+            Path::new("/srv/tacd-backup")
+        );
+    }
+}
@@ -0,0 +1,256 @@
+// This file is part of tacd, the LXA TAC system daemon
+// Copyright (C) 2024 Pengutronix e.K.
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along
+// with this program; if not, write to the Free Software Foundation, Inc.,
+// 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+
+//! Expose spare GPIO lines for use by custom hardware
+//!
+//! Only lines that are explicitly listed in the config file can be accessed,
+//! so that e.g. the DUT power switch can not be toggled this way.
+//! The config file maps the names used in the API to the names of the
+//! GPIO lines:
+//!
+//! ```yaml
+//! trigger: SPARE_GPIO_0
+//! ```
+//!
+//! Each line is available as `/v1/tac/gpio/<name>` and can be configured
+//! as input or output via `/v1/tac/gpio/<name>/direction`.
+
+use std::collections::BTreeMap;
+use std::fs::read_to_string;
+use std::io::ErrorKind;
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use async_std::channel::Receiver;
+use async_std::sync::Arc;
+use async_std::task::sleep;
+use futures::{select, FutureExt, StreamExt};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::broker::{BrokerBuilder, Topic};
+use crate::digital_io::{find_line, GpioHealth, LineRequestFlags};
+use crate::fs_root;
+use crate::watched_tasks::WatchedTasksBuilder;
+
+const CONFIG_PATH: &str = "/etc/tacd/gpio_user.yaml";
+
+/// How often the level of input lines is checked
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum GpioDirection {
+    /// Do not drive the line and publish its level
+    #[default]
+    Input,
+    /// Drive the line to the level written to it
+    Output,
+}
+
+/// The names must be usable as part of a topic path
+fn valid_name(name: &str) -> bool {
+    let allowed = |c: char| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_' || c == '-';
+
+    !name.is_empty() && name.chars().all(allowed)
+}
+
+/// Parse the allow-list of GPIO lines (name in the API -> GPIO line name)
+fn parse(content: &str) -> Result<BTreeMap<String, String>> {
+    // A file that only contains comments is an empty document (None)
+    let lines: Option<BTreeMap<String, String>> = serde_yaml::from_str(content)?;
+    let lines = lines.unwrap_or_default();
+
+    for name in lines.keys() {
+        if !valid_name(name) {
+            bail!("\"{name}\" is not a valid name. Use a-z, 0-9, _ and - only");
+        }
+    }
+
+    Ok(lines)
+}
+
+fn load() -> BTreeMap<String, String> {
+    let path = fs_root::path(CONFIG_PATH);
+
+    match read_to_string(&path) {
+        Ok(content) => parse(&content).unwrap_or_else(|e| {
+            warn!(
+                "Failed to parse {}, not exposing any GPIOs: {e}",
+                path.display()
+            );
+            BTreeMap::new()
+        }),
+        Err(e) if e.kind() == ErrorKind::NotFound => BTreeMap::new(),
+        Err(e) => {
+            warn!(
+                "Failed to read {}, not exposing any GPIOs: {e}",
+                path.display()
+            );
+            BTreeMap::new()
+        }
+    }
+}
+
+/// Handle the requests for a single line
+///
+/// The line is requested again every time the direction changes.
+async fn serve_line(
+    gpio_health: GpioHealth,
+    name: String,
+    line_name: String,
+    direction: Arc<Topic<GpioDirection>>,
+    mut requests: Receiver<bool>,
+    value: Arc<Topic<bool>>,
+) -> Result<()> {
+    let (mut direction_events, _) = direction.subscribe_unbounded();
+
+    let mut dir = match direction_events.next().await {
+        Some(dir) => dir,
+        None => return Ok(()),
+    };
+
+    loop {
+        let flags = match dir {
+            GpioDirection::Input => LineRequestFlags::INPUT,
+            GpioDirection::Output => LineRequestFlags::OUTPUT,
+        };
+
+        // Outputs always start out low after changing the direction
+        let handle = gpio_health.request(&line_name, flags, 0, None).await?;
+
+        if dir == GpioDirection::Output {
+            value.set(false);
+        }
+
+        let next = loop {
+            select! {
+                ev = direction_events.next().fuse() => match ev {
+                    Some(new) if new != dir => break Some(new),
+                    Some(_) => {}
+                    None => break None,
+                },
+                req = requests.next().fuse() => match (req, dir) {
+                    (Some(level), GpioDirection::Output) => {
+                        handle.set_value(level as _)?;
+                        value.set(level);
+                    }
+                    (Some(_), GpioDirection::Input) => {
+                        warn!("Refusing to set GPIO {name}, as it is configured as input");
+                    }
+                    (None, _) => break None,
+                },
+                _ = sleep(POLL_INTERVAL).fuse() => {
+                    if dir == GpioDirection::Input {
+                        value.set_if_changed(handle.get_value()? != 0);
+                    }
+                },
+            }
+        };
+
+        match next {
+            Some(new) => dir = new,
+            None => break,
+        }
+    }
+
+    Ok(())
+}
+
+/// Expose the GPIO lines listed in the config file via the broker framework
+pub fn run(
+    bb: &mut BrokerBuilder,
+    wtb: &mut WatchedTasksBuilder,
+    gpio_health: &GpioHealth,
+) -> Result<()> {
+    // Requesting a line that does not exist fails, which would end the tacd,
+    // so these are left out.
+    let lines: BTreeMap<String, String> = load()
+        .into_iter()
+        .filter(|(name, line_name)| {
+            let exists = find_line(line_name).is_some();
+
+            if !exists {
+                warn!("Not exposing GPIO {name}, as line {line_name} does not exist");
+            }
+
+            exists
+        })
+        .collect();
+
+    if !lines.is_empty() {
+        info!("Exposing user GPIOs: {lines:?}");
+    }
+
+    bb.topic_ro("/v1/tac/gpio", Some(lines.clone()));
+
+    for (name, line_name) in lines {
+        let path = format!("/v1/tac/gpio/{name}");
+
+        let direction = bb.topic(
+            &format!("{path}/direction"),
+            true,
+            true,
+            true,
+            Some(GpioDirection::Input),
+            1,
+        );
+
+        // Use the same path for the requests and the actual value, so that
+        // a write to an output is only echoed once it was performed.
+        let request = bb.topic_wo::<bool>(&path, None);
+        let value = bb.topic_ro::<bool>(&path, None);
+
+        let (requests, _) = request.subscribe_unbounded();
+
+        wtb.spawn_task(
+            format!("gpio-user-{name}"),
+            serve_line(
+                gpio_health.clone(),
+                name,
+                line_name,
+                direction,
+                requests,
+                value,
+            ),
+        )?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse;
+
+    #[test]
+    fn config() {
+        println!("An empty config does not expose any lines");
+        assert!(parse("{}").unwrap().is_empty());
+        assert!(parse("# Nothing\n").unwrap().is_empty());
+
+        println!("Lines are mapped from their API name to their line name");
+        let lines = parse("trigger: SPARE_GPIO_0\nreset-1: SPARE_GPIO_1\n").unwrap();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines["trigger"], "SPARE_GPIO_0");
+        assert_eq!(lines["reset-1"], "SPARE_GPIO_1");
+
+        println!("Names that do not fit into a topic path are refused");
+        assert!(parse("Trigger: SPARE_GPIO_0\n").is_err());
+        assert!(parse("a/b: SPARE_GPIO_0\n").is_err());
+        assert!(parse("\"\": SPARE_GPIO_0\n").is_err());
+    }
+}
@@ -0,0 +1,379 @@
+// This file is part of tacd, the LXA TAC system daemon
+// Copyright (C) 2024 Pengutronix e.K.
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along
+// with this program; if not, write to the Free Software Foundation, Inc.,
+// 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+
+//! Keep the recent history of measurements in memory
+//!
+//! The broker only retains the last couple of samples of each channel.
+//! This keeps the last few minutes, so that e.g. the charts in the web
+//! interface do not start out empty after a page reload.
+//!
+//! The history of a channel is available via
+//! `GET /v1/tac/history/<topic path of the channel>?since=<time_ms>`,
+//! where the optional `time_ms` uses the same format as the `ts` field of
+//! measurements (milliseconds since the Unix Epoch).
+//!
+//! The annotations of the current DUT profile are available the same way
+//! via `GET /v1/tac/history/v1/annotations?since=<time_ms>`.
+//! Those of another profile can be selected using `&profile=<name>`.
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use anyhow::Result;
+use async_std::sync::Arc;
+use futures::stream::select_all;
+use futures::{select, FutureExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use tide::{http::mime, Request, Response, Server};
+
+use crate::adc::Adc;
+use crate::annotations::{Annotation, Annotations};
+use crate::broker::{AnyTopic, BrokerBuilder, Topic};
+use crate::measurement::Measurement;
+use crate::watched_tasks::WatchedTasksBuilder;
+
+const HISTORY_ROUTE: &str = "/v1/tac/history/*channel";
+
+/// Upper limit for the retention time to bound the memory usage.
+/// The ADC channels are sampled ten times per second, so this amounts to
+/// 18000 samples per channel.
+const MAX_MINUTES: u32 = 30;
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct HistoryConfig {
+    /// Minutes of samples to keep for channels that are not listed in
+    /// `channels`
+    pub default_minutes: u32,
+    /// Minutes of samples to keep per topic path of a channel.
+    /// Zero disables the history of a channel.
+    pub channels: BTreeMap<String, u32>,
+}
+
+impl Default for HistoryConfig {
+    fn default() -> Self {
+        Self {
+            default_minutes: 10,
+            channels: BTreeMap::new(),
+        }
+    }
+}
+
+impl HistoryConfig {
+    fn retention(&self, channel: &str) -> Duration {
+        let minutes = self
+            .channels
+            .get(channel)
+            .copied()
+            .unwrap_or(self.default_minutes)
+            .min(MAX_MINUTES);
+
+        Duration::from_secs(u64::from(minutes) * 60)
+    }
+}
+
+struct ChannelHistory {
+    retention: Duration,
+    samples: VecDeque<Measurement>,
+}
+
+impl ChannelHistory {
+    fn new(retention: Duration) -> Self {
+        Self {
+            retention,
+            samples: VecDeque::new(),
+        }
+    }
+
+    /// Drop samples that are older than the retention time relative to
+    /// the newest sample
+    fn expire(&mut self) {
+        let newest = match self.samples.back() {
+            Some(m) => m.ts.as_instant(),
+            None => return,
+        };
+
+        while let Some(oldest) = self.samples.front() {
+            if newest.saturating_duration_since(oldest.ts.as_instant()) < self.retention {
+                break;
+            }
+
+            self.samples.pop_front();
+        }
+    }
+
+    fn set_retention(&mut self, retention: Duration) {
+        self.retention = retention;
+        self.expire();
+    }
+
+    fn push(&mut self, measurement: Measurement) {
+        self.samples.push_back(measurement);
+        self.expire();
+    }
+
+    /// All samples that were taken after `since` (or all if it is None)
+    fn since(&self, since: Option<SystemTime>) -> Vec<Measurement> {
+        self.samples
+            .iter()
+            .filter(|m| since.map_or(true, |since| m.ts.in_system_time() > since))
+            .copied()
+            .collect()
+    }
+}
+
+type Histories = Arc<Mutex<HashMap<String, ChannelHistory>>>;
+
+#[derive(Deserialize)]
+struct HistoryQuery {
+    /// Milliseconds since the Unix Epoch
+    since: Option<f64>,
+    /// The DUT profile to get the annotations of
+    profile: Option<String>,
+}
+
+/// The annotations of `profile` that were made after `since_ms`
+/// (or all of them if it is None)
+fn annotations_since(list: &[Annotation], profile: &str, since_ms: Option<f64>) -> Vec<Annotation> {
+    list.iter()
+        .filter(|a| a.profile == profile)
+        .filter(|a| since_ms.map_or(true, |since_ms| a.ts > since_ms))
+        .cloned()
+        .collect()
+}
+
+fn plain(status: u16, msg: &str) -> Response {
+    Response::builder(status)
+        .body(msg)
+        .content_type(mime::PLAIN)
+        .build()
+}
+
+fn serve(server: &mut Server<()>, histories: Histories, annotations: Annotations) {
+    server.at(HISTORY_ROUTE).get(move |req: Request<()>| {
+        let histories = histories.clone();
+        let annotations = annotations.clone();
+
+        async move {
+            let channel = format!("/{}", req.param("channel").unwrap_or_default());
+
+            let (since_ms, profile) = match req.query::<HistoryQuery>() {
+                Ok(HistoryQuery { since, profile })
+                    if since.map_or(true, |ms| ms.is_finite() && ms >= 0.0) =>
+                {
+                    (since, profile)
+                }
+                _ => return Ok(plain(400, "Invalid since parameter")),
+            };
+
+            let body = if channel == "/v1/annotations" {
+                let list = annotations.list.try_get().unwrap_or_default();
+                let profile = profile.unwrap_or_else(|| annotations.current_profile());
+
+                serde_json::to_vec(&annotations_since(&list, &profile, since_ms))?
+            } else {
+                let since = since_ms
+                    .map(|ms| SystemTime::UNIX_EPOCH + Duration::from_secs_f64(ms / 1000.0));
+
+                let samples = match histories.lock().unwrap().get(&channel) {
+                    Some(history) => history.since(since),
+                    None => return Ok(plain(404, "No such channel")),
+                };
+
+                serde_json::to_vec(&samples)?
+            };
+
+            let res = Response::builder(200)
+                .body(body)
+                .content_type(mime::JSON)
+                .build();
+
+            Ok(res)
+        }
+    });
+}
+
+/// Keep the recent history of all measurement channels and serve it via HTTP
+pub fn run(
+    bb: &mut BrokerBuilder,
+    wtb: &mut WatchedTasksBuilder,
+    server: &mut Server<()>,
+    adc: &Adc,
+    annotations: Annotations,
+) -> Result<()> {
+    let config: Arc<Topic<HistoryConfig>> = bb.topic(
+        "/v1/tac/history/config",
+        true,
+        true,
+        true,
+        Some(HistoryConfig::default()),
+        1,
+    );
+
+    let histories: Histories = Arc::new(Mutex::new(HashMap::new()));
+
+    serve(server, histories.clone(), annotations);
+
+    let mut streams = Vec::new();
+
+    {
+        let config = config.try_get().unwrap_or_default();
+        let mut histories = histories.lock().unwrap();
+
+        for topic in adc.measurement_topics() {
+            let path: &str = topic.path();
+            let path: Arc<str> = path.into();
+            let (stream, _) = topic.clone().subscribe_unbounded();
+
+            histories.insert(
+                path.to_string(),
+                ChannelHistory::new(config.retention(&path)),
+            );
+            streams.push(stream.map(move |m| (path.clone(), m)));
+        }
+    }
+
+    let (mut config_stream, _) = config.subscribe_unbounded();
+
+    wtb.spawn_task("history", async move {
+        let mut samples = select_all(streams);
+
+        loop {
+            select! {
+                sample = samples.next().fuse() => {
+                    let (path, measurement) = match sample {
+                        Some(sample) => sample,
+                        None => break,
+                    };
+
+                    if let Some(history) = histories.lock().unwrap().get_mut(&*path) {
+                        history.push(measurement);
+                    }
+                },
+                config = config_stream.next().fuse() => {
+                    let config = match config {
+                        Some(config) => config,
+                        None => break,
+                    };
+
+                    for (path, history) in histories.lock().unwrap().iter_mut() {
+                        history.set_retention(config.retention(path));
+                    }
+                },
+            }
+        }
+
+        Ok(())
+    })?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::time::{Duration, Instant, SystemTime};
+
+    use super::{annotations_since, ChannelHistory, HistoryConfig, MAX_MINUTES};
+    use crate::annotations::Annotation;
+    use crate::measurement::{Measurement, Timestamp};
+
+    #[test]
+    fn history() {
+        let start = Instant::now();
+        let at = |secs, value| Measurement {
+            ts: Timestamp::new(start + Duration::from_secs(secs)),
+            value,
+        };
+
+        println!("Samples older than the retention time are dropped");
+        let mut history = ChannelHistory::new(Duration::from_secs(60));
+
+        for i in 0..100 {
+            history.push(at(i, i as f32));
+        }
+
+        let samples = history.since(None);
+        assert_eq!(samples.len(), 60);
+        assert_eq!(samples[0].value, 40.0);
+        assert_eq!(samples[59].value, 99.0);
+
+        println!("Only newer samples are returned when asked to");
+        let since = samples[49].ts.in_system_time();
+        let newer = history.since(Some(since));
+        assert_eq!(newer.len(), 10);
+        assert_eq!(newer[0].value, 90.0);
+
+        assert!(history.since(Some(SystemTime::now())).is_empty());
+
+        println!("Shortening the retention drops samples right away");
+        history.set_retention(Duration::from_secs(10));
+        assert_eq!(history.since(None).len(), 10);
+
+        history.set_retention(Duration::ZERO);
+        assert!(history.since(None).is_empty());
+    }
+
+    #[test]
+    fn retention() {
+        let config = HistoryConfig {
+            default_minutes: 5,
+            channels: BTreeMap::from([
+                ("/v1/dut/feedback/voltage".to_string(), 0),
+                ("/v1/dut/feedback/current".to_string(), 24 * 60),
+            ]),
+        };
+
+        assert_eq!(
+            config.retention("/v1/iobus/feedback/voltage"),
+            Duration::from_secs(5 * 60)
+        );
+        assert_eq!(config.retention("/v1/dut/feedback/voltage"), Duration::ZERO);
+        assert_eq!(
+            config.retention("/v1/dut/feedback/current"),
+            Duration::from_secs(u64::from(MAX_MINUTES) * 60)
+        );
+    }
+
+    #[test]
+    fn annotations() {
+        let annotation = |ts, profile: &str| Annotation {
+            ts,
+            label: "boot".to_string(),
+            profile: profile.to_string(),
+        };
+
+        let list = vec![
+            annotation(1.0, "a"),
+            annotation(2.0, "b"),
+            annotation(3.0, "a"),
+        ];
+
+        println!("Only the annotations of the selected profile are returned");
+        assert_eq!(
+            annotations_since(&list, "a", None),
+            vec![list[0].clone(), list[2].clone()]
+        );
+        assert_eq!(annotations_since(&list, "c", None), vec![]);
+
+        println!("The since parameter is applied as for samples");
+        assert_eq!(
+            annotations_since(&list, "a", Some(1.0)),
+            vec![list[2].clone()]
+        );
+    }
+}
@@ -17,36 +17,93 @@
 
 use std::fs::write;
 use std::net::TcpListener;
+use std::path::{Path, PathBuf};
 
 use anyhow::Result;
-use tide::{Body, Response, Server};
+use async_std::sync::Arc;
+use tide::{Body, Request, Response, Server};
 
+use crate::broker::{BrokerBuilder, Topic};
+use crate::fs_root;
 use crate::watched_tasks::WatchedTasksBuilder;
 
+mod access_log;
+mod auth;
+mod integrity;
 mod serve_dir;
+mod upload;
+pub mod websocket;
+pub use auth::{auth_enabled, WriteAccess};
+pub use integrity::Integrity;
 use serve_dir::serve_dir;
 
 #[cfg(feature = "demo_mode")]
 mod consts {
     pub const WEBUI_DIR: &str = "web/build";
-    pub const LICENSE_DIR: &str = "demo_files/usr/share/licenses";
-    pub const LICENSE_MANIFEST: &str = "demo_files/usr/share/common-licenses/license.manifest";
-    pub const EXTRA_DIR: &str = "demo_files/srv/www";
-    pub const FS_PREFIX: &str = "demo_files";
     pub const FALLBACK_PORT: &str = "[::]:8080";
 }
 
 #[cfg(not(feature = "demo_mode"))]
 mod consts {
     pub const WEBUI_DIR: &str = "/usr/share/tacd/webui";
-    pub const LICENSE_DIR: &str = "/usr/share/licenses";
-    pub const LICENSE_MANIFEST: &str = "/usr/share/common-licenses/license.manifest";
-    pub const EXTRA_DIR: &str = "/srv/www";
-    pub const FS_PREFIX: &str = "";
     pub const FALLBACK_PORT: &str = "[::]:80";
 }
 
-use consts::{EXTRA_DIR, FALLBACK_PORT, FS_PREFIX, LICENSE_DIR, LICENSE_MANIFEST, WEBUI_DIR};
+#[cfg(any(test, feature = "demo_mode"))]
+mod sd {
+    use std::net::TcpListener;
+
+    pub(super) fn activated_listeners() -> Vec<TcpListener> {
+        Vec::new()
+    }
+}
+
+#[cfg(not(any(test, feature = "demo_mode")))]
+mod sd {
+    use std::net::TcpListener;
+
+    use log::warn;
+    use systemd::daemon::{listen_fds, tcp_listener};
+
+    /// Get the listening sockets passed to us by systemd (if any)
+    ///
+    /// This only keeps the listening socket open across restarts.
+    /// Established connections (like websockets and journal SSE streams)
+    /// are not handed over to the new instance and end with the old one,
+    /// clients have to reconnect.
+    ///
+    /// Draining established connections before exiting is not implemented
+    /// and is left for a separate change. It needs a shutdown signal and an
+    /// accept loop that tracks open connections, as tide::Server::listen()
+    /// provides neither.
+    pub(super) fn activated_listeners() -> Vec<TcpListener> {
+        let fds = match listen_fds(true) {
+            Ok(fds) => fds,
+            Err(e) => {
+                warn!("Failed to get sockets from systemd: {e}");
+                return Vec::new();
+            }
+        };
+
+        fds.iter()
+            .filter_map(|fd| match tcp_listener(fd) {
+                Ok(listener) => Some(listener),
+                Err(e) => {
+                    warn!("Ignoring socket passed by systemd: {e}");
+                    None
+                }
+            })
+            .collect()
+    }
+}
+
+use sd::activated_listeners;
+
+use consts::{FALLBACK_PORT, WEBUI_DIR};
+
+const LICENSE_DIR: &str = "/usr/share/licenses";
+const LICENSE_MANIFEST: &str = "/usr/share/common-licenses/license.manifest";
+const EXTRA_DIR: &str = "/srv/www";
 
 // openapi.json is generated by build.rs from openapi.yaml
 const OPENAPI_JSON: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/openapi.json"));
@@ -64,39 +121,61 @@ const EXPOSED_FILES_RW: &[(&str, &str)] = &[
 pub struct HttpServer {
     listeners: Vec<TcpListener>,
     pub server: Server<()>,
+    pub webui_integrity: Arc<Topic<Integrity>>,
 }
 
 impl HttpServer {
-    pub fn new() -> Self {
+    pub fn new(bb: &mut BrokerBuilder, wtb: &mut WatchedTasksBuilder) -> Result<Self> {
+        // Detect damaged web interface files (e.g. due to bit errors in the
+        // rootfs) in the background.
+        let webui_integrity = integrity::run(bb, wtb, WEBUI_DIR)?;
+
         let mut this = Self {
             listeners: Vec::new(),
             server: tide::new(),
+            webui_integrity,
         };
 
-        // Open [::]:80 / [::]:8080. This, somewhat confusingly also listens on
+        // Use the sockets provided by systemd (if any) to make socket
+        // activation work.
+        // The listening socket is then owned by systemd and stays open
+        // while the tacd is restarted, so that new connections are queued
+        // instead of being refused until the new instance is up.
+        // There is no graceful handover of established connections
+        // (see sd::activated_listeners()).
+        this.listeners.extend(activated_listeners());
+
+        // Open [::]:80 / [::]:8080 if systemd did not provide us with
+        // sockets to listen on. This, somewhat confusingly also listens on
         // 0.0.0.0 and not only on IPv6.
-        this.listeners.push(
-            TcpListener::bind(FALLBACK_PORT).expect(
+        if this.listeners.is_empty() {
+            this.listeners.push(TcpListener::bind(FALLBACK_PORT).expect(
                 "Could not bind web API to port, is there already another service running?",
-            ),
-        );
+            ));
+        }
+
+        // Record recent requests (if enabled) to help with debugging
+        access_log::run(bb, &mut this.server);
 
         this.expose_openapi_json();
-        this.expose_dir(WEBUI_DIR, "/", false, None);
-        this.expose_dir(EXTRA_DIR, "/srv", true, None);
-        this.expose_dir(LICENSE_DIR, "/docs/legal/files", true, Some("text/plain"));
+        this.expose_webui();
+        let extra_dir = fs_root::path(EXTRA_DIR);
+        this.expose_dir(extra_dir.clone(), "/srv", true, None);
+        upload::run(bb, wtb, &mut this.server, &extra_dir, "/srv")?;
+
+        let license_dir = fs_root::path(LICENSE_DIR);
+        this.expose_dir(license_dir, "/docs/legal/files", true, Some("text/plain"));
 
         this.server
             .at("/docs/legal/license.manifest")
-            .serve_file(LICENSE_MANIFEST)
+            .serve_file(fs_root::path(LICENSE_MANIFEST))
             .unwrap();
 
         for (fs_path, web_path) in EXPOSED_FILES_RW {
-            let fs_path = FS_PREFIX.to_owned() + *fs_path;
-            this.expose_file_rw(fs_path, web_path);
+            this.expose_file_rw(fs_root::path(fs_path), web_path);
         }
 
-        this
+        Ok(this)
     }
 
     /// Serve a compiled-in openapi.json file
@@ -115,20 +194,60 @@ impl HttpServer {
     /// Serve a directory from disk for reading
     fn expose_dir(
         &mut self,
-        fs_path: &'static str,
+        fs_path: PathBuf,
         web_path: &str,
         directory_listings: bool,
         force_mime: Option<&'static str>,
     ) {
-        let handler = move |req| serve_dir(req, fs_path, directory_listings, force_mime);
+        let fs_path: Arc<Path> = fs_path.into();
+
+        let handler = move |req| {
+            let fs_path = fs_path.clone();
+
+            async move { serve_dir(req, &fs_path, directory_listings, force_mime).await }
+        };
 
-        self.server.at(web_path).get(handler);
-        self.server.at(web_path).at("").get(handler);
+        self.server.at(web_path).get(handler.clone());
+        self.server.at(web_path).at("").get(handler.clone());
         self.server.at(web_path).at("*rel_path").get(handler);
     }
 
+    /// Require authentication for requests that change the state of the TAC
+    ///
+    /// This only has an effect if an API token is configured on the TAC.
+    /// While the TAC is in setup mode no authentication is required.
+    /// The handlers of such requests check the `WriteAccess` of the client
+    /// themselves, uploads use their own token instead.
+    pub fn require_auth(&mut self, setup_mode: Arc<Topic<bool>>) {
+        auth::run(&mut self.server, setup_mode);
+    }
+
+    /// Serve the web interface or a maintenance page if it is damaged
+    fn expose_webui(&mut self) {
+        let webui_integrity = self.webui_integrity.clone();
+
+        let handler = move |req: Request<()>| {
+            let webui_integrity = webui_integrity.clone();
+
+            async move {
+                if webui_integrity
+                    .try_get()
+                    .map_or(false, |i| i.is_corrupted())
+                {
+                    return Ok(integrity::maintenance_page());
+                }
+
+                serve_dir(req, Path::new(WEBUI_DIR), false, None).await
+            }
+        };
+
+        self.server.at("/").get(handler.clone());
+        self.server.at("/").at("").get(handler.clone());
+        self.server.at("/").at("*rel_path").get(handler);
+    }
+
     /// Serve a file from disk for reading and writing
-    fn expose_file_rw(&mut self, fs_path: String, web_path: &str) {
+    fn expose_file_rw(&mut self, fs_path: PathBuf, web_path: &str) {
         self.server.at(web_path).serve_file(&fs_path).unwrap();
 
         self.server
@@ -137,6 +256,10 @@ impl HttpServer {
                 let fs_path = fs_path.clone();
 
                 async move {
+                    if let Some(res) = WriteAccess::refuse(&req) {
+                        return Ok(res);
+                    }
+
                     let content = req.body_bytes().await?;
                     write(&fs_path, content)?;
 
@@ -0,0 +1,194 @@
+// This file is part of tacd, the LXA TAC system daemon
+// Copyright (C) 2024 Pengutronix e.K.
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along
+// with this program; if not, write to the Free Software Foundation, Inc.,
+// 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+
+//! Record recent HTTP requests for debugging
+//!
+//! Seeing which requests a lab automation setup sends (and how the tacd
+//! answers them) helps a lot with debugging, but previously required shell
+//! access. The most recent requests are published as a topic instead.
+//! Recording is disabled by default.
+
+use std::time::{Instant, SystemTime};
+
+use async_std::sync::Arc;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use tide::{Middleware, Next, Request, Server};
+
+use crate::broker::{BrokerBuilder, Topic};
+
+/// Number of requests to keep in the log. Older ones are dropped.
+const MAX_ENTRIES: usize = 100;
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct HttpLogEntry {
+    /// Time the request was received at in milliseconds since the Unix Epoch
+    pub ts: u64,
+    pub method: String,
+    pub path: String,
+    pub status: u16,
+    pub client: Option<String>,
+    /// Time it took to handle the request in milliseconds
+    pub duration: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug, Default)]
+pub struct HttpLogFilter {
+    /// Only record requests to paths starting with this prefix
+    pub path_prefix: Option<String>,
+    /// Only record requests that were answered with a 4xx or 5xx status
+    #[serde(default)]
+    pub errors_only: bool,
+}
+
+impl HttpLogFilter {
+    fn matches(&self, path: &str, status: u16) -> bool {
+        let path_ok = self
+            .path_prefix
+            .as_deref()
+            .map_or(true, |prefix| path.starts_with(prefix));
+
+        let status_ok = !self.errors_only || status >= 400;
+
+        path_ok && status_ok
+    }
+}
+
+/// Append `entry` to `log`, dropping the oldest entries if it grows too long
+fn append(log: Option<Vec<HttpLogEntry>>, entry: HttpLogEntry) -> Vec<HttpLogEntry> {
+    let mut log = log.unwrap_or_default();
+
+    log.push(entry);
+
+    if log.len() > MAX_ENTRIES {
+        log.drain(..(log.len() - MAX_ENTRIES));
+    }
+
+    log
+}
+
+#[derive(Clone)]
+struct AccessLog {
+    enabled: Arc<Topic<bool>>,
+    filter: Arc<Topic<HttpLogFilter>>,
+    log: Arc<Topic<Vec<HttpLogEntry>>>,
+}
+
+#[async_trait]
+impl Middleware<()> for AccessLog {
+    async fn handle(&self, req: Request<()>, next: Next<'_, ()>) -> tide::Result {
+        if !self.enabled.try_get().unwrap_or(false) {
+            return Ok(next.run(req).await);
+        }
+
+        let ts = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis() as u64);
+        let start = Instant::now();
+        let method = req.method().to_string();
+        let path = req.url().path().to_string();
+        let client = req.peer_addr().map(str::to_string);
+
+        let res = next.run(req).await;
+
+        let status = res.status() as u16;
+        let filter = self.filter.try_get().unwrap_or_default();
+
+        if filter.matches(&path, status) {
+            let entry = HttpLogEntry {
+                ts,
+                method,
+                path,
+                status,
+                client,
+                duration: start.elapsed().as_millis() as u64,
+            };
+
+            self.log.modify(|log| Some(append(log, entry)));
+        }
+
+        Ok(res)
+    }
+}
+
+/// Record the requests handled by `server` if enabled via the broker
+pub(super) fn run(bb: &mut BrokerBuilder, server: &mut Server<()>) {
+    let access_log = AccessLog {
+        enabled: bb.topic_rw("/v1/tac/daemon/http_log/enabled", Some(false)),
+        filter: bb.topic(
+            "/v1/tac/daemon/http_log/filter",
+            true,
+            true,
+            true,
+            Some(HttpLogFilter::default()),
+            1,
+        ),
+        log: bb.topic_ro("/v1/tac/daemon/http_log", Some(Vec::new())),
+    };
+
+    server.with(access_log);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{append, HttpLogEntry, HttpLogFilter, MAX_ENTRIES};
+
+    fn entry(ts: u64) -> HttpLogEntry {
+        HttpLogEntry {
+            ts,
+            method: "GET".to_string(),
+            path: "/v1/tac/info/uptime".to_string(),
+            status: 200,
+            client: None,
+            duration: 1,
+        }
+    }
+
+    #[test]
+    fn filter() {
+        let mut filter = HttpLogFilter::default();
+
+        println!("Without a filter everything is recorded");
+        assert!(filter.matches("/v1/tac/info/uptime", 200));
+        assert!(filter.matches("/", 404));
+
+        println!("Only matching paths are recorded");
+        filter.path_prefix = Some("/v1/dut".to_string());
+        assert!(filter.matches("/v1/dut/powered", 204));
+        assert!(!filter.matches("/v1/tac/info/uptime", 200));
+
+        println!("Only errors are recorded");
+        filter.errors_only = true;
+        assert!(!filter.matches("/v1/dut/powered", 204));
+        assert!(filter.matches("/v1/dut/powered", 401));
+    }
+
+    #[test]
+    fn ring() {
+        let mut log = None;
+
+        for ts in 0..(MAX_ENTRIES as u64 + 10) {
+            log = Some(append(log, entry(ts)));
+        }
+
+        let log = log.unwrap();
+
+        assert_eq!(log.len(), MAX_ENTRIES);
+        assert_eq!(log.first().unwrap().ts, 10);
+        assert_eq!(log.last().unwrap().ts, MAX_ENTRIES as u64 + 9);
+    }
+}
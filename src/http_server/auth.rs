@@ -0,0 +1,627 @@
+// This file is part of tacd, the LXA TAC system daemon
+// Copyright (C) 2024 Pengutronix e.K.
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along
+// with this program; if not, write to the Free Software Foundation, Inc.,
+// 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+
+//! Optional authentication for requests that change the state of the TAC
+//!
+//! Authentication is enabled by placing a token in `TOKEN_PATH`.
+//! Once enabled, every request that is not a plain read (e.g. switching the
+//! DUT power or starting an update) has to present either the token itself
+//! or a session id obtained from the login endpoint.
+//! Both can be sent as `Authorization: Bearer <secret>` header, the session
+//! id is also accepted as cookie, so that browsers do not have to handle it.
+//!
+//! Which requests need authentication is decided where they are handled:
+//! writes to broker topics (via REST, transactions or MQTT) are protected
+//! because the topic is web writable, other endpoints that change the state
+//! of the TAC check `WriteAccess::refuse` themselves.
+//! Reading stays possible without authentication, so that dashboards and
+//! monitoring keep working.
+//!
+//! Clients that present wrong credentials have to wait for an exponentially
+//! growing time before they may try again, to make guessing the token
+//! impractical.
+//! While the TAC is in setup mode no authentication is required, to allow
+//! for the initial provisioning.
+
+use std::collections::HashMap;
+use std::fs::{read_to_string, File};
+use std::io::Read;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use async_std::sync::Arc;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use tide::http::mime;
+use tide::{Middleware, Next, Request, Response, Server};
+
+use crate::broker::Topic;
+use crate::fs_root;
+
+const TOKEN_PATH: &str = "/etc/tacd/api-token";
+
+const STATUS_ROUTE: &str = "/v1/tac/auth";
+const LOGIN_ROUTE: &str = "/v1/tac/auth/login";
+const LOGOUT_ROUTE: &str = "/v1/tac/auth/logout";
+
+const SESSION_COOKIE: &str = "tacd_session";
+
+/// Sessions that were not used for this long have to log in again
+const SESSION_TIMEOUT: Duration = Duration::from_secs(12 * 60 * 60);
+
+/// Upper limit for the number of sessions. The least recently used session
+/// is dropped when a new one would exceed it.
+const MAX_SESSIONS: usize = 32;
+
+/// Wait time after the first failed authentication attempt of a client.
+/// It doubles with every further failure.
+const BACKOFF_MIN: Duration = Duration::from_secs(1);
+
+/// Upper limit for the time a client has to wait between attempts.
+/// Clients that did not fail for this long start over at `BACKOFF_MIN`.
+const BACKOFF_MAX: Duration = Duration::from_secs(64);
+
+/// Upper limit for the number of clients that failed attempts are tracked
+/// for. The client that failed least recently is forgotten first.
+const MAX_BACKOFF_PEERS: usize = 256;
+
+/// Compare a secret provided by a client to the expected one
+///
+/// The comparison takes the same time no matter where the first difference
+/// is, to not leak the secret via timing differences.
+pub(super) fn secret_matches(provided: &str, expected: &str) -> bool {
+    let provided = provided.as_bytes();
+    let expected = expected.as_bytes();
+
+    let diff = provided
+        .iter()
+        .zip(expected.iter())
+        .fold(0, |acc, (a, b)| acc | (a ^ b));
+
+    provided.len() == expected.len() && diff == 0
+}
+
+/// Is an API token configured, so that only authenticated clients may
+/// change the state of the TAC?
+pub fn auth_enabled() -> bool {
+    read_token().is_some()
+}
+
+/// Read the API token. Authentication is disabled if there is none.
+fn read_token() -> Option<String> {
+    read_to_string(fs_root::path(TOKEN_PATH))
+        .ok()
+        .map(|token| token.trim().to_string())
+        .filter(|token| !token.is_empty())
+}
+
+/// Get the session id from the value of a Cookie header
+fn session_cookie(header: &str) -> Option<&str> {
+    header
+        .split(';')
+        .filter_map(|cookie| cookie.trim().split_once('='))
+        .find(|(name, _)| *name == SESSION_COOKIE)
+        .map(|(_, value)| value)
+}
+
+/// The secret a client presented, either as bearer token or as cookie
+fn credential(req: &Request<()>) -> Option<String> {
+    let bearer = req
+        .header("Authorization")
+        .and_then(|h| h.as_str().strip_prefix("Bearer "));
+
+    let cookie = || {
+        req.header("Cookie")
+            .and_then(|h| session_cookie(h.as_str()))
+    };
+
+    bearer
+        .or_else(cookie)
+        .map(|secret| secret.trim().to_string())
+        .filter(|secret| !secret.is_empty())
+}
+
+/// The address of the client that sent a request, without the port,
+/// so that failed attempts from different connections add up
+fn peer_host(req: &Request<()>) -> String {
+    let addr = req.peer_addr().unwrap_or("unknown");
+
+    addr.rsplit_once(':')
+        .map_or(addr, |(host, _)| host)
+        .to_string()
+}
+
+fn new_session_id() -> std::io::Result<String> {
+    let mut bytes = [0; 16];
+    File::open("/dev/urandom")?.read_exact(&mut bytes)?;
+
+    Ok(bytes.iter().map(|b| format!("{b:02x}")).collect())
+}
+
+#[derive(Default)]
+struct Sessions {
+    last_used: HashMap<String, Instant>,
+}
+
+impl Sessions {
+    fn expire(&mut self, now: Instant) {
+        self.last_used
+            .retain(|_, last_used| now.saturating_duration_since(*last_used) < SESSION_TIMEOUT);
+    }
+
+    fn insert(&mut self, id: String, now: Instant) {
+        self.expire(now);
+
+        if self.last_used.len() >= MAX_SESSIONS {
+            let oldest = self
+                .last_used
+                .iter()
+                .min_by_key(|(_, last_used)| **last_used)
+                .map(|(id, _)| id.clone());
+
+            if let Some(oldest) = oldest {
+                self.last_used.remove(&oldest);
+            }
+        }
+
+        self.last_used.insert(id, now);
+    }
+
+    /// Check if a session is valid and mark it as used
+    fn touch(&mut self, id: &str, now: Instant) -> bool {
+        self.expire(now);
+
+        match self.last_used.get_mut(id) {
+            Some(last_used) => {
+                *last_used = now;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn remove(&mut self, id: &str) {
+        self.last_used.remove(id);
+    }
+}
+
+struct Backoff {
+    failures: u32,
+    last_failure: Instant,
+}
+
+impl Backoff {
+    fn delay(&self) -> Duration {
+        let exp = self.failures.saturating_sub(1).min(31);
+
+        BACKOFF_MIN.saturating_mul(1 << exp).min(BACKOFF_MAX)
+    }
+}
+
+/// Failed authentication attempts per client
+#[derive(Default)]
+struct Attempts {
+    peers: HashMap<String, Backoff>,
+}
+
+impl Attempts {
+    fn expire(&mut self, now: Instant) {
+        self.peers
+            .retain(|_, b| now.saturating_duration_since(b.last_failure) < BACKOFF_MAX);
+    }
+
+    /// How long `peer` still has to wait before it may try again
+    fn blocked_for(&mut self, peer: &str, now: Instant) -> Option<Duration> {
+        self.expire(now);
+
+        let backoff = self.peers.get(peer)?;
+        let waited = now.saturating_duration_since(backoff.last_failure);
+
+        backoff.delay().checked_sub(waited).filter(|d| !d.is_zero())
+    }
+
+    fn failed(&mut self, peer: &str, now: Instant) {
+        self.expire(now);
+
+        if !self.peers.contains_key(peer) && self.peers.len() >= MAX_BACKOFF_PEERS {
+            let oldest = self
+                .peers
+                .iter()
+                .min_by_key(|(_, b)| b.last_failure)
+                .map(|(peer, _)| peer.clone());
+
+            if let Some(oldest) = oldest {
+                self.peers.remove(&oldest);
+            }
+        }
+
+        let backoff = self.peers.entry(peer.to_string()).or_insert(Backoff {
+            failures: 0,
+            last_failure: now,
+        });
+
+        backoff.failures = backoff.failures.saturating_add(1);
+        backoff.last_failure = now;
+    }
+
+    fn succeeded(&mut self, peer: &str) {
+        self.peers.remove(peer);
+    }
+}
+
+/// The outcome of checking the credentials of a client
+enum Check {
+    Granted,
+    Denied,
+    /// The client failed too often and has to wait this long
+    Blocked(Duration),
+}
+
+#[derive(Clone)]
+struct Auth {
+    setup_mode: Arc<Topic<bool>>,
+    sessions: Arc<Mutex<Sessions>>,
+    attempts: Arc<Mutex<Attempts>>,
+}
+
+impl Auth {
+    /// Is a client at `peer` that presents `credential` allowed to change
+    /// the state of the TAC?
+    ///
+    /// Wrong credentials count as failed attempt of the client.
+    fn check(&self, credential: Option<&str>, peer: &str) -> Check {
+        if self.setup_mode.try_get().unwrap_or(false) {
+            return Check::Granted;
+        }
+
+        let token = match read_token() {
+            Some(token) => token,
+            None => return Check::Granted,
+        };
+
+        let credential = match credential {
+            Some(credential) => credential,
+            None => return Check::Denied,
+        };
+
+        let now = Instant::now();
+        let mut attempts = self.attempts.lock().unwrap();
+
+        if let Some(wait) = attempts.blocked_for(peer, now) {
+            return Check::Blocked(wait);
+        }
+
+        let valid = secret_matches(credential, &token)
+            || self.sessions.lock().unwrap().touch(credential, now);
+
+        if valid {
+            Check::Granted
+        } else {
+            attempts.failed(peer, now);
+            Check::Denied
+        }
+    }
+}
+
+/// Attached to every request, so that handlers can check for write access
+/// later on, e.g. for every message a client sends via a websocket.
+#[derive(Clone)]
+pub struct WriteAccess {
+    auth: Auth,
+    credential: Option<String>,
+    peer: String,
+}
+
+impl WriteAccess {
+    /// Check if the client that sent a request may change the state of
+    /// the TAC
+    ///
+    /// Requests that were not seen by the authentication middleware
+    /// (there are none outside of tests) are allowed.
+    pub fn of(req: &Request<()>) -> bool {
+        req.ext::<Self>().map_or(true, Self::allowed)
+    }
+
+    /// Get a response to send instead of handling a request, if the client
+    /// that sent it may not change the state of the TAC
+    pub fn refuse(req: &Request<()>) -> Option<Response> {
+        let access = req.ext::<Self>()?;
+
+        match access
+            .auth
+            .check(access.credential.as_deref(), &access.peer)
+        {
+            Check::Granted => None,
+            Check::Denied => {
+                let mut res = plain(401, "Authentication is required to change the TAC state");
+                res.insert_header("WWW-Authenticate", "Bearer");
+                Some(res)
+            }
+            Check::Blocked(wait) => Some(too_many_attempts(wait)),
+        }
+    }
+
+    /// Check (again) if the client may change the state of the TAC.
+    /// The result changes e.g. if the session expires or the TAC is put
+    /// into setup mode.
+    pub fn allowed(&self) -> bool {
+        matches!(
+            self.auth.check(self.credential.as_deref(), &self.peer),
+            Check::Granted
+        )
+    }
+}
+
+/// Attach the `WriteAccess` of the client to every request
+#[async_trait]
+impl Middleware<()> for Auth {
+    async fn handle(&self, mut req: Request<()>, next: Next<'_, ()>) -> tide::Result {
+        let access = WriteAccess {
+            auth: self.clone(),
+            credential: credential(&req),
+            peer: peer_host(&req),
+        };
+
+        req.set_ext(access);
+
+        Ok(next.run(req).await)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct AuthStatus {
+    /// Is an API token configured?
+    pub enabled: bool,
+    /// May the client that asked change the state of the TAC?
+    pub may_write: bool,
+}
+
+#[derive(Deserialize)]
+struct Login {
+    token: String,
+}
+
+fn plain(status: u16, msg: &str) -> Response {
+    Response::builder(status)
+        .body(msg)
+        .content_type(mime::PLAIN)
+        .build()
+}
+
+fn too_many_attempts(wait: Duration) -> Response {
+    // Round up, so that clients that honor the header do not come back early
+    let secs = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+
+    let mut res = plain(429, "Too many failed authentication attempts");
+    res.insert_header("Retry-After", secs.to_string());
+    res
+}
+
+async fn login(auth: Auth, mut req: Request<()>) -> tide::Result {
+    let token = match read_token() {
+        Some(token) => token,
+        None => return Ok(plain(403, "Authentication is disabled on this TAC")),
+    };
+
+    let login: Login = match req.body_json().await {
+        Ok(login) => login,
+        Err(_) => return Ok(plain(400, "Malformed login request")),
+    };
+
+    let peer = peer_host(&req);
+    let now = Instant::now();
+
+    {
+        let mut attempts = auth.attempts.lock().unwrap();
+
+        if let Some(wait) = attempts.blocked_for(&peer, now) {
+            return Ok(too_many_attempts(wait));
+        }
+
+        if !secret_matches(login.token.trim(), &token) {
+            attempts.failed(&peer, now);
+            return Ok(plain(401, "Invalid token"));
+        }
+
+        attempts.succeeded(&peer);
+    }
+
+    let id = new_session_id()?;
+
+    auth.sessions
+        .lock()
+        .unwrap()
+        .insert(id.clone(), Instant::now());
+
+    let res = Response::builder(200)
+        .body(serde_json::json!({ "session": id }))
+        .header(
+            "Set-Cookie",
+            format!("{SESSION_COOKIE}={id}; Path=/; HttpOnly; SameSite=Strict"),
+        )
+        .build();
+
+    Ok(res)
+}
+
+async fn logout(auth: Auth, req: Request<()>) -> tide::Result {
+    if let Some(id) = credential(&req) {
+        auth.sessions.lock().unwrap().remove(&id);
+    }
+
+    let res = Response::builder(204)
+        .header(
+            "Set-Cookie",
+            format!("{SESSION_COOKIE}=; Path=/; HttpOnly; SameSite=Strict; Max-Age=0"),
+        )
+        .build();
+
+    Ok(res)
+}
+
+/// Attach the write access of the client to every request and provide
+/// endpoints to log in and out
+pub(super) fn run(server: &mut Server<()>, setup_mode: Arc<Topic<bool>>) {
+    let auth = Auth {
+        setup_mode,
+        sessions: Arc::new(Mutex::new(Sessions::default())),
+        attempts: Arc::new(Mutex::new(Attempts::default())),
+    };
+
+    server.with(auth.clone());
+
+    server.at(STATUS_ROUTE).get(|req: Request<()>| async move {
+        let status = AuthStatus {
+            enabled: auth_enabled(),
+            may_write: WriteAccess::of(&req),
+        };
+
+        Ok(Response::builder(200)
+            .body(serde_json::to_vec(&status)?)
+            .content_type(mime::JSON)
+            .build())
+    });
+
+    let auth_login = auth.clone();
+    server
+        .at(LOGIN_ROUTE)
+        .post(move |req| login(auth_login.clone(), req));
+
+    server
+        .at(LOGOUT_ROUTE)
+        .post(move |req| logout(auth.clone(), req));
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{
+        secret_matches, session_cookie, Attempts, Sessions, BACKOFF_MAX, BACKOFF_MIN,
+        MAX_BACKOFF_PEERS, MAX_SESSIONS, SESSION_COOKIE, SESSION_TIMEOUT,
+    };
+
+    #[test]
+    fn secrets() {
+        assert!(secret_matches("secret", "secret"));
+        assert!(!secret_matches("secre", "secret"));
+        assert!(!secret_matches("secrets", "secret"));
+        assert!(!secret_matches("", "secret"));
+    }
+
+    #[test]
+    fn cookies() {
+        let header = format!("theme=dark; {SESSION_COOKIE}=abc123; other=1");
+
+        assert_eq!(session_cookie(&header), Some("abc123"));
+        assert_eq!(session_cookie("theme=dark"), None);
+        assert_eq!(session_cookie(""), None);
+    }
+
+    #[test]
+    fn sessions() {
+        let start = Instant::now();
+        let mut sessions = Sessions::default();
+
+        println!("Only known sessions are accepted");
+        sessions.insert("a".to_string(), start);
+        assert!(sessions.touch("a", start));
+        assert!(!sessions.touch("b", start));
+
+        println!("Sessions that are in use do not expire");
+        let half = start + SESSION_TIMEOUT / 2;
+        let later = half + SESSION_TIMEOUT / 2 + Duration::from_secs(1);
+        assert!(sessions.touch("a", half));
+        assert!(sessions.touch("a", later));
+
+        println!("Unused sessions expire");
+        let much_later = later + SESSION_TIMEOUT;
+        assert!(!sessions.touch("a", much_later));
+
+        println!("Logged out sessions are gone");
+        sessions.insert("c".to_string(), start);
+        sessions.remove("c");
+        assert!(!sessions.touch("c", start));
+
+        println!("The least recently used session is dropped first");
+        let mut sessions = Sessions::default();
+
+        for i in 0..MAX_SESSIONS {
+            sessions.insert(i.to_string(), start + Duration::from_secs(i as u64));
+        }
+
+        let now = start + Duration::from_secs(MAX_SESSIONS as u64);
+        assert!(sessions.touch("0", now));
+
+        sessions.insert("new".to_string(), now);
+        assert!(sessions.touch("0", now));
+        assert!(!sessions.touch("1", now));
+        assert!(sessions.touch("new", now));
+    }
+
+    #[test]
+    fn backoff() {
+        let start = Instant::now();
+        let mut attempts = Attempts::default();
+
+        println!("Clients may try as long as they did not fail");
+        assert_eq!(attempts.blocked_for("a", start), None);
+
+        println!("The wait time doubles with every failure");
+        attempts.failed("a", start);
+        assert_eq!(attempts.blocked_for("a", start), Some(BACKOFF_MIN));
+        assert_eq!(attempts.blocked_for("b", start), None);
+
+        let now = start + BACKOFF_MIN;
+        assert_eq!(attempts.blocked_for("a", now), None);
+
+        attempts.failed("a", now);
+        assert_eq!(attempts.blocked_for("a", now), Some(BACKOFF_MIN * 2));
+
+        println!("The wait time is limited");
+        for _ in 0..40 {
+            attempts.failed("a", now);
+        }
+        assert_eq!(attempts.blocked_for("a", now), Some(BACKOFF_MAX));
+
+        println!("A successful login starts over");
+        attempts.succeeded("a");
+        assert_eq!(attempts.blocked_for("a", now), None);
+
+        println!("Failures are forgotten after a while");
+        attempts.failed("a", now);
+        attempts.failed("a", now);
+        assert_eq!(attempts.blocked_for("a", now + BACKOFF_MAX), None);
+        attempts.failed("a", now + BACKOFF_MAX);
+        assert_eq!(
+            attempts.blocked_for("a", now + BACKOFF_MAX),
+            Some(BACKOFF_MIN)
+        );
+
+        println!("The number of tracked clients is limited");
+        let mut attempts = Attempts::default();
+
+        for i in 0..=MAX_BACKOFF_PEERS {
+            attempts.failed(&i.to_string(), start + Duration::from_millis(i as u64));
+        }
+
+        let now = start + Duration::from_millis(MAX_BACKOFF_PEERS as u64);
+        assert_eq!(attempts.peers.len(), MAX_BACKOFF_PEERS);
+        assert_eq!(attempts.blocked_for("0", now), None);
+        assert!(attempts.blocked_for("1", now).is_some());
+    }
+}
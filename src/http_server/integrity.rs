@@ -0,0 +1,190 @@
+// This file is part of tacd, the LXA TAC system daemon
+// Copyright (C) 2024 Pengutronix e.K.
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along
+// with this program; if not, write to the Free Software Foundation, Inc.,
+// 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+
+use std::time::Duration;
+
+use anyhow::Result;
+use async_std::fs::{read, read_to_string};
+use async_std::path::Path;
+use async_std::sync::Arc;
+use async_std::task::sleep;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use tide::{Body, Response};
+
+use crate::broker::{BrokerBuilder, Topic};
+use crate::watched_tasks::WatchedTasksBuilder;
+
+/// List of expected checksums (in `sha1sum` format) in the web interface
+/// directory. It is generated when the web interface is built.
+const MANIFEST_NAME: &str = "SHA1SUMS";
+
+/// Do not compete with the rest of the system for I/O during boot
+const STARTUP_DELAY: Duration = Duration::from_secs(5 * 60);
+
+/// Pause between checking two files to keep the I/O and CPU load low
+const FILE_INTERVAL: Duration = Duration::from_millis(200);
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+const MAINTENANCE: &str = r#"<!DOCTYPE html>
+<html>
+  <head>
+    <meta charset="utf-8" />
+    <meta name="viewport" content="width=device-width, initial-scale=1" />
+    <meta name="description" content="Maintenance required" />
+    <title>Maintenance required</title>
+    <style>
+      main {
+        background-color: #fbfbfb;
+        border-radius: 2em;
+        box-shadow: 0 0 1em #00000045;
+        left: 50%;
+        max-width: 50em;
+        padding: 2em;
+        position: absolute;
+        top: 50%;
+        transform: translate(-50%,-50%);
+      }
+    </style>
+  </head>
+  <body>
+    <main>
+      <h1>Maintenance required</h1>
+      <p>
+        Some files of the web interface on this TAC are damaged, which hints
+        at a failing storage or a failed update.
+        The web interface is disabled to prevent it from failing in
+        confusing ways.
+      </p>
+      <p>
+        The REST and MQTT APIs are still available.
+        The list of damaged files can be found at
+        <a href="/v1/tac/webui/integrity">/v1/tac/webui/integrity</a>.
+        Installing a software update (e.g. via <code>rauc install</code>)
+        replaces the damaged files.
+      </p>
+    </main>
+  </body>
+</html>
+"#;
+
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+pub enum Integrity {
+    /// No check has completed yet
+    Unknown,
+    /// There is no manifest to check against (e.g. in development builds)
+    NoManifest,
+    Okay,
+    /// The listed files are missing or do not match their checksum
+    Corrupted(Vec<String>),
+}
+
+impl Integrity {
+    pub fn is_corrupted(&self) -> bool {
+        matches!(self, Self::Corrupted(_))
+    }
+}
+
+fn sha1_hex(content: &[u8]) -> String {
+    Sha1::digest(content)
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+async fn check(base: &Path) -> Integrity {
+    let manifest = match read_to_string(base.join(MANIFEST_NAME)).await {
+        Ok(manifest) => manifest,
+        Err(_) => return Integrity::NoManifest,
+    };
+
+    let mut corrupted = Vec::new();
+
+    for line in manifest.lines() {
+        // Lines look like this: "<hex digest>  ./static/js/main.js"
+        let (expected, path) = match line.split_once("  ") {
+            Some(entry) => entry,
+            None => continue,
+        };
+
+        let path = path.trim_start_matches("./");
+
+        let matches = match read(base.join(path)).await {
+            Ok(content) => sha1_hex(&content) == expected,
+            Err(_) => false,
+        };
+
+        if !matches {
+            corrupted.push(path.to_owned());
+        }
+
+        sleep(FILE_INTERVAL).await;
+    }
+
+    if corrupted.is_empty() {
+        Integrity::Okay
+    } else {
+        Integrity::Corrupted(corrupted)
+    }
+}
+
+/// Periodically verify the files in `base` against the checksums in the
+/// manifest generated at build time
+pub fn run(
+    bb: &mut BrokerBuilder,
+    wtb: &mut WatchedTasksBuilder,
+    base: &'static str,
+) -> Result<Arc<Topic<Integrity>>> {
+    let integrity = bb.topic_ro("/v1/tac/webui/integrity", Some(Integrity::Unknown));
+    let integrity_task = integrity.clone();
+
+    wtb.spawn_task("webui-integrity-check", async move {
+        sleep(STARTUP_DELAY).await;
+
+        loop {
+            let res = check(Path::new(base)).await;
+
+            match &res {
+                Integrity::Corrupted(files) => {
+                    error!(
+                        "Web interface files are corrupted: {}. Serving a maintenance page instead",
+                        files.join(", ")
+                    )
+                }
+                Integrity::NoManifest => {
+                    info!("No web interface checksum manifest found. Skipping integrity check")
+                }
+                Integrity::Unknown | Integrity::Okay => {}
+            }
+
+            integrity_task.set_if_changed(res);
+
+            sleep(CHECK_INTERVAL).await;
+        }
+    })?;
+
+    Ok(integrity)
+}
+
+pub fn maintenance_page() -> Response {
+    let mut body = Body::from_string(MAINTENANCE.to_owned());
+    body.set_mime("text/html;charset=utf-8");
+
+    Response::builder(503).body(body).build()
+}
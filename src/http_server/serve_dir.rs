@@ -247,7 +247,7 @@ fn dir_listing(fs_path: &Path, is_root: bool) -> Result {
 
 pub async fn serve_dir(
     req: Request<()>,
-    base_path: &str,
+    base_path: &Path,
     directory_listings: bool,
     force_mime: Option<&str>,
 ) -> Result {
@@ -258,7 +258,6 @@ pub async fn serve_dir(
 
     let (path, is_root) = {
         let rel_path = Path::new(rel_path);
-        let base_path = Path::new(base_path);
         let mut path = base_path.to_owned();
 
         // Prevent path traversal via e.g. http://tac/srv/../../../etc/passwd
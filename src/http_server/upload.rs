@@ -0,0 +1,420 @@
+// This file is part of tacd, the LXA TAC system daemon
+// Copyright (C) 2024 Pengutronix e.K.
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along
+// with this program; if not, write to the Free Software Foundation, Inc.,
+// 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+
+use std::fs::{create_dir_all, read_dir, read_to_string, remove_file, rename};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use anyhow::Result;
+use async_std::fs::File;
+use async_std::io::{copy, ReadExt, WriteExt};
+use async_std::sync::{Arc, Mutex};
+use async_std::task::sleep;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use tide::{http::mime, Request, Response, Server};
+
+use super::auth::secret_matches;
+use crate::broker::{BrokerBuilder, Topic};
+use crate::fs_root;
+use crate::watched_tasks::WatchedTasksBuilder;
+
+const TOKEN_PATH: &str = "/etc/tacd/upload-token";
+
+/// The config is read-only via the broker and changed via this route
+/// instead, which requires the upload token as well.
+const CONFIG_ROUTE: &str = "/v1/tac/srv/upload/config";
+
+/// Uploaded files are placed in this sub-directory of the exposed directory
+const UPLOAD_SUBDIR: &str = "uploads";
+
+/// How often old files are looked for and the disk usage is updated
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+const MAX_NAME_LEN: usize = 255;
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct UploadConfig {
+    /// Maximum combined size (in bytes) of all files in the upload directory
+    pub quota: u64,
+    /// Remove files that were not modified for this long (in seconds)
+    pub max_age: Option<u64>,
+}
+
+impl Default for UploadConfig {
+    fn default() -> Self {
+        Self {
+            quota: 256 * 1024 * 1024,
+            max_age: None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug, Default)]
+pub struct UploadUsage {
+    /// Number of uploaded files, without partial uploads
+    pub files: u64,
+    /// Space used by all files, including partial uploads
+    pub bytes: u64,
+}
+
+/// Only allow simple file names that can not escape the upload directory
+/// or create hidden files
+fn valid_name(name: &str) -> bool {
+    let allowed = |c: char| c.is_ascii_alphanumeric() || c == '.' || c == '_' || c == '-';
+
+    !name.is_empty()
+        && name.len() <= MAX_NAME_LEN
+        && !name.starts_with('.')
+        && name.chars().all(allowed)
+}
+
+/// Compare the bearer token in an Authorization header to the expected one
+fn token_matches(header: Option<&str>, token: &str) -> bool {
+    header
+        .and_then(|h| h.strip_prefix("Bearer "))
+        .map_or(false, |provided| secret_matches(provided.trim(), token))
+}
+
+/// Uploads to an exposed directory are handled below this path
+fn route_prefix(web_path: &str) -> String {
+    format!("{web_path}/{UPLOAD_SUBDIR}/")
+}
+
+/// Read the upload token. Uploads are disabled if there is none.
+fn read_token() -> Option<String> {
+    read_to_string(fs_root::path(TOKEN_PATH))
+        .ok()
+        .map(|token| token.trim().to_string())
+        .filter(|token| !token.is_empty())
+}
+
+/// Check the upload token sent with a request
+///
+/// Returns the response to send instead if the request is not authorized.
+fn refuse_unauthorized(req: &Request<()>) -> Option<Response> {
+    let token = match read_token() {
+        Some(token) => token,
+        None => return Some(plain(403, "Uploads are disabled on this TAC")),
+    };
+
+    let authorization = req.header("Authorization").map(|h| h.as_str());
+
+    if !token_matches(authorization, &token) {
+        let mut res = plain(401, "A valid upload token is required");
+        res.insert_header("WWW-Authenticate", "Bearer");
+        return Some(res);
+    }
+
+    None
+}
+
+/// Files in the upload directory, including the hidden partial uploads
+/// if `partial` is set
+fn list_files(dir: &Path, partial: bool) -> Vec<(PathBuf, u64, SystemTime)> {
+    let entries = match read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+
+    entries
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| partial || !entry.file_name().to_string_lossy().starts_with('.'))
+        .filter_map(|entry| {
+            let meta = entry.metadata().ok().filter(|m| m.is_file())?;
+            let modified = meta.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+
+            Some((entry.path(), meta.len(), modified))
+        })
+        .collect()
+}
+
+/// Files in the upload directory that are not partial uploads
+fn uploaded_files(dir: &Path) -> Vec<(PathBuf, u64, SystemTime)> {
+    list_files(dir, false)
+}
+
+/// Count the uploaded files and the space used by them
+///
+/// Partial uploads use space as well and are included in the bytes.
+fn disk_usage(dir: &Path) -> UploadUsage {
+    UploadUsage {
+        files: uploaded_files(dir).len() as u64,
+        bytes: list_files(dir, true).iter().map(|(_, len, _)| len).sum(),
+    }
+}
+
+/// Remove partial uploads left behind e.g. by a restart of the tacd
+fn remove_partial(dir: &Path) {
+    for (path, _, _) in list_files(dir, true) {
+        let is_partial = path
+            .file_name()
+            .map_or(false, |name| name.to_string_lossy().ends_with(".part"));
+
+        if is_partial {
+            if let Err(e) = remove_file(&path) {
+                warn!("Failed to remove partial upload {}: {e}", path.display());
+            }
+        }
+    }
+}
+
+/// Remove files that were last modified more than max_age ago
+fn remove_expired(dir: &Path, max_age: Duration, now: SystemTime) {
+    for (path, _, modified) in uploaded_files(dir) {
+        let age = now.duration_since(modified).unwrap_or_default();
+
+        if age > max_age {
+            info!("Removing expired upload {}", path.display());
+
+            if let Err(e) = remove_file(&path) {
+                warn!("Failed to remove expired upload {}: {e}", path.display());
+            }
+        }
+    }
+}
+
+fn plain(status: u16, msg: &str) -> Response {
+    Response::builder(status)
+        .body(msg)
+        .content_type(mime::PLAIN)
+        .build()
+}
+
+async fn upload(
+    mut req: Request<()>,
+    dir: &Path,
+    config: &UploadConfig,
+    lock: &Mutex<()>,
+) -> Result<Response, std::io::Error> {
+    if let Some(res) = refuse_unauthorized(&req) {
+        return Ok(res);
+    }
+
+    let name = req.param("name").unwrap_or_default().to_string();
+
+    if !valid_name(&name) {
+        return Ok(plain(400, "Invalid file name"));
+    }
+
+    // Uploads are performed one after the other, so that concurrent
+    // uploads can not each fit into the quota but exceed it combined.
+    let _guard = lock.lock().await;
+
+    let path = dir.join(&name);
+    let partial = dir.join(format!(".{name}.part"));
+
+    // A file that is replaced does not count towards the quota
+    let used: u64 = list_files(dir, true)
+        .iter()
+        .filter(|(p, _, _)| *p != path && *p != partial)
+        .map(|(_, len, _)| len)
+        .sum();
+    let available = config.quota.saturating_sub(used);
+
+    if req.len().map_or(false, |len| len as u64 > available) {
+        return Ok(plain(507, "The upload quota would be exceeded"));
+    }
+
+    // Read at most one byte more than available to detect uploads without
+    // a Content-Length that do not fit.
+    let written = {
+        let mut file = File::create(&partial).await?;
+        let body = (&mut req).take(available + 1);
+        let written = copy(body, &mut file).await;
+        file.flush().await?;
+        written
+    };
+
+    match written {
+        Ok(len) if len <= available => {
+            rename(&partial, &path)?;
+            Ok(Response::new(204))
+        }
+        Ok(_) => {
+            remove_file(&partial)?;
+            Ok(plain(507, "The upload quota would be exceeded"))
+        }
+        Err(e) => {
+            remove_file(&partial)?;
+            Err(e)
+        }
+    }
+}
+
+/// Accept authenticated uploads of files to a sub-directory of an exposed
+/// directory and clean up old files
+///
+/// Uploads require a token, that is read from a file on the TAC, to be sent
+/// as `Authorization: Bearer <token>` header.
+/// Without the file uploads are disabled.
+pub(super) fn run(
+    bb: &mut BrokerBuilder,
+    wtb: &mut WatchedTasksBuilder,
+    server: &mut Server<()>,
+    fs_path: &Path,
+    web_path: &str,
+) -> Result<()> {
+    let config: Arc<Topic<UploadConfig>> = bb.topic(
+        CONFIG_ROUTE,
+        true,
+        false,
+        true,
+        Some(UploadConfig::default()),
+        1,
+    );
+    let usage: Arc<Topic<UploadUsage>> = bb.topic_ro("/v1/tac/srv/upload/usage", None);
+
+    let dir: Arc<Path> = fs_path.join(UPLOAD_SUBDIR).into();
+
+    if let Err(e) = create_dir_all(&dir) {
+        warn!("Failed to create upload directory {}: {e}", dir.display());
+    }
+
+    remove_partial(&dir);
+
+    let config_task = config.clone();
+
+    server.at(CONFIG_ROUTE).put(move |mut req: Request<()>| {
+        let config = config_task.clone();
+
+        async move {
+            if let Some(res) = refuse_unauthorized(&req) {
+                return Ok(res);
+            }
+
+            let new: UploadConfig = match req.body_json().await {
+                Ok(new) => new,
+                Err(_) => return Ok(plain(400, "Malformed upload config")),
+            };
+
+            info!("Setting upload config to {new:?}");
+
+            config.set(new);
+
+            Ok(Response::new(204))
+        }
+    });
+
+    let lock = Arc::new(Mutex::new(()));
+
+    let handler = {
+        let dir = dir.clone();
+        let config = config.clone();
+        let usage = usage.clone();
+
+        move |req: Request<()>| {
+            let dir = dir.clone();
+            let config = config.clone();
+            let usage = usage.clone();
+            let lock = lock.clone();
+
+            async move {
+                let config = config.try_get().unwrap_or_default();
+                let res = upload(req, &dir, &config, &lock).await?;
+
+                usage.set_if_changed(disk_usage(&dir));
+
+                Ok(res)
+            }
+        }
+    };
+
+    let route = format!("{}:name", route_prefix(web_path));
+    server.at(&route).put(handler.clone());
+    server.at(&route).post(handler);
+
+    wtb.spawn_task("upload-cleanup", async move {
+        loop {
+            if let Some(max_age) = config.try_get().and_then(|c| c.max_age) {
+                remove_expired(&dir, Duration::from_secs(max_age), SystemTime::now());
+            }
+
+            usage.set_if_changed(disk_usage(&dir));
+
+            sleep(CLEANUP_INTERVAL).await;
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{create_dir_all, remove_dir_all, File};
+    use std::time::{Duration, SystemTime};
+
+    use super::{disk_usage, remove_expired, remove_partial, token_matches, valid_name};
+
+    #[test]
+    fn names() {
+        println!("Simple file names are accepted");
+        assert!(valid_name("image.wic.bz2"));
+        assert!(valid_name("test_report-42.xml"));
+
+        println!("Names that escape the directory or are hidden are rejected");
+        assert!(!valid_name(""));
+        assert!(!valid_name(".."));
+        assert!(!valid_name(".hidden"));
+        assert!(!valid_name("../etc/passwd"));
+        assert!(!valid_name("dir/file"));
+        assert!(!valid_name("file name"));
+        assert!(!valid_name(&"a".repeat(256)));
+    }
+
+    #[test]
+    fn tokens() {
+        assert!(token_matches(Some("Bearer secret"), "secret"));
+        assert!(!token_matches(Some("Bearer secre"), "secret"));
+        assert!(!token_matches(Some("Bearer secrets"), "secret"));
+        assert!(!token_matches(Some("Basic secret"), "secret"));
+        assert!(!token_matches(None, "secret"));
+    }
+
+    #[test]
+    fn expiry() {
+        let dir = std::env::temp_dir().join(format!("tacd-upload-{}", std::process::id()));
+        create_dir_all(&dir).unwrap();
+
+        File::create(dir.join("report.xml"))
+            .unwrap()
+            .set_len(100)
+            .unwrap();
+        File::create(dir.join(".image.part"))
+            .unwrap()
+            .set_len(1000)
+            .unwrap();
+
+        println!("Partial uploads use space but are not counted as files");
+        let u = disk_usage(&dir);
+        assert_eq!((u.files, u.bytes), (1, 1100));
+
+        println!("Left over partial uploads are removed");
+        remove_partial(&dir);
+        let u = disk_usage(&dir);
+        assert_eq!((u.files, u.bytes), (1, 100));
+
+        println!("Recent files are kept");
+        remove_expired(&dir, Duration::from_secs(60), SystemTime::now());
+        assert_eq!(disk_usage(&dir).files, 1);
+
+        println!("Old files are removed");
+        let later = SystemTime::now() + Duration::from_secs(120);
+        remove_expired(&dir, Duration::from_secs(60), later);
+        assert_eq!(disk_usage(&dir).files, 0);
+
+        remove_dir_all(&dir).unwrap();
+    }
+}
@@ -30,6 +30,8 @@ use crate::watched_tasks::WatchedTasksBuilder;
 const CURRENT_MAX: f32 = 0.2;
 const VOLTAGE_MIN: f32 = 10.0;
 
+const SERVER_INFO_URL: &str = "http://127.0.0.1:8080/server-info/";
+
 #[cfg(feature = "demo_mode")]
 mod http {
     use super::{LSSState, Nodes, ServerInfo};
@@ -108,6 +110,16 @@ pub struct IoBus {
     pub nodes: Arc<Topic<Nodes>>,
 }
 
+/// Ask the IOBus server for information about itself right away
+///
+/// Returns None if the server could not be reached.
+pub async fn probe_server() -> Option<ServerInfo> {
+    http::get(SERVER_INFO_URL)
+        .recv_json::<ServerInfo>()
+        .await
+        .ok()
+}
+
 impl IoBus {
     pub fn new(
         bb: &mut BrokerBuilder,
@@ -126,10 +138,7 @@ impl IoBus {
 
         wtb.spawn_task("iobus-update", async move {
             loop {
-                if let Ok(si) = http::get("http://127.0.0.1:8080/server-info/")
-                    .recv_json::<ServerInfo>()
-                    .await
-                {
+                if let Ok(si) = http::get(SERVER_INFO_URL).recv_json::<ServerInfo>().await {
                     server_info_task.set_if_changed(si);
                 }
 
@@ -20,11 +20,16 @@ use async_std::io::BufReader;
 use async_std::prelude::*;
 use async_std::task::{block_on, spawn_blocking};
 
+use chrono::{DateTime, Local};
 use serde::Deserialize;
 use serde_json::to_string;
-use tide::http::Body;
+use tide::http::{mime, Body};
 use tide::{Request, Response, Server};
 
+/// Stop exporting entries once the response reaches this size, as it is
+/// assembled in memory before it is sent
+const MAX_EXPORT_SIZE: usize = 16 * 1024 * 1024;
+
 #[cfg(any(test, feature = "demo_mode"))]
 mod sd {
     use std::collections::btree_map::BTreeMap;
@@ -56,14 +61,26 @@ mod sd {
     }
 
     impl Journal {
+        pub fn seek_head(&mut self) -> Result<()> {
+            Ok(())
+        }
+
         pub fn seek_tail(&mut self) -> Result<()> {
             Ok(())
         }
 
+        pub fn seek_realtime_usec(&mut self, _: u64) -> Result<()> {
+            Ok(())
+        }
+
         pub fn previous_entry(&mut self) -> Result<Option<JournalRecord>> {
             Ok(None)
         }
 
+        pub fn next_entry(&mut self) -> Result<Option<JournalRecord>> {
+            Ok(None)
+        }
+
         pub fn watch_all_elements<F>(&mut self, mut f: F) -> Result<()>
         where
             F: FnMut(JournalRecord) -> Result<()>,
@@ -100,6 +117,27 @@ struct QueryParams {
     unit: Option<String>,
 }
 
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+enum ExportFormat {
+    /// One JSON object per line, like the entries sent via SSE
+    #[default]
+    Json,
+    /// One human readable line per entry, similar to `journalctl`
+    Text,
+}
+
+#[derive(Deserialize)]
+struct ExportParams {
+    /// Milliseconds since the Unix epoch
+    since: Option<u64>,
+    /// Milliseconds since the Unix epoch
+    until: Option<u64>,
+    unit: Option<String>,
+    #[serde(default)]
+    format: ExportFormat,
+}
+
 struct UnitFilter {
     unit: Option<String>,
 }
@@ -127,6 +165,30 @@ impl UnitFilter {
     }
 }
 
+/// Get the time (in microseconds since the Unix epoch) the entry was logged at
+fn record_timestamp_us(record: &JournalRecord) -> Option<i64> {
+    record
+        .get("_SOURCE_REALTIME_TIMESTAMP")
+        .or(record.get("__REALTIME_TIMESTAMP"))
+        .and_then(|us| us.parse().ok())
+}
+
+/// Add the time of the entry, formatted in the timezone of the TAC, as
+/// `TACD_LOCAL_TIMESTAMP`
+///
+/// This way clients do not show entries in their own timezone, which may
+/// differ from the one the TAC (and e.g. the LCD) uses.
+fn add_local_timestamp(mut record: JournalRecord) -> JournalRecord {
+    let ts = record_timestamp_us(&record).and_then(DateTime::from_timestamp_micros);
+
+    if let Some(ts) = ts {
+        let local = ts.with_timezone(&Local).format("%b %e %H:%M:%S");
+        record.insert("TACD_LOCAL_TIMESTAMP".to_string(), local.to_string());
+    }
+
+    record
+}
+
 fn open_journal(mut history_len: u64, filter: &UnitFilter) -> Result<Journal> {
     let mut journal = OpenOptions::default()
         .system(true)
@@ -155,7 +217,147 @@ fn open_journal(mut history_len: u64, filter: &UnitFilter) -> Result<Journal> {
     Ok(journal)
 }
 
+/// Format an entry like `journalctl -o short-iso-precise` does
+///
+/// Exported logs may span multiple days, so the full date is included.
+fn format_text(record: &JournalRecord) -> String {
+    let ts = record_timestamp_us(record)
+        .and_then(DateTime::from_timestamp_micros)
+        .map(|ts| {
+            ts.with_timezone(&Local)
+                .format("%Y-%m-%dT%H:%M:%S%.6f%z")
+                .to_string()
+        })
+        .unwrap_or_else(|| "-".to_string());
+
+    let ident = record
+        .get("SYSLOG_IDENTIFIER")
+        .or(record.get("UNIT"))
+        .or(record.get("_SYSTEMD_UNIT"))
+        .map_or("-", String::as_str);
+
+    let msg = record.get("MESSAGE").map_or("", String::as_str);
+
+    match record.get("_PID") {
+        Some(pid) => format!("{ts} {ident}[{pid}]: {msg}\n"),
+        None => format!("{ts} {ident}: {msg}\n"),
+    }
+}
+
+/// The exported entries and whether the export stopped at MAX_EXPORT_SIZE
+struct Export {
+    body: String,
+    truncated: bool,
+}
+
+fn export(params: &ExportParams) -> Result<Export> {
+    let filter = UnitFilter::new(params.unit.clone());
+    let since_us = params.since.map(|ms| ms.saturating_mul(1000));
+    let until_us = params.until.map(|ms| ms.saturating_mul(1000));
+
+    let mut journal = OpenOptions::default()
+        .system(true)
+        .local_only(true)
+        .open()?;
+
+    match since_us {
+        Some(us) => {
+            journal.seek_realtime_usec(us)?;
+        }
+        None => {
+            journal.seek_head()?;
+        }
+    }
+
+    let mut body = String::new();
+
+    while let Some(record) = journal.next_entry()? {
+        let ts = record_timestamp_us(&record).map(|us| us.max(0) as u64);
+
+        // The journal is ordered by the time the entries were received,
+        // so the time the entry was logged at may be a little earlier.
+        match (ts, since_us, until_us) {
+            (Some(ts), _, Some(until)) if ts > until => break,
+            (Some(ts), Some(since), _) if ts < since => continue,
+            _ => {}
+        }
+
+        let record = match filter.filter(record) {
+            Some(record) => record,
+            None => continue,
+        };
+
+        let line = match params.format {
+            ExportFormat::Json => to_string(&add_local_timestamp(record))? + "\n",
+            ExportFormat::Text => format_text(&record),
+        };
+
+        if body.len() + line.len() > MAX_EXPORT_SIZE {
+            return Ok(Export {
+                body,
+                truncated: true,
+            });
+        }
+
+        body.push_str(&line);
+    }
+
+    Ok(Export {
+        body,
+        truncated: false,
+    })
+}
+
+fn plain(status: u16, msg: &str) -> Response {
+    Response::builder(status)
+        .body(msg)
+        .content_type(mime::PLAIN)
+        .build()
+}
+
 pub fn serve(server: &mut Server<()>) {
+    // Download the entries logged during e.g. a test run in one go
+    server
+        .at("/v1/tac/journal/export")
+        .get(|req: Request<()>| async move {
+            let params = match req.query::<ExportParams>() {
+                Ok(params) => params,
+                Err(e) => return Ok(plain(400, &format!("Invalid query parameters: {e}"))),
+            };
+
+            if let (Some(since), Some(until)) = (params.since, params.until) {
+                if since > until {
+                    return Ok(plain(400, "since must not be later than until"));
+                }
+            }
+
+            let format = params.format;
+
+            // The Journal is not Send, so it is opened in the thread that
+            // reads it.
+            let export = match spawn_blocking(move || export(&params)).await {
+                Ok(export) => export,
+                Err(e) => return Ok(plain(500, &format!("Failed to read the journal: {e}"))),
+            };
+
+            let (content_type, filename) = match format {
+                ExportFormat::Json => ("application/x-ndjson", "journal.jsonl"),
+                ExportFormat::Text => ("text/plain", "journal.txt"),
+            };
+
+            let res = Response::builder(200)
+                .body(export.body)
+                .content_type(content_type)
+                .header(
+                    "Content-Disposition",
+                    format!("attachment; filename=\"{filename}\""),
+                )
+                .header("X-Journal-Truncated", export.truncated.to_string())
+                .build();
+
+            Ok(res)
+        });
+
     server
         .at("/v1/tac/journal")
         .get(|req: Request<()>| async move {
@@ -215,7 +417,7 @@ pub fn serve(server: &mut Server<()>) {
                 let sender_watch = sender.clone();
                 let res = journal.watch_all_elements(move |element| {
                     if let Some(elem) = filter.filter(element) {
-                        let json = to_string(&elem)?;
+                        let json = to_string(&add_local_timestamp(elem))?;
                         block_on(sender_watch.send("entry", &json, None))?;
                     }
 
@@ -240,3 +442,33 @@ pub fn serve(server: &mut Server<()>) {
             Ok(resp)
         });
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{format_text, JournalRecord};
+
+    fn record(fields: &[(&str, &str)]) -> JournalRecord {
+        fields
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn text_format() {
+        println!("Entries are formatted like journalctl does");
+        let line = format_text(&record(&[
+            ("_SOURCE_REALTIME_TIMESTAMP", "1714564800000000"),
+            ("SYSLOG_IDENTIFIER", "tacd"),
+            ("_PID", "123"),
+            ("MESSAGE", "Says HI!"),
+        ]));
+
+        assert!(line.starts_with("2024-05-01T"));
+        assert!(line.ends_with(" tacd[123]: Says HI!\n"));
+
+        println!("Missing fields do not prevent an entry from being exported");
+        let line = format_text(&record(&[("UNIT", "tacd.service")]));
+        assert_eq!(line, "- tacd.service: \n");
+    }
+}
@@ -21,23 +21,42 @@ use anyhow::Result;
 use async_std::prelude::*;
 use async_std::sync::Arc;
 use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use tracing::info_span;
 
 use crate::broker::{BrokerBuilder, Topic};
+use crate::measurement::Timestamp;
 use crate::watched_tasks::WatchedTasksBuilder;
 
 #[cfg(feature = "demo_mode")]
 mod demo_mode;
 
 mod extras;
+mod signal;
 
 #[cfg(feature = "demo_mode")]
-use demo_mode::{Brightness, Leds, SysClass};
+use demo_mode::{sys_class, Brightness, Leds, SysClass};
 
+#[cfg(not(feature = "demo_mode"))]
+use crate::fs_root::sys_class;
 #[cfg(not(feature = "demo_mode"))]
 use sysfs_class::{Brightness, Leds, SysClass};
 
-pub use extras::{BlinkPattern, BlinkPatternBuilder};
+pub use extras::{BlinkPattern, BlinkPatternBuilder, LedMeaning};
 use extras::{Pattern, RgbColor};
+use signal::LedSignal;
+
+/// Number of past pattern changes to keep per LED for debugging purposes
+const PATTERN_HISTORY_LENGTH: usize = 8;
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PatternRecord {
+    pub ts: Timestamp,
+    pub source: String,
+    pub pattern: BlinkPattern,
+}
+
+pub type PatternHistory = Arc<Topic<Vec<PatternRecord>>>;
 
 pub struct Led {
     pub out_0: Arc<Topic<BlinkPattern>>,
@@ -47,6 +66,7 @@ pub struct Led {
     pub eth_lab: Arc<Topic<BlinkPattern>>,
     pub status: Arc<Topic<BlinkPattern>>,
     pub status_color: Arc<Topic<(f32, f32, f32)>>,
+    pub history: Vec<(&'static str, PatternHistory)>,
 }
 
 /// Get the specified LED and output an appropriate message if it fails
@@ -55,7 +75,7 @@ pub struct Led {
 /// so not finding an LED should not be a critical error.
 /// Just show a not and go on if an LED can not be set up.
 fn get_led_checked(hardware_name: &'static str) -> Option<Leds> {
-    match Leds::new(hardware_name) {
+    match sys_class::<Leds>(hardware_name) {
         Ok(led) => Some(led),
         Err(err) if err.kind() == ErrorKind::NotFound => {
             info!("Hardware does not have LED {hardware_name}, ignoring");
@@ -68,20 +88,103 @@ fn get_led_checked(hardware_name: &'static str) -> Option<Leds> {
     }
 }
 
+/// Get a function that sets the pattern of an LED class device (if present)
+///
+/// This is also used for the beeper, which is connected via gpio-leds on the
+/// hardware revisions that have one, so it can be driven by the pattern
+/// trigger as well.
+pub fn pattern_output(
+    hardware_name: &'static str,
+) -> Option<impl Fn(BlinkPattern) -> std::io::Result<()>> {
+    get_led_checked(hardware_name).map(|led| move |pattern| led.set_pattern(pattern))
+}
+
+type Signal = Arc<Topic<Option<LedSignal>>>;
+
+/// Set up the topics for an LED
+///
+/// The returned pattern topic is the one used by the tacd itself.
+/// What is actually shown on the LED (and exported via the API) may also
+/// be a signal requested by an external tool.
 fn handle_pattern(
     bb: &mut BrokerBuilder,
     wtb: &mut WatchedTasksBuilder,
     hardware_name: &'static str,
     topic_name: &'static str,
-) -> Result<Arc<Topic<BlinkPattern>>> {
+    has_color: bool,
+) -> Result<(Arc<Topic<BlinkPattern>>, PatternHistory, Signal)> {
+    let requested = Topic::anonymous(None);
+    let signal = signal::handle_requests(bb, wtb, topic_name, has_color)?;
     let topic = bb.topic_ro(&format!("/v1/tac/led/{topic_name}/pattern"), None);
+    let history = bb.topic_ro(
+        &format!("/v1/tac/led/{topic_name}/history"),
+        Some(Vec::new()),
+    );
+    let meaning = bb.topic_ro(&format!("/v1/tac/led/{topic_name}/meaning"), None);
+
+    signal::overlay(
+        wtb,
+        format!("led-{topic_name}-signal"),
+        requested.clone(),
+        signal.clone(),
+        topic.clone(),
+        |signal| Some(signal.blink_pattern()),
+    )?;
+
+    // Blink patterns are hard to interpret for API consumers, so also
+    // provide what they are trying to tell the user.
+    {
+        let (mut rx, _) = topic.clone().subscribe_unbounded();
+
+        wtb.spawn_task(format!("led-{topic_name}-meaning"), async move {
+            while let Some(pattern) = rx.next().await {
+                meaning.set_if_changed(pattern.meaning());
+            }
+
+            Ok(())
+        })?;
+    }
+
+    // Keep a record of who set which pattern when.
+    // This is done regardless of the LED actually being present on this
+    // hardware, to make it possible to debug the blink logic anyways.
+    {
+        let (mut rx, _) = topic.clone().subscribe_unbounded();
+        let history = history.clone();
+
+        wtb.spawn_task(format!("led-{topic_name}-history"), async move {
+            while let Some(pattern) = rx.next().await {
+                let record = PatternRecord {
+                    ts: Timestamp::now(),
+                    source: pattern.origin().to_owned(),
+                    pattern,
+                };
+
+                history.modify(|prev| {
+                    let mut records = prev.unwrap_or_default();
+
+                    records.push(record);
+
+                    if records.len() > PATTERN_HISTORY_LENGTH {
+                        records.drain(..(records.len() - PATTERN_HISTORY_LENGTH));
+                    }
+
+                    Some(records)
+                });
+            }
+
+            Ok(())
+        })?;
+    }
 
     if let Some(led) = get_led_checked(hardware_name) {
         let (mut rx, _) = topic.clone().subscribe_unbounded();
 
         wtb.spawn_task("led-pattern-update", async move {
             while let Some(pattern) = rx.next().await {
-                if let Err(e) = led.set_pattern(pattern) {
+                let span = info_span!("led_pattern", led = topic_name);
+
+                if let Err(e) = span.in_scope(|| led.set_pattern(pattern)) {
                     warn!("Failed to set LED pattern: {}", e);
                 }
             }
@@ -90,7 +193,7 @@ fn handle_pattern(
         })?;
     }
 
-    Ok(topic)
+    Ok((requested, history, signal))
 }
 
 fn handle_color(
@@ -98,9 +201,21 @@ fn handle_color(
     wtb: &mut WatchedTasksBuilder,
     hardware_name: &'static str,
     topic_name: &'static str,
+    signal: Signal,
 ) -> Result<Arc<Topic<(f32, f32, f32)>>> {
+    let requested = Topic::anonymous(None);
     let topic = bb.topic_ro(&format!("/v1/tac/led/{topic_name}/color"), None);
 
+    // Signals without a color keep the one set by the tacd
+    signal::overlay(
+        wtb,
+        format!("led-{topic_name}-signal-color"),
+        requested.clone(),
+        signal,
+        topic.clone(),
+        |signal| signal.color,
+    )?;
+
     if let Some(led) = get_led_checked(hardware_name) {
         let (mut rx, _) = topic.clone().subscribe_unbounded();
 
@@ -121,19 +236,38 @@ fn handle_color(
         })?;
     }
 
-    Ok(topic)
+    Ok(requested)
 }
 
 impl Led {
     pub fn new(bb: &mut BrokerBuilder, wtb: &mut WatchedTasksBuilder) -> Result<Self> {
+        let (out_0, out_0_history, _) = handle_pattern(bb, wtb, "tac:green:out0", "out_0", false)?;
+        let (out_1, out_1_history, _) = handle_pattern(bb, wtb, "tac:green:out1", "out_1", false)?;
+        let (dut_pwr, dut_pwr_history, _) =
+            handle_pattern(bb, wtb, "tac:green:dutpwr", "dut_pwr", false)?;
+        let (eth_dut, eth_dut_history, _) =
+            handle_pattern(bb, wtb, "tac:green:statusdut", "eth_dut", false)?;
+        let (eth_lab, eth_lab_history, _) =
+            handle_pattern(bb, wtb, "tac:green:statuslab", "eth_lab", false)?;
+        let (status, status_history, status_signal) =
+            handle_pattern(bb, wtb, "rgb:status", "status", true)?;
+
         Ok(Self {
-            out_0: handle_pattern(bb, wtb, "tac:green:out0", "out_0")?,
-            out_1: handle_pattern(bb, wtb, "tac:green:out1", "out_1")?,
-            dut_pwr: handle_pattern(bb, wtb, "tac:green:dutpwr", "dut_pwr")?,
-            eth_dut: handle_pattern(bb, wtb, "tac:green:statusdut", "eth_dut")?,
-            eth_lab: handle_pattern(bb, wtb, "tac:green:statuslab", "eth_lab")?,
-            status: handle_pattern(bb, wtb, "rgb:status", "status")?,
-            status_color: handle_color(bb, wtb, "rgb:status", "status")?,
+            out_0,
+            out_1,
+            dut_pwr,
+            eth_dut,
+            eth_lab,
+            status,
+            status_color: handle_color(bb, wtb, "rgb:status", "status", status_signal)?,
+            history: vec![
+                ("out_0", out_0_history),
+                ("out_1", out_1_history),
+                ("dut_pwr", dut_pwr_history),
+                ("eth_dut", eth_dut_history),
+                ("eth_lab", eth_lab_history),
+                ("status", status_history),
+            ],
         })
     }
 }
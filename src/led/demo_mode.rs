@@ -25,6 +25,7 @@ const FILES_READ: &[(&str, &str)] = &[
     ("tac:green:out0/max_brightness", "1"),
     ("tac:green:out1/max_brightness", "1"),
     ("tac:green:dutpwr/max_brightness", "1"),
+    ("tac:buzzer/max_brightness", "1"),
     ("rgb:status/max_brightness", "65535"),
     ("rgb:status/multi_index", "red green blue"),
 ];
@@ -87,3 +88,9 @@ impl SysClass for Leds {
 impl Brightness for Leds {
     trait_method!(max_brightness parse_file u64);
 }
+
+/// The simulated devices are not looked up in the filesystem, so there is
+/// nothing to resolve via `fs_root` here
+pub fn sys_class<C: SysClass>(id: &str) -> Result<C> {
+    C::new(id)
+}
@@ -48,10 +48,44 @@ impl RgbColor for Leds {
     }
 }
 
-#[derive(Serialize, Deserialize, Clone)]
+/// Get the name of the task or thread we are currently running in
+///
+/// This is used to keep track of who created a BlinkPattern, to make it
+/// easier to debug why an LED behaves the way it does.
+fn current_task_name() -> String {
+    async_std::task::try_current()
+        .and_then(|task| task.name().map(str::to_owned))
+        .or_else(|| std::thread::current().name().map(str::to_owned))
+        .unwrap_or_else(|| "<unknown>".to_owned())
+}
+
+/// What an LED is trying to tell the user, independent of the blink pattern
+/// used to show it
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum LedMeaning {
+    Off,
+    On,
+    /// Some blink pattern without a more specific meaning attached
+    Blink,
+    /// Slowly fading in and out while e.g. probing the DUT power output
+    Probing,
+    /// Three quick blinks followed by a pause. Signals an error condition.
+    ErrorBlink,
+    /// The locator was activated to find this TAC
+    Locator,
+    /// An external tool uses the LED to signal e.g. the state of a test
+    Signal,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
 pub struct BlinkPattern {
     repetitions: i32,
     steps: Vec<(f32, Duration)>,
+    #[serde(skip)]
+    origin: Option<String>,
+    #[serde(skip)]
+    meaning: Option<LedMeaning>,
 }
 
 impl BlinkPattern {
@@ -62,6 +96,70 @@ impl BlinkPattern {
                 (val, Duration::from_millis(1000)),
                 (val, Duration::from_millis(1000)),
             ],
+            origin: Some(current_task_name()),
+            meaning: None,
+        }
+    }
+
+    /// Attach a meaning to the pattern that can not be derived from its steps
+    pub fn with_meaning(mut self, meaning: LedMeaning) -> Self {
+        self.meaning = Some(meaning);
+        self
+    }
+
+    /// What the pattern is trying to tell the user
+    ///
+    /// Patterns without an explicitly attached meaning are classified as
+    /// being off, on (at any brightness) or blinking based on their steps.
+    pub fn meaning(&self) -> LedMeaning {
+        if let Some(meaning) = self.meaning {
+            return meaning;
+        }
+
+        let first = self.steps.first().map(|(brightness, _)| *brightness);
+        let is_solid = self
+            .steps
+            .iter()
+            .all(|(brightness, _)| Some(*brightness) == first);
+
+        match (is_solid, first) {
+            (_, None) => LedMeaning::Off,
+            (true, Some(brightness)) if brightness <= 0.0 => LedMeaning::Off,
+            (true, Some(_)) => LedMeaning::On,
+            (false, Some(_)) => LedMeaning::Blink,
+        }
+    }
+
+    /// The name of the task or thread that created this pattern
+    pub fn origin(&self) -> &str {
+        self.origin.as_deref().unwrap_or("<unknown>")
+    }
+
+    /// Get a short human readable description of the pattern
+    pub fn describe(&self) -> String {
+        let first = self.steps.first().map(|(brightness, _)| *brightness);
+        let is_solid = self
+            .steps
+            .iter()
+            .all(|(brightness, _)| Some(*brightness) == first);
+
+        match (is_solid, first) {
+            (_, None) => "empty".to_string(),
+            (true, Some(brightness)) => format!("solid {brightness:.2}"),
+            (false, Some(_)) => {
+                let period: Duration = self.steps.iter().map(|(_, duration)| *duration).sum();
+                let repetitions = match self.repetitions {
+                    -1 => "forever".to_string(),
+                    r => format!("{r}x"),
+                };
+
+                format!(
+                    "blink {} steps {}ms {}",
+                    self.steps.len(),
+                    period.as_millis(),
+                    repetitions
+                )
+            }
         }
     }
 
@@ -93,6 +191,8 @@ impl BlinkPatternBuilder {
             pattern: BlinkPattern {
                 repetitions: 0,
                 steps: Vec::new(),
+                origin: Some(current_task_name()),
+                meaning: None,
             },
         }
     }
@@ -117,7 +217,6 @@ impl BlinkPatternBuilder {
         self.pattern
     }
 
-    #[allow(dead_code)]
     pub fn once(self) -> BlinkPattern {
         self.repeat(1)
     }
@@ -0,0 +1,302 @@
+// This file is part of tacd, the LXA TAC system daemon
+// Copyright (C) 2024 Pengutronix e.K.
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along
+// with this program; if not, write to the Free Software Foundation, Inc.,
+// 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+
+//! Let external tools use the LEDs to signal e.g. the state of a test
+//!
+//! Signals are requested using a high level description instead of raw
+//! blink patterns and take precedence over the patterns set by the tacd
+//! itself until they are cleared again.
+
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use async_std::sync::Arc;
+use futures::{select, FutureExt, StreamExt};
+use log::{info, warn};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use super::{BlinkPattern, BlinkPatternBuilder, LedMeaning};
+use crate::broker::{BrokerBuilder, Topic};
+use crate::watched_tasks::WatchedTasksBuilder;
+
+/// Blinking faster than this is unpleasant to look at
+const MIN_PERIOD: Duration = Duration::from_millis(200);
+const MAX_PERIOD: Duration = Duration::from_secs(10);
+const DEFAULT_PERIOD: Duration = Duration::from_millis(1000);
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum SignalMode {
+    Solid,
+    /// Evenly on and off
+    Blink,
+    /// Two short pulses followed by a pause
+    Heartbeat,
+}
+
+fn full_brightness() -> f32 {
+    1.0
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct LedSignal {
+    pub mode: SignalMode,
+    /// Brightness between 0.0 and 1.0
+    #[serde(default = "full_brightness")]
+    pub brightness: f32,
+    /// Length of one blink or heartbeat cycle
+    #[serde(default)]
+    pub period_ms: Option<u64>,
+    /// RGB color with components between 0.0 and 1.0 (status LED only)
+    #[serde(default)]
+    pub color: Option<(f32, f32, f32)>,
+}
+
+fn check_fraction(name: &str, val: f32) -> Result<()> {
+    if !(0.0..=1.0).contains(&val) {
+        bail!("{name} {val} is not between 0.0 and 1.0");
+    }
+
+    Ok(())
+}
+
+impl LedSignal {
+    /// Check a signal requested via the API for an LED with or without
+    /// color control
+    fn check(&self, has_color: bool) -> Result<()> {
+        check_fraction("Brightness", self.brightness)?;
+
+        if let Some(period_ms) = self.period_ms {
+            if self.mode == SignalMode::Solid {
+                bail!("A period can not be set for solid signals");
+            }
+
+            let period = Duration::from_millis(period_ms);
+
+            if !(MIN_PERIOD..=MAX_PERIOD).contains(&period) {
+                bail!(
+                    "Period {period_ms}ms is not between {}ms and {}ms",
+                    MIN_PERIOD.as_millis(),
+                    MAX_PERIOD.as_millis()
+                );
+            }
+        }
+
+        if let Some((r, g, b)) = self.color {
+            if !has_color {
+                bail!("The color of this LED can not be set");
+            }
+
+            for component in [r, g, b] {
+                check_fraction("Color component", component)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn period(&self) -> Duration {
+        self.period_ms
+            .map(Duration::from_millis)
+            .unwrap_or(DEFAULT_PERIOD)
+    }
+
+    pub fn blink_pattern(&self) -> BlinkPattern {
+        let on = self.brightness;
+        let period = self.period();
+
+        let pattern = match self.mode {
+            SignalMode::Solid => BlinkPattern::solid(on),
+            SignalMode::Blink => BlinkPatternBuilder::new(on)
+                .step_to(on)
+                .stay_for(period / 2)
+                .step_to(0.0)
+                .stay_for(period - period / 2)
+                .forever(),
+            SignalMode::Heartbeat => {
+                let pulse = period / 8;
+
+                BlinkPatternBuilder::new(on)
+                    .step_to(on)
+                    .stay_for(pulse)
+                    .step_to(0.0)
+                    .stay_for(pulse)
+                    .step_to(on)
+                    .stay_for(pulse)
+                    .step_to(0.0)
+                    .stay_for(period - pulse * 3)
+                    .forever()
+            }
+        };
+
+        pattern.with_meaning(LedMeaning::Signal)
+    }
+}
+
+/// Subscribe to signal requests for an LED from the web
+///
+/// Only valid requests end up in the returned topic.
+/// `None` clears the signal and hands the LED back to the tacd.
+pub(super) fn handle_requests(
+    bb: &mut BrokerBuilder,
+    wtb: &mut WatchedTasksBuilder,
+    topic_name: &'static str,
+    has_color: bool,
+) -> Result<Arc<Topic<Option<LedSignal>>>> {
+    let signal = Topic::anonymous(Some(None));
+
+    let (mut requests, _) = bb
+        .topic_wo::<Option<LedSignal>>(&format!("/v1/tac/led/{topic_name}/pattern"), None)
+        .subscribe_unbounded();
+
+    let signal_task = signal.clone();
+
+    wtb.spawn_task(format!("led-{topic_name}-request"), async move {
+        while let Some(req) = requests.next().await {
+            if let Some(Err(e)) = req.as_ref().map(|req| req.check(has_color)) {
+                warn!("Refusing to signal {req:?} on LED {topic_name}: {e}");
+                continue;
+            }
+
+            info!("Signaling {req:?} on LED {topic_name}");
+
+            signal_task.set(req);
+        }
+
+        Ok(())
+    })?;
+
+    Ok(signal)
+}
+
+/// Show the values `requested` by the tacd in `effective` unless a signal
+/// that is mapped to a value by `map` is active
+pub(super) fn overlay<E, F>(
+    wtb: &mut WatchedTasksBuilder,
+    name: String,
+    requested: Arc<Topic<E>>,
+    signal: Arc<Topic<Option<LedSignal>>>,
+    effective: Arc<Topic<E>>,
+    map: F,
+) -> Result<()>
+where
+    E: Serialize + DeserializeOwned + Send + Sync + Clone + 'static,
+    F: Fn(&LedSignal) -> Option<E> + Send + 'static,
+{
+    let (mut requested_events, _) = requested.clone().subscribe_unbounded();
+    let (mut signal_events, _) = signal.subscribe_unbounded();
+
+    wtb.spawn_task(name, async move {
+        let mut overlaid = None;
+
+        loop {
+            select! {
+                ev = requested_events.next().fuse() => match ev {
+                    // Changes are recorded but not shown while signaling
+                    Some(val) if overlaid.is_none() => effective.set(val),
+                    Some(_) => {}
+                    None => break,
+                },
+                ev = signal_events.next().fuse() => match ev {
+                    Some(signal) => {
+                        overlaid = signal.as_ref().and_then(&map);
+
+                        if let Some(val) = overlaid.clone().or_else(|| requested.try_get()) {
+                            effective.set(val);
+                        }
+                    }
+                    None => break,
+                },
+            }
+        }
+
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LedSignal, SignalMode};
+    use crate::led::LedMeaning;
+
+    #[test]
+    fn signal_validation() {
+        let signal = |mode, period_ms, color| LedSignal {
+            mode,
+            brightness: 1.0,
+            period_ms,
+            color,
+        };
+
+        println!("Signals with sensible parameters are accepted");
+        assert!(signal(SignalMode::Solid, None, None).check(false).is_ok());
+        assert!(signal(SignalMode::Blink, Some(500), None)
+            .check(false)
+            .is_ok());
+        assert!(signal(SignalMode::Heartbeat, None, Some((1.0, 0.0, 0.0)))
+            .check(true)
+            .is_ok());
+
+        println!("Colors can only be set on LEDs that have them");
+        assert!(signal(SignalMode::Solid, None, Some((1.0, 0.0, 0.0)))
+            .check(false)
+            .is_err());
+        assert!(signal(SignalMode::Solid, None, Some((2.0, 0.0, 0.0)))
+            .check(true)
+            .is_err());
+
+        println!("Periods have to fit the mode and be in range");
+        assert!(signal(SignalMode::Solid, Some(500), None)
+            .check(false)
+            .is_err());
+        assert!(signal(SignalMode::Blink, Some(10), None)
+            .check(false)
+            .is_err());
+        assert!(signal(SignalMode::Blink, Some(60_000), None)
+            .check(false)
+            .is_err());
+
+        println!("Brightnesses have to be in range as well");
+        let too_bright = LedSignal {
+            brightness: 1.5,
+            ..signal(SignalMode::Solid, None, None)
+        };
+        assert!(too_bright.check(false).is_err());
+        let nan = LedSignal {
+            brightness: f32::NAN,
+            ..signal(SignalMode::Solid, None, None)
+        };
+        assert!(nan.check(false).is_err());
+    }
+
+    #[test]
+    fn signal_patterns() {
+        let signal = |mode| LedSignal {
+            mode,
+            brightness: 1.0,
+            period_ms: None,
+            color: None,
+        };
+
+        let solid = signal(SignalMode::Solid).blink_pattern();
+        assert!(solid.is_on());
+        assert_eq!(solid.meaning(), LedMeaning::Signal);
+
+        assert!(signal(SignalMode::Blink).blink_pattern().is_blinking());
+        assert!(signal(SignalMode::Heartbeat).blink_pattern().is_blinking());
+    }
+}
@@ -20,45 +20,78 @@ use async_std::future::pending;
 use log::{error, info};
 
 mod adc;
+mod annotations;
+mod backends;
 mod backlight;
+mod beacon;
+mod beeper;
 mod broker;
+mod camera;
+mod confirmation;
+mod conflicts;
+mod crash_report;
 mod dbus;
 mod digital_io;
 mod dut_power;
+mod firewall;
+mod fs_root;
+mod gpio_user;
+mod history;
 mod http_server;
 mod iobus;
 mod journal;
 mod led;
 mod measurement;
 mod motd;
+mod notifier;
+mod reachability;
+mod realtime;
+mod recorder;
 mod regulators;
+mod rtc;
+mod selftest;
+mod serial;
 mod setup_mode;
+mod speedtest;
+mod status_page;
 mod system;
 mod temperatures;
+mod traces;
 mod ui;
 mod usb_hub;
 mod watchdog;
 mod watched_tasks;
 
 use adc::Adc;
+use backends::{Backend, Backends, Degraded};
 use backlight::Backlight;
 use broker::BrokerBuilder;
+use confirmation::Confirmation;
+use conflicts::ConflictGuard;
 use dbus::DbusSession;
-use digital_io::DigitalIo;
+use digital_io::{DigitalIo, GpioHealth, LineConflict};
 use dut_power::DutPwrThread;
+use firewall::Firewall;
 use http_server::HttpServer;
 use iobus::IoBus;
 use led::Led;
+use realtime::Realtime;
 use regulators::Regulators;
+use rtc::Rtc;
+use selftest::SelfTest;
 use setup_mode::SetupMode;
-use system::{HardwareGeneration, System};
+use status_page::StatusTopics;
+use system::{HardwareConsistency, HardwareGeneration, System};
 use temperatures::Temperatures;
-use ui::{message, setup_display, ScreenShooter, Ui, UiResources};
+use ui::{message, setup_display, FrameStats, FrameTimer, Locator, ScreenShooter, Ui, UiResources};
 use usb_hub::UsbHub;
 use watchdog::Watchdog;
 use watched_tasks::WatchedTasksBuilder;
 
-async fn init(screenshooter: ScreenShooter) -> Result<(Ui, WatchedTasksBuilder)> {
+async fn init(
+    screenshooter: ScreenShooter,
+    frame_timer: FrameTimer,
+) -> Result<(Ui, WatchedTasksBuilder)> {
     // The tacd spawns a couple of async tasks that should run as long as
     // the tacd runs and if any one fails the tacd should stop.
     // These tasks are spawned via the watched task builder.
@@ -73,22 +106,89 @@ async fn init(screenshooter: ScreenShooter) -> Result<(Ui, WatchedTasksBuilder)>
     // places in the init process.
     let hardware_generation = HardwareGeneration::get()?;
 
+    // Make sure that the boards and their calibration data fit together.
+    // The DUT power stays locked until mismatches are acknowledged.
+    let hardware_consistency = HardwareConsistency::new(&mut bb, &mut wtb, hardware_generation)?;
+
+    // Some subsystems can be stubbed out via a config file, e.g. to keep
+    // using a TAC with a damaged power board.
+    let backends = Backends::load(&mut bb);
+    let degraded = Degraded::new(&mut bb);
+
+    // Record timing information about some code paths, that can be sent
+    // to an OpenTelemetry collector for debugging.
+    // This is set up early on so that the spans of all other parts are seen.
+    traces::run(&mut bb, &mut wtb)?;
+
+    // Keep track of GPIO lines that are used by other processes, so that
+    // conflicts are visible instead of outputs silently not switching.
+    let gpio_health = GpioHealth::new(&mut bb);
+
+    // Destructive actions requested via the API may have to be confirmed
+    // by pressing a button on the TAC (if enabled).
+    let confirmation = Confirmation::new(&mut bb);
+
+    // Opposing requests made on the LCD and via the API in short succession
+    // have to be confirmed, instead of the last one silently winning.
+    let conflicts = ConflictGuard::new(&mut bb, &mut wtb)?;
+
     // Expose hardware on the TAC via the broker framework.
     let backlight = Backlight::new(&mut bb, &mut wtb)?;
     let led = Led::new(&mut bb, &mut wtb)?;
-    let adc = Adc::new(&mut bb, &mut wtb, hardware_generation).await?;
-    let dut_pwr = DutPwrThread::new(
+    let realtime = Realtime::new(&mut bb, &mut wtb)?;
+    let adc = Adc::new(
         &mut bb,
         &mut wtb,
-        adc.pwr_volt.clone(),
-        adc.pwr_curr.clone(),
-        led.dut_pwr.clone(),
+        &realtime,
         hardware_generation,
+        &backends,
+        &degraded,
     )
     .await?;
-    let dig_io = DigitalIo::new(&mut bb, &mut wtb, led.out_0.clone(), led.out_1.clone())?;
+    // The power thread can not protect the output without feedback from the
+    // power board ADC, so it is only started if both are available.
+    let dut_pwr = if adc.pwr_available && backends.dut_power == Backend::Hardware {
+        DutPwrThread::new(
+            &mut bb,
+            &mut wtb,
+            &realtime,
+            adc.pwr_volt.clone(),
+            adc.pwr_curr.clone(),
+            led.dut_pwr.clone(),
+            &gpio_health,
+            hardware_generation,
+            hardware_consistency.power_locked.clone(),
+            confirmation.clone(),
+            conflicts.clone(),
+        )
+        .await?
+    } else {
+        DutPwrThread::unavailable(&mut bb, &mut wtb, adc.pwr_volt.clone())?
+    };
+    let dig_io = DigitalIo::new(
+        &mut bb,
+        &mut wtb,
+        &realtime,
+        &gpio_health,
+        led.out_0.clone(),
+        led.out_1.clone(),
+    )?;
+
+    // Spare GPIO lines that are explicitly allowed to be used via the API.
+    gpio_user::run(&mut bb, &mut wtb, &gpio_health)?;
+
     let regulators = Regulators::new(&mut bb, &mut wtb)?;
     let temperatures = Temperatures::new(&mut bb, &mut wtb)?;
+
+    // Sound the buzzer (on hardware that has one) on request or when the TAC
+    // is in a critical state.
+    beeper::run(
+        &mut bb,
+        &mut wtb,
+        dut_pwr.state.clone(),
+        temperatures.warning.clone(),
+    )?;
+
     let usb_hub = UsbHub::new(
         &mut bb,
         &mut wtb,
@@ -96,6 +196,7 @@ async fn init(screenshooter: ScreenShooter) -> Result<(Ui, WatchedTasksBuilder)>
         adc.usb_host1_curr.fast.clone(),
         adc.usb_host2_curr.fast.clone(),
         adc.usb_host3_curr.fast.clone(),
+        conflicts.clone(),
     )?;
 
     // Expose other software on the TAC via the broker framework by connecting
@@ -107,39 +208,145 @@ async fn init(screenshooter: ScreenShooter) -> Result<(Ui, WatchedTasksBuilder)>
         adc.iobus_curr.fast.clone(),
         adc.iobus_volt.fast.clone(),
     )?;
-    let (hostname, network, rauc, systemd) = {
-        let dbus =
-            DbusSession::new(&mut bb, &mut wtb, led.eth_dut.clone(), led.eth_lab.clone()).await?;
+    // Set up a http server and provide some static files like the web
+    // interface and config files that may be edited inside the web ui.
+    let mut http_server = HttpServer::new(&mut bb, &mut wtb)?;
 
-        (dbus.hostname, dbus.network, dbus.rauc, dbus.systemd)
+    // Allow editing some aspects of the TAC configuration when in "setup mode".
+    let setup_mode = SetupMode::new(&mut bb, &mut wtb, &mut http_server.server)?;
+
+    // Only allow authenticated clients to change the state of the TAC
+    // (if an API token is configured and the TAC is not in setup mode).
+    http_server.require_auth(setup_mode.setup_mode.clone());
+
+    // Turning the confirmations on or off via the API requires setup mode
+    // or a confirmation on the TAC itself.
+    confirmation.handle_requests(&mut bb, &mut wtb, setup_mode.setup_mode.clone())?;
+
+    let (hostname, network, rauc, systemd, timedate) = {
+        let dbus = DbusSession::new(
+            &mut bb,
+            &mut wtb,
+            led.eth_dut.clone(),
+            led.eth_lab.clone(),
+            setup_mode.setup_mode.clone(),
+            dut_pwr.state.clone(),
+            confirmation.clone(),
+        )
+        .await?;
+
+        (
+            dbus.hostname,
+            dbus.network,
+            dbus.rauc,
+            dbus.systemd,
+            dbus.timedate,
+        )
     };
 
+    // Tell someone outside of the lab about faults like a DUT overcurrent
+    // via webhooks and / or e-mail.
+    notifier::run(
+        &mut bb,
+        &mut wtb,
+        hostname.hostname.clone(),
+        dut_pwr.state.clone(),
+        dut_pwr.maintenance_override.clone(),
+        usb_hub.overload.clone(),
+        temperatures.warning.clone(),
+        &rauc,
+    )?;
+
+    // Allow isolating the DUT from the uplink network by switching between
+    // predefined nftables profiles.
+    let firewall = Firewall::new(&mut bb, &mut wtb)?;
+
     // Expose information about the system provided by the kernel via the
     // broker framework.
     let system = System::new(&mut bb, hardware_generation)?;
+    system.serve_factory_data(&mut http_server.server, setup_mode.setup_mode.clone());
 
-    // Make sure the ADC and power switching threads of the tacd are not
-    // stalled for too long by providing watchdog events to systemd
-    // (if requested on start).
-    let watchdog = Watchdog::new(dut_pwr.tick());
+    // Keep an eye on the RTC backup cell (on hardware that has one).
+    let rtc = Rtc::new(&mut bb, &mut wtb)?;
 
-    // Set up a http server and provide some static files like the web
-    // interface and config files that may be edited inside the web ui.
-    let mut http_server = HttpServer::new();
+    // Expose information about the last crash of the tacd (if any).
+    crash_report::register(&mut bb);
 
-    // Allow editing some aspects of the TAC configuration when in "setup mode".
-    let setup_mode = SetupMode::new(&mut bb, &mut wtb, &mut http_server.server)?;
+    // Make sure the ADC, power switching and digital IO threads of the tacd
+    // are not stalled for too long by providing watchdog events to systemd
+    // (if requested on start).
+    // Information to diagnose stalls is exposed via the broker either way.
+    let watchdog = Watchdog::new(
+        &mut bb,
+        &mut wtb,
+        dut_pwr.tick(),
+        dig_io.tick(),
+        realtime.threads.clone(),
+    )?;
 
     // Expose a live log of the TAC's systemd journal so it can be viewed
-    // in the web interface.
+    // in the web interface, and allow exporting past entries.
     journal::serve(&mut http_server.server);
 
+    // Allow test scripts to mark events during long measurement captures.
+    // The annotations are grouped by DUT profile.
+    let annotations = annotations::run(&mut bb, &mut wtb, dut_pwr.profile.clone())?;
+
+    // Record measurements over long test runs into downloadable CSV files.
+    recorder::run(
+        &mut bb,
+        &mut wtb,
+        &mut http_server.server,
+        &adc,
+        annotations.clone(),
+    )?;
+
+    // Keep the last minutes of measurements in memory, so that charts do
+    // not start out empty after reloading the web interface.
+    history::run(
+        &mut bb,
+        &mut wtb,
+        &mut http_server.server,
+        &adc,
+        annotations,
+    )?;
+
+    // Stream ADC samples at full rate for scope-like views and analysis.
+    adc.serve_stream(&mut http_server.server);
+
+    // Provide snapshots of a USB camera that watches e.g. the DUT's display.
+    camera::run(&mut bb, &mut wtb, &mut http_server.server, &usb_hub)?;
+
+    // Bridge the RS232/RS485 header (if populated) and the DUT console to
+    // websocket clients.
+    serial::run(&mut bb, &mut wtb, &mut http_server.server)?;
+
+    // Allow sharing USB port presets and DUT power profiles between TACs
+    // as JSON files.
+    usb_hub.serve_presets(&mut http_server.server);
+    dut_pwr.serve_profiles(&mut http_server.server);
+
+    // Measure the network throughput between the TAC and the DUT on request.
+    speedtest::run(&mut bb, &mut wtb)?;
+    reachability::run(&mut bb, &mut wtb)?;
+
+    // Check the hardware and software of the TAC on request, e.g. after it
+    // was moved to another lab.
+    let selftest = SelfTest::new(&mut bb, &mut wtb, &adc, &dig_io, &gpio_health, &led, &rauc)?;
+
+    // Help finding the TAC in a shared lab. The locator is shown on the LCD,
+    // via the status LED and in the MOTD.
+    let locator = Locator::topic(&mut bb);
+
     // Maintain a /etc/motd with useful information about the TAC.
     if let Err(err) = motd::run(
         &mut wtb,
         &dut_pwr,
+        &http_server,
         &iobus,
+        &locator,
         &rauc,
+        &rtc,
         &setup_mode,
         &temperatures,
         &usb_hub,
@@ -147,31 +354,76 @@ async fn init(screenshooter: ScreenShooter) -> Result<(Ui, WatchedTasksBuilder)>
         error!("failed to start motd update service with {err}");
     }
 
+    // Provide a lightweight status page for quick checks e.g. from a phone.
+    let status_topics = StatusTopics::new(
+        &hostname,
+        &system,
+        &dut_pwr,
+        &temperatures,
+        &usb_hub,
+        &iobus,
+        &rtc,
+    );
+
+    status_page::serve(&mut http_server.server, &status_topics);
+
+    // Announce the same information via UDP broadcasts (if enabled),
+    // e.g. for wallboards that show many TACs at once.
+    beacon::run(&mut bb, &mut wtb, status_topics)?;
+
     // Set up the user interface for the hardware display on the TAC.
     // The different screens receive updates via the topics provided in
     // the UiResources struct.
     let ui = {
+        // Publish how long it takes to draw on the display
+        let frame_stats = FrameStats::new(&mut bb, &mut wtb, frame_timer)?;
+
+        // Tell users if settings were reset because the state file was damaged
+        let state_recovery = bb.state_recovery();
+
         let resources = UiResources {
             adc,
             backlight,
+            confirmation,
+            conflicts,
+            degraded,
             dig_io,
             dut_pwr,
+            firewall,
+            frame_stats,
+            gpio_health,
+            hardware_consistency,
             hostname,
             iobus,
             led,
+            locator,
             network,
             rauc,
             regulators,
+            selftest,
             setup_mode,
+            state_recovery,
             system,
             systemd,
             temperatures,
+            timedate,
             usb_hub,
         };
 
         Ui::new(&mut bb, &mut wtb, resources)?
     };
 
+    // Capture the display content when something goes wrong, so that it
+    // can be looked at later.
+    if let Err(err) = ui.capture_alerts(
+        &mut bb,
+        &mut wtb,
+        &mut http_server.server,
+        screenshooter.clone(),
+    ) {
+        error!("failed to set up alert captures with {err}");
+    }
+
     // Consume the BrokerBuilder (no further topics can be added or removed)
     // and expose the topics via HTTP and MQTT-over-websocket.
     bb.build(&mut wtb, &mut http_server.server)?;
@@ -179,6 +431,10 @@ async fn init(screenshooter: ScreenShooter) -> Result<(Ui, WatchedTasksBuilder)>
     // Expose the display as a .png on the web server
     ui::serve_display(&mut http_server.server, screenshooter);
 
+    // Allow starting the locator from the web interface with information
+    // about who started it
+    ui.serve_locator(&mut http_server.server);
+
     // Start serving files and the API
     http_server.serve(&mut wtb)?;
 
@@ -194,32 +450,57 @@ async fn init(screenshooter: ScreenShooter) -> Result<(Ui, WatchedTasksBuilder)>
 async fn main() -> Result<()> {
     env_logger::init();
 
+    // Set up the directories tacd keeps its files in before anything else
+    // (like the crash report below) accesses them.
+    fs_root::init()?;
+
+    // Keep a record of panics, so they can be diagnosed after a restart.
+    crash_report::install_panic_hook();
+
     // Show a splash screen very early on
     let display = setup_display();
 
     // This allows us to expose screenshoots of the LCD screen via HTTP
     let screenshooter = display.screenshooter();
 
-    match init(screenshooter).await {
+    // Record how long it takes to draw on the display
+    let frame_timer = display.frame_timer();
+
+    match init(screenshooter, frame_timer).await {
         Ok((ui, mut wtb)) => {
             // Start drawing the UI
             ui.run(&mut wtb, display)?;
 
             info!("Setup complete. Handling requests");
 
-            wtb.watch().await
+            let res = wtb.watch().await;
+
+            if let Err(e) = &res {
+                crash_report::record_error(e);
+            }
+
+            res
         }
         Err(e) => {
             // Display a detailed error message on stderr (and thus in the journal) ...
             error!("Failed to initialize tacd: {e}");
 
             // ... and a generic message on the LCD, as it can not fit a lot of detail.
+            // GPIO conflicts are an exception, as they are likely caused by
+            // another process the user can stop.
+            let text = match e.downcast_ref::<LineConflict>() {
+                Some(LineConflict {
+                    line,
+                    consumer: Some(consumer),
+                }) => format!(
+                    "tacd failed to start!\n\nGPIO {line} is\nused by {consumer}.\nWaiting for watchdog\nto restart tacd."
+                ),
+                _ => "tacd failed to start!\n\nCheck log for info.\nWaiting for watchdog\nto restart tacd.".to_string(),
+            };
+
             display.clear();
             display.with_lock(|target| {
-                message(
-                    target,
-                    "tacd failed to start!\n\nCheck log for info.\nWaiting for watchdog\nto restart tacd.",
-                );
+                message(target, &text);
             });
 
             // Wait forever (or more likely until the systemd watchdog timer hits)
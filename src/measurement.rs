@@ -38,6 +38,95 @@ impl Measurement {
     }
 }
 
+/// How the values of a measurement channel should be presented to humans
+///
+/// Values are scaled using SI prefixes (e.g. 0.01234A is shown as 12.3 mA)
+/// and are rounded to a fixed number of significant digits.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DisplayFormat {
+    /// The base unit of the values, like "V" or "A"
+    pub unit: String,
+    pub significant_digits: u8,
+    /// Use a decimal comma instead of a decimal point
+    #[serde(default)]
+    pub decimal_comma: bool,
+}
+
+/// SI prefixes as (exponent, prefix, ASCII only prefix)
+const SI_PREFIXES: [(i32, &str, &str); 7] = [
+    (-12, "p", "p"),
+    (-9, "n", "n"),
+    (-6, "µ", "u"),
+    (-3, "m", "m"),
+    (0, "", ""),
+    (3, "k", "k"),
+    (6, "M", "M"),
+];
+
+const SIGNIFICANT_DIGITS_MAX: u8 = 6;
+
+fn order_of_magnitude(value: f64) -> i32 {
+    if value == 0.0 {
+        0
+    } else {
+        value.abs().log10().floor() as i32
+    }
+}
+
+impl DisplayFormat {
+    pub fn new(unit: &str, significant_digits: u8) -> Self {
+        Self {
+            unit: unit.to_string(),
+            significant_digits,
+            decimal_comma: false,
+        }
+    }
+
+    fn format_with(&self, value: f32, ascii_only: bool) -> String {
+        if !value.is_finite() {
+            return format!("{value} {}", self.unit);
+        }
+
+        let digits = self.significant_digits.clamp(1, SIGNIFICANT_DIGITS_MAX) as i32;
+        let value = value as f64;
+
+        // Round first, as this may change the order of magnitude
+        // (e.g. 999.96 -> 1000 when using three significant digits).
+        let scale = 10f64.powi(digits - 1 - order_of_magnitude(value));
+        let rounded = (value * scale).round() / scale;
+        let magnitude = order_of_magnitude(rounded);
+
+        let (exponent, prefix, prefix_ascii) = SI_PREFIXES
+            .iter()
+            .rev()
+            .find(|(exp, _, _)| *exp <= magnitude)
+            .unwrap_or(&SI_PREFIXES[0]);
+
+        let prefix = if ascii_only { prefix_ascii } else { prefix };
+        let scaled = rounded / 10f64.powi(*exponent);
+        let decimals = (digits - 1 - (magnitude - exponent)).max(0) as usize;
+
+        let mut number = format!("{scaled:.decimals$}");
+
+        if self.decimal_comma {
+            number = number.replace('.', ",");
+        }
+
+        format!("{number} {prefix}{}", self.unit)
+    }
+
+    /// Format a value with an SI prefix and the configured precision
+    pub fn format(&self, value: f32) -> String {
+        self.format_with(value, false)
+    }
+
+    /// Like format(), but only uses ASCII characters (e.g. "u" instead
+    /// of "µ"), as supported by the fonts used on the LCD.
+    pub fn format_ascii(&self, value: f32) -> String {
+        self.format_with(value, true)
+    }
+}
+
 impl Timestamp {
     pub fn new(inst: Instant) -> Self {
         Self(inst)
@@ -102,3 +191,33 @@ impl<'d> Deserialize<'d> for Timestamp {
         unimplemented!();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::DisplayFormat;
+
+    #[test]
+    fn si_prefixes() {
+        let amps = DisplayFormat::new("A", 3);
+
+        assert_eq!(amps.format(0.01234), "12.3 mA");
+        assert_eq!(amps.format(1.234), "1.23 A");
+        assert_eq!(amps.format(12.34), "12.3 A");
+        assert_eq!(amps.format(0.0000123), "12.3 µA");
+        assert_eq!(amps.format_ascii(0.0000123), "12.3 uA");
+        assert_eq!(amps.format(-0.5), "-500 mA");
+        assert_eq!(amps.format(0.0), "0.00 A");
+
+        // Rounding may move the value to the next prefix
+        assert_eq!(amps.format(0.99996), "1.00 A");
+
+        let volts = DisplayFormat {
+            unit: "V".to_string(),
+            significant_digits: 4,
+            decimal_comma: true,
+        };
+
+        assert_eq!(volts.format(12.3456), "12,35 V");
+        assert_eq!(volts.format(1234.0), "1,234 kV");
+    }
+}
@@ -1,23 +1,25 @@
 use std::fmt::{self, Display, Formatter};
 use std::fs::{create_dir_all, File};
 use std::io::{Seek, Write};
-use std::path::Path;
 
 use anyhow::Result;
+use async_std::sync::Arc;
 use futures::FutureExt;
 use nix::errno::Errno;
 use nix::mount::MsFlags;
 
+use crate::broker::Topic;
+use crate::dbus::rauc::Eta;
 use crate::dut_power::OutputState;
+use crate::fs_root;
+use crate::rtc::BackupState;
 use crate::temperatures::Warning;
+use crate::ui::Locator;
 use crate::usb_hub::OverloadedPort;
 use crate::WatchedTasksBuilder;
 
 #[cfg(feature = "demo_mode")]
 mod setup {
-    pub(super) const VAR_RUN_TACD: &str = "demo_files/var/run/tacd";
-    pub(super) const ETC: &str = "demo_files/etc";
-
     /// umount stub for demo_mode that works without root permissions
     ///
     /// (by doing nothing).
@@ -42,20 +44,25 @@ mod setup {
 #[cfg(not(feature = "demo_mode"))]
 mod setup {
     pub(super) use nix::mount::{mount, umount};
-    pub(super) const VAR_RUN_TACD: &str = "/var/run/tacd";
-    pub(super) const ETC: &str = "/etc";
 }
 
 use setup::*;
 
+const VAR_RUN_TACD: &str = "/var/run/tacd";
+const ETC: &str = "/etc";
+
 struct Motd {
     dut_pwr_state: OutputState,
     iobus_fault: bool,
+    locator: Locator,
+    rauc_eta: Option<Eta>,
     rauc_should_reboot: bool,
     rauc_update_urls: Vec<String>,
+    rtc_backup: BackupState,
     setup_mode_active: bool,
     temperature_warning: bool,
     usb_overload: Option<OverloadedPort>,
+    webui_corrupted: bool,
     handle: File,
 }
 
@@ -77,6 +84,20 @@ impl Display for Motd {
             writeln!(f, "  it cool down.")?;
         }
 
+        if self.locator.active {
+            writeln!(
+                f,
+                "- {COLOR_YELLOW}INFO{COLOR_RESET}: Someone is looking for this TAC using the locator.",
+            )?;
+
+            // The message is set via the API, do not let it mess with
+            // the terminal of whoever logs in.
+            for line in self.locator.describe() {
+                let line: String = line.chars().filter(|c| !c.is_control()).collect();
+                writeln!(f, "  {line}")?;
+            }
+        }
+
         if self.setup_mode_active {
             writeln!(
                 f,
@@ -89,6 +110,14 @@ impl Display for Motd {
             writeln!(f, "  to leave the setup mode.")?;
         }
 
+        if let Some(eta) = &self.rauc_eta {
+            writeln!(
+                f,
+                "- {COLOR_YELLOW}INFO{COLOR_RESET}: A software update is being installed. Time left: {}",
+                eta.format(),
+            )?;
+        }
+
         if self.rauc_should_reboot {
             writeln!(
                 f,
@@ -116,7 +145,10 @@ impl Display for Motd {
                     "- {COLOR_GREEN}NOTE{COLOR_RESET}: The device under test is currently powered on.",
                 )?;
             }
-            OutputState::Off | OutputState::OffFloating | OutputState::Changing => {}
+            OutputState::Off
+            | OutputState::OffFloating
+            | OutputState::Changing
+            | OutputState::Probing => {}
             OutputState::InvertedPolarity => {
                 writeln!(
                         f,
@@ -159,6 +191,30 @@ impl Display for Motd {
             )?;
         }
 
+        match self.rtc_backup {
+            BackupState::Missing => {
+                writeln!(
+                    f,
+                    "- {COLOR_RED}WARNING{COLOR_RESET}: The RTC backup cell is missing. The system time will be lost",
+                )?;
+                writeln!(f, "  when the TAC loses power.")?;
+            }
+            BackupState::Depleted => {
+                writeln!(
+                    f,
+                    "- {COLOR_RED}WARNING{COLOR_RESET}: The RTC backup cell is depleted. The system time will be lost",
+                )?;
+                writeln!(f, "  when the TAC loses power. Please replace the cell.")?;
+            }
+            BackupState::Low => {
+                writeln!(
+                    f,
+                    "- {COLOR_YELLOW}INFO{COLOR_RESET}: The RTC backup cell is running low. Please replace it soon.",
+                )?;
+            }
+            BackupState::NotFitted | BackupState::Okay => {}
+        }
+
         if self.iobus_fault {
             writeln!(
                 f,
@@ -166,15 +222,30 @@ impl Display for Motd {
             )?;
         }
 
+        if self.webui_corrupted {
+            writeln!(
+                f,
+                "- {COLOR_RED}WARNING{COLOR_RESET}: Some files of the web interface are damaged. This hints at",
+            )?;
+            writeln!(
+                f,
+                "  a failing storage. Please re-install the software via \"rauc install\"."
+            )?;
+        }
+
         Ok(())
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn run(
     wtb: &mut WatchedTasksBuilder,
     dut_pwr: &crate::dut_power::DutPwrThread,
+    http_server: &crate::http_server::HttpServer,
     iobus: &crate::iobus::IoBus,
+    locator: &Arc<Topic<Locator>>,
     rauc: &crate::dbus::Rauc,
+    rtc: &crate::rtc::Rtc,
     setup_mode: &crate::setup_mode::SetupMode,
     temperatures: &crate::temperatures::Temperatures,
     usb_hub: &crate::usb_hub::UsbHub,
@@ -187,11 +258,15 @@ pub fn run(
     // Spawn a task that accepts motd updates and dumps them into the file in /var/run.
     let (state_events, _) = dut_pwr.state.clone().subscribe_unbounded();
     let (fault_events, _) = iobus.supply_fault.clone().subscribe_unbounded();
+    let (locator_events, _) = locator.clone().subscribe_unbounded();
+    let (eta_events, _) = rauc.eta.clone().subscribe_unbounded();
     let (should_reboot_events, _) = rauc.should_reboot.clone().subscribe_unbounded();
     let (channels_events, _) = rauc.channels.clone().subscribe_unbounded();
+    let (rtc_events, _) = rtc.backup.clone().subscribe_unbounded();
     let (setup_mode_events, _) = setup_mode.setup_mode.clone().subscribe_unbounded();
     let (temperature_events, _) = temperatures.warning.clone().subscribe_unbounded();
     let (usb_events, _) = usb_hub.overload.clone().subscribe_unbounded();
+    let (webui_events, _) = http_server.webui_integrity.clone().subscribe_unbounded();
 
     wtb.spawn_task("motd-file-service", async move {
         loop {
@@ -202,6 +277,12 @@ pub fn run(
                 update = fault_events.recv().fuse() => {
                     motd.iobus_fault = update?;
                 },
+                update = locator_events.recv().fuse() => {
+                    motd.locator = update?;
+                },
+                update = eta_events.recv().fuse() => {
+                    motd.rauc_eta = update?;
+                },
                 update = should_reboot_events.recv().fuse() => {
                     motd.rauc_should_reboot = update?;
                 },
@@ -216,6 +297,9 @@ pub fn run(
                         })
                         .collect();
                 },
+                update = rtc_events.recv().fuse() => {
+                    motd.rtc_backup = update?.state;
+                },
                 update = setup_mode_events.recv().fuse() => {
                     motd.setup_mode_active = update?;
                 },
@@ -228,6 +312,9 @@ pub fn run(
                 update = usb_events.recv().fuse() => {
                     motd.usb_overload = update?;
                 },
+                update = webui_events.recv().fuse() => {
+                    motd.webui_corrupted = update?.is_corrupted();
+                },
             };
 
             motd.update()?;
@@ -241,12 +328,13 @@ impl Motd {
     /// Create a motd in a tmpfs so we can write it without harming the eMMC
     fn new() -> Result<Self> {
         // Create /var/run/tacd (or an equivalent in demo mode).
-        create_dir_all(VAR_RUN_TACD)?;
+        let var_run_tacd = fs_root::path(VAR_RUN_TACD);
+        create_dir_all(&var_run_tacd)?;
 
-        // "/var/run/tacd/motd" or "demo_files/var/run/tacd/motd"
-        // "/etc/motd" or "demo_files/etc/motd"
-        let path_runtime_motd = Path::new(VAR_RUN_TACD).join("motd");
-        let path_etc_motd = Path::new(ETC).join("motd");
+        // "/var/run/tacd/motd" or e.g. "demo_files/var/run/tacd/motd"
+        // "/etc/motd" or e.g. "demo_files/etc/motd"
+        let path_runtime_motd = var_run_tacd.join("motd");
+        let path_etc_motd = fs_root::path(ETC).join("motd");
 
         // Create the motd file in /var/run/tacd.
         let runtime_motd = File::create(&path_runtime_motd)?;
@@ -277,11 +365,15 @@ impl Motd {
         Ok(Self {
             dut_pwr_state: OutputState::Off,
             iobus_fault: false,
+            locator: Locator::stopped(),
+            rauc_eta: None,
             rauc_should_reboot: false,
             rauc_update_urls: Vec::new(),
+            rtc_backup: BackupState::NotFitted,
             setup_mode_active: false,
             temperature_warning: false,
             usb_overload: None,
+            webui_corrupted: false,
             handle: runtime_motd,
         })
     }
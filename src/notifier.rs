@@ -0,0 +1,885 @@
+// This file is part of tacd, the LXA TAC system daemon
+// Copyright (C) 2024 Pengutronix e.K.
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along
+// with this program; if not, write to the Free Software Foundation, Inc.,
+// 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+
+//! Notify someone outside of the lab about faults on the TAC
+//!
+//! Faults like a DUT overcurrent are easily missed if nobody looks at the
+//! TAC. The notifier watches the topics that report such faults and sends
+//! a notification to the configured webhooks (as JSON `POST` requests)
+//! and / or via e-mail using an SMTP server.
+//! Notifications can be turned off per event type.
+
+use std::fs::{rename, File, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Result};
+use async_std::channel::{bounded, Sender};
+use async_std::future::timeout;
+use async_std::net::TcpStream;
+use async_std::sync::Arc;
+use async_tls::TlsConnector;
+use base64::Engine;
+use futures::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use futures::StreamExt;
+use log::{info, warn};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{from_reader, to_vec_pretty};
+use surf::Url;
+
+use crate::broker::{BrokerBuilder, Topic};
+use crate::dbus::Rauc;
+use crate::dut_power::{MaintenanceOverride, OutputState};
+use crate::fs_root;
+use crate::measurement::Timestamp;
+use crate::temperatures::Warning;
+use crate::usb_hub::OverloadedPort;
+use crate::watched_tasks::WatchedTasksBuilder;
+
+const CONFIG_PATH: &str = "/srv/tacd/notifier.json";
+
+/// Notifications that were not delivered yet. Further notifications are
+/// dropped if the delivery can not keep up.
+const QUEUE_LENGTH: usize = 16;
+
+/// Give up on a single delivery (webhook request or SMTP session)
+/// that takes longer than this.
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FaultEvent {
+    DutOverCurrent,
+    UsbOverload,
+    TemperatureWarning,
+    UpdateError,
+    MaintenanceOverride,
+}
+
+impl FaultEvent {
+    const ALL: [Self; 5] = [
+        Self::DutOverCurrent,
+        Self::UsbOverload,
+        Self::TemperatureWarning,
+        Self::UpdateError,
+        Self::MaintenanceOverride,
+    ];
+
+    fn topic_name(&self) -> &'static str {
+        match self {
+            Self::DutOverCurrent => "dut_overcurrent",
+            Self::UsbOverload => "usb_overload",
+            Self::TemperatureWarning => "temperature_warning",
+            Self::UpdateError => "update_error",
+            Self::MaintenanceOverride => "maintenance_override",
+        }
+    }
+}
+
+/// The JSON body sent to the webhooks
+#[derive(Serialize, Clone, Debug)]
+pub struct Notification {
+    pub event: FaultEvent,
+    pub hostname: String,
+    pub ts: Timestamp,
+    pub message: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct SmtpConfig {
+    pub host: String,
+    pub port: u16,
+    /// Use implicit TLS (usually on port 465). STARTTLS is not supported.
+    pub tls: bool,
+    /// Authentication is only supported together with `tls`
+    pub username: Option<String>,
+    /// Only stored on the TAC and never sent out via the API.
+    /// Sending `None` keeps the stored password (as long as the server and
+    /// user stay the same), an empty string clears it.
+    pub password: Option<String>,
+    pub from: String,
+    pub to: Vec<String>,
+}
+
+impl SmtpConfig {
+    /// Resolve the password of a config requested via the API
+    ///
+    /// The stored password is only kept if the request connects to the
+    /// same server, in the same way, as the same user. Otherwise any web
+    /// client could point the notifier at their own server and receive
+    /// the stored credentials.
+    fn resolve_password(&mut self, current: Option<&Self>) {
+        let stored = current
+            .filter(|current| {
+                self.host == current.host
+                    && self.port == current.port
+                    && self.tls == current.tls
+                    && self.username == current.username
+            })
+            .and_then(|current| current.password.clone());
+
+        self.password = match self.password.take() {
+            None => stored,
+            Some(pw) if pw.is_empty() => None,
+            Some(pw) => Some(pw),
+        };
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug, Default)]
+pub struct NotifierConfig {
+    /// URLs to `POST` a `Notification` to
+    pub webhooks: Vec<String>,
+    pub smtp: Option<SmtpConfig>,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug, Default)]
+pub struct NotifierStatus {
+    /// Notifications delivered to all configured backends
+    pub sent: u64,
+    /// Notifications that could not be delivered to at least one backend
+    pub failed: u64,
+    /// Notifications dropped because the delivery could not keep up
+    pub dropped: u64,
+    pub last_error: Option<String>,
+}
+
+impl NotifierConfig {
+    fn load(path: &Path) -> Result<Self> {
+        if !path.is_file() {
+            return Ok(Self::default());
+        }
+
+        Ok(from_reader(File::open(path)?)?)
+    }
+
+    /// Store the config in a file only we can read, as it contains the password
+    fn save(&self, path: &Path) -> Result<()> {
+        let path_tmp = path.with_extension("tmp");
+
+        {
+            let mut fd = OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .mode(0o600)
+                .open(&path_tmp)?;
+
+            fd.write_all(&to_vec_pretty(self)?)?;
+            fd.sync_all()?;
+        }
+
+        rename(path_tmp, path)?;
+
+        Ok(())
+    }
+
+    /// The config as it is shown via the API
+    fn redacted(&self) -> Self {
+        Self {
+            webhooks: self.webhooks.clone(),
+            smtp: self.smtp.clone().map(|smtp| SmtpConfig {
+                password: None,
+                ..smtp
+            }),
+        }
+    }
+
+    fn validate(&self) -> Result<()> {
+        for webhook in self.webhooks.iter() {
+            // surf panics on URLs it can not parse, so check them beforehand
+            let url = Url::parse(webhook).map_err(|e| anyhow!("\"{webhook}\": {e}"))?;
+
+            if !matches!(url.scheme(), "http" | "https") {
+                bail!("\"{webhook}\" is not a http(s) URL");
+            }
+        }
+
+        if let Some(smtp) = &self.smtp {
+            if smtp.host.is_empty() {
+                bail!("A host is required to send e-mails");
+            }
+
+            if smtp.to.is_empty() {
+                bail!("At least one recipient is required to send e-mails");
+            }
+
+            // STARTTLS is not supported, so credentials would be sent in
+            // the clear
+            if smtp.username.is_some() && !smtp.tls {
+                bail!("Authentication is only supported with TLS");
+            }
+
+            // The addresses end up in SMTP commands and mail headers
+            for addr in smtp.to.iter().chain([&smtp.from]) {
+                if !addr.contains('@') || addr.contains(['<', '>', '\r', '\n']) {
+                    bail!("\"{addr}\" is not a valid e-mail address");
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Describe the change from `prev` to `new`, if it is worth a notification
+fn dut_power_fault(prev: &OutputState, new: &OutputState) -> Option<String> {
+    if *prev != OutputState::OverCurrent && *new == OutputState::OverCurrent {
+        Some("The DUT power was turned off due to an overcurrent".to_string())
+    } else {
+        None
+    }
+}
+
+fn usb_overload(prev: &Option<OverloadedPort>, new: &Option<OverloadedPort>) -> Option<String> {
+    match new {
+        Some(port) if prev.as_ref() != Some(port) => {
+            Some(format!("The USB host port(s) are overloaded ({port:?})"))
+        }
+        _ => None,
+    }
+}
+
+fn temperature_warning(prev: &Warning, new: &Warning) -> Option<String> {
+    match (prev, new) {
+        (Warning::Okay, Warning::SocHigh) => {
+            Some("The SoC temperature is getting high".to_string())
+        }
+        (Warning::Okay | Warning::SocHigh, Warning::SocCritical) => {
+            Some("The SoC temperature is critical".to_string())
+        }
+        _ => None,
+    }
+}
+
+fn update_error(prev: &str, new: &str) -> Option<String> {
+    if !new.is_empty() && prev != new {
+        Some(format!("Installing an update failed: {new}"))
+    } else {
+        None
+    }
+}
+
+/// Report both the start and the end of a maintenance override, as the DUT
+/// power protections are relaxed in between
+fn maintenance_override(
+    prev: &Option<MaintenanceOverride>,
+    new: &Option<MaintenanceOverride>,
+) -> Option<String> {
+    match (prev, new) {
+        (_, Some(ov)) => Some(format!(
+            "The DUT power protections were relaxed for {}s (current limit {}A): {}",
+            ov.duration_s, ov.current_limit, ov.reason
+        )),
+        (Some(_), None) => Some("The DUT power protections were restored".to_string()),
+        (None, None) => None,
+    }
+}
+
+/// Hands notifications over to the delivery task
+#[derive(Clone)]
+struct Dispatcher {
+    hostname: Arc<Topic<String>>,
+    queue: Sender<Notification>,
+    status: Arc<Topic<NotifierStatus>>,
+}
+
+impl Dispatcher {
+    fn notify(&self, event: FaultEvent, message: String) {
+        info!("Sending notification: {message}");
+
+        let notification = Notification {
+            event,
+            hostname: self.hostname.try_get().unwrap_or_default(),
+            ts: Timestamp::now(),
+            message,
+        };
+
+        if self.queue.try_send(notification).is_err() {
+            warn!("Dropping notification, the delivery does not keep up");
+
+            self.status.modify(|status| {
+                let mut status = status.unwrap_or_default();
+                status.dropped += 1;
+                Some(status)
+            });
+        }
+    }
+}
+
+/// Send a notification whenever `describe` finds a change in `topic` worth
+/// reporting (and notifications for `event` are enabled)
+fn watch<E, F>(
+    wtb: &mut WatchedTasksBuilder,
+    topic: Arc<Topic<E>>,
+    event: FaultEvent,
+    enabled: Arc<Topic<bool>>,
+    dispatcher: Dispatcher,
+    describe: F,
+) -> Result<()>
+where
+    E: Serialize + DeserializeOwned + Clone + Send + Sync + 'static,
+    F: Fn(&E, &E) -> Option<String> + Send + 'static,
+{
+    let (mut events, _) = topic.subscribe_unbounded();
+    let name = format!("notifier-watch-{}", event.topic_name());
+
+    wtb.spawn_task(name, async move {
+        // The state at startup is not a change and thus not reported
+        let mut prev = match events.next().await {
+            Some(prev) => prev,
+            None => return Ok(()),
+        };
+
+        while let Some(new) = events.next().await {
+            if let Some(message) = describe(&prev, &new) {
+                if enabled.try_get().unwrap_or(false) {
+                    dispatcher.notify(event, message);
+                }
+            }
+
+            prev = new;
+        }
+
+        Ok(())
+    })
+}
+
+async fn send_webhook(url: &str, notification: &Notification) -> Result<()> {
+    let url = Url::parse(url)?;
+
+    let request = async {
+        let res = surf::post(url)
+            .body_json(notification)
+            .map_err(|e| anyhow!("{e}"))?
+            .await
+            .map_err(|e| anyhow!("{e}"))?;
+
+        if !res.status().is_success() {
+            bail!("Webhook responded with {}", res.status());
+        }
+
+        Ok(())
+    };
+
+    timeout(DELIVERY_TIMEOUT, request).await?
+}
+
+/// Read an SMTP reply and make sure it has the `expected` status code
+async fn smtp_reply<S>(conn: &mut BufReader<S>, expected: &str) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut line = String::new();
+
+    loop {
+        line.clear();
+
+        if conn.read_line(&mut line).await? == 0 {
+            bail!("The SMTP server closed the connection");
+        }
+
+        // Multiline replies use a "-" instead of a " " after the code
+        // on all but the last line.
+        if line.as_bytes().get(3) != Some(&b'-') {
+            break;
+        }
+    }
+
+    if !line.starts_with(expected) {
+        bail!("Unexpected SMTP reply: {}", line.trim_end());
+    }
+
+    Ok(())
+}
+
+async fn smtp_command<S>(conn: &mut BufReader<S>, command: &str, expected: &str) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    conn.get_mut()
+        .write_all(format!("{command}\r\n").as_bytes())
+        .await?;
+
+    smtp_reply(conn, expected).await
+}
+
+/// Build the mail for `notification`, already escaped for the DATA command
+fn mail_body(smtp: &SmtpConfig, notification: &Notification) -> String {
+    let subject = format!("[{}] {:?}", notification.hostname, notification.event);
+
+    let mut mail = format!(
+        "From: <{}>\r\nTo: {}\r\nSubject: {subject}\r\n\
+         Content-Type: text/plain; charset=utf-8\r\n\r\n",
+        smtp.from,
+        smtp.to
+            .iter()
+            .map(|to| format!("<{to}>"))
+            .collect::<Vec<_>>()
+            .join(", "),
+    );
+
+    for line in notification.message.lines() {
+        // Lines starting with a dot have to be escaped, as a single dot
+        // ends the mail.
+        if line.starts_with('.') {
+            mail.push('.');
+        }
+
+        mail.push_str(line);
+        mail.push_str("\r\n");
+    }
+
+    mail.push('.');
+
+    mail
+}
+
+async fn smtp_session<S>(smtp: &SmtpConfig, stream: S, notification: &Notification) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut conn = BufReader::new(stream);
+
+    smtp_reply(&mut conn, "220").await?;
+    smtp_command(&mut conn, "EHLO tacd", "250").await?;
+
+    if let Some(username) = &smtp.username {
+        if !smtp.tls {
+            bail!("Refusing to authenticate without TLS");
+        }
+
+        let password = smtp.password.as_deref().unwrap_or_default();
+        let credentials =
+            base64::engine::general_purpose::STANDARD.encode(format!("\0{username}\0{password}"));
+
+        smtp_command(&mut conn, &format!("AUTH PLAIN {credentials}"), "235").await?;
+    }
+
+    smtp_command(&mut conn, &format!("MAIL FROM:<{}>", smtp.from), "250").await?;
+
+    for to in smtp.to.iter() {
+        smtp_command(&mut conn, &format!("RCPT TO:<{to}>"), "250").await?;
+    }
+
+    smtp_command(&mut conn, "DATA", "354").await?;
+    smtp_command(&mut conn, &mail_body(smtp, notification), "250").await?;
+    smtp_command(&mut conn, "QUIT", "221").await?;
+
+    Ok(())
+}
+
+async fn send_mail(smtp: &SmtpConfig, notification: &Notification) -> Result<()> {
+    let session = async {
+        let tcp = TcpStream::connect((smtp.host.as_str(), smtp.port)).await?;
+
+        if smtp.tls {
+            let tls = TlsConnector::default().connect(&smtp.host, tcp).await?;
+            smtp_session(smtp, tls, notification).await
+        } else {
+            smtp_session(smtp, tcp, notification).await
+        }
+    };
+
+    timeout(DELIVERY_TIMEOUT, session).await?
+}
+
+/// Deliver `notification` to all configured backends
+async fn deliver(config: &NotifierConfig, notification: &Notification) -> Result<()> {
+    let mut errors = Vec::new();
+
+    for webhook in config.webhooks.iter() {
+        if let Err(e) = send_webhook(webhook, notification).await {
+            errors.push(format!("{webhook}: {e}"));
+        }
+    }
+
+    if let Some(smtp) = &config.smtp {
+        if let Err(e) = send_mail(smtp, notification).await {
+            errors.push(format!("{}: {e}", smtp.host));
+        }
+    }
+
+    match errors.is_empty() {
+        true => Ok(()),
+        false => Err(anyhow!(errors.join(", "))),
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn run(
+    bb: &mut BrokerBuilder,
+    wtb: &mut WatchedTasksBuilder,
+    hostname: Arc<Topic<String>>,
+    dut_pwr_state: Arc<Topic<OutputState>>,
+    dut_pwr_override: Arc<Topic<Option<MaintenanceOverride>>>,
+    usb_overload_topic: Arc<Topic<Option<OverloadedPort>>>,
+    temperature_warning_topic: Arc<Topic<Warning>>,
+    rauc: &Rauc,
+) -> Result<()> {
+    let initial = NotifierConfig::load(&fs_root::path(CONFIG_PATH)).unwrap_or_else(|e| {
+        warn!("Failed to load notifier config: {e}");
+        NotifierConfig::default()
+    });
+
+    // The config contains the SMTP password, so the full config is kept in
+    // an anonymous topic and only a redacted version is exposed.
+    let config_ro = bb.topic_ro("/v1/tac/notifier/config", Some(initial.redacted()));
+    let requests = bb.topic_wo::<NotifierConfig>("/v1/tac/notifier/config", None);
+    let status = bb.topic_ro("/v1/tac/notifier/status", Some(NotifierStatus::default()));
+    let config = Topic::anonymous(Some(initial));
+
+    let config_task = config.clone();
+    let (mut requests, _) = requests.subscribe_unbounded();
+
+    wtb.spawn_task("notifier-config", async move {
+        while let Some(mut req) = requests.next().await {
+            let current = config_task.try_get().unwrap_or_default();
+
+            if let Some(smtp) = req.smtp.as_mut() {
+                smtp.resolve_password(current.smtp.as_ref());
+            }
+
+            if let Err(e) = req.validate() {
+                warn!("Refusing invalid notifier config: {e}");
+                continue;
+            }
+
+            if let Err(e) = req.save(&fs_root::path(CONFIG_PATH)) {
+                warn!("Failed to save notifier config: {e}");
+            }
+
+            config_ro.set(req.redacted());
+            config_task.set(req);
+        }
+
+        Ok(())
+    })?;
+
+    let (queue, queue_rx) = bounded(QUEUE_LENGTH);
+
+    let dispatcher = Dispatcher {
+        hostname,
+        queue,
+        status: status.clone(),
+    };
+
+    for event in FaultEvent::ALL {
+        let path = format!("/v1/tac/notifier/enabled/{}", event.topic_name());
+        let enabled = bb.topic(&path, true, true, true, Some(true), 1);
+        let dispatcher = dispatcher.clone();
+
+        match event {
+            FaultEvent::DutOverCurrent => watch(
+                wtb,
+                dut_pwr_state.clone(),
+                event,
+                enabled,
+                dispatcher,
+                dut_power_fault,
+            )?,
+            FaultEvent::UsbOverload => watch(
+                wtb,
+                usb_overload_topic.clone(),
+                event,
+                enabled,
+                dispatcher,
+                usb_overload,
+            )?,
+            FaultEvent::TemperatureWarning => watch(
+                wtb,
+                temperature_warning_topic.clone(),
+                event,
+                enabled,
+                dispatcher,
+                temperature_warning,
+            )?,
+            FaultEvent::UpdateError => watch(
+                wtb,
+                rauc.last_error.clone(),
+                event,
+                enabled,
+                dispatcher,
+                |prev: &String, new: &String| update_error(prev, new),
+            )?,
+            FaultEvent::MaintenanceOverride => watch(
+                wtb,
+                dut_pwr_override.clone(),
+                event,
+                enabled,
+                dispatcher,
+                maintenance_override,
+            )?,
+        }
+    }
+
+    wtb.spawn_task("notifier-deliver", async move {
+        while let Ok(notification) = queue_rx.recv().await {
+            let config = config.try_get().unwrap_or_default();
+            let res = deliver(&config, &notification).await;
+
+            status.modify(|status| {
+                let mut status = status.unwrap_or_default();
+
+                match res {
+                    Ok(()) => status.sent += 1,
+                    Err(e) => {
+                        warn!("Failed to deliver notification: {e}");
+                        status.failed += 1;
+                        status.last_error = Some(e.to_string());
+                    }
+                }
+
+                Some(status)
+            });
+        }
+
+        Ok(())
+    })?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    use async_std::task::block_on;
+    use futures::io::{AsyncRead, AsyncWrite, Cursor};
+
+    use super::{
+        dut_power_fault, maintenance_override, smtp_session, temperature_warning, update_error,
+        usb_overload, FaultEvent, Notification, NotifierConfig, SmtpConfig,
+    };
+    use crate::dut_power::{MaintenanceOverride, OutputState};
+    use crate::measurement::Timestamp;
+    use crate::temperatures::Warning;
+    use crate::usb_hub::OverloadedPort;
+
+    /// A fake SMTP connection that replies from a script and records the
+    /// commands it receives
+    struct Scripted {
+        replies: Cursor<Vec<u8>>,
+        sent: Vec<u8>,
+    }
+
+    impl AsyncRead for Scripted {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut [u8],
+        ) -> Poll<std::io::Result<usize>> {
+            Pin::new(&mut self.replies).poll_read(cx, buf)
+        }
+    }
+
+    impl AsyncWrite for Scripted {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<std::io::Result<usize>> {
+            self.sent.extend_from_slice(buf);
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    fn smtp_config() -> SmtpConfig {
+        SmtpConfig {
+            host: "mail.example.com".to_string(),
+            port: 25,
+            tls: false,
+            username: None,
+            password: None,
+            from: "tac@example.com".to_string(),
+            to: vec!["lab@example.com".to_string()],
+        }
+    }
+
+    #[test]
+    fn events() {
+        println!("Only new faults are reported");
+        assert!(dut_power_fault(&OutputState::On, &OutputState::OverCurrent).is_some());
+        assert!(dut_power_fault(&OutputState::OverCurrent, &OutputState::Off).is_none());
+        assert!(dut_power_fault(&OutputState::Off, &OutputState::On).is_none());
+
+        assert!(usb_overload(&None, &Some(OverloadedPort::Port1)).is_some());
+        assert!(usb_overload(&Some(OverloadedPort::Port1), &Some(OverloadedPort::Port1)).is_none());
+        assert!(usb_overload(&Some(OverloadedPort::Port1), &None).is_none());
+
+        println!("Temperature warnings are reported when they get worse");
+        assert!(temperature_warning(&Warning::Okay, &Warning::SocHigh).is_some());
+        assert!(temperature_warning(&Warning::SocHigh, &Warning::SocCritical).is_some());
+        assert!(temperature_warning(&Warning::SocCritical, &Warning::SocHigh).is_none());
+        assert!(temperature_warning(&Warning::SocHigh, &Warning::Okay).is_none());
+
+        println!("Clearing the RAUC error is not an error");
+        assert!(update_error("", "No space left").is_some());
+        assert!(update_error("No space left", "").is_none());
+
+        println!("Maintenance overrides are reported when they start and end");
+        let ov = MaintenanceOverride {
+            current_limit: 7.5,
+            grace_period_ms: 2000,
+            duration_s: 600,
+            reason: "Inrush of the new carrier board".to_string(),
+            expires: Timestamp::now(),
+        };
+        assert!(maintenance_override(&None, &Some(ov.clone())).is_some());
+        assert!(maintenance_override(&Some(ov), &None).is_some());
+        assert!(maintenance_override(&None, &None).is_none());
+    }
+
+    #[test]
+    fn config() {
+        let mut config = NotifierConfig {
+            webhooks: vec!["https://chat.example.com/hooks/tac".to_string()],
+            smtp: Some(SmtpConfig {
+                password: Some("secret".to_string()),
+                ..smtp_config()
+            }),
+        };
+
+        println!("The password is not exposed");
+        assert!(config.validate().is_ok());
+        assert!(config.redacted().smtp.unwrap().password.is_none());
+
+        println!("Invalid webhooks and addresses are refused");
+        config.webhooks = vec!["file:///etc/shadow".to_string()];
+        assert!(config.validate().is_err());
+
+        config.webhooks.clear();
+        config.smtp.as_mut().unwrap().to = vec!["lab@example.com>\r\nBcc: <x@y".to_string()];
+        assert!(config.validate().is_err());
+
+        println!("Authentication requires TLS");
+        let plain = NotifierConfig {
+            webhooks: Vec::new(),
+            smtp: Some(SmtpConfig {
+                username: Some("tac".to_string()),
+                ..smtp_config()
+            }),
+        };
+        assert!(plain.validate().is_err());
+    }
+
+    #[test]
+    fn passwords() {
+        let stored = SmtpConfig {
+            tls: true,
+            port: 465,
+            username: Some("tac".to_string()),
+            password: Some("secret".to_string()),
+            ..smtp_config()
+        };
+
+        let request = || SmtpConfig {
+            password: None,
+            ..stored.clone()
+        };
+
+        println!("The stored password is kept for the same server");
+        let mut req = request();
+        req.resolve_password(Some(&stored));
+        assert_eq!(req.password, stored.password);
+
+        println!("But not if the server or user change");
+        let changes: [fn(&mut SmtpConfig); 4] = [
+            |req| req.host = "evil.example.com".to_string(),
+            |req| req.port = 587,
+            |req| req.tls = false,
+            |req| req.username = Some("mallory".to_string()),
+        ];
+
+        for change in changes {
+            let mut req = request();
+            change(&mut req);
+            req.resolve_password(Some(&stored));
+            assert_eq!(req.password, None);
+        }
+
+        println!("Empty passwords clear the stored one");
+        let mut req = SmtpConfig {
+            password: Some(String::new()),
+            ..stored.clone()
+        };
+        req.resolve_password(Some(&stored));
+        assert_eq!(req.password, None);
+    }
+
+    #[test]
+    fn smtp() {
+        let notification = Notification {
+            event: FaultEvent::DutOverCurrent,
+            hostname: "lxatac-00010".to_string(),
+            ts: Timestamp::now(),
+            message: "Overcurrent\n.\nDone".to_string(),
+        };
+
+        let replies = "220 mail.example.com ESMTP\r\n\
+                       250-mail.example.com\r\n250 SIZE 1000000\r\n\
+                       250 Ok\r\n250 Ok\r\n354 Go ahead\r\n250 Queued\r\n221 Bye\r\n";
+
+        let mut conn = Scripted {
+            replies: Cursor::new(replies.as_bytes().to_vec()),
+            sent: Vec::new(),
+        };
+
+        println!("A complete session is accepted");
+        block_on(smtp_session(&smtp_config(), &mut conn, &notification)).unwrap();
+
+        let sent = String::from_utf8(conn.sent).unwrap();
+        assert!(sent.starts_with("EHLO tacd\r\nMAIL FROM:<tac@example.com>\r\n"));
+        assert!(sent.contains("Subject: [lxatac-00010] DutOverCurrent\r\n"));
+
+        println!("Lone dots in the message are escaped");
+        assert!(sent.contains("\r\nOvercurrent\r\n..\r\nDone\r\n.\r\nQUIT\r\n"));
+
+        println!("Errors reported by the server fail the delivery");
+        let mut conn = Scripted {
+            replies: Cursor::new(b"554 No service\r\n".to_vec()),
+            sent: Vec::new(),
+        };
+
+        assert!(block_on(smtp_session(&smtp_config(), &mut conn, &notification)).is_err());
+
+        println!("Credentials are never sent over plain connections");
+        let replies = "220 mail.example.com ESMTP\r\n250 mail.example.com\r\n";
+        let mut conn = Scripted {
+            replies: Cursor::new(replies.as_bytes().to_vec()),
+            sent: Vec::new(),
+        };
+        let plain = SmtpConfig {
+            username: Some("tac".to_string()),
+            password: Some("secret".to_string()),
+            ..smtp_config()
+        };
+
+        assert!(block_on(smtp_session(&plain, &mut conn, &notification)).is_err());
+        assert!(!String::from_utf8(conn.sent).unwrap().contains("AUTH"));
+    }
+}
@@ -0,0 +1,475 @@
+// This file is part of tacd, the LXA TAC system daemon
+// Copyright (C) 2024 Pengutronix e.K.
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along
+// with this program; if not, write to the Free Software Foundation, Inc.,
+// 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+
+//! Monitor if the network stack of the DUT is up
+//!
+//! The DUT is probed periodically using ICMP echo requests or TCP
+//! connection attempts. Test scripts can wait for the DUT to become
+//! reachable instead of guessing how long it takes to boot.
+
+use std::collections::VecDeque;
+use std::net::IpAddr;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Result};
+use async_std::future::timeout;
+use async_std::prelude::*;
+use async_std::sync::Arc;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::broker::{BrokerBuilder, Topic};
+use crate::watched_tasks::WatchedTasksBuilder;
+
+const CONFIG_PATH: &str = "/v1/dut/network/reachable/config";
+
+/// Probes that were not answered within this time count as lost
+const PROBE_TIMEOUT: Duration = Duration::from_secs(1);
+
+const MIN_INTERVAL: Duration = PROBE_TIMEOUT;
+const MAX_INTERVAL: Duration = Duration::from_secs(600);
+const MAX_THRESHOLD: u32 = 100;
+
+/// Number of recent probes the latency and loss statistics are based on
+const STATS_WINDOW: usize = 20;
+
+#[cfg(feature = "demo_mode")]
+mod probe {
+    use std::net::IpAddr;
+    use std::time::Duration;
+
+    use anyhow::Result;
+    use rand::{thread_rng, Rng};
+
+    /// The demo DUT always answers with a bit of jitter
+    pub(super) async fn icmp(_addr: IpAddr) -> Result<Duration> {
+        Ok(Duration::from_micros(thread_rng().gen_range(300..700)))
+    }
+
+    pub(super) async fn tcp(addr: IpAddr, _port: u16) -> Result<Duration> {
+        icmp(addr).await
+    }
+}
+
+#[cfg(not(feature = "demo_mode"))]
+mod probe {
+    use std::io::ErrorKind;
+    use std::net::IpAddr;
+    use std::process::Command;
+    use std::time::{Duration, Instant};
+
+    use anyhow::{anyhow, bail, Result};
+    use async_std::net::TcpStream;
+    use async_std::task::spawn_blocking;
+
+    use super::{parse_ping_time, PROBE_TIMEOUT};
+
+    /// Send a single ICMP echo request using the ping command, which has
+    /// the privileges to do so
+    pub(super) async fn icmp(addr: IpAddr) -> Result<Duration> {
+        spawn_blocking(move || {
+            let output = Command::new("ping")
+                .arg("-n")
+                .args(["-c", "1"])
+                .args(["-W", &PROBE_TIMEOUT.as_secs().to_string()])
+                .arg(addr.to_string())
+                .output()?;
+
+            if !output.status.success() {
+                bail!("No reply from {addr}");
+            }
+
+            parse_ping_time(&String::from_utf8_lossy(&output.stdout))
+                .ok_or_else(|| anyhow!("Failed to parse the ping output"))
+        })
+        .await
+    }
+
+    /// Try to open a TCP connection to the DUT
+    ///
+    /// A refused connection still means that the network stack of the DUT
+    /// is up, so it counts as reachable as well.
+    pub(super) async fn tcp(addr: IpAddr, port: u16) -> Result<Duration> {
+        let start = Instant::now();
+
+        match TcpStream::connect((addr, port)).await {
+            Ok(_) => Ok(start.elapsed()),
+            Err(e) if e.kind() == ErrorKind::ConnectionRefused => Ok(start.elapsed()),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(default)]
+pub struct ReachabilityConfig {
+    /// IP address of the DUT. The monitor is disabled if unset.
+    pub target: Option<String>,
+    /// Probe using TCP connections to this port instead of ICMP echo
+    /// requests, e.g. if the DUT does not answer to those
+    pub tcp_port: Option<u16>,
+    pub interval_ms: u64,
+    /// Number of consecutive probes that have to be answered (or lost)
+    /// before the DUT is considered (un)reachable
+    pub threshold: u32,
+}
+
+impl Default for ReachabilityConfig {
+    fn default() -> Self {
+        Self {
+            target: None,
+            tcp_port: None,
+            interval_ms: 1000,
+            threshold: 3,
+        }
+    }
+}
+
+impl ReachabilityConfig {
+    /// Check a config requested via the API and get the address to probe
+    fn check(&self) -> Result<Option<IpAddr>> {
+        let interval = Duration::from_millis(self.interval_ms);
+
+        if !(MIN_INTERVAL..=MAX_INTERVAL).contains(&interval) {
+            bail!(
+                "Interval {}ms is not between {}ms and {}ms",
+                self.interval_ms,
+                MIN_INTERVAL.as_millis(),
+                MAX_INTERVAL.as_millis()
+            );
+        }
+
+        if !(1..=MAX_THRESHOLD).contains(&self.threshold) {
+            bail!(
+                "Threshold {} is not between 1 and {MAX_THRESHOLD}",
+                self.threshold
+            );
+        }
+
+        if self.tcp_port == Some(0) {
+            bail!("Port 0 can not be probed");
+        }
+
+        let target = match self.target.as_deref() {
+            Some(target) => target,
+            None => return Ok(None),
+        };
+
+        let addr: IpAddr = target
+            .parse()
+            .map_err(|_| anyhow!("\"{target}\" is not an IP address"))?;
+
+        if addr.is_unspecified() || addr.is_multicast() {
+            bail!("\"{target}\" can not be probed");
+        }
+
+        Ok(Some(addr))
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug, Default)]
+pub struct Reachability {
+    /// The last `threshold` probes were answered.
+    /// Stays true until `threshold` probes in a row were lost.
+    pub reachable: bool,
+    pub target: Option<String>,
+    /// Number of recent probes the statistics below are based on
+    pub probes: u32,
+    /// Number of recent probes that were not answered
+    pub lost: u32,
+    /// Round trip time of the last probe, if it was answered
+    pub last_ms: Option<f64>,
+    pub min_ms: Option<f64>,
+    pub avg_ms: Option<f64>,
+    pub max_ms: Option<f64>,
+}
+
+/// Keep track of the probe results for a single target
+struct Monitor {
+    threshold: u32,
+    reachable: bool,
+    /// Number of consecutive probes with the same outcome
+    streak: u32,
+    last_answered: bool,
+    recent: VecDeque<Option<Duration>>,
+}
+
+impl Monitor {
+    fn new(threshold: u32) -> Self {
+        Self {
+            threshold,
+            reachable: false,
+            streak: 0,
+            last_answered: false,
+            recent: VecDeque::with_capacity(STATS_WINDOW + 1),
+        }
+    }
+
+    /// Record the round trip time of an answered probe or `None` for a
+    /// lost one. Returns true if the reachability changed.
+    fn record(&mut self, rtt: Option<Duration>) -> bool {
+        let answered = rtt.is_some();
+
+        self.recent.push_back(rtt);
+
+        if self.recent.len() > STATS_WINDOW {
+            self.recent.pop_front();
+        }
+
+        if answered == self.last_answered {
+            self.streak = self.streak.saturating_add(1);
+        } else {
+            self.last_answered = answered;
+            self.streak = 1;
+        }
+
+        let changed = self.streak >= self.threshold && self.reachable != answered;
+
+        if changed {
+            self.reachable = answered;
+        }
+
+        changed
+    }
+
+    fn state(&self, target: &str) -> Reachability {
+        let ms = |rtt: Duration| rtt.as_micros() as f64 / 1000.0;
+        let answered: Vec<f64> = self.recent.iter().flatten().copied().map(ms).collect();
+
+        let min_ms = answered.iter().copied().reduce(f64::min);
+        let max_ms = answered.iter().copied().reduce(f64::max);
+        let avg_ms =
+            (!answered.is_empty()).then(|| answered.iter().sum::<f64>() / (answered.len() as f64));
+
+        Reachability {
+            reachable: self.reachable,
+            target: Some(target.to_owned()),
+            probes: self.recent.len() as u32,
+            lost: (self.recent.len() - answered.len()) as u32,
+            last_ms: self.recent.back().copied().flatten().map(ms),
+            min_ms,
+            avg_ms,
+            max_ms,
+        }
+    }
+}
+
+/// Get the round trip time from the output of the ping command
+#[cfg_attr(feature = "demo_mode", allow(dead_code))]
+fn parse_ping_time(output: &str) -> Option<Duration> {
+    let time = output
+        .split_whitespace()
+        .find_map(|w| w.strip_prefix("time="))?;
+    let ms: f64 = time.parse().ok()?;
+
+    (ms.is_finite() && ms >= 0.0).then(|| Duration::from_nanos((ms * 1e6).round() as u64))
+}
+
+async fn probe(addr: IpAddr, tcp_port: Option<u16>) -> Option<Duration> {
+    let res = match tcp_port {
+        Some(port) => timeout(PROBE_TIMEOUT, probe::tcp(addr, port)).await,
+        // ping enforces the timeout itself. Leave some headroom for starting it.
+        None => timeout(PROBE_TIMEOUT * 2, probe::icmp(addr)).await,
+    };
+
+    res.ok().and_then(Result::ok)
+}
+
+/// Probe the DUT as configured and publish whether it is reachable
+pub fn run(bb: &mut BrokerBuilder, wtb: &mut WatchedTasksBuilder) -> Result<()> {
+    // Use the "register a read-only and a write-only topic with the same
+    // name" trick to only report a config once it was validated.
+    // The requested config is persisted, so that the monitor keeps running
+    // after a restart of the tacd.
+    let request = bb.topic(
+        CONFIG_PATH,
+        false,
+        true,
+        true,
+        Some(ReachabilityConfig::default()),
+        1,
+    );
+    let config = bb.topic_ro(CONFIG_PATH, None);
+    let state: Arc<Topic<Reachability>> =
+        bb.topic_ro("/v1/dut/network/reachable", Some(Reachability::default()));
+
+    let (mut requests, _) = request.subscribe_unbounded();
+    let config_task = config.clone();
+
+    wtb.spawn_task("dut-reachability-config", async move {
+        while let Some(req) = requests.next().await {
+            match req.check() {
+                Ok(_) => config_task.set(req),
+                Err(e) => warn!("Refusing DUT reachability config {req:?}: {e}"),
+            }
+        }
+
+        Ok(())
+    })?;
+
+    let (mut configs, _) = config.subscribe_unbounded();
+
+    wtb.spawn_task("dut-reachability", async move {
+        let mut next = configs.next().await;
+
+        while let Some(config) = next.take() {
+            let interval = Duration::from_millis(config.interval_ms);
+
+            let (target, addr) = match (config.target.as_deref(), config.check()) {
+                (Some(target), Ok(Some(addr))) => (target, addr),
+                _ => {
+                    state.set_if_changed(Reachability::default());
+                    next = configs.next().await;
+                    continue;
+                }
+            };
+
+            let mut monitor = Monitor::new(config.threshold);
+
+            while next.is_none() {
+                let rtt = probe(addr, config.tcp_port).await;
+
+                if monitor.record(rtt) {
+                    match monitor.reachable {
+                        true => info!("DUT at {target} became reachable"),
+                        false => info!("DUT at {target} is no longer reachable"),
+                    }
+                }
+
+                state.set_if_changed(monitor.state(target));
+
+                // Go back to the start of the outer loop on config changes
+                match timeout(interval, configs.next()).await {
+                    Ok(Some(config)) => next = Some(config),
+                    Ok(None) => return Ok(()),
+                    Err(_) => {}
+                }
+            }
+        }
+
+        Ok(())
+    })?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{parse_ping_time, Monitor, ReachabilityConfig, STATS_WINDOW};
+
+    #[test]
+    fn config_validation() {
+        let config = |target: Option<&str>, interval_ms, threshold| ReachabilityConfig {
+            target: target.map(str::to_owned),
+            tcp_port: None,
+            interval_ms,
+            threshold,
+        };
+
+        println!("The default config is valid but disabled");
+        assert_eq!(ReachabilityConfig::default().check().unwrap(), None);
+
+        println!("IP addresses are accepted");
+        assert!(config(Some("192.168.1.2"), 1000, 3)
+            .check()
+            .unwrap()
+            .is_some());
+        assert!(config(Some("2001:db8::2"), 1000, 3)
+            .check()
+            .unwrap()
+            .is_some());
+
+        println!("Hostnames and unusable addresses are not");
+        assert!(config(Some("dut.example.com"), 1000, 3).check().is_err());
+        assert!(config(Some("0.0.0.0"), 1000, 3).check().is_err());
+        assert!(config(Some("224.0.0.1"), 1000, 3).check().is_err());
+
+        println!("Intervals and thresholds have to be in range");
+        assert!(config(None, 10, 3).check().is_err());
+        assert!(config(None, 3_600_000, 3).check().is_err());
+        assert!(config(None, 1000, 0).check().is_err());
+        assert!(config(None, 1000, 1000).check().is_err());
+
+        let port_zero = ReachabilityConfig {
+            tcp_port: Some(0),
+            ..config(Some("192.168.1.2"), 1000, 3)
+        };
+        assert!(port_zero.check().is_err());
+    }
+
+    #[test]
+    fn ping_output() {
+        let output = "PING 192.168.1.2 (192.168.1.2) 56(84) bytes of data.\n\
+                      64 bytes from 192.168.1.2: icmp_seq=1 ttl=64 time=0.412 ms\n";
+
+        assert_eq!(parse_ping_time(output), Some(Duration::from_micros(412)));
+
+        println!("Busybox ping uses a slightly different format");
+        let output = "64 bytes from 192.168.1.2: seq=0 ttl=64 time=1.000 ms\n";
+        assert_eq!(parse_ping_time(output), Some(Duration::from_millis(1)));
+
+        assert_eq!(parse_ping_time("1 packets transmitted, 0 received"), None);
+    }
+
+    #[test]
+    fn monitor() {
+        let ms = Duration::from_millis;
+        let mut monitor = Monitor::new(3);
+
+        println!("The DUT becomes reachable after three answered probes");
+        assert!(!monitor.record(Some(ms(1))));
+        assert!(!monitor.record(Some(ms(3))));
+        assert!(monitor.record(Some(ms(2))));
+        assert!(monitor.reachable);
+
+        let state = monitor.state("192.168.1.2");
+        assert_eq!(state.probes, 3);
+        assert_eq!(state.lost, 0);
+        assert_eq!(state.last_ms, Some(2.0));
+        assert_eq!(state.min_ms, Some(1.0));
+        assert_eq!(state.avg_ms, Some(2.0));
+        assert_eq!(state.max_ms, Some(3.0));
+
+        println!("Single lost probes do not make it unreachable");
+        assert!(!monitor.record(None));
+        assert!(!monitor.record(Some(ms(2))));
+        assert!(!monitor.record(None));
+        assert!(!monitor.record(None));
+        assert!(monitor.reachable);
+
+        let state = monitor.state("192.168.1.2");
+        assert_eq!(state.lost, 3);
+        assert_eq!(state.last_ms, None);
+
+        println!("But three lost probes in a row do");
+        assert!(monitor.record(None));
+        assert!(!monitor.reachable);
+
+        println!("Statistics only cover the recent probes");
+        for _ in 0..(2 * STATS_WINDOW) {
+            monitor.record(None);
+        }
+
+        let state = monitor.state("192.168.1.2");
+        assert_eq!(state.probes as usize, STATS_WINDOW);
+        assert_eq!(state.lost as usize, STATS_WINDOW);
+        assert_eq!(state.min_ms, None);
+        assert_eq!(state.avg_ms, None);
+    }
+}
@@ -0,0 +1,379 @@
+// This file is part of tacd, the LXA TAC system daemon
+// Copyright (C) 2024 Pengutronix e.K.
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along
+// with this program; if not, write to the Free Software Foundation, Inc.,
+// 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+
+use std::convert::TryFrom;
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use async_std::prelude::*;
+use async_std::sync::Arc;
+use async_std::task::sleep;
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::broker::{BrokerBuilder, Topic};
+use crate::watched_tasks::WatchedTasksBuilder;
+
+#[cfg(any(test, feature = "demo_mode"))]
+mod sched {
+    use anyhow::Result;
+
+    pub(super) fn is_realtime() -> bool {
+        true
+    }
+
+    pub(super) fn pin_to_cpu(_cpu: Option<u32>) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(not(any(test, feature = "demo_mode")))]
+mod sched {
+    use anyhow::Result;
+    use nix::sched::{sched_setaffinity, CpuSet};
+    use nix::unistd::Pid;
+    use thread_priority::*;
+
+    /// Check if the calling thread still uses the FIFO realtime policy
+    pub(super) fn is_realtime() -> bool {
+        matches!(
+            thread_schedule_policy(),
+            Ok(ThreadSchedulePolicy::Realtime(
+                RealtimeThreadSchedulePolicy::Fifo
+            ))
+        )
+    }
+
+    /// Restrict the calling thread to a single CPU or allow all of them again
+    pub(super) fn pin_to_cpu(cpu: Option<u32>) -> Result<()> {
+        let mut set = CpuSet::new();
+
+        match cpu {
+            Some(cpu) => set.set(cpu as usize)?,
+            None => {
+                // The kernel ignores CPUs that are not present
+                for cpu in 0..CpuSet::count() {
+                    set.set(cpu)?;
+                }
+            }
+        }
+
+        sched_setaffinity(Pid::from_raw(0), &set)?;
+
+        Ok(())
+    }
+}
+
+/// How often the audit results are published
+const AUDIT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How often the monitored threads check their own scheduling policy
+const POLICY_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Copy, Debug)]
+pub enum Warning {
+    Okay,
+    /// A thread woke up more than half a period later than it should have
+    HighLatency,
+    /// A thread lost its realtime scheduling policy
+    NotRealtime,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct ThreadReport {
+    pub name: String,
+    /// The thread still uses the FIFO realtime scheduling policy
+    pub realtime: bool,
+    /// Worst wakeup latency since the last report in microseconds
+    pub latency_us: u64,
+    /// Worst wakeup latency since the tacd started in microseconds
+    pub latency_max_us: u64,
+    /// Latencies above this limit (in microseconds) result in a warning
+    pub latency_limit_us: u64,
+}
+
+/// Scheduling statistics shared between a monitored thread and the audit task
+struct MonitorState {
+    name: &'static str,
+    period: Duration,
+    ref_instant: Instant,
+    /// Time of the last wakeup in microseconds since `ref_instant`.
+    /// Zero if the thread did not wake up yet.
+    last_wakeup_us: AtomicU64,
+    latency_us: AtomicU64,
+    latency_max_us: AtomicU64,
+    realtime: AtomicBool,
+    /// Pinning the thread to the requested CPU failed. This is logged by
+    /// the audit task, so that the thread itself does not have to.
+    pin_failed: AtomicBool,
+}
+
+impl MonitorState {
+    fn record(&self, latency: Duration) {
+        let latency_us = u64::try_from(latency.as_micros()).unwrap_or(u64::MAX);
+
+        self.latency_us.fetch_max(latency_us, Ordering::Relaxed);
+        self.latency_max_us.fetch_max(latency_us, Ordering::Relaxed);
+    }
+
+    fn latency_limit(&self) -> Duration {
+        self.period / 2
+    }
+
+    /// Collect the statistics since the last report and start over
+    fn report(&self, now: Instant) -> ThreadReport {
+        let last_wakeup_us = self.last_wakeup_us.load(Ordering::Relaxed);
+
+        // A thread that does not get to run at all can not report its own
+        // latency, so take the time since its last wakeup into account.
+        if last_wakeup_us != 0 {
+            let last_wakeup = self.ref_instant + Duration::from_micros(last_wakeup_us);
+            let due = last_wakeup + self.period;
+
+            self.record(now.saturating_duration_since(due));
+        }
+
+        ThreadReport {
+            name: self.name.to_string(),
+            realtime: self.realtime.load(Ordering::Relaxed),
+            latency_us: self.latency_us.swap(0, Ordering::Relaxed),
+            latency_max_us: self.latency_max_us.load(Ordering::Relaxed),
+            latency_limit_us: self.latency_limit().as_micros() as u64,
+        }
+    }
+}
+
+/// Handle for a realtime thread to report its scheduling behaviour
+///
+/// The thread is expected to call `woke_up()` once per loop iteration,
+/// right after the blocking operation that determines its period.
+/// `woke_up()` only stores atomic variables and does not log, so that it
+/// can not be delayed by lower priority threads.
+pub struct ThreadMonitor {
+    state: Arc<MonitorState>,
+    /// The CPU requested via the API or -1 to run on any CPU
+    cpu_request: Arc<AtomicI64>,
+    cpu_applied: i64,
+    last_wakeup: Option<Instant>,
+    last_policy_check: Option<Instant>,
+}
+
+impl ThreadMonitor {
+    pub fn woke_up(&mut self) {
+        let now = Instant::now();
+
+        if let Some(last) = self.last_wakeup {
+            self.state
+                .record(now.saturating_duration_since(last + self.state.period));
+        }
+
+        self.last_wakeup = Some(now);
+
+        let since_ref = now.saturating_duration_since(self.state.ref_instant);
+        let since_ref_us = u64::try_from(since_ref.as_micros()).unwrap_or(u64::MAX);
+        self.state
+            .last_wakeup_us
+            .store(since_ref_us.max(1), Ordering::Relaxed);
+
+        let check_due = self
+            .last_policy_check
+            .map(|ts| now.duration_since(ts) >= POLICY_CHECK_INTERVAL)
+            .unwrap_or(true);
+
+        if check_due {
+            self.last_policy_check = Some(now);
+            self.state
+                .realtime
+                .store(sched::is_realtime(), Ordering::Relaxed);
+
+            let cpu = self.cpu_request.load(Ordering::Relaxed);
+
+            if cpu != self.cpu_applied {
+                // Do not retry every second if pinning fails, e.g. because
+                // the CPU does not exist.
+                self.cpu_applied = cpu;
+
+                let res = sched::pin_to_cpu(u32::try_from(cpu).ok());
+
+                self.state.pin_failed.store(res.is_err(), Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+/// Audit the scheduling of the threads that have realtime requirements
+///
+/// The power and ADC threads request the FIFO realtime policy, but nothing
+/// guarantees that they keep it or that they are not starved by other load.
+/// The audit measures how late the threads wake up compared to their
+/// nominal period and raises a warning when that gets out of hand.
+pub struct Realtime {
+    pub threads: Arc<Topic<Vec<ThreadReport>>>,
+    cpu_request: Arc<AtomicI64>,
+    monitors: Arc<Mutex<Vec<Arc<MonitorState>>>>,
+}
+
+impl Realtime {
+    pub fn new(bb: &mut BrokerBuilder, wtb: &mut WatchedTasksBuilder) -> Result<Self> {
+        let threads: Arc<Topic<Vec<ThreadReport>>> =
+            bb.topic_ro("/v1/tac/realtime/threads", Some(Vec::new()));
+        let warning = bb.topic_ro("/v1/tac/realtime/warning", Some(Warning::Okay));
+
+        // Pinning the realtime threads to a CPU that is otherwise not used
+        // much can reduce their latency, but there is no good default for
+        // it, so it is opt-in.
+        let cpu: Arc<Topic<Option<u32>>> =
+            bb.topic("/v1/tac/realtime/cpu", true, true, true, Some(None), 1);
+
+        let cpu_request = Arc::new(AtomicI64::new(-1));
+        let monitors: Arc<Mutex<Vec<Arc<MonitorState>>>> = Arc::new(Mutex::new(Vec::new()));
+
+        let (mut cpu_events, _) = cpu.subscribe_unbounded();
+        let cpu_request_task = cpu_request.clone();
+
+        wtb.spawn_task("realtime-cpu-update", async move {
+            while let Some(cpu) = cpu_events.next().await {
+                let cpu = cpu.map(i64::from).unwrap_or(-1);
+                cpu_request_task.store(cpu, Ordering::Relaxed);
+            }
+
+            Ok(())
+        })?;
+
+        let monitors_task = monitors.clone();
+        let threads_task = threads.clone();
+
+        wtb.spawn_task("realtime-audit", async move {
+            loop {
+                sleep(AUDIT_INTERVAL).await;
+
+                let now = Instant::now();
+                let monitors = monitors_task.lock().unwrap().clone();
+                let mut reports = Vec::new();
+                let mut status = Warning::Okay;
+
+                for monitor in monitors.iter() {
+                    let report = monitor.report(now);
+
+                    if monitor.pin_failed.swap(false, Ordering::Relaxed) {
+                        warn!("Failed to pin thread {} to the requested CPU", report.name);
+                    }
+
+                    if !report.realtime {
+                        warn!("Thread {} lost its realtime priority", report.name);
+                        status = Warning::NotRealtime;
+                    } else if report.latency_us > report.latency_limit_us {
+                        warn!(
+                            "Thread {} woke up {}us late (limit {}us)",
+                            report.name, report.latency_us, report.latency_limit_us
+                        );
+
+                        if status == Warning::Okay {
+                            status = Warning::HighLatency;
+                        }
+                    }
+
+                    reports.push(report);
+                }
+
+                threads_task.set(reports);
+                warning.set_if_changed(status);
+            }
+        })?;
+
+        Ok(Self {
+            threads,
+            cpu_request,
+            monitors,
+        })
+    }
+
+    /// Register a realtime thread that should wake up every `period`
+    pub fn monitor(&self, name: &'static str, period: Duration) -> ThreadMonitor {
+        let state = Arc::new(MonitorState {
+            name,
+            period,
+            ref_instant: Instant::now(),
+            last_wakeup_us: AtomicU64::new(0),
+            latency_us: AtomicU64::new(0),
+            latency_max_us: AtomicU64::new(0),
+            realtime: AtomicBool::new(true),
+            pin_failed: AtomicBool::new(false),
+        });
+
+        self.monitors.lock().unwrap().push(state.clone());
+
+        ThreadMonitor {
+            state,
+            cpu_request: self.cpu_request.clone(),
+            cpu_applied: -1,
+            last_wakeup: None,
+            last_policy_check: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread::sleep;
+    use std::time::{Duration, Instant};
+
+    use super::Realtime;
+    use crate::broker::BrokerBuilder;
+    use crate::watched_tasks::WatchedTasksBuilder;
+
+    #[test]
+    fn wakeup_latency() {
+        let mut bb = BrokerBuilder::new();
+        let mut wtb = WatchedTasksBuilder::new();
+
+        let realtime = Realtime::new(&mut bb, &mut wtb).unwrap();
+        let mut monitor = realtime.monitor("test-thread", Duration::from_millis(20));
+
+        println!("Wake up on time");
+        for _ in 0..5 {
+            monitor.woke_up();
+            sleep(Duration::from_millis(20));
+        }
+
+        monitor.woke_up();
+
+        let report = monitor.state.report(Instant::now());
+        assert!(report.realtime);
+        assert!(report.latency_us < report.latency_limit_us);
+
+        println!("Wake up late");
+        sleep(Duration::from_millis(50));
+        monitor.woke_up();
+
+        let report = monitor.state.report(Instant::now());
+        assert!(report.latency_us >= 30_000);
+        assert!(report.latency_us > report.latency_limit_us);
+        assert_eq!(report.latency_max_us, report.latency_us);
+
+        println!("Stall completely");
+        sleep(Duration::from_millis(50));
+
+        // The thread did not get to report its own latency,
+        // but the report takes the time since the last wakeup into account.
+        let report = monitor.state.report(Instant::now());
+        assert!(report.latency_us >= 30_000);
+        assert!(report.latency_max_us >= report.latency_us);
+    }
+}
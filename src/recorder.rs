@@ -0,0 +1,507 @@
+// This file is part of tacd, the LXA TAC system daemon
+// Copyright (C) 2024 Pengutronix e.K.
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along
+// with this program; if not, write to the Free Software Foundation, Inc.,
+// 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+
+//! Record measurements over long test runs into CSV files
+//!
+//! Each sample is written as a line of `time_ms,channel,value`, where
+//! `time_ms` uses the same format as the `ts` field of measurements
+//! (milliseconds since the Unix Epoch) and `channel` is the topic path of
+//! the channel.
+//! Annotations of the current DUT profile that are made while recording
+//! are written as lines with `/v1/annotations` as channel and the quoted
+//! label as value.
+
+use std::collections::HashMap;
+use std::fs::{create_dir_all, read_dir, remove_file};
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime};
+
+use anyhow::{anyhow, bail, Result};
+use async_std::channel::Receiver;
+use async_std::fs::File;
+use async_std::io::{BufWriter, WriteExt};
+use async_std::sync::Arc;
+use chrono::Local;
+use futures::stream::select_all;
+use futures::{select, FutureExt, StreamExt};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use tide::{http::mime, Body, Request, Response, Server};
+
+use crate::adc::Adc;
+use crate::annotations::{self, Annotations};
+use crate::broker::{AnyTopic, BrokerBuilder, Topic};
+use crate::fs_root;
+use crate::http_server::WriteAccess;
+use crate::measurement::Measurement;
+use crate::watched_tasks::WatchedTasksBuilder;
+
+mod upload;
+
+const RECORDINGS_DIR: &str = "/srv/tacd/recordings";
+
+const CSV_HEADER: &str = "time_ms,channel,value\n";
+
+/// How often the status is updated and the file is flushed while recording
+const STATUS_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct RecorderConfig {
+    /// Topic paths of the channels to record. All channels are recorded if
+    /// the list is empty.
+    pub channels: Vec<String>,
+    /// Record at most one sample per channel in this interval (in
+    /// milliseconds). Every sample is recorded if this is zero.
+    pub interval_ms: u64,
+    /// Stop recording once the file reaches this size (in bytes)
+    pub max_size: u64,
+}
+
+impl Default for RecorderConfig {
+    fn default() -> Self {
+        Self {
+            channels: vec![
+                "/v1/dut/feedback/voltage".to_string(),
+                "/v1/dut/feedback/current".to_string(),
+            ],
+            interval_ms: 0,
+            max_size: 64 * 1024 * 1024,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug, Default)]
+pub struct RecorderStatus {
+    /// The file that is currently being written to (if any)
+    pub recording: Option<String>,
+    pub samples: u64,
+    pub bytes: u64,
+    /// Why the last recording ended early (if it did)
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct Recording {
+    pub name: String,
+    pub size: u64,
+}
+
+/// Only allow the names of recordings, so that other files can not be read
+/// or removed
+fn valid_name(name: &str) -> bool {
+    let allowed = |c: char| c.is_ascii_alphanumeric() || c == '.' || c == '_' || c == '-';
+
+    !name.starts_with('.') && name.ends_with(".csv") && name.chars().all(allowed)
+}
+
+fn list_recordings(dir: &Path) -> Vec<Recording> {
+    let mut recordings: Vec<Recording> = match read_dir(dir) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| {
+                let name = entry.file_name().into_string().ok()?;
+                let meta = entry.metadata().ok().filter(|m| m.is_file())?;
+
+                valid_name(&name).then_some(Recording {
+                    name,
+                    size: meta.len(),
+                })
+            })
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+
+    recordings.sort_by(|a, b| a.name.cmp(&b.name));
+    recordings
+}
+
+fn csv_line(path: &str, measurement: &Measurement) -> String {
+    let ts = measurement
+        .ts
+        .in_system_time()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default();
+
+    format!(
+        "{:.3},{path},{}\n",
+        1000.0 * ts.as_secs_f64(),
+        measurement.value
+    )
+}
+
+/// Drop samples that arrive less than `interval` after the last recorded
+/// sample of the same channel
+struct Decimator {
+    interval: Duration,
+    last: HashMap<Arc<str>, Instant>,
+}
+
+impl Decimator {
+    fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            last: HashMap::new(),
+        }
+    }
+
+    fn keep(&mut self, channel: &Arc<str>, ts: Instant) -> bool {
+        match self.last.get(channel) {
+            Some(last) if ts.saturating_duration_since(*last) < self.interval => false,
+            _ => {
+                self.last.insert(channel.clone(), ts);
+                true
+            }
+        }
+    }
+}
+
+/// Record the selected channels until a stop is requested or the file
+/// becomes too large
+async fn record(
+    dir: &Path,
+    channels: &[Arc<Topic<Measurement>>],
+    config: &RecorderConfig,
+    annotations: &Annotations,
+    requests: &mut Receiver<bool>,
+    status: &Topic<RecorderStatus>,
+) -> Result<()> {
+    let is_selected = |ch: &&Arc<Topic<Measurement>>| {
+        let path: &str = ch.path();
+        config.channels.is_empty() || config.channels.iter().any(|c| c == path)
+    };
+
+    let selected: Vec<&Arc<Topic<Measurement>>> = channels.iter().filter(is_selected).collect();
+
+    if selected.is_empty() {
+        bail!("None of the configured channels exist");
+    }
+
+    create_dir_all(dir)?;
+
+    let name = format!("recording-{}.csv", Local::now().format("%Y%m%d-%H%M%S"));
+    let mut file = BufWriter::new(File::create(dir.join(&name)).await?);
+
+    info!("Recording {} channels to {name}", selected.len());
+
+    file.write_all(CSV_HEADER.as_bytes()).await?;
+
+    let mut current = RecorderStatus {
+        recording: Some(name),
+        bytes: CSV_HEADER.len() as u64,
+        ..Default::default()
+    };
+
+    status.set(current.clone());
+
+    let mut handles = Vec::new();
+    let mut streams = Vec::new();
+
+    for channel in selected {
+        let path: &str = channel.path();
+        let path: Arc<str> = path.into();
+        let (stream, handle) = channel.clone().subscribe_unbounded();
+
+        handles.push(handle);
+        streams.push(stream.map(move |m| (path.clone(), m)));
+    }
+
+    // Only annotations that are made while recording are written to the
+    // file. The first event is the list as it is now and adds nothing.
+    let mut known_annotations = annotations.list.try_get().unwrap_or_default();
+    let (mut annotation_events, annotation_handle) = annotations.list.clone().subscribe_unbounded();
+
+    let mut samples = select_all(streams);
+    let mut decimator = Decimator::new(Duration::from_millis(config.interval_ms));
+    let mut last_status = Instant::now();
+
+    let res = loop {
+        select! {
+            sample = samples.next().fuse() => {
+                let (path, measurement) = match sample {
+                    Some(sample) => sample,
+                    None => break Ok(()),
+                };
+
+                if !decimator.keep(&path, measurement.ts.as_instant()) {
+                    continue;
+                }
+
+                let line = csv_line(&path, &measurement);
+
+                if current.bytes + line.len() as u64 > config.max_size {
+                    break Err(anyhow!("The maximum file size was reached"));
+                }
+
+                if let Err(e) = file.write_all(line.as_bytes()).await {
+                    break Err(e.into());
+                }
+
+                current.samples += 1;
+                current.bytes += line.len() as u64;
+
+                if last_status.elapsed() >= STATUS_INTERVAL {
+                    if let Err(e) = file.flush().await {
+                        break Err(e.into());
+                    }
+
+                    status.set(current.clone());
+                    last_status = Instant::now();
+                }
+            },
+            list = annotation_events.next().fuse() => {
+                let list = match list {
+                    Some(list) => list,
+                    None => break Ok(()),
+                };
+
+                let profile = annotations.current_profile();
+                let lines: String = annotations::added(&known_annotations, &list, &profile)
+                    .map(|a| a.csv_line())
+                    .collect();
+
+                known_annotations = list;
+
+                if lines.is_empty() {
+                    continue;
+                }
+
+                if current.bytes + lines.len() as u64 > config.max_size {
+                    break Err(anyhow!("The maximum file size was reached"));
+                }
+
+                if let Err(e) = file.write_all(lines.as_bytes()).await {
+                    break Err(e.into());
+                }
+
+                current.bytes += lines.len() as u64;
+            },
+            req = requests.next().fuse() => match req {
+                Some(true) => {}
+                Some(false) | None => break Ok(()),
+            },
+        }
+    };
+
+    for handle in handles {
+        handle.unsubscribe();
+    }
+
+    annotation_handle.unsubscribe();
+
+    file.flush().await?;
+
+    current.recording = None;
+    current.error = res.as_ref().err().map(|e| e.to_string());
+    status.set(current);
+
+    res
+}
+
+fn plain(status: u16, msg: &str) -> Response {
+    Response::builder(status)
+        .body(msg)
+        .content_type(mime::PLAIN)
+        .build()
+}
+
+fn serve(
+    server: &mut Server<()>,
+    status: Arc<Topic<RecorderStatus>>,
+    files: Arc<Topic<Vec<Recording>>>,
+) {
+    let route = "/v1/tac/recorder/files/:name";
+
+    server.at(route).get(|req: Request<()>| async move {
+        let name = req.param("name").unwrap_or_default();
+
+        if !valid_name(name) {
+            return Ok(plain(400, "Invalid file name"));
+        }
+
+        let body = match Body::from_file(fs_root::path(RECORDINGS_DIR).join(name)).await {
+            Ok(body) => body,
+            Err(_) => return Ok(plain(404, "No such recording")),
+        };
+
+        let res = Response::builder(200)
+            .body(body)
+            .header("Content-Type", "text/csv")
+            .header(
+                "Content-Disposition",
+                format!("attachment; filename={name}"),
+            )
+            .build();
+
+        Ok(res)
+    });
+
+    server.at(route).delete(move |req: Request<()>| {
+        let status = status.clone();
+        let files = files.clone();
+
+        async move {
+            if let Some(res) = WriteAccess::refuse(&req) {
+                return Ok(res);
+            }
+
+            let name = req.param("name").unwrap_or_default();
+
+            if !valid_name(name) {
+                return Ok(plain(400, "Invalid file name"));
+            }
+
+            let active = status.try_get().and_then(|s| s.recording);
+
+            if active.as_deref() == Some(name) {
+                return Ok(plain(409, "The recording is still in progress"));
+            }
+
+            let dir = fs_root::path(RECORDINGS_DIR);
+
+            if remove_file(dir.join(name)).is_err() {
+                return Ok(plain(404, "No such recording"));
+            }
+
+            files.set(list_recordings(&dir));
+
+            Ok(Response::new(204))
+        }
+    });
+}
+
+/// Record measurements into files on the data partition on request and
+/// make them available for download
+pub fn run(
+    bb: &mut BrokerBuilder,
+    wtb: &mut WatchedTasksBuilder,
+    server: &mut Server<()>,
+    adc: &Adc,
+    annotations: Annotations,
+) -> Result<()> {
+    let config: Arc<Topic<RecorderConfig>> = bb.topic(
+        "/v1/tac/recorder/config",
+        true,
+        true,
+        true,
+        Some(RecorderConfig::default()),
+        1,
+    );
+    let request = bb.topic_wo::<bool>("/v1/tac/recorder/recording", None);
+    let status = bb.topic_ro("/v1/tac/recorder/status", Some(RecorderStatus::default()));
+    let files = bb.topic_ro(
+        "/v1/tac/recorder/files",
+        Some(list_recordings(&fs_root::path(RECORDINGS_DIR))),
+    );
+
+    serve(server, status.clone(), files.clone());
+
+    upload::run(bb, wtb, status.clone(), files.clone())?;
+
+    let channels = adc.measurement_topics();
+    let (mut requests, _) = request.subscribe_unbounded();
+
+    wtb.spawn_task("recorder", async move {
+        let dir = fs_root::path(RECORDINGS_DIR);
+
+        while let Some(start) = requests.next().await {
+            if !start {
+                continue;
+            }
+
+            let config = config.try_get().unwrap_or_default();
+
+            let res = record(
+                &dir,
+                &channels,
+                &config,
+                &annotations,
+                &mut requests,
+                &status,
+            )
+            .await;
+
+            if let Err(e) = res {
+                warn!("Recording ended early: {e}");
+
+                status.modify(|prev| {
+                    let mut status = prev.unwrap_or_default();
+                    status.error = Some(e.to_string());
+                    Some(status)
+                });
+            }
+
+            files.set(list_recordings(&dir));
+        }
+
+        Ok(())
+    })?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use async_std::sync::Arc;
+
+    use super::{csv_line, valid_name, Decimator};
+    use crate::measurement::Measurement;
+
+    #[test]
+    fn names() {
+        assert!(valid_name("recording-20240101-120000.csv"));
+        assert!(!valid_name(".hidden.csv"));
+        assert!(!valid_name("../tacd/ssh-key-imports.log"));
+        assert!(!valid_name("../../etc/passwd.csv"));
+        assert!(!valid_name("recording.txt"));
+    }
+
+    #[test]
+    fn lines() {
+        let line = csv_line("/v1/dut/feedback/voltage", &Measurement::now(12.5));
+        let fields: Vec<&str> = line.trim_end().split(',').collect();
+
+        assert!(line.ends_with('\n'));
+        assert_eq!(fields.len(), 3);
+        assert!(fields[0].parse::<f64>().unwrap() > 0.0);
+        assert_eq!(fields[1], "/v1/dut/feedback/voltage");
+        assert_eq!(fields[2], "12.5");
+    }
+
+    #[test]
+    fn decimation() {
+        let volt: Arc<str> = "volt".into();
+        let curr: Arc<str> = "curr".into();
+        let start = Instant::now();
+        let ms = |ms| start + Duration::from_millis(ms);
+
+        println!("Every sample is kept without an interval");
+        let mut decimator = Decimator::new(Duration::ZERO);
+        assert!(decimator.keep(&volt, ms(0)));
+        assert!(decimator.keep(&volt, ms(0)));
+
+        println!("Samples are dropped per channel");
+        let mut decimator = Decimator::new(Duration::from_millis(100));
+        assert!(decimator.keep(&volt, ms(0)));
+        assert!(decimator.keep(&curr, ms(50)));
+        assert!(!decimator.keep(&volt, ms(50)));
+        assert!(decimator.keep(&volt, ms(100)));
+        assert!(!decimator.keep(&curr, ms(100)));
+        assert!(decimator.keep(&curr, ms(150)));
+    }
+}
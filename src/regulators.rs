@@ -32,7 +32,7 @@ mod reg {
 
     pub fn regulator_set(name: &str, state: bool) -> Result<()> {
         if name == "output_iobus_12v" {
-            let iio_thread = block_on(IioThread::new_stm32(&(), ())).unwrap();
+            let iio_thread = block_on(IioThread::new_stm32(&(), &(), ())).unwrap();
 
             iio_thread
                 .clone()
@@ -53,10 +53,13 @@ mod reg {
 mod reg {
     use std::fs::write;
     use std::io::Result;
-    use std::path::Path;
+
+    use crate::fs_root;
 
     pub fn regulator_set(name: &str, state: bool) -> Result<()> {
-        let path = Path::new("/sys/devices/platform").join(name).join("state");
+        let path = fs_root::path("/sys/devices/platform")
+            .join(name)
+            .join("state");
         let state = if state { "enabled" } else { "disabled" };
 
         write(path, state)
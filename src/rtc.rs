@@ -0,0 +1,206 @@
+// This file is part of tacd, the LXA TAC system daemon
+// Copyright (C) 2024 Pengutronix e.K.
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along
+// with this program; if not, write to the Free Software Foundation, Inc.,
+// 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+
+use std::time::Duration;
+
+use anyhow::Result;
+use async_std::sync::Arc;
+use async_std::task::sleep;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::broker::{BrokerBuilder, Topic};
+use crate::watched_tasks::WatchedTasksBuilder;
+
+#[cfg(feature = "demo_mode")]
+mod hw {
+    pub(super) struct Supply;
+
+    impl Supply {
+        pub(super) fn find() -> Option<Self> {
+            Some(Self)
+        }
+
+        pub(super) fn attr(&self, name: &str) -> Option<String> {
+            match name {
+                "present" => Some("1".into()),
+                "voltage_now" => Some("3012000".into()),
+                "health" => Some("Good".into()),
+                _ => None,
+            }
+        }
+    }
+}
+
+#[cfg(not(feature = "demo_mode"))]
+mod hw {
+    use std::fs::{read_dir, read_to_string};
+    use std::path::PathBuf;
+
+    use crate::fs_root;
+
+    const POWER_SUPPLY_CLASS: &str = "/sys/class/power_supply";
+
+    pub(super) struct Supply(PathBuf);
+
+    impl Supply {
+        /// Find the RTC backup cell in the power supply class
+        ///
+        /// The TAC itself is not battery powered, so the only supply of
+        /// type "Battery" we may encounter is the RTC backup cell.
+        pub(super) fn find() -> Option<Self> {
+            read_dir(fs_root::path(POWER_SUPPLY_CLASS))
+                .ok()?
+                .filter_map(|entry| entry.ok())
+                .map(|entry| Self(entry.path()))
+                .find(|supply| supply.attr("type").as_deref() == Some("Battery"))
+        }
+
+        pub(super) fn attr(&self, name: &str) -> Option<String> {
+            read_to_string(self.0.join(name))
+                .ok()
+                .map(|content| content.trim().to_string())
+        }
+    }
+}
+
+use hw::Supply;
+
+const UPDATE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Below this voltage the cell should be replaced soon
+const VOLTAGE_LOW: f32 = 2.5;
+
+/// Below this voltage the RTC can not be expected to keep the time
+const VOLTAGE_DEPLETED: f32 = 2.0;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum BackupState {
+    /// This hardware revision does not have an RTC backup cell
+    NotFitted,
+    /// The cell is missing from its holder
+    Missing,
+    Depleted,
+    Low,
+    Okay,
+}
+
+impl BackupState {
+    /// Will the RTC lose the time on the next power loss?
+    pub fn is_problem(&self) -> bool {
+        matches!(self, Self::Missing | Self::Depleted)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct RtcBackup {
+    pub state: BackupState,
+    /// Voltage of the backup cell (if reported by the kernel)
+    pub voltage: Option<f32>,
+    /// Health as reported by the kernel (e.g. "Good" or "Dead")
+    pub health: Option<String>,
+}
+
+impl RtcBackup {
+    fn read() -> Self {
+        let supply = match Supply::find() {
+            Some(supply) => supply,
+            None => {
+                return Self {
+                    state: BackupState::NotFitted,
+                    voltage: None,
+                    health: None,
+                }
+            }
+        };
+
+        let present = supply.attr("present").map_or(true, |p| p != "0");
+
+        // The kernel reports voltages in µV.
+        let voltage = supply
+            .attr("voltage_now")
+            .and_then(|v| v.parse::<f32>().ok())
+            .map(|v| v / 1_000_000.0);
+
+        let health = supply.attr("health");
+
+        let dead = matches!(health.as_deref(), Some("Dead"));
+
+        let state = match voltage {
+            _ if !present => BackupState::Missing,
+            _ if dead => BackupState::Depleted,
+            Some(v) if v < VOLTAGE_DEPLETED => BackupState::Depleted,
+            Some(v) if v < VOLTAGE_LOW => BackupState::Low,
+            _ => BackupState::Okay,
+        };
+
+        Self {
+            state,
+            voltage,
+            health,
+        }
+    }
+}
+
+pub struct Rtc {
+    pub backup: Arc<Topic<RtcBackup>>,
+}
+
+impl Rtc {
+    pub fn new(bb: &mut BrokerBuilder, wtb: &mut WatchedTasksBuilder) -> Result<Self> {
+        let initial = RtcBackup::read();
+        let fitted = initial.state != BackupState::NotFitted;
+
+        match initial.state {
+            BackupState::NotFitted => info!("No RTC backup cell found. Not monitoring it"),
+            state if state.is_problem() => {
+                warn!("RTC backup cell is {state:?}. The time will be lost on power loss")
+            }
+            _ => {}
+        }
+
+        let backup = bb.topic_ro("/v1/tac/system/rtc", Some(initial));
+
+        // Hardware without a backup cell will not grow one at runtime.
+        // There is no need to keep polling.
+        if fitted {
+            let backup_task = backup.clone();
+
+            wtb.spawn_task("rtc-backup-update", async move {
+                loop {
+                    sleep(UPDATE_INTERVAL).await;
+
+                    let current = RtcBackup::read();
+                    let previous = backup_task.try_get();
+
+                    if current.state.is_problem()
+                        && previous.map(|p| p.state) != Some(current.state)
+                    {
+                        warn!(
+                            "RTC backup cell is {:?}. The time will be lost on power loss",
+                            current.state
+                        );
+                    }
+
+                    backup_task.set_if_changed(current);
+                }
+            })?;
+        }
+
+        Ok(Self { backup })
+    }
+}
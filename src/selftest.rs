@@ -0,0 +1,586 @@
+// This file is part of tacd, the LXA TAC system daemon
+// Copyright (C) 2024 Pengutronix e.K.
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along
+// with this program; if not, write to the Free Software Foundation, Inc.,
+// 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+
+//! Check the hardware and software of the TAC on request
+//!
+//! The self-test runs a fixed sequence of checks, e.g. after a TAC was moved
+//! to another lab, and publishes a report with one entry per check.
+//! Checks that can not be performed in the current setup (like the output
+//! loopback if nothing is connected to the outputs) are reported as skipped
+//! instead of failed.
+//! The output loopback changes what is applied to whatever is connected to
+//! the outputs and is only performed if it was explicitly requested.
+
+use std::collections::{BTreeMap, HashMap};
+use std::time::Duration;
+
+use anyhow::Result;
+use async_std::future::timeout;
+use async_std::prelude::*;
+use async_std::sync::Arc;
+use async_std::task::sleep;
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use crate::adc::{Adc, AdcChannel};
+use crate::broker::{BrokerBuilder, Topic};
+use crate::dbus::Rauc;
+use crate::digital_io::{DigitalIo, GpioHealth, LineHealth, Waveform};
+use crate::iobus::probe_server;
+use crate::led::{BlinkPattern, BlinkPatternBuilder, Led};
+use crate::measurement::{Measurement, Timestamp};
+use crate::usb_hub::hub_present;
+use crate::watched_tasks::WatchedTasksBuilder;
+
+/// Samples older than this mean that the ADC stopped sampling
+const ADC_MAX_AGE: Duration = Duration::from_secs(1);
+
+/// The range of values each channel returned by `Adc::channels()` can
+/// plausibly read, given the ratings of the hardware
+const ADC_RANGES: [(&str, f32, f32); 10] = [
+    ("usb-host-curr", -0.05, 1.0),
+    ("usb-host1-curr", -0.05, 1.0),
+    ("usb-host2-curr", -0.05, 1.0),
+    ("usb-host3-curr", -0.05, 1.0),
+    ("out0-volt", -1.0, 50.0),
+    ("out1-volt", -1.0, 50.0),
+    ("iobus-curr", -0.05, 0.5),
+    ("iobus-volt", -0.5, 15.0),
+    ("pwr-volt", -1.0, 50.0),
+    ("pwr-curr", -0.5, 6.0),
+];
+
+/// The outputs are only pulsed if at least this voltage is applied to them
+/// from the outside, as nothing can be observed on an unconnected output
+const LOOPBACK_MIN_VOLTAGE: f32 = 1.0;
+
+/// How long an output is inverted during the loopback check.
+/// The voltage is measured half way through the pulse.
+const LOOPBACK_PULSE_MS: u64 = 100;
+
+/// How long the LEDs blink for to be inspected visually
+const LED_BLINK_INTERVAL: Duration = Duration::from_millis(250);
+const LED_BLINK_COUNT: i32 = 4;
+
+const IOBUS_TIMEOUT: Duration = Duration::from_secs(2);
+
+type SlotStatus = HashMap<String, HashMap<String, String>>;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub enum CheckStatus {
+    Pass,
+    /// The check passed, but something looks suspicious
+    Warn,
+    Fail,
+    /// The check could not be performed in the current setup
+    Skip,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct CheckResult {
+    pub name: String,
+    pub status: CheckStatus,
+    pub detail: String,
+}
+
+impl CheckResult {
+    fn new(name: &str, status: CheckStatus, detail: impl Into<String>) -> Self {
+        Self {
+            name: name.to_string(),
+            status,
+            detail: detail.into(),
+        }
+    }
+}
+
+/// What to include in a self-test run
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+pub struct SelfTestRequest {
+    /// Pulse the outputs that are not in use and have a voltage applied
+    #[serde(default)]
+    pub loopback: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SelfTestReport {
+    /// The time the self-test was completed at
+    pub ts: Timestamp,
+    pub checks: Vec<CheckResult>,
+}
+
+impl SelfTestReport {
+    /// A report passed if none of its checks failed
+    pub fn passed(&self) -> bool {
+        self.checks.iter().all(|c| c.status != CheckStatus::Fail)
+    }
+}
+
+/// Check that the ADC channels are sampled and read plausible values
+///
+/// `samples` contains the current sample (if any) for every channel listed in
+/// `ADC_RANGES`. Samples of the DUT power channels are only checked if
+/// `pwr_available`.
+fn check_adc(samples: &[Option<Measurement>], pwr_available: bool) -> CheckResult {
+    let mut problems = Vec::new();
+
+    for ((name, min, max), sample) in ADC_RANGES.iter().zip(samples) {
+        if name.starts_with("pwr-") && !pwr_available {
+            continue;
+        }
+
+        match sample {
+            None => problems.push(format!("{name}: no sample")),
+            Some(m) if m.ts.elapsed() > ADC_MAX_AGE => problems.push(format!("{name}: stalled")),
+            Some(m) if !(*min..=*max).contains(&m.value) => {
+                problems.push(format!("{name}: {:.3} out of range", m.value))
+            }
+            Some(_) => {}
+        }
+    }
+
+    match (problems.is_empty(), pwr_available) {
+        (true, true) => CheckResult::new("ADC", CheckStatus::Pass, "All channels plausible"),
+        (true, false) => {
+            CheckResult::new("ADC", CheckStatus::Warn, "DUT power channels unavailable")
+        }
+        (false, _) => CheckResult::new("ADC", CheckStatus::Fail, problems.join(", ")),
+    }
+}
+
+/// Check that the tacd got exclusive access to all of its GPIO lines
+fn check_gpio_lines(lines: &BTreeMap<String, LineHealth>) -> CheckResult {
+    let conflicts: Vec<&str> = lines
+        .iter()
+        .filter(|(_, health)| **health != LineHealth::Ok)
+        .map(|(line, _)| line.as_str())
+        .collect();
+
+    if conflicts.is_empty() {
+        CheckResult::new("GPIO", CheckStatus::Pass, "All lines requested")
+    } else {
+        let detail = format!("Lines in use elsewhere: {}", conflicts.join(", "));
+        CheckResult::new("GPIO", CheckStatus::Fail, detail)
+    }
+}
+
+/// Check that the booted slot was marked good and RAUC reported no errors
+fn check_rauc(slot_status: Option<&SlotStatus>, last_error: Option<&str>) -> CheckResult {
+    let slot_status = match slot_status {
+        Some(slot_status) => slot_status,
+        None => return CheckResult::new("RAUC", CheckStatus::Fail, "No slot status available"),
+    };
+
+    let booted = slot_status
+        .iter()
+        .find(|(_, slot)| slot.get("state").map(String::as_str) == Some("booted"));
+
+    let (name, slot) = match booted {
+        Some(booted) => booted,
+        None => return CheckResult::new("RAUC", CheckStatus::Fail, "No booted slot"),
+    };
+
+    let boot_status = slot.get("boot_status").map(String::as_str).unwrap_or("?");
+
+    if boot_status != "good" {
+        let detail = format!("Booted slot {name} is {boot_status}");
+        return CheckResult::new("RAUC", CheckStatus::Fail, detail);
+    }
+
+    match last_error {
+        Some(err) if !err.is_empty() => {
+            CheckResult::new("RAUC", CheckStatus::Warn, format!("Last error: {err}"))
+        }
+        _ => CheckResult::new(
+            "RAUC",
+            CheckStatus::Pass,
+            format!("Booted slot {name} is good"),
+        ),
+    }
+}
+
+/// Judge the voltages measured on an output before and during a pulse
+fn loopback_status(idle: f32, pulsed: f32) -> CheckStatus {
+    // Switching the output should change the voltage applied from the
+    // outside considerably, independent of the direction.
+    if (idle - pulsed).abs() > idle / 2.0 {
+        CheckStatus::Pass
+    } else {
+        CheckStatus::Fail
+    }
+}
+
+/// Restore the `previous` pattern of an LED, unless someone else changed
+/// it since the self-test set it to `blinking`
+fn restore_led(led: &Topic<BlinkPattern>, blinking: &BlinkPattern, previous: Option<BlinkPattern>) {
+    led.modify(|current| match current {
+        Some(current) if current == *blinking => previous,
+        _ => None,
+    });
+}
+
+/// An output and the ADC channel that measures its voltage
+struct Output {
+    name: &'static str,
+    asserted: Arc<Topic<bool>>,
+    waveform: Arc<Topic<Waveform>>,
+    volt: AdcChannel,
+}
+
+/// The topics and channels needed to perform the checks
+struct Subjects {
+    adc: [AdcChannel; 10],
+    pwr_available: bool,
+    outputs: [Output; 2],
+    gpio_lines: Arc<Topic<BTreeMap<String, LineHealth>>>,
+    leds: Vec<Arc<Topic<BlinkPattern>>>,
+    slot_status: Arc<Topic<Arc<SlotStatus>>>,
+    last_error: Arc<Topic<String>>,
+}
+
+impl Subjects {
+    fn adc(&self) -> CheckResult {
+        let samples: Vec<Option<Measurement>> =
+            self.adc.iter().map(|ch| ch.fast.get().ok()).collect();
+
+        check_adc(&samples, self.pwr_available)
+    }
+
+    /// Pulse the outputs and check that the measured voltage follows
+    ///
+    /// This is only possible if a voltage is applied to the output from the
+    /// outside and the output is not used otherwise.
+    async fn loopback(&self) -> Vec<CheckResult> {
+        let mut results = Vec::new();
+
+        for output in &self.outputs {
+            let name = format!("{} loopback", output.name);
+            let in_use = output.asserted.try_get().unwrap_or(false)
+                || output.waveform.try_get() != Some(Waveform::Static);
+
+            if in_use {
+                results.push(CheckResult::new(&name, CheckStatus::Skip, "Output in use"));
+                continue;
+            }
+
+            let idle = match output.volt.fast.get() {
+                Ok(m) => m.value,
+                Err(_) => {
+                    results.push(CheckResult::new(&name, CheckStatus::Fail, "No sample"));
+                    continue;
+                }
+            };
+
+            if idle < LOOPBACK_MIN_VOLTAGE {
+                results.push(CheckResult::new(
+                    &name,
+                    CheckStatus::Skip,
+                    "Nothing connected",
+                ));
+                continue;
+            }
+
+            output.waveform.set(Waveform::Pulse {
+                width_ms: LOOPBACK_PULSE_MS,
+            });
+
+            sleep(Duration::from_millis(LOOPBACK_PULSE_MS / 2)).await;
+
+            let pulsed = output.volt.fast.get().map(|m| m.value).unwrap_or(idle);
+
+            // Make sure the pulse is over before the next output is tested
+            sleep(Duration::from_millis(LOOPBACK_PULSE_MS)).await;
+
+            let status = loopback_status(idle, pulsed);
+            let detail = format!("{idle:.2} V -> {pulsed:.2} V");
+
+            results.push(CheckResult::new(&name, status, detail));
+        }
+
+        results
+    }
+
+    /// Blink all LEDs for a visual inspection and restore them afterwards
+    async fn leds(&self) -> CheckResult {
+        let previous: Vec<Option<BlinkPattern>> =
+            self.leds.iter().map(|led| led.try_get()).collect();
+
+        let blinking = BlinkPatternBuilder::new(0.0)
+            .step_to(1.0)
+            .stay_for(LED_BLINK_INTERVAL)
+            .step_to(0.0)
+            .stay_for(LED_BLINK_INTERVAL)
+            .repeat(LED_BLINK_COUNT);
+
+        for led in &self.leds {
+            led.set(blinking.clone());
+        }
+
+        sleep(LED_BLINK_INTERVAL * 2 * (LED_BLINK_COUNT as u32)).await;
+
+        for (led, pattern) in self.leds.iter().zip(previous) {
+            restore_led(led, &blinking, pattern);
+        }
+
+        CheckResult::new("LEDs", CheckStatus::Pass, "Blinked, check visually")
+    }
+
+    fn usb_hub(&self) -> CheckResult {
+        if hub_present() {
+            CheckResult::new("USB hub", CheckStatus::Pass, "All ports present")
+        } else {
+            CheckResult::new("USB hub", CheckStatus::Fail, "Hub not enumerated")
+        }
+    }
+
+    async fn iobus(&self) -> CheckResult {
+        match timeout(IOBUS_TIMEOUT, probe_server()).await {
+            Ok(Some(info)) if !info.can_interface_is_up => {
+                let detail = format!("{} is down", info.can_interface);
+                CheckResult::new("IOBus", CheckStatus::Warn, detail)
+            }
+            Ok(Some(info)) if info.can_tx_error => {
+                let detail = format!("Transmit errors on {}", info.can_interface);
+                CheckResult::new("IOBus", CheckStatus::Warn, detail)
+            }
+            Ok(Some(_)) => CheckResult::new("IOBus", CheckStatus::Pass, "Server reachable"),
+            _ => CheckResult::new("IOBus", CheckStatus::Fail, "Server not reachable"),
+        }
+    }
+
+    fn rauc(&self) -> CheckResult {
+        let slot_status = self.slot_status.try_get();
+        let last_error = self.last_error.try_get();
+
+        check_rauc(slot_status.as_deref(), last_error.as_deref())
+    }
+
+    async fn run(&self, request: SelfTestRequest) -> SelfTestReport {
+        let mut checks = vec![
+            self.adc(),
+            check_gpio_lines(&self.gpio_lines.try_get().unwrap_or_default()),
+        ];
+
+        if request.loopback {
+            checks.extend(self.loopback().await);
+        } else {
+            for output in &self.outputs {
+                let name = format!("{} loopback", output.name);
+                checks.push(CheckResult::new(&name, CheckStatus::Skip, "Not requested"));
+            }
+        }
+
+        checks.push(self.leds().await);
+        checks.push(self.usb_hub());
+        checks.push(self.iobus().await);
+        checks.push(self.rauc());
+
+        SelfTestReport {
+            ts: Timestamp::now(),
+            checks,
+        }
+    }
+}
+
+pub struct SelfTest {
+    pub run: Arc<Topic<SelfTestRequest>>,
+    pub running: Arc<Topic<bool>>,
+    pub result: Arc<Topic<Option<SelfTestReport>>>,
+}
+
+impl SelfTest {
+    pub fn new(
+        bb: &mut BrokerBuilder,
+        wtb: &mut WatchedTasksBuilder,
+        adc: &Adc,
+        dig_io: &DigitalIo,
+        gpio_health: &GpioHealth,
+        led: &Led,
+        rauc: &Rauc,
+    ) -> Result<Self> {
+        let run = bb.topic_wo::<SelfTestRequest>("/v1/tac/selftest/run", None);
+        let running = bb.topic_ro("/v1/tac/selftest/running", Some(false));
+        let result = bb.topic_ro("/v1/tac/selftest/result", Some(None));
+
+        let subjects = Subjects {
+            adc: adc.channels(),
+            pwr_available: adc.pwr_available,
+            outputs: [
+                Output {
+                    name: "OUT_0",
+                    asserted: dig_io.out_0.clone(),
+                    waveform: dig_io.out_0_waveform.clone(),
+                    volt: adc.out0_volt.clone(),
+                },
+                Output {
+                    name: "OUT_1",
+                    asserted: dig_io.out_1.clone(),
+                    waveform: dig_io.out_1_waveform.clone(),
+                    volt: adc.out1_volt.clone(),
+                },
+            ],
+            gpio_lines: gpio_health.lines.clone(),
+            leds: vec![
+                led.out_0.clone(),
+                led.out_1.clone(),
+                led.dut_pwr.clone(),
+                led.eth_dut.clone(),
+                led.eth_lab.clone(),
+                led.status.clone(),
+            ],
+            slot_status: rauc.slot_status.clone(),
+            last_error: rauc.last_error.clone(),
+        };
+
+        let (mut run_requests, _) = run.clone().subscribe_unbounded();
+        let running_task = running.clone();
+        let result_task = result.clone();
+
+        wtb.spawn_task("selftest-run", async move {
+            while let Some(request) = run_requests.next().await {
+                info!("Starting self-test ({request:?})");
+
+                // Clear the previous report, so that it is not mistaken
+                // for the result of this run.
+                result_task.set(None);
+                running_task.set(true);
+
+                let report = subjects.run(request).await;
+
+                info!(
+                    "Self-test {}",
+                    if report.passed() { "passed" } else { "failed" }
+                );
+
+                result_task.set(Some(report));
+                running_task.set(false);
+            }
+
+            Ok(())
+        })?;
+
+        Ok(Self {
+            run,
+            running,
+            result,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{BTreeMap, HashMap};
+
+    use super::{
+        check_adc, check_gpio_lines, check_rauc, loopback_status, restore_led, CheckStatus,
+        ADC_RANGES,
+    };
+    use crate::broker::Topic;
+    use crate::digital_io::LineHealth;
+    use crate::led::BlinkPattern;
+    use crate::measurement::Measurement;
+
+    #[test]
+    fn adc_plausibility() {
+        let samples = |value: f32| vec![Some(Measurement::now(value)); ADC_RANGES.len()];
+
+        println!("Values within the ratings pass");
+        assert_eq!(check_adc(&samples(0.0), true).status, CheckStatus::Pass);
+
+        println!("Missing and out of range samples fail");
+        let mut missing = samples(0.0);
+        missing[6] = None;
+        assert_eq!(check_adc(&missing, true).status, CheckStatus::Fail);
+        assert_eq!(check_adc(&samples(-5.0), true).status, CheckStatus::Fail);
+
+        println!("Unavailable DUT power channels are not checked");
+        let mut pwr_missing = samples(0.0);
+        pwr_missing[8] = None;
+        pwr_missing[9] = None;
+        assert_eq!(check_adc(&pwr_missing, false).status, CheckStatus::Warn);
+    }
+
+    #[test]
+    fn gpio_and_loopback() {
+        let mut lines = BTreeMap::new();
+        lines.insert("OUT_0".to_string(), LineHealth::Ok);
+
+        println!("Lines held by the tacd pass");
+        assert_eq!(check_gpio_lines(&lines).status, CheckStatus::Pass);
+
+        println!("Lines held by other processes do not");
+        lines.insert(
+            "OUT_1".to_string(),
+            LineHealth::Conflict {
+                consumer: Some("gpioset".to_string()),
+                attempts: 3,
+            },
+        );
+        let result = check_gpio_lines(&lines);
+        assert_eq!(result.status, CheckStatus::Fail);
+        assert!(result.detail.contains("OUT_1"));
+        assert!(!result.detail.contains("OUT_0"));
+
+        println!("Outputs have to change the applied voltage when pulsed");
+        assert_eq!(loopback_status(12.0, 0.1), CheckStatus::Pass);
+        assert_eq!(loopback_status(3.3, 3.2), CheckStatus::Fail);
+    }
+
+    #[test]
+    fn led_restore() {
+        let led = Topic::anonymous(Some(BlinkPattern::solid(0.0)));
+        let blinking = BlinkPattern::solid(0.5);
+
+        println!("The previous pattern is restored after blinking");
+        led.set(blinking.clone());
+        restore_led(&led, &blinking, Some(BlinkPattern::solid(0.0)));
+        assert!(led.try_get().unwrap().is_off());
+
+        println!("Patterns set while blinking are kept");
+        led.set(blinking.clone());
+        led.set(BlinkPattern::solid(1.0));
+        restore_led(&led, &blinking, Some(BlinkPattern::solid(0.0)));
+        assert!(led.try_get().unwrap().is_on());
+    }
+
+    #[test]
+    fn rauc_status() {
+        let slot = |state: &str, boot_status: &str| {
+            let mut slot = HashMap::new();
+            slot.insert("state".to_string(), state.to_string());
+            slot.insert("boot_status".to_string(), boot_status.to_string());
+            slot
+        };
+
+        let mut slots = HashMap::new();
+        slots.insert("rootfs_0".to_string(), slot("booted", "good"));
+        slots.insert("rootfs_1".to_string(), slot("inactive", "bad"));
+
+        println!("A good booted slot passes");
+        assert_eq!(check_rauc(Some(&slots), Some("")).status, CheckStatus::Pass);
+
+        println!("Errors reported by RAUC are worth a warning");
+        assert_eq!(
+            check_rauc(Some(&slots), Some("Failed to download")).status,
+            CheckStatus::Warn
+        );
+
+        println!("Bad booted slots or missing information fail");
+        slots.insert("rootfs_0".to_string(), slot("booted", "bad"));
+        assert_eq!(check_rauc(Some(&slots), None).status, CheckStatus::Fail);
+        assert_eq!(check_rauc(None, None).status, CheckStatus::Fail);
+    }
+}
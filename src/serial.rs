@@ -0,0 +1,608 @@
+// This file is part of tacd, the LXA TAC system daemon
+// Copyright (C) 2024 Pengutronix e.K.
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along
+// with this program; if not, write to the Free Software Foundation, Inc.,
+// 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+
+use std::fs::{canonicalize, read_to_string, remove_file, OpenOptions};
+use std::io::{ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use anyhow::Result;
+use async_std::channel::bounded;
+use async_std::future::timeout;
+use async_std::sync::Arc;
+use async_std::task::{block_on, sleep, spawn_blocking};
+use async_tungstenite::tungstenite::Message;
+use async_tungstenite::WebSocketStream;
+use futures::{select, FutureExt, SinkExt, StreamExt};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use tide::http::upgrade::Connection;
+use tide::{Request, Response, Server};
+
+use crate::broker::{BrokerBuilder, Topic};
+use crate::fs_root;
+use crate::http_server::{websocket, WriteAccess};
+use crate::watched_tasks::WatchedTasksBuilder;
+
+#[cfg(feature = "demo_mode")]
+mod tty {
+    use std::io::{self, Read, Write};
+
+    use anyhow::{bail, Result};
+
+    use super::SerialConfig;
+
+    pub(super) struct Tty;
+
+    impl Tty {
+        pub(super) fn open(_config: &SerialConfig) -> Result<Self> {
+            bail!("There are no serial ports in demo mode")
+        }
+
+        pub(super) fn try_clone(&self) -> Result<Self> {
+            bail!("There are no serial ports in demo mode")
+        }
+    }
+
+    impl Read for Tty {
+        fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+            Err(io::ErrorKind::Unsupported.into())
+        }
+    }
+
+    impl Write for Tty {
+        fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+            Err(io::ErrorKind::Unsupported.into())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+}
+
+#[cfg(not(feature = "demo_mode"))]
+mod tty;
+
+use tty::Tty;
+
+const LOCK_DIR: &str = "/run/lock";
+
+/// How often to check if the port exists and if someone else uses it
+const STATUS_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How often to check if data was sent or received via the port
+const ACTIVITY_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Number of chunks read from the tty that may wait to be sent to the client
+const QUEUE_LEN: usize = 16;
+
+const READ_CHUNK_SIZE: usize = 1024;
+
+/// A serial port that can be bridged to websocket clients
+struct PortInfo {
+    /// Used as prefix for the topic and websocket paths
+    path: &'static str,
+    /// Used to name the tasks that keep the status up to date
+    name: &'static str,
+    default_device: &'static str,
+    /// Sent to clients if the device does not exist
+    missing: &'static str,
+}
+
+/// The RS232/RS485 header on hardware variants that have it populated
+const HEADER: PortInfo = PortInfo {
+    path: "/v1/serial",
+    name: "serial",
+    default_device: "/dev/ttySTM2",
+    missing: "The RS232/RS485 header is not populated on this TAC",
+};
+
+/// The UART that is connected to the console of the DUT
+const DUT_CONSOLE: PortInfo = PortInfo {
+    path: "/v1/dut/serial",
+    name: "dut-serial",
+    default_device: "/dev/ttySTM1",
+    missing: "The configured DUT console device does not exist",
+};
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub enum Parity {
+    None,
+    Even,
+    Odd,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug, Default)]
+pub struct Rs485Config {
+    /// Drive the RS485 transceiver direction via the RTS line
+    pub enabled: bool,
+    /// Level of RTS while sending
+    pub rts_on_send: bool,
+    /// Level of RTS after sending
+    pub rts_after_send: bool,
+    /// Delay (in milliseconds) between setting RTS and sending
+    pub delay_rts_before_send: u32,
+    /// Delay (in milliseconds) between sending and resetting RTS
+    pub delay_rts_after_send: u32,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct SerialConfig {
+    pub device: String,
+    pub baud_rate: u32,
+    pub parity: Parity,
+    /// 1 or 2
+    pub stop_bits: u8,
+    pub rs485: Rs485Config,
+}
+
+impl SerialConfig {
+    fn new(device: &str) -> Self {
+        Self {
+            device: device.to_string(),
+            baud_rate: 115200,
+            parity: Parity::None,
+            stop_bits: 1,
+            rs485: Rs485Config::default(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub enum SerialStatus {
+    /// The device does not exist, e.g. because the header is not populated
+    /// on this TAC
+    NotPopulated,
+    Idle,
+    /// A client with the contained address is connected via websocket
+    Connected(String),
+    /// The port is locked by another process, e.g. ser2net started by the
+    /// labgrid exporter
+    InUse {
+        pid: Option<u32>,
+    },
+    /// The last attempt to use the port failed with the contained error
+    Failed(String),
+}
+
+/// A UUCP style lock file, like it is used by e.g. ser2net, that marks a
+/// tty as being in use
+struct PortLock {
+    path: PathBuf,
+}
+
+impl PortLock {
+    fn path(lock_dir: &Path, device: &str) -> PathBuf {
+        // Make sure that different names of the same device (e.g. symlinks
+        // created by udev) result in the same lock file.
+        let device = canonicalize(device).unwrap_or_else(|_| PathBuf::from(device));
+        let name = device
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_default();
+
+        lock_dir.join(format!("LCK..{name}"))
+    }
+
+    /// Get the process holding the lock, if it is still running
+    fn holder(path: &Path) -> Option<u32> {
+        let pid: u32 = read_to_string(path).ok()?.trim().parse().ok()?;
+
+        Path::new("/proc")
+            .join(pid.to_string())
+            .exists()
+            .then_some(pid)
+    }
+
+    /// Lock the port or return the process that holds the lock
+    fn acquire(lock_dir: &Path, device: &str) -> std::result::Result<Self, Option<u32>> {
+        let path = Self::path(lock_dir, device);
+
+        // Lock files of processes that are no longer running are stale
+        if path.exists() {
+            match Self::holder(&path) {
+                Some(pid) => return Err(Some(pid)),
+                None => {
+                    let _ = remove_file(&path);
+                }
+            }
+        }
+
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .map_err(|_| Self::holder(&path))?;
+
+        // The HDB UUCP format is the PID as ten characters wide ASCII number
+        let pid = format!("{:>10}\n", std::process::id());
+
+        if file.write_all(pid.as_bytes()).is_err() {
+            let _ = remove_file(&path);
+            return Err(None);
+        }
+
+        Ok(Self { path })
+    }
+}
+
+impl Drop for PortLock {
+    fn drop(&mut self) {
+        let _ = remove_file(&self.path);
+    }
+}
+
+/// Marks the port as in use by a websocket client until dropped
+struct Busy(Arc<AtomicBool>);
+
+impl Busy {
+    fn acquire(flag: &Arc<AtomicBool>) -> Option<Self> {
+        flag.compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+            .ok()
+            .map(|_| Self(flag.clone()))
+    }
+}
+
+impl Drop for Busy {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::SeqCst);
+    }
+}
+
+/// Was data received from / sent to the port recently?
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+pub struct SerialActivity {
+    pub rx: bool,
+    pub tx: bool,
+}
+
+fn error_response(status: u16, msg: String) -> Response {
+    Response::builder(status).body(msg).build()
+}
+
+#[derive(Clone)]
+struct Serial {
+    info: &'static PortInfo,
+    config: Arc<Topic<SerialConfig>>,
+    status: Arc<Topic<SerialStatus>>,
+    busy: Arc<AtomicBool>,
+    /// Number of bytes received from / sent to the port
+    rx_bytes: Arc<AtomicU64>,
+    tx_bytes: Arc<AtomicU64>,
+}
+
+impl Serial {
+    fn config(&self) -> SerialConfig {
+        self.config
+            .try_get()
+            .unwrap_or_else(|| SerialConfig::new(self.info.default_device))
+    }
+
+    /// Can the port be used right now?
+    fn availability(&self, config: &SerialConfig) -> SerialStatus {
+        if !Path::new(&config.device).exists() {
+            return SerialStatus::NotPopulated;
+        }
+
+        let lock = PortLock::path(&fs_root::path(LOCK_DIR), &config.device);
+
+        match lock.exists().then(|| PortLock::holder(&lock)).flatten() {
+            Some(pid) => SerialStatus::InUse { pid: Some(pid) },
+            None => SerialStatus::Idle,
+        }
+    }
+
+    fn update_status(&self) {
+        if self.busy.load(Ordering::SeqCst) {
+            // The bridge will update the status once it is done
+            return;
+        }
+
+        let config = self.config();
+        let available = self.availability(&config);
+
+        self.status.modify(|prev| match prev {
+            // Keep errors around until something changes
+            Some(SerialStatus::Failed(_)) if available == SerialStatus::Idle => None,
+            prev => (prev.as_ref() != Some(&available)).then_some(available),
+        });
+    }
+
+    /// Reserve and open the port
+    ///
+    /// Returns a response to send to the client if it can not be used.
+    fn open(&self) -> std::result::Result<(Tty, PortLock, Busy), Response> {
+        let config = self.config();
+
+        if !Path::new(&config.device).exists() {
+            return Err(error_response(503, self.info.missing.into()));
+        }
+
+        let busy = Busy::acquire(&self.busy).ok_or_else(|| {
+            error_response(409, "The port is already used by another client".into())
+        })?;
+
+        let lock = match PortLock::acquire(&fs_root::path(LOCK_DIR), &config.device) {
+            Ok(lock) => lock,
+            Err(pid) => {
+                self.status.set_if_changed(SerialStatus::InUse { pid });
+
+                return Err(error_response(
+                    409,
+                    "The port is used by another process, e.g. the labgrid exporter".into(),
+                ));
+            }
+        };
+
+        match Tty::open(&config) {
+            Ok(tty) => Ok((tty, lock, busy)),
+            Err(e) => {
+                self.status.set(SerialStatus::Failed(e.to_string()));
+
+                Err(error_response(500, format!("Failed to open the port: {e}")))
+            }
+        }
+    }
+
+    /// Forward data between the tty and a websocket client until either
+    /// side goes away
+    async fn bridge(&self, tty: Tty, ws: WebSocketStream<Connection>) -> Result<()> {
+        let (mut ws_tx, mut ws_rx) = ws.split();
+        let (tty_tx, tty_rx) = bounded::<Vec<u8>>(QUEUE_LEN);
+
+        let running = Arc::new(AtomicBool::new(true));
+        let mut reader = tty.try_clone()?;
+        let mut writer = tty;
+
+        // Reads from the tty time out regularly (see tty::Tty::open),
+        // so the thread notices when the connection is closed.
+        let running_thread = running.clone();
+        let rx_bytes = self.rx_bytes.clone();
+        thread::Builder::new()
+            .name("serial-bridge".into())
+            .spawn(move || {
+                let mut buf = [0; READ_CHUNK_SIZE];
+
+                while running_thread.load(Ordering::SeqCst) {
+                    match reader.read(&mut buf) {
+                        Ok(0) => {}
+                        Ok(len) => {
+                            rx_bytes.fetch_add(len as u64, Ordering::Relaxed);
+
+                            if block_on(tty_tx.send(buf[..len].to_vec())).is_err() {
+                                break;
+                            }
+                        }
+                        Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                        Err(e) => {
+                            warn!("Failed to read from serial port: {e}");
+                            break;
+                        }
+                    }
+                }
+            })?;
+
+        let res = loop {
+            select! {
+                msg = ws_rx.next().fuse() => {
+                    let data = match msg {
+                        Some(Ok(Message::Binary(data))) => data,
+                        Some(Ok(Message::Text(text))) => text.into_bytes(),
+                        Some(Ok(Message::Close(_))) | None => break Ok(()),
+                        Some(Ok(_)) => continue,
+                        Some(Err(e)) => break Err(e.into()),
+                    };
+
+                    self.tx_bytes.fetch_add(data.len() as u64, Ordering::Relaxed);
+
+                    // Writes may take a while at low baud rates
+                    let written = spawn_blocking(move || {
+                        writer.write_all(&data)?;
+                        Ok::<_, std::io::Error>(writer)
+                    })
+                    .await;
+
+                    match written {
+                        Ok(w) => writer = w,
+                        Err(e) => break Err(e.into()),
+                    }
+                },
+                data = tty_rx.next().fuse() => match data {
+                    Some(data) => {
+                        if let Err(e) = ws_tx.send(Message::binary(data)).await {
+                            break Err(e.into());
+                        }
+                    }
+                    None => break Err(anyhow::anyhow!("Reading from the port stopped")),
+                },
+            }
+        };
+
+        running.store(false, Ordering::SeqCst);
+
+        // Give the client a chance to notice that the bridge is closed
+        let _ = timeout(Duration::from_secs(1), ws_tx.close()).await;
+
+        res
+    }
+}
+
+/// Expose a serial port via a websocket and keep its status up to date
+fn setup_port(
+    bb: &mut BrokerBuilder,
+    wtb: &mut WatchedTasksBuilder,
+    server: &mut Server<()>,
+    info: &'static PortInfo,
+) -> Result<()> {
+    let serial = Serial {
+        info,
+        config: bb.topic(
+            &format!("{}/config", info.path),
+            true,
+            true,
+            true,
+            Some(SerialConfig::new(info.default_device)),
+            1,
+        ),
+        status: bb.topic_ro(
+            &format!("{}/status", info.path),
+            Some(SerialStatus::NotPopulated),
+        ),
+        busy: Arc::new(AtomicBool::new(false)),
+        rx_bytes: Arc::new(AtomicU64::new(0)),
+        tx_bytes: Arc::new(AtomicU64::new(0)),
+    };
+
+    let activity = bb.topic_ro(
+        &format!("{}/activity", info.path),
+        Some(SerialActivity::default()),
+    );
+
+    // Update the status whenever the configuration changes and periodically
+    // to notice other processes using the port.
+    let serial_task = serial.clone();
+    wtb.spawn_task(format!("{}-status", info.name), async move {
+        let (mut config_events, _) = serial_task.config.clone().subscribe_unbounded();
+
+        loop {
+            serial_task.update_status();
+
+            if let Ok(None) = timeout(STATUS_INTERVAL, config_events.next()).await {
+                break Ok(());
+            }
+        }
+    })?;
+
+    let serial_task = serial.clone();
+    wtb.spawn_task(format!("{}-activity", info.name), async move {
+        let mut prev = (0, 0);
+
+        loop {
+            sleep(ACTIVITY_INTERVAL).await;
+
+            let curr = (
+                serial_task.rx_bytes.load(Ordering::Relaxed),
+                serial_task.tx_bytes.load(Ordering::Relaxed),
+            );
+
+            activity.set_if_changed(SerialActivity {
+                rx: curr.0 != prev.0,
+                tx: curr.1 != prev.1,
+            });
+
+            prev = curr;
+        }
+    })?;
+
+    server
+        .at(&format!("{}/ws", info.path))
+        .get(move |req: Request<()>| {
+            let serial = serial.clone();
+
+            async move {
+                // Anything typed into the console is sent to the DUT
+                if let Some(res) = WriteAccess::refuse(&req) {
+                    return Ok(res);
+                }
+
+                let (tty, lock, busy) = match serial.open() {
+                    Ok(opened) => opened,
+                    Err(resp) => return Ok(resp),
+                };
+
+                let client = req.peer_addr().unwrap_or("unknown").to_string();
+
+                websocket::upgrade(&req, &[], move |ws| async move {
+                    serial.status.set(SerialStatus::Connected(client));
+
+                    let res = serial.bridge(tty, ws).await;
+
+                    drop(lock);
+                    drop(busy);
+
+                    match res {
+                        Ok(()) => serial.update_status(),
+                        Err(e) => {
+                            warn!("Serial bridge failed: {e}");
+                            serial.status.set(SerialStatus::Failed(e.to_string()));
+                        }
+                    }
+                })
+                .await
+            }
+        });
+
+    Ok(())
+}
+
+/// Expose the RS232/RS485 header of the TAC (if populated) and the console
+/// of the DUT via websockets
+///
+/// A port is only used while a client is connected. It is locked using a
+/// UUCP style lock file, like ser2net (and thus the labgrid exporter) does,
+/// so that only one of them can use it at a time.
+pub fn run(
+    bb: &mut BrokerBuilder,
+    wtb: &mut WatchedTasksBuilder,
+    server: &mut Server<()>,
+) -> Result<()> {
+    setup_port(bb, wtb, server, &HEADER)?;
+    setup_port(bb, wtb, server, &DUT_CONSOLE)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{create_dir_all, remove_dir_all, write};
+
+    use super::PortLock;
+
+    #[test]
+    fn lock() {
+        let dir = std::env::temp_dir().join(format!("tacd-serial-{}", std::process::id()));
+        create_dir_all(&dir).unwrap();
+
+        let device = "/dev/ttyTEST0";
+        let path = PortLock::path(&dir, device);
+        assert_eq!(path, dir.join("LCK..ttyTEST0"));
+
+        println!("Locks are created in the HDB UUCP format and removed on drop");
+        let lock = PortLock::acquire(&dir, device).unwrap();
+        let content = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(content.len(), 11);
+        assert_eq!(content.trim(), std::process::id().to_string());
+
+        println!("Locks held by running processes are respected");
+        assert_eq!(
+            PortLock::acquire(&dir, device).err(),
+            Some(Some(std::process::id()))
+        );
+
+        drop(lock);
+        assert!(!path.exists());
+
+        println!("Stale locks are removed");
+        write(&path, format!("{:>10}\n", u32::MAX)).unwrap();
+        assert!(PortLock::acquire(&dir, device).is_ok());
+
+        remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,191 @@
+// This file is part of tacd, the LXA TAC system daemon
+// Copyright (C) 2024 Pengutronix e.K.
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along
+// with this program; if not, write to the Free Software Foundation, Inc.,
+// 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+
+//! Raw mode access to a tty with optional RS485 direction control
+//!
+//! The RS485 structure definition follows `linux/serial.h`.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Error, Read, Write};
+use std::mem::zeroed;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+
+use anyhow::{bail, Context, Result};
+use nix::ioctl_write_ptr_bad;
+use nix::libc::{self, speed_t};
+
+use super::{Parity, Rs485Config, SerialConfig};
+
+const SER_RS485_ENABLED: u32 = 1 << 0;
+const SER_RS485_RTS_ON_SEND: u32 = 1 << 1;
+const SER_RS485_RTS_AFTER_SEND: u32 = 1 << 2;
+
+/// Reads return after this many tenths of a second without data, so that
+/// readers can check if they should stop
+const READ_TIMEOUT_DS: u8 = 1;
+
+#[repr(C)]
+struct SerialRs485 {
+    flags: u32,
+    delay_rts_before_send: u32,
+    delay_rts_after_send: u32,
+    padding: [u32; 5],
+}
+
+ioctl_write_ptr_bad!(tiocsrs485, libc::TIOCSRS485, SerialRs485);
+
+fn speed(baud_rate: u32) -> Result<speed_t> {
+    let speed = match baud_rate {
+        1200 => libc::B1200,
+        2400 => libc::B2400,
+        4800 => libc::B4800,
+        9600 => libc::B9600,
+        19200 => libc::B19200,
+        38400 => libc::B38400,
+        57600 => libc::B57600,
+        115200 => libc::B115200,
+        230400 => libc::B230400,
+        460800 => libc::B460800,
+        500000 => libc::B500000,
+        921600 => libc::B921600,
+        1000000 => libc::B1000000,
+        _ => bail!("Unsupported baud rate {baud_rate}"),
+    };
+
+    Ok(speed)
+}
+
+fn set_rs485(file: &File, config: &Rs485Config) -> Result<()> {
+    let mut flags = 0;
+
+    if config.enabled {
+        flags |= SER_RS485_ENABLED;
+    }
+
+    if config.rts_on_send {
+        flags |= SER_RS485_RTS_ON_SEND;
+    }
+
+    if config.rts_after_send {
+        flags |= SER_RS485_RTS_AFTER_SEND;
+    }
+
+    let rs485 = SerialRs485 {
+        flags,
+        delay_rts_before_send: config.delay_rts_before_send,
+        delay_rts_after_send: config.delay_rts_after_send,
+        padding: [0; 5],
+    };
+
+    let res = unsafe { tiocsrs485(file.as_raw_fd(), &rs485) };
+
+    // UARTs without RS485 support reject the ioctl, which is fine as long
+    // as RS485 mode was not requested.
+    if config.enabled {
+        res.context("Failed to enable RS485 mode")?;
+    }
+
+    Ok(())
+}
+
+pub(super) struct Tty {
+    file: File,
+}
+
+impl Tty {
+    /// Open the tty in raw mode with exclusive access
+    pub(super) fn open(config: &SerialConfig) -> Result<Self> {
+        let path = &config.device;
+
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NOCTTY)
+            .open(path)
+            .with_context(|| format!("Failed to open {path}"))?;
+
+        let fd = file.as_raw_fd();
+
+        // Prevent other processes from opening the tty while we use it
+        if unsafe { libc::ioctl(fd, libc::TIOCEXCL) } < 0 {
+            return Err(Error::last_os_error()).context("Failed to get exclusive access");
+        }
+
+        let mut tios: libc::termios = unsafe { zeroed() };
+
+        if unsafe { libc::tcgetattr(fd, &mut tios) } < 0 {
+            return Err(Error::last_os_error()).with_context(|| format!("{path} is not a tty"));
+        }
+
+        unsafe { libc::cfmakeraw(&mut tios) };
+
+        let speed = speed(config.baud_rate)?;
+        unsafe {
+            libc::cfsetispeed(&mut tios, speed);
+            libc::cfsetospeed(&mut tios, speed);
+        }
+
+        tios.c_cflag |= libc::CLOCAL | libc::CREAD;
+        tios.c_cflag &= !(libc::PARENB | libc::PARODD | libc::CSTOPB | libc::CRTSCTS);
+
+        match config.parity {
+            Parity::None => {}
+            Parity::Even => tios.c_cflag |= libc::PARENB,
+            Parity::Odd => tios.c_cflag |= libc::PARENB | libc::PARODD,
+        }
+
+        match config.stop_bits {
+            1 => {}
+            2 => tios.c_cflag |= libc::CSTOPB,
+            n => bail!("Unsupported number of stop bits {n}"),
+        }
+
+        tios.c_cc[libc::VMIN] = 0;
+        tios.c_cc[libc::VTIME] = READ_TIMEOUT_DS;
+
+        if unsafe { libc::tcsetattr(fd, libc::TCSANOW, &tios) } < 0 {
+            return Err(Error::last_os_error()).context("Failed to configure the tty");
+        }
+
+        set_rs485(&file, &config.rs485)?;
+
+        Ok(Self { file })
+    }
+
+    pub(super) fn try_clone(&self) -> Result<Self> {
+        Ok(Self {
+            file: self.file.try_clone()?,
+        })
+    }
+}
+
+impl Read for Tty {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.file.read(buf)
+    }
+}
+
+impl Write for Tty {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
@@ -17,7 +17,6 @@
 
 use std::fs::{create_dir_all, read, write};
 use std::io::ErrorKind;
-use std::path::Path;
 
 use anyhow::Result;
 use async_std::prelude::*;
@@ -25,17 +24,20 @@ use async_std::sync::Arc;
 use tide::{http::mime, Request, Response, Server};
 
 use crate::broker::{BrokerBuilder, Topic};
+use crate::fs_root;
 use crate::watched_tasks::WatchedTasksBuilder;
 
-#[cfg(feature = "demo_mode")]
-const AUTHORIZED_KEYS_PATH: &str = "demo_files/home/root/ssh/authorized_keys";
+mod key_import;
+pub use key_import::{Decision, KeyImport, PendingImport};
 
-#[cfg(not(feature = "demo_mode"))]
 const AUTHORIZED_KEYS_PATH: &str = "/home/root/.ssh/authorized_keys";
 
 pub struct SetupMode {
     pub setup_mode: Arc<Topic<bool>>,
     pub show_help: Arc<Topic<bool>>,
+    /// Set every time the setup mode is left, e.g. to start the intro tour
+    pub completed: Arc<Topic<bool>>,
+    pub key_import: KeyImport,
 }
 
 impl SetupMode {
@@ -51,7 +53,7 @@ impl SetupMode {
 
             async move {
                 let res = if setup_mode.get().await {
-                    let fs_path = Path::new(fs_path);
+                    let fs_path = fs_root::path(fs_path);
                     let parent = fs_path.parent().unwrap();
 
                     if !parent.exists() {
@@ -59,7 +61,7 @@ impl SetupMode {
                     }
 
                     let content = req.body_bytes().await?;
-                    write(fs_path, content)?;
+                    write(&fs_path, content)?;
 
                     Response::new(204)
                 } else {
@@ -79,7 +81,7 @@ impl SetupMode {
 
             async move {
                 let res = if setup_mode.get().await {
-                    match read(fs_path) {
+                    match read(fs_root::path(fs_path)) {
                         Ok(content) => Response::builder(200)
                             .body(content)
                             .content_type(mime::PLAIN)
@@ -120,12 +122,19 @@ impl SetupMode {
             .topic_wo::<bool>("/v1/tac/setup_mode", None)
             .subscribe_unbounded();
         let setup_mode = self.setup_mode.clone();
+        let completed = self.completed.clone();
 
         wtb.spawn_task("setup-mode-leave-request", async move {
             while let Some(lr) = leave_requests.next().await {
                 if !lr {
+                    let was_active = setup_mode.try_get() == Some(true);
+
                     // Only ever set the setup mode to false in here
-                    setup_mode.set(false)
+                    setup_mode.set(false);
+
+                    if was_active {
+                        completed.set(true);
+                    }
                 }
             }
 
@@ -138,8 +147,14 @@ impl SetupMode {
         wtb: &mut WatchedTasksBuilder,
         server: &mut Server<()>,
     ) -> Result<Self> {
+        let setup_mode = bb.topic("/v1/tac/setup_mode", true, false, true, Some(true), 1);
+
+        // Allow importing SSH keys from the network, with a confirmation
+        // on the LCD.
+        let key_import = KeyImport::new(bb, wtb, setup_mode.clone())?;
+
         let this = Self {
-            setup_mode: bb.topic("/v1/tac/setup_mode", true, false, true, Some(true), 1),
+            setup_mode,
             show_help: bb.topic(
                 "/v1/tac/display/show_help",
                 true,
@@ -148,6 +163,8 @@ impl SetupMode {
                 Some(true),
                 1,
             ),
+            completed: Topic::anonymous(None),
+            key_import,
         };
 
         this.handle_leave_requests(bb, wtb)?;
@@ -0,0 +1,522 @@
+// This file is part of tacd, the LXA TAC system daemon
+// Copyright (C) 2024 Pengutronix e.K.
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along
+// with this program; if not, write to the Free Software Foundation, Inc.,
+// 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+
+//! Import SSH public keys from GitHub, GitLab or an arbitrary URL
+//!
+//! Fetched keys are not trusted right away. Their fingerprints are shown on
+//! the LCD and the import has to be confirmed there, so that only someone
+//! with physical access to the TAC can add keys this way.
+
+use std::fmt;
+use std::fs::{create_dir_all, read_to_string, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Result};
+use async_std::future::timeout;
+use async_std::io::Read;
+use async_std::prelude::*;
+use async_std::sync::Arc;
+use base64::engine::general_purpose::{STANDARD, STANDARD_NO_PAD};
+use base64::Engine;
+use chrono::Local;
+use futures::{select, FutureExt};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use surf::Url;
+
+use super::AUTHORIZED_KEYS_PATH;
+use crate::broker::{BrokerBuilder, Topic};
+use crate::fs_root;
+use crate::watched_tasks::WatchedTasksBuilder;
+
+const AUDIT_LOG_PATH: &str = "/srv/tacd/ssh-key-imports.log";
+
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Nobody has this many keys. Refuse to handle responses that are larger.
+const MAX_RESPONSE_SIZE: usize = 64 * 1024;
+
+/// Key types accepted for import
+const KEY_TYPES: &[&str] = &[
+    "ssh-ed25519",
+    "ssh-rsa",
+    "ecdsa-sha2-nistp256",
+    "ecdsa-sha2-nistp384",
+    "ecdsa-sha2-nistp521",
+    "sk-ssh-ed25519@openssh.com",
+    "sk-ecdsa-sha2-nistp256@openssh.com",
+];
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub enum KeySource {
+    /// The keys of a GitHub user (https://github.com/<user>.keys)
+    GitHub(String),
+    /// The keys of a gitlab.com user (https://gitlab.com/<user>.keys)
+    GitLab(String),
+    /// A URL that provides keys in authorized_keys format
+    Url(String),
+}
+
+impl KeySource {
+    fn url(&self) -> Result<Url> {
+        let valid_user = |user: &str| {
+            !user.is_empty()
+                && !user.starts_with('.')
+                && user
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c))
+        };
+
+        let url = match self {
+            Self::GitHub(user) if valid_user(user) => format!("https://github.com/{user}.keys"),
+            Self::GitLab(user) if valid_user(user) => format!("https://gitlab.com/{user}.keys"),
+            Self::GitHub(user) | Self::GitLab(user) => bail!("Invalid user name \"{user}\""),
+            Self::Url(url) => url.clone(),
+        };
+
+        // surf panics on URLs it can not parse, so check them beforehand
+        let url = Url::parse(&url)?;
+
+        if !matches!(url.scheme(), "http" | "https") {
+            bail!("Unsupported URL \"{url}\"");
+        }
+
+        Ok(url)
+    }
+
+    /// A short description of the source for the display and the comment
+    /// field of imported keys
+    pub fn label(&self) -> String {
+        match self {
+            Self::GitHub(user) => format!("github:{user}"),
+            Self::GitLab(user) => format!("gitlab:{user}"),
+            Self::Url(url) => url.clone(),
+        }
+    }
+}
+
+impl fmt::Display for KeySource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.label())
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct ImportedKey {
+    pub key_type: String,
+    /// The base64 encoded public key
+    pub key: String,
+    pub comment: Option<String>,
+    /// The fingerprint in the format used by `ssh-keygen -l`
+    pub fingerprint: String,
+}
+
+impl ImportedKey {
+    /// Parse and validate a single line in authorized_keys format
+    ///
+    /// Lines containing options (like `command="..."`) are rejected,
+    /// as they have no place in a list of keys fetched from the network.
+    fn parse(line: &str) -> Result<Self> {
+        let mut fields = line.split_whitespace();
+
+        let key_type = fields.next().ok_or_else(|| anyhow!("Empty line"))?;
+        let key = fields.next().ok_or_else(|| anyhow!("Missing key data"))?;
+        let comment: Vec<&str> = fields.collect();
+
+        if !KEY_TYPES.contains(&key_type) {
+            bail!("Unsupported key type \"{key_type}\"");
+        }
+
+        let blob = STANDARD.decode(key)?;
+
+        // The blob starts with the key type as length-prefixed string
+        let embedded_type = blob
+            .get(..4)
+            .map(|len| u32::from_be_bytes([len[0], len[1], len[2], len[3]]) as usize)
+            .and_then(|len| blob.get(4..(4 + len)));
+
+        if embedded_type != Some(key_type.as_bytes()) {
+            bail!("Key data does not match the key type \"{key_type}\"");
+        }
+
+        let fingerprint = format!("SHA256:{}", STANDARD_NO_PAD.encode(Sha256::digest(&blob)));
+
+        Ok(Self {
+            key_type: key_type.to_string(),
+            key: key.to_string(),
+            comment: (!comment.is_empty()).then(|| comment.join(" ")),
+            fingerprint,
+        })
+    }
+
+    /// Parse all keys in a document in authorized_keys format
+    fn parse_all(content: &str) -> Result<Vec<Self>> {
+        let mut keys: Vec<Self> = Vec::new();
+
+        for line in content.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let key = Self::parse(line)?;
+
+            if !keys.iter().any(|k| k.key == key.key) {
+                keys.push(key);
+            }
+        }
+
+        if keys.is_empty() {
+            bail!("No SSH keys found");
+        }
+
+        Ok(keys)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct PendingImport {
+    pub id: u64,
+    pub source: KeySource,
+    pub keys: Vec<ImportedKey>,
+}
+
+/// The answer given on the LCD to a pending import
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub struct Decision {
+    /// The id of the pending import this decision is about
+    pub id: u64,
+    pub accept: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub enum ImportStatus {
+    Idle,
+    Fetching,
+    AwaitingConfirmation,
+    /// The contained number of new keys was added
+    Imported(usize),
+    Rejected,
+    Failed(String),
+}
+
+/// Append a line to the log of all import attempts
+fn audit(message: &str) {
+    let path = fs_root::path(AUDIT_LOG_PATH);
+    let line = format!("{} {message}\n", Local::now().to_rfc3339());
+
+    let res = path
+        .parent()
+        .map_or(Ok(()), create_dir_all)
+        .and_then(|_| OpenOptions::new().create(true).append(true).open(&path))
+        .and_then(|mut file| file.write_all(line.as_bytes()));
+
+    if let Err(e) = res {
+        warn!("Failed to write SSH key import audit log: {e}");
+    }
+}
+
+fn fingerprints(keys: &[ImportedKey]) -> String {
+    keys.iter()
+        .map(|k| k.fingerprint.as_str())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Read a response body without ever buffering more than `limit` bytes
+/// (plus one to detect responses that are too large)
+async fn read_limited<R: Read + Unpin>(body: R, limit: usize) -> Result<String> {
+    let mut content = Vec::new();
+
+    body.take(limit as u64 + 1)
+        .read_to_end(&mut content)
+        .await?;
+
+    if content.len() > limit {
+        bail!("Response is too large");
+    }
+
+    Ok(String::from_utf8(content)?)
+}
+
+async fn fetch(source: &KeySource) -> Result<Vec<ImportedKey>> {
+    let url = source.url()?;
+
+    let request = async {
+        let mut res = surf::get(url).await.map_err(|e| anyhow!("{e}"))?;
+
+        if !res.status().is_success() {
+            bail!("Server responded with {}", res.status());
+        }
+
+        read_limited(res.take_body(), MAX_RESPONSE_SIZE).await
+    };
+
+    let content = timeout(FETCH_TIMEOUT, request).await??;
+
+    ImportedKey::parse_all(&content)
+}
+
+/// Add the keys to the authorized_keys file, skipping those that are already in it
+///
+/// Returns the number of keys that were added.
+fn install(import: &PendingImport) -> Result<usize> {
+    let path = fs_root::path(AUTHORIZED_KEYS_PATH);
+
+    let existing = match read_to_string(&path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e.into()),
+    };
+
+    let is_known = |key: &ImportedKey| {
+        existing
+            .lines()
+            .any(|line| line.split_whitespace().any(|field| field == key.key))
+    };
+
+    let mut lines = String::new();
+    let mut added = 0;
+
+    for key in import.keys.iter().filter(|k| !is_known(k)) {
+        let comment = key.comment.clone().unwrap_or_else(|| import.source.label());
+
+        lines.push_str(&format!("{} {} {comment}\n", key.key_type, key.key));
+        added += 1;
+    }
+
+    if added > 0 {
+        if let Some(parent) = path.parent() {
+            create_dir_all(parent)?;
+        }
+
+        let mut file = OpenOptions::new().create(true).append(true).open(&path)?;
+
+        if !existing.is_empty() && !existing.ends_with('\n') {
+            file.write_all(b"\n")?;
+        }
+
+        file.write_all(lines.as_bytes())?;
+    }
+
+    Ok(added)
+}
+
+pub struct KeyImport {
+    pub pending: Arc<Topic<Option<PendingImport>>>,
+    pub decision: Arc<Topic<Decision>>,
+}
+
+impl KeyImport {
+    pub fn new(
+        bb: &mut BrokerBuilder,
+        wtb: &mut WatchedTasksBuilder,
+        setup_mode: Arc<Topic<bool>>,
+    ) -> Result<Self> {
+        let request = bb.topic_wo::<KeySource>("/v1/tac/ssh/import", None);
+        let pending = bb.topic_ro("/v1/tac/ssh/import/pending", Some(None));
+        let status = bb.topic_ro("/v1/tac/ssh/import/status", Some(ImportStatus::Idle));
+
+        // Decisions can only be made using the buttons on the TAC,
+        // so this topic is not exposed to the outside.
+        let decision = Topic::anonymous(None);
+
+        let (mut request_events, _) = request.subscribe_unbounded();
+        let (mut decision_events, _) = decision.clone().subscribe_unbounded();
+        let (mut setup_mode_events, _) = setup_mode.clone().subscribe_unbounded();
+        let pending_task = pending.clone();
+
+        wtb.spawn_task("ssh-key-import", async move {
+            let mut next_id = 1;
+
+            loop {
+                select! {
+                    source = request_events.next().fuse() => {
+                        let source: KeySource = match source {
+                            Some(source) => source,
+                            None => break,
+                        };
+
+                        if setup_mode.try_get() != Some(true) {
+                            audit(&format!("Refused import from {source}: not in setup mode"));
+                            status.set(ImportStatus::Failed(
+                                "SSH keys can only be imported in setup mode".to_string(),
+                            ));
+                            continue;
+                        }
+
+                        pending_task.set(None);
+                        status.set(ImportStatus::Fetching);
+
+                        match fetch(&source).await {
+                            Ok(keys) => {
+                                audit(&format!(
+                                    "Fetched {} keys from {source}: {}",
+                                    keys.len(),
+                                    fingerprints(&keys)
+                                ));
+
+                                pending_task.set(Some(PendingImport {
+                                    id: next_id,
+                                    source,
+                                    keys,
+                                }));
+                                status.set(ImportStatus::AwaitingConfirmation);
+
+                                next_id += 1;
+                            }
+                            Err(e) => {
+                                audit(&format!("Failed to fetch keys from {source}: {e}"));
+                                status.set(ImportStatus::Failed(e.to_string()));
+                            }
+                        }
+                    },
+                    decision = decision_events.next().fuse() => {
+                        let decision = match decision {
+                            Some(decision) => decision,
+                            None => break,
+                        };
+
+                        // Ignore decisions about imports that were superseded
+                        // in the meantime.
+                        let import = match pending_task.try_get().flatten() {
+                            Some(import) if import.id == decision.id => import,
+                            _ => continue,
+                        };
+
+                        pending_task.set(None);
+
+                        if !decision.accept {
+                            audit(&format!(
+                                "Rejected keys from {}: {}",
+                                import.source,
+                                fingerprints(&import.keys)
+                            ));
+                            status.set(ImportStatus::Rejected);
+                            continue;
+                        }
+
+                        match install(&import) {
+                            Ok(added) => {
+                                audit(&format!(
+                                    "Imported {added} new keys from {}: {}",
+                                    import.source,
+                                    fingerprints(&import.keys)
+                                ));
+                                status.set(ImportStatus::Imported(added));
+                            }
+                            Err(e) => {
+                                audit(&format!(
+                                    "Failed to import keys from {}: {e}",
+                                    import.source
+                                ));
+                                status.set(ImportStatus::Failed(e.to_string()));
+                            }
+                        }
+                    },
+                    setup_mode = setup_mode_events.next().fuse() => {
+                        match setup_mode {
+                            Some(true) => {}
+                            Some(false) => {
+                                if let Some(import) = pending_task.try_get().flatten() {
+                                    audit(&format!(
+                                        "Discarded keys from {}: left setup mode",
+                                        import.source
+                                    ));
+                                    pending_task.set(None);
+                                    status.set(ImportStatus::Idle);
+                                }
+                            }
+                            None => break,
+                        }
+                    },
+                }
+            }
+
+            Ok(())
+        })?;
+
+        Ok(Self { pending, decision })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_std::task::block_on;
+
+    use super::{read_limited, ImportedKey, KeySource};
+
+    const KEY: &str = "AAAAC3NzaC1lZDI1NTE5AAAAIPA3uuH2CBIlwGgn7hA9mSqMV/AWFRqj1tflhntj11Gx";
+
+    #[test]
+    fn parse_keys() {
+        println!("Valid key with comment");
+        let key = ImportedKey::parse(&format!("ssh-ed25519 {KEY} tux@igloo")).unwrap();
+        assert_eq!(key.key_type, "ssh-ed25519");
+        assert_eq!(key.comment.as_deref(), Some("tux@igloo"));
+        assert_eq!(
+            key.fingerprint,
+            "SHA256:SK7bnQLW/8vgm6yMRKD/Cy8RVVWOAK2TIWN1BgqcAJw"
+        );
+
+        println!("Duplicates, comments and empty lines");
+        let keys = ImportedKey::parse_all(&format!(
+            "# keys\n\nssh-ed25519 {KEY}\nssh-ed25519 {KEY} again\n"
+        ))
+        .unwrap();
+        assert_eq!(keys.len(), 1);
+        assert_eq!(keys[0].comment, None);
+
+        println!("Invalid keys");
+        assert!(ImportedKey::parse(&format!("ssh-rsa {KEY}")).is_err());
+        assert!(ImportedKey::parse(&format!("command=\"sh\" ssh-ed25519 {KEY}")).is_err());
+        assert!(ImportedKey::parse("ssh-ed25519 not-base64").is_err());
+        assert!(ImportedKey::parse_all("# nothing here\n").is_err());
+    }
+
+    #[test]
+    fn source_urls() {
+        assert_eq!(
+            KeySource::GitHub("tux".into()).url().unwrap().as_str(),
+            "https://github.com/tux.keys"
+        );
+        assert_eq!(
+            KeySource::GitLab("tux.penguin".into())
+                .url()
+                .unwrap()
+                .as_str(),
+            "https://gitlab.com/tux.penguin.keys"
+        );
+        assert!(KeySource::GitHub("../tux".into()).url().is_err());
+        assert!(KeySource::GitHub("".into()).url().is_err());
+        assert!(KeySource::Url("file:///etc/shadow".into()).url().is_err());
+        assert!(KeySource::Url("https://".into()).url().is_err());
+    }
+
+    #[test]
+    fn response_limit() {
+        let body = "ssh-ed25519 AAAA\n".repeat(4);
+
+        println!("Responses up to the limit are read completely");
+        let content = block_on(read_limited(body.as_bytes(), body.len())).unwrap();
+        assert_eq!(content, body);
+
+        println!("Larger ones are refused");
+        assert!(block_on(read_limited(body.as_bytes(), body.len() - 1)).is_err());
+    }
+}
@@ -0,0 +1,298 @@
+// This file is part of tacd, the LXA TAC system daemon
+// Copyright (C) 2024 Pengutronix e.K.
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along
+// with this program; if not, write to the Free Software Foundation, Inc.,
+// 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+
+//! Measure the TCP throughput between the TAC and the DUT
+//!
+//! This is not compatible with iperf3. Instead plain TCP streams are used,
+//! so that no special tools are needed on the DUT. E.g.:
+//!
+//! * DUT to TAC: `dd if=/dev/zero bs=1M count=100 | nc <tac> 5202`
+//! * TAC to DUT: `nc -l -p 5202 > /dev/null` on the DUT
+
+use std::net::Shutdown;
+use std::time::{Duration, Instant, SystemTime};
+
+use anyhow::{anyhow, bail, Result};
+use async_std::future::timeout;
+use async_std::net::{TcpListener, TcpStream};
+use async_std::prelude::*;
+use async_std::sync::Arc;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::broker::{BrokerBuilder, Topic};
+use crate::watched_tasks::WatchedTasksBuilder;
+
+const SPEEDTEST_PORT: u16 = 5202;
+
+/// How long to wait for the DUT to connect or to accept a connection
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Tests stop after this time, even if the DUT keeps on sending
+const MAX_DURATION: Duration = Duration::from_secs(60);
+
+/// Consider the test failed if no data can be sent or received for this long
+const STALL_TIMEOUT: Duration = Duration::from_secs(5);
+
+const BUFFER_SIZE: usize = 128 * 1024;
+
+/// Number of past results to keep around
+const HISTORY_LENGTH: usize = 16;
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub enum SpeedtestRequest {
+    /// Wait for the DUT to connect and send data to the TAC
+    Receive,
+    /// Connect to a listener on the DUT and send data for a number of seconds
+    Send { address: String, duration: u64 },
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub enum SpeedtestDirection {
+    DutToTac,
+    TacToDut,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub enum SpeedtestStatus {
+    Idle,
+    /// Waiting for the DUT to connect on the contained port
+    Listening(u16),
+    Running,
+    Failed(String),
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct SpeedtestResult {
+    /// Javascript timestamp (milliseconds since Unix Epoch 0) of the start
+    pub ts: f64,
+    pub direction: SpeedtestDirection,
+    pub peer: String,
+    pub bytes: u64,
+    /// Duration of the transfer in seconds
+    pub duration: f64,
+    pub mbit_per_s: f64,
+}
+
+fn js_timestamp_now() -> f64 {
+    let since_epoch = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default();
+
+    1000.0 * since_epoch.as_secs_f64()
+}
+
+impl SpeedtestResult {
+    fn new(
+        ts: f64,
+        direction: SpeedtestDirection,
+        peer: String,
+        bytes: u64,
+        duration: Duration,
+    ) -> Self {
+        let duration = duration.as_secs_f64();
+
+        let mbit_per_s = if duration > 0.0 {
+            (bytes as f64) * 8.0 / duration / 1_000_000.0
+        } else {
+            0.0
+        };
+
+        Self {
+            ts,
+            direction,
+            peer,
+            bytes,
+            duration,
+            mbit_per_s,
+        }
+    }
+}
+
+/// Wait for the DUT to connect and count the bytes it sends
+async fn receive(status: &Topic<SpeedtestStatus>) -> Result<SpeedtestResult> {
+    let listener = TcpListener::bind(("::", SPEEDTEST_PORT)).await?;
+
+    status.set(SpeedtestStatus::Listening(SPEEDTEST_PORT));
+
+    let (mut stream, peer) = timeout(CONNECT_TIMEOUT, listener.accept())
+        .await
+        .map_err(|_| anyhow!("The DUT did not connect in time"))??;
+
+    // Only accept a single connection per test
+    drop(listener);
+
+    status.set(SpeedtestStatus::Running);
+
+    let ts = js_timestamp_now();
+    let start = Instant::now();
+    let mut buf = vec![0; BUFFER_SIZE];
+    let mut bytes = 0;
+
+    while start.elapsed() < MAX_DURATION {
+        let len = timeout(STALL_TIMEOUT, stream.read(&mut buf))
+            .await
+            .map_err(|_| anyhow!("The DUT stopped sending"))??;
+
+        if len == 0 {
+            break;
+        }
+
+        bytes += len as u64;
+    }
+
+    let direction = SpeedtestDirection::DutToTac;
+
+    Ok(SpeedtestResult::new(
+        ts,
+        direction,
+        peer.to_string(),
+        bytes,
+        start.elapsed(),
+    ))
+}
+
+/// Connect to a listener on the DUT and send data to it
+async fn send(address: &str, duration: Duration) -> Result<SpeedtestResult> {
+    if duration.is_zero() || duration > MAX_DURATION {
+        bail!(
+            "The duration must be between 1 and {}s",
+            MAX_DURATION.as_secs()
+        );
+    }
+
+    let mut stream = timeout(CONNECT_TIMEOUT, TcpStream::connect(address))
+        .await
+        .map_err(|_| anyhow!("Connecting to {address} timed out"))??;
+
+    let peer = stream.peer_addr()?;
+    let buf = vec![0; BUFFER_SIZE];
+
+    let ts = js_timestamp_now();
+    let start = Instant::now();
+    let mut bytes = 0;
+
+    while start.elapsed() < duration {
+        timeout(STALL_TIMEOUT, stream.write_all(&buf))
+            .await
+            .map_err(|_| anyhow!("The DUT stopped receiving"))??;
+
+        bytes += buf.len() as u64;
+    }
+
+    stream.shutdown(Shutdown::Write)?;
+
+    let direction = SpeedtestDirection::TacToDut;
+
+    Ok(SpeedtestResult::new(
+        ts,
+        direction,
+        peer.to_string(),
+        bytes,
+        start.elapsed(),
+    ))
+}
+
+/// Allow measuring the throughput between the TAC and the DUT on request
+pub fn run(bb: &mut BrokerBuilder, wtb: &mut WatchedTasksBuilder) -> Result<()> {
+    let request = bb.topic_wo::<SpeedtestRequest>("/v1/tac/network/dut/speedtest", None);
+    let status = bb.topic_ro(
+        "/v1/tac/network/dut/speedtest/status",
+        Some(SpeedtestStatus::Idle),
+    );
+    let history: Arc<Topic<Vec<SpeedtestResult>>> =
+        bb.topic_ro("/v1/tac/network/dut/speedtest/history", Some(Vec::new()));
+
+    // Requests that arrive while a test is running are queued up and
+    // handled one after another.
+    let (mut requests, _) = request.subscribe_unbounded();
+
+    wtb.spawn_task("speedtest", async move {
+        while let Some(req) = requests.next().await {
+            let res = match req {
+                SpeedtestRequest::Receive => receive(&status).await,
+                SpeedtestRequest::Send { address, duration } => {
+                    status.set(SpeedtestStatus::Running);
+                    send(&address, Duration::from_secs(duration)).await
+                }
+            };
+
+            match res {
+                Ok(result) => {
+                    info!(
+                        "Speed test with {}: {:.1} MBit/s",
+                        result.peer, result.mbit_per_s
+                    );
+
+                    history.modify(|prev| {
+                        let mut list = prev.unwrap_or_default();
+
+                        list.push(result);
+
+                        if list.len() > HISTORY_LENGTH {
+                            list.drain(..(list.len() - HISTORY_LENGTH));
+                        }
+
+                        Some(list)
+                    });
+
+                    status.set(SpeedtestStatus::Idle);
+                }
+                Err(e) => {
+                    warn!("Speed test failed: {e}");
+                    status.set(SpeedtestStatus::Failed(e.to_string()));
+                }
+            }
+        }
+
+        Ok(())
+    })?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{SpeedtestDirection, SpeedtestResult};
+
+    #[test]
+    fn throughput() {
+        let result = SpeedtestResult::new(
+            0.0,
+            SpeedtestDirection::DutToTac,
+            "[::1]:1234".to_string(),
+            125_000_000,
+            Duration::from_secs(10),
+        );
+
+        assert_eq!(result.duration, 10.0);
+        assert_eq!(result.mbit_per_s, 100.0);
+
+        println!("Empty transfers do not divide by zero");
+        let result = SpeedtestResult::new(
+            0.0,
+            SpeedtestDirection::TacToDut,
+            "[::1]:1234".to_string(),
+            0,
+            Duration::ZERO,
+        );
+
+        assert_eq!(result.mbit_per_s, 0.0);
+    }
+}
@@ -0,0 +1,303 @@
+// This file is part of tacd, the LXA TAC system daemon
+// Copyright (C) 2024 Pengutronix e.K.
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along
+// with this program; if not, write to the Free Software Foundation, Inc.,
+// 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+
+use std::fmt::{self, Display, Formatter};
+
+use async_std::sync::Arc;
+use html_escape::encode_text;
+use tide::{Body, Response, Server};
+
+use crate::broker::Topic;
+use crate::dbus::Hostname;
+use crate::dut_power::{DutPwrThread, OutputState};
+use crate::iobus::IoBus;
+use crate::measurement::Measurement;
+use crate::rtc::{BackupState, Rtc, RtcBackup};
+use crate::system::{Barebox, System, Uname};
+use crate::temperatures::{Temperatures, Warning};
+use crate::usb_hub::{OverloadedPort, UsbHub, UsbPortFault};
+
+/// How often the browser should reload the page
+const REFRESH_INTERVAL_S: u32 = 10;
+
+/// A snapshot of the values shown on the page
+pub struct Status {
+    pub hostname: Option<String>,
+    pub tacd_version: Option<String>,
+    pub kernel_version: Option<String>,
+    pub bootloader_version: Option<String>,
+    pub dut_pwr: Option<OutputState>,
+    pub soc_temperature: Option<f32>,
+    pub alarms: Vec<String>,
+}
+
+impl Display for Status {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let hostname = encode_text(self.hostname.as_deref().unwrap_or("LXA TAC"));
+        let unknown = || "unknown".to_string();
+
+        writeln!(f, "<!DOCTYPE html>")?;
+        writeln!(f, "<html>")?;
+        writeln!(f, "  <head>")?;
+        writeln!(f, "    <meta charset=\"utf-8\" />")?;
+        writeln!(
+            f,
+            "    <meta name=\"viewport\" content=\"width=device-width, initial-scale=1\" />"
+        )?;
+        writeln!(
+            f,
+            "    <meta http-equiv=\"refresh\" content=\"{REFRESH_INTERVAL_S}\" />"
+        )?;
+        writeln!(f, "    <title>{hostname} - Status</title>")?;
+        writeln!(f, "    <style>")?;
+        writeln!(f, "      body {{ font-family: sans-serif; }}")?;
+        writeln!(f, "      th {{ text-align: left; padding-right: 1em; }}")?;
+        writeln!(f, "      .alarm {{ color: #d91515; }}")?;
+        writeln!(f, "    </style>")?;
+        writeln!(f, "  </head>")?;
+        writeln!(f, "  <body>")?;
+        writeln!(f, "    <h1>{hostname}</h1>")?;
+
+        writeln!(f, "    <h2>Alarms</h2>")?;
+
+        if self.alarms.is_empty() {
+            writeln!(f, "    <p>None</p>")?;
+        } else {
+            writeln!(f, "    <ul>")?;
+
+            for alarm in &self.alarms {
+                writeln!(f, "      <li class=\"alarm\">{}</li>", encode_text(alarm))?;
+            }
+
+            writeln!(f, "    </ul>")?;
+        }
+
+        let dut_pwr = self
+            .dut_pwr
+            .map_or_else(unknown, |state| format!("{state:?}"));
+        let soc_temperature = self
+            .soc_temperature
+            .map_or_else(unknown, |temp| format!("{temp:.1}°C"));
+
+        writeln!(f, "    <h2>Status</h2>")?;
+        writeln!(f, "    <table>")?;
+        writeln!(f, "      <tr><th>DUT power</th><td>{dut_pwr}</td></tr>")?;
+        writeln!(
+            f,
+            "      <tr><th>SoC temperature</th><td>{soc_temperature}</td></tr>"
+        )?;
+        writeln!(f, "    </table>")?;
+
+        let versions = [
+            ("tacd", &self.tacd_version),
+            ("Kernel", &self.kernel_version),
+            ("Bootloader", &self.bootloader_version),
+        ];
+
+        writeln!(f, "    <h2>Versions</h2>")?;
+        writeln!(f, "    <table>")?;
+
+        for (name, version) in versions {
+            let version = version
+                .as_deref()
+                .map_or_else(unknown, |version| encode_text(version).into_owned());
+            writeln!(f, "      <tr><th>{name}</th><td>{version}</td></tr>")?;
+        }
+
+        writeln!(f, "    </table>")?;
+        writeln!(f, "  </body>")?;
+        writeln!(f, "</html>")?;
+
+        Ok(())
+    }
+}
+
+/// The topics the status page (and the beacon) is rendered from
+#[derive(Clone)]
+pub struct StatusTopics {
+    hostname: Arc<Topic<String>>,
+    tacd_version: Arc<Topic<String>>,
+    uname: Arc<Topic<Arc<Uname>>>,
+    barebox: Arc<Topic<Arc<Barebox>>>,
+    dut_pwr: Arc<Topic<OutputState>>,
+    soc_temperature: Arc<Topic<Measurement>>,
+    temperature_warning: Arc<Topic<Warning>>,
+    usb_overload: Arc<Topic<Option<OverloadedPort>>>,
+    usb_faults: [Arc<Topic<Option<UsbPortFault>>>; 3],
+    iobus_fault: Arc<Topic<bool>>,
+    rtc_backup: Arc<Topic<RtcBackup>>,
+}
+
+impl StatusTopics {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        hostname: &Hostname,
+        system: &System,
+        dut_pwr: &DutPwrThread,
+        temperatures: &Temperatures,
+        usb_hub: &UsbHub,
+        iobus: &IoBus,
+        rtc: &Rtc,
+    ) -> Self {
+        Self {
+            hostname: hostname.hostname.clone(),
+            tacd_version: system.tacd_version.clone(),
+            uname: system.uname.clone(),
+            barebox: system.barebox.clone(),
+            dut_pwr: dut_pwr.state.clone(),
+            soc_temperature: temperatures.soc_temperature.clone(),
+            temperature_warning: temperatures.warning.clone(),
+            usb_overload: usb_hub.overload.clone(),
+            usb_faults: [
+                usb_hub.port1.fault.clone(),
+                usb_hub.port2.fault.clone(),
+                usb_hub.port3.fault.clone(),
+            ],
+            iobus_fault: iobus.supply_fault.clone(),
+            rtc_backup: rtc.backup.clone(),
+        }
+    }
+
+    fn alarms(&self) -> Vec<String> {
+        let mut alarms = Vec::new();
+
+        match self.temperature_warning.try_get() {
+            Some(Warning::SocHigh) => alarms.push("The TAC is getting hot".to_string()),
+            Some(Warning::SocCritical) => alarms.push("The TAC is overheating".to_string()),
+            Some(Warning::Okay) | None => {}
+        }
+
+        let dut_pwr_reason = match self.dut_pwr.try_get() {
+            Some(OutputState::InvertedPolarity) => Some("inverted polarity"),
+            Some(OutputState::OverCurrent) => Some("overcurrent"),
+            Some(OutputState::OverVoltage) => Some("overvoltage"),
+            Some(OutputState::RealtimeViolation) => Some("a realtime violation"),
+            _ => None,
+        };
+
+        if let Some(reason) = dut_pwr_reason {
+            alarms.push(format!("The DUT was powered off due to {reason}"));
+        }
+
+        match self.usb_overload.try_get().flatten() {
+            Some(OverloadedPort::Total) => alarms.push("The USB ports are overloaded".to_string()),
+            Some(OverloadedPort::Port1) => alarms.push("USB port 1 is overloaded".to_string()),
+            Some(OverloadedPort::Port2) => alarms.push("USB port 2 is overloaded".to_string()),
+            Some(OverloadedPort::Port3) => alarms.push("USB port 3 is overloaded".to_string()),
+            None => {}
+        }
+
+        for (num, fault) in self.usb_faults.iter().enumerate() {
+            let reason = match fault.try_get().flatten() {
+                Some(UsbPortFault::OverCurrent) => "an overcurrent event",
+                Some(UsbPortFault::VbusShort) => "a short circuit on VBUS",
+                Some(UsbPortFault::OverBudget) => "exceeding its power budget",
+                None => continue,
+            };
+
+            alarms.push(format!(
+                "USB port {} was powered off due to {reason}",
+                num + 1
+            ));
+        }
+
+        if self.iobus_fault.try_get() == Some(true) {
+            alarms.push("The IOBus power supply is overloaded".to_string());
+        }
+
+        match self.rtc_backup.try_get().map(|backup| backup.state) {
+            Some(BackupState::Missing) => alarms.push("The RTC backup cell is missing".to_string()),
+            Some(BackupState::Depleted) => {
+                alarms.push("The RTC backup cell is depleted".to_string())
+            }
+            _ => {}
+        }
+
+        alarms
+    }
+
+    pub fn status(&self) -> Status {
+        Status {
+            hostname: self.hostname.try_get(),
+            tacd_version: self.tacd_version.try_get(),
+            kernel_version: self.uname.try_get().map(|uname| uname.release.clone()),
+            bootloader_version: self.barebox.try_get().map(|bb| bb.version.clone()),
+            dut_pwr: self.dut_pwr.try_get(),
+            soc_temperature: self.soc_temperature.try_get().map(|m| m.value),
+            alarms: self.alarms(),
+        }
+    }
+}
+
+/// Serve a simple, read-only status page that is rendered on the server
+///
+/// This allows quick checks using e.g. a phone without loading the
+/// complete web interface. The page does not use any JavaScript and
+/// reloads itself periodically.
+pub fn serve(server: &mut Server<()>, topics: &StatusTopics) {
+    let topics = topics.clone();
+
+    server.at("/status").get(move |_req| {
+        let topics = topics.clone();
+
+        async move {
+            let mut body = Body::from_string(topics.status().to_string());
+            body.set_mime("text/html;charset=utf-8");
+
+            Ok(Response::builder(200).body(body).build())
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Status;
+    use crate::dut_power::OutputState;
+
+    #[test]
+    fn render() {
+        println!("Values from the topics are escaped");
+        let mut status = Status {
+            hostname: Some("lxatac-<script>".to_string()),
+            tacd_version: Some("1.0.0 & <b>more</b>".to_string()),
+            kernel_version: None,
+            bootloader_version: None,
+            dut_pwr: Some(OutputState::On),
+            soc_temperature: Some(42.04),
+            alarms: Vec::new(),
+        };
+
+        let html = status.to_string();
+
+        assert!(html.contains("<h1>lxatac-&lt;script&gt;</h1>"));
+        assert!(!html.contains("<script>"));
+        assert!(html.contains("<td>1.0.0 &amp; &lt;b&gt;more&lt;/b&gt;</td>"));
+        assert!(html.contains("<meta http-equiv=\"refresh\""));
+        assert!(html.contains("<td>On</td>"));
+        assert!(html.contains("<td>42.0°C</td>"));
+        assert!(html.contains("<th>Kernel</th><td>unknown</td>"));
+        assert!(html.contains("<p>None</p>"));
+
+        println!("Alarms are listed");
+        status.alarms.push("The TAC is overheating".to_string());
+
+        let html = status.to_string();
+
+        assert!(html.contains("<li class=\"alarm\">The TAC is overheating</li>"));
+        assert!(!html.contains("<p>None</p>"));
+    }
+}
@@ -24,6 +24,11 @@ use serde::{Deserialize, Serialize};
 
 use crate::broker::{BrokerBuilder, Topic};
 
+mod consistency;
+mod factory_data;
+pub use consistency::HardwareConsistency;
+pub use factory_data::FactoryData;
+
 #[cfg(feature = "demo_mode")]
 mod read_dt_props {
     use anyhow::{anyhow, Result};
@@ -82,20 +87,34 @@ mod read_dt_props {
 
         Ok(*content)
     }
+
+    /// All factory data nodes (like the calibration data) are present and
+    /// of the expected size in demo mode
+    pub fn read_dt_property_len(path: &str) -> Result<u64> {
+        let is_factory_data = path.starts_with("chosen/baseboard-factory-data/")
+            || path.starts_with("chosen/powerboard-factory-data/");
+
+        match is_factory_data {
+            true => Ok(8),
+            false => Err(anyhow!("could not find devicetree property {path}")),
+        }
+    }
 }
 
 #[cfg(not(feature = "demo_mode"))]
 mod read_dt_props {
-    use std::fs::read;
+    use std::fs::{metadata, read};
     use std::str::from_utf8;
 
     use anyhow::{anyhow, Result};
 
+    use crate::fs_root;
+
     const DT_BASE: &str = "/sys/firmware/devicetree/base/";
 
     pub fn read_dt_property(path: &str) -> Result<String> {
         let path = [DT_BASE, path].join("/");
-        let bytes = read(&path)?;
+        let bytes = read(fs_root::path(&path))?;
         let stripped_bytes = bytes
             .strip_suffix(&[0])
             .ok_or_else(|| anyhow!("Devicetree property {path} did not contain a value"))?;
@@ -110,9 +129,15 @@ mod read_dt_props {
 
         Ok(value)
     }
+
+    pub fn read_dt_property_len(path: &str) -> Result<u64> {
+        let path = [DT_BASE, path].join("/");
+
+        Ok(metadata(fs_root::path(path))?.len())
+    }
 }
 
-use read_dt_props::{read_dt_property, read_dt_property_u32};
+use read_dt_props::{read_dt_property, read_dt_property_len, read_dt_property_u32};
 
 #[derive(Serialize, Deserialize)]
 pub struct Uname {
@@ -231,6 +256,7 @@ pub struct System {
     pub tacd_version: Arc<Topic<String>>,
     #[allow(dead_code)]
     pub hardware_generation: Arc<Topic<HardwareGeneration>>,
+    pub factory_data: Arc<Topic<Vec<FactoryData>>>,
 }
 
 impl System {
@@ -239,6 +265,7 @@ impl System {
 
         let uname = Uname::get()?;
         let barebox = Barebox::get()?;
+        let factory_data = factory_data::read_all();
 
         Ok(Self {
             uname: bb.topic_ro("/v1/tac/info/uname", Some(Arc::new(uname))),
@@ -248,6 +275,16 @@ impl System {
                 "/v1/tac/info/hardware_generation",
                 Some(hardware_generation),
             ),
+            factory_data: bb.topic_ro("/v1/tac/info/factory_data", Some(factory_data)),
         })
     }
+
+    /// Allow re-provisioning the factory data in the board EEPROMs
+    ///
+    /// Writing is only possible in setup mode and if re-provisioning was
+    /// explicitly enabled on the TAC (which should only be the case in
+    /// production).
+    pub fn serve_factory_data(&self, server: &mut tide::Server<()>, setup_mode: Arc<Topic<bool>>) {
+        factory_data::serve(server, self.factory_data.clone(), setup_mode);
+    }
 }
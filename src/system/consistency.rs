@@ -0,0 +1,243 @@
+// This file is part of tacd, the LXA TAC system daemon
+// Copyright (C) 2024 Pengutronix e.K.
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along
+// with this program; if not, write to the Free Software Foundation, Inc.,
+// 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+
+//! Check that the boards and their calibration data fit together
+//!
+//! A power board from another hardware generation may be fitted to a TAC,
+//! e.g. after a repair. The ADC channels would then use calibration data
+//! that does not match the hardware, resulting in silently wrong
+//! measurements. Mismatches are detected on startup and the DUT power
+//! stays locked until they are acknowledged.
+
+use anyhow::Result;
+use async_std::sync::Arc;
+use log::{error, warn};
+
+use super::{read_dt_property, read_dt_property_len, HardwareGeneration};
+use crate::broker::{BrokerBuilder, Topic};
+use crate::watched_tasks::WatchedTasksBuilder;
+
+const BOARDS: &[&str] = &["baseboard", "powerboard"];
+
+/// The calibration data used by the ADC channels (see `adc/iio/hardware`)
+/// for all hardware generations
+const CALIBRATION_NODES: &[(&str, &str)] = &[
+    ("baseboard", "usb-host-curr"),
+    ("baseboard", "usb-host1-curr"),
+    ("baseboard", "usb-host2-curr"),
+    ("baseboard", "usb-host3-curr"),
+    ("baseboard", "out0-volt"),
+    ("baseboard", "out1-volt"),
+    ("baseboard", "iobus-curr"),
+    ("baseboard", "iobus-volt"),
+    ("powerboard", "pwr-volt"),
+    ("powerboard", "pwr-curr"),
+];
+
+/// A calibration node contains a scale and an offset (big endian f32 each)
+const CALIBRATION_LEN: u64 = 8;
+
+impl HardwareGeneration {
+    /// The revision used in the hardware release of the boards,
+    /// e.g. `R03` in `lxatac-S05-R03-V01-C00`
+    fn revision(&self) -> u32 {
+        match self {
+            Self::Gen1 => 1,
+            Self::Gen2 => 2,
+            Self::Gen3 => 3,
+        }
+    }
+}
+
+/// Extract the revision from a hardware release like `lxatac-S05-R03-V01-C00`
+fn release_revision(release: &str) -> Option<u32> {
+    release
+        .split('-')
+        .find_map(|part| part.strip_prefix('R'))
+        .and_then(|rev| rev.parse().ok())
+}
+
+/// Compare the detected hardware generation to the factory data and
+/// calibration nodes provided by the bootloader
+///
+/// `property` reads a devicetree property as string, `property_len` gets the
+/// length of a (binary) property. Both take paths relative to the
+/// devicetree base.
+fn check(
+    generation: HardwareGeneration,
+    property: impl Fn(&str) -> Result<String>,
+    property_len: impl Fn(&str) -> Result<u64>,
+) -> Vec<String> {
+    let mut mismatches = Vec::new();
+
+    for board in BOARDS {
+        let base = format!("chosen/{board}-factory-data");
+
+        let release = match property(&format!("{base}/pcba-hardware-release")) {
+            Ok(release) => release,
+            Err(_) => {
+                mismatches.push(format!("No factory data for the {board}"));
+                continue;
+            }
+        };
+
+        match release_revision(&release) {
+            Some(rev) if rev != generation.revision() => mismatches.push(format!(
+                "The {board} ({release}) does not match hardware generation {}",
+                generation.revision()
+            )),
+            Some(_) => {}
+            None => warn!("Can not determine the revision of the {board} from \"{release}\""),
+        }
+
+        let calibrated = property(&format!("{base}/featureset"))
+            .map(|fs| fs.split(',').any(|feature| feature == "calibrated"))
+            .unwrap_or(false);
+
+        if !calibrated {
+            mismatches.push(format!("The {board} was not calibrated"));
+        }
+    }
+
+    for (board, node) in CALIBRATION_NODES {
+        let path = format!("chosen/{board}-factory-data/{node}");
+
+        match property_len(&path) {
+            Ok(CALIBRATION_LEN) => {}
+            Ok(_) => mismatches.push(format!("Malformed calibration data for {board} {node}")),
+            Err(_) => mismatches.push(format!("No calibration data for {board} {node}")),
+        }
+    }
+
+    mismatches
+}
+
+pub struct HardwareConsistency {
+    pub mismatches: Arc<Topic<Vec<String>>>,
+    pub acknowledged: Arc<Topic<bool>>,
+    /// DUT power-on requests are refused while this is true
+    pub power_locked: Arc<Topic<bool>>,
+}
+
+impl HardwareConsistency {
+    pub fn new(
+        bb: &mut BrokerBuilder,
+        wtb: &mut WatchedTasksBuilder,
+        generation: HardwareGeneration,
+    ) -> Result<Self> {
+        let mismatches = check(generation, read_dt_property, read_dt_property_len);
+
+        for mismatch in &mismatches {
+            error!("Hardware consistency check failed: {mismatch}");
+        }
+
+        let has_mismatches = !mismatches.is_empty();
+
+        let mismatches = bb.topic_ro("/v1/tac/hardware/consistency", Some(mismatches));
+        let acknowledged = bb.topic_rw("/v1/tac/hardware/consistency/acknowledged", Some(false));
+        let power_locked = Topic::anonymous(Some(has_mismatches));
+
+        bb.link(wtb, &acknowledged, &power_locked, move |ack| {
+            Some(has_mismatches && !ack)
+        })?;
+
+        Ok(Self {
+            mismatches,
+            acknowledged,
+            power_locked,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use anyhow::{anyhow, Result};
+
+    use super::{check, release_revision, HardwareGeneration, CALIBRATION_NODES};
+
+    #[test]
+    fn consistency() {
+        let mut props = HashMap::from([
+            (
+                "chosen/baseboard-factory-data/pcba-hardware-release".to_string(),
+                "lxatac-S01-R03-B02-C00".to_string(),
+            ),
+            (
+                "chosen/powerboard-factory-data/pcba-hardware-release".to_string(),
+                "lxatac-S05-R03-V01-C00".to_string(),
+            ),
+            (
+                "chosen/baseboard-factory-data/featureset".to_string(),
+                "base,tft,calibrated".to_string(),
+            ),
+            (
+                "chosen/powerboard-factory-data/featureset".to_string(),
+                "base,calibrated".to_string(),
+            ),
+        ]);
+
+        let mut nodes: HashMap<String, u64> = CALIBRATION_NODES
+            .iter()
+            .map(|(board, node)| (format!("chosen/{board}-factory-data/{node}"), 8))
+            .collect();
+
+        let run = |props: &HashMap<String, String>, nodes: &HashMap<String, u64>| {
+            let property = |path: &str| -> Result<String> {
+                props.get(path).cloned().ok_or_else(|| anyhow!("missing"))
+            };
+            let property_len = |path: &str| -> Result<u64> {
+                nodes.get(path).copied().ok_or_else(|| anyhow!("missing"))
+            };
+
+            check(HardwareGeneration::Gen3, property, property_len)
+        };
+
+        println!("Matching boards and calibration data pass the check");
+        assert_eq!(release_revision("lxatac-S05-R03-V01-C00"), Some(3));
+        assert!(run(&props, &nodes).is_empty());
+
+        println!("A power board from another generation is detected");
+        props.insert(
+            "chosen/powerboard-factory-data/pcba-hardware-release".to_string(),
+            "lxatac-S05-R02-V01-C00".to_string(),
+        );
+        let mismatches = run(&props, &nodes);
+        assert_eq!(mismatches.len(), 1);
+        assert!(mismatches[0].contains("powerboard"));
+
+        println!("Unknown release formats are not counted as mismatch");
+        props.insert(
+            "chosen/powerboard-factory-data/pcba-hardware-release".to_string(),
+            "prototype".to_string(),
+        );
+        assert!(run(&props, &nodes).is_empty());
+
+        println!("Missing and malformed calibration data is detected");
+        nodes.remove("chosen/powerboard-factory-data/pwr-curr");
+        nodes.insert("chosen/baseboard-factory-data/out0-volt".to_string(), 4);
+        assert_eq!(run(&props, &nodes).len(), 2);
+
+        println!("Boards without calibration are detected");
+        props.insert(
+            "chosen/baseboard-factory-data/featureset".to_string(),
+            "base,tft".to_string(),
+        );
+        assert_eq!(run(&props, &nodes).len(), 3);
+    }
+}
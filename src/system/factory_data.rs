@@ -0,0 +1,438 @@
+// This file is part of tacd, the LXA TAC system daemon
+// Copyright (C) 2024 Pengutronix e.K.
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along
+// with this program; if not, write to the Free Software Foundation, Inc.,
+// 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+
+//! Read (and in production re-write) the factory data in the board EEPROMs
+//!
+//! The data is stored in the barebox TLV format:
+//!
+//! * A header consisting of a magic, the length of the TLV data and the
+//!   length of a signature (all 32 bit big endian).
+//! * TLV entries with a 16 bit tag and a 16 bit length (big endian).
+//! * The signature (which is not used on the TAC).
+//! * A CRC-32/MPEG-2 over all of the above (big endian).
+
+use std::convert::TryInto;
+use std::fs::{create_dir_all, read, read_dir, OpenOptions};
+use std::io::Write;
+
+use anyhow::{bail, Result};
+use async_std::sync::Arc;
+use chrono::Local;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use tide::{http::mime, Request, Response, Server};
+
+use crate::broker::Topic;
+use crate::fs_root;
+
+const NVMEM_DIR: &str = "/sys/bus/nvmem/devices";
+const PROVISIONING_FLAG: &str = "/etc/tacd/factory-provisioning";
+const AUDIT_LOG_PATH: &str = "/srv/tacd/factory-data-writes.log";
+
+const HEADER_LEN: usize = 12;
+const CRC_LEN: usize = 4;
+
+/// The magics used by barebox to distinguish the LXA TAC boards
+const BOARDS: &[(u32, &str)] = &[(0xbc288dfe, "baseboard"), (0xc6895c21, "powerboard")];
+
+#[derive(Clone, Copy)]
+enum Format {
+    String,
+    Number,
+}
+
+/// The tags that are decoded into human readable values
+const TAGS: &[(u16, &str, Format)] = &[
+    (0x0002, "device-hardware-release", Format::String),
+    (0x0003, "factory-timestamp", Format::Number),
+    (0x0004, "modification", Format::Number),
+    (0x0005, "featureset", Format::String),
+    (0x0006, "pcba-serial-number", Format::String),
+    (0x0007, "pcba-hardware-release", Format::String),
+];
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct TlvEntry {
+    pub tag: u16,
+    /// The name of the tag, if it is a known one
+    pub name: Option<String>,
+    /// The decoded value, if the tag is a known one
+    pub value: Option<String>,
+    /// The value as hex string
+    pub raw: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct FactoryData {
+    /// The name of the nvmem device the data was read from
+    pub device: String,
+    pub board: String,
+    pub magic: u32,
+    pub entries: Vec<TlvEntry>,
+}
+
+/// Only the tag and raw value are used when writing. This allows reading
+/// the entries, modifying some of them and writing them back.
+#[derive(Deserialize)]
+struct RawEntry {
+    tag: u16,
+    raw: String,
+}
+
+fn crc32_mpeg2(data: &[u8]) -> u32 {
+    let mut crc = 0xffffffffu32;
+
+    for byte in data {
+        crc ^= (*byte as u32) << 24;
+
+        for _ in 0..8 {
+            crc = if crc & 0x80000000 != 0 {
+                (crc << 1) ^ 0x04c11db7
+            } else {
+                crc << 1
+            };
+        }
+    }
+
+    crc
+}
+
+fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn from_hex(hex: &str) -> Result<Vec<u8>> {
+    if hex.len() % 2 != 0 || !hex.is_ascii() {
+        bail!("Malformed hex string");
+    }
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| Ok(u8::from_str_radix(&hex[i..i + 2], 16)?))
+        .collect()
+}
+
+fn be_u32(buf: &[u8]) -> u32 {
+    u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]])
+}
+
+/// Parse the TLV data at the start of `buf`
+///
+/// The buffer may be longer than the TLV data, e.g. if it contains the
+/// content of the whole EEPROM.
+fn parse(buf: &[u8]) -> Result<(u32, Vec<(u16, Vec<u8>)>)> {
+    if buf.len() < HEADER_LEN + CRC_LEN {
+        bail!("Too short for TLV data");
+    }
+
+    let magic = be_u32(&buf[0..]);
+    let len_tlv = be_u32(&buf[4..]) as usize;
+    let len_sig = be_u32(&buf[8..]) as usize;
+
+    let len = HEADER_LEN
+        .checked_add(len_tlv)
+        .and_then(|l| l.checked_add(len_sig))
+        .filter(|l| l.checked_add(CRC_LEN).map_or(false, |l| l <= buf.len()));
+
+    let len = match len {
+        Some(len) => len,
+        None => bail!("TLV data is longer than the buffer"),
+    };
+
+    if crc32_mpeg2(&buf[..len]) != be_u32(&buf[len..]) {
+        bail!("TLV data has an invalid CRC");
+    }
+
+    let mut entries = Vec::new();
+    let mut rem = &buf[HEADER_LEN..(HEADER_LEN + len_tlv)];
+
+    while !rem.is_empty() {
+        if rem.len() < 4 {
+            bail!("Truncated TLV entry");
+        }
+
+        let tag = u16::from_be_bytes([rem[0], rem[1]]);
+        let len = u16::from_be_bytes([rem[2], rem[3]]) as usize;
+
+        if rem.len() < 4 + len {
+            bail!("Truncated TLV entry");
+        }
+
+        entries.push((tag, rem[4..(4 + len)].to_vec()));
+        rem = &rem[(4 + len)..];
+    }
+
+    Ok((magic, entries))
+}
+
+/// Build TLV data (without signature) from a magic and entries
+fn serialize(magic: u32, entries: &[(u16, Vec<u8>)]) -> Result<Vec<u8>> {
+    let mut tlv = Vec::new();
+
+    for (tag, value) in entries {
+        let len: u16 = match value.len().try_into() {
+            Ok(len) => len,
+            Err(_) => bail!("The value of tag {tag:#06x} is too long"),
+        };
+
+        tlv.extend_from_slice(&tag.to_be_bytes());
+        tlv.extend_from_slice(&len.to_be_bytes());
+        tlv.extend_from_slice(value);
+    }
+
+    let mut buf = Vec::new();
+    buf.extend_from_slice(&magic.to_be_bytes());
+    buf.extend_from_slice(&(tlv.len() as u32).to_be_bytes());
+    buf.extend_from_slice(&0u32.to_be_bytes());
+    buf.extend_from_slice(&tlv);
+
+    let crc = crc32_mpeg2(&buf);
+    buf.extend_from_slice(&crc.to_be_bytes());
+
+    Ok(buf)
+}
+
+fn decode(tag: u16, value: &[u8]) -> TlvEntry {
+    let known = TAGS.iter().find(|(t, _, _)| *t == tag);
+
+    let decoded = known.and_then(|(_, _, format)| match format {
+        Format::String => std::str::from_utf8(value).ok().map(str::to_string),
+        Format::Number if value.len() <= 8 => Some(
+            value
+                .iter()
+                .fold(0u64, |acc, b| (acc << 8) | (*b as u64))
+                .to_string(),
+        ),
+        Format::Number => None,
+    });
+
+    TlvEntry {
+        tag,
+        name: known.map(|(_, name, _)| name.to_string()),
+        value: decoded,
+        raw: to_hex(value),
+    }
+}
+
+fn read_device(device: &str) -> Result<FactoryData> {
+    let content = read(fs_root::path(NVMEM_DIR).join(device).join("nvmem"))?;
+    let (magic, entries) = parse(&content)?;
+
+    let board = match BOARDS.iter().find(|(m, _)| *m == magic) {
+        Some((_, board)) => board.to_string(),
+        None => bail!("Unknown TLV magic {magic:#010x}"),
+    };
+
+    Ok(FactoryData {
+        device: device.to_string(),
+        board,
+        magic,
+        entries: entries.iter().map(|(t, v)| decode(*t, v)).collect(),
+    })
+}
+
+/// Read the factory data from all nvmem devices that contain some
+///
+/// Other nvmem devices (like the SoC OTP fuses) are skipped.
+pub(super) fn read_all() -> Vec<FactoryData> {
+    let mut res: Vec<FactoryData> = read_dir(fs_root::path(NVMEM_DIR))
+        .into_iter()
+        .flatten()
+        .flatten()
+        .filter_map(|entry| read_device(entry.file_name().to_str()?).ok())
+        .collect();
+
+    res.sort_by(|a, b| a.device.cmp(&b.device));
+
+    res
+}
+
+/// Overwrite the factory data on a device and read it back
+fn write_device(device: &str, magic: u32, entries: &[(u16, Vec<u8>)]) -> Result<FactoryData> {
+    let blob = serialize(magic, entries)?;
+    let path = fs_root::path(NVMEM_DIR).join(device).join("nvmem");
+
+    let mut file = OpenOptions::new().write(true).open(&path)?;
+
+    if blob.len() as u64 > file.metadata()?.len() {
+        bail!("The factory data does not fit into the EEPROM");
+    }
+
+    file.write_all(&blob)?;
+    file.sync_all()?;
+
+    read_device(device)
+}
+
+/// Append a line to the log of all writes
+fn audit(message: &str) {
+    let path = fs_root::path(AUDIT_LOG_PATH);
+    let line = format!("{} {message}\n", Local::now().to_rfc3339());
+
+    let res = path
+        .parent()
+        .map_or(Ok(()), create_dir_all)
+        .and_then(|_| OpenOptions::new().create(true).append(true).open(&path))
+        .and_then(|mut file| file.write_all(line.as_bytes()));
+
+    if let Err(e) = res {
+        warn!("Failed to write factory data audit log: {e}");
+    }
+}
+
+fn plain(status: u16, msg: &str) -> Response {
+    Response::builder(status)
+        .body(msg)
+        .content_type(mime::PLAIN)
+        .build()
+}
+
+/// Allow re-provisioning the factory data
+///
+/// This is only possible while the TAC is in setup mode _and_ the
+/// provisioning flag file exists, which is only the case in production.
+pub(super) fn serve(
+    server: &mut Server<()>,
+    factory_data: Arc<Topic<Vec<FactoryData>>>,
+    setup_mode: Arc<Topic<bool>>,
+) {
+    server
+        .at("/v1/tac/info/factory_data/:device")
+        .put(move |mut req: Request<()>| {
+            let factory_data = factory_data.clone();
+            let setup_mode = setup_mode.clone();
+
+            async move {
+                if setup_mode.try_get() != Some(true) {
+                    return Ok(plain(403, "Factory data may only be written in setup mode"));
+                }
+
+                if !fs_root::path(PROVISIONING_FLAG).exists() {
+                    return Ok(plain(403, "Re-provisioning is not enabled on this TAC"));
+                }
+
+                let device = req.param("device")?.to_string();
+
+                // Only allow writing to devices that already contain factory
+                // data. This also makes sure that `device` is not something
+                // like "../../".
+                let current = factory_data.try_get().unwrap_or_default();
+                let magic = match current.iter().find(|fd| fd.device == device) {
+                    Some(fd) => fd.magic,
+                    None => return Ok(plain(404, "No factory data on this device")),
+                };
+
+                let raw: Vec<RawEntry> = match req.body_json().await {
+                    Ok(raw) => raw,
+                    Err(e) => return Ok(plain(400, &format!("Malformed entries: {e}"))),
+                };
+
+                let entries: Result<Vec<(u16, Vec<u8>)>> = raw
+                    .into_iter()
+                    .map(|e| Ok((e.tag, from_hex(&e.raw)?)))
+                    .collect();
+
+                let entries = match entries {
+                    Ok(entries) => entries,
+                    Err(e) => return Ok(plain(400, &e.to_string())),
+                };
+
+                let peer = req.peer_addr().unwrap_or("an unknown address").to_string();
+
+                match write_device(&device, magic, &entries) {
+                    Ok(written) => {
+                        audit(&format!(
+                            "{peer} wrote {} entries to {device}",
+                            entries.len()
+                        ));
+
+                        factory_data.modify(|prev| {
+                            let mut list = prev.unwrap_or_default();
+
+                            for fd in list.iter_mut().filter(|fd| fd.device == device) {
+                                *fd = written.clone();
+                            }
+
+                            Some(list)
+                        });
+
+                        Ok(Response::new(204))
+                    }
+                    Err(e) => {
+                        audit(&format!("{peer} failed to write to {device}: {e}"));
+                        Ok(plain(500, &format!("Failed to write factory data: {e}")))
+                    }
+                }
+            }
+        });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{crc32_mpeg2, decode, from_hex, parse, serialize, to_hex};
+
+    #[test]
+    fn crc() {
+        // The check value of CRC-32/MPEG-2
+        assert_eq!(crc32_mpeg2(b"123456789"), 0x0376e6e7);
+    }
+
+    #[test]
+    fn tlv() {
+        let entries = vec![
+            (0x0006, b"12345".to_vec()),
+            (0x0004, vec![0x01]),
+            (0x8000, vec![0xde, 0xad]),
+        ];
+
+        let mut blob = serialize(0xbc288dfe, &entries).unwrap();
+
+        println!("Serialized TLV data can be parsed again");
+        assert_eq!(parse(&blob).unwrap(), (0xbc288dfe, entries.clone()));
+
+        println!("Trailing data (e.g. the rest of the EEPROM) is ignored");
+        blob.extend_from_slice(&[0xff; 64]);
+        assert_eq!(parse(&blob).unwrap(), (0xbc288dfe, entries));
+
+        println!("Corrupted data is rejected");
+        blob[14] ^= 0x01;
+        assert!(parse(&blob).is_err());
+        assert!(parse(&[0xff; 256]).is_err());
+        assert!(parse(&[0x00; 8]).is_err());
+
+        println!("Known tags are decoded");
+        let serial = decode(0x0006, b"12345");
+        assert_eq!(serial.name.as_deref(), Some("pcba-serial-number"));
+        assert_eq!(serial.value.as_deref(), Some("12345"));
+
+        let timestamp = decode(0x0003, &1678086417u64.to_be_bytes());
+        assert_eq!(timestamp.value.as_deref(), Some("1678086417"));
+
+        let unknown = decode(0x8000, &[0xde, 0xad]);
+        assert_eq!(unknown.name, None);
+        assert_eq!(unknown.raw, "dead");
+    }
+
+    #[test]
+    fn hex() {
+        assert_eq!(to_hex(&[0x00, 0x7f, 0xff]), "007fff");
+        assert_eq!(from_hex("007fFF").unwrap(), vec![0x00, 0x7f, 0xff]);
+        assert!(from_hex("abc").is_err());
+        assert!(from_hex("zz").is_err());
+        assert!(from_hex("äa").is_err());
+    }
+}
@@ -23,7 +23,7 @@ use anyhow::Result;
 use async_std::sync::Arc;
 use serde::{Deserialize, Serialize};
 
-use crate::broker::{BrokerBuilder, Topic};
+use crate::broker::{BrokerBuilder, Topic, TopicMeta};
 use crate::measurement::Measurement;
 use crate::watched_tasks::WatchedTasksBuilder;
 
@@ -44,11 +44,11 @@ mod hw {
         }
     }
 
-    impl HwMon {
-        pub(super) fn new(_: &'static str) -> Result<Self> {
-            Ok(Self)
-        }
+    pub(super) fn sys_class(_: &str) -> Result<HwMon> {
+        Ok(HwMon)
+    }
 
+    impl HwMon {
         pub(super) fn temp(&self, _: u64) -> Result<TempDecoy> {
             Ok(TempDecoy)
         }
@@ -57,10 +57,11 @@ mod hw {
 
 #[cfg(not(feature = "demo_mode"))]
 mod hw {
+    pub(super) use crate::fs_root::sys_class;
     pub(super) use sysfs_class::*;
 }
 
-use hw::{HwMon, SysClass};
+use hw::{sys_class, HwMon, SysClass};
 
 const UPDATE_INTERVAL: Duration = Duration::from_millis(500);
 const TEMPERATURE_SOC_CRITICAL: f32 = 90.0;
@@ -97,13 +98,22 @@ impl Temperatures {
         let soc_temperature = bb.topic_ro("/v1/tac/temperatures/soc", None);
         let warning = bb.topic_ro("/v1/tac/temperatures/warning", None);
 
+        bb.describe(
+            &soc_temperature,
+            TopicMeta::new("Temperature of the SoC")
+                .unit("°C")
+                .tag("measurement")
+                .ttl(5 * UPDATE_INTERVAL),
+        );
+
         let run_thread = run.clone();
         let soc_temperature_thread = soc_temperature.clone();
         let warning_thread = warning.clone();
 
         wtb.spawn_thread("temperature-update", move || {
             while run_thread.load(Ordering::Relaxed) {
-                let val = HwMon::new("hwmon0")?.temp(1)?.input()?;
+                let hwmon: HwMon = sys_class("hwmon0")?;
+                let val = hwmon.temp(1)?.input()?;
 
                 let val = val as f32 / 1000.0;
 
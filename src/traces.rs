@@ -0,0 +1,427 @@
+// This file is part of tacd, the LXA TAC system daemon
+// Copyright (C) 2024 Pengutronix e.K.
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along
+// with this program; if not, write to the Free Software Foundation, Inc.,
+// 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+
+//! Export `tracing` spans to an OpenTelemetry collector
+//!
+//! Some code paths in the tacd (like switching the DUT power) are
+//! instrumented with spans that show how long the individual steps take.
+//! They can be sent to a collector that speaks OTLP/HTTP with JSON encoding
+//! (like Jaeger) by enabling the exporter via the API.
+//! While the exporter is disabled spans are not recorded at all.
+
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, SystemTime};
+
+use anyhow::{anyhow, bail, Result};
+use async_std::channel::{bounded, Sender};
+use async_std::future::timeout;
+use async_std::prelude::*;
+use async_std::sync::Arc;
+use async_std::task::sleep;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use surf::Url;
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::subscriber::Interest;
+use tracing::{Metadata, Subscriber};
+use tracing_subscriber::layer::{Context, SubscriberExt};
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::{Layer, Registry};
+
+use crate::broker::BrokerBuilder;
+use crate::watched_tasks::WatchedTasksBuilder;
+
+/// Finished spans that were not exported yet. Spans are dropped if the
+/// exporter can not keep up.
+const QUEUE_LENGTH: usize = 1024;
+
+const EXPORT_INTERVAL: Duration = Duration::from_secs(1);
+const EXPORT_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct OtlpConfig {
+    pub enabled: bool,
+    /// The OTLP/HTTP traces endpoint of the collector,
+    /// e.g. "http://jaeger.example.com:4318/v1/traces"
+    pub endpoint: String,
+}
+
+impl Default for OtlpConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            endpoint: "http://localhost:4318/v1/traces".to_string(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug, Default)]
+pub struct ExportStats {
+    /// Number of spans successfully sent to the collector
+    pub exported: u64,
+    /// Number of spans dropped because the exporter could not keep up
+    pub dropped: u64,
+    /// Number of spans that could not be sent to the collector
+    pub failed: u64,
+    pub last_error: Option<String>,
+}
+
+/// Timing and context information attached to each span while it is open
+struct SpanData {
+    trace_id: u128,
+    span_id: u64,
+    parent_span_id: Option<u64>,
+    start: SystemTime,
+    attributes: Vec<(&'static str, String)>,
+}
+
+struct FinishedSpan {
+    name: &'static str,
+    target: &'static str,
+    data: SpanData,
+    end: SystemTime,
+}
+
+struct AttributeVisitor<'a>(&'a mut Vec<(&'static str, String)>);
+
+impl Visit for AttributeVisitor<'_> {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.push((field.name(), value.to_string()));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0.push((field.name(), format!("{value:?}")));
+    }
+}
+
+/// Hand out trace and span ids that are unique enough for debugging
+///
+/// The trace ids start with the time the tacd was started, so that they
+/// do not repeat after a restart.
+struct IdGenerator {
+    base: u64,
+    counter: AtomicU64,
+}
+
+impl IdGenerator {
+    fn new() -> Self {
+        let base = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|t| t.as_nanos() as u64)
+            .unwrap_or(0);
+
+        Self {
+            base,
+            counter: AtomicU64::new(1),
+        }
+    }
+
+    fn next_id(&self) -> u64 {
+        self.counter.fetch_add(1, Ordering::Relaxed)
+    }
+
+    fn trace_id(&self) -> u128 {
+        (u128::from(self.base) << 64) | u128::from(self.next_id())
+    }
+}
+
+struct OtlpLayer {
+    enabled: Arc<AtomicBool>,
+    queue: Sender<FinishedSpan>,
+    dropped: Arc<AtomicU64>,
+    ids: IdGenerator,
+}
+
+impl<S> Layer<S> for OtlpLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn register_callsite(&self, _metadata: &'static Metadata<'static>) -> Interest {
+        // The exporter can be enabled and disabled at runtime, so whether
+        // a span is interesting can not be cached.
+        Interest::sometimes()
+    }
+
+    fn enabled(&self, metadata: &Metadata<'_>, _ctx: Context<'_, S>) -> bool {
+        // Only record spans from the tacd itself, not from its dependencies.
+        // Otherwise the HTTP client used for exporting could create spans
+        // about exporting spans.
+        metadata.is_span()
+            && metadata.target().starts_with("tacd")
+            && self.enabled.load(Ordering::Relaxed)
+    }
+
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let span = match ctx.span(id) {
+            Some(span) => span,
+            None => return,
+        };
+
+        let parent = span.parent().and_then(|parent| {
+            let extensions = parent.extensions();
+            extensions
+                .get::<SpanData>()
+                .map(|data| (data.trace_id, data.span_id))
+        });
+
+        let (trace_id, parent_span_id) = match parent {
+            Some((trace_id, span_id)) => (trace_id, Some(span_id)),
+            None => (self.ids.trace_id(), None),
+        };
+
+        let mut attributes = Vec::new();
+        attrs.record(&mut AttributeVisitor(&mut attributes));
+
+        span.extensions_mut().insert(SpanData {
+            trace_id,
+            span_id: self.ids.next_id(),
+            parent_span_id,
+            start: SystemTime::now(),
+            attributes,
+        });
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            if let Some(data) = span.extensions_mut().get_mut::<SpanData>() {
+                values.record(&mut AttributeVisitor(&mut data.attributes));
+            }
+        }
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        let span = match ctx.span(&id) {
+            Some(span) => span,
+            None => return,
+        };
+
+        let data = match span.extensions_mut().remove::<SpanData>() {
+            Some(data) => data,
+            None => return,
+        };
+
+        let finished = FinishedSpan {
+            name: span.name(),
+            target: span.metadata().target(),
+            data,
+            end: SystemTime::now(),
+        };
+
+        if self.queue.try_send(finished).is_err() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+fn unix_nanos(ts: SystemTime) -> String {
+    ts.duration_since(SystemTime::UNIX_EPOCH)
+        .map(|t| t.as_nanos())
+        .unwrap_or(0)
+        .to_string()
+}
+
+fn string_attribute(key: &str, value: &str) -> Value {
+    json!({
+        "key": key,
+        "value": { "stringValue": value },
+    })
+}
+
+/// Encode spans as ExportTraceServiceRequest in the OTLP JSON encoding
+fn otlp_json(spans: &[FinishedSpan]) -> Value {
+    let spans: Vec<Value> = spans
+        .iter()
+        .map(|span| {
+            let mut attributes: Vec<Value> = span
+                .data
+                .attributes
+                .iter()
+                .map(|(key, value)| string_attribute(key, value))
+                .collect();
+
+            attributes.push(string_attribute("code.namespace", span.target));
+
+            let mut encoded = json!({
+                "traceId": format!("{:032x}", span.data.trace_id),
+                "spanId": format!("{:016x}", span.data.span_id),
+                "name": span.name,
+                // SPAN_KIND_INTERNAL
+                "kind": 1,
+                "startTimeUnixNano": unix_nanos(span.data.start),
+                "endTimeUnixNano": unix_nanos(span.end),
+                "attributes": attributes,
+            });
+
+            if let Some(parent) = span.data.parent_span_id {
+                encoded["parentSpanId"] = json!(format!("{parent:016x}"));
+            }
+
+            encoded
+        })
+        .collect();
+
+    json!({
+        "resourceSpans": [{
+            "resource": {
+                "attributes": [
+                    string_attribute("service.name", "tacd"),
+                    string_attribute("service.version", env!("VERSION_STRING")),
+                ],
+            },
+            "scopeSpans": [{
+                "scope": { "name": "tacd" },
+                "spans": spans,
+            }],
+        }],
+    })
+}
+
+async fn export(endpoint: &str, spans: &[FinishedSpan]) -> Result<()> {
+    // surf panics on URLs it can not parse, so check them beforehand
+    let url = Url::parse(endpoint)?;
+    let body = otlp_json(spans);
+
+    let request = async {
+        let res = surf::post(url)
+            .body_json(&body)
+            .map_err(|e| anyhow!("{e}"))?
+            .await
+            .map_err(|e| anyhow!("{e}"))?;
+
+        if !res.status().is_success() {
+            bail!("Collector responded with {}", res.status());
+        }
+
+        Ok(())
+    };
+
+    timeout(EXPORT_TIMEOUT, request).await?
+}
+
+/// Install the span recorder and start the exporter task
+///
+/// This should happen early on, as spans created before the recorder
+/// is installed are not recorded.
+pub fn run(bb: &mut BrokerBuilder, wtb: &mut WatchedTasksBuilder) -> Result<()> {
+    let config = bb.topic(
+        "/v1/tac/traces/otlp",
+        true,
+        true,
+        true,
+        Some(OtlpConfig::default()),
+        1,
+    );
+    let stats = bb.topic_ro("/v1/tac/traces/stats", Some(ExportStats::default()));
+
+    let enabled = Arc::new(AtomicBool::new(false));
+    let dropped = Arc::new(AtomicU64::new(0));
+    let (queue_tx, queue_rx) = bounded(QUEUE_LENGTH);
+
+    let layer = OtlpLayer {
+        enabled: enabled.clone(),
+        queue: queue_tx,
+        dropped: dropped.clone(),
+        ids: IdGenerator::new(),
+    };
+
+    if let Err(e) = tracing::subscriber::set_global_default(Registry::default().with(layer)) {
+        warn!("Failed to install span recorder: {e}");
+    }
+
+    let (mut config_events, _) = config.clone().subscribe_unbounded();
+
+    wtb.spawn_task("traces-config", async move {
+        while let Some(config) = config_events.next().await {
+            enabled.store(config.enabled, Ordering::Relaxed);
+        }
+
+        Ok(())
+    })?;
+
+    wtb.spawn_task("traces-export", async move {
+        let mut current = ExportStats::default();
+
+        loop {
+            sleep(EXPORT_INTERVAL).await;
+
+            let mut spans = Vec::new();
+
+            while let Ok(span) = queue_rx.try_recv() {
+                spans.push(span);
+            }
+
+            let config = config.try_get().unwrap_or_default();
+
+            // Spans that were recorded before the exporter was disabled
+            // are discarded.
+            if config.enabled && !spans.is_empty() {
+                match export(&config.endpoint, &spans).await {
+                    Ok(()) => current.exported += spans.len() as u64,
+                    Err(e) => {
+                        current.failed += spans.len() as u64;
+                        current.last_error = Some(e.to_string());
+                    }
+                }
+            }
+
+            current.dropped = dropped.load(Ordering::Relaxed);
+            stats.set_if_changed(current.clone());
+        }
+    })?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use super::{otlp_json, FinishedSpan, SpanData};
+
+    #[test]
+    fn otlp_encoding() {
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1);
+
+        let span = FinishedSpan {
+            name: "dut_power_request",
+            target: "tacd::dut_power",
+            data: SpanData {
+                trace_id: 0x1234,
+                span_id: 0x56,
+                parent_span_id: Some(0x78),
+                start,
+                attributes: vec![("request", "On".to_string())],
+            },
+            end: start + Duration::from_millis(5),
+        };
+
+        let encoded = otlp_json(&[span]);
+        let span = &encoded["resourceSpans"][0]["scopeSpans"][0]["spans"][0];
+
+        assert_eq!(span["traceId"], "00000000000000000000000000001234");
+        assert_eq!(span["spanId"], "0000000000000056");
+        assert_eq!(span["parentSpanId"], "0000000000000078");
+        assert_eq!(span["name"], "dut_power_request");
+        assert_eq!(span["startTimeUnixNano"], "1000000000");
+        assert_eq!(span["endTimeUnixNano"], "1005000000");
+        assert_eq!(span["attributes"][0]["key"], "request");
+        assert_eq!(span["attributes"][0]["value"]["stringValue"], "On");
+    }
+}
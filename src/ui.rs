@@ -21,47 +21,66 @@ use anyhow::Result;
 use async_std::prelude::*;
 use async_std::sync::Arc;
 use futures::{select, FutureExt};
-use tide::{Response, Server};
+use log::info;
+use tide::{Request, Response, Server};
 
-use crate::broker::{BrokerBuilder, Topic};
-use crate::led::{BlinkPattern, BlinkPatternBuilder};
+use crate::broker::{AnyTopic, BrokerBuilder, Topic};
+use crate::led::{BlinkPattern, BlinkPatternBuilder, LedMeaning};
 use crate::watched_tasks::WatchedTasksBuilder;
 
+mod alert_captures;
 mod alerts;
 mod buttons;
 mod display;
+mod locator;
+mod qr;
 mod screens;
+mod tour;
 mod widgets;
 
-use alerts::{AlertList, Alerter};
+use alerts::{handle_alerts, AlertList, Alerter};
 use buttons::{handle_buttons, Button, ButtonEvent, Direction, PressDuration, Source};
-pub use display::{Display, ScreenShooter};
+pub use display::{Display, FrameStats, FrameTimer, ScreenShooter};
+pub use locator::Locator;
 pub use screens::message;
 use screens::{splash, ActivatableScreen, AlertScreen, NormalScreen, Screen};
+use tour::handle_tour;
 
 pub struct UiResources {
     pub adc: crate::adc::Adc,
     pub backlight: crate::backlight::Backlight,
+    pub confirmation: crate::confirmation::Confirmation,
+    pub conflicts: crate::conflicts::ConflictGuard,
+    pub degraded: crate::backends::Degraded,
     pub dig_io: crate::digital_io::DigitalIo,
     pub dut_pwr: crate::dut_power::DutPwrThread,
+    pub firewall: crate::firewall::Firewall,
+    pub frame_stats: FrameStats,
+    pub gpio_health: crate::digital_io::GpioHealth,
+    pub hardware_consistency: crate::system::HardwareConsistency,
     pub hostname: crate::dbus::Hostname,
     pub iobus: crate::iobus::IoBus,
     pub led: crate::led::Led,
+    pub locator: Arc<Topic<Locator>>,
     pub network: crate::dbus::Network,
     pub rauc: crate::dbus::Rauc,
     pub regulators: crate::regulators::Regulators,
+    pub selftest: crate::selftest::SelfTest,
     pub setup_mode: crate::setup_mode::SetupMode,
+    pub state_recovery: Arc<Topic<Option<crate::broker::StateRecovery>>>,
     #[allow(dead_code)]
     pub system: crate::system::System,
     pub systemd: crate::dbus::Systemd,
     pub temperatures: crate::temperatures::Temperatures,
+    pub timedate: crate::dbus::Timedate,
     pub usb_hub: crate::usb_hub::UsbHub,
 }
 
 pub struct Ui {
     screen: Arc<Topic<NormalScreen>>,
     alerts: Arc<Topic<AlertList>>,
-    locator: Arc<Topic<bool>>,
+    visible_alerts: Arc<Topic<AlertList>>,
+    locator: Arc<Topic<Locator>>,
     buttons: Arc<Topic<ButtonEvent>>,
     screens: Vec<Box<dyn ActivatableScreen>>,
     reboot_message: Arc<Topic<Option<String>>>,
@@ -102,6 +121,12 @@ impl InputEvent {
 
 pub fn setup_display() -> Display {
     let display = Display::new();
+    let info = display.info();
+
+    info!(
+        "Using {} display with {}x{} pixels",
+        info.name, info.size.width, info.size.height
+    );
 
     display.clear();
     display.with_lock(splash);
@@ -110,18 +135,41 @@ pub fn setup_display() -> Display {
 }
 
 /// Add a web endpoint that serves the current display content as png
+///
+/// Clients that poll the display should send the ETag of the last response
+/// as If-None-Match header to get a "304 Not Modified" response instead of
+/// the same image over and over again.
 pub fn serve_display(server: &mut Server<()>, screenshooter: ScreenShooter) {
-    server.at("/v1/tac/display/content").get(move |_| {
-        let png = screenshooter.as_png();
-
-        async move {
-            Ok(Response::builder(200)
-                .content_type("image/png")
-                .header("Cache-Control", "no-store")
-                .body(png)
-                .build())
-        }
-    });
+    server
+        .at("/v1/tac/display/content")
+        .get(move |req: Request<()>| {
+            let screenshot = screenshooter.cached();
+
+            async move {
+                let etag = format!("\"{}\"", screenshot.hash);
+
+                let unchanged = req.header("If-None-Match").map_or(false, |tags| {
+                    tags.as_str()
+                        .split(',')
+                        .any(|tag| tag.trim() == etag || tag.trim() == "*")
+                });
+
+                // Allow clients to keep the image, but make them check
+                // if it is still current every time.
+                let res = if unchanged {
+                    Response::builder(304)
+                } else {
+                    Response::builder(200)
+                        .content_type("image/png")
+                        .body(screenshot.png.to_vec())
+                };
+
+                Ok(res
+                    .header("Cache-Control", "no-cache")
+                    .header("ETag", etag)
+                    .build())
+            }
+        });
 }
 
 impl Ui {
@@ -131,45 +179,63 @@ impl Ui {
         res: UiResources,
     ) -> Result<Self> {
         let screen = bb.topic_rw("/v1/tac/display/screen", Some(NormalScreen::first()));
-        let locator = bb.topic_rw("/v1/tac/display/locator", Some(false));
+        let locator = res.locator.clone();
         let buttons = bb.topic("/v1/tac/display/buttons", true, true, false, None, 0);
         let alerts = bb.topic_ro("/v1/tac/display/alerts", Some(AlertList::new()));
         let reboot_message = Topic::anonymous(None);
 
         alerts.assert(AlertScreen::ScreenSaver);
 
+        // Sort the asserted alerts by their configured priority and hide
+        // the ones that are currently snoozed.
+        let visible_alerts = handle_alerts(bb, wtb, alerts.clone())?;
+
+        // Guide new users through the screens once the setup is completed
+        let tour_step = handle_tour(bb, wtb, &res.setup_mode.completed, &screen, &alerts)?;
+
         // Initialize all the screens now so they can be activated later
-        let screens = screens::init(wtb, &res, &alerts, &buttons, &reboot_message, &locator)?;
-
-        handle_buttons(
-            wtb,
-            "/dev/input/by-path/platform-gpio-keys-event",
-            buttons.clone(),
-        )?;
-
-        // Blink the status LED when locator is active
-        let led_status_pattern = res.led.status.clone();
-        let led_status_color = res.led.status_color.clone();
-        let (mut locator_stream, _) = locator.clone().subscribe_unbounded();
-        wtb.spawn_task("locator-led-updater", async move {
-            let pattern_locator_on = BlinkPatternBuilder::new(0.0)
-                .fade_to(1.0, Duration::from_millis(100))
-                .stay_for(Duration::from_millis(300))
-                .fade_to(0.0, Duration::from_millis(100))
-                .stay_for(Duration::from_millis(500))
-                .forever();
-
-            let pattern_locator_off = BlinkPattern::solid(1.0);
-
-            while let Some(ev) = locator_stream.next().await {
-                if ev {
-                    // White blinking when locator is on
-                    led_status_color.set((1.0, 1.0, 1.0));
-                    led_status_pattern.set(pattern_locator_on.clone());
+        let screens = screens::init(wtb, &res, &alerts, &reboot_message, &locator, &tour_step)?;
+
+        handle_buttons(bb, wtb, buttons.clone())?;
+
+        // Any button press wakes up the display and resets the idle timeout
+        let (mut button_events, _) = buttons.clone().subscribe_unbounded();
+        let backlight = res.backlight.clone();
+
+        wtb.spawn_task("backlight-wake-on-button", async move {
+            while button_events.next().await.is_some() {
+                backlight.wake();
+            }
+
+            Ok(())
+        })?;
+
+        // Blink the status LED in white when locator is active
+        let pattern_locator_on = BlinkPatternBuilder::new(0.0)
+            .fade_to(1.0, Duration::from_millis(100))
+            .stay_for(Duration::from_millis(300))
+            .fade_to(0.0, Duration::from_millis(100))
+            .stay_for(Duration::from_millis(500))
+            .forever()
+            .with_meaning(LedMeaning::Locator);
+
+        let pattern_locator_off = BlinkPattern::solid(1.0);
+
+        // Pattern and color are set from the same task, so that the two can
+        // not get out of step.
+        let (mut locator_events, _) = locator.clone().subscribe_unbounded();
+        let status_led = res.led.status.clone();
+        let status_led_color = res.led.status_color.clone();
+
+        wtb.spawn_task("locator-status-led", async move {
+            while let Some(locator) = locator_events.next().await {
+                if locator.active {
+                    status_led_color.set((1.0, 1.0, 1.0));
+                    status_led.set(pattern_locator_on.clone());
                 } else {
                     // Green light when locator is off
-                    led_status_color.set((0.0, 0.23, 0.0));
-                    led_status_pattern.set(pattern_locator_off.clone());
+                    status_led_color.set((0.0, 0.23, 0.0));
+                    status_led.set(pattern_locator_off.clone());
                 }
             }
 
@@ -179,6 +245,7 @@ impl Ui {
         Ok(Self {
             screen,
             alerts,
+            visible_alerts,
             locator,
             buttons,
             screens,
@@ -187,9 +254,43 @@ impl Ui {
         })
     }
 
+    /// Add a web endpoint that starts the locator and records who started it
+    pub fn serve_locator(&self, server: &mut Server<()>) {
+        locator::serve(server, self.locator.clone());
+    }
+
+    /// Keep a record of what the display showed when a high-severity alert
+    /// was asserted, along with the values of the topics related to it.
+    pub fn capture_alerts(
+        &self,
+        bb: &mut BrokerBuilder,
+        wtb: &mut WatchedTasksBuilder,
+        server: &mut Server<()>,
+        screenshooter: ScreenShooter,
+    ) -> Result<()> {
+        let topics: Vec<Arc<dyn AnyTopic>> = vec![
+            self.alerts.clone(),
+            self.res.dut_pwr.state.clone(),
+            self.res.adc.pwr_volt.topic.clone(),
+            self.res.adc.pwr_curr.topic.clone(),
+            self.res.adc.usb_host_curr.topic.clone(),
+            self.res.adc.usb_host1_curr.topic.clone(),
+            self.res.adc.usb_host2_curr.topic.clone(),
+            self.res.adc.usb_host3_curr.topic.clone(),
+            self.res.adc.iobus_curr.topic.clone(),
+            self.res.adc.iobus_volt.topic.clone(),
+            self.res.iobus.supply_fault.clone(),
+            self.res.temperatures.soc_temperature.clone(),
+            self.res.temperatures.warning.clone(),
+            self.res.usb_hub.overload.clone(),
+        ];
+
+        alert_captures::run(bb, wtb, server, screenshooter, self.alerts.clone(), topics)
+    }
+
     pub async fn render_loop(mut self, display: Display) -> Result<(), std::io::Error> {
         let (mut screen_rx, _) = self.screen.clone().subscribe_unbounded();
-        let (mut alerts_rx, _) = self.alerts.clone().subscribe_unbounded();
+        let (mut alerts_rx, _) = self.visible_alerts.clone().subscribe_unbounded();
         let (mut button_events, _) = self.buttons.clone().subscribe_unbounded();
 
         // Helper to go to the next screen and activate the screensaver after
@@ -228,6 +329,11 @@ impl Ui {
         let mut display = Some(display);
 
         'exit: loop {
+            // Wake the display up if e.g. an alert pops up while it is dimmed
+            if showing != Screen::Alert(AlertScreen::ScreenSaver) {
+                self.res.backlight.wake();
+            }
+
             let mut active_screen = {
                 let display = display.take().unwrap();
                 display.clear();
@@ -0,0 +1,321 @@
+// This file is part of tacd, the LXA TAC system daemon
+// Copyright (C) 2024 Pengutronix e.K.
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along
+// with this program; if not, write to the Free Software Foundation, Inc.,
+// 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+
+use std::collections::BTreeMap;
+use std::fs::{create_dir_all, read_dir, remove_file, File};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use anyhow::Result;
+use async_std::prelude::*;
+use async_std::sync::Arc;
+use async_std::task::sleep;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use serde_json::{from_reader, to_writer_pretty};
+use tide::{Request, Response, Server};
+
+use super::alerts::AlertList;
+use super::{AlertScreen, ScreenShooter};
+use crate::broker::{AnyTopic, BrokerBuilder, Topic};
+use crate::fs_root;
+use crate::watched_tasks::WatchedTasksBuilder;
+
+const CAPTURE_DIR: &str = "/var/run/tacd/alert_captures";
+
+const WEB_PATH: &str = "/v1/tac/display/alert_captures";
+
+/// The number of captures to keep before the oldest ones are removed
+const MAX_CAPTURES: usize = 16;
+
+/// Give the render loop a moment to actually draw the alert screen
+const SETTLE_TIME: Duration = Duration::from_secs(1);
+
+/// Alerts that hint at something having gone wrong with the DUT or the TAC
+const CAPTURED_ALERTS: [AlertScreen; 4] = [
+    AlertScreen::OverTemperature,
+    AlertScreen::PowerFail,
+    AlertScreen::UsbOverload,
+    AlertScreen::IoBusHealth,
+];
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct AlertCapture {
+    pub id: u64,
+    /// Seconds since Unix Epoch 0
+    pub timestamp: u64,
+    pub alert: AlertScreen,
+    /// Web path of the display content at the time of the alert (png)
+    pub screenshot: String,
+    /// Web path of the topic values at the time of the alert (json)
+    pub snapshot: String,
+}
+
+/// The content of the json file stored alongside the screenshot
+#[derive(Serialize, Deserialize)]
+struct Snapshot {
+    capture: AlertCapture,
+    topics: BTreeMap<String, serde_json::Value>,
+}
+
+/// A bounded number of captures in a directory on a tmpfs
+///
+/// The captures survive a restart of the tacd (e.g. because it crashed
+/// along with whatever caused the alert) but not a reboot of the TAC.
+struct CaptureRing {
+    dir: PathBuf,
+    captures: Vec<AlertCapture>,
+}
+
+impl CaptureRing {
+    /// Pick up the captures left behind by a previous run of the tacd
+    fn load(dir: &Path) -> Result<Self> {
+        create_dir_all(dir)?;
+
+        let mut captures = Vec::new();
+
+        for entry in read_dir(dir)? {
+            let path = entry?.path();
+
+            if path.extension().map_or(true, |ext| ext != "json") {
+                continue;
+            }
+
+            let snapshot: serde_json::Result<Snapshot> = File::open(&path)
+                .map_err(serde_json::Error::io)
+                .and_then(from_reader);
+
+            match snapshot {
+                Ok(snapshot) => captures.push(snapshot.capture),
+                Err(e) => warn!("Ignoring alert capture {}: {e}", path.display()),
+            }
+        }
+
+        captures.sort_by_key(|c| c.id);
+
+        let mut this = Self {
+            dir: dir.to_owned(),
+            captures,
+        };
+
+        this.prune();
+
+        Ok(this)
+    }
+
+    fn prune(&mut self) {
+        while self.captures.len() > MAX_CAPTURES {
+            let oldest = self.captures.remove(0);
+
+            for ext in ["png", "json"] {
+                let _ = remove_file(self.dir.join(format!("{}.{ext}", oldest.id)));
+            }
+        }
+    }
+
+    fn push(
+        &mut self,
+        alert: AlertScreen,
+        png: &[u8],
+        topics: BTreeMap<String, serde_json::Value>,
+    ) -> Result<()> {
+        let id = self.captures.last().map_or(1, |c| c.id + 1);
+
+        let timestamp = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|t| t.as_secs())
+            .unwrap_or(0);
+
+        let capture = AlertCapture {
+            id,
+            timestamp,
+            alert,
+            screenshot: format!("{WEB_PATH}/{id}.png"),
+            snapshot: format!("{WEB_PATH}/{id}.json"),
+        };
+
+        std::fs::write(self.dir.join(format!("{id}.png")), png)?;
+
+        // Write the json file last, as it is what marks a capture as
+        // complete when loading them after a restart.
+        let snapshot = Snapshot {
+            capture: capture.clone(),
+            topics,
+        };
+
+        let file = File::create(self.dir.join(format!("{id}.json")))?;
+        to_writer_pretty(file, &snapshot)?;
+
+        self.captures.push(capture);
+        self.prune();
+
+        Ok(())
+    }
+}
+
+/// Serve the files belonging to one of the captures
+fn serve_captures(server: &mut Server<()>, captures: Arc<Topic<Vec<AlertCapture>>>) {
+    server
+        .at(&format!("{WEB_PATH}/:file"))
+        .get(move |req: Request<()>| {
+            let captures = captures.clone();
+
+            async move {
+                let file = req.param("file").unwrap_or("").to_owned();
+
+                // Only serve files that belong to a known capture, so that
+                // the parameter can not be used to escape the directory.
+                let known = file.split_once('.').and_then(|(id, ext)| {
+                    let id: u64 = id.parse().ok()?;
+                    let mime = match ext {
+                        "png" => "image/png",
+                        "json" => "application/json",
+                        _ => return None,
+                    };
+
+                    captures
+                        .try_get()
+                        .unwrap_or_default()
+                        .iter()
+                        .any(|c| c.id == id)
+                        .then_some(mime)
+                });
+
+                let content = match known {
+                    Some(mime) => async_std::fs::read(fs_root::path(CAPTURE_DIR).join(&file))
+                        .await
+                        .ok()
+                        .map(|content| (mime, content)),
+                    None => None,
+                };
+
+                let resp = match content {
+                    Some((mime, content)) => Response::builder(200)
+                        .content_type(mime)
+                        .body(content)
+                        .build(),
+                    None => Response::builder(404)
+                        .body(format!("No such alert capture: {file}"))
+                        .build(),
+                };
+
+                Ok(resp)
+            }
+        });
+}
+
+/// Capture the display content and a snapshot of some topics when a
+/// high-severity alert is asserted
+///
+/// This allows finding out what the TAC showed when something went wrong
+/// in the middle of the night.
+pub fn run(
+    bb: &mut BrokerBuilder,
+    wtb: &mut WatchedTasksBuilder,
+    server: &mut Server<()>,
+    screenshooter: ScreenShooter,
+    alerts: Arc<Topic<AlertList>>,
+    topics: Vec<Arc<dyn AnyTopic>>,
+) -> Result<()> {
+    let mut ring = CaptureRing::load(&fs_root::path(CAPTURE_DIR))?;
+
+    let captures = bb.topic_ro(WEB_PATH, Some(ring.captures.clone()));
+
+    serve_captures(server, captures.clone());
+
+    let (mut alerts_events, _) = alerts.subscribe_unbounded();
+
+    wtb.spawn_task("alert-captures", async move {
+        let mut prev = AlertList::new();
+
+        while let Some(list) = alerts_events.next().await {
+            let new: Vec<AlertScreen> = CAPTURED_ALERTS
+                .iter()
+                .filter(|screen| list.contains(**screen) && !prev.contains(**screen))
+                .copied()
+                .collect();
+
+            prev = list;
+
+            for alert in new {
+                sleep(SETTLE_TIME).await;
+
+                let png = screenshooter.as_png();
+
+                let topics = topics
+                    .iter()
+                    .map(|topic| {
+                        let value = topic.try_get_json_value();
+                        let value = value.unwrap_or(serde_json::Value::Null);
+
+                        (topic.path().to_string(), value)
+                    })
+                    .collect();
+
+                match ring.push(alert, &png, topics) {
+                    Ok(()) => captures.set(ring.captures.clone()),
+                    Err(e) => warn!("Failed to store alert capture: {e}"),
+                }
+            }
+        }
+
+        Ok(())
+    })?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::fs::remove_dir_all;
+
+    use super::{AlertScreen, CaptureRing, MAX_CAPTURES};
+
+    #[test]
+    fn capture_ring() {
+        let dir = std::env::temp_dir().join(format!("tacd-alert-captures-{}", std::process::id()));
+
+        let mut ring = CaptureRing::load(&dir).unwrap();
+        assert!(ring.captures.is_empty());
+
+        println!("Fill the ring past its limit");
+        for _ in 0..(MAX_CAPTURES + 3) {
+            ring.push(AlertScreen::PowerFail, b"png", BTreeMap::new())
+                .unwrap();
+        }
+
+        assert_eq!(ring.captures.len(), MAX_CAPTURES);
+        assert_eq!(ring.captures[0].id, 4);
+        assert!(!dir.join("3.png").exists());
+        assert!(!dir.join("3.json").exists());
+        assert!(dir.join("4.png").exists());
+
+        println!("Pick the captures up again after a restart");
+        let restored = CaptureRing::load(&dir).unwrap();
+        assert_eq!(restored.captures, ring.captures);
+
+        let last = restored.captures.last().unwrap();
+        assert_eq!(last.id, MAX_CAPTURES as u64 + 3);
+        assert_eq!(
+            last.screenshot,
+            format!("/v1/tac/display/alert_captures/{}.png", last.id)
+        );
+
+        remove_dir_all(&dir).unwrap();
+    }
+}
@@ -15,14 +15,64 @@
 // with this program; if not, write to the Free Software Foundation, Inc.,
 // 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
 
+use std::cmp::Reverse;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use async_std::future::pending;
+use async_std::prelude::*;
+use async_std::sync::Arc;
+use async_std::task::sleep;
+use futures::{select, FutureExt};
+use log::warn;
 use serde::{Deserialize, Serialize};
 
 use super::AlertScreen;
-use crate::broker::Topic;
+use crate::broker::{BrokerBuilder, Topic};
+use crate::watched_tasks::WatchedTasksBuilder;
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+/// The longest time an alert can be snoozed for
+const SNOOZE_MAX: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// The order in which alerts are shown if no other order is configured,
+/// with the highest priority alert first.
+const DEFAULT_PRIORITY: [AlertScreen; 23] = [
+    AlertScreen::OverTemperature,
+    AlertScreen::ConfirmAction,
+    AlertScreen::Conflict,
+    AlertScreen::HardwareMismatch,
+    AlertScreen::StateRecovery,
+    AlertScreen::SshKeyImport,
+    AlertScreen::SelfTest,
+    AlertScreen::Diagnostics,
+    AlertScreen::Setup,
+    AlertScreen::Help,
+    AlertScreen::Tour,
+    AlertScreen::QrCode,
+    AlertScreen::CommandPalette,
+    AlertScreen::UsbOverload,
+    AlertScreen::UpdateInstallation,
+    AlertScreen::UpdateAvailable,
+    AlertScreen::RebootConfirm,
+    AlertScreen::Locator,
+    AlertScreen::Degraded,
+    AlertScreen::GpioConflict,
+    AlertScreen::PowerFail,
+    AlertScreen::IoBusHealth,
+    AlertScreen::ScreenSaver,
+];
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct AlertList(Vec<AlertScreen>);
 
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SnoozeRequest {
+    alert: AlertScreen,
+    /// Time in seconds until the alert is shown again.
+    /// A value of zero ends an active snooze.
+    seconds: u64,
+}
+
 pub trait Alerter {
     fn assert(&self, screen: AlertScreen);
     fn deassert(&self, screen: AlertScreen);
@@ -36,6 +86,127 @@ impl AlertList {
     pub fn highest_priority(&self) -> Option<AlertScreen> {
         self.0.last().copied()
     }
+
+    pub fn contains(&self, screen: AlertScreen) -> bool {
+        self.0.contains(&screen)
+    }
+
+    /// Get the alerts that should actually be shown
+    ///
+    /// Snoozed alerts are removed and the remaining ones are sorted by
+    /// the priority order, which lists the highest priority alert first.
+    /// Alerts not mentioned in the priority order are sorted below all others.
+    fn visible(&self, priority: &[AlertScreen], snoozed: &[AlertScreen]) -> Self {
+        let mut list: Vec<AlertScreen> = self
+            .0
+            .iter()
+            .filter(|s| !snoozed.contains(s))
+            .copied()
+            .collect();
+
+        list.sort_by_key(|screen| {
+            let pos = priority.iter().position(|s| s == screen);
+            (pos.map(Reverse), *screen)
+        });
+
+        Self(list)
+    }
+}
+
+/// Check that a priority order does not mention an alert more than once
+fn priority_is_valid(priority: &[AlertScreen]) -> bool {
+    priority
+        .iter()
+        .enumerate()
+        .all(|(i, screen)| !priority[..i].contains(screen))
+}
+
+/// Handle the alert priority order and snoozing of alerts
+///
+/// Returns a topic containing the alerts that should actually be shown,
+/// in the order of their priority.
+pub fn handle_alerts(
+    bb: &mut BrokerBuilder,
+    wtb: &mut WatchedTasksBuilder,
+    alerts: Arc<Topic<AlertList>>,
+) -> Result<Arc<Topic<AlertList>>> {
+    let priority_req = bb.topic(
+        "/v1/tac/display/alerts/priority",
+        false,
+        true,
+        true,
+        Some(DEFAULT_PRIORITY.to_vec()),
+        1,
+    );
+    let priority = bb.topic_ro("/v1/tac/display/alerts/priority", None);
+    let snooze_req = bb.topic_wo::<SnoozeRequest>("/v1/tac/display/alerts/snooze", None);
+    let snoozed = bb.topic_ro("/v1/tac/display/alerts/snoozed", Some(Vec::new()));
+    let visible = Topic::anonymous(None);
+
+    let (mut alerts_rx, _) = alerts.subscribe_unbounded();
+    let (mut priority_rx, _) = priority_req.subscribe_unbounded();
+    let (mut snooze_rx, _) = snooze_req.subscribe_unbounded();
+
+    let visible_task = visible.clone();
+
+    wtb.spawn_task("alert-prioritization", async move {
+        let mut list = AlertList::new();
+        let mut order = DEFAULT_PRIORITY.to_vec();
+        let mut snoozes: Vec<(AlertScreen, Instant)> = Vec::new();
+
+        loop {
+            // Wait until the next snooze runs out or forever if nothing
+            // is snoozed.
+            let next_expiry = snoozes.iter().map(|(_, until)| *until).min();
+            let expiry = async move {
+                match next_expiry {
+                    Some(until) => sleep(until.saturating_duration_since(Instant::now())).await,
+                    None => pending().await,
+                }
+            };
+
+            select! {
+                new = alerts_rx.next().fuse() => match new {
+                    Some(new) => list = new,
+                    None => break,
+                },
+                new = priority_rx.next().fuse() => match new {
+                    Some(new) if priority_is_valid(&new) => order = new,
+                    Some(_) => warn!("Ignoring alert priority order with duplicate entries"),
+                    None => break,
+                },
+                req = snooze_rx.next().fuse() => match req {
+                    Some(req) if req.seconds == 0 => {
+                        snoozes.retain(|(screen, _)| *screen != req.alert);
+                    }
+                    Some(req) => {
+                        let duration = Duration::from_secs(req.seconds).min(SNOOZE_MAX);
+                        let until = Instant::now() + duration;
+
+                        snoozes.retain(|(screen, _)| *screen != req.alert);
+                        snoozes.push((req.alert, until));
+                    }
+                    None => break,
+                },
+                _ = expiry.fuse() => {},
+            }
+
+            // Snoozed alerts that have run out are shown again if they
+            // are still asserted.
+            let now = Instant::now();
+            snoozes.retain(|(_, until)| *until > now);
+
+            let snoozed_list: Vec<AlertScreen> = snoozes.iter().map(|(s, _)| *s).collect();
+
+            priority.set_if_changed(order.clone());
+            visible_task.set_if_changed(list.visible(&order, &snoozed_list));
+            snoozed.set_if_changed(snoozed_list);
+        }
+
+        Ok(())
+    })?;
+
+    Ok(visible)
 }
 
 impl Alerter for Topic<AlertList> {
@@ -18,15 +18,28 @@
 use std::time::Duration;
 
 use anyhow::Result;
+use async_std::prelude::*;
 use async_std::sync::Arc;
 use async_std::task::{block_on, sleep, spawn, JoinHandle};
+use log::warn;
 use serde::{Deserialize, Serialize};
 
-use crate::broker::Topic;
+use crate::broker::{BrokerBuilder, Topic};
 use crate::watched_tasks::WatchedTasksBuilder;
 
 pub const LONG_PRESS: Duration = Duration::from_millis(500);
 
+/// Time to wait before trying to open an input device again after it
+/// went away or could not be opened.
+/// Open devices are also checked this often for configuration changes.
+const REOPEN_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Additional input devices are read by a fixed set of threads, one per
+/// entry in the configured list. Longer lists are refused.
+const MAX_INPUT_SOURCES: usize = 4;
+
+const GPIO_KEYS_PATH: &str = "/dev/input/by-path/platform-gpio-keys-event";
+
 #[cfg(feature = "demo_mode")]
 mod evd {
     use evdev::FetchEventsSynced;
@@ -35,24 +48,63 @@ mod evd {
     pub(super) struct Device {}
 
     impl Device {
-        pub fn open(_path: &'static str) -> Result<Self, ()> {
+        pub fn open(_path: &str) -> std::io::Result<Self> {
             Ok(Self {})
         }
 
-        pub fn fetch_events(&mut self) -> Result<FetchEventsSynced, ()> {
+        pub fn fetch_events(&mut self) -> std::io::Result<FetchEventsSynced> {
             loop {
                 std::thread::park()
             }
         }
     }
+
+    pub(super) fn wait_readable(_: &Device, timeout: std::time::Duration) -> std::io::Result<bool> {
+        std::thread::sleep(timeout);
+        Ok(false)
+    }
 }
 
 #[cfg(not(feature = "demo_mode"))]
 mod evd {
+    use std::io::{Error, ErrorKind, Result};
+    use std::os::unix::io::AsRawFd;
+    use std::time::Duration;
+
+    use nix::libc;
+
     pub(super) use evdev::*;
+
+    /// Wait for up to `timeout` for the device to have events to read
+    ///
+    /// `fetch_events()` blocks until there are events, so without this a
+    /// device that is no longer configured would only be closed on its
+    /// next key press.
+    pub(super) fn wait_readable(device: &Device, timeout: Duration) -> Result<bool> {
+        let mut fds = libc::pollfd {
+            fd: device.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+
+        let res = unsafe { libc::poll(&mut fds, 1, timeout.as_millis() as libc::c_int) };
+
+        match res {
+            0 => Ok(false),
+            res if res > 0 => Ok(true),
+            _ => {
+                let e = Error::last_os_error();
+
+                match e.kind() {
+                    ErrorKind::Interrupted => Ok(false),
+                    _ => Err(e),
+                }
+            }
+        }
+    }
 }
 
-use evd::{Device, EventType, InputEventKind, Key};
+use evd::{wait_readable, Device, EventType, InputEventKind, Key};
 
 #[derive(Serialize, Deserialize, Clone, Copy, Debug)]
 pub enum Direction {
@@ -133,27 +185,125 @@ impl ButtonEvent {
     }
 }
 
-/// Spawn a thread that blockingly reads user input and pushes them into
-/// a broker framework topic.
-pub fn handle_buttons(
-    wtb: &mut WatchedTasksBuilder,
-    path: &'static str,
-    topic: Arc<Topic<ButtonEvent>>,
-) -> Result<()> {
-    wtb.spawn_thread("button-input-thread", move || {
-        let mut device = Device::open(path).unwrap();
-        let mut press_task: [Option<JoinHandle<()>>; 2] = [None, None];
-        let mut start_time = [None, None];
+/// An input device whose keys act like the buttons on the TAC
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct InputSource {
+    /// e.g. "/dev/input/by-id/usb-0000_Keypad-event-kbd"
+    pub path: String,
+    /// Linux input event code of the key that acts as the upper button
+    pub upper: u16,
+    /// Linux input event code of the key that acts as the lower button
+    pub lower: u16,
+}
+
+impl InputSource {
+    fn gpio_keys() -> Self {
+        Self {
+            path: GPIO_KEYS_PATH.to_string(),
+            upper: Key::KEY_HOME.code(),
+            lower: Key::KEY_ESC.code(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct InputStatus {
+    pub path: String,
+    pub connected: bool,
+    /// Why the device could not be opened or read from (if it could not)
+    pub error: Option<String>,
+}
+
+fn update_status(status: &Topic<Vec<InputStatus>>, path: &str, new: Option<InputStatus>) {
+    status.modify(|prev| {
+        let mut list = prev.unwrap_or_default();
+
+        list.retain(|s| s.path != path);
+        list.extend(new);
+        list.sort_by(|a, b| a.path.cmp(&b.path));
+
+        Some(list)
+    });
+}
+
+/// Blockingly read user input from a device and push it into a broker
+/// framework topic
+///
+/// The device is re-opened if it goes away (e.g. because a USB keypad was
+/// unplugged). Returns once `configured` says that the source should no
+/// longer be used.
+fn read_input(
+    source: &InputSource,
+    topic: &Arc<Topic<ButtonEvent>>,
+    status: &Topic<Vec<InputStatus>>,
+    configured: &dyn Fn(&InputSource) -> bool,
+) {
+    let mut press_task: [Option<JoinHandle<()>>; 2] = [None, None];
+    let mut start_time = [None, None];
+
+    let set_status = |error: Option<String>| {
+        let new = InputStatus {
+            path: source.path.clone(),
+            connected: error.is_none(),
+            error,
+        };
+
+        update_status(status, &source.path, Some(new));
+    };
+
+    while configured(source) {
+        let mut device = match Device::open(&source.path) {
+            Ok(device) => device,
+            Err(e) => {
+                set_status(Some(e.to_string()));
+                std::thread::sleep(REOPEN_INTERVAL);
+                continue;
+            }
+        };
+
+        set_status(None);
 
         loop {
-            for ev in device.fetch_events().unwrap() {
+            // Wake up regularly to notice configuration changes even if
+            // the device does not send any events.
+            let readable = match wait_readable(&device, REOPEN_INTERVAL) {
+                Ok(readable) => readable,
+                Err(e) => {
+                    warn!("Failed to wait for input device {}: {e}", source.path);
+                    set_status(Some(e.to_string()));
+                    break;
+                }
+            };
+
+            if !configured(source) {
+                break;
+            }
+
+            if !readable {
+                continue;
+            }
+
+            let events = match device.fetch_events() {
+                Ok(events) => events,
+                Err(e) => {
+                    warn!("Failed to read from input device {}: {e}", source.path);
+                    set_status(Some(e.to_string()));
+                    break;
+                }
+            };
+
+            if !configured(source) {
+                break;
+            }
+
+            for ev in events {
                 if ev.event_type() != EventType::KEY {
                     continue;
                 }
 
                 let id = match ev.kind() {
-                    InputEventKind::Key(Key::KEY_HOME) => 0,
-                    InputEventKind::Key(Key::KEY_ESC) => 1,
+                    InputEventKind::Key(key) if key.code() == source.upper => 0,
+                    InputEventKind::Key(key) if key.code() == source.lower => 1,
                     _ => continue,
                 };
 
@@ -185,7 +335,99 @@ pub fn handle_buttons(
                 }
             }
         }
+
+        // Do not turn a press into a long press if the release was lost
+        // along with the device.
+        for task in press_task.iter_mut().filter_map(Option::take) {
+            block_on(task.cancel());
+        }
+
+        start_time = [None, None];
+    }
+
+    update_status(status, &source.path, None);
+}
+
+/// Spawn threads that blockingly read user input from the buttons on the
+/// TAC and from additionally configured input devices
+pub fn handle_buttons(
+    bb: &mut BrokerBuilder,
+    wtb: &mut WatchedTasksBuilder,
+    topic: Arc<Topic<ButtonEvent>>,
+) -> Result<()> {
+    // Use the "register a read-only and a write-only topic with the same
+    // name" trick to refuse lists with more entries than there are threads.
+    // The request is persisted, so that it is re-applied after a restart.
+    let request = bb.topic(
+        "/v1/tac/display/buttons/inputs",
+        false,
+        true,
+        true,
+        Some(Vec::<InputSource>::new()),
+        1,
+    );
+    let config: Arc<Topic<Vec<InputSource>>> = bb.topic_ro("/v1/tac/display/buttons/inputs", None);
+    let status = bb.topic_ro("/v1/tac/display/buttons/inputs/status", Some(Vec::new()));
+
+    let (mut requests, _) = request.subscribe_unbounded();
+    let config_task = config.clone();
+
+    wtb.spawn_task("button-input-config", async move {
+        while let Some(sources) = requests.next().await {
+            if sources.len() > MAX_INPUT_SOURCES {
+                warn!(
+                    "Refusing {} input devices, at most {MAX_INPUT_SOURCES} are supported",
+                    sources.len()
+                );
+                continue;
+            }
+
+            config_task.set_if_changed(sources);
+        }
+
+        Ok(())
+    })?;
+
+    let topic_thread = topic.clone();
+    let status_thread = status.clone();
+
+    wtb.spawn_thread("button-input-thread", move || {
+        read_input(
+            &InputSource::gpio_keys(),
+            &topic_thread,
+            &status_thread,
+            &|_| true,
+        );
+
+        Ok(())
     })?;
 
+    for slot in 0..MAX_INPUT_SOURCES {
+        let config = config.clone();
+        let topic = topic.clone();
+        let status = status.clone();
+        let (mut config_events, _) = config.clone().subscribe_unbounded();
+
+        wtb.spawn_thread(format!("button-input-{slot}"), move || loop {
+            let source = config.try_get().and_then(|c| c.get(slot).cloned());
+
+            match source {
+                Some(source) => {
+                    let configured = |s: &InputSource| {
+                        config.try_get().map_or(false, |c| c.get(slot) == Some(s))
+                    };
+
+                    read_input(&source, &topic, &status, &configured);
+                }
+                None => {
+                    // Wait for the configuration to change
+                    if block_on(config_events.next()).is_none() {
+                        return Ok(());
+                    }
+                }
+            }
+        })?;
+    }
+
     Ok(())
 }
@@ -17,60 +17,94 @@
 
 use std::io::Cursor;
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use embedded_graphics::{pixelcolor::BinaryColor, prelude::*};
 use png::{BitDepth, ColorType, Encoder};
+use sha2::{Digest, Sha256};
+
+mod frame_times;
+mod framebuffer;
+pub use self::frame_times::{FrameStats, FrameTimer};
+pub use self::framebuffer::FramebufferDriver;
+
+/// How fast a display can show newly drawn content
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Refresh {
+    /// Drawn pixels are visible right away, so animations are fine
+    Immediate,
+    /// Showing new content takes a while and should not happen more often
+    /// than every `min_interval` (e.g. for e-paper panels)
+    #[allow(dead_code)]
+    Slow { min_interval: Duration },
+}
 
-#[cfg(feature = "demo_mode")]
-mod backend {
-    use framebuffer::{FixScreeninfo, VarScreeninfo};
-
-    pub(super) struct Framebuffer {
-        pub device: (),
-        pub var_screen_info: VarScreeninfo,
-        pub fix_screen_info: FixScreeninfo,
-        pub frame: [u8; 240 * 240 * 2],
-    }
-
-    impl Framebuffer {
-        pub fn new(_: &str) -> Result<Self, ()> {
-            Ok(Self {
-                device: (),
-                var_screen_info: VarScreeninfo {
-                    bits_per_pixel: 16,
-                    xres: 240,
-                    yres: 240,
-                    ..Default::default()
-                },
-                fix_screen_info: FixScreeninfo {
-                    line_length: 480,
-                    ..Default::default()
-                },
-                frame: [0; 240 * 240 * 2],
-            })
-        }
+/// Characteristics of a display that screens may adapt their layout to
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct DisplayInfo {
+    pub name: &'static str,
+    /// The resolution in pixels
+    pub size: Size,
+    pub refresh: Refresh,
+}
+
+/// A monochrome display panel the UI can be drawn on
+pub trait DisplayDriver: Send {
+    fn info(&self) -> DisplayInfo;
 
-        pub fn put_var_screeninfo(_: &(), _: &VarScreeninfo) -> Result<(), ()> {
-            Ok(())
+    /// Set a single pixel. Pixels outside of the display are ignored.
+    fn set_pixel(&mut self, x: u32, y: u32, color: BinaryColor);
+
+    /// Get the color of a single pixel, e.g. for screenshots
+    fn pixel(&self, x: u32, y: u32) -> BinaryColor;
+
+    fn clear(&mut self) {
+        let size = self.info().size;
+
+        for y in 0..size.height {
+            for x in 0..size.width {
+                self.set_pixel(x, y, BinaryColor::Off);
+            }
         }
     }
-}
 
-#[cfg(not(feature = "demo_mode"))]
-mod backend {
-    pub(super) use framebuffer::*;
+    /// Show the drawn content on the panel
+    ///
+    /// This is called after every drawing operation. Drivers for displays
+    /// with `Refresh::Slow` should coalesce updates that come in faster than
+    /// the panel can handle them.
+    fn flush(&mut self) {}
 }
 
-use backend::Framebuffer;
+/// Encode screenshots at most this often and serve the previous one
+/// otherwise, so that many clients polling the display do not load the CPU
+const MIN_ENCODE_INTERVAL: Duration = Duration::from_millis(250);
 
-pub struct DisplayExclusive(Framebuffer);
+pub struct DisplayExclusive(Box<dyn DisplayDriver>);
 
 pub struct Display {
     inner: Arc<Mutex<DisplayExclusive>>,
+    frame_timer: FrameTimer,
+}
+
+/// A PNG encoded screenshot
+#[derive(Clone)]
+pub struct Screenshot {
+    pub png: Arc<[u8]>,
+    /// Changes whenever the display content changes, e.g. for use as ETag
+    pub hash: String,
+}
+
+struct CachedScreenshot {
+    taken: Instant,
+    frame: Vec<u8>,
+    screenshot: Screenshot,
 }
 
+#[derive(Clone)]
 pub struct ScreenShooter {
     inner: Arc<Mutex<DisplayExclusive>>,
+    cache: Arc<Mutex<Option<CachedScreenshot>>>,
 }
 
 pub struct DisplayRotated<'a> {
@@ -78,69 +112,152 @@ pub struct DisplayRotated<'a> {
 }
 
 impl Display {
+    /// Use the ST7789 LCD of the LXA TAC via its framebuffer device
     pub fn new() -> Self {
-        let mut fb = Framebuffer::new("/dev/fb0").unwrap();
-        fb.var_screen_info.activate = 128; // FB_ACTIVATE_FORCE
-        Framebuffer::put_var_screeninfo(&fb.device, &fb.var_screen_info).unwrap();
+        Self::with_driver(Box::new(FramebufferDriver::new("/dev/fb0")))
+    }
 
-        let de = DisplayExclusive(fb);
+    pub fn with_driver(driver: Box<dyn DisplayDriver>) -> Self {
+        let de = DisplayExclusive(driver);
         let inner = Arc::new(Mutex::new(de));
+        let frame_timer = FrameTimer::default();
 
-        Self { inner }
+        Self { inner, frame_timer }
     }
 
     pub fn with_lock<F, R>(&self, cb: F) -> R
     where
         F: FnOnce(&mut DisplayExclusive) -> R,
     {
-        cb(&mut self.inner.lock().unwrap())
+        let mut target = self.inner.lock().unwrap();
+
+        let start = Instant::now();
+        let res = cb(&mut target);
+        let drawn = start.elapsed();
+
+        // Drawing the overlay does not count towards the frame time
+        if self.frame_timer.overlay_enabled() {
+            self.frame_timer.draw_overlay(&mut target);
+        }
+
+        let start = Instant::now();
+        target.0.flush();
+        self.frame_timer.record(drawn + start.elapsed());
+
+        res
     }
 
     pub fn clear(&self) {
-        self.with_lock(|target| target.0.frame.iter_mut().for_each(|p| *p = 0x00));
+        self.with_lock(|target| target.0.clear());
+    }
+
+    pub fn info(&self) -> DisplayInfo {
+        self.inner.lock().unwrap().info()
+    }
+
+    /// Get the recorder for the time it takes to draw frames
+    pub fn frame_timer(&self) -> FrameTimer {
+        self.frame_timer.clone()
     }
 
     pub fn screenshooter(&self) -> ScreenShooter {
         ScreenShooter {
             inner: self.inner.clone(),
+            cache: Arc::new(Mutex::new(None)),
         }
     }
 }
 
 impl ScreenShooter {
-    pub fn as_png(&self) -> Vec<u8> {
-        let (image, xres, yres) = {
-            let fb = &self.inner.lock().unwrap().0;
+    /// Get the current display content as grayscale image and its resolution
+    fn frame(&self) -> (Vec<u8>, u32, u32) {
+        let driver = &self.inner.lock().unwrap().0;
 
-            let bpp = (fb.var_screen_info.bits_per_pixel / 8) as usize;
-            let xres = fb.var_screen_info.xres;
-            let yres = fb.var_screen_info.yres;
-            let res = (xres as usize) * (yres as usize);
+        let size = driver.info().size;
 
-            let image: Vec<u8> = (0..res)
-                .map(|i| if fb.frame[i * bpp] != 0 { 0xff } else { 0 })
-                .collect();
+        let image: Vec<u8> = (0..size.height)
+            .flat_map(|y| (0..size.width).map(move |x| (x, y)))
+            .map(|(x, y)| match driver.pixel(x, y) {
+                BinaryColor::On => 0xff,
+                BinaryColor::Off => 0,
+            })
+            .collect();
 
-            (image, xres, yres)
-        };
+        (image, size.width, size.height)
+    }
+
+    pub fn as_png(&self) -> Vec<u8> {
+        let (image, xres, yres) = self.frame();
 
-        let mut dst = Cursor::new(Vec::new());
+        encode(&image, xres, yres)
+    }
 
-        let mut writer = {
-            let mut enc = Encoder::new(&mut dst, xres, yres);
-            enc.set_color(ColorType::Grayscale);
-            enc.set_depth(BitDepth::Eight);
-            enc.write_header().unwrap()
-        };
+    /// Get a screenshot that is at most MIN_ENCODE_INTERVAL old
+    ///
+    /// The display content is only encoded again if it actually changed.
+    pub fn cached(&self) -> Screenshot {
+        self.cached_at(Instant::now())
+    }
 
-        writer.write_image_data(&image).unwrap();
-        writer.finish().unwrap();
+    fn cached_at(&self, now: Instant) -> Screenshot {
+        let mut cache = self.cache.lock().unwrap();
 
-        dst.into_inner()
+        if let Some(cached) = cache.as_mut() {
+            if now.saturating_duration_since(cached.taken) < MIN_ENCODE_INTERVAL {
+                return cached.screenshot.clone();
+            }
+        }
+
+        let (frame, xres, yres) = self.frame();
+
+        match cache.as_mut() {
+            Some(cached) if cached.frame == frame => {
+                cached.taken = now;
+                cached.screenshot.clone()
+            }
+            _ => {
+                let hash = Sha256::digest(&frame);
+                let hash: String = hash[..8].iter().map(|b| format!("{b:02x}")).collect();
+
+                let screenshot = Screenshot {
+                    png: encode(&frame, xres, yres).into(),
+                    hash,
+                };
+
+                *cache = Some(CachedScreenshot {
+                    taken: now,
+                    frame,
+                    screenshot: screenshot.clone(),
+                });
+
+                screenshot
+            }
+        }
     }
 }
 
+/// Encode a grayscale image as PNG
+fn encode(image: &[u8], xres: u32, yres: u32) -> Vec<u8> {
+    let mut dst = Cursor::new(Vec::new());
+
+    let mut writer = {
+        let mut enc = Encoder::new(&mut dst, xres, yres);
+        enc.set_color(ColorType::Grayscale);
+        enc.set_depth(BitDepth::Eight);
+        enc.write_header().unwrap()
+    };
+
+    writer.write_image_data(image).unwrap();
+    writer.finish().unwrap();
+
+    dst.into_inner()
+}
+
 impl DisplayExclusive {
+    pub fn info(&self) -> DisplayInfo {
+        self.0.info()
+    }
+
     /// Return an DrawTarget that draws everything rotated by 90deg
     ///
     /// Drawing a pixel to the bottom of DisplayRotated results in the pixel
@@ -162,27 +279,12 @@ impl DrawTarget for DisplayExclusive {
     where
         I: IntoIterator<Item = Pixel<Self::Color>>,
     {
-        let bpp = self.0.var_screen_info.bits_per_pixel / 8;
-        let xres = self.0.var_screen_info.xres;
-        let yres = self.0.var_screen_info.yres;
-        let line_length = self.0.fix_screen_info.line_length;
-
         for Pixel(coord, color) in pixels {
-            let x = coord.x as u32;
-            let y = coord.y as u32;
-
-            if x >= xres || y >= yres {
+            if coord.x < 0 || coord.y < 0 {
                 continue;
             }
 
-            let offset = line_length * y + bpp * x;
-
-            for b in 0..bpp {
-                self.0.frame[(offset + b) as usize] = match color {
-                    BinaryColor::Off => 0x00,
-                    BinaryColor::On => 0xff,
-                }
-            }
+            self.0.set_pixel(coord.x as u32, coord.y as u32, color);
         }
 
         Ok(())
@@ -191,7 +293,7 @@ impl DrawTarget for DisplayExclusive {
 
 impl OriginDimensions for DisplayExclusive {
     fn size(&self) -> Size {
-        Size::new(self.0.var_screen_info.xres, self.0.var_screen_info.yres)
+        self.0.info().size
     }
 }
 
@@ -203,8 +305,8 @@ impl DrawTarget for DisplayRotated<'_> {
     where
         I: IntoIterator<Item = Pixel<Self::Color>>,
     {
-        let res = self.size();
-        let res_y = res.height as i32;
+        // The width of the rotated display is the height of the actual one
+        let res_y = self.size().width as i32;
 
         let rotated_pixels = pixels
             .into_iter()
@@ -221,3 +323,126 @@ impl OriginDimensions for DisplayRotated<'_> {
         Size::new(orig.height, orig.width)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Instant;
+
+    use embedded_graphics::{
+        pixelcolor::BinaryColor,
+        prelude::*,
+        primitives::{PrimitiveStyle, Rectangle},
+    };
+
+    use super::{Display, DisplayDriver, DisplayInfo, Refresh, MIN_ENCODE_INTERVAL};
+
+    /// A display that only exists in memory and has a non-square resolution
+    struct MemoryDriver {
+        size: Size,
+        pixels: Vec<BinaryColor>,
+    }
+
+    impl DisplayDriver for MemoryDriver {
+        fn info(&self) -> DisplayInfo {
+            DisplayInfo {
+                name: "memory",
+                size: self.size,
+                refresh: Refresh::Immediate,
+            }
+        }
+
+        fn set_pixel(&mut self, x: u32, y: u32, color: BinaryColor) {
+            if x < self.size.width && y < self.size.height {
+                self.pixels[(y * self.size.width + x) as usize] = color;
+            }
+        }
+
+        fn pixel(&self, x: u32, y: u32) -> BinaryColor {
+            self.pixels[(y * self.size.width + x) as usize]
+        }
+    }
+
+    #[test]
+    fn drivers() {
+        let size = Size::new(8, 4);
+        let display = Display::with_driver(Box::new(MemoryDriver {
+            size,
+            pixels: vec![BinaryColor::Off; 32],
+        }));
+
+        println!("The resolution is taken from the driver");
+        assert_eq!(display.info().size, size);
+        assert_eq!(display.with_lock(|target| target.size()), size);
+
+        println!("Pixels outside of the display are ignored");
+        display.with_lock(|target| {
+            Rectangle::new(Point::new(-2, -2), Size::new(20, 20))
+                .into_styled(PrimitiveStyle::with_fill(BinaryColor::On))
+                .draw(target)
+                .unwrap();
+        });
+
+        let on = |x, y| display.with_lock(|target| target.0.pixel(x, y) == BinaryColor::On);
+        assert!(on(0, 0) && on(7, 3));
+
+        println!("Clearing uses the driver resolution");
+        display.clear();
+        assert!(!on(0, 0) && !on(7, 3));
+
+        println!("Rotated drawing swaps the axes");
+        display.with_lock(|target| {
+            let mut rotated = target.rotate();
+            assert_eq!(rotated.size(), Size::new(4, 8));
+
+            Pixel(Point::new(1, 1), BinaryColor::On)
+                .draw(&mut rotated)
+                .unwrap();
+        });
+        assert!(on(1, 3));
+
+        println!("Screenshots have the driver resolution");
+        assert!(!display.screenshooter().as_png().is_empty());
+    }
+
+    #[test]
+    fn screenshot_cache() {
+        let display = Display::with_driver(Box::new(MemoryDriver {
+            size: Size::new(8, 4),
+            pixels: vec![BinaryColor::Off; 32],
+        }));
+
+        let shooter = display.screenshooter();
+        let start = Instant::now();
+        let set = |x, y| {
+            display.with_lock(|target| {
+                Pixel(Point::new(x, y), BinaryColor::On)
+                    .draw(target)
+                    .unwrap()
+            })
+        };
+
+        let first = shooter.cached_at(start);
+
+        println!("Changes are not picked up within the encode interval");
+        set(1, 1);
+        let second = shooter.cached_at(start + MIN_ENCODE_INTERVAL / 2);
+        assert_eq!(first.hash, second.hash);
+        assert!(Arc::ptr_eq(&first.png, &second.png));
+
+        println!("Changes are picked up afterwards");
+        let third = shooter.cached_at(start + MIN_ENCODE_INTERVAL);
+        assert_ne!(first.hash, third.hash);
+        assert_eq!(&*third.png, &shooter.as_png()[..]);
+
+        println!("The same content is not encoded again");
+        let fourth = shooter.cached_at(start + MIN_ENCODE_INTERVAL * 3);
+        assert_eq!(third.hash, fourth.hash);
+        assert!(Arc::ptr_eq(&third.png, &fourth.png));
+
+        println!("Going back to a previous content yields the previous hash");
+        display.clear();
+        let fifth = shooter.cached_at(start + MIN_ENCODE_INTERVAL * 4);
+        assert_eq!(first.hash, fifth.hash);
+    }
+}
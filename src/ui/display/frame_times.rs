@@ -0,0 +1,252 @@
+// This file is part of tacd, the LXA TAC system daemon
+// Copyright (C) 2024 Pengutronix e.K.
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along
+// with this program; if not, write to the Free Software Foundation, Inc.,
+// 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+
+//! Keep track of how long it takes to draw on the display
+//!
+//! Every `Display::with_lock` call draws (part of) a frame and flushes it to
+//! the panel. The time this takes is recorded to find slow redraws, e.g.
+//! during fast measurement updates, and to validate rendering optimizations.
+
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use async_std::prelude::*;
+use async_std::task::sleep;
+use embedded_graphics::{
+    mono_font::{ascii::FONT_6X10, MonoTextStyleBuilder},
+    pixelcolor::BinaryColor,
+    prelude::*,
+    text::{Baseline, Text},
+};
+use serde::{Deserialize, Serialize};
+
+use super::DisplayExclusive;
+use crate::broker::{BrokerBuilder, Topic};
+use crate::watched_tasks::WatchedTasksBuilder;
+
+/// Number of frames the percentiles are calculated over
+const MAX_SAMPLES: usize = 512;
+
+/// How often the frame times are published
+const UPDATE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Frames drawn within this time are counted for the frame rate
+const FPS_WINDOW: Duration = Duration::from_secs(1);
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug, Default)]
+pub struct FrameTimes {
+    /// Frames drawn since the tacd started
+    pub frames: u64,
+    /// Frames drawn within the last second
+    pub fps: u32,
+    /// Times to draw and flush a frame in milliseconds, over the most
+    /// recent frames
+    pub p50_ms: f32,
+    pub p99_ms: f32,
+    pub max_ms: f32,
+}
+
+#[derive(Default)]
+struct Samples {
+    frames: u64,
+    /// When the recent frames were drawn and how long that took
+    recent: VecDeque<(Instant, Duration)>,
+}
+
+impl Samples {
+    fn fps_at(&self, now: Instant) -> u32 {
+        let frames = self
+            .recent
+            .iter()
+            .filter(|(ts, _)| now.saturating_duration_since(*ts) < FPS_WINDOW)
+            .count();
+
+        frames as u32
+    }
+}
+
+/// Record the time it takes to draw frames
+///
+/// This is shared between the `Display` and the `FrameStats` that
+/// publish the results.
+#[derive(Clone, Default)]
+pub struct FrameTimer {
+    samples: Arc<Mutex<Samples>>,
+    overlay: Arc<AtomicBool>,
+}
+
+/// Get the `percent`th percentile of the `sorted` durations
+fn percentile(sorted: &[Duration], percent: usize) -> Duration {
+    match sorted.len() {
+        0 => Duration::ZERO,
+        len => sorted[(len - 1) * percent / 100],
+    }
+}
+
+fn as_ms(duration: Duration) -> f32 {
+    duration.as_secs_f32() * 1000.0
+}
+
+impl FrameTimer {
+    pub(super) fn record(&self, duration: Duration) {
+        self.record_at(Instant::now(), duration)
+    }
+
+    fn record_at(&self, now: Instant, duration: Duration) {
+        let mut samples = self.samples.lock().unwrap();
+
+        samples.frames += 1;
+        samples.recent.push_back((now, duration));
+
+        if samples.recent.len() > MAX_SAMPLES {
+            samples.recent.pop_front();
+        }
+    }
+
+    fn report_at(&self, now: Instant) -> FrameTimes {
+        let samples = self.samples.lock().unwrap();
+
+        let mut sorted: Vec<Duration> = samples.recent.iter().map(|(_, d)| *d).collect();
+        sorted.sort_unstable();
+
+        FrameTimes {
+            frames: samples.frames,
+            fps: samples.fps_at(now),
+            p50_ms: as_ms(percentile(&sorted, 50)),
+            p99_ms: as_ms(percentile(&sorted, 99)),
+            max_ms: as_ms(sorted.last().copied().unwrap_or_default()),
+        }
+    }
+
+    pub(super) fn overlay_enabled(&self) -> bool {
+        self.overlay.load(Ordering::Relaxed)
+    }
+
+    /// Show the time the last frame took and the frame rate in the upper
+    /// right corner of the display
+    pub(super) fn draw_overlay(&self, target: &mut DisplayExclusive) {
+        let (last, fps) = {
+            let samples = self.samples.lock().unwrap();
+            let last = samples.recent.back().map_or(Duration::ZERO, |(_, d)| *d);
+
+            (last, samples.fps_at(Instant::now()))
+        };
+
+        let text = format!("{:5.1}ms {fps:3}fps", as_ms(last));
+
+        let style = MonoTextStyleBuilder::new()
+            .font(&FONT_6X10)
+            .text_color(BinaryColor::On)
+            .background_color(BinaryColor::Off)
+            .build();
+
+        let width = FONT_6X10.character_size.width * text.len() as u32;
+        let x = target.size().width.saturating_sub(width) as i32;
+
+        Text::with_baseline(&text, Point::new(x, 0), style, Baseline::Top)
+            .draw(target)
+            .unwrap();
+    }
+}
+
+/// Topics to look at the frame times and to enable the on-screen overlay
+#[derive(Clone)]
+pub struct FrameStats {
+    pub times: Arc<Topic<FrameTimes>>,
+    pub overlay: Arc<Topic<bool>>,
+}
+
+impl FrameStats {
+    pub fn new(
+        bb: &mut BrokerBuilder,
+        wtb: &mut WatchedTasksBuilder,
+        timer: FrameTimer,
+    ) -> Result<Self> {
+        let times = bb.topic_ro("/v1/tac/display/frame_times", Some(FrameTimes::default()));
+        let overlay = bb.topic_rw("/v1/tac/display/frame_times/overlay", Some(false));
+
+        let times_task = times.clone();
+        let timer_task = timer.clone();
+
+        wtb.spawn_task("display-frame-times", async move {
+            loop {
+                sleep(UPDATE_INTERVAL).await;
+
+                times_task.set_if_changed(timer_task.report_at(Instant::now()));
+            }
+        })?;
+
+        let (mut overlay_events, _) = overlay.clone().subscribe_unbounded();
+
+        wtb.spawn_task("display-frame-times-overlay", async move {
+            while let Some(enabled) = overlay_events.next().await {
+                timer.overlay.store(enabled, Ordering::Relaxed);
+            }
+
+            Ok(())
+        })?;
+
+        Ok(Self { times, overlay })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{FrameTimer, FPS_WINDOW, MAX_SAMPLES};
+
+    #[test]
+    fn frame_times() {
+        let timer = FrameTimer::default();
+        let start = Instant::now();
+
+        println!("No frames were drawn yet");
+        let report = timer.report_at(start);
+        assert_eq!(report.frames, 0);
+        assert_eq!(report.p99_ms, 0.0);
+
+        println!("Percentiles are taken over the recent frames");
+        for i in 0..100 {
+            let duration = if i < 98 { 10 } else { 50 };
+            timer.record_at(start, Duration::from_millis(duration));
+        }
+
+        let report = timer.report_at(start);
+        assert_eq!(report.frames, 100);
+        assert_eq!(report.fps, 100);
+        assert_eq!(report.p50_ms, 10.0);
+        assert_eq!(report.p99_ms, 50.0);
+        assert_eq!(report.max_ms, 50.0);
+
+        println!("Only frames within the last second count for the frame rate");
+        let report = timer.report_at(start + FPS_WINDOW);
+        assert_eq!(report.fps, 0);
+
+        println!("Old frames are dropped");
+        for _ in 0..MAX_SAMPLES {
+            timer.record_at(start, Duration::from_millis(1));
+        }
+
+        let report = timer.report_at(start);
+        assert_eq!(report.frames, 100 + MAX_SAMPLES as u64);
+        assert_eq!(report.max_ms, 1.0);
+    }
+}
@@ -0,0 +1,125 @@
+// This file is part of tacd, the LXA TAC system daemon
+// Copyright (C) 2022 Pengutronix e.K.
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along
+// with this program; if not, write to the Free Software Foundation, Inc.,
+// 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+
+use embedded_graphics::{pixelcolor::BinaryColor, prelude::*};
+
+use super::{DisplayDriver, DisplayInfo, Refresh};
+
+#[cfg(feature = "demo_mode")]
+mod backend {
+    use framebuffer::{FixScreeninfo, VarScreeninfo};
+
+    pub(super) struct Framebuffer {
+        pub device: (),
+        pub var_screen_info: VarScreeninfo,
+        pub fix_screen_info: FixScreeninfo,
+        pub frame: [u8; 240 * 240 * 2],
+    }
+
+    impl Framebuffer {
+        pub fn new(_: &str) -> Result<Self, ()> {
+            Ok(Self {
+                device: (),
+                var_screen_info: VarScreeninfo {
+                    bits_per_pixel: 16,
+                    xres: 240,
+                    yres: 240,
+                    ..Default::default()
+                },
+                fix_screen_info: FixScreeninfo {
+                    line_length: 480,
+                    ..Default::default()
+                },
+                frame: [0; 240 * 240 * 2],
+            })
+        }
+
+        pub fn put_var_screeninfo(_: &(), _: &VarScreeninfo) -> Result<(), ()> {
+            Ok(())
+        }
+    }
+}
+
+#[cfg(not(feature = "demo_mode"))]
+mod backend {
+    pub(super) use framebuffer::*;
+}
+
+use backend::Framebuffer;
+
+/// A display that is exposed as Linux framebuffer device, like the ST7789
+/// LCD on the LXA TAC
+///
+/// The framebuffer is memory mapped, so drawn pixels are visible right away.
+pub struct FramebufferDriver(Framebuffer);
+
+impl FramebufferDriver {
+    pub fn new(path: &str) -> Self {
+        let mut fb = Framebuffer::new(path).unwrap();
+        fb.var_screen_info.activate = 128; // FB_ACTIVATE_FORCE
+        Framebuffer::put_var_screeninfo(&fb.device, &fb.var_screen_info).unwrap();
+
+        Self(fb)
+    }
+
+    fn offset(&self, x: u32, y: u32) -> Option<usize> {
+        let bpp = self.0.var_screen_info.bits_per_pixel / 8;
+        let xres = self.0.var_screen_info.xres;
+        let yres = self.0.var_screen_info.yres;
+        let line_length = self.0.fix_screen_info.line_length;
+
+        if x >= xres || y >= yres {
+            return None;
+        }
+
+        Some((line_length * y + bpp * x) as usize)
+    }
+}
+
+impl DisplayDriver for FramebufferDriver {
+    fn info(&self) -> DisplayInfo {
+        DisplayInfo {
+            name: "framebuffer",
+            size: Size::new(self.0.var_screen_info.xres, self.0.var_screen_info.yres),
+            refresh: Refresh::Immediate,
+        }
+    }
+
+    fn set_pixel(&mut self, x: u32, y: u32, color: BinaryColor) {
+        let bpp = (self.0.var_screen_info.bits_per_pixel / 8) as usize;
+
+        if let Some(offset) = self.offset(x, y) {
+            let val = match color {
+                BinaryColor::Off => 0x00,
+                BinaryColor::On => 0xff,
+            };
+
+            self.0.frame[offset..(offset + bpp)].fill(val);
+        }
+    }
+
+    fn pixel(&self, x: u32, y: u32) -> BinaryColor {
+        match self.offset(x, y) {
+            Some(offset) if self.0.frame[offset] != 0 => BinaryColor::On,
+            _ => BinaryColor::Off,
+        }
+    }
+
+    fn clear(&mut self) {
+        self.0.frame.iter_mut().for_each(|p| *p = 0x00);
+    }
+}
@@ -0,0 +1,252 @@
+// This file is part of tacd, the LXA TAC system daemon
+// Copyright (C) 2024 Pengutronix e.K.
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along
+// with this program; if not, write to the Free Software Foundation, Inc.,
+// 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+
+use std::net::SocketAddr;
+
+use async_std::sync::Arc;
+use serde::{Deserialize, Serialize};
+use tide::{Request, Response, Server};
+
+use crate::broker::{BrokerBuilder, Topic};
+use crate::http_server::WriteAccess;
+
+/// Longest message that is accepted to be shown on the LCD
+const MAX_MESSAGE_LEN: usize = 40;
+
+/// Who started the locator
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub enum LocatorSource {
+    /// Using the buttons on the TAC
+    Lcd,
+    /// Via the web interface from the given address
+    Web(String),
+    /// Via the API by a client that identified itself by this name
+    Api(String),
+}
+
+impl LocatorSource {
+    /// A short description to show on the LCD
+    pub fn describe(&self) -> String {
+        match self {
+            Self::Lcd => "Started on the TAC".to_string(),
+            Self::Web(address) => format!("Started from {address}"),
+            Self::Api(name) => format!("Started by {name}"),
+        }
+    }
+}
+
+/// The value of the locator topic
+///
+/// For compatibility a plain boolean is accepted as well when setting it.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug, Default)]
+#[serde(from = "LocatorRepr")]
+pub struct Locator {
+    pub active: bool,
+    pub source: Option<LocatorSource>,
+    /// A message for the people near the TAC
+    pub message: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum LocatorRepr {
+    Active(bool),
+    Full {
+        active: bool,
+        #[serde(default)]
+        source: Option<LocatorSource>,
+        #[serde(default)]
+        message: Option<String>,
+    },
+}
+
+impl From<LocatorRepr> for Locator {
+    fn from(repr: LocatorRepr) -> Self {
+        match repr {
+            LocatorRepr::Active(active) => Self {
+                active,
+                ..Default::default()
+            },
+            LocatorRepr::Full {
+                active,
+                source,
+                message,
+            } => Self {
+                active,
+                source,
+                message: message.map(|m| m.chars().take(MAX_MESSAGE_LEN).collect()),
+            },
+        }
+    }
+}
+
+impl Locator {
+    pub fn started(source: LocatorSource, message: Option<String>) -> Self {
+        LocatorRepr::Full {
+            active: true,
+            source: Some(source),
+            message,
+        }
+        .into()
+    }
+
+    pub fn stopped() -> Self {
+        Self::default()
+    }
+
+    /// Register the topic the locator state is kept in
+    ///
+    /// This happens outside of the UI, as e.g. the MOTD shows the locator
+    /// as well.
+    pub fn topic(bb: &mut BrokerBuilder) -> Arc<Topic<Self>> {
+        bb.topic_rw("/v1/tac/display/locator", Some(Self::stopped()))
+    }
+
+    /// Describe who started the locator and why, one line each
+    pub fn describe(&self) -> Vec<String> {
+        let source = self
+            .source
+            .as_ref()
+            .map(|s| s.describe())
+            .unwrap_or_else(|| "Started via the API".to_string());
+
+        match &self.message {
+            Some(message) => vec![source, format!("Message: {message}")],
+            None => vec![source],
+        }
+    }
+}
+
+/// The body of a request to start the locator
+#[derive(Deserialize)]
+struct StartRequest {
+    /// Identify API clients by name instead of by their address
+    name: Option<String>,
+    message: Option<String>,
+}
+
+fn source_for(name: Option<String>, peer_addr: Option<&str>) -> LocatorSource {
+    match name {
+        Some(name) => LocatorSource::Api(name),
+        None => {
+            let address = peer_addr
+                .map(|addr| match addr.parse::<SocketAddr>() {
+                    Ok(sa) => sa.ip().to_string(),
+                    Err(_) => addr.to_string(),
+                })
+                .unwrap_or_else(|| "an unknown address".to_string());
+
+            LocatorSource::Web(address)
+        }
+    }
+}
+
+/// Add a web endpoint that starts the locator and records who started it
+pub fn serve(server: &mut Server<()>, locator: Arc<Topic<Locator>>) {
+    server
+        .at("/v1/tac/display/locator/start")
+        .put(move |mut req: Request<()>| {
+            let locator = locator.clone();
+
+            async move {
+                if let Some(res) = WriteAccess::refuse(&req) {
+                    return Ok(res);
+                }
+
+                let start: StartRequest = req.body_json().await?;
+                let source = source_for(start.name, req.peer_addr());
+
+                locator.set(Locator::started(source, start.message));
+
+                Ok(Response::new(204))
+            }
+        });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{source_for, Locator, LocatorSource};
+
+    #[test]
+    fn parse() {
+        println!("Plain booleans are still accepted");
+        let locator: Locator = serde_json::from_str("true").unwrap();
+        assert_eq!(
+            locator,
+            Locator {
+                active: true,
+                source: None,
+                message: None,
+            }
+        );
+
+        println!("The source and message are optional");
+        let locator: Locator = serde_json::from_str(r#"{"active": false}"#).unwrap();
+        assert_eq!(locator, Locator::stopped());
+
+        let locator: Locator = serde_json::from_str(
+            r#"{"active": true, "source": {"Api": "ci"}, "message": "Replace the SD card"}"#,
+        )
+        .unwrap();
+        assert_eq!(locator.source, Some(LocatorSource::Api("ci".to_string())));
+        assert_eq!(locator.message.as_deref(), Some("Replace the SD card"));
+
+        println!("Long messages are shortened");
+        let locator = Locator::started(LocatorSource::Lcd, Some("a".repeat(100)));
+        assert_eq!(locator.message.unwrap().len(), 40);
+
+        println!("Values round-trip");
+        let locator = Locator::started(LocatorSource::Web("::1".to_string()), None);
+        let json = serde_json::to_string(&locator).unwrap();
+        assert_eq!(serde_json::from_str::<Locator>(&json).unwrap(), locator);
+    }
+
+    #[test]
+    fn describe() {
+        let locator = Locator::started(LocatorSource::Api("ci".to_string()), None);
+        assert_eq!(locator.describe(), ["Started by ci"]);
+
+        let locator = Locator::started(
+            LocatorSource::Web("192.168.1.10".to_string()),
+            Some("Replace the SD card".to_string()),
+        );
+        assert_eq!(
+            locator.describe(),
+            ["Started from 192.168.1.10", "Message: Replace the SD card"]
+        );
+
+        println!("Plain booleans do not carry a source");
+        let locator: Locator = serde_json::from_str("true").unwrap();
+        assert_eq!(locator.describe(), ["Started via the API"]);
+    }
+
+    #[test]
+    fn sources() {
+        assert_eq!(
+            source_for(None, Some("192.168.1.10:51234")),
+            LocatorSource::Web("192.168.1.10".to_string())
+        );
+        assert_eq!(
+            source_for(None, Some("[fe80::1]:51234")),
+            LocatorSource::Web("fe80::1".to_string())
+        );
+        assert_eq!(
+            source_for(Some("ci".to_string()), Some("192.168.1.10:51234")),
+            LocatorSource::Api("ci".to_string())
+        );
+    }
+}
@@ -0,0 +1,476 @@
+// This file is part of tacd, the LXA TAC system daemon
+// Copyright (C) 2024 Pengutronix e.K.
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along
+// with this program; if not, write to the Free Software Foundation, Inc.,
+// 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+
+//! A minimal QR code encoder
+//!
+//! We only ever encode short URLs to show on the LCD, so this encoder is
+//! restricted to what is needed for that:
+//! byte mode, error correction level L and versions 1 to 5.
+//! These versions use a single error correction block, which saves us
+//! from having to implement codeword interleaving.
+//! The largest symbol (version 5) holds up to 106 bytes.
+
+/// Number of data codewords per version (starting at version 1)
+const DATA_CODEWORDS: [usize; 5] = [19, 34, 55, 80, 108];
+
+/// Number of error correction codewords per version (starting at version 1)
+const ECC_CODEWORDS: [usize; 5] = [7, 10, 15, 20, 26];
+
+/// Format bits for error correction level L
+const ECC_LEVEL_L: u32 = 0b01;
+
+/// Number of modules of light area that should surround the symbol
+pub const QUIET_ZONE: usize = 2;
+
+pub struct QrCode {
+    size: usize,
+    modules: Vec<bool>,
+    function: Vec<bool>,
+}
+
+impl QrCode {
+    /// Encode `data` into the smallest QR code that fits it
+    ///
+    /// Returns `None` if the data does not fit into a version 5 symbol.
+    pub fn encode(data: &[u8]) -> Option<Self> {
+        // Mode indicator (4 bits) and character count (8 bits) are prepended
+        // to the data.
+        let version = DATA_CODEWORDS
+            .iter()
+            .position(|&cap| data.len() + 2 <= cap)?
+            + 1;
+
+        let codewords = codewords(data, version);
+
+        let mut qr = Self::new(version);
+        qr.draw_function_patterns(version);
+        qr.draw_codewords(&codewords);
+
+        // Try all masks and keep the one that results in the least
+        // confusing image for the scanner.
+        let mask = (0..8)
+            .min_by_key(|&mask| {
+                qr.apply_mask(mask);
+                qr.draw_format_bits(mask);
+                let penalty = qr.penalty();
+                qr.apply_mask(mask);
+                penalty
+            })
+            .unwrap();
+
+        qr.apply_mask(mask);
+        qr.draw_format_bits(mask);
+
+        Some(qr)
+    }
+
+    /// Number of modules along each side (excluding the quiet zone)
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Is the module at `x`, `y` dark?
+    pub fn get(&self, x: usize, y: usize) -> bool {
+        self.modules[y * self.size + x]
+    }
+
+    fn new(version: usize) -> Self {
+        let size = 17 + 4 * version;
+
+        Self {
+            size,
+            modules: vec![false; size * size],
+            function: vec![false; size * size],
+        }
+    }
+
+    fn set_function(&mut self, x: usize, y: usize, dark: bool) {
+        let idx = y * self.size + x;
+
+        self.modules[idx] = dark;
+        self.function[idx] = true;
+    }
+
+    fn draw_function_patterns(&mut self, version: usize) {
+        let size = self.size;
+
+        // Timing patterns
+        for i in 0..size {
+            self.set_function(6, i, i % 2 == 0);
+            self.set_function(i, 6, i % 2 == 0);
+        }
+
+        // Finder patterns (including their separators)
+        for (cx, cy) in [(3, 3), (size - 4, 3), (3, size - 4)] {
+            for dy in -4..=4_isize {
+                for dx in -4..=4_isize {
+                    let x = cx as isize + dx;
+                    let y = cy as isize + dy;
+
+                    if (0..size as isize).contains(&x) && (0..size as isize).contains(&y) {
+                        let dist = dx.abs().max(dy.abs());
+                        self.set_function(x as usize, y as usize, dist != 2 && dist != 4);
+                    }
+                }
+            }
+        }
+
+        // Versions 2 to 6 have a single alignment pattern
+        if version >= 2 {
+            let c = size - 7;
+
+            for dy in -2..=2_isize {
+                for dx in -2..=2_isize {
+                    let dist = dx.abs().max(dy.abs());
+                    let x = (c as isize + dx) as usize;
+                    let y = (c as isize + dy) as usize;
+
+                    self.set_function(x, y, dist != 1);
+                }
+            }
+        }
+
+        // Reserve the format information areas.
+        // They are overwritten with the actual format once the mask is known.
+        self.draw_format_bits(0);
+    }
+
+    fn draw_format_bits(&mut self, mask: u32) {
+        let size = self.size;
+        let bits = format_bits(mask);
+        let bit = |i: usize| (bits >> i) & 1 != 0;
+
+        // First copy, around the top left finder pattern
+        for i in 0..=5 {
+            self.set_function(8, i, bit(i));
+        }
+
+        self.set_function(8, 7, bit(6));
+        self.set_function(8, 8, bit(7));
+        self.set_function(7, 8, bit(8));
+
+        for i in 9..15 {
+            self.set_function(14 - i, 8, bit(i));
+        }
+
+        // Second copy, split between the other two finder patterns
+        for i in 0..8 {
+            self.set_function(size - 1 - i, 8, bit(i));
+        }
+
+        for i in 8..15 {
+            self.set_function(8, size - 15 + i, bit(i));
+        }
+
+        // The "dark module" is always set
+        self.set_function(8, size - 8, true);
+    }
+
+    /// Place the codewords in the zig-zag pattern mandated by the standard
+    fn draw_codewords(&mut self, codewords: &[u8]) {
+        let size = self.size;
+        let total_bits = codewords.len() * 8;
+        let mut i = 0;
+
+        let mut right = size - 1;
+
+        loop {
+            // Skip the vertical timing pattern
+            if right == 6 {
+                right = 5;
+            }
+
+            let upward = (right + 1) & 2 == 0;
+
+            for vert in 0..size {
+                for j in 0..2 {
+                    let x = right - j;
+                    let y = if upward { size - 1 - vert } else { vert };
+                    let idx = y * size + x;
+
+                    if !self.function[idx] && i < total_bits {
+                        self.modules[idx] = (codewords[i / 8] >> (7 - (i % 8))) & 1 != 0;
+                        i += 1;
+                    }
+                }
+            }
+
+            if right < 2 {
+                break;
+            }
+
+            right -= 2;
+        }
+    }
+
+    /// Toggle all non-function modules selected by `mask`
+    ///
+    /// Applying the same mask twice restores the original state.
+    fn apply_mask(&mut self, mask: u32) {
+        let size = self.size;
+
+        for y in 0..size {
+            for x in 0..size {
+                let invert = match mask {
+                    0 => (x + y) % 2 == 0,
+                    1 => y % 2 == 0,
+                    2 => x % 3 == 0,
+                    3 => (x + y) % 3 == 0,
+                    4 => (x / 3 + y / 2) % 2 == 0,
+                    5 => x * y % 2 + x * y % 3 == 0,
+                    6 => (x * y % 2 + x * y % 3) % 2 == 0,
+                    _ => ((x + y) % 2 + x * y % 3) % 2 == 0,
+                };
+
+                let idx = y * size + x;
+
+                if invert && !self.function[idx] {
+                    self.modules[idx] = !self.modules[idx];
+                }
+            }
+        }
+    }
+
+    /// Rate how hard the symbol is to scan (lower is better)
+    fn penalty(&self) -> u32 {
+        let size = self.size;
+        let mut penalty = 0;
+
+        let rows = (0..size).map(|y| (0..size).map(|x| self.get(x, y)).collect::<Vec<_>>());
+        let cols = (0..size).map(|x| (0..size).map(|y| self.get(x, y)).collect::<Vec<_>>());
+
+        for line in rows.chain(cols) {
+            // Runs of five or more modules of the same color
+            let mut run = 1;
+
+            for i in 1..=size {
+                if i < size && line[i] == line[i - 1] {
+                    run += 1;
+                } else {
+                    if run >= 5 {
+                        penalty += run - 2;
+                    }
+
+                    run = 1;
+                }
+            }
+
+            // Patterns that look like finder patterns
+            const FINDER_LIKE: [bool; 11] = [
+                true, false, true, true, true, false, true, false, false, false, false,
+            ];
+
+            for window in line.windows(FINDER_LIKE.len()) {
+                if window == FINDER_LIKE || window.iter().rev().eq(FINDER_LIKE.iter()) {
+                    penalty += 40;
+                }
+            }
+        }
+
+        // 2x2 blocks of the same color
+        for y in 1..size {
+            for x in 1..size {
+                let c = self.get(x, y);
+
+                if c == self.get(x - 1, y) && c == self.get(x, y - 1) && c == self.get(x - 1, y - 1)
+                {
+                    penalty += 3;
+                }
+            }
+        }
+
+        // Deviation from a 50/50 ratio of dark and light modules
+        let total = (size * size) as u32;
+        let dark = self.modules.iter().filter(|&&m| m).count() as u32;
+        let deviation = (dark * 20).abs_diff(total * 10);
+
+        penalty + (deviation / total) * 10
+    }
+}
+
+/// BCH-protected and masked format information for error correction level L
+fn format_bits(mask: u32) -> u32 {
+    let data = (ECC_LEVEL_L << 3) | mask;
+
+    let mut rem = data;
+    for _ in 0..10 {
+        rem = (rem << 1) ^ ((rem >> 9) * 0x537);
+    }
+
+    ((data << 10) | rem) ^ 0x5412
+}
+
+/// Build the data and error correction codewords for a byte mode segment
+fn codewords(data: &[u8], version: usize) -> Vec<u8> {
+    let capacity = DATA_CODEWORDS[version - 1];
+    let mut bits = BitBuffer::default();
+
+    bits.push(0b0100, 4);
+    bits.push(data.len() as u32, 8);
+
+    for &byte in data {
+        bits.push(byte as u32, 8);
+    }
+
+    // Terminator and padding to a byte boundary
+    let terminator = (capacity * 8 - bits.len).min(4);
+    bits.push(0, terminator);
+    bits.push(0, (8 - bits.len % 8) % 8);
+
+    let mut codewords = bits.bytes;
+
+    for pad in [0xec, 0x11].iter().cycle() {
+        if codewords.len() >= capacity {
+            break;
+        }
+
+        codewords.push(*pad);
+    }
+
+    let ecc = reed_solomon(&codewords, ECC_CODEWORDS[version - 1]);
+    codewords.extend(ecc);
+
+    codewords
+}
+
+#[derive(Default)]
+struct BitBuffer {
+    bytes: Vec<u8>,
+    len: usize,
+}
+
+impl BitBuffer {
+    fn push(&mut self, val: u32, num_bits: usize) {
+        for i in (0..num_bits).rev() {
+            if self.len == self.bytes.len() * 8 {
+                self.bytes.push(0);
+            }
+
+            if (val >> i) & 1 != 0 {
+                let last = self.bytes.last_mut().unwrap();
+                *last |= 0x80 >> (self.len % 8);
+            }
+
+            self.len += 1;
+        }
+    }
+}
+
+/// Multiply in GF(2^8) modulo x^8 + x^4 + x^3 + x^2 + 1
+fn gf_mul(x: u8, y: u8) -> u8 {
+    let mut z: u8 = 0;
+
+    for i in (0..8).rev() {
+        z = (z << 1) ^ if z & 0x80 != 0 { 0x1d } else { 0 };
+
+        if (y >> i) & 1 != 0 {
+            z ^= x;
+        }
+    }
+
+    z
+}
+
+/// Calculate `degree` Reed-Solomon error correction codewords for `data`
+fn reed_solomon(data: &[u8], degree: usize) -> Vec<u8> {
+    // Calculate the generator polynomial (x - a^0)(x - a^1)...(x - a^(degree-1))
+    // with the leading coefficient omitted.
+    let mut divisor = vec![0u8; degree];
+    divisor[degree - 1] = 1;
+
+    let mut root = 1;
+
+    for _ in 0..degree {
+        for j in 0..degree {
+            divisor[j] = gf_mul(divisor[j], root);
+
+            if j + 1 < degree {
+                divisor[j] ^= divisor[j + 1];
+            }
+        }
+
+        root = gf_mul(root, 0x02);
+    }
+
+    // Polynomial division, keeping only the remainder
+    let mut remainder = vec![0u8; degree];
+
+    for &byte in data {
+        let factor = byte ^ remainder.remove(0);
+        remainder.push(0);
+
+        for (r, d) in remainder.iter_mut().zip(divisor.iter()) {
+            *r ^= gf_mul(*d, factor);
+        }
+    }
+
+    remainder
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{format_bits, reed_solomon, QrCode};
+
+    #[test]
+    fn error_correction() {
+        // The example from the "HELLO WORLD" 1-M walkthrough on thonky.com
+        let data = [
+            32, 91, 11, 120, 209, 114, 220, 77, 67, 64, 236, 17, 236, 17, 236, 17,
+        ];
+        let ecc = [196, 35, 39, 119, 235, 215, 231, 226, 93, 23];
+
+        assert_eq!(reed_solomon(&data, 10), ecc);
+    }
+
+    #[test]
+    fn format_information() {
+        assert_eq!(format_bits(0), 0b111011111000100);
+        assert_eq!(format_bits(1), 0b111001011110011);
+        assert_eq!(format_bits(7), 0b110100101110110);
+    }
+
+    #[test]
+    fn versions() {
+        let url = "http://lxatac-12345";
+
+        assert_eq!(QrCode::encode(url.as_bytes()).unwrap().size(), 25);
+        assert_eq!(QrCode::encode(&[b'a'; 17]).unwrap().size(), 21);
+        assert_eq!(QrCode::encode(&[b'a'; 18]).unwrap().size(), 25);
+        assert_eq!(QrCode::encode(&[b'a'; 106]).unwrap().size(), 37);
+        assert!(QrCode::encode(&[b'a'; 107]).is_none());
+    }
+
+    #[test]
+    fn finder_patterns() {
+        let qr = QrCode::encode(b"http://192.168.1.1").unwrap();
+        let size = qr.size();
+
+        for (ox, oy) in [(0, 0), (size - 7, 0), (0, size - 7)] {
+            for i in 0..7 {
+                // Outer ring is dark
+                assert!(qr.get(ox + i, oy));
+                assert!(qr.get(ox + i, oy + 6));
+                assert!(qr.get(ox, oy + i));
+                assert!(qr.get(ox + 6, oy + i));
+            }
+
+            // Followed by a light ring and a dark center
+            assert!(!qr.get(ox + 1, oy + 1));
+            assert!(qr.get(ox + 3, oy + 3));
+        }
+    }
+}
@@ -27,8 +27,14 @@ use embedded_graphics::{
 };
 use serde::{Deserialize, Serialize};
 
+mod command_palette;
+mod confirm_action;
+mod conflict;
+mod degraded;
 mod diagnostics;
 mod dig_out;
+mod gpio_conflict;
+mod hardware_mismatch;
 mod help;
 mod iobus;
 mod iobus_health;
@@ -36,18 +42,29 @@ mod locator;
 mod overtemperature;
 mod power;
 mod power_fail;
+mod qr_code;
 mod reboot;
 mod screensaver;
+mod selftest;
 mod setup;
+mod ssh_key_import;
+mod state_recovery;
 mod system;
+mod tour;
 mod uart;
 mod update_available;
 mod update_installation;
 mod usb;
 mod usb_overload;
 
+use command_palette::CommandPaletteScreen;
+use confirm_action::ConfirmActionScreen;
+use conflict::ConflictScreen;
+use degraded::DegradedScreen;
 use diagnostics::DiagnosticsScreen;
 use dig_out::DigOutScreen;
+use gpio_conflict::GpioConflictScreen;
+use hardware_mismatch::HardwareMismatchScreen;
 use help::HelpScreen;
 use iobus::IoBusScreen;
 use iobus_health::IoBusHealthScreen;
@@ -55,10 +72,15 @@ use locator::LocatorScreen;
 use overtemperature::OverTemperatureScreen;
 use power::PowerScreen;
 use power_fail::PowerFailScreen;
+use qr_code::QrCodeScreen;
 use reboot::RebootConfirmScreen;
 use screensaver::ScreenSaverScreen;
+use selftest::SelfTestScreen;
 use setup::SetupScreen;
+use ssh_key_import::SshKeyImportScreen;
+use state_recovery::StateRecoveryScreen;
 use system::SystemScreen;
+use tour::TourScreen;
 use uart::UartScreen;
 use update_available::UpdateAvailableScreen;
 use update_installation::UpdateInstallationScreen;
@@ -69,8 +91,8 @@ use super::buttons;
 use super::widgets;
 use super::{AlertList, Alerter, InputEvent, Ui, UiResources};
 use crate::ui::display::{Display, DisplayExclusive};
+use crate::ui::locator::Locator;
 use crate::{broker::Topic, watched_tasks::WatchedTasksBuilder};
-use buttons::ButtonEvent;
 use widgets::UI_TEXT_FONT;
 
 #[derive(Serialize, Deserialize, PartialEq, PartialOrd, Eq, Ord, Clone, Copy, Debug)]
@@ -88,15 +110,26 @@ pub enum AlertScreen {
     ScreenSaver,
     IoBusHealth,
     PowerFail,
+    GpioConflict,
     Locator,
     RebootConfirm,
     UpdateAvailable,
     UpdateInstallation,
     UsbOverload,
+    QrCode,
     Help,
     Setup,
     Diagnostics,
+    SshKeyImport,
     OverTemperature,
+    Tour,
+    Degraded,
+    CommandPalette,
+    HardwareMismatch,
+    ConfirmAction,
+    Conflict,
+    StateRecovery,
+    SelfTest,
 }
 
 #[derive(Serialize, Deserialize, PartialEq, PartialOrd, Eq, Ord, Clone, Copy, Debug)]
@@ -121,6 +154,100 @@ impl NormalScreen {
             Self::Uart => Self::DutPower,
         }
     }
+
+    /// All normal screens in the order they are cycled through
+    pub fn all() -> Vec<Self> {
+        let mut screens = vec![Self::first()];
+
+        loop {
+            let next = screens.last().unwrap().next();
+
+            if next == Self::first() {
+                break screens;
+            }
+
+            screens.push(next);
+        }
+    }
+
+    /// Explain what this screen shows to new users
+    pub fn annotation(&self) -> Annotation {
+        match self {
+            Self::DutPower => Annotation {
+                title: "DUT Power",
+                caption: "Switch the power
+supply of the DUT and
+see the voltage and
+current it draws.",
+                topics: &[
+                    "/v1/dut/powered",
+                    "/v1/dut/feedback/voltage",
+                    "/v1/dut/feedback/current",
+                ],
+            },
+            Self::Usb => Annotation {
+                title: "USB Host",
+                caption: "Switch the power of
+the three USB host
+ports and see the
+current they supply.",
+                topics: &[
+                    "/v1/usb/host/port1/powered",
+                    "/v1/usb/host/port2/powered",
+                    "/v1/usb/host/port3/powered",
+                    "/v1/usb/host/total/feedback/current",
+                ],
+            },
+            Self::DigOut => Annotation {
+                title: "Digital Out",
+                caption: "Control the two
+digital outputs, e.g.
+to press buttons on
+the DUT.",
+                topics: &["/v1/output/out_0/asserted", "/v1/output/out_1/asserted"],
+            },
+            Self::System => Annotation {
+                title: "System Status",
+                caption: "See the state of the
+TAC itself, like its
+temperature and
+network links.",
+                topics: &[
+                    "/v1/tac/temperatures/soc",
+                    "/v1/tac/network/interface/uplink",
+                    "/v1/tac/network/interface/dut",
+                ],
+            },
+            Self::IoBus => Annotation {
+                title: "IOBus",
+                caption: "Power the IOBus for
+LXA IOBus devices
+and see how many are
+connected.",
+                topics: &[
+                    "/v1/iobus/powered",
+                    "/v1/iobus/server/info",
+                    "/v1/iobus/feedback/voltage",
+                ],
+            },
+            Self::Uart => Annotation {
+                title: "DUT UART",
+                caption: "Enable the serial
+console of the DUT
+in each direction.",
+                topics: &["/v1/uart/rx/enabled", "/v1/uart/tx/enabled"],
+            },
+        }
+    }
+}
+
+/// An explanation of a normal screen for the intro tour
+pub struct Annotation {
+    pub title: &'static str,
+    /// Text for the LCD, with line breaks to fit the display
+    pub caption: &'static str,
+    /// Topics that control or show the same things in the web interface
+    pub topics: &'static [&'static str],
 }
 
 #[async_trait]
@@ -146,17 +273,21 @@ fn draw_border(target: &mut DisplayExclusive, text: &str, screen: NormalScreen)
     .draw(target)
     .unwrap();
 
-    Line::new(Point::new(0, 23), Point::new(240, 23))
+    let size = target.info().size;
+    let width = size.width as i32;
+    let height = size.height as i32;
+
+    Line::new(Point::new(0, 23), Point::new(width, 23))
         .into_styled(PrimitiveStyle::with_stroke(BinaryColor::On, 2))
         .draw(target)
         .unwrap();
 
     let screen_idx = screen as i32;
     let num_screens = (NormalScreen::Uart as i32) + 1;
-    let x_start = screen_idx * 240 / num_screens;
-    let x_end = (screen_idx + 1) * 240 / num_screens;
+    let x_start = screen_idx * width / num_screens;
+    let x_end = (screen_idx + 1) * width / num_screens;
 
-    Line::new(Point::new(x_start, 240), Point::new(x_end, 240))
+    Line::new(Point::new(x_start, height), Point::new(x_end, height))
         .into_styled(PrimitiveStyle::with_stroke(BinaryColor::On, 4))
         .draw(target)
         .unwrap();
@@ -174,7 +305,8 @@ pub fn message(target: &mut DisplayExclusive, text: &str) -> Rectangle {
 
     let mut text = Text::with_alignment(text, Point::zero(), ui_text_style, Alignment::Center);
 
-    let offset = Point::new(120, 120) - text.bounding_box().center();
+    let center = Point::zero() + target.info().size / 2;
+    let offset = center - text.bounding_box().center();
     text.translate_mut(offset);
 
     text.draw(target).unwrap();
@@ -190,9 +322,9 @@ pub(super) fn init(
     wtb: &mut WatchedTasksBuilder,
     res: &UiResources,
     alerts: &Arc<Topic<AlertList>>,
-    buttons: &Arc<Topic<ButtonEvent>>,
     reboot_message: &Arc<Topic<Option<String>>>,
-    locator: &Arc<Topic<bool>>,
+    locator: &Arc<Topic<Locator>>,
+    tour_step: &Arc<Topic<Option<usize>>>,
 ) -> Result<Vec<Box<dyn ActivatableScreen>>> {
     Ok(vec![
         Box::new(DigOutScreen::new()),
@@ -202,7 +334,10 @@ pub(super) fn init(
         Box::new(UartScreen::new()),
         Box::new(UsbScreen::new()),
         Box::new(DiagnosticsScreen::new()),
+        Box::new(QrCodeScreen::new()),
+        Box::new(CommandPaletteScreen::new()),
         Box::new(HelpScreen::new(wtb, alerts, &res.setup_mode.show_help)?),
+        Box::new(TourScreen::new(tour_step)),
         Box::new(IoBusHealthScreen::new(
             wtb,
             alerts,
@@ -215,10 +350,20 @@ pub(super) fn init(
             reboot_message,
             &res.rauc.should_reboot,
         )?),
-        Box::new(UpdateAvailableScreen::new(wtb, alerts, &res.rauc.channels)?),
+        Box::new(UpdateAvailableScreen::new(
+            wtb,
+            alerts,
+            &res.rauc.channels,
+            &res.rauc.bundle_info,
+        )?),
         Box::new(RebootConfirmScreen::new(wtb, alerts, reboot_message)?),
-        Box::new(ScreenSaverScreen::new(wtb, buttons, alerts)?),
+        Box::new(ScreenSaverScreen::new(wtb, &res.backlight.idle, alerts)?),
         Box::new(SetupScreen::new(wtb, alerts, &res.setup_mode.setup_mode)?),
+        Box::new(SshKeyImportScreen::new(
+            wtb,
+            alerts,
+            &res.setup_mode.key_import.pending,
+        )?),
         Box::new(OverTemperatureScreen::new(
             wtb,
             alerts,
@@ -227,5 +372,24 @@ pub(super) fn init(
         Box::new(LocatorScreen::new(wtb, alerts, locator)?),
         Box::new(UsbOverloadScreen::new(wtb, alerts, &res.usb_hub.overload)?),
         Box::new(PowerFailScreen::new(wtb, alerts, &res.dut_pwr.state)?),
+        Box::new(GpioConflictScreen::new(
+            wtb,
+            alerts,
+            &res.gpio_health.lines,
+        )?),
+        Box::new(DegradedScreen::new(wtb, alerts, &res.degraded.subsystems)?),
+        Box::new(HardwareMismatchScreen::new(
+            wtb,
+            alerts,
+            &res.hardware_consistency.power_locked,
+        )?),
+        Box::new(ConfirmActionScreen::new(
+            wtb,
+            alerts,
+            &res.confirmation.armed,
+        )?),
+        Box::new(ConflictScreen::new(wtb, alerts, &res.conflicts.pending)?),
+        Box::new(StateRecoveryScreen::new(wtb, alerts, &res.state_recovery)?),
+        Box::new(SelfTestScreen::new(wtb, alerts, &res.selftest.running)?),
     ])
 }
@@ -0,0 +1,270 @@
+// This file is part of tacd, the LXA TAC system daemon
+// Copyright (C) 2024 Pengutronix e.K.
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along
+// with this program; if not, write to the Free Software Foundation, Inc.,
+// 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+
+use async_std::sync::Arc;
+use async_trait::async_trait;
+use embedded_graphics::{
+    mono_font::MonoTextStyle,
+    pixelcolor::BinaryColor,
+    prelude::*,
+    text::{Alignment, Text},
+};
+
+use super::buttons::Source;
+use super::widgets::*;
+use super::{
+    row_anchor, ActivatableScreen, ActiveScreen, AlertList, AlertScreen, Alerter, Display,
+    InputEvent, Screen, Ui,
+};
+use crate::broker::Topic;
+use crate::dut_power::{OutputRequest, OutputState};
+use crate::selftest::SelfTestRequest;
+use crate::ui::locator::{Locator, LocatorSource};
+
+const SCREEN_TYPE: AlertScreen = AlertScreen::CommandPalette;
+
+/// Number of commands that fit on the screen at once.
+/// The list scrolls if there are more.
+const VISIBLE_ROWS: usize = 8;
+
+/// An action that can be performed from the command palette
+struct Command {
+    label: &'static str,
+    run: Box<dyn Fn() + Send + Sync>,
+}
+
+impl Command {
+    fn new(label: &'static str, run: impl Fn() + Send + Sync + 'static) -> Self {
+        Self {
+            label,
+            run: Box::new(run),
+        }
+    }
+}
+
+/// All commands that are offered, in the order they are listed on the screen
+///
+/// Commands added here show up in the palette without further changes.
+fn registry(ui: &Ui) -> Vec<Command> {
+    let mut commands = Vec::new();
+
+    {
+        let state = ui.res.dut_pwr.state.clone();
+        let request = ui.res.dut_pwr.lcd_request.clone();
+
+        commands.push(Command::new("Toggle DUT Power", move || {
+            let req = match state.try_get() {
+                Some(OutputState::On) => OutputRequest::Off,
+                _ => OutputRequest::On,
+            };
+
+            request.set(req);
+        }));
+    }
+
+    let ports = [
+        ("Toggle USB 1", &ui.res.usb_hub.port1),
+        ("Toggle USB 2", &ui.res.usb_hub.port2),
+        ("Toggle USB 3", &ui.res.usb_hub.port3),
+    ];
+
+    for (label, port) in ports {
+        let status = port.status.clone();
+        let request = port.lcd_request.clone();
+
+        commands.push(Command::new(label, move || {
+            request.set(!status.try_get().unwrap_or(false));
+        }));
+    }
+
+    {
+        let locator = ui.locator.clone();
+
+        commands.push(Command::new("Start Locator", move || {
+            locator.set(Locator::started(LocatorSource::Lcd, None))
+        }));
+    }
+
+    {
+        // The QR code screen shows the URL of the web interface,
+        // including the IP address.
+        let alerts = ui.alerts.clone();
+
+        commands.push(Command::new("Show IP", move || {
+            alerts.assert(AlertScreen::QrCode)
+        }));
+    }
+
+    {
+        // The self-test screen comes up on its own once the test runs
+        let run = ui.res.selftest.run.clone();
+
+        commands.push(Command::new("Run Self-Test", move || {
+            run.set(SelfTestRequest::default())
+        }));
+    }
+
+    {
+        let reboot_message = ui.reboot_message.clone();
+
+        commands.push(Command::new("Reboot", move || {
+            reboot_message.set(Some(
+                "Really reboot?\nLong press lower\nbutton to confirm.".to_string(),
+            ))
+        }));
+    }
+
+    commands
+}
+
+/// Get the label to show in a row of the screen given the highlighted command
+///
+/// The list is scrolled so that the highlighted command is always visible.
+fn row_label(labels: &[&str], highlighted: usize, row: usize) -> String {
+    let first = (highlighted + 1).saturating_sub(VISIBLE_ROWS);
+    let idx = first + row;
+
+    match labels.get(idx) {
+        Some(label) if idx == highlighted => format!("> {label}"),
+        Some(label) => format!("  {label}"),
+        None => String::new(),
+    }
+}
+
+pub struct CommandPaletteScreen;
+
+impl CommandPaletteScreen {
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+struct Active {
+    widgets: WidgetContainer,
+    commands: Vec<Command>,
+    highlighted: Arc<Topic<usize>>,
+    alerts: Arc<Topic<AlertList>>,
+}
+
+impl ActivatableScreen for CommandPaletteScreen {
+    fn my_type(&self) -> Screen {
+        Screen::Alert(SCREEN_TYPE)
+    }
+
+    fn activate(&mut self, ui: &Ui, display: Display) -> Box<dyn ActiveScreen> {
+        let ui_text_style: MonoTextStyle<BinaryColor> =
+            MonoTextStyle::new(&UI_TEXT_FONT, BinaryColor::On);
+
+        display.with_lock(|target| {
+            draw_button_legend(target, "Action", "Close");
+
+            Text::with_alignment(
+                "Commands",
+                Point::new(112, 25),
+                ui_text_style,
+                Alignment::Center,
+            )
+            .draw(target)
+            .unwrap();
+        });
+
+        let commands = registry(ui);
+        let labels: Arc<[&'static str]> = commands.iter().map(|c| c.label).collect();
+        let highlighted = Topic::anonymous(Some(0));
+
+        let mut widgets = WidgetContainer::new(display);
+
+        for row in 0..VISIBLE_ROWS.min(labels.len()) {
+            let labels = labels.clone();
+
+            widgets.push(|display| {
+                DynamicWidget::text(
+                    highlighted.clone(),
+                    display,
+                    row_anchor(row as u8),
+                    Box::new(move |highlighted: &usize| row_label(&labels, *highlighted, row)),
+                )
+            });
+        }
+
+        let alerts = ui.alerts.clone();
+
+        let active = Active {
+            widgets,
+            commands,
+            highlighted,
+            alerts,
+        };
+
+        Box::new(active)
+    }
+}
+
+#[async_trait]
+impl ActiveScreen for Active {
+    fn my_type(&self) -> Screen {
+        Screen::Alert(SCREEN_TYPE)
+    }
+
+    async fn deactivate(mut self: Box<Self>) -> Display {
+        self.widgets.destroy().await
+    }
+
+    fn input(&mut self, ev: InputEvent) {
+        let highlighted = self.highlighted.try_get().unwrap_or(0);
+
+        // Like on the system screen commands are only performed with
+        // Source::Local, as the list contains e.g. a reboot.
+
+        match ev {
+            InputEvent::NextScreen => self.alerts.deassert(SCREEN_TYPE),
+            InputEvent::ToggleAction(_) => self
+                .highlighted
+                .set((highlighted + 1) % self.commands.len()),
+            InputEvent::PerformAction(Source::Local) => {
+                // Close the palette first, so that screens the command
+                // brings up (e.g. the reboot confirmation) are visible.
+                self.alerts.deassert(SCREEN_TYPE);
+                (self.commands[highlighted].run)();
+            }
+            InputEvent::PerformAction(_) => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{row_label, VISIBLE_ROWS};
+
+    #[test]
+    fn scrolling() {
+        let labels: Vec<&str> = vec!["A", "B", "C", "D", "E", "F", "G", "H", "I", "J"];
+
+        println!("The list starts at the top");
+        assert_eq!(row_label(&labels, 0, 0), "> A");
+        assert_eq!(row_label(&labels, 0, 1), "  B");
+        assert_eq!(row_label(&labels, 0, VISIBLE_ROWS - 1), "  H");
+
+        println!("The list scrolls once the highlight leaves the screen");
+        assert_eq!(row_label(&labels, 8, 0), "  B");
+        assert_eq!(row_label(&labels, 8, VISIBLE_ROWS - 1), "> I");
+        assert_eq!(row_label(&labels, 9, VISIBLE_ROWS - 1), "> J");
+
+        println!("Rows below the end of a short list are empty");
+        assert_eq!(row_label(&labels[..3], 0, 3), "");
+    }
+}
@@ -0,0 +1,149 @@
+// This file is part of tacd, the LXA TAC system daemon
+// Copyright (C) 2024 Pengutronix e.K.
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along
+// with this program; if not, write to the Free Software Foundation, Inc.,
+// 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+
+use anyhow::Result;
+use async_std::prelude::*;
+use async_std::sync::Arc;
+use async_trait::async_trait;
+use embedded_graphics::{
+    mono_font::MonoTextStyle, pixelcolor::BinaryColor, prelude::*, text::Text,
+};
+
+use super::buttons::Source;
+use super::widgets::*;
+use super::{
+    row_anchor, ActivatableScreen, ActiveScreen, AlertList, AlertScreen, Alerter, Display,
+    InputEvent, Screen, Ui,
+};
+use crate::broker::Topic;
+use crate::confirmation::{ArmedAction, ConfirmableAction, Confirmation};
+use crate::watched_tasks::WatchedTasksBuilder;
+
+const SCREEN_TYPE: AlertScreen = AlertScreen::ConfirmAction;
+
+pub struct ConfirmActionScreen;
+
+struct Active {
+    widgets: WidgetContainer,
+    confirmation: Confirmation,
+}
+
+impl ConfirmActionScreen {
+    pub fn new(
+        wtb: &mut WatchedTasksBuilder,
+        alerts: &Arc<Topic<AlertList>>,
+        armed: &Arc<Topic<Option<ArmedAction>>>,
+    ) -> Result<Self> {
+        let (mut armed_events, _) = armed.clone().subscribe_unbounded();
+        let alerts = alerts.clone();
+
+        wtb.spawn_task("screen-confirm-action-activator", async move {
+            while let Some(armed) = armed_events.next().await {
+                if armed.is_some() {
+                    alerts.assert(SCREEN_TYPE);
+                } else {
+                    alerts.deassert(SCREEN_TYPE);
+                }
+            }
+
+            Ok(())
+        })?;
+
+        Ok(Self)
+    }
+}
+
+impl ActivatableScreen for ConfirmActionScreen {
+    fn my_type(&self) -> Screen {
+        Screen::Alert(SCREEN_TYPE)
+    }
+
+    fn activate(&mut self, ui: &Ui, display: Display) -> Box<dyn ActiveScreen> {
+        let ui_text_style: MonoTextStyle<BinaryColor> =
+            MonoTextStyle::new(&UI_TEXT_FONT, BinaryColor::On);
+
+        display.with_lock(|target| {
+            draw_button_legend(target, "Confirm", "-");
+
+            Text::new(
+                "Confirm Action",
+                row_anchor(0) - (row_anchor(1) - row_anchor(0)),
+                ui_text_style,
+            )
+            .draw(target)
+            .unwrap();
+
+            Text::new(
+                "Long press lower\nbutton to confirm,\nshort press to\ncancel.",
+                row_anchor(5),
+                ui_text_style,
+            )
+            .draw(target)
+            .unwrap();
+        });
+
+        let mut widgets = WidgetContainer::new(display);
+
+        widgets.push(|display| {
+            DynamicWidget::text(
+                ui.res.confirmation.armed.clone(),
+                display,
+                row_anchor(0),
+                Box::new(|armed: &Option<ArmedAction>| {
+                    let action = match armed.as_ref().map(|a| a.action) {
+                        Some(ConfirmableAction::Install) => "install an update",
+                        Some(ConfirmableAction::PowerOff) => "turn off the DUT",
+                        Some(ConfirmableAction::ChangeSetting) => {
+                            "change if actions\nneed confirmation"
+                        }
+                        None => return String::new(),
+                    };
+
+                    format!("A request to\n{action}\nwas received via\nthe API.")
+                }),
+            )
+        });
+
+        let confirmation = ui.res.confirmation.clone();
+
+        Box::new(Active {
+            widgets,
+            confirmation,
+        })
+    }
+}
+
+#[async_trait]
+impl ActiveScreen for Active {
+    fn my_type(&self) -> Screen {
+        Screen::Alert(SCREEN_TYPE)
+    }
+
+    async fn deactivate(mut self: Box<Self>) -> Display {
+        self.widgets.destroy().await
+    }
+
+    fn input(&mut self, ev: InputEvent) {
+        // Button presses simulated via the API do not count, as the point
+        // is that someone at the TAC agrees with the request.
+        match ev {
+            InputEvent::ToggleAction(Source::Local) => self.confirmation.answer(false),
+            InputEvent::PerformAction(Source::Local) => self.confirmation.answer(true),
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,153 @@
+// This file is part of tacd, the LXA TAC system daemon
+// Copyright (C) 2024 Pengutronix e.K.
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along
+// with this program; if not, write to the Free Software Foundation, Inc.,
+// 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+
+use anyhow::Result;
+use async_std::prelude::*;
+use async_std::sync::Arc;
+use async_trait::async_trait;
+use embedded_graphics::{
+    mono_font::MonoTextStyle, pixelcolor::BinaryColor, prelude::*, text::Text,
+};
+
+use super::buttons::Source;
+use super::widgets::*;
+use super::{
+    row_anchor, ActivatableScreen, ActiveScreen, AlertList, AlertScreen, Alerter, Display,
+    InputEvent, Screen, Ui,
+};
+use crate::broker::Topic;
+use crate::conflicts::{Conflict, ConflictGuard, RequestSource, SourcedRequest};
+use crate::watched_tasks::WatchedTasksBuilder;
+
+const SCREEN_TYPE: AlertScreen = AlertScreen::Conflict;
+
+pub struct ConflictScreen;
+
+struct Active {
+    widgets: WidgetContainer,
+    conflicts: ConflictGuard,
+}
+
+fn describe(req: &SourcedRequest) -> String {
+    let state = if req.on { "on" } else { "off" };
+    let source = match req.source {
+        RequestSource::Lcd => "LCD",
+        RequestSource::Api => "API",
+    };
+
+    format!("{state} via {source}")
+}
+
+impl ConflictScreen {
+    pub fn new(
+        wtb: &mut WatchedTasksBuilder,
+        alerts: &Arc<Topic<AlertList>>,
+        pending: &Arc<Topic<Option<Conflict>>>,
+    ) -> Result<Self> {
+        let (mut pending_events, _) = pending.clone().subscribe_unbounded();
+        let alerts = alerts.clone();
+
+        wtb.spawn_task("screen-conflict-activator", async move {
+            while let Some(pending) = pending_events.next().await {
+                if pending.is_some() {
+                    alerts.assert(SCREEN_TYPE);
+                } else {
+                    alerts.deassert(SCREEN_TYPE);
+                }
+            }
+
+            Ok(())
+        })?;
+
+        Ok(Self)
+    }
+}
+
+impl ActivatableScreen for ConflictScreen {
+    fn my_type(&self) -> Screen {
+        Screen::Alert(SCREEN_TYPE)
+    }
+
+    fn activate(&mut self, ui: &Ui, display: Display) -> Box<dyn ActiveScreen> {
+        let ui_text_style: MonoTextStyle<BinaryColor> =
+            MonoTextStyle::new(&UI_TEXT_FONT, BinaryColor::On);
+
+        display.with_lock(|target| {
+            draw_button_legend(target, "Confirm", "-");
+
+            Text::new(
+                "Conflicting Requests",
+                row_anchor(0) - (row_anchor(1) - row_anchor(0)),
+                ui_text_style,
+            )
+            .draw(target)
+            .unwrap();
+
+            Text::new(
+                "Long press lower\nbutton to confirm,\nshort press to\ncancel.",
+                row_anchor(5),
+                ui_text_style,
+            )
+            .draw(target)
+            .unwrap();
+        });
+
+        let mut widgets = WidgetContainer::new(display);
+
+        widgets.push(|display| {
+            DynamicWidget::text(
+                ui.res.conflicts.pending.clone(),
+                display,
+                row_anchor(0),
+                Box::new(|pending: &Option<Conflict>| match pending {
+                    Some(conflict) => format!(
+                        "{} was turned\n{},\nnow requested\n{}.",
+                        conflict.output,
+                        describe(&conflict.applied),
+                        describe(&conflict.held),
+                    ),
+                    None => String::new(),
+                }),
+            )
+        });
+
+        let conflicts = ui.res.conflicts.clone();
+
+        Box::new(Active { widgets, conflicts })
+    }
+}
+
+#[async_trait]
+impl ActiveScreen for Active {
+    fn my_type(&self) -> Screen {
+        Screen::Alert(SCREEN_TYPE)
+    }
+
+    async fn deactivate(mut self: Box<Self>) -> Display {
+        self.widgets.destroy().await
+    }
+
+    fn input(&mut self, ev: InputEvent) {
+        // Button presses simulated via the API do not count, API users
+        // answer via /v1/tac/conflicts/answer instead.
+        match ev {
+            InputEvent::ToggleAction(Source::Local) => self.conflicts.answer(false),
+            InputEvent::PerformAction(Source::Local) => self.conflicts.answer(true),
+            _ => {}
+        }
+    }
+}
@@ -19,7 +19,7 @@ use std::fmt::Write;
 
 use async_std::sync::Arc;
 use async_trait::async_trait;
-use chrono::DateTime;
+use chrono::{DateTime, Local, Utc};
 use embedded_graphics::{
     mono_font::{ascii::FONT_6X10, MonoTextStyle},
     pixelcolor::BinaryColor,
@@ -36,21 +36,34 @@ use crate::{broker::Topic, led::BlinkPattern, system::HardwareGeneration};
 
 const SCREEN_TYPE: AlertScreen = AlertScreen::Diagnostics;
 
+/// On this page the lower button toggles the frame time overlay
+/// instead of the LEDs
+const FRAME_TIMES_PAGE: usize = 2;
+
 pub struct DiagnosticsScreen;
 
 struct Active {
     display: Option<Display>,
     alerts: Arc<Topic<AlertList>>,
+    pages: [String; 3],
+    page: usize,
     led_cycle_state: u8,
+    frame_overlay: Arc<Topic<bool>>,
     leds: [Arc<Topic<BlinkPattern>>; 5],
     status_led_color: Arc<Topic<(f32, f32, f32)>>,
     backlight_brightness: Arc<Topic<f32>>,
 }
 
+/// Show timestamps in the timezone configured on the TAC
+fn local_rfc3339(ts: DateTime<Utc>) -> String {
+    ts.with_timezone(&Local).to_rfc3339()
+}
+
 fn diagnostic_text(ui: &Ui) -> Result<String, std::fmt::Error> {
     let mut text = String::new();
 
     writeln!(&mut text, "Diagnostics | Not self-updating!")?;
+    writeln!(&mut text, "Short press upper button to switch page.")?;
     writeln!(&mut text, "Short press lower button to toggle LEDs.")?;
     writeln!(&mut text, "Long press lower button to exit.")?;
     writeln!(&mut text)?;
@@ -79,6 +92,10 @@ fn diagnostic_text(ui: &Ui) -> Result<String, std::fmt::Error> {
 
     writeln!(&mut text)?;
 
+    if let Some(timezone) = ui.res.timedate.timezone.try_get() {
+        writeln!(&mut text, "tz: {timezone}")?;
+    }
+
     if let Some(bridge_interface) = ui.res.network.bridge_interface.try_get() {
         write!(&mut text, "br: ")?;
 
@@ -89,6 +106,16 @@ fn diagnostic_text(ui: &Ui) -> Result<String, std::fmt::Error> {
         writeln!(&mut text)?;
     }
 
+    if let Some(bridge_interface) = ui.res.network.bridge_interface_ipv6.try_get() {
+        write!(&mut text, "br6: ")?;
+
+        for ip in bridge_interface {
+            write!(&mut text, "{ip}, ")?;
+        }
+
+        writeln!(&mut text)?;
+    }
+
     let interfaces = [
         ("dut", &ui.res.network.dut_interface),
         ("uplink", &ui.res.network.uplink_interface),
@@ -104,15 +131,24 @@ fn diagnostic_text(ui: &Ui) -> Result<String, std::fmt::Error> {
     }
 
     writeln!(&mut text)?;
+
+    if let Some(profile) = ui.res.firewall.profile.try_get() {
+        let failed = match ui.res.firewall.error.try_get().flatten() {
+            Some(_) => " (last change failed)",
+            None => "",
+        };
+
+        writeln!(&mut text, "fw: {profile:?}{failed}")?;
+    }
     writeln!(&mut text)?;
 
     if let Some(barebox) = ui.res.system.barebox.try_get() {
         let baseboard_release = barebox.baseboard_release.trim_start_matches("lxatac-");
         let powerboard_release = barebox.powerboard_release.trim_start_matches("lxatac-");
         let baseboard_timestamp = DateTime::from_timestamp(barebox.baseboard_timestamp as i64, 0)
-            .map_or_else(|| "???".to_string(), |ts| ts.to_rfc3339());
+            .map_or_else(|| "???".to_string(), local_rfc3339);
         let powerboard_timestamp = DateTime::from_timestamp(barebox.powerboard_timestamp as i64, 0)
-            .map_or_else(|| "???".to_string(), |ts| ts.to_rfc3339());
+            .map_or_else(|| "???".to_string(), local_rfc3339);
         let baseboard_featureset = barebox.baseboard_featureset.join(",");
         let powerboard_featureset = barebox.powerboard_featureset.join(",");
 
@@ -180,6 +216,74 @@ fn diagnostic_text(ui: &Ui) -> Result<String, std::fmt::Error> {
     Ok(text)
 }
 
+fn led_history_text(ui: &Ui) -> Result<String, std::fmt::Error> {
+    let mut text = String::new();
+
+    writeln!(&mut text, "LED history | Not self-updating!")?;
+    writeln!(&mut text, "Short press upper button to switch page.")?;
+    writeln!(&mut text)?;
+
+    for (name, history) in &ui.res.led.history {
+        // There is not enough space on the screen to show the complete
+        // history for all LEDs. Only show the most recent change.
+        // The full history is available via the API.
+        match history
+            .try_get()
+            .and_then(|records| records.last().cloned())
+        {
+            Some(record) => {
+                let ts = DateTime::<Local>::from(record.ts.in_system_time()).format("%H:%M:%S");
+
+                writeln!(&mut text, "{name} {ts} {}", record.source)?;
+                writeln!(&mut text, "  {}", record.pattern.describe())?;
+            }
+            None => writeln!(&mut text, "{name} -")?,
+        }
+    }
+
+    Ok(text)
+}
+
+fn frame_times_text(ui: &Ui) -> Result<String, std::fmt::Error> {
+    let mut text = String::new();
+
+    writeln!(&mut text, "Frame times | Not self-updating!")?;
+    writeln!(&mut text, "Short press upper button to switch page.")?;
+    writeln!(&mut text, "Short press lower button to toggle the")?;
+    writeln!(&mut text, "frame time overlay.")?;
+    writeln!(&mut text)?;
+
+    if let Some(times) = ui.res.frame_stats.times.try_get() {
+        writeln!(
+            &mut text,
+            "frames: {} ({} in the last s)",
+            times.frames, times.fps
+        )?;
+        writeln!(&mut text, "p50: {:.1} ms", times.p50_ms)?;
+        writeln!(&mut text, "p99: {:.1} ms", times.p99_ms)?;
+        writeln!(&mut text, "max: {:.1} ms", times.max_ms)?;
+    }
+
+    Ok(text)
+}
+
+fn draw_page(display: &Display, text: &str) {
+    let ui_text_style: MonoTextStyle<BinaryColor> = MonoTextStyle::new(&FONT_6X10, BinaryColor::On);
+
+    display.clear();
+
+    display.with_lock(|target| {
+        Rectangle::with_corners(Point::new(0, 0), Point::new(239, 239))
+            .into_styled(PrimitiveStyle::with_stroke(BinaryColor::On, 1))
+            .draw(target)
+            .unwrap();
+
+        Text::with_baseline(text, Point::new(4, 2), ui_text_style, Baseline::Top)
+            .draw(target)
+            .unwrap();
+    });
+}
+
 impl DiagnosticsScreen {
     pub fn new() -> Self {
         Self
@@ -192,21 +296,15 @@ impl ActivatableScreen for DiagnosticsScreen {
     }
 
     fn activate(&mut self, ui: &Ui, display: Display) -> Box<dyn ActiveScreen> {
-        let ui_text_style: MonoTextStyle<BinaryColor> =
-            MonoTextStyle::new(&FONT_6X10, BinaryColor::On);
-
-        let text = diagnostic_text(ui).unwrap_or_else(|_| "Failed to format text".into());
-
-        display.with_lock(|target| {
-            Rectangle::with_corners(Point::new(0, 0), Point::new(239, 239))
-                .into_styled(PrimitiveStyle::with_stroke(BinaryColor::On, 1))
-                .draw(target)
-                .unwrap();
+        // Render the LED history before we set the status LED below,
+        // so that our own change does not show up in it.
+        let pages = [
+            diagnostic_text(ui).unwrap_or_else(|_| "Failed to format text".into()),
+            led_history_text(ui).unwrap_or_else(|_| "Failed to format text".into()),
+            frame_times_text(ui).unwrap_or_else(|_| "Failed to format text".into()),
+        ];
 
-            Text::with_baseline(&text, Point::new(4, 2), ui_text_style, Baseline::Top)
-                .draw(target)
-                .unwrap();
-        });
+        draw_page(&display, &pages[0]);
 
         let leds = [
             ui.res.led.out_0.clone(),
@@ -227,7 +325,10 @@ impl ActivatableScreen for DiagnosticsScreen {
         let active = Active {
             display: Some(display),
             alerts: ui.alerts.clone(),
+            pages,
+            page: 0,
             led_cycle_state: 0,
+            frame_overlay: ui.res.frame_stats.overlay.clone(),
             leds,
             status_led_color,
             backlight_brightness,
@@ -250,7 +351,16 @@ impl ActiveScreen for Active {
 
     fn input(&mut self, ev: InputEvent) {
         match ev {
-            InputEvent::NextScreen => {}
+            InputEvent::NextScreen => {
+                self.page = (self.page + 1) % self.pages.len();
+
+                if let Some(display) = &self.display {
+                    draw_page(display, &self.pages[self.page]);
+                }
+            }
+            InputEvent::ToggleAction(_) if self.page == FRAME_TIMES_PAGE => {
+                self.frame_overlay.toggle(false);
+            }
             InputEvent::ToggleAction(_) => {
                 self.led_cycle_state = self.led_cycle_state.wrapping_add(1);
 
@@ -61,18 +61,8 @@ impl ActivatableScreen for DigOutScreen {
 
     fn activate(&mut self, ui: &Ui, display: Display) -> Box<dyn ActiveScreen> {
         let ports = [
-            (
-                0,
-                "OUT 0:",
-                &ui.res.dig_io.out_0,
-                &ui.res.adc.out0_volt.topic,
-            ),
-            (
-                1,
-                "OUT 1:",
-                &ui.res.dig_io.out_1,
-                &ui.res.adc.out1_volt.topic,
-            ),
+            (0, "OUT 0:", &ui.res.dig_io.out_0, &ui.res.adc.out0_volt),
+            (1, "OUT 1:", &ui.res.dig_io.out_1, &ui.res.adc.out1_volt),
         ];
 
         let ui_text_style: MonoTextStyle<BinaryColor> =
@@ -128,17 +118,19 @@ impl ActivatableScreen for DigOutScreen {
             });
 
             widgets.push(|display| {
+                let fmt = voltage.lcd_formatter();
+
                 DynamicWidget::text(
-                    voltage.clone(),
+                    voltage.topic.clone(),
                     display,
                     anchor_voltage,
-                    Box::new(|meas: &Measurement| format!("  Volt: {:>4.1}V", meas.value)),
+                    Box::new(move |meas: &Measurement| format!("  Volt:{:>7}", fmt(meas.value))),
                 )
             });
 
             widgets.push(|display| {
                 DynamicWidget::bar(
-                    voltage.clone(),
+                    voltage.topic.clone(),
                     display,
                     anchor_bar,
                     WIDTH_BAR,
@@ -0,0 +1,138 @@
+// This file is part of tacd, the LXA TAC system daemon
+// Copyright (C) 2024 Pengutronix e.K.
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along
+// with this program; if not, write to the Free Software Foundation, Inc.,
+// 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+
+use std::collections::BTreeMap;
+
+use anyhow::Result;
+use async_std::prelude::*;
+use async_std::sync::Arc;
+use async_trait::async_trait;
+use embedded_graphics::{
+    mono_font::MonoTextStyle, pixelcolor::BinaryColor, prelude::*, text::Text,
+};
+
+use super::widgets::*;
+use super::{
+    row_anchor, ActivatableScreen, ActiveScreen, AlertList, AlertScreen, Alerter, Display,
+    InputEvent, Screen, Ui,
+};
+use crate::broker::Topic;
+use crate::digital_io::LineHealth;
+use crate::watched_tasks::WatchedTasksBuilder;
+
+const SCREEN_TYPE: AlertScreen = AlertScreen::GpioConflict;
+
+pub struct GpioConflictScreen;
+
+struct Active {
+    widgets: WidgetContainer,
+}
+
+impl GpioConflictScreen {
+    pub fn new(
+        wtb: &mut WatchedTasksBuilder,
+        alerts: &Arc<Topic<AlertList>>,
+        gpio_lines: &Arc<Topic<BTreeMap<String, LineHealth>>>,
+    ) -> Result<Self> {
+        let (mut gpio_lines_events, _) = gpio_lines.clone().subscribe_unbounded();
+        let alerts = alerts.clone();
+
+        wtb.spawn_task("screen-gpio-conflict-activator", async move {
+            while let Some(lines) = gpio_lines_events.next().await {
+                if lines.values().any(|health| health != &LineHealth::Ok) {
+                    alerts.assert(SCREEN_TYPE);
+                } else {
+                    alerts.deassert(SCREEN_TYPE);
+                }
+            }
+
+            Ok(())
+        })?;
+
+        Ok(Self)
+    }
+}
+
+impl ActivatableScreen for GpioConflictScreen {
+    fn my_type(&self) -> Screen {
+        Screen::Alert(SCREEN_TYPE)
+    }
+
+    fn activate(&mut self, ui: &Ui, display: Display) -> Box<dyn ActiveScreen> {
+        let ui_text_style: MonoTextStyle<BinaryColor> =
+            MonoTextStyle::new(&UI_TEXT_FONT, BinaryColor::On);
+
+        display.with_lock(|target| {
+            // This screen can only be left by resolving the underlying issue
+            draw_button_legend(target, "-", "-");
+
+            Text::new(
+                "GPIO Conflict",
+                row_anchor(0) - (row_anchor(1) - row_anchor(0)),
+                ui_text_style,
+            )
+            .draw(target)
+            .unwrap();
+
+            Text::new(
+                "Another process uses\nGPIOs needed by tacd.\nRetrying.",
+                row_anchor(1),
+                ui_text_style,
+            )
+            .draw(target)
+            .unwrap();
+        });
+
+        let mut widgets = WidgetContainer::new(display);
+
+        widgets.push(|display| {
+            DynamicWidget::text(
+                ui.res.gpio_health.lines.clone(),
+                display,
+                row_anchor(5),
+                Box::new(|lines: &BTreeMap<String, LineHealth>| {
+                    lines
+                        .iter()
+                        .filter_map(|(name, health)| match health {
+                            LineHealth::Ok => None,
+                            LineHealth::Conflict { consumer, .. } => {
+                                Some(format!("{name}: {}", consumer.as_deref().unwrap_or("?")))
+                            }
+                        })
+                        .take(4)
+                        .collect::<Vec<_>>()
+                        .join("\n")
+                }),
+            )
+        });
+
+        Box::new(Active { widgets })
+    }
+}
+
+#[async_trait]
+impl ActiveScreen for Active {
+    fn my_type(&self) -> Screen {
+        Screen::Alert(SCREEN_TYPE)
+    }
+
+    async fn deactivate(mut self: Box<Self>) -> Display {
+        self.widgets.destroy().await
+    }
+
+    fn input(&mut self, _ev: InputEvent) {}
+}
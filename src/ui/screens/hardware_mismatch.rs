@@ -0,0 +1,143 @@
+// This file is part of tacd, the LXA TAC system daemon
+// Copyright (C) 2024 Pengutronix e.K.
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along
+// with this program; if not, write to the Free Software Foundation, Inc.,
+// 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+
+use anyhow::Result;
+use async_std::prelude::*;
+use async_std::sync::Arc;
+use async_trait::async_trait;
+use embedded_graphics::{
+    mono_font::MonoTextStyle, pixelcolor::BinaryColor, prelude::*, text::Text,
+};
+
+use super::widgets::*;
+use super::{
+    row_anchor, ActivatableScreen, ActiveScreen, AlertList, AlertScreen, Alerter, Display,
+    InputEvent, Screen, Ui,
+};
+use crate::broker::Topic;
+use crate::watched_tasks::WatchedTasksBuilder;
+
+const SCREEN_TYPE: AlertScreen = AlertScreen::HardwareMismatch;
+
+pub struct HardwareMismatchScreen;
+
+struct Active {
+    widgets: WidgetContainer,
+    acknowledged: Arc<Topic<bool>>,
+}
+
+impl HardwareMismatchScreen {
+    pub fn new(
+        wtb: &mut WatchedTasksBuilder,
+        alerts: &Arc<Topic<AlertList>>,
+        power_locked: &Arc<Topic<bool>>,
+    ) -> Result<Self> {
+        let (mut power_locked_events, _) = power_locked.clone().subscribe_unbounded();
+        let alerts = alerts.clone();
+
+        wtb.spawn_task("screen-hardware-mismatch-activator", async move {
+            while let Some(locked) = power_locked_events.next().await {
+                if locked {
+                    alerts.assert(SCREEN_TYPE);
+                } else {
+                    alerts.deassert(SCREEN_TYPE);
+                }
+            }
+
+            Ok(())
+        })?;
+
+        Ok(Self)
+    }
+}
+
+impl ActivatableScreen for HardwareMismatchScreen {
+    fn my_type(&self) -> Screen {
+        Screen::Alert(SCREEN_TYPE)
+    }
+
+    fn activate(&mut self, ui: &Ui, display: Display) -> Box<dyn ActiveScreen> {
+        let ui_text_style: MonoTextStyle<BinaryColor> =
+            MonoTextStyle::new(&UI_TEXT_FONT, BinaryColor::On);
+
+        display.with_lock(|target| {
+            draw_button_legend(target, "Unlock", "-");
+
+            Text::new(
+                "Hardware Mismatch",
+                row_anchor(0) - (row_anchor(1) - row_anchor(0)),
+                ui_text_style,
+            )
+            .draw(target)
+            .unwrap();
+
+            Text::new(
+                "The calibration data\ndoes not match the\nhardware. DUT power\nis locked.",
+                row_anchor(0),
+                ui_text_style,
+            )
+            .draw(target)
+            .unwrap();
+
+            Text::new(
+                "Long press lower\nbutton to unlock\nanyway.",
+                row_anchor(6),
+                ui_text_style,
+            )
+            .draw(target)
+            .unwrap();
+        });
+
+        let mut widgets = WidgetContainer::new(display);
+
+        widgets.push(|display| {
+            DynamicWidget::text(
+                ui.res.hardware_consistency.mismatches.clone(),
+                display,
+                row_anchor(4),
+                Box::new(|mismatches: &Vec<String>| format!("Problems: {}", mismatches.len())),
+            )
+        });
+
+        let acknowledged = ui.res.hardware_consistency.acknowledged.clone();
+
+        Box::new(Active {
+            widgets,
+            acknowledged,
+        })
+    }
+}
+
+#[async_trait]
+impl ActiveScreen for Active {
+    fn my_type(&self) -> Screen {
+        Screen::Alert(SCREEN_TYPE)
+    }
+
+    async fn deactivate(mut self: Box<Self>) -> Display {
+        self.widgets.destroy().await
+    }
+
+    fn input(&mut self, ev: InputEvent) {
+        // Only a long press unlocks the DUT power, so that the alert can not
+        // be dismissed by accident.
+        match ev {
+            InputEvent::NextScreen | InputEvent::ToggleAction(_) => {}
+            InputEvent::PerformAction(_) => self.acknowledged.set(true),
+        }
+    }
+}
@@ -102,20 +102,24 @@ impl ActivatableScreen for IoBusHealthScreen {
         let mut widgets = WidgetContainer::new(display);
 
         widgets.push(|display| {
+            let fmt = ui.res.adc.iobus_volt.lcd_formatter();
+
             DynamicWidget::text(
                 ui.res.adc.iobus_volt.topic.clone(),
                 display,
                 row_anchor(5),
-                Box::new(|meas: &Measurement| format!("  {:-6.2}V /  12V", meas.value)),
+                Box::new(move |meas: &Measurement| format!("  {:>8} /  12V", fmt(meas.value))),
             )
         });
 
         widgets.push(|display| {
+            let fmt = ui.res.adc.iobus_curr.lcd_formatter();
+
             DynamicWidget::text(
                 ui.res.adc.iobus_curr.topic.clone(),
                 display,
                 row_anchor(6),
-                Box::new(|meas: &Measurement| format!("  {:-6.2}A / 0.2A", meas.value)),
+                Box::new(move |meas: &Measurement| format!("  {:>8} / 0.2A", fmt(meas.value))),
             )
         });
 
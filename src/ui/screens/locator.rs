@@ -35,14 +35,42 @@ use super::{
     Ui,
 };
 use crate::broker::Topic;
+use crate::ui::locator::Locator;
 use crate::watched_tasks::WatchedTasksBuilder;
 
 const SCREEN_TYPE: AlertScreen = AlertScreen::Locator;
 
+/// Characters per line that fit between the screen edge and button legend
+const LINE_LEN: usize = 20;
+
+/// Lines available for the source and the message each
+const MAX_LINES: usize = 2;
+
+/// Break text into lines that fit on the screen at word boundaries and drop
+/// what does not fit
+fn wrap(text: &str) -> String {
+    let mut lines: Vec<String> = Vec::new();
+
+    for word in text.split_whitespace() {
+        let word: String = word.chars().take(LINE_LEN).collect();
+
+        match lines.last_mut() {
+            Some(line) if line.chars().count() + 1 + word.chars().count() <= LINE_LEN => {
+                line.push(' ');
+                line.push_str(&word);
+            }
+            _ => lines.push(word),
+        }
+    }
+
+    lines.truncate(MAX_LINES);
+    lines.join("\n")
+}
+
 pub struct LocatorScreen;
 
 struct Active {
-    locator: Arc<Topic<bool>>,
+    locator: Arc<Topic<Locator>>,
     widgets: WidgetContainer,
 }
 
@@ -50,14 +78,14 @@ impl LocatorScreen {
     pub fn new(
         wtb: &mut WatchedTasksBuilder,
         alerts: &Arc<Topic<AlertList>>,
-        locator: &Arc<Topic<bool>>,
+        locator: &Arc<Topic<Locator>>,
     ) -> Result<Self> {
         let (mut locator_events, _) = locator.clone().subscribe_unbounded();
         let alerts = alerts.clone();
 
         wtb.spawn_task("screen-locator-activator", async move {
             while let Some(locator) = locator_events.next().await {
-                if locator {
+                if locator.active {
                     alerts.assert(SCREEN_TYPE);
                 } else {
                     alerts.deassert(SCREEN_TYPE);
@@ -85,7 +113,7 @@ impl ActivatableScreen for LocatorScreen {
 
             Text::with_alignment(
                 "Locating this TAC",
-                Point::new(120, 80),
+                Point::new(120, 45),
                 ui_text_style,
                 Alignment::Center,
             )
@@ -94,7 +122,7 @@ impl ActivatableScreen for LocatorScreen {
 
             Text::with_alignment(
                 "> Found it!",
-                Point::new(120, 200),
+                Point::new(120, 215),
                 ui_text_style,
                 Alignment::Center,
             )
@@ -108,11 +136,36 @@ impl ActivatableScreen for LocatorScreen {
             DynamicWidget::text_center(
                 ui.res.hostname.hostname.clone(),
                 display,
-                Point::new(120, 130),
+                Point::new(120, 85),
                 Box::new(|hostname| hostname.clone()),
             )
         });
 
+        // Tell the people near the TAC who is looking for it and why
+        widgets.push(|display| {
+            DynamicWidget::text_center(
+                ui.locator.clone(),
+                display,
+                Point::new(120, 120),
+                Box::new(|locator| {
+                    locator
+                        .source
+                        .as_ref()
+                        .map(|source| wrap(&source.describe()))
+                        .unwrap_or_default()
+                }),
+            )
+        });
+
+        widgets.push(|display| {
+            DynamicWidget::text_center(
+                ui.locator.clone(),
+                display,
+                Point::new(120, 170),
+                Box::new(|locator| locator.message.as_deref().map(wrap).unwrap_or_default()),
+            )
+        });
+
         let start = Instant::now();
 
         widgets.push(|display| {
@@ -124,7 +177,7 @@ impl ActivatableScreen for LocatorScreen {
                     let on = (now.duration_since(start).as_millis() / 500) % 2 == 0;
 
                     if on {
-                        let line = Line::new(Point::new(40, 135), Point::new(200, 135))
+                        let line = Line::new(Point::new(40, 90), Point::new(200, 90))
                             .into_styled(PrimitiveStyle::with_stroke(BinaryColor::On, 2));
 
                         line.draw(target).unwrap();
@@ -160,8 +213,24 @@ impl ActiveScreen for Active {
             InputEvent::NextScreen => {}
             InputEvent::ToggleAction(_) => {}
             InputEvent::PerformAction(_) => {
-                self.locator.set(false);
+                self.locator.set(Locator::stopped());
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::wrap;
+
+    #[test]
+    fn wrapping() {
+        assert_eq!(wrap("Started from 10.0.0.1"), "Started from\n10.0.0.1");
+        assert_eq!(wrap("short"), "short");
+        assert_eq!(
+            wrap("Please replace the SD card in slot 3 and reboot"),
+            "Please replace the\nSD card in slot 3"
+        );
+        assert_eq!(wrap(&"a".repeat(30)), "a".repeat(20));
+    }
+}
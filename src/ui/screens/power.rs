@@ -25,7 +25,7 @@ use super::{
     Screen, Ui,
 };
 use crate::broker::Topic;
-use crate::dut_power::{OutputRequest, OutputState};
+use crate::dut_power::{ExternalVoltage, MaintenanceOverride, OutputRequest, OutputState};
 use crate::measurement::Measurement;
 
 const SCREEN_TYPE: NormalScreen = NormalScreen::DutPower;
@@ -61,11 +61,13 @@ impl ActivatableScreen for PowerScreen {
         let mut widgets = WidgetContainer::new(display);
 
         widgets.push(|display| {
+            let fmt = ui.res.adc.pwr_volt.lcd_formatter();
+
             DynamicWidget::text(
                 ui.res.adc.pwr_volt.topic.clone(),
                 display,
                 row_anchor(0),
-                Box::new(|meas: &Measurement| format!("V: {:-6.3}V", meas.value)),
+                Box::new(move |meas: &Measurement| format!("V: {:>8}", fmt(meas.value))),
             )
         });
 
@@ -81,11 +83,13 @@ impl ActivatableScreen for PowerScreen {
         });
 
         widgets.push(|display| {
+            let fmt = ui.res.adc.pwr_curr.lcd_formatter();
+
             DynamicWidget::text(
                 ui.res.adc.pwr_curr.topic.clone(),
                 display,
                 row_anchor(1),
-                Box::new(|meas: &Measurement| format!("I: {:-6.3}A", meas.value)),
+                Box::new(move |meas: &Measurement| format!("I: {:>8}", fmt(meas.value))),
             )
         });
 
@@ -100,6 +104,22 @@ impl ActivatableScreen for PowerScreen {
             )
         });
 
+        widgets.push(|display| {
+            let fmt = ui.res.adc.pwr_volt.lcd_formatter();
+
+            // Warn about voltages that are applied to the output while it is
+            // off, so that miswired setups get noticed before switching on.
+            DynamicWidget::text(
+                ui.res.dut_pwr.external_voltage.clone(),
+                display,
+                row_anchor(2),
+                Box::new(move |ext: &Option<ExternalVoltage>| match ext {
+                    Some(ext) => format!("! Ext: {:>8}", fmt(ext.voltage)),
+                    None => String::new(),
+                }),
+            )
+        });
+
         widgets.push(|display| {
             DynamicWidget::text(
                 ui.res.dut_pwr.state.clone(),
@@ -114,6 +134,20 @@ impl ActivatableScreen for PowerScreen {
                     OutputState::OverCurrent => "> Ov. Curr.".into(),
                     OutputState::OverVoltage => "> Ov. Volt.".into(),
                     OutputState::RealtimeViolation => "> Rt Err.".into(),
+                    OutputState::Probing => "> Probing".into(),
+                }),
+            )
+        });
+
+        widgets.push(|display| {
+            // The protections are relaxed, which should not go unnoticed
+            DynamicWidget::text(
+                ui.res.dut_pwr.maintenance_override.clone(),
+                display,
+                row_anchor(4),
+                Box::new(|ov: &Option<MaintenanceOverride>| match ov {
+                    Some(ov) => format!("! Override {}A", ov.current_limit),
+                    None => String::new(),
                 }),
             )
         });
@@ -126,7 +160,7 @@ impl ActivatableScreen for PowerScreen {
                 Box::new(|state: &OutputState| match state {
                     OutputState::On => IndicatorState::On,
                     OutputState::Off | OutputState::OffFloating => IndicatorState::Off,
-                    OutputState::Changing => IndicatorState::Unknown,
+                    OutputState::Changing | OutputState::Probing => IndicatorState::Unknown,
                     _ => IndicatorState::Error,
                 }),
             )
@@ -148,7 +182,7 @@ impl ActivatableScreen for PowerScreen {
         });
 
         let power_state = ui.res.dut_pwr.state.clone();
-        let power_request = ui.res.dut_pwr.request.clone();
+        let power_request = ui.res.dut_pwr.lcd_request.clone();
 
         let active = Active {
             widgets,
@@ -71,9 +71,10 @@ impl PowerFailScreen {
         wtb.spawn_task("screen-power-fail-activator", async move {
             while let Some(state) = out_state_events.next().await {
                 match state {
-                    OutputState::On | OutputState::Off | OutputState::OffFloating => {
-                        alerts.deassert(SCREEN_TYPE)
-                    }
+                    OutputState::On
+                    | OutputState::Off
+                    | OutputState::OffFloating
+                    | OutputState::Probing => alerts.deassert(SCREEN_TYPE),
                     OutputState::InvertedPolarity
                     | OutputState::OverCurrent
                     | OutputState::OverVoltage
@@ -119,9 +120,10 @@ impl ActivatableScreen for PowerFailScreen {
                 row_anchor(2),
                 Box::new(|state: &OutputState| {
                     let msg = match state {
-                        OutputState::On | OutputState::Off | OutputState::OffFloating => {
-                            "The error was resolved"
-                        }
+                        OutputState::On
+                        | OutputState::Off
+                        | OutputState::OffFloating
+                        | OutputState::Probing => "The error was resolved",
                         OutputState::InvertedPolarity => {
                             "Output disabled due\nto inverted polarity."
                         }
@@ -160,7 +162,7 @@ impl ActivatableScreen for PowerFailScreen {
             )
         });
 
-        let request = ui.res.dut_pwr.request.clone();
+        let request = ui.res.dut_pwr.lcd_request.clone();
 
         Box::new(Active {
             widgets,
@@ -0,0 +1,129 @@
+// This file is part of tacd, the LXA TAC system daemon
+// Copyright (C) 2024 Pengutronix e.K.
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along
+// with this program; if not, write to the Free Software Foundation, Inc.,
+// 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+
+use async_std::sync::Arc;
+use async_trait::async_trait;
+use embedded_graphics::{
+    mono_font::MonoTextStyle,
+    pixelcolor::BinaryColor,
+    prelude::*,
+    text::{Alignment, Text},
+};
+
+use super::setup::ConnectivityWatcher;
+use super::widgets::*;
+use super::{
+    ActivatableScreen, ActiveScreen, AlertList, AlertScreen, Alerter, Display, InputEvent, Screen,
+    Ui,
+};
+use crate::broker::Topic;
+
+const SCREEN_TYPE: AlertScreen = AlertScreen::QrCode;
+
+pub struct QrCodeScreen;
+
+impl QrCodeScreen {
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+struct Active {
+    widgets: WidgetContainer,
+    connectivity: ConnectivityWatcher,
+    alerts: Arc<Topic<AlertList>>,
+}
+
+impl ActivatableScreen for QrCodeScreen {
+    fn my_type(&self) -> Screen {
+        Screen::Alert(SCREEN_TYPE)
+    }
+
+    fn activate(&mut self, ui: &Ui, display: Display) -> Box<dyn ActiveScreen> {
+        let ui_text_style: MonoTextStyle<BinaryColor> =
+            MonoTextStyle::new(&UI_TEXT_FONT, BinaryColor::On);
+
+        display.with_lock(|target| {
+            draw_button_legend(target, "Close", "-");
+
+            Text::with_alignment(
+                "Web Interface",
+                Point::new(112, 25),
+                ui_text_style,
+                Alignment::Center,
+            )
+            .draw(target)
+            .unwrap();
+        });
+
+        let connectivity = ConnectivityWatcher::new(ui);
+
+        let mut widgets = WidgetContainer::new(display);
+
+        widgets.push(|display| {
+            DynamicWidget::qr_code(
+                connectivity.topic.clone(),
+                display,
+                Point::new(112, 115),
+                150,
+                Box::new(|connectivity| connectivity.url().unwrap_or_default()),
+            )
+        });
+
+        widgets.push(|display| {
+            DynamicWidget::text_center(
+                connectivity.topic.clone(),
+                display,
+                Point::new(112, 215),
+                Box::new(|connectivity| {
+                    connectivity.url().unwrap_or_else(|| "Not connected".into())
+                }),
+            )
+        });
+
+        let alerts = ui.alerts.clone();
+
+        let active = Active {
+            widgets,
+            connectivity,
+            alerts,
+        };
+
+        Box::new(active)
+    }
+}
+
+#[async_trait]
+impl ActiveScreen for Active {
+    fn my_type(&self) -> Screen {
+        Screen::Alert(SCREEN_TYPE)
+    }
+
+    async fn deactivate(mut self: Box<Self>) -> Display {
+        self.connectivity.unsubscribe();
+        self.widgets.destroy().await
+    }
+
+    fn input(&mut self, ev: InputEvent) {
+        match ev {
+            InputEvent::NextScreen => {}
+            InputEvent::ToggleAction(_) | InputEvent::PerformAction(_) => {
+                self.alerts.deassert(SCREEN_TYPE);
+            }
+        }
+    }
+}
@@ -16,10 +16,9 @@
 // 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
 
 use std::convert::TryInto;
-use std::time::{Duration, SystemTime};
+use std::time::SystemTime;
 
 use anyhow::Result;
-use async_std::future::timeout;
 use async_std::prelude::*;
 use async_std::sync::Arc;
 use async_trait::async_trait;
@@ -31,26 +30,33 @@ use embedded_graphics::{
     text::Text,
 };
 
-use super::buttons::*;
 use super::widgets::*;
 use super::{
     splash, ActivatableScreen, ActiveScreen, AlertList, AlertScreen, Alerter, Display, InputEvent,
     Screen, Ui,
 };
+use crate::backlight::{Backlight, IdleState};
 use crate::broker::Topic;
+use crate::ui::display::Refresh;
+use crate::ui::locator::{Locator, LocatorSource};
 use crate::watched_tasks::WatchedTasksBuilder;
 
 const UI_TEXT_FONT: MonoFont = FONT_10X20;
 const SCREEN_TYPE: AlertScreen = AlertScreen::ScreenSaver;
-const SCREENSAVER_TIMEOUT: Duration = Duration::from_secs(600);
 
 struct BounceAnimation {
     bounding_box: Rectangle,
+    /// Keep objects centered on displays that can not keep up with the
+    /// animation
+    animated: bool,
 }
 
 impl BounceAnimation {
-    pub fn new(bounding_box: Rectangle) -> Self {
-        Self { bounding_box }
+    pub fn new(bounding_box: Rectangle, animated: bool) -> Self {
+        Self {
+            bounding_box,
+            animated,
+        }
     }
 
     fn offset(&self, obj_size: Size) -> Point {
@@ -84,6 +90,11 @@ impl BounceAnimation {
     }
 
     pub fn bounce<O: Transform + Dimensions>(&self, obj: O) -> O {
+        if !self.animated {
+            let offset = self.bounding_box.center() - obj.bounding_box().center();
+            return obj.translate(offset);
+        }
+
         let obj_size = obj.bounding_box().size;
         obj.translate(self.offset(obj_size))
     }
@@ -94,23 +105,17 @@ pub struct ScreenSaverScreen;
 impl ScreenSaverScreen {
     pub fn new(
         wtb: &mut WatchedTasksBuilder,
-        buttons: &Arc<Topic<ButtonEvent>>,
+        idle: &Arc<Topic<IdleState>>,
         alerts: &Arc<Topic<AlertList>>,
     ) -> Result<Self> {
-        // Activate screensaver if no button is pressed for some time
-        let (mut buttons_events, _) = buttons.clone().subscribe_unbounded();
+        // Activate the screensaver once the backlight is dimmed because
+        // no button was pressed for some time
+        let (mut idle_events, _) = idle.clone().subscribe_unbounded();
         let alerts = alerts.clone();
 
         wtb.spawn_task("screen-screensaver-activator", async move {
-            loop {
-                let ev = timeout(SCREENSAVER_TIMEOUT, buttons_events.next()).await;
-                let activate_screensaver = match ev {
-                    Ok(None) => break,
-                    Ok(Some(_)) => false,
-                    Err(_) => true,
-                };
-
-                if activate_screensaver {
+            while let Some(state) = idle_events.next().await {
+                if state != IdleState::Active {
                     alerts.assert(SCREEN_TYPE);
                 }
             }
@@ -124,9 +129,9 @@ impl ScreenSaverScreen {
 
 struct Active {
     widgets: WidgetContainer,
-    locator: Arc<Topic<bool>>,
+    locator: Arc<Topic<Locator>>,
     alerts: Arc<Topic<AlertList>>,
-    brightness: Arc<Topic<f32>>,
+    backlight: Backlight,
 }
 
 impl ActivatableScreen for ScreenSaverScreen {
@@ -137,10 +142,15 @@ impl ActivatableScreen for ScreenSaverScreen {
     fn activate(&mut self, ui: &Ui, display: Display) -> Box<dyn ActiveScreen> {
         display.with_lock(|target| draw_button_legend(target, "Locator", "Wake Up"));
 
-        let bounce = BounceAnimation::new(Rectangle::with_corners(
-            Point::new(0, 8),
-            Point::new(223, 240),
-        ));
+        let info = display.info();
+
+        let bounce = BounceAnimation::new(
+            Rectangle::with_corners(
+                Point::new(0, 8),
+                Point::new(info.size.width as i32 - 17, info.size.height as i32),
+            ),
+            info.refresh == Refresh::Immediate,
+        );
 
         let mut widgets = WidgetContainer::new(display);
 
@@ -169,16 +179,16 @@ impl ActivatableScreen for ScreenSaverScreen {
 
         let locator = ui.locator.clone();
         let alerts = ui.alerts.clone();
-        let brightness = ui.res.backlight.brightness.clone();
+        let backlight = ui.res.backlight.clone();
 
-        // Dim to 10% brightness in screensaver mode
-        brightness.set(0.1);
+        // Dim the backlight in screensaver mode (if it is not already)
+        backlight.dim();
 
         let active = Active {
             widgets,
             locator,
             alerts,
-            brightness,
+            backlight,
         };
 
         Box::new(active)
@@ -192,16 +202,22 @@ impl ActiveScreen for Active {
     }
 
     async fn deactivate(mut self: Box<Self>) -> Display {
-        // Restore full backlight brightness
-        self.brightness.set(1.0);
+        // Restore the backlight brightness
+        self.backlight.wake();
         self.widgets.destroy().await
     }
 
     fn input(&mut self, ev: InputEvent) {
         match ev {
             InputEvent::NextScreen => self.alerts.deassert(SCREEN_TYPE),
-            InputEvent::ToggleAction(_) => {}
-            InputEvent::PerformAction(_) => self.locator.toggle(false),
+            InputEvent::ToggleAction(_) => self.alerts.assert(AlertScreen::CommandPalette),
+            InputEvent::PerformAction(_) => self.locator.modify(|prev| {
+                if prev.map_or(false, |l| l.active) {
+                    Some(Locator::stopped())
+                } else {
+                    Some(Locator::started(LocatorSource::Lcd, None))
+                }
+            }),
         }
     }
 }
@@ -0,0 +1,174 @@
+// This file is part of tacd, the LXA TAC system daemon
+// Copyright (C) 2024 Pengutronix e.K.
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along
+// with this program; if not, write to the Free Software Foundation, Inc.,
+// 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+
+use anyhow::Result;
+use async_std::prelude::*;
+use async_std::sync::Arc;
+use async_trait::async_trait;
+use embedded_graphics::{
+    mono_font::MonoTextStyle, pixelcolor::BinaryColor, prelude::*, text::Text,
+};
+
+use super::buttons::Source;
+use super::widgets::*;
+use super::{
+    row_anchor, ActivatableScreen, ActiveScreen, AlertList, AlertScreen, Alerter, Display,
+    InputEvent, Screen, Ui,
+};
+use crate::broker::Topic;
+use crate::selftest::{CheckStatus, SelfTestReport, SelfTestRequest};
+use crate::watched_tasks::WatchedTasksBuilder;
+
+const SCREEN_TYPE: AlertScreen = AlertScreen::SelfTest;
+
+pub struct SelfTestScreen;
+
+struct Active {
+    widgets: WidgetContainer,
+    alerts: Arc<Topic<AlertList>>,
+    run: Arc<Topic<SelfTestRequest>>,
+}
+
+fn report_text(report: &Option<SelfTestReport>) -> String {
+    let report = match report {
+        Some(report) => report,
+        None => return String::new(),
+    };
+
+    report
+        .checks
+        .iter()
+        .map(|check| {
+            let status = match check.status {
+                CheckStatus::Pass => "ok",
+                CheckStatus::Warn => "warn",
+                CheckStatus::Fail => "FAIL",
+                CheckStatus::Skip => "skip",
+            };
+
+            format!("{status:<4} {}", check.name)
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+impl SelfTestScreen {
+    pub fn new(
+        wtb: &mut WatchedTasksBuilder,
+        alerts: &Arc<Topic<AlertList>>,
+        running: &Arc<Topic<bool>>,
+    ) -> Result<Self> {
+        let (mut running_events, _) = running.clone().subscribe_unbounded();
+        let alerts = alerts.clone();
+
+        // Show the progress and result of every self-test, no matter if it
+        // was started on the LCD or via the API.
+        // The screen stays up until it is closed.
+        wtb.spawn_task("screen-selftest-activator", async move {
+            while let Some(running) = running_events.next().await {
+                if running {
+                    alerts.assert(SCREEN_TYPE);
+                }
+            }
+
+            Ok(())
+        })?;
+
+        Ok(Self)
+    }
+}
+
+impl ActivatableScreen for SelfTestScreen {
+    fn my_type(&self) -> Screen {
+        Screen::Alert(SCREEN_TYPE)
+    }
+
+    fn activate(&mut self, ui: &Ui, display: Display) -> Box<dyn ActiveScreen> {
+        let ui_text_style: MonoTextStyle<BinaryColor> =
+            MonoTextStyle::new(&UI_TEXT_FONT, BinaryColor::On);
+
+        display.with_lock(|target| {
+            draw_button_legend(target, "Run again", "Close");
+
+            Text::new(
+                "Self-Test",
+                row_anchor(0) - (row_anchor(1) - row_anchor(0)),
+                ui_text_style,
+            )
+            .draw(target)
+            .unwrap();
+        });
+
+        let mut widgets = WidgetContainer::new(display);
+
+        widgets.push(|display| {
+            DynamicWidget::text(
+                ui.res.selftest.result.clone(),
+                display,
+                row_anchor(0),
+                Box::new(report_text),
+            )
+        });
+
+        widgets.push(|display| {
+            DynamicWidget::text(
+                ui.res.selftest.running.clone(),
+                display,
+                row_anchor(8),
+                Box::new(|running: &bool| {
+                    if *running {
+                        "Running ...".into()
+                    } else {
+                        String::new()
+                    }
+                }),
+            )
+        });
+
+        let alerts = ui.alerts.clone();
+        let run = ui.res.selftest.run.clone();
+
+        Box::new(Active {
+            widgets,
+            alerts,
+            run,
+        })
+    }
+}
+
+#[async_trait]
+impl ActiveScreen for Active {
+    fn my_type(&self) -> Screen {
+        Screen::Alert(SCREEN_TYPE)
+    }
+
+    async fn deactivate(mut self: Box<Self>) -> Display {
+        self.widgets.destroy().await
+    }
+
+    fn input(&mut self, ev: InputEvent) {
+        // The LEDs are inspected visually during the self-test, so it is
+        // only started again by someone standing in front of the TAC.
+        // The output loopback is never requested from the LCD.
+        match ev {
+            InputEvent::NextScreen => self.alerts.deassert(SCREEN_TYPE),
+            InputEvent::ToggleAction(_) => {}
+            InputEvent::PerformAction(Source::Local) => self.run.set(SelfTestRequest::default()),
+            InputEvent::PerformAction(_) => {}
+        }
+    }
+}
@@ -15,12 +15,21 @@
 // with this program; if not, write to the Free Software Foundation, Inc.,
 // 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
 
+use std::net::Ipv6Addr;
+
 use anyhow::Result;
 use async_std::prelude::*;
 use async_std::sync::Arc;
 use async_std::task::spawn;
 use async_trait::async_trait;
-use embedded_graphics::{prelude::Point, text::Alignment};
+use embedded_graphics::{
+    mono_font::MonoTextStyle,
+    pixelcolor::BinaryColor,
+    prelude::*,
+    primitives::Rectangle,
+    text::{Alignment, Text},
+};
+use futures::stream::select;
 use serde::{Deserialize, Serialize};
 
 use super::buttons::Source;
@@ -33,56 +42,58 @@ use crate::broker::{Native, SubscriptionHandle, Topic};
 use crate::watched_tasks::WatchedTasksBuilder;
 
 const SCREEN_TYPE: AlertScreen = AlertScreen::Setup;
+const NOT_CONNECTED_TEXT: &str =
+    "Welcome to your TAC!\n\n\nPlease connect\nto a network\nto continue\nthe setup";
 
 #[derive(Serialize, Deserialize, Clone)]
-enum Connectivity {
+pub(super) enum Connectivity {
     Nothing,
     HostnameOnly(String),
     IpOnly(String),
     Both(String, String),
 }
 
-pub struct SetupScreen;
+impl Connectivity {
+    /// The URL that is most likely to work when encoded as QR code
+    ///
+    /// Resolving the hostname depends on the DNS setup of the network
+    /// the phone scanning the code is in, so the IP based URL is preferred.
+    pub(super) fn url(&self) -> Option<String> {
+        match self {
+            Self::Nothing => None,
+            Self::HostnameOnly(c) | Self::IpOnly(c) | Self::Both(c, _) => {
+                Some(format!("http://{c}"))
+            }
+        }
+    }
+}
 
-struct Active {
-    widgets: WidgetContainer,
+/// Keep track of the ways the web interface of this TAC can be reached
+pub(super) struct ConnectivityWatcher {
+    pub(super) topic: Arc<Topic<Connectivity>>,
     hostname_update_handle: SubscriptionHandle<String, Native>,
     ip_update_handle: SubscriptionHandle<Vec<String>, Native>,
-    alerts: Arc<Topic<AlertList>>,
-    diagnostics_presses: u8,
+    ipv6_update_handle: SubscriptionHandle<Vec<String>, Native>,
 }
 
-impl SetupScreen {
-    pub fn new(
-        wtb: &mut WatchedTasksBuilder,
-        alerts: &Arc<Topic<AlertList>>,
-        setup_mode: &Arc<Topic<bool>>,
-    ) -> Result<Self> {
-        let (mut setup_mode_events, _) = setup_mode.clone().subscribe_unbounded();
-        let alerts = alerts.clone();
-
-        wtb.spawn_task("screen-setup-avtivator", async move {
-            while let Some(setup_mode) = setup_mode_events.next().await {
-                if setup_mode {
-                    alerts.assert(AlertScreen::Setup);
-                } else {
-                    alerts.deassert(AlertScreen::Setup);
-                }
-            }
-
-            Ok(())
-        })?;
-
-        Ok(Self)
+/// Pick the address to show in the connection hints
+///
+/// IPv4 addresses are preferred, as they are shorter.
+/// Link local IPv6 addresses are skipped, as they would need a zone index to
+/// be usable in an URL.
+fn preferred_ip(ipv4: &[String], ipv6: &[String]) -> Option<String> {
+    if let Some(ip) = ipv4.first() {
+        return Some(ip.clone());
     }
-}
 
-impl ActivatableScreen for SetupScreen {
-    fn my_type(&self) -> Screen {
-        Screen::Alert(SCREEN_TYPE)
-    }
+    ipv6.iter()
+        .filter_map(|ip| ip.parse::<Ipv6Addr>().ok())
+        .find(|ip| !ip.is_loopback() && (ip.segments()[0] & 0xffc0) != 0xfe80)
+        .map(|ip| format!("[{ip}]"))
+}
 
-    fn activate(&mut self, ui: &Ui, display: Display) -> Box<dyn ActiveScreen> {
+impl ConnectivityWatcher {
+    pub(super) fn new(ui: &Ui) -> Self {
         /* We want to display hints on how to connect to this TAC.
          * We want to show:
          * - An URL based on the hostname, e.g. http://lxatac-12345
@@ -90,22 +101,22 @@ impl ActivatableScreen for SetupScreen {
          * - Both
          *
          * This information may not be immediately available on boot,
-         * so we collect it in connectivity_topic and update it once it comes
-         * in.
+         * so we collect it in the connectivity topic and update it once it
+         * comes in.
          *
          * [1]: We can barely fit a maximum-length IPv4 address in one line,
-         * so we currently opt out of showing an IPv6 based URL as well.
+         * so an IPv6 based URL is only shown if there is no IPv4 address.
          * It would most likely be too long to practically read it and type into a
-         * browser anyways. */
-        let connectivity_topic = Topic::anonymous(Some(Connectivity::Nothing));
+         * browser anyways, but the QR code still works. */
+        let topic = Topic::anonymous(Some(Connectivity::Nothing));
 
-        let connectivity_topic_task = connectivity_topic.clone();
+        let topic_task = topic.clone();
         let (mut hostname_stream, hostname_update_handle) =
             ui.res.hostname.hostname.clone().subscribe_unbounded();
 
         spawn(async move {
             while let Some(hostname) = hostname_stream.next().await {
-                connectivity_topic_task.modify(|prev| match prev.unwrap() {
+                topic_task.modify(|prev| match prev.unwrap() {
                     Connectivity::Nothing | Connectivity::HostnameOnly(_) => {
                         Some(Connectivity::HostnameOnly(hostname))
                     }
@@ -116,18 +127,38 @@ impl ActivatableScreen for SetupScreen {
             }
         });
 
-        let connectivity_topic_task = connectivity_topic.clone();
-        let (mut ip_stream, ip_update_handle) = ui
+        let topic_task = topic.clone();
+        let (ip_stream, ip_update_handle) = ui
             .res
             .network
             .bridge_interface
             .clone()
             .subscribe_unbounded();
+        let (ipv6_stream, ipv6_update_handle) = ui
+            .res
+            .network
+            .bridge_interface_ipv6
+            .clone()
+            .subscribe_unbounded();
+
+        let mut ip_stream = select(
+            ip_stream.map(|ips| (false, ips)),
+            ipv6_stream.map(|ips| (true, ips)),
+        );
 
         spawn(async move {
-            while let Some(ips) = ip_stream.next().await {
-                connectivity_topic_task.modify(|prev| {
-                    let ip = ips.first().cloned();
+            let mut ipv4 = Vec::new();
+            let mut ipv6 = Vec::new();
+
+            while let Some((is_ipv6, ips)) = ip_stream.next().await {
+                if is_ipv6 {
+                    ipv6 = ips;
+                } else {
+                    ipv4 = ips;
+                }
+
+                topic_task.modify(|prev| {
+                    let ip = preferred_ip(&ipv4, &ipv6);
 
                     match (prev.unwrap(), ip) {
                         (Connectivity::Nothing, Some(ip)) | (Connectivity::IpOnly(_), Some(ip)) => {
@@ -145,34 +176,127 @@ impl ActivatableScreen for SetupScreen {
             }
         });
 
+        Self {
+            topic,
+            hostname_update_handle,
+            ip_update_handle,
+            ipv6_update_handle,
+        }
+    }
+
+    pub(super) fn unsubscribe(self) {
+        self.hostname_update_handle.unsubscribe();
+        self.ip_update_handle.unsubscribe();
+        self.ipv6_update_handle.unsubscribe();
+    }
+}
+
+pub struct SetupScreen;
+
+struct Active {
+    widgets: WidgetContainer,
+    connectivity: ConnectivityWatcher,
+    alerts: Arc<Topic<AlertList>>,
+    diagnostics_presses: u8,
+}
+
+impl SetupScreen {
+    pub fn new(
+        wtb: &mut WatchedTasksBuilder,
+        alerts: &Arc<Topic<AlertList>>,
+        setup_mode: &Arc<Topic<bool>>,
+    ) -> Result<Self> {
+        let (mut setup_mode_events, _) = setup_mode.clone().subscribe_unbounded();
+        let alerts = alerts.clone();
+
+        wtb.spawn_task("screen-setup-avtivator", async move {
+            while let Some(setup_mode) = setup_mode_events.next().await {
+                if setup_mode {
+                    alerts.assert(AlertScreen::Setup);
+                } else {
+                    alerts.deassert(AlertScreen::Setup);
+                }
+            }
+
+            Ok(())
+        })?;
+
+        Ok(Self)
+    }
+}
+
+impl ActivatableScreen for SetupScreen {
+    fn my_type(&self) -> Screen {
+        Screen::Alert(SCREEN_TYPE)
+    }
+
+    fn activate(&mut self, ui: &Ui, display: Display) -> Box<dyn ActiveScreen> {
+        let connectivity = ConnectivityWatcher::new(ui);
+
         let mut widgets = WidgetContainer::new(display);
 
-        widgets.push(|display|
-            DynamicWidget::text_aligned(
-                connectivity_topic,
+        // The text, URLs and QR code are drawn by a single widget,
+        // as the layout changes completely once an URL is known.
+        widgets.push(|display| {
+            DynamicWidget::new(
+                connectivity.topic.clone(),
                 display,
-                Point::new(120, 55),
-                Box::new(|connectivity| match connectivity {
-                    Connectivity::Nothing => {
-                        "Welcome to your TAC!\n\n\nPlease connect\nto a network\nto continue\nthe setup".into()
-                    }
-                    Connectivity::HostnameOnly(c) | Connectivity::IpOnly(c) => {
-                        format!("Welcome to your TAC!\n\nPlease continue the\nsetup at:\n\n\nhttp://{c}")
+                Box::new(|connectivity, target| {
+                    let ui_text_style: MonoTextStyle<BinaryColor> =
+                        MonoTextStyle::new(&UI_TEXT_FONT, BinaryColor::On);
+
+                    let urls = match connectivity {
+                        Connectivity::Nothing => {
+                            let text = Text::with_alignment(
+                                NOT_CONNECTED_TEXT,
+                                Point::new(120, 55),
+                                ui_text_style,
+                                Alignment::Center,
+                            );
+
+                            text.draw(target).unwrap();
+
+                            return Some(text.bounding_box());
+                        }
+                        Connectivity::HostnameOnly(c) | Connectivity::IpOnly(c) => {
+                            format!("\nhttp://{c}")
+                        }
+                        Connectivity::Both(ip, hn) => format!("http://{hn}\nhttp://{ip}"),
+                    };
+
+                    Text::with_alignment(
+                        "Welcome to your TAC!\nScan or continue the\nsetup at:",
+                        Point::new(120, 20),
+                        ui_text_style,
+                        Alignment::Center,
+                    )
+                    .draw(target)
+                    .unwrap();
+
+                    if let Some(url) = connectivity.url() {
+                        draw_qr_code(target, Point::new(120, 130), 110, &url);
                     }
-                    Connectivity::Both(ip, hn) => format!(
-                        "Welcome to your TAC!\n\nPlease continue the\nsetup at:\n\nhttp://{hn}\nor\nhttp://{ip}"
-                    ),
+
+                    Text::with_alignment(
+                        &urls,
+                        Point::new(120, 200),
+                        ui_text_style,
+                        Alignment::Center,
+                    )
+                    .draw(target)
+                    .unwrap();
+
+                    Some(Rectangle::new(Point::zero(), target.info().size))
                 }),
-                Alignment::Center,
-        ));
+            )
+        });
 
         let alerts = ui.alerts.clone();
         let diagnostics_presses = 0;
 
         let active = Active {
             widgets,
-            hostname_update_handle,
-            ip_update_handle,
+            connectivity,
             alerts,
             diagnostics_presses,
         };
@@ -188,8 +312,7 @@ impl ActiveScreen for Active {
     }
 
     async fn deactivate(mut self: Box<Self>) -> Display {
-        self.hostname_update_handle.unsubscribe();
-        self.ip_update_handle.unsubscribe();
+        self.connectivity.unsubscribe();
         self.widgets.destroy().await
     }
 
@@ -0,0 +1,159 @@
+// This file is part of tacd, the LXA TAC system daemon
+// Copyright (C) 2024 Pengutronix e.K.
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along
+// with this program; if not, write to the Free Software Foundation, Inc.,
+// 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+
+use anyhow::Result;
+use async_std::prelude::*;
+use async_std::sync::Arc;
+use async_trait::async_trait;
+use embedded_graphics::{
+    mono_font::MonoTextStyle, pixelcolor::BinaryColor, prelude::*, text::Text,
+};
+
+use super::widgets::*;
+use super::{
+    row_anchor, ActivatableScreen, ActiveScreen, AlertList, AlertScreen, Alerter, Display,
+    InputEvent, Screen, Ui,
+};
+use crate::broker::Topic;
+use crate::setup_mode::{Decision, PendingImport};
+use crate::watched_tasks::WatchedTasksBuilder;
+
+const SCREEN_TYPE: AlertScreen = AlertScreen::SshKeyImport;
+
+/// Show at most this many fingerprints. The rest is summarized as "+N more".
+const MAX_KEYS_SHOWN: usize = 4;
+
+/// The number of characters of a fingerprint that fit onto the screen
+const FINGERPRINT_LEN: usize = 24;
+
+pub struct SshKeyImportScreen {
+    pending: Arc<Topic<Option<PendingImport>>>,
+}
+
+struct Active {
+    display: Display,
+    decision: Arc<Topic<Decision>>,
+    id: Option<u64>,
+}
+
+impl SshKeyImportScreen {
+    pub fn new(
+        wtb: &mut WatchedTasksBuilder,
+        alerts: &Arc<Topic<AlertList>>,
+        pending: &Arc<Topic<Option<PendingImport>>>,
+    ) -> Result<Self> {
+        let (mut pending_events, _) = pending.clone().subscribe_unbounded();
+        let alerts = alerts.clone();
+
+        wtb.spawn_task("screen-ssh-key-import-activator", async move {
+            while let Some(pending) = pending_events.next().await {
+                if pending.is_some() {
+                    alerts.assert(SCREEN_TYPE);
+                } else {
+                    alerts.deassert(SCREEN_TYPE);
+                }
+            }
+
+            Ok(())
+        })?;
+
+        Ok(Self {
+            pending: pending.clone(),
+        })
+    }
+}
+
+impl ActivatableScreen for SshKeyImportScreen {
+    fn my_type(&self) -> Screen {
+        Screen::Alert(SCREEN_TYPE)
+    }
+
+    fn activate(&mut self, ui: &Ui, display: Display) -> Box<dyn ActiveScreen> {
+        let ui_text_style: MonoTextStyle<BinaryColor> =
+            MonoTextStyle::new(&UI_TEXT_FONT, BinaryColor::On);
+        let small_text_style: MonoTextStyle<BinaryColor> =
+            MonoTextStyle::new(&SMALL_TEXT_FONT, BinaryColor::On);
+
+        let pending = self.pending.try_get().flatten();
+
+        display.with_lock(|target| {
+            draw_button_legend(target, "Import", "Reject");
+
+            Text::new(
+                "Import SSH keys",
+                row_anchor(0) - (row_anchor(1) - row_anchor(0)),
+                ui_text_style,
+            )
+            .draw(target)
+            .unwrap();
+
+            if let Some(pending) = &pending {
+                let source: String = pending.source.label().chars().take(20).collect();
+
+                Text::new(&format!("from {source}?"), row_anchor(0), ui_text_style)
+                    .draw(target)
+                    .unwrap();
+
+                let mut fingerprints: Vec<String> = pending
+                    .keys
+                    .iter()
+                    .take(MAX_KEYS_SHOWN)
+                    .map(|key| key.fingerprint.chars().take(FINGERPRINT_LEN).collect())
+                    .collect();
+
+                if pending.keys.len() > MAX_KEYS_SHOWN {
+                    fingerprints.push(format!("+{} more", pending.keys.len() - MAX_KEYS_SHOWN));
+                }
+
+                Text::new(&fingerprints.join("\n"), row_anchor(2), small_text_style)
+                    .draw(target)
+                    .unwrap();
+            }
+        });
+
+        let active = Active {
+            display,
+            decision: ui.res.setup_mode.key_import.decision.clone(),
+            id: pending.map(|p| p.id),
+        };
+
+        Box::new(active)
+    }
+}
+
+#[async_trait]
+impl ActiveScreen for Active {
+    fn my_type(&self) -> Screen {
+        Screen::Alert(SCREEN_TYPE)
+    }
+
+    async fn deactivate(mut self: Box<Self>) -> Display {
+        self.display
+    }
+
+    fn input(&mut self, ev: InputEvent) {
+        let accept = match ev {
+            InputEvent::NextScreen => false,
+            InputEvent::ToggleAction(_) => return,
+            InputEvent::PerformAction(_) => true,
+        };
+
+        if let Some(id) = self.id {
+            self.decision.set(Decision { id, accept });
+        }
+    }
+}
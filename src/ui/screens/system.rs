@@ -37,6 +37,7 @@ enum Action {
     Help,
     SetupMode,
     Updates,
+    ShowQr,
 }
 
 impl Action {
@@ -45,7 +46,8 @@ impl Action {
             Self::Reboot => Self::Help,
             Self::Help => Self::SetupMode,
             Self::SetupMode => Self::Updates,
-            Self::Updates => Self::Reboot,
+            Self::Updates => Self::ShowQr,
+            Self::ShowQr => Self::Reboot,
         }
     }
 }
@@ -130,7 +132,7 @@ impl ActivatableScreen for SystemScreen {
             DynamicWidget::text(
                 highlighted.clone(),
                 display,
-                row_anchor(5),
+                row_anchor(4),
                 Box::new(|action| match action {
                     Action::Reboot => "> Reboot".into(),
                     _ => "  Reboot".into(),
@@ -142,7 +144,7 @@ impl ActivatableScreen for SystemScreen {
             DynamicWidget::text(
                 highlighted.clone(),
                 display,
-                row_anchor(6),
+                row_anchor(5),
                 Box::new(|action| match action {
                     Action::Help => "> Help".into(),
                     _ => "  Help".into(),
@@ -154,7 +156,7 @@ impl ActivatableScreen for SystemScreen {
             DynamicWidget::text(
                 highlighted.clone(),
                 display,
-                row_anchor(7),
+                row_anchor(6),
                 Box::new(|action| match action {
                     Action::SetupMode => "> Setup Mode".into(),
                     _ => "  Setup Mode".into(),
@@ -166,7 +168,7 @@ impl ActivatableScreen for SystemScreen {
             DynamicWidget::text(
                 highlighted.clone(),
                 display,
-                row_anchor(8),
+                row_anchor(7),
                 Box::new(|action| match action {
                     Action::Updates => "> Updates".into(),
                     _ => "  Updates".into(),
@@ -174,6 +176,18 @@ impl ActivatableScreen for SystemScreen {
             )
         });
 
+        widgets.push(|display| {
+            DynamicWidget::text(
+                highlighted.clone(),
+                display,
+                row_anchor(8),
+                Box::new(|action| match action {
+                    Action::ShowQr => "> Show QR".into(),
+                    _ => "  Show QR".into(),
+                }),
+            )
+        });
+
         let reboot_message = ui.reboot_message.clone();
         let setup_mode = ui.res.setup_mode.setup_mode.clone();
         let show_help = ui.res.setup_mode.show_help.clone();
@@ -218,6 +232,7 @@ impl ActiveScreen for Active {
                 Action::Help => self.show_help.set(true),
                 Action::SetupMode => self.setup_mode.set(true),
                 Action::Updates => self.alerts.assert(AlertScreen::UpdateAvailable),
+                Action::ShowQr => self.alerts.assert(AlertScreen::QrCode),
             },
             _ => {}
         }
@@ -0,0 +1,108 @@
+// This file is part of tacd, the LXA TAC system daemon
+// Copyright (C) 2024 Pengutronix e.K.
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along
+// with this program; if not, write to the Free Software Foundation, Inc.,
+// 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+
+use async_std::sync::Arc;
+use async_trait::async_trait;
+use embedded_graphics::prelude::Point;
+
+use super::widgets::*;
+use super::Display;
+use super::{ActivatableScreen, ActiveScreen, AlertScreen, InputEvent, NormalScreen, Screen, Ui};
+use crate::broker::Topic;
+
+const SCREEN_TYPE: AlertScreen = AlertScreen::Tour;
+
+pub struct TourScreen {
+    step: Arc<Topic<Option<usize>>>,
+}
+
+struct Active {
+    widgets: WidgetContainer,
+    step: Arc<Topic<Option<usize>>>,
+}
+
+impl TourScreen {
+    pub fn new(step: &Arc<Topic<Option<usize>>>) -> Self {
+        Self { step: step.clone() }
+    }
+}
+
+fn page(step: usize) -> String {
+    let screens = NormalScreen::all();
+
+    match screens.get(step) {
+        Some(screen) => {
+            let annotation = screen.annotation();
+
+            format!(
+                "{}/{} {}\n\n{}\n\nAlso see the web UI.",
+                step + 1,
+                screens.len(),
+                annotation.title,
+                annotation.caption,
+            )
+        }
+        None => String::new(),
+    }
+}
+
+impl ActivatableScreen for TourScreen {
+    fn my_type(&self) -> Screen {
+        Screen::Alert(SCREEN_TYPE)
+    }
+
+    fn activate(&mut self, _ui: &Ui, display: Display) -> Box<dyn ActiveScreen> {
+        display.with_lock(|target| draw_button_legend(target, "Leave", "Next"));
+
+        let mut widgets = WidgetContainer::new(display);
+
+        widgets.push(|display| {
+            DynamicWidget::text(
+                self.step.clone(),
+                display,
+                Point::new(8, 24),
+                Box::new(|step| step.map(page).unwrap_or_default()),
+            )
+        });
+
+        let active = Active {
+            widgets,
+            step: self.step.clone(),
+        };
+
+        Box::new(active)
+    }
+}
+
+#[async_trait]
+impl ActiveScreen for Active {
+    fn my_type(&self) -> Screen {
+        Screen::Alert(SCREEN_TYPE)
+    }
+
+    async fn deactivate(mut self: Box<Self>) -> Display {
+        self.widgets.destroy().await
+    }
+
+    fn input(&mut self, ev: InputEvent) {
+        match ev {
+            InputEvent::NextScreen => self.step.modify(|step| Some(step.flatten().map(|s| s + 1))),
+            InputEvent::ToggleAction(_) => {}
+            InputEvent::PerformAction(_) => self.step.set(None),
+        }
+    }
+}
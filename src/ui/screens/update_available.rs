@@ -30,7 +30,7 @@ use super::{
     InputEvent, Screen, Ui,
 };
 use crate::broker::Topic;
-use crate::dbus::rauc::Channel;
+use crate::dbus::rauc::{BundleInfo, Channel};
 use crate::watched_tasks::WatchedTasksBuilder;
 
 const SCREEN_TYPE: AlertScreen = AlertScreen::UpdateAvailable;
@@ -59,6 +59,8 @@ impl Highlight {
 struct Selection {
     channels: Vec<Channel>,
     highlight: Highlight,
+    /// What the most recently inspected bundle contains
+    bundle_info: Option<BundleInfo>,
 }
 
 impl Selection {
@@ -66,9 +68,24 @@ impl Selection {
         Self {
             channels: Vec::new(),
             highlight: Highlight::Dismiss,
+            bundle_info: None,
         }
     }
 
+    fn highlighted_channel(&self) -> Option<&Channel> {
+        match self.highlight {
+            Highlight::Channel(idx) => self.channels.get(idx),
+            Highlight::Dismiss => None,
+        }
+    }
+
+    /// The bundle info for the highlighted channel (if it was inspected)
+    fn highlighted_bundle_info(&self) -> Option<&BundleInfo> {
+        let url = &self.highlighted_channel()?.url;
+
+        self.bundle_info.as_ref().filter(|info| &info.url == url)
+    }
+
     fn have_update(&self) -> bool {
         !self.channels.is_empty()
     }
@@ -103,6 +120,18 @@ impl Selection {
         Some(Self {
             channels,
             highlight,
+            bundle_info: self.bundle_info.clone(),
+        })
+    }
+
+    fn update_bundle_info(self, bundle_info: Option<BundleInfo>) -> Option<Self> {
+        if bundle_info == self.bundle_info {
+            return None;
+        }
+
+        Some(Self {
+            bundle_info,
+            ..self
         })
     }
 
@@ -111,10 +140,7 @@ impl Selection {
         let highlight = self.highlight.next(num_channels);
 
         if highlight != self.highlight {
-            Some(Self {
-                channels: self.channels,
-                highlight,
-            })
+            Some(Self { highlight, ..self })
         } else {
             None
         }
@@ -136,6 +162,7 @@ struct Active {
     widgets: WidgetContainer,
     alerts: Arc<Topic<AlertList>>,
     install: Arc<Topic<String>>,
+    inspect: Arc<Topic<String>>,
     selection: Arc<Topic<Selection>>,
 }
 
@@ -144,6 +171,7 @@ impl UpdateAvailableScreen {
         wtb: &mut WatchedTasksBuilder,
         alerts: &Arc<Topic<AlertList>>,
         channels: &Arc<Topic<Vec<Channel>>>,
+        bundle_info: &Arc<Topic<Option<BundleInfo>>>,
     ) -> Result<Self> {
         let (mut channels_events, _) = channels.clone().subscribe_unbounded();
         let alerts = alerts.clone();
@@ -164,6 +192,17 @@ impl UpdateAvailableScreen {
             Ok(())
         })?;
 
+        let (mut bundle_info_events, _) = bundle_info.clone().subscribe_unbounded();
+        let selection_task = selection.clone();
+
+        wtb.spawn_task("screen-update-available-bundle-info", async move {
+            while let Some(info) = bundle_info_events.next().await {
+                selection_task.modify(|sel| sel.unwrap().update_bundle_info(info));
+            }
+
+            Ok(())
+        })?;
+
         Ok(Self { selection })
     }
 }
@@ -224,6 +263,18 @@ impl ActivatableScreen for UpdateAvailableScreen {
                         .draw(target)
                         .unwrap();
 
+                    if let Some(info) = sel.highlighted_bundle_info() {
+                        let text = match (&info.version, &info.build) {
+                            (Some(version), Some(build)) => format!("{version}\nbuilt {build}"),
+                            (Some(version), None) => version.clone(),
+                            _ => "Failed to inspect\nthe bundle".to_string(),
+                        };
+
+                        Text::new(&text, row_anchor(7), ui_text_style)
+                            .draw(target)
+                            .unwrap();
+                    }
+
                     // Don't bother tracking the actual bounding box and instead
                     // clear the whole screen on update.
                     Some(target.bounding_box())
@@ -233,12 +284,14 @@ impl ActivatableScreen for UpdateAvailableScreen {
 
         let alerts = ui.alerts.clone();
         let install = ui.res.rauc.install.clone();
+        let inspect = ui.res.rauc.inspect.clone();
         let selection = self.selection.clone();
 
         Box::new(Active {
             widgets,
             alerts,
             install,
+            inspect,
             selection,
         })
     }
@@ -260,6 +313,16 @@ impl ActiveScreen for Active {
             InputEvent::ToggleAction(_) => {
                 self.selection
                     .modify(|selection| selection.and_then(|s| s.toggle()));
+
+                // Show what the bundle of the highlighted channel contains
+                let url = self
+                    .selection
+                    .try_get()
+                    .and_then(|s| s.highlighted_channel().map(|ch| ch.url.clone()));
+
+                if let Some(url) = url {
+                    self.inspect.set(url);
+                }
             }
             InputEvent::PerformAction(_) => {
                 if let Some(selection) = self.selection.try_get() {
@@ -27,7 +27,7 @@ use super::{
     Ui,
 };
 use crate::broker::Topic;
-use crate::dbus::rauc::Progress;
+use crate::dbus::rauc::{Eta, Progress};
 use crate::watched_tasks::WatchedTasksBuilder;
 
 const SCREEN_TYPE: AlertScreen = AlertScreen::UpdateInstallation;
@@ -138,6 +138,18 @@ impl ActivatableScreen for UpdateInstallationScreen {
             )
         });
 
+        widgets.push(|display| {
+            DynamicWidget::text_center(
+                ui.res.rauc.eta.clone(),
+                display,
+                Point::new(120, 215),
+                Box::new(|eta: &Option<Eta>| match eta {
+                    Some(eta) => format!("{} left", eta.format()),
+                    None => String::new(),
+                }),
+            )
+        });
+
         Box::new(Active { widgets })
     }
 }
@@ -149,9 +149,9 @@ impl ActivatableScreen for UsbScreen {
         }
 
         let port_requests = [
-            ui.res.usb_hub.port1.request.clone(),
-            ui.res.usb_hub.port2.request.clone(),
-            ui.res.usb_hub.port3.request.clone(),
+            ui.res.usb_hub.port1.lcd_request.clone(),
+            ui.res.usb_hub.port2.lcd_request.clone(),
+            ui.res.usb_hub.port3.lcd_request.clone(),
         ];
         let port_states = [
             ui.res.usb_hub.port1.status.clone(),
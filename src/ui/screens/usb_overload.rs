@@ -109,10 +109,10 @@ impl ActivatableScreen for UsbOverloadScreen {
         let mut widgets = WidgetContainer::new(display);
 
         let ports = [
-            (0, &ui.res.adc.usb_host_curr.topic, MAX_TOTAL_CURRENT),
-            (2, &ui.res.adc.usb_host1_curr.topic, MAX_PORT_CURRENT),
-            (3, &ui.res.adc.usb_host2_curr.topic, MAX_PORT_CURRENT),
-            (4, &ui.res.adc.usb_host3_curr.topic, MAX_PORT_CURRENT),
+            (0, &ui.res.adc.usb_host_curr, MAX_TOTAL_CURRENT),
+            (2, &ui.res.adc.usb_host1_curr, MAX_PORT_CURRENT),
+            (3, &ui.res.adc.usb_host2_curr, MAX_PORT_CURRENT),
+            (4, &ui.res.adc.usb_host3_curr, MAX_PORT_CURRENT),
         ];
 
         for (idx, current, max_current) in ports {
@@ -120,7 +120,7 @@ impl ActivatableScreen for UsbOverloadScreen {
 
             widgets.push(|display| {
                 DynamicWidget::bar(
-                    current.clone(),
+                    current.topic.clone(),
                     display,
                     anchor_port + OFFSET_BAR,
                     WIDTH_BAR,
@@ -130,11 +130,13 @@ impl ActivatableScreen for UsbOverloadScreen {
             });
 
             widgets.push(|display| {
+                let fmt = current.lcd_formatter();
+
                 DynamicWidget::text(
-                    current.clone(),
+                    current.topic.clone(),
                     display,
                     anchor_port + OFFSET_VAL,
-                    Box::new(|meas: &Measurement| format!("{:>4.0}mA", meas.value * 1000.0)),
+                    Box::new(move |meas: &Measurement| format!("{:>8}", fmt(meas.value))),
                 )
             });
         }
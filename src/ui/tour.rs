@@ -0,0 +1,161 @@
+// This file is part of tacd, the LXA TAC system daemon
+// Copyright (C) 2024 Pengutronix e.K.
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along
+// with this program; if not, write to the Free Software Foundation, Inc.,
+// 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+
+use anyhow::Result;
+use async_std::prelude::*;
+use async_std::sync::Arc;
+use futures::{select, FutureExt};
+use serde::{Deserialize, Serialize};
+
+use super::{AlertList, AlertScreen, Alerter, NormalScreen};
+use crate::broker::{BrokerBuilder, Topic};
+use crate::watched_tasks::WatchedTasksBuilder;
+
+/// The current step of the intro tour as published to the web interface
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct TourStep {
+    /// Zero based index of this step
+    pub step: usize,
+    pub steps: usize,
+    /// The LCD screen this step explains
+    pub screen: NormalScreen,
+    pub title: String,
+    pub caption: String,
+    /// Topics the web interface should highlight during this step
+    pub topics: Vec<String>,
+}
+
+impl TourStep {
+    fn new(step: usize) -> Option<Self> {
+        let screens = NormalScreen::all();
+        let screen = *screens.get(step)?;
+        let annotation = screen.annotation();
+
+        Some(Self {
+            step,
+            steps: screens.len(),
+            screen,
+            title: annotation.title.to_string(),
+            // The line breaks only make sense on the LCD
+            caption: annotation.caption.replace('\n', " "),
+            topics: annotation.topics.iter().map(|t| t.to_string()).collect(),
+        })
+    }
+}
+
+/// Guide new users through the LCD screens and the matching parts of the
+/// web interface
+///
+/// The tour is started once the setup mode is completed or on request via
+/// the web interface.
+/// It is advanced using the buttons on the TAC (see the tour screen) and
+/// every step is published in the `/v1/tac/display/tour` topic, so that the
+/// web interface can highlight the matching controls.
+/// Returns the topic containing the index of the current step.
+pub(super) fn handle_tour(
+    bb: &mut BrokerBuilder,
+    wtb: &mut WatchedTasksBuilder,
+    completed: &Arc<Topic<bool>>,
+    screen: &Arc<Topic<NormalScreen>>,
+    alerts: &Arc<Topic<AlertList>>,
+) -> Result<Arc<Topic<Option<usize>>>> {
+    // Use the ro/wo topic with the same path trick, so that the web interface
+    // can start and stop the tour but not skip to arbitrary steps.
+    let request = bb.topic_wo::<bool>("/v1/tac/display/tour", None);
+    let tour = bb.topic_ro::<Option<TourStep>>("/v1/tac/display/tour", Some(None));
+    let step = Topic::anonymous(Some(None));
+
+    let (mut completed_events, _) = completed.clone().subscribe_unbounded();
+    let (mut requests, _) = request.subscribe_unbounded();
+    let (mut step_events, _) = step.clone().subscribe_unbounded();
+
+    let step_task = step.clone();
+    let screen = screen.clone();
+    let alerts = alerts.clone();
+
+    wtb.spawn_task("tour-orchestration", async move {
+        loop {
+            select! {
+                ev = completed_events.next().fuse() => match ev {
+                    Some(_) => step_task.set(Some(0)),
+                    None => break,
+                },
+                req = requests.next().fuse() => match req {
+                    Some(true) => step_task.set(Some(0)),
+                    Some(false) => step_task.set(None),
+                    None => break,
+                },
+                ev = step_events.next().fuse() => match ev {
+                    Some(idx) => match idx.and_then(TourStep::new) {
+                        Some(ts) => {
+                            // Have the explained screen ready once the user
+                            // leaves the tour
+                            screen.set(ts.screen);
+                            tour.set(Some(ts));
+                            alerts.assert(AlertScreen::Tour);
+                        }
+                        None => {
+                            // Stepping past the last screen ends the tour
+                            if idx.is_some() {
+                                step_task.set(None);
+                            }
+
+                            tour.set_if_changed(None);
+                            alerts.deassert(AlertScreen::Tour);
+                        }
+                    },
+                    None => break,
+                },
+            }
+        }
+
+        Ok(())
+    })?;
+
+    Ok(step)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{NormalScreen, TourStep};
+
+    #[test]
+    fn steps() {
+        let screens = NormalScreen::all();
+
+        println!("Every normal screen is part of the tour");
+        assert_eq!(screens.first(), Some(&NormalScreen::first()));
+        assert_eq!(screens.len(), NormalScreen::Uart as usize + 1);
+
+        for (idx, screen) in screens.iter().enumerate() {
+            let step = TourStep::new(idx).unwrap();
+
+            assert_eq!(step.screen, *screen);
+            assert_eq!(step.steps, screens.len());
+            assert!(!step.topics.is_empty());
+            assert!(!step.caption.contains('\n'));
+
+            // The caption has to fit the LCD
+            for line in screen.annotation().caption.lines() {
+                assert!(line.len() <= 21, "Caption line too long: {line}");
+            }
+        }
+
+        println!("The tour ends after the last screen");
+        assert!(TourStep::new(screens.len()).is_none());
+    }
+}
@@ -32,6 +32,7 @@ use serde::Serialize;
 
 use crate::broker::{Native, SubscriptionHandle, Topic};
 use crate::ui::display::{Display, DisplayExclusive};
+use crate::ui::qr::{QrCode, QUIET_ZONE};
 
 pub const UI_TEXT_FONT: MonoFont = FONT_10X20;
 pub const SMALL_TEXT_FONT: MonoFont = FONT_8X13;
@@ -168,6 +169,55 @@ pub fn draw_button_legend(target: &mut DisplayExclusive, lower: &str, upper: &st
     Rectangle::with_corners(Point::new(224, 26), Point::new(240, 214))
 }
 
+/// Draw `text` as QR code
+///
+/// The code is centered on `anchor` and scaled up as far as it fits into a
+/// square of `max_size` pixels (including the quiet zone).
+/// Nothing is drawn if the text is empty or too long to be encoded.
+pub fn draw_qr_code(
+    target: &mut DisplayExclusive,
+    anchor: Point,
+    max_size: u32,
+    text: &str,
+) -> Option<Rectangle> {
+    if text.is_empty() {
+        return None;
+    }
+
+    let qr = QrCode::encode(text.as_bytes())?;
+
+    let modules = (qr.size() + 2 * QUIET_ZONE) as u32;
+    let scale = (max_size / modules).max(1);
+    let side = modules * scale;
+
+    let bounding = Rectangle::with_center(anchor, Size::new_equal(side));
+
+    // Scanners expect dark modules on a light background, so draw the
+    // background (including the quiet zone) first and punch the dark
+    // modules into it.
+    bounding
+        .into_styled(PrimitiveStyle::with_fill(BinaryColor::On))
+        .draw(target)
+        .unwrap();
+
+    let origin = bounding.top_left + Point::new_equal((QUIET_ZONE as u32 * scale) as i32);
+
+    for y in 0..qr.size() {
+        for x in 0..qr.size() {
+            if qr.get(x, y) {
+                let offset = Point::new((x as u32 * scale) as i32, (y as u32 * scale) as i32);
+
+                Rectangle::new(origin + offset, Size::new_equal(scale))
+                    .into_styled(PrimitiveStyle::with_fill(BinaryColor::Off))
+                    .draw(target)
+                    .unwrap();
+            }
+        }
+    }
+
+    Some(bounding)
+}
+
 impl<T: Serialize + DeserializeOwned + Send + Sync + Clone + 'static> DynamicWidget<T> {
     /// Create a generic dynamic widget
     ///
@@ -339,6 +389,27 @@ impl<T: Serialize + DeserializeOwned + Send + Sync + Clone + 'static> DynamicWid
         )
     }
 
+    /// Draw a self-updating QR code
+    ///
+    /// See `draw_qr_code` for how the `anchor` and `max_size` are used.
+    pub fn qr_code(
+        topic: Arc<Topic<T>>,
+        display: Arc<Display>,
+        anchor: Point,
+        max_size: u32,
+        format_fn: Box<dyn TextFormatFn<T> + Sync + Send>,
+    ) -> Self {
+        Self::new(
+            topic,
+            display,
+            Box::new(move |msg, target| {
+                let text = format_fn(msg);
+
+                draw_qr_code(target, anchor, max_size, &text)
+            }),
+        )
+    }
+
     /// Draw self-updating text with configurable alignment
     pub fn text_aligned(
         topic: Arc<Topic<T>>,
@@ -15,19 +15,29 @@
 // with this program; if not, write to the Free Software Foundation, Inc.,
 // 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
 
-use std::path::Path;
-use std::time::Duration;
+use std::collections::VecDeque;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::time::{Duration, Instant};
 
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, bail, Result};
 use async_std::prelude::*;
-use async_std::sync::Arc;
+use async_std::sync::{Arc, Mutex};
 use async_std::task::sleep;
+use futures::stream::{select, select_all};
+use log::warn;
 use serde::{Deserialize, Serialize};
+use tide::Server;
 
 use crate::adc::CalibratedChannel;
 use crate::broker::{BrokerBuilder, Topic};
+use crate::conflicts::{ConflictGuard, RequestSource, SourcedRequest};
+use crate::fs_root;
+use crate::measurement::Timestamp;
 use crate::watched_tasks::WatchedTasksBuilder;
 
+mod presets;
+
 #[cfg(feature = "demo_mode")]
 mod rw {
     use std::collections::HashMap;
@@ -94,7 +104,7 @@ mod rw {
 
         for (path_tail, iio_channel) in DISABLE_CHANNELS {
             if path.ends_with(path_tail) {
-                let iio_thread = block_on(IioThread::new_stm32(&(), ())).unwrap();
+                let iio_thread = block_on(IioThread::new_stm32(&(), &(), ())).unwrap();
 
                 iio_thread
                     .get_channel(iio_channel)
@@ -122,6 +132,38 @@ use rw::{read_to_string, write};
 
 const POLL_INTERVAL: Duration = Duration::from_secs(1);
 
+/// Port currents are sampled more often when looking for faults, as the
+/// current spikes of a shorted port are quite short.
+const FAULT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A port is considered faulty if its current jumped above the per-port
+/// limit (after having been below it) FAULT_SPIKE_COUNT times in the last
+/// FAULT_WINDOW samples.
+const FAULT_WINDOW: u32 = 20;
+const FAULT_SPIKE_COUNT: u32 = 5;
+
+/// Time (in milliseconds) the current has to stay above the per-port limit
+/// before the port is turned off if the power budget is enforced.
+const BUDGET_DEFAULT_DEBOUNCE: u64 = 1000;
+
+/// Longer delays between ports in a preset are most likely a mistake (e.g.
+/// seconds instead of milliseconds). Port switching and fault handling
+/// wait for the whole preset, so they are capped to this.
+const MAX_DELAY_MS: u64 = 10_000;
+
+/// Upper limits for the overload config, as it can be set via the API
+const MAX_OVERLOAD_WINDOW: u64 = 60_000;
+const MAX_OVERLOAD_HOLD: u64 = 600_000;
+const MAX_OVERLOAD_UTILIZATION: f32 = 2.0;
+
+/// More than enough samples to cover MAX_OVERLOAD_WINDOW at POLL_INTERVAL
+const MAX_OVERLOAD_SAMPLES: usize = 64;
+
+/// Time to wait between switching two ports in a bulk operation.
+/// Turning on all ports at once would add up the inrush currents of the
+/// attached devices.
+const BULK_SWITCH_DELAY: Duration = Duration::from_millis(200);
+
 const PORTS: &[(&str, &str)] = &[
     (
         "port1",
@@ -137,17 +179,21 @@ const PORTS: &[(&str, &str)] = &[
     ),
 ];
 
+/// Check if the ports of the internal USB hub are visible to the system
+///
+/// The ports are only listed in sysfs once the hub was enumerated.
+pub fn hub_present() -> bool {
+    PORTS
+        .iter()
+        .all(|(_, base)| read_to_string(fs_root::path(base).join("disable")).is_ok())
+}
+
 // The total current for all ports is limited to 700mA, the per-port current is
 // limited to 500mA.
 pub const MAX_TOTAL_CURRENT: f32 = 0.7;
 pub const MAX_PORT_CURRENT: f32 = 0.5;
 
-// The measurement is not _that_ exact so start warning at 90% utilization.
-const CURRENT_MARGIN: f32 = 0.9;
-const WARN_TOTAL_CURRENT: f32 = MAX_TOTAL_CURRENT * CURRENT_MARGIN;
-const WARN_PORT_CURRENT: f32 = MAX_PORT_CURRENT * CURRENT_MARGIN;
-
-#[derive(Serialize, Deserialize, Clone, PartialEq)]
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
 pub enum OverloadedPort {
     Total,
     Port1,
@@ -156,20 +202,316 @@ pub enum OverloadedPort {
 }
 
 impl OverloadedPort {
-    fn from_currents(total: f32, port1: f32, port2: f32, port3: f32) -> Option<Self> {
+    /// The total current and the currents of port 1 to 3
+    fn current(&self, currents: &[f32; 4]) -> f32 {
+        match self {
+            Self::Total => currents[0],
+            Self::Port1 => currents[1],
+            Self::Port2 => currents[2],
+            Self::Port3 => currents[3],
+        }
+    }
+
+    fn limit(&self) -> f32 {
+        match self {
+            Self::Total => MAX_TOTAL_CURRENT,
+            Self::Port1 | Self::Port2 | Self::Port3 => MAX_PORT_CURRENT,
+        }
+    }
+
+    fn is_above(&self, currents: &[f32; 4], utilization: f32) -> bool {
+        self.current(currents) > self.limit() * utilization
+    }
+
+    fn from_currents(currents: &[f32; 4], utilization: f32) -> Option<Self> {
         // Based on the maximum / per-port limits it should not be possible for two
         // individual ports to be overloaded at the same time while the total is not
         // overloaded, so reporting either "total" or one of the ports should be
         // sufficient.
+        [Self::Total, Self::Port1, Self::Port2, Self::Port3]
+            .iter()
+            .find(|port| port.is_above(currents, utilization))
+            .cloned()
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct OverloadConfig {
+    /// Report an overload once the averaged current exceeds this part of
+    /// the limit. The measurement is not _that_ exact, so the default is to
+    /// start warning at 90% utilization.
+    pub enter: f32,
+    /// Stop reporting the overload once the averaged current drops below
+    /// this part of the limit
+    pub exit: f32,
+    /// Report an overload for at least this long (in milliseconds)
+    pub hold: u64,
+    /// Average the currents over this time window (in milliseconds)
+    pub window: u64,
+}
+
+impl Default for OverloadConfig {
+    fn default() -> Self {
+        Self {
+            enter: 0.9,
+            exit: 0.8,
+            hold: 5000,
+            window: 5000,
+        }
+    }
+}
+
+impl OverloadConfig {
+    /// Check a config requested via the API
+    fn check(&self) -> Result<()> {
+        for (name, val) in [("enter", self.enter), ("exit", self.exit)] {
+            if !(0.0..=MAX_OVERLOAD_UTILIZATION).contains(&val) {
+                bail!("{name} {val} is not between 0 and {MAX_OVERLOAD_UTILIZATION}");
+            }
+        }
+
+        if self.window > MAX_OVERLOAD_WINDOW {
+            bail!(
+                "Window {}ms is longer than {MAX_OVERLOAD_WINDOW}ms",
+                self.window
+            );
+        }
+
+        if self.hold > MAX_OVERLOAD_HOLD {
+            bail!(
+                "Hold time {}ms is longer than {MAX_OVERLOAD_HOLD}ms",
+                self.hold
+            );
+        }
+
+        Ok(())
+    }
+}
+
+/// Debounce overload warnings for devices with bursty current draw
+struct OverloadDetector {
+    samples: VecDeque<(Instant, [f32; 4])>,
+    state: Option<(OverloadedPort, Instant)>,
+}
+
+impl OverloadDetector {
+    fn new() -> Self {
+        Self {
+            samples: VecDeque::new(),
+            state: None,
+        }
+    }
+
+    fn average(&self) -> [f32; 4] {
+        let mut sum = [0.0; 4];
+
+        for (_, currents) in &self.samples {
+            for (s, c) in sum.iter_mut().zip(currents) {
+                *s += c;
+            }
+        }
+
+        sum.map(|s| s / self.samples.len().max(1) as f32)
+    }
+
+    /// Add a sample of the total current and the currents of port 1 to 3
+    /// and return the port to report as overloaded
+    fn step(
+        &mut self,
+        currents: [f32; 4],
+        now: Instant,
+        config: &OverloadConfig,
+    ) -> Option<OverloadedPort> {
+        let window = Duration::from_millis(config.window);
+
+        self.samples.push_back((now, currents));
+
+        while let Some((ts, _)) = self.samples.front() {
+            let expired = now.saturating_duration_since(*ts) > window;
+
+            if !expired && self.samples.len() <= MAX_OVERLOAD_SAMPLES {
+                break;
+            }
+
+            self.samples.pop_front();
+        }
+
+        let average = self.average();
+
+        // An exit threshold above the enter threshold would make the state
+        // flap on every sample.
+        let exit = config.exit.min(config.enter);
+
+        if let Some((port, since)) = &self.state {
+            let held = now.saturating_duration_since(*since) < Duration::from_millis(config.hold);
+
+            if held || port.is_above(&average, exit) {
+                return Some(port.clone());
+            }
+        }
+
+        self.state = OverloadedPort::from_currents(&average, config.enter).map(|port| {
+            match &self.state {
+                // Keep the time the overload started if the port did not change
+                Some((prev, since)) if *prev == port => (port, *since),
+                _ => (port, now),
+            }
+        });
+
+        self.state.as_ref().map(|(port, _)| port.clone())
+    }
+}
+
+/// The reason a port was turned off automatically
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub enum UsbPortFault {
+    /// The hub reported an over-current condition on the port
+    OverCurrent,
+    /// The current repeatedly hit the per-port limit, e.g. because VBUS is
+    /// shorted or a device drags the rail down
+    VbusShort,
+    /// The current stayed above the per-port limit for longer than the
+    /// configured debounce time while the power budget was enforced
+    OverBudget,
+}
+
+/// Information about the last time a port was turned off automatically
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct UsbPortTrip {
+    pub port: String,
+    pub reason: UsbPortFault,
+    /// The current (in Ampere) measured when the port was turned off
+    pub current: f32,
+    pub ts: Timestamp,
+}
+
+/// Configuration of the per-port power budget enforcement, shared by all
+/// ports
+#[derive(Clone)]
+struct Budget {
+    enforce: Arc<Topic<bool>>,
+    debounce: Arc<Topic<u64>>,
+    last_trip: Arc<Topic<Option<UsbPortTrip>>>,
+}
+
+impl Budget {
+    fn new(bb: &mut BrokerBuilder) -> Self {
+        Self {
+            enforce: bb.topic(
+                "/v1/usb/host/budget/enforce",
+                true,
+                true,
+                true,
+                Some(false),
+                1,
+            ),
+            debounce: bb.topic(
+                "/v1/usb/host/budget/debounce",
+                true,
+                true,
+                true,
+                Some(BUDGET_DEFAULT_DEBOUNCE),
+                1,
+            ),
+            last_trip: bb.topic_ro("/v1/usb/host/last_trip", Some(None)),
+        }
+    }
+}
+
+/// Check if a port exceeds its power budget for longer than allowed
+struct BudgetGuard {
+    over_since: Option<Instant>,
+}
+
+impl BudgetGuard {
+    fn new() -> Self {
+        Self { over_since: None }
+    }
+
+    /// Add a current measurement and return true if the port should be
+    /// turned off
+    fn step(&mut self, current: f32, now: Instant, debounce: Duration) -> bool {
+        if current <= MAX_PORT_CURRENT {
+            self.over_since = None;
+            return false;
+        }
+
+        let since = *self.over_since.get_or_insert(now);
+
+        now.duration_since(since) >= debounce
+    }
+}
+
+/// Look for current spike patterns that hint at a shorted VBUS
+///
+/// A device that constantly draws too much current is reported as an
+/// overload, but a short circuit is usually not visible as a constant
+/// current, as the current limiter of the port repeatedly cuts it off.
+/// Only jumps above the limit are counted, so that a sustained overload
+/// is left to the power budget enforcement.
+struct SpikeDetector {
+    /// One bit per sample, set if the current jumped above the limit.
+    /// The most recent sample is in the least significant bit.
+    spikes: u32,
+    was_above: bool,
+}
+
+impl SpikeDetector {
+    fn new() -> Self {
+        Self {
+            spikes: 0,
+            was_above: false,
+        }
+    }
+
+    /// Add a current measurement and return true if the port looks shorted
+    fn step(&mut self, current: f32) -> bool {
+        let above = current > MAX_PORT_CURRENT;
+        let spike = above && !self.was_above;
+        let mask = (1 << FAULT_WINDOW) - 1;
 
-        if total > WARN_TOTAL_CURRENT {
-            Some(Self::Total)
-        } else if port1 > WARN_PORT_CURRENT {
-            Some(Self::Port1)
-        } else if port2 > WARN_PORT_CURRENT {
-            Some(Self::Port2)
-        } else if port3 > WARN_PORT_CURRENT {
-            Some(Self::Port3)
+        self.was_above = above;
+        self.spikes = ((self.spikes << 1) | u32::from(spike)) & mask;
+        self.spikes.count_ones() >= FAULT_SPIKE_COUNT
+    }
+}
+
+/// Decide if (and why) a powered port should be turned off
+struct FaultMonitor {
+    detector: SpikeDetector,
+    guard: BudgetGuard,
+}
+
+impl FaultMonitor {
+    fn new() -> Self {
+        Self {
+            detector: SpikeDetector::new(),
+            guard: BudgetGuard::new(),
+        }
+    }
+
+    /// Add a current measurement and return the fault to turn the port
+    /// off for (if any)
+    ///
+    /// Sustained overloads only turn the port off if the power budget is
+    /// enforced.
+    fn step(
+        &mut self,
+        over_current: bool,
+        current: f32,
+        now: Instant,
+        debounce: Duration,
+        enforce: bool,
+    ) -> Option<UsbPortFault> {
+        let shorted = self.detector.step(current);
+        let over_budget = self.guard.step(current, now, debounce) && enforce;
+
+        if over_current {
+            Some(UsbPortFault::OverCurrent)
+        } else if shorted {
+            Some(UsbPortFault::VbusShort)
+        } else if over_budget {
+            Some(UsbPortFault::OverBudget)
         } else {
             None
         }
@@ -184,11 +526,60 @@ pub struct UsbDevice {
     product: String,
 }
 
+/// A named combination of port power states that can be applied at once
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct UsbPreset {
+    pub name: String,
+    /// The power state for ports 1 to 3. Ports set to `None` are left alone.
+    pub ports: [Option<bool>; 3],
+    /// Time to wait between switching two ports in milliseconds
+    pub delay_ms: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+pub struct UsbHostStatus {
+    /// The power state of ports 1 to 3
+    pub powered: [bool; 3],
+    /// The bulk operation that is currently being applied (if any)
+    pub applying: Option<String>,
+}
+
+enum BulkRequest {
+    All(bool),
+    Preset(String),
+}
+
 #[derive(Clone)]
 pub struct UsbPort {
     pub request: Arc<Topic<bool>>,
+    /// Requests made on the LCD, which are checked for conflicts with
+    /// requests made via the API (`request`) before they are performed
+    pub lcd_request: Arc<Topic<bool>>,
     pub status: Arc<Topic<bool>>,
     pub device: Arc<Topic<Option<UsbDevice>>>,
+    pub fault: Arc<Topic<Option<UsbPortFault>>>,
+    disable_path: PathBuf,
+}
+
+impl UsbPort {
+    fn switch(&self, on: bool) -> Result<()> {
+        write(&self.disable_path, if on { b"0" } else { b"1" })?;
+
+        // Clear the device info upon power off so it does not contain stale
+        // information until the next poll.
+        if !on {
+            self.device.set(None);
+        }
+
+        // Turning a port back on is what clears a latched fault
+        if on {
+            self.fault.set_if_changed(None);
+        }
+
+        self.status.set(on);
+
+        Ok(())
+    }
 }
 
 pub struct UsbHub {
@@ -196,6 +587,7 @@ pub struct UsbHub {
     pub port1: UsbPort,
     pub port2: UsbPort,
     pub port3: UsbPort,
+    presets: Arc<Topic<Vec<UsbPreset>>>,
 }
 
 fn handle_port(
@@ -203,32 +595,49 @@ fn handle_port(
     wtb: &mut WatchedTasksBuilder,
     name: &'static str,
     base: &'static str,
+    switch_lock: Arc<Mutex<()>>,
+    conflicts: ConflictGuard,
 ) -> Result<UsbPort> {
     let port = UsbPort {
         request: bb.topic_wo(format!("/v1/usb/host/{name}/powered").as_str(), None),
+        lcd_request: Topic::anonymous(None),
         status: bb.topic_ro(format!("/v1/usb/host/{name}/powered").as_str(), None),
         device: bb.topic_ro(format!("/v1/usb/host/{name}/device").as_str(), Some(None)),
+        fault: bb.topic_ro(format!("/v1/usb/host/{name}/fault").as_str(), Some(None)),
+        disable_path: fs_root::path(base).join("disable"),
     };
 
-    let request = port.request.clone();
-    let status = port.status.clone();
-    let device = port.device.clone();
-    let disable_path = Path::new(base).join("disable");
+    let port_task = port.clone();
 
     // Spawn a task that turns USB port power on or off upon request.
-    // Also clears the device info upon power off so it does not contain stale
-    // information until the next poll.
     wtb.spawn_task(format!("usb-hub-{name}-actions"), async move {
-        let (mut src, _) = request.subscribe_unbounded();
+        let (api_src, _) = port_task.request.clone().subscribe_unbounded();
+        let (lcd_src, _) = port_task.lcd_request.clone().subscribe_unbounded();
+
+        let mut src = select(
+            api_src.map(|on| SourcedRequest {
+                source: RequestSource::Api,
+                on,
+            }),
+            lcd_src.map(|on| SourcedRequest {
+                source: RequestSource::Lcd,
+                on,
+            }),
+        );
 
-        while let Some(ev) = src.next().await {
-            write(&disable_path, if ev { b"0" } else { b"1" })?;
+        let output = format!("USB {name}");
 
-            if !ev {
-                device.set(None);
+        while let Some(req) = src.next().await {
+            // Someone on the LCD and an API user may disagree on the state
+            // of the port, in which case one of them has to confirm.
+            if !conflicts.allowed(&output, req).await {
+                continue;
             }
 
-            status.set(ev);
+            // Wait for bulk operations to complete before switching
+            let _guard = switch_lock.lock().await;
+
+            port_task.switch(req.on)?;
         }
 
         Ok(())
@@ -236,9 +645,9 @@ fn handle_port(
 
     let status = port.status.clone();
     let device = port.device.clone();
-    let disable_path = Path::new(base).join("disable");
+    let disable_path = fs_root::path(base).join("disable");
     let (id_product_path, id_vendor_path, manufacturer_path, product_path) = {
-        let device_path = Path::new(base).join("device");
+        let device_path = fs_root::path(base).join("device");
         (
             device_path.join("idProduct"),
             device_path.join("idVendor"),
@@ -295,16 +704,43 @@ fn handle_overloads(
 ) -> Result<Arc<Topic<Option<OverloadedPort>>>> {
     let overload = bb.topic_ro("/v1/usb/host/overload", None);
 
+    // Use the "register a read-only and a write-only topic with the same
+    // name" trick to only use (and report) validated configs.
+    let request = bb.topic(
+        "/v1/usb/host/overload/config",
+        false,
+        true,
+        true,
+        Some(OverloadConfig::default()),
+        1,
+    );
+    let config = bb.topic_ro("/v1/usb/host/overload/config", None);
+
+    let (mut requests, _) = request.subscribe_unbounded();
+    let config_task = config.clone();
+
+    wtb.spawn_task("usb-hub-overload-config", async move {
+        while let Some(req) = requests.next().await {
+            match req.check() {
+                Ok(()) => config_task.set(req),
+                Err(e) => warn!("Refusing USB overload config {req:?}: {e}"),
+            }
+        }
+
+        Ok(())
+    })?;
+
     let overload_task = overload.clone();
 
     wtb.spawn_task("usb-hub-overload-state", async move {
+        let mut detector = OverloadDetector::new();
+
         loop {
-            let overloaded_port = OverloadedPort::from_currents(
-                total.get().map(|m| m.value).unwrap_or(0.0),
-                port1.get().map(|m| m.value).unwrap_or(0.0),
-                port2.get().map(|m| m.value).unwrap_or(0.0),
-                port3.get().map(|m| m.value).unwrap_or(0.0),
-            );
+            let currents = [&total, &port1, &port2, &port3]
+                .map(|channel| channel.get().map(|m| m.value).unwrap_or(0.0));
+
+            let config = config.try_get().unwrap_or_default();
+            let overloaded_port = detector.step(currents, Instant::now(), &config);
 
             overload_task.set_if_changed(overloaded_port);
 
@@ -315,6 +751,215 @@ fn handle_overloads(
     Ok(overload)
 }
 
+/// Turn a port off if the hub reports an over-current condition, the
+/// current shows the spike pattern of a shorted VBUS or (if enforced) the
+/// port exceeds its power budget
+///
+/// The port stays off (and the fault is reported) until it is turned
+/// on again by request.
+fn handle_faults(
+    wtb: &mut WatchedTasksBuilder,
+    name: &'static str,
+    base: &'static str,
+    port: UsbPort,
+    current: CalibratedChannel,
+    switch_lock: Arc<Mutex<()>>,
+    budget: Budget,
+) -> Result<()> {
+    // Not all hubs provide this counter
+    let over_current_count_path = fs_root::path(base).join("over_current_count");
+    let read_over_current_count = move || -> Option<u64> {
+        read_to_string(&over_current_count_path)
+            .ok()
+            .and_then(|count| count.trim().parse().ok())
+    };
+
+    wtb.spawn_task(format!("usb-hub-{name}-faults"), async move {
+        let mut monitor = FaultMonitor::new();
+        let mut last_over_current_count = read_over_current_count();
+
+        loop {
+            sleep(FAULT_POLL_INTERVAL).await;
+
+            let over_current_count = read_over_current_count();
+            let over_current = match (last_over_current_count, over_current_count) {
+                (Some(last), Some(now)) => now > last,
+                _ => false,
+            };
+
+            last_over_current_count = over_current_count;
+
+            if port.status.try_get() != Some(true) {
+                monitor = FaultMonitor::new();
+                continue;
+            }
+
+            let curr = current.get().map(|m| m.value).unwrap_or(0.0);
+            let debounce = budget.debounce.try_get().unwrap_or(BUDGET_DEFAULT_DEBOUNCE);
+            let enforce = budget.enforce.try_get().unwrap_or(false);
+
+            let fault = match monitor.step(
+                over_current,
+                curr,
+                Instant::now(),
+                Duration::from_millis(debounce),
+                enforce,
+            ) {
+                Some(fault) => fault,
+                None => continue,
+            };
+
+            warn!("Turning off USB {name} due to {fault:?}");
+
+            let _guard = switch_lock.lock().await;
+
+            port.switch(false)?;
+            port.fault.set(Some(fault));
+
+            budget.last_trip.set(Some(UsbPortTrip {
+                port: name.to_string(),
+                reason: fault,
+                current: curr,
+                ts: Timestamp::now(),
+            }));
+
+            monitor = FaultMonitor::new();
+        }
+    })?;
+
+    Ok(())
+}
+
+/// Switch multiple ports at once, either all of them or using a preset
+///
+/// Bulk operations are applied one after another, in port order and with
+/// a delay between ports. Requests for individual ports wait until a bulk
+/// operation is complete.
+fn handle_bulk(
+    bb: &mut BrokerBuilder,
+    wtb: &mut WatchedTasksBuilder,
+    ports: [UsbPort; 3],
+    switch_lock: Arc<Mutex<()>>,
+) -> Result<Arc<Topic<Vec<UsbPreset>>>> {
+    let all_request = bb.topic_wo::<bool>("/v1/usb/host/all/powered", None);
+    let all_status = bb.topic_ro::<bool>("/v1/usb/host/all/powered", None);
+    let presets: Arc<Topic<Vec<UsbPreset>>> = bb.topic(
+        "/v1/usb/host/presets",
+        true,
+        true,
+        true,
+        Some(Vec::new()),
+        1,
+    );
+    let apply = bb.topic_wo::<String>("/v1/usb/host/presets/apply", None);
+    let status = bb.topic_ro("/v1/usb/host/status", None);
+    let applying: Arc<Topic<Option<String>>> = Topic::anonymous(Some(None));
+
+    let ports_task = ports.clone();
+    let applying_task = applying.clone();
+
+    // Combine the power states and the bulk operation into a single topic
+    wtb.spawn_task("usb-hub-status", async move {
+        let mut events: Vec<Pin<Box<dyn Stream<Item = ()> + Send>>> = Vec::new();
+
+        for port in ports_task.iter() {
+            let (port_events, _) = port.status.clone().subscribe_unbounded();
+            events.push(Box::pin(port_events.map(|_| ())));
+        }
+
+        let (applying_events, _) = applying_task.clone().subscribe_unbounded();
+        events.push(Box::pin(applying_events.map(|_| ())));
+
+        let mut events = select_all(events);
+
+        while events.next().await.is_some() {
+            let powered = [
+                ports_task[0].status.try_get().unwrap_or(false),
+                ports_task[1].status.try_get().unwrap_or(false),
+                ports_task[2].status.try_get().unwrap_or(false),
+            ];
+
+            all_status.set_if_changed(powered.iter().all(|p| *p));
+
+            status.set_if_changed(UsbHostStatus {
+                powered,
+                applying: applying_task.try_get().flatten(),
+            });
+        }
+
+        Ok(())
+    })?;
+
+    let presets_task = presets.clone();
+
+    let (all_events, _) = all_request.subscribe_unbounded();
+    let (apply_events, _) = apply.subscribe_unbounded();
+
+    let mut requests = select(
+        all_events.map(BulkRequest::All),
+        apply_events.map(BulkRequest::Preset),
+    );
+
+    wtb.spawn_task("usb-hub-bulk", async move {
+        while let Some(req) = requests.next().await {
+            let (name, targets, delay) = match req {
+                BulkRequest::All(on) => {
+                    let name = if on { "all on" } else { "all off" };
+                    (name.to_string(), [Some(on); 3], BULK_SWITCH_DELAY)
+                }
+                BulkRequest::Preset(name) => {
+                    let preset = presets_task
+                        .try_get()
+                        .unwrap_or_default()
+                        .into_iter()
+                        .find(|p| p.name == name);
+
+                    match preset {
+                        Some(p) => {
+                            let delay = p
+                                .delay_ms
+                                .map(|ms| Duration::from_millis(ms.min(MAX_DELAY_MS)));
+
+                            (p.name, p.ports, delay.unwrap_or(BULK_SWITCH_DELAY))
+                        }
+                        None => {
+                            warn!("Requested unknown USB port preset \"{name}\"");
+                            continue;
+                        }
+                    }
+                }
+            };
+
+            let _guard = switch_lock.lock().await;
+
+            applying.set(Some(name));
+
+            let mut first = true;
+
+            for (port, target) in ports.iter().zip(targets) {
+                let on = match target {
+                    Some(on) if port.status.try_get() != Some(on) => on,
+                    _ => continue,
+                };
+
+                if !first {
+                    sleep(delay).await;
+                }
+
+                first = false;
+
+                port.switch(on)?;
+            }
+
+            applying.set(None);
+        }
+
+        Ok(())
+    })?;
+
+    Ok(presets)
+}
+
 impl UsbHub {
     pub fn new(
         bb: &mut BrokerBuilder,
@@ -323,24 +968,267 @@ impl UsbHub {
         port1: CalibratedChannel,
         port2: CalibratedChannel,
         port3: CalibratedChannel,
+        conflicts: ConflictGuard,
     ) -> Result<Self> {
+        let currents = [port1.clone(), port2.clone(), port3.clone()];
+
         let overload = handle_overloads(bb, wtb, total, port1, port2, port3)?;
 
-        let mut ports = PORTS
-            .iter()
-            .map(|(name, base)| handle_port(bb, wtb, name, base));
+        // Switching operations on the ports must not interleave
+        let switch_lock = Arc::new(Mutex::new(()));
+
+        let mut ports = PORTS.iter().map(|(name, base)| {
+            handle_port(bb, wtb, name, base, switch_lock.clone(), conflicts.clone())
+        });
+
+        let port1 = ports
+            .next()
+            .ok_or_else(|| anyhow!("Failed to find USB port 1"))??;
+        let port2 = ports
+            .next()
+            .ok_or_else(|| anyhow!("Failed to find USB port 2"))??;
+        let port3 = ports
+            .next()
+            .ok_or_else(|| anyhow!("Failed to find USB port 3"))??;
+
+        let ports = [port1.clone(), port2.clone(), port3.clone()];
+        let budget = Budget::new(bb);
+
+        for ((port, current), (name, base)) in ports.iter().zip(currents).zip(PORTS) {
+            handle_faults(
+                wtb,
+                name,
+                base,
+                port.clone(),
+                current,
+                switch_lock.clone(),
+                budget.clone(),
+            )?;
+        }
+
+        let presets = handle_bulk(bb, wtb, ports, switch_lock)?;
 
         Ok(Self {
             overload,
-            port1: ports
-                .next()
-                .ok_or_else(|| anyhow!("Failed to find USB port 1"))??,
-            port2: ports
-                .next()
-                .ok_or_else(|| anyhow!("Failed to find USB port 2"))??,
-            port3: ports
-                .next()
-                .ok_or_else(|| anyhow!("Failed to find USB port 3"))??,
+            port1,
+            port2,
+            port3,
+            presets,
         })
     }
+
+    /// Add web endpoints to share presets between TACs
+    pub fn serve_presets(&self, server: &mut Server<()>) {
+        presets::serve(server, self.presets.clone());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{
+        BudgetGuard, FaultMonitor, OverloadConfig, OverloadDetector, OverloadedPort, SpikeDetector,
+        UsbPortFault, FAULT_POLL_INTERVAL, FAULT_SPIKE_COUNT, FAULT_WINDOW, MAX_OVERLOAD_SAMPLES,
+        MAX_PORT_CURRENT, MAX_TOTAL_CURRENT,
+    };
+
+    const SPIKE: f32 = MAX_PORT_CURRENT * 1.5;
+    const NORMAL: f32 = MAX_PORT_CURRENT * 0.5;
+
+    #[test]
+    fn spike_detector() {
+        let mut detector = SpikeDetector::new();
+
+        println!("A steady current below the limit is fine");
+        for _ in 0..(FAULT_WINDOW * 2) {
+            assert!(!detector.step(NORMAL));
+        }
+
+        println!("Few spikes spread out over time are fine");
+        for _ in 0..(FAULT_WINDOW * 2) {
+            assert!(!detector.step(SPIKE));
+
+            for _ in 0..(FAULT_WINDOW / (FAULT_SPIKE_COUNT - 1)) {
+                assert!(!detector.step(NORMAL));
+            }
+        }
+
+        println!("A constant overload is not a short");
+        let mut detector = SpikeDetector::new();
+
+        for _ in 0..(FAULT_WINDOW * 2) {
+            assert!(!detector.step(SPIKE));
+        }
+
+        println!("Repeated spikes are a fault");
+        let mut detector = SpikeDetector::new();
+
+        for _ in 0..(FAULT_SPIKE_COUNT - 1) {
+            assert!(!detector.step(SPIKE));
+            assert!(!detector.step(NORMAL));
+        }
+
+        assert!(detector.step(SPIKE));
+    }
+
+    #[test]
+    fn budget_guard() {
+        let debounce = Duration::from_secs(1);
+        let start = Instant::now();
+        let mut guard = BudgetGuard::new();
+
+        println!("A current below the limit is fine");
+        assert!(!guard.step(NORMAL, start, debounce));
+        assert!(!guard.step(NORMAL, start + debounce * 2, debounce));
+
+        println!("Short overloads are fine as well");
+        let start = start + debounce * 3;
+        assert!(!guard.step(SPIKE, start, debounce));
+        assert!(!guard.step(SPIKE, start + debounce / 2, debounce));
+        assert!(!guard.step(NORMAL, start + debounce * 3 / 4, debounce));
+        assert!(!guard.step(SPIKE, start + debounce * 3 / 2, debounce));
+
+        println!("But overloads longer than the debounce time are not");
+        assert!(guard.step(SPIKE, start + debounce * 5 / 2, debounce));
+    }
+
+    #[test]
+    fn fault_monitor() {
+        let debounce = Duration::from_secs(1);
+        let start = Instant::now();
+        let at = |n: u32| start + FAULT_POLL_INTERVAL * n;
+        let samples = FAULT_WINDOW * 4;
+
+        println!("A sustained overload keeps the port on if not enforced");
+        let mut monitor = FaultMonitor::new();
+        for n in 0..samples {
+            assert_eq!(monitor.step(false, SPIKE, at(n), debounce, false), None);
+        }
+
+        println!("If enforced it is turned off as over budget after the debounce time");
+        let mut monitor = FaultMonitor::new();
+        let tripped = (0..samples)
+            .find_map(|n| {
+                monitor
+                    .step(false, SPIKE, at(n), debounce, true)
+                    .map(|fault| (n, fault))
+            })
+            .unwrap();
+        assert_eq!(tripped.1, UsbPortFault::OverBudget);
+        assert!(FAULT_POLL_INTERVAL * tripped.0 >= debounce);
+
+        println!("A shorted port is turned off regardless of enforcement");
+        let mut monitor = FaultMonitor::new();
+        let mut fault = None;
+        for n in 0..samples {
+            let current = if n % 2 == 0 { SPIKE } else { NORMAL };
+            fault = fault.or(monitor.step(false, current, at(n), debounce, false));
+        }
+        assert_eq!(fault, Some(UsbPortFault::VbusShort));
+
+        println!("Hub reported over-currents take precedence");
+        let mut monitor = FaultMonitor::new();
+        assert_eq!(
+            monitor.step(true, NORMAL, at(0), debounce, false),
+            Some(UsbPortFault::OverCurrent)
+        );
+    }
+
+    #[test]
+    fn overload_config() {
+        assert!(OverloadConfig::default().check().is_ok());
+
+        let config = |enter, exit, hold, window| OverloadConfig {
+            enter,
+            exit,
+            hold,
+            window,
+        };
+
+        println!("Utilizations and times have to be in range");
+        assert!(config(-0.1, 0.8, 5000, 5000).check().is_err());
+        assert!(config(0.9, f32::NAN, 5000, 5000).check().is_err());
+        assert!(config(0.9, 0.8, u64::MAX, 5000).check().is_err());
+        assert!(config(0.9, 0.8, 5000, u64::MAX).check().is_err());
+    }
+
+    #[test]
+    fn overload_detector() {
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let config = OverloadConfig {
+            enter: 0.9,
+            exit: 0.8,
+            hold: 3000,
+            window: 2000,
+        };
+
+        let port1 = |current: f32| [current, current, 0.0, 0.0];
+        let mut detector = OverloadDetector::new();
+
+        println!("Short bursts are averaged out");
+        assert_eq!(detector.step(port1(0.1), at(0), &config), None);
+        assert_eq!(detector.step(port1(0.1), at(1), &config), None);
+        assert_eq!(detector.step(port1(0.6), at(2), &config), None);
+        assert_eq!(detector.step(port1(0.1), at(3), &config), None);
+
+        println!("A sustained overload is reported");
+        assert_eq!(detector.step(port1(0.6), at(4), &config), None);
+        assert_eq!(detector.step(port1(0.6), at(5), &config), None);
+        assert_eq!(
+            detector.step(port1(0.6), at(6), &config),
+            Some(OverloadedPort::Port1)
+        );
+
+        println!("The overload is reported for at least the hold time");
+        assert_eq!(
+            detector.step(port1(0.0), at(7), &config),
+            Some(OverloadedPort::Port1)
+        );
+        assert_eq!(
+            detector.step(port1(0.0), at(8), &config),
+            Some(OverloadedPort::Port1)
+        );
+        assert_eq!(detector.step(port1(0.0), at(9), &config), None);
+
+        println!("Between the enter and exit threshold the state is kept");
+        let mut detector = OverloadDetector::new();
+        let between = MAX_PORT_CURRENT * 0.85;
+
+        assert_eq!(
+            detector.step(port1(MAX_PORT_CURRENT), at(0), &config),
+            Some(OverloadedPort::Port1)
+        );
+        for secs in 1..10 {
+            assert_eq!(
+                detector.step(port1(between), at(secs), &config),
+                Some(OverloadedPort::Port1)
+            );
+        }
+
+        let mut detector = OverloadDetector::new();
+        for secs in 0..10 {
+            assert_eq!(detector.step(port1(between), at(secs), &config), None);
+        }
+
+        println!("The number of samples is limited, even for long windows");
+        let mut detector = OverloadDetector::new();
+        let long = OverloadConfig {
+            window: u64::MAX,
+            ..config.clone()
+        };
+        for secs in 0..(MAX_OVERLOAD_SAMPLES as u64 * 2) {
+            detector.step(port1(0.0), at(secs), &long);
+        }
+        assert_eq!(detector.samples.len(), MAX_OVERLOAD_SAMPLES);
+
+        println!("The total current takes precedence");
+        let mut detector = OverloadDetector::new();
+        let currents = [MAX_TOTAL_CURRENT, MAX_PORT_CURRENT, 0.0, 0.0];
+        assert_eq!(
+            detector.step(currents, at(0), &config),
+            Some(OverloadedPort::Total)
+        );
+    }
 }
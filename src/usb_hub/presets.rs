@@ -0,0 +1,351 @@
+// This file is part of tacd, the LXA TAC system daemon
+// Copyright (C) 2024 Pengutronix e.K.
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along
+// with this program; if not, write to the Free Software Foundation, Inc.,
+// 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+
+//! Share USB port presets between TACs
+//!
+//! Presets are exported as a self-contained JSON document that carries a
+//! schema version, so that documents written by older versions of the tacd
+//! can still be recognized when the format changes.
+
+use std::collections::HashSet;
+use std::fs::{create_dir_all, OpenOptions};
+use std::io::Write;
+
+use anyhow::{bail, Result};
+use async_std::sync::Arc;
+use chrono::Local;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use tide::{http::mime, Request, Response, Server};
+
+use super::{UsbPreset, MAX_DELAY_MS};
+use crate::broker::Topic;
+use crate::fs_root;
+use crate::http_server::WriteAccess;
+
+const AUDIT_LOG_PATH: &str = "/srv/tacd/usb-preset-imports.log";
+
+/// Version of the export format. Bump it on incompatible changes.
+const SCHEMA_VERSION: u32 = 1;
+
+const MAX_NAME_LEN: usize = 64;
+const MAX_PRESETS: usize = 64;
+
+/// A set of presets as it is exported and imported
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct PresetExport {
+    pub schema_version: u32,
+    pub presets: Vec<UsbPreset>,
+}
+
+/// What to do with imported presets that have the same name as an existing one
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+enum OnConflict {
+    /// Refuse the whole import
+    #[default]
+    Reject,
+    /// Overwrite the existing preset
+    Replace,
+    /// Import the preset under a new name
+    Rename,
+}
+
+#[derive(Deserialize)]
+struct ExportParams {
+    /// Only export the preset with this name instead of all of them
+    name: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ImportParams {
+    #[serde(default)]
+    on_conflict: OnConflict,
+}
+
+/// Check an imported document before anything is changed
+fn validate(export: &PresetExport) -> Result<()> {
+    if export.schema_version != SCHEMA_VERSION {
+        bail!(
+            "Unsupported schema version {} (expected {SCHEMA_VERSION})",
+            export.schema_version
+        );
+    }
+
+    if export.presets.len() > MAX_PRESETS {
+        bail!("Too many presets (at most {MAX_PRESETS} are allowed)");
+    }
+
+    let mut names = HashSet::new();
+
+    for preset in &export.presets {
+        let name = preset.name.as_str();
+
+        if name.trim().is_empty() || name.len() > MAX_NAME_LEN {
+            bail!("Preset names must be between 1 and {MAX_NAME_LEN} bytes long");
+        }
+
+        if name.chars().any(char::is_control) {
+            bail!(
+                "The name of preset \"{}\" is not printable",
+                name.escape_debug()
+            );
+        }
+
+        if !names.insert(name) {
+            bail!("Preset \"{name}\" is contained more than once");
+        }
+
+        if preset.delay_ms.map_or(false, |d| d > MAX_DELAY_MS) {
+            bail!("The delay of preset \"{name}\" is longer than {MAX_DELAY_MS}ms");
+        }
+    }
+
+    Ok(())
+}
+
+/// Find a name that is not in use yet by appending a counter
+fn free_name(name: &str, taken: &[UsbPreset]) -> String {
+    (2..)
+        .map(|n| format!("{name} ({n})"))
+        .find(|candidate| taken.iter().all(|p| &p.name != candidate))
+        .unwrap()
+}
+
+/// Add the imported presets to the existing ones
+///
+/// Returns the names the presets were imported as or, if conflicts are
+/// rejected, the names of the conflicting presets as error.
+fn merge(
+    existing: &mut Vec<UsbPreset>,
+    imported: Vec<UsbPreset>,
+    on_conflict: OnConflict,
+) -> Result<Vec<String>, Vec<String>> {
+    let conflicts: Vec<String> = imported
+        .iter()
+        .filter(|i| existing.iter().any(|e| e.name == i.name))
+        .map(|i| i.name.clone())
+        .collect();
+
+    if on_conflict == OnConflict::Reject && !conflicts.is_empty() {
+        return Err(conflicts);
+    }
+
+    let mut names = Vec::new();
+
+    for mut preset in imported {
+        match existing.iter().position(|e| e.name == preset.name) {
+            None => existing.push(preset.clone()),
+            Some(idx) if on_conflict == OnConflict::Replace => existing[idx] = preset.clone(),
+            Some(_) => {
+                preset.name = free_name(&preset.name, existing);
+                existing.push(preset.clone());
+            }
+        }
+
+        names.push(preset.name);
+    }
+
+    Ok(names)
+}
+
+/// Append a line to the log of all imports
+fn audit(message: &str) {
+    let path = fs_root::path(AUDIT_LOG_PATH);
+    let line = format!("{} {message}\n", Local::now().to_rfc3339());
+
+    let res = path
+        .parent()
+        .map_or(Ok(()), create_dir_all)
+        .and_then(|_| OpenOptions::new().create(true).append(true).open(&path))
+        .and_then(|mut file| file.write_all(line.as_bytes()));
+
+    if let Err(e) = res {
+        warn!("Failed to write USB preset import audit log: {e}");
+    }
+}
+
+fn plain(status: u16, msg: &str) -> Response {
+    Response::builder(status)
+        .body(msg)
+        .content_type(mime::PLAIN)
+        .build()
+}
+
+/// Add web endpoints to export and import presets
+pub(super) fn serve(server: &mut Server<()>, presets: Arc<Topic<Vec<UsbPreset>>>) {
+    let presets_export = presets.clone();
+
+    server
+        .at("/v1/usb/host/presets/export")
+        .get(move |req: Request<()>| {
+            let presets = presets_export.clone();
+
+            async move {
+                let params: ExportParams = req
+                    .query()
+                    .map_err(|_| tide::Error::from_str(400, "Malformed query parameters"))?;
+
+                let mut presets = presets.try_get().unwrap_or_default();
+
+                if let Some(name) = params.name {
+                    presets.retain(|p| p.name == name);
+
+                    if presets.is_empty() {
+                        return Ok(plain(404, "No such preset"));
+                    }
+                }
+
+                let export = PresetExport {
+                    schema_version: SCHEMA_VERSION,
+                    presets,
+                };
+
+                let res = Response::builder(200)
+                    .body(tide::Body::from_json(&export)?)
+                    .header(
+                        "Content-Disposition",
+                        "attachment; filename=usb-presets.json",
+                    )
+                    .build();
+
+                Ok(res)
+            }
+        });
+
+    server
+        .at("/v1/usb/host/presets/import")
+        .put(move |mut req: Request<()>| {
+            let presets = presets.clone();
+
+            async move {
+                if let Some(res) = WriteAccess::refuse(&req) {
+                    return Ok(res);
+                }
+
+                let params: ImportParams = req
+                    .query()
+                    .map_err(|_| tide::Error::from_str(400, "Malformed query parameters"))?;
+
+                let export: PresetExport = match req.body_json().await {
+                    Ok(export) => export,
+                    Err(e) => return Ok(plain(400, &format!("Malformed preset export: {e}"))),
+                };
+
+                let peer = req.peer_addr().unwrap_or("an unknown address").to_string();
+
+                if let Err(e) = validate(&export) {
+                    audit(&format!("rejected import from {peer}: {e}"));
+                    return Ok(plain(422, &e.to_string()));
+                }
+
+                let mut merged = presets.try_get().unwrap_or_default();
+
+                match merge(&mut merged, export.presets, params.on_conflict) {
+                    Ok(names) => {
+                        audit(&format!(
+                            "imported from {peer} ({:?}): {}",
+                            params.on_conflict,
+                            names.join(", ")
+                        ));
+
+                        presets.set(merged);
+
+                        Ok(Response::builder(200)
+                            .body(tide::Body::from_json(&names)?)
+                            .build())
+                    }
+                    Err(conflicts) => {
+                        let msg = format!("Presets already exist: {}", conflicts.join(", "));
+                        audit(&format!("rejected import from {peer}: {msg}"));
+                        Ok(plain(409, &msg))
+                    }
+                }
+            }
+        });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{merge, validate, OnConflict, PresetExport, UsbPreset, SCHEMA_VERSION};
+
+    fn preset(name: &str, port1: bool) -> UsbPreset {
+        UsbPreset {
+            name: name.to_string(),
+            ports: [Some(port1), None, None],
+            delay_ms: None,
+        }
+    }
+
+    #[test]
+    fn validation() {
+        let mut export = PresetExport {
+            schema_version: SCHEMA_VERSION,
+            presets: vec![preset("flash", true), preset("boot", false)],
+        };
+
+        println!("A valid export is accepted");
+        assert!(validate(&export).is_ok());
+
+        println!("Exports round-trip");
+        let json = serde_json::to_string(&export).unwrap();
+        assert_eq!(serde_json::from_str::<PresetExport>(&json).unwrap(), export);
+
+        println!("Unknown schema versions are rejected");
+        export.schema_version = SCHEMA_VERSION + 1;
+        assert!(validate(&export).is_err());
+        export.schema_version = SCHEMA_VERSION;
+
+        println!("Duplicate and empty names are rejected");
+        export.presets.push(preset("flash", false));
+        assert!(validate(&export).is_err());
+        export.presets[2].name = " ".to_string();
+        assert!(validate(&export).is_err());
+        export.presets.pop();
+
+        println!("Unreasonable delays are rejected");
+        export.presets[0].delay_ms = Some(3_600_000);
+        assert!(validate(&export).is_err());
+    }
+
+    #[test]
+    fn conflicts() {
+        let existing = vec![preset("flash", true), preset("boot", true)];
+        let imported = vec![preset("flash", false), preset("debug", true)];
+
+        println!("Conflicts are rejected by default");
+        let mut merged = existing.clone();
+        let res = merge(&mut merged, imported.clone(), OnConflict::Reject);
+        assert_eq!(res, Err(vec!["flash".to_string()]));
+        assert_eq!(merged, existing);
+
+        println!("Existing presets can be replaced");
+        let mut merged = existing.clone();
+        let res = merge(&mut merged, imported.clone(), OnConflict::Replace);
+        assert_eq!(res, Ok(vec!["flash".to_string(), "debug".to_string()]));
+        assert_eq!(merged.len(), 3);
+        assert_eq!(merged[0], imported[0]);
+
+        println!("Or imported under a new name");
+        let mut merged = existing.clone();
+        merged.push(preset("flash (2)", true));
+        let res = merge(&mut merged, imported, OnConflict::Rename);
+        assert_eq!(res, Ok(vec!["flash (3)".to_string(), "debug".to_string()]));
+        assert_eq!(merged[0], existing[0]);
+        assert_eq!(merged.len(), 5);
+    }
+}
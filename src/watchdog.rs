@@ -15,12 +15,17 @@
 // with this program; if not, write to the Free Software Foundation, Inc.,
 // 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
 
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use anyhow::{bail, Result};
+use async_std::sync::Arc;
 use async_std::task::sleep;
+use serde::{Deserialize, Serialize};
 
+use crate::broker::{BrokerBuilder, Topic};
 use crate::dut_power::TickReader;
+use crate::measurement::Timestamp;
+use crate::realtime::ThreadReport;
 use crate::watched_tasks::WatchedTasksBuilder;
 
 #[cfg(any(test, feature = "demo_mode"))]
@@ -46,25 +51,107 @@ mod sd {
 
 use sd::{notify, watchdog_enabled, STATE_READY, STATE_WATCHDOG};
 
+/// How often the watchdog health information is updated
+const HEALTH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Information to diagnose watchdog and RealtimeViolation trips remotely
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct WatchdogHealth {
+    /// Interval at which systemd is notified in milliseconds, if systemd
+    /// requested a watchdog at all
+    pub interval: Option<u64>,
+    /// The last time systemd was notified
+    pub last_feed: Option<Timestamp>,
+    /// Ticks of the power thread per second. The thread only ticks while
+    /// the ADC values are fresh.
+    pub tick_rate: f32,
+    /// Worst wakeup latency of the power thread since the tacd started in
+    /// microseconds
+    pub latency_max_us: Option<u64>,
+}
+
 pub struct Watchdog {
     interval: Duration,
     dut_power_tick: TickReader,
+    digital_io_tick: TickReader,
+    health: Arc<Topic<WatchdogHealth>>,
+}
+
+/// Keep the watchdog health information up to date
+fn handle_health(
+    wtb: &mut WatchedTasksBuilder,
+    health: Arc<Topic<WatchdogHealth>>,
+    mut dut_power_tick: TickReader,
+    realtime_threads: Arc<Topic<Vec<ThreadReport>>>,
+) -> Result<()> {
+    wtb.spawn_task("watchdog-health", async move {
+        let mut last = Instant::now();
+
+        loop {
+            sleep(HEALTH_INTERVAL).await;
+
+            let now = Instant::now();
+            let ticks = dut_power_tick.progress();
+            let tick_rate = ticks as f32 / now.duration_since(last).as_secs_f32();
+            last = now;
+
+            // The realtime audit knows how late the thread woke up
+            let latency_max_us = realtime_threads.try_get().and_then(|threads| {
+                threads
+                    .iter()
+                    .find(|thread| thread.name == "power-thread")
+                    .map(|thread| thread.latency_max_us)
+            });
+
+            health.modify(|prev| {
+                let mut health = prev?;
+                health.tick_rate = tick_rate;
+                health.latency_max_us = latency_max_us;
+                Some(health)
+            });
+        }
+    })
 }
 
 impl Watchdog {
-    pub fn new(dut_power_tick: TickReader) -> Option<Self> {
+    pub fn new(
+        bb: &mut BrokerBuilder,
+        wtb: &mut WatchedTasksBuilder,
+        dut_power_tick: TickReader,
+        digital_io_tick: TickReader,
+        realtime_threads: Arc<Topic<Vec<ThreadReport>>>,
+    ) -> Result<Option<Self>> {
         let micros = watchdog_enabled(false).unwrap_or(0);
-
-        if micros != 0 {
-            let interval = Duration::from_micros(micros) / 2;
-
-            Some(Self {
+        let interval = (micros != 0).then(|| Duration::from_micros(micros) / 2);
+
+        let health = bb.topic_ro(
+            "/v1/tac/watchdog",
+            Some(WatchdogHealth {
+                interval: interval.map(|i| i.as_millis() as u64),
+                last_feed: None,
+                tick_rate: 0.0,
+                latency_max_us: None,
+            }),
+        );
+
+        handle_health(
+            wtb,
+            health.clone(),
+            dut_power_tick.clone(),
+            realtime_threads,
+        )?;
+
+        match interval {
+            Some(interval) => Ok(Some(Self {
                 interval,
                 dut_power_tick,
-            })
-        } else {
-            log::info!("Watchdog not requested. Disabling");
-            None
+                digital_io_tick,
+                health,
+            })),
+            None => {
+                log::info!("Watchdog not requested. Disabling");
+                Ok(None)
+            }
         }
     }
 
@@ -74,6 +161,8 @@ impl Watchdog {
     /// - dut_pwr thread - otherwise the tick would not be incremented
     /// - adc thread - if the adc values are too old dut_pwr_thread will
     ///   not increment the tick.
+    /// - digital io thread - otherwise pulses and PWM signals on OUT_0 and
+    ///   OUT_1 could get stuck.
     pub fn keep_fed(mut self, wtb: &mut WatchedTasksBuilder) -> Result<()> {
         notify(false, [(STATE_READY, "1")].iter())?;
 
@@ -87,7 +176,19 @@ impl Watchdog {
                     bail!("Power Thread stalled for too long");
                 }
 
+                if self.digital_io_tick.is_stale() {
+                    notify(false, [(STATE_WATCHDOG, "trigger")].iter())?;
+
+                    bail!("Digital IO Thread stalled for too long");
+                }
+
                 notify(false, [(STATE_WATCHDOG, "1")].iter())?;
+
+                self.health.modify(|prev| {
+                    let mut health = prev?;
+                    health.last_feed = Some(Timestamp::now());
+                    Some(health)
+                });
             }
         })?;
 
@@ -1,4 +1,6 @@
+use std::any::Any;
 use std::future::Future;
+use std::panic::{catch_unwind, AssertUnwindSafe};
 use std::pin::Pin;
 use std::sync::{Arc, Mutex};
 use std::task::{Context, Poll, Waker};
@@ -46,6 +48,15 @@ pub struct WatchedTasks {
     threads: Vec<ThreadHandle>,
 }
 
+/// Get the message a panic was started with (if it is a string)
+pub fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("<unknown panic payload>")
+}
+
 impl ThreadHandle {
     fn new<F>(name: String, function: F) -> Result<Self>
     where
@@ -66,9 +77,11 @@ impl ThreadHandle {
         // manually.
 
         let handle = thread::Builder::new().name(name).spawn(move || {
-            // We could std::panic::catch_unwind() here in the future to handle
-            // panics inside of spawned threads.
-            let res = function();
+            // Turn panics inside of spawned threads into errors, so that they
+            // are handled like any other thread exit below.
+            // The panic itself is recorded by the panic hook.
+            let res = catch_unwind(AssertUnwindSafe(function))
+                .unwrap_or_else(|payload| Err(anyhow!("Panicked: {}", panic_message(&*payload))));
 
             // Keep the Mutex locked until exiting the thread to prevent the case
             // following race condition:
@@ -335,4 +348,22 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn thread_panic_ends_execution() -> Result<()> {
+        let mut wtb = WatchedTasksBuilder::new();
+
+        wtb.spawn_thread("panicking-thread", || panic!("Oh no!"))?;
+
+        let mut wt = wtb.watch();
+
+        // The panic should be turned into an error instead of leaving the
+        // WatchedTasks waiting forever.
+        let wt_res = block_on(timeout(TIMEOUT, async { (&mut wt).await }));
+        let err = wt_res?.unwrap_err();
+
+        assert!(format!("{err:#}").contains("Oh no!"));
+
+        Ok(())
+    }
 }
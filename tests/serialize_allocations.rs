@@ -0,0 +1,108 @@
+// This file is part of tacd, the LXA TAC system daemon
+// Copyright (C) 2022 Pengutronix e.K.
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along
+// with this program; if not, write to the Free Software Foundation, Inc.,
+// 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+
+//! Count the allocations made when serializing topic values
+//!
+//! This needs a global allocator that counts allocations, which should not
+//! be installed in the unit tests of the tacd itself, so it lives in its
+//! own test binary.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::sync::Arc;
+
+use serde::Serialize;
+
+#[path = "../src/broker/scratch.rs"]
+mod scratch;
+
+use scratch::serialize_pooled;
+
+std::thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+/// Count the allocations made by each thread, to keep an eye on the
+/// allocator churn caused by serializing values
+struct CountingAllocator;
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        // The counter may already be gone while the thread exits
+        let _ = ALLOCATIONS.try_with(|a| a.set(a.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|a| a.set(a.get() + 1));
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+/// Get the number of allocations `f` performed on this thread
+fn allocations(f: impl FnOnce()) -> usize {
+    let before = ALLOCATIONS.with(Cell::get);
+    f();
+    ALLOCATIONS.with(Cell::get) - before
+}
+
+/// Serializes like a `Measurement` of the tacd
+#[derive(Serialize, Clone, Copy)]
+struct Measurement {
+    ts: f64,
+    value: f32,
+}
+
+#[test]
+fn serialization_allocations() {
+    // One second worth of updates of the ten ADC channels plus the
+    // computed DUT power and resistance, which are published at 10Hz.
+    const UPDATES: usize = 12 * 10;
+
+    let value = Measurement {
+        ts: 1.7e12,
+        value: 12.345,
+    };
+
+    println!("Serializing into a fresh Vec allocates multiple times per value");
+    let unpooled = allocations(|| {
+        for _ in 0..UPDATES {
+            let ser = serde_json::to_vec(&value).unwrap();
+            let _: Arc<[u8]> = Arc::from(ser.into_boxed_slice());
+        }
+    });
+
+    println!("Reusing the scratch buffer only allocates the shared Arc");
+    let _ = serialize_pooled(&value);
+
+    let pooled = allocations(|| {
+        for _ in 0..UPDATES {
+            let _ = serialize_pooled(&value);
+        }
+    });
+
+    println!("{unpooled} allocations without and {pooled} with the scratch buffer");
+    assert_eq!(pooled, UPDATES);
+    assert!(unpooled >= 2 * pooled);
+}
@@ -29,16 +29,21 @@ import "@cloudscape-design/global-styles/index.css";
 
 import "./App.css";
 import { useMqttSubscription } from "./mqtt";
-import { ApiPickerButton, MqttButton } from "./MqttComponents";
+import { ApiPickerButton } from "./MqttComponents";
 import {
   IOBusFaultNotification,
+  InterlockNotification,
   RebootNotification,
   UpdateNotification,
   PowerFailNotification,
+  MaintenanceOverrideNotification,
   ProgressNotification,
   LocatorNotification,
+  TourNotification,
   OverTemperatureNotification,
+  StateRecoveryNotification,
   UsbOverloadNotification,
+  UsbPortFaultNotification,
   CmdHintNotification,
 } from "./TacComponents";
 
@@ -130,13 +135,18 @@ function Navigation() {
         ]}
       />
       <div className="nav_footer">
-        <MqttButton
+        <Button
+          formAction="none"
           iconName="search"
-          topic="/v1/tac/display/locator"
-          send={true}
+          onClick={() =>
+            fetch("/v1/tac/display/locator/start", {
+              method: "PUT",
+              body: JSON.stringify({}),
+            })
+          }
         >
           Find this TAC
-        </MqttButton>
+        </Button>
         <ApiPickerButton />
       </div>
     </>
@@ -171,12 +181,19 @@ function Notifications(props: NotificationsProps) {
     <>
       <ConnectionNotification />
       <RebootNotification />
+      <InterlockNotification />
+      <StateRecoveryNotification />
       <OverTemperatureNotification />
       <ProgressNotification />
       <UsbOverloadNotification />
+      <UsbPortFaultNotification port={1} />
+      <UsbPortFaultNotification port={2} />
+      <UsbPortFaultNotification port={3} />
       <PowerFailNotification />
+      <MaintenanceOverrideNotification />
       <UpdateNotification />
       <LocatorNotification />
+      <TourNotification />
       <IOBusFaultNotification />
       <CmdHintNotification
         cmdHint={props.cmdHint}
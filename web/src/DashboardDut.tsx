@@ -15,6 +15,7 @@
 // with this program; if not, write to the Free Software Foundation, Inc.,
 // 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
 
+import Badge from "@cloudscape-design/components/badge";
 import Box from "@cloudscape-design/components/box";
 import Link from "@cloudscape-design/components/link";
 import Header from "@cloudscape-design/components/header";
@@ -28,11 +29,14 @@ import { ReactElement, useState } from "react";
 
 import {
   MqttBox,
+  MqttButton,
   MqttToggleConv,
   MqttToggle,
   MqttBarMeter,
   MqttChart,
 } from "./MqttComponents";
+import { useMqttSubscription } from "./mqtt";
+import { MaintenanceOverride } from "./TacComponents";
 
 type IOBusServerStatus = {
   hostname: string;
@@ -54,6 +58,42 @@ type Measurement = {
   value: number;
 };
 
+type SequenceStatus =
+  | "Idle"
+  | "Done"
+  | "Cancelled"
+  | { Running: { step: number; steps: number } }
+  | { Failed: string };
+
+function formatSequenceStatus(status: SequenceStatus) {
+  if (typeof status === "string") {
+    return status;
+  }
+
+  if ("Running" in status) {
+    return `Running step ${status.Running.step + 1} of ${status.Running.steps}`;
+  }
+
+  return `Failed: ${status.Failed}`;
+}
+
+type DischargeStatus = {
+  load: number;
+  cooling_down: boolean;
+};
+
+type SerialActivity = {
+  rx: boolean;
+  tx: boolean;
+};
+
+const POWER_CYCLE_SEQUENCE = {
+  Run: [
+    { request: "Off", duration: 2000 },
+    { request: "On", duration: 0 },
+  ],
+};
+
 type UsbDevice = {
   id_product: string;
   id_vendor: string;
@@ -80,6 +120,23 @@ function UnloadingSection(props: UnloadingSectionProps) {
   );
 }
 
+// Make it obvious that the output is not protected as usual
+function OverrideBadge() {
+  const ov = useMqttSubscription<MaintenanceOverride | null>(
+    "/v1/dut/override",
+  );
+
+  if (!ov) {
+    return null;
+  }
+
+  return (
+    <Badge color="red">
+      Override until {new Date(ov.expires).toLocaleTimeString()}
+    </Badge>
+  );
+}
+
 export default function DashboardDut() {
   return (
     <SpaceBetween size="m">
@@ -130,14 +187,17 @@ export default function DashboardDut() {
             <MqttToggleConv
               topic="/v1/dut/powered"
               to_bool={(status: string) => status === "On"}
-              from_bool={(on: boolean) => (on ? "On" : "Off")}
+              from_bool={(on: boolean) => (on ? "On" : "OffDefault")}
             >
               Power On
             </MqttToggleConv>
           </Box>
           <Box>
             <Box variant="awsui-key-label">Status</Box>
-            <MqttBox topic="/v1/dut/powered" format={(msg: string) => msg} />
+            <SpaceBetween direction="horizontal" size="xs">
+              <MqttBox topic="/v1/dut/powered" format={(msg: string) => msg} />
+              <OverrideBadge />
+            </SpaceBetween>
           </Box>
           <Box>
             <Box variant="awsui-key-label">Voltage</Box>
@@ -158,6 +218,53 @@ export default function DashboardDut() {
             />
           </Box>
         </ColumnLayout>
+        <ColumnLayout columns={4} variant="text-grid">
+          <Box>
+            <Box variant="awsui-key-label">Power Sequence</Box>
+            <SpaceBetween direction="horizontal" size="xs">
+              <MqttButton
+                iconName="refresh"
+                topic="/v1/dut/powered/sequence"
+                send={POWER_CYCLE_SEQUENCE}
+              >
+                Power Cycle
+              </MqttButton>
+              <MqttButton
+                iconName="close"
+                topic="/v1/dut/powered/sequence"
+                send={"Cancel"}
+              >
+                Cancel
+              </MqttButton>
+            </SpaceBetween>
+          </Box>
+          <Box>
+            <Box variant="awsui-key-label">Sequence Status</Box>
+            <MqttBox
+              topic="/v1/dut/powered/sequence/status"
+              format={formatSequenceStatus}
+            />
+          </Box>
+          <Box>
+            <Box variant="awsui-key-label">Discharge Resistor Load</Box>
+            <MqttBox
+              topic="/v1/dut/powered/discharge"
+              format={(obj: DischargeStatus) => {
+                const load = `${(obj.load * 100).toFixed(0)}%`;
+                return obj.cooling_down ? `${load} (cooling down)` : load;
+              }}
+            />
+          </Box>
+          <Box>
+            <Box variant="awsui-key-label">Console Activity</Box>
+            <MqttBox
+              topic="/v1/dut/serial/activity"
+              format={(obj: SerialActivity) =>
+                `${obj.rx ? "●" : "○"} RX ${obj.tx ? "●" : "○"} TX`
+              }
+            />
+          </Box>
+        </ColumnLayout>
       </Container>
 
       <Container
@@ -373,6 +480,21 @@ export default function DashboardDut() {
             return (100 * obj.value) / 0.7;
           }}
         />
+        <ColumnLayout columns={2} variant="text-grid">
+          <Box>
+            <Box variant="awsui-key-label">Power Budget</Box>
+            <MqttToggle topic="/v1/usb/host/budget/enforce">
+              Turn off ports that exceed 500mA
+            </MqttToggle>
+          </Box>
+          <Box>
+            <Box variant="awsui-key-label">Debounce Time</Box>
+            <MqttBox
+              topic="/v1/usb/host/budget/debounce"
+              format={(ms: number) => `${ms}ms`}
+            />
+          </Box>
+        </ColumnLayout>
         <ExpandableSection header="Per-port details">
           {["1", "2", "3"].map((port, idx) => (
             <Box variant="div" key={`port.${idx}`}>
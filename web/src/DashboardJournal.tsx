@@ -19,6 +19,7 @@ import { Terminal } from "xterm";
 import { FitAddon } from "xterm-addon-fit";
 import "xterm/css/xterm.css";
 
+import Button from "@cloudscape-design/components/button";
 import Header from "@cloudscape-design/components/header";
 import SpaceBetween from "@cloudscape-design/components/space-between";
 
@@ -83,6 +84,11 @@ export function JournalView(props: JournalViewProps) {
         ts = entry["SYSLOG_TIMESTAMP"];
       }
 
+      // Prefer the time in the timezone of the TAC over the one of the browser
+      if (entry["TACD_LOCAL_TIMESTAMP"] !== undefined) {
+        ts = entry["TACD_LOCAL_TIMESTAMP"];
+      }
+
       ts = ts.padEnd(15).slice(0, 15);
 
       let unit =
@@ -121,10 +127,29 @@ export function JournalView(props: JournalViewProps) {
   return <div className="terminal_wrap" ref={terminal_div} />;
 }
 
+function ExportButton() {
+  // Compute the time range when the button is clicked, not when it is rendered
+  function download() {
+    const since = Date.now() - 60 * 60 * 1000;
+
+    window.location.href = `/v1/tac/journal/export?format=text&since=${since}`;
+  }
+
+  return (
+    <Button iconName="download" onClick={download}>
+      Download last hour
+    </Button>
+  );
+}
+
 export default function DashboardJournal() {
   return (
     <SpaceBetween size="m">
-      <Header variant="h1" description="Watch the Systemd Journal">
+      <Header
+        variant="h1"
+        description="Watch the Systemd Journal"
+        actions={<ExportButton />}
+      >
         LXA TAC / Systemd Journal
       </Header>
 
@@ -21,6 +21,9 @@ import Header from "@cloudscape-design/components/header";
 import Container from "@cloudscape-design/components/container";
 import SpaceBetween from "@cloudscape-design/components/space-between";
 import ColumnLayout from "@cloudscape-design/components/column-layout";
+import Button from "@cloudscape-design/components/button";
+import Input from "@cloudscape-design/components/input";
+import Link from "@cloudscape-design/components/link";
 
 import { MqttBox, MqttButton } from "./MqttComponents";
 import { UpdateContainer } from "./TacComponents";
@@ -220,6 +223,8 @@ export default function DashboardTac(props: DashboardTacProps) {
 
       <UpdateContainer setCmdHint={props.setCmdHint} />
 
+      <FileUploadContainer />
+
       <Container
         header={
           <Header variant="h2" description="Check your online status">
@@ -262,8 +267,114 @@ export default function DashboardTac(props: DashboardTacProps) {
               }}
             />
           </Box>
+          <Box>
+            <Box variant="awsui-key-label">IPv6 Address</Box>
+            <MqttBox
+              topic="/v1/tac/network/interface/tac-bridge/ipv6"
+              format={(obj: IpList) => {
+                // Link local addresses can not be used without a zone index
+                const global = obj.filter(
+                  (ip) => !ip.toLowerCase().startsWith("fe80:"),
+                );
+                return global.length < 1 ? "-" : global[0];
+              }}
+            />
+          </Box>
+          <Box>
+            <Box variant="awsui-key-label">MAC Address</Box>
+            <MqttBox
+              topic="/v1/tac/network/interface/tac-bridge/mac"
+              format={(msg: string) => msg}
+            />
+          </Box>
+          <Box>
+            <Box variant="awsui-key-label">DUT Interface MAC Address</Box>
+            <MqttBox
+              topic="/v1/tac/network/interface/dut/mac"
+              format={(msg: string) => msg}
+            />
+          </Box>
         </ColumnLayout>
       </Container>
     </SpaceBetween>
   );
 }
+
+type UploadUsage = {
+  files: number;
+  bytes: number;
+};
+
+function FileUploadContainer() {
+  const [token, setToken] = useState("");
+  const [file, setFile] = useState<File | null>(null);
+  const [result, setResult] = useState<string | null>(null);
+
+  function upload() {
+    if (file === null) {
+      return;
+    }
+
+    setResult("Uploading ...");
+
+    fetch(`/srv/uploads/${encodeURIComponent(file.name)}`, {
+      method: "PUT",
+      headers: { Authorization: `Bearer ${token}` },
+      body: file,
+    })
+      .then((response) =>
+        response.ok
+          ? setResult(`Uploaded ${file.name}`)
+          : response.text().then((text) => setResult(`Error: ${text}`)),
+      )
+      .catch((e) => setResult(`Error: ${e}`));
+  }
+
+  return (
+    <Container
+      header={
+        <Header
+          variant="h2"
+          description="Exchange files like test artifacts with this TAC"
+        >
+          File Uploads
+        </Header>
+      }
+    >
+      <ColumnLayout columns={3} variant="text-grid">
+        <SpaceBetween size="xs">
+          <Box variant="awsui-key-label">Upload Token</Box>
+          <Input
+            type="password"
+            value={token}
+            onChange={({ detail }) => setToken(detail.value)}
+          />
+        </SpaceBetween>
+        <SpaceBetween size="xs">
+          <Box variant="awsui-key-label">File</Box>
+          <input
+            type="file"
+            onChange={(ev) => setFile(ev.target.files?.item(0) ?? null)}
+          />
+          <Button disabled={file === null} onClick={upload}>
+            Upload
+          </Button>
+          {result !== null ? <Box>{result}</Box> : null}
+        </SpaceBetween>
+        <Box>
+          <Box variant="awsui-key-label">
+            <Link external href="/srv/uploads/">
+              Uploaded Files
+            </Link>
+          </Box>
+          <MqttBox
+            topic="/v1/tac/srv/upload/usage"
+            format={(obj: UploadUsage) =>
+              `${obj.files} files, ${(obj.bytes / 1e6).toFixed(1)} MB`
+            }
+          />
+        </Box>
+      </ColumnLayout>
+    </Container>
+  );
+}
@@ -272,7 +272,25 @@ export function MqttChart(props: MqttChartProps) {
     200,
     measToPoint,
   );
-  let values = history.current;
+
+  // Samples of measurement channels taken before the page was loaded
+  const [backlog, setBacklog] = useState<Array<Point>>([]);
+
+  useEffect(() => {
+    fetch(`/v1/tac/history${props.topic}`)
+      .then((response) => (response.ok ? response.json() : []))
+      .then((samples: Array<Measurement>) =>
+        setBacklog(samples.map(measToPoint)),
+      )
+      .catch(() => setBacklog([]));
+  }, [props.topic]);
+
+  let live = history.current;
+  let first = live.length >= 1 ? live[0]["x"] : undefined;
+  let values = backlog
+    .filter((p) => first === undefined || p.x < first)
+    .concat(live)
+    .slice(-200);
 
   // Find y-axis snap points that are smaller/larger than all samples in view.
   let minSnap = 0;
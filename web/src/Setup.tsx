@@ -17,11 +17,15 @@
 
 import { useState } from "react";
 
+import Alert from "@cloudscape-design/components/alert";
 import Box from "@cloudscape-design/components/box";
+import Button from "@cloudscape-design/components/button";
 import Container from "@cloudscape-design/components/container";
 import Icon from "@cloudscape-design/components/icon";
 import Header from "@cloudscape-design/components/header";
+import Input from "@cloudscape-design/components/input";
 import Link from "@cloudscape-design/components/link";
+import Select from "@cloudscape-design/components/select";
 import SpaceBetween from "@cloudscape-design/components/space-between";
 import Spinner from "@cloudscape-design/components/spinner";
 import Wizard from "@cloudscape-design/components/wizard";
@@ -29,7 +33,7 @@ import Wizard from "@cloudscape-design/components/wizard";
 import { LabgridService, LabgridConfig } from "./SettingsLabgrid";
 import { MqttToggle } from "./MqttComponents";
 import { ConfigEditor } from "./ConfigEditor";
-import { useMqttState } from "./mqtt";
+import { useMqttAction, useMqttState, useMqttSubscription } from "./mqtt";
 
 const SSH_AUTH_KEYS_EXAMPLE =
   "# Paste one (or multiple) of your ssh public keys here.\n" +
@@ -39,6 +43,179 @@ const SSH_AUTH_KEYS_EXAMPLE =
   "#\n" +
   "# ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIBlPtT5dnGcZn0Z6FyD6VGqt3Jx0s+BHhMahxR0KlJ8G tux@igloo";
 
+type SshKeySource = { GitHub: string } | { GitLab: string } | { Url: string };
+
+type SshImportStatus =
+  | "Idle"
+  | "Fetching"
+  | "AwaitingConfirmation"
+  | "Rejected"
+  | { Imported: number }
+  | { Failed: string };
+
+const SSH_KEY_SOURCES = [
+  { value: "GitHub", label: "GitHub user" },
+  { value: "GitLab", label: "GitLab user" },
+  { value: "Url", label: "URL" },
+];
+
+function SshKeyImport() {
+  const [source, setSource] = useState(SSH_KEY_SOURCES[0]);
+  const [name, setName] = useState("");
+  const startImport = useMqttAction<SshKeySource>("/v1/tac/ssh/import");
+  const status = useMqttSubscription<SshImportStatus>(
+    "/v1/tac/ssh/import/status",
+  );
+
+  let message = null;
+  let type: "info" | "success" | "error" = "info";
+
+  if (status === "Fetching") {
+    message = "Fetching keys ...";
+  } else if (status === "AwaitingConfirmation") {
+    message =
+      "Please compare the fingerprints shown on the LCD of your TAC with " +
+      "those of your keys and confirm the import via the lower button.";
+  } else if (status === "Rejected") {
+    message = "The import was rejected on the TAC.";
+  } else if (status !== undefined && typeof status !== "string") {
+    if ("Imported" in status) {
+      type = "success";
+      message = `Imported ${status.Imported} new keys. Reload the editor below to see them.`;
+    } else {
+      type = "error";
+      message = `Import failed: ${status.Failed}`;
+    }
+  }
+
+  const submit = () => {
+    const value = name.trim();
+
+    if (value === "") {
+      return;
+    }
+
+    if (source.value === "GitHub") {
+      startImport({ GitHub: value });
+    } else if (source.value === "GitLab") {
+      startImport({ GitLab: value });
+    } else {
+      startImport({ Url: value });
+    }
+  };
+
+  return (
+    <SpaceBetween size="s">
+      <SpaceBetween size="xs" direction="horizontal">
+        <Select
+          selectedOption={source}
+          options={SSH_KEY_SOURCES}
+          onChange={({ detail }) =>
+            setSource(detail.selectedOption as (typeof SSH_KEY_SOURCES)[0])
+          }
+        />
+        <Input
+          value={name}
+          placeholder={source.value === "Url" ? "https://..." : "username"}
+          onChange={({ detail }) => setName(detail.value)}
+        />
+        <Button
+          formAction="none"
+          disabled={status === "Fetching"}
+          onClick={submit}
+        >
+          Import
+        </Button>
+      </SpaceBetween>
+      <Alert type={type} visible={message !== null}>
+        {message}
+      </Alert>
+    </SpaceBetween>
+  );
+}
+
+// The same checks the TAC performs before applying the settings
+const HOSTNAME_LABEL = /^[a-zA-Z0-9]([a-zA-Z0-9-]*[a-zA-Z0-9])?$/;
+const MAX_DNS_SERVERS = 3;
+
+function isValidHostname(hostname: string) {
+  return (
+    hostname.length > 0 &&
+    hostname.length <= 64 &&
+    hostname
+      .split(".")
+      .every((label) => label.length <= 63 && HOSTNAME_LABEL.test(label))
+  );
+}
+
+function NetworkSettings() {
+  const hostname = useMqttSubscription<string>("/v1/tac/network/hostname");
+  const dnsServers = useMqttSubscription<Array<string>>("/v1/tac/network/dns");
+  const setHostname = useMqttAction<string>("/v1/tac/network/hostname");
+  const setDnsServers = useMqttAction<Array<string>>("/v1/tac/network/dns");
+
+  const [newHostname, setNewHostname] = useState("");
+  const [newDnsServers, setNewDnsServers] = useState("");
+
+  const servers = newDnsServers
+    .split(/[\s,]+/)
+    .filter((server) => server !== "");
+
+  const hostnameValid = isValidHostname(newHostname);
+  const serversValid = servers.length <= MAX_DNS_SERVERS;
+
+  return (
+    <SpaceBetween size="m">
+      <SpaceBetween size="xs">
+        <Box variant="awsui-key-label">
+          Hostname (currently: {hostname ?? "unknown"})
+        </Box>
+        <SpaceBetween size="xs" direction="horizontal">
+          <Input
+            value={newHostname}
+            placeholder={hostname ?? "lxatac"}
+            invalid={newHostname !== "" && !hostnameValid}
+            onChange={({ detail }) => setNewHostname(detail.value)}
+          />
+          <Button
+            formAction="none"
+            disabled={!hostnameValid}
+            onClick={() => setHostname(newHostname)}
+          >
+            Set hostname
+          </Button>
+        </SpaceBetween>
+      </SpaceBetween>
+      <SpaceBetween size="xs">
+        <Box variant="awsui-key-label">
+          DNS servers (currently:{" "}
+          {dnsServers === undefined
+            ? "unknown"
+            : dnsServers.length > 0
+              ? dnsServers.join(", ")
+              : "from DHCP"}
+          )
+        </Box>
+        <SpaceBetween size="xs" direction="horizontal">
+          <Input
+            value={newDnsServers}
+            placeholder="e.g. 192.168.1.1, 2001:db8::53"
+            invalid={!serversValid}
+            onChange={({ detail }) => setNewDnsServers(detail.value)}
+          />
+          <Button
+            formAction="none"
+            disabled={!serversValid}
+            onClick={() => setDnsServers(servers)}
+          >
+            {servers.length > 0 ? "Set DNS servers" : "Use DHCP"}
+          </Button>
+        </SpaceBetween>
+      </SpaceBetween>
+    </SpaceBetween>
+  );
+}
+
 export default function Setup() {
   const [setupModeSettled, setupMode, setSetupMode] =
     useMqttState<boolean>("/v1/tac/setup_mode");
@@ -131,6 +308,28 @@ export default function Setup() {
                   </Container>
                 ),
               },
+              {
+                title: "Configure Network",
+                description: "Choose how your TAC is named and resolves names",
+                content: (
+                  <Container>
+                    <Box variant="p">
+                      Your TAC announces its hostname in the network, so that
+                      you can reach it by name instead of its IP address. Give
+                      it a name that tells it apart from the other TACs in
+                      your lab.
+                    </Box>
+                    <Box variant="p">
+                      By default the DNS servers announced via DHCP are used.
+                      If your network does not provide them you can enter up to
+                      three static DNS servers instead. Leave the field empty
+                      to go back to the servers announced via DHCP.
+                    </Box>
+                    <NetworkSettings />
+                  </Container>
+                ),
+                isOptional: true,
+              },
               {
                 title: "Configure Software Updates",
                 description:
@@ -197,6 +396,14 @@ export default function Setup() {
                         Make sure to check if logging in works before leaving
                         the setup mode.
                       </Box>
+                      <Box variant="p">
+                        Alternatively you can import the public keys of a
+                        GitHub or GitLab user or from any other URL that
+                        provides them. The fingerprints of the fetched keys are
+                        shown on the LCD of the TAC and the import has to be
+                        confirmed there.
+                      </Box>
+                      <SshKeyImport />
                       <ConfigEditor
                         path="/v1/tac/ssh/authorized_keys"
                         language="text"
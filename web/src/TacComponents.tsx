@@ -15,7 +15,7 @@
 // with this program; if not, write to the Free Software Foundation, Inc.,
 // 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
 
-import { useEffect, useState, useRef } from "react";
+import { useEffect, useRef, useState } from "react";
 
 import Alert from "@cloudscape-design/components/alert";
 import Box from "@cloudscape-design/components/box";
@@ -77,17 +77,20 @@ type RaucSlots = {
   bootloader_0: BootloaderSlot;
 };
 
-type RaucProgress = {
-  percentage: number;
+type PhaseProgress = {
+  percentage: number | null;
   message: string;
-  nesting_depth: number;
+  done: boolean;
 };
 
-enum RaucInstallStep {
-  Idle,
-  Installing,
-  Done,
-}
+type InstallOutcome = "Running" | "Succeeded" | "Unknown" | { Failed: string };
+
+type InstallSession = {
+  started: number;
+  source: string | null;
+  channel: string | null;
+  outcome: InstallOutcome;
+};
 
 enum UsbOverload {
   Total = "Total",
@@ -96,6 +99,12 @@ enum UsbOverload {
   Port3 = "Port3",
 }
 
+enum UsbPortFault {
+  OverCurrent = "OverCurrent",
+  VbusShort = "VbusShort",
+  OverBudget = "OverBudget",
+}
+
 enum OutputState {
   On = "On",
   Off = "Off",
@@ -105,8 +114,26 @@ enum OutputState {
   OverCurrent = "OverCurrent",
   OverVoltage = "OverVoltage",
   RealtimeViolation = "RealtimeViolation",
+  Probing = "Probing",
 }
 
+export type MaintenanceOverride = {
+  current_limit: number;
+  grace_period_ms: number;
+  duration_s: number;
+  reason: string;
+  expires: number;
+};
+
+type TourStep = {
+  step: number;
+  steps: number;
+  screen: string;
+  title: string;
+  caption: string;
+  topics: Array<string>;
+};
+
 type Duration = {
   secs: number;
   nanos: number;
@@ -118,6 +145,14 @@ type UpstreamBundle = {
   newer_than_installed: boolean;
 };
 
+type BundleInfo = {
+  url: string;
+  compatible: string | null;
+  version: string | null;
+  build: string | null;
+  error: string | null;
+};
+
 type Channel = {
   name: string;
   display_name: string;
@@ -126,6 +161,8 @@ type Channel = {
   polling_interval?: Duration;
   enabled: boolean;
   bundle?: UpstreamBundle;
+  authentication?: "basic" | "bearer";
+  has_credentials: boolean;
 };
 
 interface SlotStatusProps {
@@ -253,6 +290,12 @@ export function UpdateConfig() {
             Periodically check for updates
           </MqttToggle>
         </Box>
+        <Box>
+          <Box variant="awsui-key-label">Update Interlock</Box>
+          <MqttToggle topic="/v1/tac/update/interlock">
+            Refuse to install updates or reboot while the DUT is powered
+          </MqttToggle>
+        </Box>
       </ColumnLayout>
     </Container>
   );
@@ -269,6 +312,9 @@ export function UpdateChannels(props: UpdateChannelsProps) {
   const enable_polling_topic = useMqttSubscription<Array<Channel>>(
     "/v1/tac/update/enable_polling",
   );
+  const bundle_info = useMqttSubscription<BundleInfo | null>(
+    "/v1/tac/update/bundle_info",
+  );
 
   const channels = channels_topic !== undefined ? channels_topic : [];
   const enable_polling =
@@ -371,6 +417,39 @@ export function UpdateChannels(props: UpdateChannelsProps) {
             return `Every ${seconds} Seconds`;
           },
         },
+        {
+          id: "bundle",
+          header: "Bundle",
+          cell: (e) => {
+            if (bundle_info && bundle_info.url === e.url) {
+              if (bundle_info.error) {
+                return `Failed to inspect: ${bundle_info.error}`;
+              }
+
+              return (
+                <SpaceBetween size="xs">
+                  <span>{bundle_info.version}</span>
+                  <span>Built {bundle_info.build ?? "at an unknown date"}</span>
+                  <span>{bundle_info.compatible}</span>
+                </SpaceBetween>
+              );
+            }
+
+            if (!e.enabled) {
+              return "Not enabled";
+            }
+
+            return (
+              <MqttButton
+                iconName="search"
+                topic="/v1/tac/update/bundle_info"
+                send={e.url}
+              >
+                Inspect
+              </MqttButton>
+            );
+          },
+        },
         {
           id: "upgrade",
           header: "Upgrade",
@@ -411,50 +490,67 @@ export function UpdateChannels(props: UpdateChannelsProps) {
 }
 
 export function ProgressNotification() {
-  const operation = useMqttSubscription<string>("/v1/tac/update/operation");
-  const progress = useMqttSubscription<RaucProgress>("/v1/tac/update/progress");
-  const last_error = useMqttSubscription<string>("/v1/tac/update/last_error");
+  const session = useMqttSubscription<InstallSession>("/v1/tac/update/session");
+  const download = useMqttSubscription<PhaseProgress>(
+    "/v1/tac/update/progress/download",
+  );
+  const install = useMqttSubscription<PhaseProgress>(
+    "/v1/tac/update/progress/install",
+  );
 
-  const [installStep, setInstallStep] = useState(RaucInstallStep.Idle);
-  const prev_operation = useRef<string | undefined>(undefined);
+  // The session is persisted on the TAC, so a failure would be shown forever.
+  // Only show it if the installation was seen running in this browser.
+  const running = session !== undefined && session.outcome === "Running";
+  const seen_running = useRef(false);
 
   useEffect(() => {
-    if (prev_operation.current === "idle" && operation === "installing") {
-      setInstallStep(RaucInstallStep.Installing);
+    if (running) {
+      seen_running.current = true;
     }
-
-    if (prev_operation.current === "installing" && operation === "idle") {
-      setInstallStep(RaucInstallStep.Done);
-    }
-
-    prev_operation.current = operation;
-  }, [operation]);
+  }, [running]);
 
   let inner = null;
 
-  if (installStep === RaucInstallStep.Installing) {
-    let valid = progress !== undefined;
-    let value = progress === undefined ? 0 : progress.percentage;
-    let message = progress === undefined ? "" : progress.message;
+  if (running) {
+    // RAUC does not report how much of the bundle was downloaded yet,
+    // so the download phase can only be shown as started or done.
+    let download_done = download !== undefined && download.done;
+    let download_value = download_done ? 100 : 0;
+    let download_message = download === undefined ? "" : download.message;
+
+    let install_value =
+      install === undefined || install.percentage === null
+        ? 0
+        : install.percentage;
+    let install_message = install === undefined ? "" : install.message;
 
     inner = (
-      <ProgressBar
-        status={valid ? "in-progress" : "error"}
-        value={value}
-        description="Installation may take several minutes"
-        additionalInfo={message}
-      />
+      <SpaceBetween size="s">
+        <ProgressBar
+          status={download_done ? "success" : "in-progress"}
+          value={download_value}
+          label="Fetching and checking the bundle"
+          additionalInfo={download_done ? "" : download_message}
+        />
+        <ProgressBar
+          status="in-progress"
+          value={install_value}
+          label="Installing"
+          description="Installation may take several minutes"
+          additionalInfo={install_message}
+        />
+      </SpaceBetween>
     );
   }
 
-  if (installStep === RaucInstallStep.Done) {
-    if (last_error !== undefined && last_error !== "") {
+  if (seen_running.current && session !== undefined) {
+    if (typeof session.outcome === "object") {
       inner = (
         <ProgressBar
           status={"error"}
           value={100}
-          description="Failure"
-          additionalInfo="Bundle installation failed"
+          description="Bundle installation failed"
+          additionalInfo={session.outcome.Failed}
         />
       );
     }
@@ -493,6 +589,51 @@ export function RebootNotification() {
   );
 }
 
+type BlockedRequest = {
+  action: "Install" | "Reboot";
+  reason: string;
+  ts: number;
+};
+
+export function InterlockNotification() {
+  const blocked = useMqttSubscription<BlockedRequest | null>(
+    "/v1/tac/update/interlock/blocked",
+  );
+  const [dismissed, setDismissed] = useState<number | null>(null);
+
+  const visible =
+    blocked !== undefined && blocked !== null && blocked.ts !== dismissed;
+  const what =
+    blocked?.action === "Install"
+      ? "install the software update"
+      : "reboot the LXA TAC";
+
+  return (
+    <Alert
+      statusIconAriaLabel="Warning"
+      type="warning"
+      visible={visible}
+      dismissible
+      onDismiss={() => setDismissed(blocked?.ts ?? null)}
+      action={
+        blocked?.action === "Reboot" ? (
+          <MqttButton
+            iconName="refresh"
+            topic="/v1/tac/reboot/override"
+            send={true}
+          >
+            Reboot anyway
+          </MqttButton>
+        ) : undefined
+      }
+      header={`Refused to ${what}`}
+    >
+      {blocked?.reason}. Turn off the DUT first or disable the update
+      interlock in the settings.
+    </Alert>
+  );
+}
+
 interface UpdateContainerProps {
   setCmdHint: (hint: React.ReactNode | null) => void;
 }
@@ -564,13 +705,33 @@ export function UpdateNotification() {
   );
 }
 
+type LocatorSource = "Lcd" | { Web: string } | { Api: string };
+
+type Locator = {
+  active: boolean;
+  source: LocatorSource | null;
+  message: string | null;
+};
+
+function describeLocatorSource(source: LocatorSource | null) {
+  if (source === null) {
+    return "Someone";
+  } else if (source === "Lcd") {
+    return "Someone at the TAC";
+  } else if ("Web" in source) {
+    return `Someone at ${source.Web}`;
+  } else {
+    return source.Api;
+  }
+}
+
 export function LocatorNotification() {
-  const locator = useMqttSubscription<boolean>("/v1/tac/display/locator");
+  const locator = useMqttSubscription<Locator>("/v1/tac/display/locator");
 
   return (
     <Alert
       statusIconAriaLabel="Info"
-      visible={locator === true}
+      visible={locator?.active === true}
       action={
         <MqttButton topic="/v1/tac/display/locator" send={false}>
           Found it!
@@ -578,7 +739,36 @@ export function LocatorNotification() {
       }
       header="Find this TAC"
     >
-      Someone is looking for this TAC.
+      {describeLocatorSource(locator?.source ?? null)} is looking for this TAC.
+      {locator?.message ? ` Message: ${locator.message}` : null}
+    </Alert>
+  );
+}
+
+export function TourNotification() {
+  const tour = useMqttSubscription<TourStep | null>("/v1/tac/display/tour");
+
+  return (
+    <Alert
+      statusIconAriaLabel="Info"
+      visible={tour !== undefined && tour !== null}
+      action={
+        <MqttButton topic="/v1/tac/display/tour" send={false}>
+          End tour
+        </MqttButton>
+      }
+      header={
+        tour ? `Tour ${tour.step + 1}/${tour.steps}: ${tour.title}` : "Tour"
+      }
+    >
+      <SpaceBetween size="xs">
+        <Box>{tour?.caption}</Box>
+        <Box>
+          Use the upper button on the TAC to continue. The same controls are
+          available via these topics:
+        </Box>
+        <Box variant="code">{tour?.topics.join(", ")}</Box>
+      </SpaceBetween>
     </Alert>
   );
 }
@@ -599,6 +789,43 @@ export function IOBusFaultNotification() {
   );
 }
 
+type StateRecovery = {
+  reason: string;
+  damaged_file: string | null;
+  defaulted: Array<string>;
+};
+
+export function StateRecoveryNotification() {
+  const recovery = useMqttSubscription<StateRecovery | null>(
+    "/v1/tac/config/recovery",
+  );
+
+  return (
+    <Alert
+      statusIconAriaLabel="Warning"
+      type="warning"
+      visible={recovery !== undefined && recovery !== null}
+      action={
+        <MqttButton topic="/v1/tac/config/recovery" send={null}>
+          Dismiss
+        </MqttButton>
+      }
+      header="Some settings were reset to their defaults"
+    >
+      <SpaceBetween size="xs">
+        <Box>
+          {recovery?.reason}.{" "}
+          {recovery?.damaged_file
+            ? `The damaged file was kept at ${recovery.damaged_file}.`
+            : null}{" "}
+          Please check the configured limits and other settings:
+        </Box>
+        <Box variant="code">{recovery?.defaulted.join(", ")}</Box>
+      </SpaceBetween>
+    </Alert>
+  );
+}
+
 export function OverTemperatureNotification() {
   const warning = useMqttSubscription<string>("/v1/tac/temperatures/warning");
 
@@ -652,6 +879,53 @@ export function UsbOverloadNotification() {
   );
 }
 
+interface UsbPortFaultNotificationProps {
+  port: number;
+}
+
+export function UsbPortFaultNotification(
+  props: UsbPortFaultNotificationProps,
+) {
+  const fault = useMqttSubscription<UsbPortFault | null>(
+    `/v1/usb/host/port${props.port}/fault`,
+  );
+
+  let reason = null;
+
+  switch (fault) {
+    case UsbPortFault.OverCurrent:
+      reason = "an overcurrent event reported by the hub";
+      break;
+    case UsbPortFault.VbusShort:
+      reason = "a short circuit on VBUS";
+      break;
+    case UsbPortFault.OverBudget:
+      reason = "a current above the per-port limit of 500mA";
+      break;
+  }
+
+  return (
+    <Alert
+      statusIconAriaLabel="Warning"
+      type="warning"
+      visible={reason !== null}
+      action={
+        <MqttButton
+          iconName="refresh"
+          topic={`/v1/usb/host/port${props.port}/powered`}
+          send={true}
+        >
+          Turn port back on
+        </MqttButton>
+      }
+      header={`USB port ${props.port} powered off`}
+    >
+      The USB port was powered off due to {reason}. Check the attached device
+      and its cable before turning the port back on.
+    </Alert>
+  );
+}
+
 export function PowerFailNotification() {
   const state = useMqttSubscription<OutputState>("/v1/dut/powered");
 
@@ -697,6 +971,31 @@ export function PowerFailNotification() {
   );
 }
 
+export function MaintenanceOverrideNotification() {
+  const ov = useMqttSubscription<MaintenanceOverride | null>(
+    "/v1/dut/override",
+  );
+
+  const until = ov ? new Date(ov.expires).toLocaleString() : "";
+
+  return (
+    <Alert
+      statusIconAriaLabel="Warning"
+      type="warning"
+      visible={ov !== undefined && ov !== null}
+      action={
+        <MqttButton iconName="undo" topic="/v1/dut/override" send={null}>
+          Restore protections
+        </MqttButton>
+      }
+      header="DUT power protections relaxed"
+    >
+      A maintenance override raised the DUT current limit to{" "}
+      {ov?.current_limit}A until {until}. Reason: {ov?.reason}
+    </Alert>
+  );
+}
+
 interface CmdHintNotificationProps {
   cmdHint: React.ReactNode | null;
   setCmdHint: (hint: React.ReactNode | null) => void;
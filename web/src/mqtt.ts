@@ -31,6 +31,61 @@ let retained: {
   [topic: string]: Message;
 } = {};
 
+// Some proxies block websocket connections. In that case we fall back to
+// long-polling the REST API of the individual topics instead.
+let useLongPoll = false;
+
+function dispatch(message: Message) {
+  if (message.destinationName in subscriptions) {
+    for (let handler of subscriptions[message.destinationName]) {
+      handler(message);
+    }
+
+    retained[message.destinationName] = message;
+  }
+}
+
+async function longPoll(topic: string) {
+  let since: string | null = null;
+
+  while (topic in subscriptions) {
+    const url: string =
+      since === null ? topic : `${topic}?since=${since}&timeout=30`;
+
+    try {
+      const response = await fetch(url, { cache: "no-store" });
+
+      since = response.headers.get("X-Tacd-Sequence") ?? since;
+
+      if (response.status === 200) {
+        const message = new Message(await response.text());
+        message.destinationName = topic;
+        dispatch(message);
+      } else if (response.status !== 204) {
+        // The topic does not have a value yet or is not readable.
+        // Try again later instead of hammering the server.
+        await new Promise((resolve) => setTimeout(resolve, 5000));
+      }
+    } catch (e) {
+      await new Promise((resolve) => setTimeout(resolve, 5000));
+    }
+  }
+}
+
+function startLongPoll() {
+  if (useLongPoll) {
+    return;
+  }
+
+  console.log("Websocket connection failed. Falling back to long-polling");
+
+  useLongPoll = true;
+
+  for (let topic in subscriptions) {
+    longPoll(topic);
+  }
+}
+
 session.onConnectionLost = function (responseObject) {
   if (responseObject.errorCode !== 0) {
     console.log("onConnectionLost:" + responseObject.errorMessage);
@@ -45,22 +100,47 @@ session.onConnectionLost = function (responseObject) {
   }
 };
 
-session.onMessageArrived = function (message) {
-  if (message.destinationName in subscriptions) {
-    for (let handler of subscriptions[message.destinationName]) {
-      handler(message);
-    }
+session.onMessageArrived = dispatch;
 
-    retained[message.destinationName] = message;
+// Topics that are waiting to be subscribed to in the next batch.
+// Components tend to subscribe to many topics at once, e.g. on page load,
+// which are all sent in a single subscribe packet to save round trips.
+let pendingSubscriptions: Set<string> = new Set();
+
+function subscribeMany(topics: string[]) {
+  if (topics.length > 0 && session.isConnected()) {
+    // paho-mqtt accepts a list of topic filters, which are sent as a single
+    // subscribe packet, but its type definitions do not know about that.
+    session.subscribe(topics as unknown as string);
   }
+}
+
+function flushSubscriptions() {
+  const topics = Array.from(pendingSubscriptions).filter(
+    (topic) => topic in subscriptions,
+  );
+
+  pendingSubscriptions.clear();
+  subscribeMany(topics);
+}
+
+function queueSubscription(topic: string) {
+  if (pendingSubscriptions.size === 0) {
+    setTimeout(flushSubscriptions, 0);
+  }
+
+  pendingSubscriptions.add(topic);
+}
+
+// Subscriptions do not survive a reconnect (e.g. after the tacd was
+// restarted), so subscribe to all topics whenever a connection is made.
+session.onConnected = function () {
+  pendingSubscriptions.clear();
+  subscribeMany(Object.keys(subscriptions));
 };
 
 session.connect({
-  onSuccess: function () {
-    for (let topic in subscriptions) {
-      session.subscribe(topic);
-    }
-  },
+  onFailure: startLongPoll,
   reconnect: true,
 });
 
@@ -70,10 +150,14 @@ function subscribe(
 ) {
   if (subscriptions[topic] === undefined) {
     if (session.isConnected()) {
-      session.subscribe(topic);
+      queueSubscription(topic);
     }
 
     subscriptions[topic] = [];
+
+    if (useLongPoll) {
+      longPoll(topic);
+    }
   }
 
   subscriptions[topic].push(handleMessage);
@@ -121,7 +205,12 @@ export function useMqttState<T>(topic: string, initial?: T) {
 
   function setPayload(payload: T) {
     setShadow([false, payload]);
-    session.send(topic, JSON.stringify(payload), 0, true);
+
+    if (useLongPoll) {
+      fetch(topic, { method: "PUT", body: JSON.stringify(payload) });
+    } else {
+      session.send(topic, JSON.stringify(payload), 0, true);
+    }
   }
 
   const [settled, payload] = shadow;